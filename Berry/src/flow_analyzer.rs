@@ -1,31 +1,150 @@
 // flow_analyzer.rs
-use tokio;
 use lapin::{options::*, types::FieldTable};
 use futures_util::stream::StreamExt;
-use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
 
 mod mq;
 use mq::{create_channel, publish_message, declare_exchange};
+use rts_core::messages::{Recommendation, TrafficUpdate};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TrafficUpdate {
-    pub lane_id: u32,
-    pub vehicle_count: u32,
-    pub timestamp: u64,
+/// Berry's log events are plain text, so they carry no typed payload.
+pub type LogEvent = rts_core::messages::LogEvent<()>;
+use rts_core::messages::Level;
+
+/// Length of the sliding window used to average each lane's vehicle count.
+const WINDOW_SECS: u64 = 60;
+/// Rolling average at or above which a lane is considered congested.
+const CONGESTION_THRESHOLD: f64 = 4.0;
+/// Fraction of the threshold the rolling average must drop below before a
+/// congested lane counts as clear again, so a lane hovering around the
+/// threshold does not flap in and out of congestion.
+const CLEAR_RATIO: f64 = 0.75;
+/// How long the rolling average must stay at or above the threshold before a
+/// lane counts as congested.
+const SUSTAIN_SECS: u64 = 15;
+/// Trend, in vehicles per minute, below which a lane is draining fast enough
+/// that it is left to clear on its own.
+const DRAINING_TREND: f64 = -2.0;
+/// Minimum time between two recommendations for the same lane.
+const COOLDOWN_SECS: u64 = 60;
+/// Green time recommended for a lane right at the threshold.
+const BASE_GREEN_TIME: u32 = 10;
+/// Extra green seconds per vehicle the average sits above the threshold.
+const GREEN_SECS_PER_EXCESS_VEHICLE: f64 = 5.0;
+/// Upper bound on any recommended green time.
+const MAX_GREEN_TIME: u32 = 60;
+
+/// Where a lane stands between sustained congestion and clear.
+#[derive(Debug, Default)]
+struct LaneCongestion {
+    /// When the rolling average last rose to the threshold, while it stays there.
+    above_since: Option<u64>,
+    /// Sustained congestion detected and not yet cleared.
+    congested: bool,
+    last_recommended: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Recommendation {
-    pub lane_id: u32,
-    pub new_green_time: u32,
-    pub timestamp: u64,
+/// Tracks a sliding window of vehicle counts per lane and decides when a lane
+/// has been congested long enough to warrant a recommendation. A lane becomes
+/// congested once its rolling average has stayed at or above the threshold
+/// for SUSTAIN_SECS without draining, and stays congested until the average
+/// falls below CLEAR_RATIO of the threshold. A congested lane gets at most
+/// one recommendation per cooldown.
+pub struct CongestionDetector {
+    window_secs: u64,
+    threshold: f64,
+    cooldown_secs: u64,
+    samples: HashMap<u32, VecDeque<(u64, u32)>>,
+    lanes: HashMap<u32, LaneCongestion>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
+impl CongestionDetector {
+    pub fn new(window_secs: u64, threshold: f64, cooldown_secs: u64) -> Self {
+        CongestionDetector {
+            window_secs,
+            threshold,
+            cooldown_secs,
+            samples: HashMap::new(),
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// Records a vehicle count for a lane and drops samples older than the window.
+    pub fn record(&mut self, lane_id: u32, vehicle_count: u32, now: u64) {
+        let window = self.samples.entry(lane_id).or_default();
+        window.push_back((now, vehicle_count));
+        while let Some(&(ts, _)) = window.front() {
+            if ts + self.window_secs <= now {
+                window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Rolling average of the samples currently in the lane's window.
+    pub fn average(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        if window.is_empty() {
+            return None;
+        }
+        let sum: u32 = window.iter().map(|&(_, count)| count).sum();
+        Some(sum as f64 / window.len() as f64)
+    }
+
+    /// Least-squares slope of the lane's counts over its window, in vehicles
+    /// per minute: positive while the queue builds, negative while it drains.
+    pub fn trend(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        let n = window.len() as f64;
+        let mean_t = window.iter().map(|&(ts, _)| ts as f64).sum::<f64>() / n;
+        let mean_c = window.iter().map(|&(_, count)| count as f64).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for &(ts, count) in window {
+            let dt = ts as f64 - mean_t;
+            covariance += dt * (count as f64 - mean_c);
+            variance += dt * dt;
+        }
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance * 60.0)
+    }
+
+    /// Returns the green time to recommend for the lane, if it is in sustained
+    /// congestion (see `CongestionDetector`), not draining and not cooling down.
+    pub fn evaluate(&mut self, lane_id: u32, now: u64) -> Option<u32> {
+        let average = self.average(lane_id)?;
+        let trend = self.trend(lane_id).unwrap_or(0.0);
+        let lane = self.lanes.entry(lane_id).or_default();
+        if average < self.threshold {
+            lane.above_since = None;
+        } else if lane.above_since.is_none() {
+            lane.above_since = Some(now);
+        }
+        if lane.congested {
+            if average < self.threshold * CLEAR_RATIO {
+                lane.congested = false;
+            }
+        } else if lane.above_since.is_some_and(|since| now >= since + SUSTAIN_SECS) && trend > DRAINING_TREND {
+            lane.congested = true;
+        }
+        if !lane.congested || trend <= DRAINING_TREND {
+            return None;
+        }
+        if lane.last_recommended.is_some_and(|last| now < last + self.cooldown_secs) {
+            return None;
+        }
+        lane.last_recommended = Some(now);
+        Some(green_time_for(average, self.threshold))
+    }
+}
+
+/// Scales the recommended green time with how far the average exceeds the threshold.
+fn green_time_for(average: f64, threshold: f64) -> u32 {
+    let excess = (average - threshold).max(0.0);
+    let green = BASE_GREEN_TIME as f64 + excess * GREEN_SECS_PER_EXCESS_VEHICLE;
+    (green.round() as u32).min(MAX_GREEN_TIME)
 }
 
 fn current_time_secs() -> u64 {
@@ -51,22 +170,29 @@ async fn main() {
 
     println!("Flow Analyzer waiting for simulation updates...");
 
+    let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+
     while let Some(delivery_result) = consumer.next().await {
         if let Ok(delivery) = delivery_result {
-            let data = delivery.data;
-            if let Ok(update) = serde_json::from_slice::<TrafficUpdate>(&data) {
+            if let Ok(update) = serde_json::from_slice::<TrafficUpdate>(&delivery.data) {
                 println!("Received update: {:?}", update);
-                if update.vehicle_count >= 4 {
+                let now = current_time_secs();
+                detector.record(update.lane_id, update.vehicle_count, now);
+                if let Some(new_green_time) = detector.evaluate(update.lane_id, now) {
                     let rec = Recommendation {
                         lane_id: update.lane_id,
-                        new_green_time: 40,
-                        timestamp: current_time_secs(),
+                        new_green_time,
+                        timestamp: now,
                     };
                     publish_message(&channel, "recommendations", "", &rec).await;
                     let log = LogEvent {
                         source: "FlowAnalyzer".into(),
-                        message: format!("Published recommendation for lane {}", update.lane_id),
-                        timestamp: current_time_secs(),
+                        message: format!("Published recommendation for lane {} (avg {:.1} vehicles over {}s, trend {:+.1}/min, green {}s)",
+                                         update.lane_id, detector.average(update.lane_id).unwrap_or(0.0), WINDOW_SECS,
+                                         detector.trend(update.lane_id).unwrap_or(0.0), new_green_time),
+                        timestamp: now,
+                        level: Level::Info,
+                        kind: (),
                     };
                     publish_message(&channel, "logs", "", &log).await;
                 }
@@ -1,13 +1,18 @@
 // mq.rs
+//
+// Every bin includes this module, and each uses a different part of it.
+#![allow(dead_code)]
+
 use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties, Channel, ExchangeKind, BasicProperties};
-use tokio_amqp::*;
 use serde::Serialize;
-use serde_json;
 
 /// Create a RabbitMQ channel using a connection string from the AMQP_ADDR environment variable.
 pub async fn create_channel() -> Channel {
     let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-    let connection = Connection::connect(&addr, ConnectionProperties::default().with_tokio())
+    let properties = ConnectionProperties::default().with_executor(tokio_executor_trait::Tokio::current());
+    #[cfg(unix)]
+    let properties = properties.with_reactor(tokio_reactor_trait::Tokio);
+    let connection = Connection::connect(&addr, properties)
         .await
         .expect("Failed to connect to RabbitMQ");
     connection.create_channel().await.expect("Failed to create channel")
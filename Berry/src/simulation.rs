@@ -1,53 +1,49 @@
 // simulation.rs
-use tokio;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use std::collections::HashMap;
-use tokio::time::{sleep, Duration};
-use serde::{Serialize, Deserialize};
+use tokio::time::{sleep, Duration, Instant};
 use rand::Rng;
 use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 
 mod mq;
 use mq::{create_channel, publish_message, declare_exchange};
-use lanes::{load_lanes, Lane, LaneCategory};
+use rts_core::lanes::{load_lanes, Lane, LaneCategory};
+use rts_core::messages::TrafficUpdate;
+use rts_core::demand::{Arrivals, Demand};
+use rts_core::export::{lane_samples, Export, Journey, LaneSample};
 
-#[derive(Serialize, Deserialize)]
-pub struct TrafficUpdate {
-    pub lane_id: u32,
-    pub vehicle_count: u32,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
-}
+/// Berry's log events are plain text, so they carry no typed payload.
+pub type LogEvent = rts_core::messages::LogEvent<()>;
+use rts_core::messages::Level;
 
 fn current_time_secs() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
+/// Seconds between two samples of the lane counts for `--export`.
+const EXPORT_SAMPLE_SECS: u64 = 1;
+
 /// Simulate a single car journey.
 /// For brevity, we simulate a car by updating one lane’s count.
+/// Berry has no lights, so the journey returned never waits.
 async fn simulate_car(
     car_id: u32,
     entry_lanes: Vec<Lane>,
     exit_lanes: Vec<Lane>,
     sim_event: Arc<Mutex<HashMap<u32, u32>>>,
     channel: &lapin::Channel,
-) {
+) -> Journey {
+    let start = Instant::now();
     // Use a seeded RNG (ChaCha8Rng is Send)
-    let mut rng = ChaCha8Rng::from_entropy();
-    let speed: f64 = rng.gen_range(70.0..=90.0);
-    let input_lane = entry_lanes[rng.gen_range(0..entry_lanes.len())].clone();
-    let mut exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
+    let mut rng = ChaCha8Rng::from_os_rng();
+    let speed: f64 = rng.random_range(70.0..=90.0);
+    let input_lane = entry_lanes[rng.random_range(0..entry_lanes.len())].clone();
+    let mut exit_lane = exit_lanes[rng.random_range(0..exit_lanes.len())].clone();
     while exit_lane.id == input_lane.id {
-        exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
+        exit_lane = exit_lanes[rng.random_range(0..exit_lanes.len())].clone();
     }
 
     // Log car generation.
@@ -55,6 +51,8 @@ async fn simulate_car(
         source: format!("Car-{}", car_id),
         message: format!("Car generated: input lane {} exit lane {}", input_lane.id, exit_lane.id),
         timestamp: current_time_secs(),
+        level: Level::Info,
+        kind: (),
     };
     publish_message(channel, "logs", "", &log).await;
 
@@ -97,8 +95,13 @@ async fn simulate_car(
         source: format!("Car-{}", car_id),
         message: "Completed journey".into(),
         timestamp: current_time_secs(),
+        level: Level::Info,
+        kind: (),
     };
     publish_message(channel, "logs", "", &log2).await;
+
+    let total = start.elapsed().as_secs_f64();
+    Journey { car_id, entry_lane: input_lane.id, exit_lane: exit_lane.id, wait: 0.0, drive: total, total }
 }
 
 #[tokio::main]
@@ -122,21 +125,57 @@ async fn main() {
         .cloned()
         .collect();
 
-    // Spawn simulation tasks for 30 cars.
+    // Spawn a simulation task per car: `--cars` or SIM_CARS of them, 30 by
+    // default, all at once or at `--arrival-rate` (SIM_ARRIVAL_RATE) per minute.
+    let args: Vec<String> = std::env::args().collect();
+    let demand = Demand::from_args(&args);
+    // `--export <path>` writes the cars' journeys and the lane counts, sampled
+    // every EXPORT_SAMPLE_SECS, when the run ends (see `rts_core::export`).
+    let export = Export::from_args(&args);
+    let lane_series: Arc<Mutex<Vec<LaneSample>>> = Arc::default();
+    let sampler = export.as_ref().map(|_| {
+        let sim_event_clone = Arc::clone(&sim_event);
+        let lane_series_clone = Arc::clone(&lane_series);
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(EXPORT_SAMPLE_SECS)).await;
+                let samples = lane_samples(current_time_secs(), &*sim_event_clone.lock().await);
+                lane_series_clone.lock().await.extend(samples);
+            }
+        })
+    });
     let mut handles = vec![];
-    for car_id in 1..=30 {
+    let mut rng = rand::rng();
+    for car_id in 1..=demand.cars {
+        if car_id > 1 && demand.arrivals != Arrivals::AllAtOnce {
+            sleep(demand.gap(rng.random())).await;
+        }
         let entry_clone = entry_lanes.clone();
         let exit_clone = exit_lanes.clone();
         let sim_event_clone = Arc::clone(&sim_event);
         let channel_clone = channel.clone();
         let handle = tokio::spawn(async move {
-            simulate_car(car_id, entry_clone, exit_clone, sim_event_clone, &channel_clone).await;
+            simulate_car(car_id, entry_clone, exit_clone, sim_event_clone, &channel_clone).await
         });
         handles.push(handle);
     }
 
+    let mut journeys = Vec::new();
     for handle in handles {
-        handle.await.unwrap();
+        journeys.push(handle.await.unwrap());
+    }
+    if let Some(sampler) = sampler {
+        sampler.abort();
+    }
+    if let Some(export) = &export {
+        let lane_series = lane_series.lock().await;
+        match export.write(&journeys, &lane_series) {
+            Ok((cars, lanes)) => println!(
+                "Exported {} journeys to {} and {} lane counts to {}",
+                journeys.len(), cars.display(), lane_series.len(), lanes.display()
+            ),
+            Err(e) => eprintln!("Failed to export metrics: {}", e),
+        }
     }
 
     // Log simulation completion.
@@ -144,6 +183,8 @@ async fn main() {
         source: "Simulation".into(),
         message: "Simulation complete".into(),
         timestamp: current_time_secs(),
+        level: Level::Info,
+        kind: (),
     };
     publish_message(&channel, "logs", "", &log_complete).await;
 }
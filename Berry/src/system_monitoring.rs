@@ -1,18 +1,12 @@
 // system_monitoring.rs
-use tokio;
 use lapin::{options::*, types::FieldTable};
 use futures_util::stream::StreamExt;
-use serde::{Serialize, Deserialize};
 
 mod mq;
 use mq::{create_channel, declare_exchange};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
-}
+/// Berry's log events are plain text, so they carry no typed payload.
+pub type LogEvent = rts_core::messages::LogEvent<()>;
 
 #[tokio::main]
 async fn main() {
@@ -30,14 +24,12 @@ async fn main() {
 
     println!("System Monitoring waiting for log messages...");
 
-    while let Some(delivery) = consumer.next().await {
-        if let Ok((channel, delivery)) = delivery {
-            let data = delivery.data;
-            if let Ok(log) = serde_json::from_slice::<LogEvent>(&data) {
+    while let Some(delivery_result) = consumer.next().await {
+        if let Ok(delivery) = delivery_result {
+            if let Ok(log) = serde_json::from_slice::<LogEvent>(&delivery.data) {
                 println!("[Time: {}] {}: {}", log.timestamp, log.source, log.message);
             }
-            channel.basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                .await.expect("Ack failed");
+            delivery.ack(BasicAckOptions::default()).await.expect("Ack failed");
         }
     }
 }
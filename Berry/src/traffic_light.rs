@@ -1,33 +1,20 @@
 // traffic_light.rs
-use tokio;
 use lapin::{options::*, types::FieldTable};
 use futures_util::stream::StreamExt;
-use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 mod mq;
 use mq::{create_channel, declare_exchange, publish_message};
+use rts_core::messages::Recommendation;
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-pub enum LightColor {
-    Red,
-    Green,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Recommendation {
-    pub lane_id: u32,
-    pub new_green_time: u32,
-    pub timestamp: u64,
-}
+pub use rts_core::messages::LightColor;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
-}
+/// Berry's log events are plain text, so they carry no typed payload.
+pub type LogEvent = rts_core::messages::LogEvent<()>;
+use rts_core::messages::Level;
 
 fn current_time_secs() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -58,19 +45,22 @@ async fn main() {
     let mut consumer = channel.basic_consume(queue.name().as_str(), "traffic_light", BasicConsumeOptions::default(), FieldTable::default())
         .await.expect("Failed to create consumer");
 
-    // Initialize local traffic lights state.
-    let mut traffic_lights = initialize_traffic_lights();
+    // Initialize local traffic lights state, shared with the cycling task.
+    let traffic_lights = Arc::new(Mutex::new(initialize_traffic_lights()));
 
     // Spawn a periodic task that cycles local traffic lights.
     let channel_for_cycle = channel.clone();
+    let lights_for_cycle = Arc::clone(&traffic_lights);
     tokio::spawn(async move {
         loop {
-            for (lane, color) in traffic_lights.iter_mut() {
+            for (lane, color) in lights_for_cycle.lock().await.iter_mut() {
                 *color = if *color == LightColor::Red { LightColor::Green } else { LightColor::Red };
                 let log = LogEvent {
                     source: format!("TrafficLight-{}", lane),
                     message: format!("Cycled to {:?}", color),
                     timestamp: current_time_secs(),
+                    level: Level::Info,
+                    kind: (),
                 };
                 publish_message(&channel_for_cycle, "logs", "", &log).await;
             }
@@ -82,15 +72,16 @@ async fn main() {
 
     while let Some(delivery_result) = consumer.next().await {
         if let Ok(delivery) = delivery_result {
-            let data = delivery.data;
-            if let Ok(rec) = serde_json::from_slice::<Recommendation>(&data) {
+            if let Ok(rec) = serde_json::from_slice::<Recommendation>(&delivery.data) {
                 println!("Received recommendation: {:?}", rec);
-                if let Some(light) = traffic_lights.get_mut(&rec.lane_id) {
+                if let Some(light) = traffic_lights.lock().await.get_mut(&rec.lane_id) {
                     *light = LightColor::Green;
                     let log = LogEvent {
                         source: format!("TrafficLight-{}", rec.lane_id),
                         message: "Set to Green per recommendation".into(),
                         timestamp: current_time_secs(),
+                        level: Level::Info,
+                        kind: (),
                     };
                     publish_message(&channel, "logs", "", &log).await;
                 }
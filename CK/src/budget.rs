@@ -0,0 +1,268 @@
+// budget.rs
+//
+// Lightweight time budget accounting: answers "where does the runtime go" by
+// accumulating wall time per category (routing, lock waits, sleeps, transport,
+// serialization) for each component. Timers are coarse-grained and accumulate into a
+// thread-local table; threads fold their table into the global registry with
+// flush(), and report() aggregates everything for the simulation summary.
+//
+// Accounting is off by default. Set RTS_TIME_BUDGET=1 to enable it; when
+// disabled every timer is a no-op that never reads the clock.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Categories of time tracked per component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Routing,
+    LockWait,
+    Sleep,
+    Transport,
+    Serialization,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Routing,
+        Category::LockWait,
+        Category::Sleep,
+        Category::Transport,
+        Category::Serialization,
+    ];
+
+    fn index(self) -> usize {
+        match self {
+            Category::Routing => 0,
+            Category::LockWait => 1,
+            Category::Sleep => 2,
+            Category::Transport => 3,
+            Category::Serialization => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Routing => "routing",
+            Category::LockWait => "lock_wait",
+            Category::Sleep => "sleep",
+            Category::Transport => "transport",
+            Category::Serialization => "serialization",
+        }
+    }
+}
+
+/// Only one in LOCK_SAMPLE_RATE lock acquisitions is timed; the sample is
+/// scaled back up so totals stay comparable with the other categories.
+const LOCK_SAMPLE_RATE: u32 = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static LOCAL: RefCell<[Duration; 5]> = const { RefCell::new([Duration::ZERO; 5]) };
+    static LOCAL_WALL: Cell<Option<Instant>> = const { Cell::new(None) };
+    static LOCK_COUNTER: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Accumulated totals for a single component.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ComponentBudget {
+    pub component: String,
+    pub totals: [Duration; 5],
+    pub wall: Duration,
+}
+
+impl ComponentBudget {
+    pub fn get(&self, category: Category) -> Duration {
+        self.totals[category.index()]
+    }
+
+    /// Sum of all tracked categories.
+    pub fn accounted(&self) -> Duration {
+        self.totals.iter().sum()
+    }
+}
+
+fn registry() -> &'static Mutex<HashMap<String, ComponentBudget>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, ComponentBudget>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Enables accounting if RTS_TIME_BUDGET is set to a truthy value.
+pub fn init_from_env() {
+    let enabled = matches!(
+        std::env::var("RTS_TIME_BUDGET").as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    );
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A timer that adds its elapsed time to the current thread's table on drop.
+pub struct ScopedTimer {
+    category: Category,
+    start: Option<Instant>,
+    scale: u32,
+}
+
+impl Drop for ScopedTimer {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            let elapsed = start.elapsed() * self.scale;
+            LOCAL.with(|local| local.borrow_mut()[self.category.index()] += elapsed);
+        }
+    }
+}
+
+/// Starts timing `category` for the current scope.
+pub fn time(category: Category) -> ScopedTimer {
+    if !is_enabled() {
+        return ScopedTimer { category, start: None, scale: 1 };
+    }
+    LOCAL_WALL.with(|wall| {
+        if wall.get().is_none() {
+            wall.set(Some(Instant::now()));
+        }
+    });
+    ScopedTimer { category, start: Some(Instant::now()), scale: 1 }
+}
+
+/// Starts a sampled lock-wait timer. Most calls return a no-op timer.
+pub fn time_lock() -> ScopedTimer {
+    if !is_enabled() {
+        return ScopedTimer { category: Category::LockWait, start: None, scale: 1 };
+    }
+    let sampled = LOCK_COUNTER.with(|counter| {
+        let n = counter.get().wrapping_add(1);
+        counter.set(n);
+        n % LOCK_SAMPLE_RATE == 0
+    });
+    if sampled {
+        ScopedTimer { category: Category::LockWait, start: Some(Instant::now()), scale: LOCK_SAMPLE_RATE }
+    } else {
+        ScopedTimer { category: Category::LockWait, start: None, scale: 1 }
+    }
+}
+
+/// Folds this thread's accumulated totals into the registry under `component`.
+/// Threads that live for the whole run should call this periodically. A
+/// thread that never started a timer adds nothing.
+pub fn flush(component: &str) {
+    if !is_enabled() || LOCAL_WALL.with(|wall| wall.get().is_none()) {
+        return;
+    }
+    let totals = LOCAL.with(|local| std::mem::replace(&mut *local.borrow_mut(), [Duration::ZERO; 5]));
+    let wall = LOCAL_WALL.with(|wall| {
+        let now = Instant::now();
+        let started = wall.replace(Some(now));
+        started.map(|s| now - s).unwrap_or_default()
+    });
+    let mut registry = registry().lock().unwrap();
+    let entry = registry.entry(component.to_string()).or_insert_with(|| ComponentBudget {
+        component: component.to_string(),
+        ..Default::default()
+    });
+    for (slot, value) in entry.totals.iter_mut().zip(totals.iter()) {
+        *slot += *value;
+    }
+    entry.wall += wall;
+}
+
+/// Returns the aggregated budget of every component, sorted by name.
+pub fn report() -> Vec<ComponentBudget> {
+    let registry = registry().lock().unwrap();
+    let mut budgets: Vec<ComponentBudget> = registry.values().cloned().collect();
+    budgets.sort_by(|a, b| a.component.cmp(&b.component));
+    budgets
+}
+
+/// Formats a component's budget as a single log line.
+pub fn format_budget(budget: &ComponentBudget) -> String {
+    let wall = budget.wall.as_secs_f64();
+    let parts: Vec<String> = Category::ALL
+        .iter()
+        .map(|&c| {
+            let secs = budget.get(c).as_secs_f64();
+            let share = if wall > 0.0 { secs / wall * 100.0 } else { 0.0 };
+            format!("{}={:.2}s ({:.0}%)", c.name(), secs, share)
+        })
+        .collect();
+    format!("{} [wall={:.2}s, accounted={:.2}s] {}",
+            budget.component, wall, budget.accounted().as_secs_f64(), parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Burns the CPU for `duration` without sleeping.
+    fn spin(duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Turns accounting on until dropped, then puts the flag back as it
+    /// was, so other tests in the process keep the default.
+    struct Enabled(bool);
+
+    impl Enabled {
+        fn on() -> Enabled {
+            Enabled(ENABLED.swap(true, Ordering::Relaxed))
+        }
+    }
+
+    impl Drop for Enabled {
+        fn drop(&mut self) {
+            ENABLED.store(self.0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn categories_add_up_to_wall_time() {
+        let _enabled = Enabled::on();
+        // A thread of its own, so its table starts empty.
+        std::thread::spawn(|| {
+            {
+                let _t = time(Category::Routing);
+                spin(Duration::from_millis(20));
+            }
+            {
+                let _t = time(Category::Sleep);
+                std::thread::sleep(Duration::from_millis(30));
+            }
+            {
+                let _t = time(Category::Serialization);
+                serde_json::to_string(&vec![1.5_f64; 1000]).unwrap();
+                spin(Duration::from_millis(10));
+            }
+            {
+                let _t = time(Category::Transport);
+                spin(Duration::from_millis(10));
+            }
+            flush("categories_add_up");
+        })
+        .join()
+        .unwrap();
+
+        let budget = report().into_iter().find(|budget| budget.component == "categories_add_up").unwrap();
+        assert!(budget.get(Category::Routing) >= Duration::from_millis(20));
+        assert!(budget.get(Category::Sleep) >= Duration::from_millis(30));
+        assert!(budget.get(Category::Serialization) >= Duration::from_millis(10));
+        assert!(budget.get(Category::Transport) >= Duration::from_millis(10));
+        // Only the gaps between the timers go unaccounted: a small share of
+        // the wall time however slow the machine.
+        let unaccounted = budget.wall.saturating_sub(budget.accounted());
+        assert!(budget.accounted() <= budget.wall, "{}", format_budget(&budget));
+        assert!(unaccounted < budget.wall / 10, "{}", format_budget(&budget));
+    }
+}
@@ -0,0 +1,265 @@
+// coordination.rs
+//
+// Green waves along corridors. RTS_CORRIDORS lists chains of junctions, each
+// one lane on from the last, e.g. `3-4-5,7-8-9`. Every junction of a corridor
+// runs the same cycle, the longest baseline cycle among them; the others hold
+// their coordinated phase green for the difference. The coordinated phase of
+// a junction is the one serving the corridor lane from the junction before
+// it, or, at the first junction, the approach that carries straight on into
+// the corridor. Each junction starts that phase an offset after the one
+// before it: the time a car at progression speed takes from passing one
+// light to reaching the next. A platoon released by one green then reaches
+// the next light as it turns green.
+//
+// A junction that falls out of step (a recommendation held a phase longer,
+// say) waits at all-red for its next slot before serving the coordinated
+// phase again. For the summary, every vehicle arriving at a coordinated
+// light after the first junction is counted, and whether it stopped; with
+// uncoordinated arrivals, the share that stop would be the share of the
+// cycle the light is not green, which gives the stops the wave saved.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use rts_core::clock::SimClock;
+use rts_core::lanes::{Lane, Movement};
+use rts_core::network::Network;
+use rts_core::phase_plan::{build_phase_plan, movement};
+
+use crate::junction_box::CROSSING_SECS;
+use crate::signal_timing::JunctionTimings;
+use crate::simulation::CarMetrics;
+use crate::summary::CorridorStats;
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use crate::vehicle::{VehicleKind, STOPPED_SECS};
+
+/// How late a junction may reach its coordinated phase and still serve it
+/// rather than wait for the next slot.
+const SLOT_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// How one junction keeps in step with its corridor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoordinatedJunction {
+    /// Index of the coordinated phase in the junction's phase plan.
+    pub phase: usize,
+    /// When the coordinated phase starts within every cycle, counted from
+    /// the start of the run.
+    pub offset: Duration,
+    /// The corridor's common cycle.
+    pub cycle: Duration,
+    /// Extra green for the coordinated phase, bringing the junction's own
+    /// cycle up to the common one.
+    pub slack: Duration,
+}
+
+impl CoordinatedJunction {
+    /// How long to hold the junction red before serving its coordinated
+    /// phase, `since` the start of the run.
+    pub fn wait_for_slot(&self, since: Duration) -> Duration {
+        if since < self.offset {
+            return self.offset - since;
+        }
+        let cycle = self.cycle.as_nanos();
+        let late = (since - self.offset).as_nanos() % cycle;
+        if late <= SLOT_TOLERANCE.as_nanos() {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos((cycle - late) as u64)
+        }
+    }
+}
+
+/// A corridor and the offsets chosen for it.
+#[derive(Debug, Clone)]
+struct Corridor {
+    junctions: Vec<u32>,
+    /// Coordinated approach lane of each junction.
+    lanes: Vec<u32>,
+    members: Vec<CoordinatedJunction>,
+    /// Share of the cycle each junction's coordinated phase is green.
+    green_shares: Vec<f64>,
+}
+
+/// The coordinated corridors of a run.
+#[derive(Debug, Clone, Default)]
+pub struct Coordination {
+    corridors: Vec<Corridor>,
+}
+
+/// Parses a list of corridors such as `3-4-5,7-8-9`.
+pub fn parse_corridors(spec: &str) -> Result<Vec<Vec<u32>>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|corridor| !corridor.is_empty())
+        .map(|corridor| {
+            let junctions = corridor
+                .split('-')
+                .map(|id| id.trim().parse().map_err(|_| format!("invalid junction '{}'", id.trim())))
+                .collect::<Result<Vec<u32>, String>>()?;
+            if junctions.len() < 2 {
+                return Err(format!("corridor '{}' needs at least two junctions", corridor));
+            }
+            Ok(junctions)
+        })
+        .collect()
+}
+
+/// Speed platoons are assumed to travel at: the middle of a car's range.
+fn progression_speed() -> f64 {
+    let range = VehicleKind::Car.speed_range();
+    (range.start() + range.end()) / 2.0
+}
+
+impl Coordination {
+    /// Offsets for every corridor in RTS_CORRIDORS; none, with a warning, if
+    /// it is invalid or names junctions that are not a lane apart.
+    pub fn from_env(lanes: &[Lane], network: &Network, timings: &JunctionTimings) -> Coordination {
+        let Ok(spec) = std::env::var("RTS_CORRIDORS") else {
+            return Coordination::default();
+        };
+        let corridors = parse_corridors(&spec).and_then(|corridors| {
+            corridors.iter().map(|junctions| Corridor::new(junctions, lanes, network, timings)).collect()
+        });
+        match corridors {
+            Ok(corridors) => Coordination { corridors },
+            Err(e) => {
+                eprintln!("Ignoring RTS_CORRIDORS: {}", e);
+                Coordination::default()
+            }
+        }
+    }
+
+    /// How `junction` keeps in step, if it is on a corridor. A junction on
+    /// several follows the first.
+    pub fn junction(&self, junction: u32) -> Option<CoordinatedJunction> {
+        self.corridors.iter().find_map(|corridor| {
+            let index = corridor.junctions.iter().position(|&id| id == junction)?;
+            Some(corridor.members[index])
+        })
+    }
+
+    /// Prints every corridor's cycle and offsets and logs them for
+    /// monitoring.
+    pub fn publish(&self, log_tx: &Sender<LogEvent>, clock: &SimClock) {
+        for corridor in &self.corridors {
+            let offsets: Vec<String> =
+                corridor.members.iter().map(|member| format!("{:.0}s", member.offset.as_secs_f64())).collect();
+            let message = format!(
+                "Corridor {}: {:.0}s cycle, offsets {}",
+                corridor.name(),
+                corridor.members[0].cycle.as_secs_f64(),
+                offsets.join("/")
+            );
+            println!("{}", message);
+            log_tx.send(LogEvent {
+                source: "Coordination".to_string(),
+                message,
+                timestamp: clock.now_secs(),
+                level: Level::Info,
+                kind: EventKind::Generic,
+            }).ok();
+        }
+    }
+
+    /// Arrivals and stops at the coordinated lights after each corridor's
+    /// first junction, over the visits in `metrics`.
+    pub fn stats(&self, metrics: &[CarMetrics]) -> Vec<CorridorStats> {
+        self.corridors
+            .iter()
+            .map(|corridor| {
+                let shares: HashMap<u32, f64> =
+                    corridor.lanes.iter().copied().zip(corridor.green_shares.iter().copied()).skip(1).collect();
+                let (mut arrivals, mut stops, mut expected_stops) = (0, 0, 0.0);
+                for visit in metrics.iter().flat_map(|car| &car.lanes) {
+                    let Some(share) = shares.get(&visit.lane_id) else {
+                        continue;
+                    };
+                    arrivals += 1;
+                    if visit.wait_time >= STOPPED_SECS {
+                        stops += 1;
+                    }
+                    expected_stops += 1.0 - share;
+                }
+                CorridorStats {
+                    junctions: corridor.junctions.clone(),
+                    cycle_secs: corridor.members[0].cycle.as_secs_f64(),
+                    offsets_secs: corridor.members.iter().map(|member| member.offset.as_secs_f64()).collect(),
+                    arrivals,
+                    stops,
+                    expected_stops,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Corridor {
+    fn new(junctions: &[u32], lanes: &[Lane], network: &Network, timings: &JunctionTimings) -> Result<Corridor, String> {
+        let between = |from: u32, to: u32| {
+            lanes
+                .iter()
+                .find(|lane| lane.start_intersection == from && lane.end_intersection == to)
+                .ok_or_else(|| format!("no lane from junction {} to junction {}", from, to))
+        };
+        // The first junction's approach that carries straight on into the
+        // corridor, or any that can turn into it.
+        let first_exit = between(junctions[0], junctions[1])?;
+        let approaches: Vec<&Lane> = lanes.iter().filter(|lane| lane.end_intersection == junctions[0]).collect();
+        let first = approaches
+            .iter()
+            .find(|lane| movement(junctions[0], lane, first_exit, lanes, network) == Some(Movement::Straight))
+            .or_else(|| approaches.iter().find(|lane| movement(junctions[0], lane, first_exit, lanes, network).is_some()))
+            .ok_or_else(|| format!("no approach to junction {} leads into the corridor", junctions[0]))?;
+        let mut corridor_lanes = vec![*first];
+        for pair in junctions.windows(2) {
+            corridor_lanes.push(between(pair[0], pair[1])?);
+        }
+
+        // Each junction's own cycle, and the phase serving its corridor lane.
+        let mut cycles = Vec::new();
+        let mut phases = Vec::new();
+        for (&junction, lane) in junctions.iter().zip(&corridor_lanes) {
+            let plan = build_phase_plan(junction, lanes, network);
+            let timing = timings.for_junction(junction);
+            cycles.push((timing.green + timing.amber + timing.all_red) * plan.len() as u32);
+            let phase = plan
+                .iter()
+                .position(|phase| phase.lanes.contains(&lane.id))
+                .ok_or_else(|| format!("lane {} has no phase at junction {}", lane.id, junction))?;
+            phases.push((phase, timing.green));
+        }
+        let cycle = cycles.iter().copied().max().unwrap_or_default();
+        if cycle.is_zero() {
+            return Err(format!("corridor {:?} has no signal cycle", junctions));
+        }
+
+        let speed = progression_speed();
+        let mut offset = 0.0;
+        let mut members = Vec::new();
+        let mut green_shares = Vec::new();
+        for (index, (&(phase, green), own_cycle)) in phases.iter().zip(&cycles).enumerate() {
+            if index > 0 {
+                offset += CROSSING_SECS + corridor_lanes[index - 1].length / speed;
+            }
+            let slack = cycle - *own_cycle;
+            members.push(CoordinatedJunction {
+                phase,
+                offset: Duration::from_secs_f64(offset % cycle.as_secs_f64()),
+                cycle,
+                slack,
+            });
+            green_shares.push((green + slack).as_secs_f64() / cycle.as_secs_f64());
+        }
+        Ok(Corridor {
+            junctions: junctions.to_vec(),
+            lanes: corridor_lanes.iter().map(|lane| lane.id).collect(),
+            members,
+            green_shares,
+        })
+    }
+
+    fn name(&self) -> String {
+        self.junctions.iter().map(u32::to_string).collect::<Vec<_>>().join("-")
+    }
+}
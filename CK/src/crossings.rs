@@ -0,0 +1,188 @@
+// crossings.rs
+//
+// Pedestrian crossings. Junctions listed in RTS_CROSSINGS get crossings on
+// some of their sides, and every `every` signal cycles their controller runs
+// an all-red walk phase of `walk` seconds. Pedestrians are modelled only as
+// counts: each simulated minute the simulation draws a Poisson arrival count
+// per junction from its configured rate and sends the counts to the flow
+// analyzer as a PedestrianUpdate, which may lengthen or skip the next walks.
+//
+// The spec lists `junction=sides/arrivals-per-minute` entries plus optional
+// `every` and `walk` keys, e.g. `6=NS/6,7=NESW/4,every=3,walk=8`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Side of a junction a crossing spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Crossing {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Crossing {
+    fn parse(side: char) -> Result<Crossing, String> {
+        match side.to_ascii_uppercase() {
+            'N' => Ok(Crossing::North),
+            'E' => Ok(Crossing::East),
+            'S' => Ok(Crossing::South),
+            'W' => Ok(Crossing::West),
+            other => Err(format!("unknown side '{}' (expected N, E, S or W)", other)),
+        }
+    }
+}
+
+impl fmt::Display for Crossing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Crossing::North => "north",
+            Crossing::East => "east",
+            Crossing::South => "south",
+            Crossing::West => "west",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The crossings of one junction and how many pedestrians arrive at them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JunctionCrossings {
+    /// Sorted, without duplicates.
+    pub crossings: Vec<Crossing>,
+    /// Mean pedestrian arrivals per minute over all of the junction's crossings.
+    pub arrivals_per_minute: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossingConfig {
+    junctions: BTreeMap<u32, JunctionCrossings>,
+    /// Signal cycles between two walk phases at a junction.
+    pub every: u32,
+    /// How long a walk phase holds every vehicle lane red.
+    pub walk: Duration,
+}
+
+impl Default for CrossingConfig {
+    /// No crossings anywhere.
+    fn default() -> Self {
+        CrossingConfig { junctions: BTreeMap::new(), every: 3, walk: Duration::from_secs(8) }
+    }
+}
+
+impl CrossingConfig {
+    pub fn is_empty(&self) -> bool {
+        self.junctions.is_empty()
+    }
+
+    pub fn for_junction(&self, junction: u32) -> Option<&JunctionCrossings> {
+        self.junctions.get(&junction)
+    }
+
+    /// Junctions with crossings, in ascending order.
+    pub fn junctions(&self) -> impl Iterator<Item = (u32, &JunctionCrossings)> {
+        self.junctions.iter().map(|(&junction, crossings)| (junction, crossings))
+    }
+
+    /// Parses a list such as `6=NS/6,7=NESW/4,every=3,walk=8`.
+    pub fn parse(spec: &str) -> Result<CrossingConfig, String> {
+        let mut config = CrossingConfig::default();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", entry))?;
+            let value = value.trim();
+            match key.trim() {
+                "every" => {
+                    config.every = value.parse().map_err(|_| format!("invalid cycle count '{}'", value))?;
+                    if config.every == 0 {
+                        return Err("every must be positive".to_string());
+                    }
+                }
+                "walk" => {
+                    let secs: u64 = value.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+                    if secs == 0 {
+                        return Err("walk must be positive".to_string());
+                    }
+                    config.walk = Duration::from_secs(secs);
+                }
+                id => {
+                    let junction = id.parse().map_err(|_| format!("invalid junction '{}'", id))?;
+                    config.junctions.insert(junction, JunctionCrossings::parse(value)?);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Crossings from RTS_CROSSINGS; none (with a warning if it is invalid)
+    /// otherwise.
+    pub fn from_env() -> CrossingConfig {
+        match std::env::var("RTS_CROSSINGS") {
+            Ok(spec) => CrossingConfig::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_CROSSINGS: {}", e);
+                CrossingConfig::default()
+            }),
+            Err(_) => CrossingConfig::default(),
+        }
+    }
+}
+
+impl JunctionCrossings {
+    /// Parses `sides/arrivals-per-minute`, e.g. `NS/6`.
+    fn parse(spec: &str) -> Result<JunctionCrossings, String> {
+        let (sides, rate) = spec
+            .split_once('/')
+            .ok_or_else(|| format!("expected sides/arrivals-per-minute, got '{}'", spec))?;
+        let mut crossings = sides.trim().chars().map(Crossing::parse).collect::<Result<Vec<_>, _>>()?;
+        crossings.sort();
+        crossings.dedup();
+        if crossings.is_empty() {
+            return Err(format!("no crossings in '{}'", spec));
+        }
+        let arrivals_per_minute: f64 = rate
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid arrival rate '{}'", rate.trim()))?;
+        if !arrivals_per_minute.is_finite() || arrivals_per_minute < 0.0 {
+            return Err(format!("arrival rate must be zero or more in '{}'", spec));
+        }
+        Ok(JunctionCrossings { crossings, arrivals_per_minute })
+    }
+}
+
+/// Pedestrians that arrived at each junction with crossings over the last
+/// simulated minute, sent by the simulation to the flow analyzer.
+#[derive(Debug, Clone)]
+pub struct PedestrianUpdate {
+    pub arrivals: HashMap<u32, u32>,
+}
+
+/// How often the simulation draws pedestrian arrivals, in simulated time.
+pub const ARRIVAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Draws one interval's arrivals for every junction with crossings.
+pub fn draw_arrivals(config: &CrossingConfig, rng: &mut impl Rng) -> HashMap<u32, u32> {
+    config
+        .junctions()
+        .map(|(junction, crossings)| (junction, poisson(crossings.arrivals_per_minute, rng)))
+        .collect()
+}
+
+/// Samples a Poisson-distributed count with the given mean (Knuth's method,
+/// which is plenty for the handful of pedestrians per minute modelled here).
+fn poisson(mean: f64, rng: &mut impl Rng) -> u32 {
+    let limit = (-mean).exp();
+    let mut count = 0;
+    let mut product: f64 = rng.random();
+    while product > limit {
+        count += 1;
+        product *= rng.random::<f64>();
+    }
+    count
+}
@@ -0,0 +1,84 @@
+// csv_sink.rs
+//
+// Writes structured monitoring records to a CSV file for offline analysis.
+// Car completions and traffic light phase changes share one file; the
+// `record` column tells them apart and columns that don't apply are empty.
+// Lane lists are joined with ';' so they stay in a single column.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::system_monitoring::EventKind;
+
+const HEADER: &str = "record,timestamp,car_id,entry_lane,exit_lane,route_length,\
+wait_time,drive_time,total_time,junction,phase,green_lanes,red_lanes";
+
+/// Buffered CSV writer for monitoring records.
+pub struct CsvSink {
+    writer: BufWriter<File>,
+}
+
+impl CsvSink {
+    /// Creates (or truncates) the file at `path` and writes the header row.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        Ok(CsvSink { writer })
+    }
+
+    /// Writes one row per car completion or phase change; other events are skipped.
+    pub fn write_event(&mut self, timestamp: u64, kind: &EventKind) -> io::Result<()> {
+        match kind {
+            EventKind::Generic
+            | EventKind::VehicleGenerated { .. }
+            | EventKind::VehicleGenerationFailed { .. }
+            | EventKind::CarProgress { .. }
+            | EventKind::RightTurnOnRed { .. }
+            | EventKind::LaneWait { .. }
+            | EventKind::PhaseDecision { .. }
+            | EventKind::PedestrianPhase { .. }
+            | EventKind::Recommendation { .. }
+            | EventKind::RerouteAdvisory { .. }
+            | EventKind::ScenarioEvent { .. }
+            | EventKind::MonitorOverloaded { .. }
+            | EventKind::RunStarted { .. }
+            | EventKind::Summary(_) => Ok(()),
+            EventKind::CarCompleted {
+                car_id,
+                entry_lane,
+                exit_lane,
+                route_length,
+                wait_time,
+                drive_time,
+                total_time,
+            } => writeln!(
+                self.writer,
+                "{},{},{},{},{},{:.2},{:.3},{:.3},{:.3},,,,",
+                kind.name(), timestamp, car_id, entry_lane, exit_lane, route_length, wait_time, drive_time, total_time
+            ),
+            EventKind::PhaseChange { junction, phase, green_lanes, red_lanes } => writeln!(
+                self.writer,
+                "{},{},,,,,,,,{},{},{},{}",
+                kind.name(), timestamp, junction, phase, join_ids(green_lanes), join_ids(red_lanes)
+            ),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn join_ids(ids: &[u32]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(";")
+}
+
+/// Resolves the CSV output path from `--csv <path>` on the command line,
+/// falling back to the RTS_CSV_PATH environment variable.
+pub fn csv_path_from(args: &[String]) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == "--csv")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("RTS_CSV_PATH").ok().filter(|p| !p.is_empty()))
+}
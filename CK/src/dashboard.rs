@@ -0,0 +1,299 @@
+// dashboard.rs
+//
+// Live terminal view of a run for `--dashboard`, in place of the
+// line-by-line event log. The monitor feeds every event to a Dashboard,
+// which keeps the current state built from the typed events: each
+// junction's active phase, the estimated position of every car on the grid
+// (see rts_core::progress), completed cars and their waits, and recent
+// warnings. The monitor redraws it every REFRESH by clearing the terminal
+// with plain ANSI escapes, or, with `--tui`, draws it full-screen as the
+// junction grid (see `tui`). For the grid it also keeps the color of every
+// light as of its junction's latest phase change, and the latest events for
+// the log pane.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::shutdown::ShutdownFlag;
+use crate::system_monitoring::{EventKind, LogEvent};
+use crate::traffic_light::LightColor;
+use rts_core::lanes::load_lanes;
+use rts_core::progress::PositionEstimator;
+
+/// How often the monitor redraws the dashboard, in real time.
+pub const REFRESH: Duration = Duration::from_secs(1);
+
+/// Clears the terminal and moves the cursor to the top left.
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Lanes shown in the occupancy table.
+const TOP_LANES: usize = 10;
+
+/// Cars shown in the position table.
+const SHOWN_CARS: usize = 10;
+
+/// Warnings kept for display, newest last.
+const RECENT_WARNINGS: usize = 5;
+
+/// Events kept for the log pane, newest last.
+const LOG_LINES: usize = 500;
+
+/// Where the dashboard is drawn.
+pub enum Screen {
+    /// Redrawn as text every REFRESH.
+    Text,
+    /// Full-screen, for `--tui`.
+    #[cfg(feature = "tui")]
+    Tui(Box<crate::tui::Tui>),
+}
+
+impl Screen {
+    /// How often the monitor redraws the dashboard.
+    pub fn refresh(&self) -> Duration {
+        match self {
+            Screen::Text => REFRESH,
+            #[cfg(feature = "tui")]
+            Screen::Tui(_) => crate::tui::REFRESH,
+        }
+    }
+
+    pub fn draw(&mut self, dashboard: &Dashboard, out: &mut impl Write) {
+        match self {
+            Screen::Text => {
+                write!(out, "{}{}", CLEAR_SCREEN, dashboard).ok();
+                out.flush().ok();
+            }
+            #[cfg(feature = "tui")]
+            Screen::Tui(tui) => {
+                if let Err(e) = tui.draw(dashboard) {
+                    eprintln!("Failed to draw the dashboard: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Leaves the final state on screen, as text, for the closing report to
+    /// follow.
+    pub fn finish(self, dashboard: &Dashboard, out: &mut impl Write) {
+        #[cfg(feature = "tui")]
+        if let Screen::Tui(tui) = self {
+            tui.finish();
+        }
+        write!(out, "{}{}", CLEAR_SCREEN, dashboard).ok();
+    }
+}
+
+/// The dashboard `--dashboard` or `--tui` asks for, if either is among the
+/// command-line arguments. Ctrl-C in the full-screen one raises
+/// `interrupted`; without the `tui` feature, or if the terminal cannot be
+/// taken over, `--tui` falls back to text.
+pub fn requested(args: &[String], interrupted: &ShutdownFlag) -> Option<(Dashboard, Screen)> {
+    if args.iter().any(|arg| arg == "--tui") {
+        return Some((Dashboard::default(), full_screen(interrupted)));
+    }
+    args.iter().any(|arg| arg == "--dashboard").then(|| (Dashboard::default(), Screen::Text))
+}
+
+#[cfg(feature = "tui")]
+fn full_screen(interrupted: &ShutdownFlag) -> Screen {
+    match crate::tui::Tui::start(std::sync::Arc::clone(interrupted)) {
+        Ok(tui) => Screen::Tui(Box::new(tui)),
+        Err(e) => {
+            eprintln!("Cannot draw the full-screen dashboard, showing it as text: {}", e);
+            Screen::Text
+        }
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn full_screen(_interrupted: &ShutdownFlag) -> Screen {
+    eprintln!("Full-screen dashboard requested but RTS was built without the tui feature; showing it as text");
+    Screen::Text
+}
+
+pub struct Dashboard {
+    /// Active phase and green lanes of every junction that has changed phase.
+    junctions: BTreeMap<u32, (usize, Vec<u32>)>,
+    /// Where the cars on the grid are.
+    positions: PositionEstimator,
+    completed: u32,
+    total_wait: f64,
+    warnings: VecDeque<String>,
+    /// Color of every light whose junction has changed phase.
+    lights: HashMap<u32, LightColor>,
+    /// Approach lanes of every junction.
+    approaches: HashMap<u32, Vec<u32>>,
+    /// The latest events, rendered.
+    log: VecDeque<String>,
+    /// Timestamp of the latest event.
+    now: u64,
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        let lanes = load_lanes();
+        let mut approaches: HashMap<u32, Vec<u32>> = HashMap::new();
+        for lane in lanes.iter().filter(|lane| lane.end_intersection != 0) {
+            approaches.entry(lane.end_intersection).or_default().push(lane.id);
+        }
+        Dashboard {
+            junctions: BTreeMap::new(),
+            positions: PositionEstimator::new(&lanes),
+            completed: 0,
+            total_wait: 0.0,
+            warnings: VecDeque::new(),
+            lights: HashMap::new(),
+            approaches,
+            log: VecDeque::new(),
+            now: 0,
+        }
+    }
+}
+
+impl Dashboard {
+    pub fn observe(&mut self, event: &LogEvent) {
+        self.now = self.now.max(event.timestamp);
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(format!("[{}] {}: {}", event.timestamp, event.source, event.describe()));
+        match &event.kind {
+            EventKind::PhaseChange { junction, phase, green_lanes, red_lanes } => {
+                self.junctions.insert(*junction, (*phase, green_lanes.clone()));
+                self.lights.extend(green_lanes.iter().map(|&lane_id| (lane_id, LightColor::Green)));
+                self.lights.extend(red_lanes.iter().map(|&lane_id| (lane_id, LightColor::Red)));
+            }
+            EventKind::PedestrianPhase { junction, .. } => {
+                let lanes = self.approaches.get(junction).into_iter().flatten();
+                self.lights.extend(lanes.map(|&lane_id| (lane_id, LightColor::Red)));
+            }
+            EventKind::VehicleGenerated { car_id, speed, .. } => {
+                self.positions.generated(*car_id, *speed);
+            }
+            EventKind::CarProgress { car_id, lane_id, transition, route_index } => {
+                self.positions.progress(*car_id, *lane_id, *transition, *route_index, event.timestamp);
+            }
+            EventKind::CarCompleted { car_id, wait_time, .. } => {
+                self.positions.forget(*car_id);
+                self.completed += 1;
+                self.total_wait += wait_time;
+            }
+            EventKind::RerouteAdvisory { .. }
+            | EventKind::VehicleGenerationFailed { .. }
+            | EventKind::ScenarioEvent { .. }
+            | EventKind::MonitorOverloaded { .. } => {
+                self.warn(event);
+            }
+            _ => {}
+        }
+    }
+
+    fn warn(&mut self, event: &LogEvent) {
+        if self.warnings.len() == RECENT_WARNINGS {
+            self.warnings.pop_front();
+        }
+        self.warnings.push_back(format!("[{}] {}: {}", event.timestamp, event.source, event.describe()));
+    }
+
+    /// Vehicles on every occupied lane.
+    pub fn lane_counts(&self) -> HashMap<u32, u32> {
+        let mut counts: HashMap<u32, u32> = HashMap::new();
+        for position in self.positions.positions(self.now) {
+            *counts.entry(position.lane_id).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// The most occupied lanes, busiest first, ties by lane id.
+    fn top_lanes(&self) -> Vec<(u32, u32)> {
+        let mut lanes: Vec<(u32, u32)> = self.lane_counts().into_iter().collect();
+        lanes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        lanes.truncate(TOP_LANES);
+        lanes
+    }
+}
+
+/// What the full-screen dashboard draws besides the lane counts.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+impl Dashboard {
+    /// Color of a lane's light as of its junction's latest phase change.
+    pub fn light(&self, lane_id: u32) -> Option<LightColor> {
+        self.lights.get(&lane_id).copied()
+    }
+
+    /// Active phase of a junction that has changed phase.
+    pub fn phase(&self, junction: u32) -> Option<usize> {
+        self.junctions.get(&junction).map(|(phase, _)| *phase)
+    }
+
+    /// The latest events, oldest first.
+    pub fn log(&self) -> &VecDeque<String> {
+        &self.log
+    }
+
+    /// The one-line header: time, completed cars and their mean wait, and
+    /// cars on the grid.
+    pub fn status(&self) -> String {
+        let mean_wait = if self.completed > 0 { self.total_wait / self.completed as f64 } else { 0.0 };
+        format!("Time: {}   Cars completed: {}   Mean wait: {:.2}s   On the grid: {}",
+                self.now, self.completed, mean_wait, self.positions.len())
+    }
+}
+
+impl fmt::Display for Dashboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "=== Traffic Dashboard [Time: {}] ===", self.now)?;
+        let mean_wait = if self.completed > 0 { self.total_wait / self.completed as f64 } else { 0.0 };
+        writeln!(f, "Cars completed: {}   Mean wait: {:.2}s   On the grid: {}",
+                 self.completed, mean_wait, self.positions.len())?;
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>5}  Green lanes", "Junction", "Phase")?;
+        if self.junctions.is_empty() {
+            writeln!(f, "  no phase changes yet")?;
+        }
+        for (junction, (phase, green_lanes)) in &self.junctions {
+            let green: Vec<String> = green_lanes.iter().map(u32::to_string).collect();
+            writeln!(f, "{:>8}  {:>5}  {}", junction, phase, green.join(", "))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  Vehicles", "Lane")?;
+        let lanes = self.top_lanes();
+        if lanes.is_empty() {
+            writeln!(f, "  no vehicles on the grid")?;
+        }
+        for (lane_id, count) in lanes {
+            writeln!(f, "{:>8}  {}", lane_id, count)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>8}  {:>4}  Estimated position", "Car", "Lane", "Leg")?;
+        let positions = self.positions.positions(self.now);
+        if positions.is_empty() {
+            writeln!(f, "  no vehicles on the grid")?;
+        }
+        for position in positions.iter().take(SHOWN_CARS) {
+            let along = match position.distance {
+                Some(distance) => format!("{:.0} of {:.0} m", distance, position.lane_length),
+                None => "unknown speed".to_string(),
+            };
+            writeln!(f, "{:>8}  {:>8}  {:>4}  {}", position.car_id, position.lane_id, position.route_index, along)?;
+        }
+        if positions.len() > SHOWN_CARS {
+            writeln!(f, "  and {} more", positions.len() - SHOWN_CARS)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Recent warnings")?;
+        if self.warnings.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "  {}", warning)?;
+        }
+        Ok(())
+    }
+}
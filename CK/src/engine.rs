@@ -0,0 +1,922 @@
+// engine.rs
+//
+// Discrete-event engine. With `--engine events` (or RTS_ENGINE=events) the
+// cars and the junction controllers are not threads: everything that happens
+// in a run, a car reaching the end of a lane, a light changing, a snapshot
+// for the analyzer, is an event in one priority queue, ordered by simulated
+// time, and a single thread handles them in turn. A run of thousands of cars
+// costs a heap entry per car instead of a thread per car.
+//
+// Events are handled once the clock reaches them, so `--speedup` sets how
+// fast the run goes, and the analyzer and monitoring receive the same lane
+// snapshots and log events, with the same timestamps, as from the thread
+// engine. Cars follow the same rules: a lane admits a vehicle while its
+// footprint fits, a car blocked on a full lane for BLOCKED_REROUTE_SECS
+// re-routes around it, and the cars at a light pass in the order they reached
+// it once it turns green, then cross the junction once no conflicting
+// movement is inside (see `junction_box`). Travel, waits and metrics use the event times, so
+// they do not depend on how far behind the clock the thread falls.
+//
+// Replays, lane generators, scenarios, pedestrian crossings, adaptive phase
+// ordering and dynamic routing are thread-engine features; a run that asks
+// for any of them under the event engine is refused before it starts (see
+// `check_supported`).
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::budget::{self, Category};
+use crate::coordination::Coordination;
+use crate::crossings::CrossingConfig;
+use crate::flow_analyzer::Recommendation;
+use crate::gridlock::AdvisedLanes;
+use crate::junction_box::{self, JunctionBox, CROSSING_SECS};
+use crate::log_scope::LogScope;
+use crate::scenario::Scenario;
+use crate::shutdown::{self, ShutdownFlag, SleepOrShutdown};
+use crate::signal_timing::{JunctionTiming, JunctionTimings};
+use crate::simulation::{
+    self, AnalyzerLinks, CarMetrics, GenerationFailed, LaneSnapshot, LaneVisit, RunVehicles, BLOCKED_REROUTE_SECS,
+};
+use crate::summary::SimulationSummary;
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use crate::vehicle::{Vehicle, VehicleMix};
+use rts_core::cadence::{self, CadenceController};
+use rts_core::clock::SimClock;
+use rts_core::demand::Arrivals;
+use rts_core::trips::{self, BoundaryLanes, Trip};
+use rts_core::export;
+use rts_core::lanes::{load_lanes, Lane, LaneCategory};
+use rts_core::messages::{LightColor, RecommendationApplied};
+use rts_core::network::{load_network, Network};
+use rts_core::phase_order::PhaseOrder;
+use rts_core::phase_plan::{build_phase_plan, Phase};
+use rts_core::progress::LaneTransition;
+use rts_core::routing::{self, find_lane_path};
+
+/// How the run is simulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// A thread per car and per junction (the default).
+    Threads,
+    /// Every car and junction in one thread; see the top of this file.
+    Events,
+}
+
+impl EngineKind {
+    fn parse(value: &str) -> Result<EngineKind, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "threads" => Ok(EngineKind::Threads),
+            "events" => Ok(EngineKind::Events),
+            _ => Err(format!("invalid engine '{}' (expected threads or events)", value.trim())),
+        }
+    }
+
+    /// Engine from `--engine <threads|events>` (or `--engine=<...>`), else
+    /// from RTS_ENGINE; threads, with a warning, if it is invalid.
+    pub fn from_env() -> EngineKind {
+        let args: Vec<String> = std::env::args().collect();
+        let flag = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--engine") {
+            Some("") => Some(("--engine", args.get(i + 1).cloned().unwrap_or_default())),
+            Some(value) => value.strip_prefix('=').map(|value| ("--engine", value.to_string())),
+            None => None,
+        });
+        let (source, value) = match flag {
+            Some(flag) => flag,
+            None => match std::env::var("RTS_ENGINE") {
+                Ok(value) => ("RTS_ENGINE", value),
+                Err(_) => return EngineKind::Threads,
+            },
+        };
+        EngineKind::parse(&value).unwrap_or_else(|e| {
+            eprintln!("Ignoring {}: {}", source, e);
+            EngineKind::Threads
+        })
+    }
+}
+
+/// Checks that `vehicles` and the environment ask for nothing only the thread
+/// engine has; the error names every such feature requested.
+pub fn check_supported(vehicles: &RunVehicles) -> Result<(), String> {
+    let unsupported = [
+        (vehicles.replay.is_some(), "replays (RTS_REPLAY)"),
+        (vehicles.generator.is_some(), "lane generators (--duration)"),
+        (!Scenario::from_env(&load_lanes()).is_empty(), "scenarios (RTS_SCENARIO)"),
+        (!CrossingConfig::from_env().is_empty(), "pedestrian crossings (RTS_CROSSINGS)"),
+        (matches!(PhaseOrder::from_env(), PhaseOrder::Adaptive { .. }), "adaptive phase ordering (RTS_ADAPTIVE_PHASES)"),
+        (routing::dynamic_routing_enabled(), "dynamic routing (RTS_DYNAMIC_ROUTING)"),
+    ];
+    let requested: Vec<&str> =
+        unsupported.iter().filter(|(requested, _)| *requested).map(|&(_, feature)| feature).collect();
+    if requested.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("the event engine does not support {}", requested.join(", ")))
+    }
+}
+
+/// Something due at a point in simulated time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Action {
+    /// A vehicle enters the grid on its entry lane.
+    Spawn(u32),
+    /// A car reaches the end of the lane it is driving.
+    LaneEnd(u32),
+    /// A car reaches the end of its exit lane and leaves the grid.
+    Leave(u32),
+    /// A car has been blocked on a full lane for BLOCKED_REROUTE_SECS, unless
+    /// it has moved on since; the count tells the blocks of one car apart.
+    Reroute(u32, u32),
+    /// A car has crossed a junction and leaves its box.
+    Cleared(u32, u32),
+    /// A junction turns its next phase green.
+    Green(u32),
+    /// A junction turns its green phase amber.
+    Amber(u32),
+    /// A junction starts its all-red clearance.
+    AllRed(u32),
+    /// The lane counts go out to the analyzer.
+    Snapshot,
+}
+
+/// A car on the road.
+struct Car {
+    vehicle: Vehicle,
+    entry: Lane,
+    exit: Lane,
+    route: Vec<Lane>,
+    /// Index in `route` of the lane the car is driving towards or on.
+    index: usize,
+    /// Internal lane the car holds a slot on.
+    occupied: Option<u32>,
+    spawned_at: Duration,
+    /// When the car started waiting for `route[index]`.
+    wait_start: Duration,
+    /// Occupancy of `route[index]` right after the car entered it.
+    occupancy: u32,
+    /// When the car passed its light and reached the junction.
+    box_since: Duration,
+    /// Blocks on a full lane so far; see `Action::Reroute`.
+    blocks: u32,
+    wait_time: f64,
+    drive_time: f64,
+    route_length: f64,
+    visits: Vec<LaneVisit>,
+    redraws: u32,
+}
+
+/// A junction controller: the same cycle as `traffic_light::run_traffic_lights`.
+struct Junction {
+    lanes: Vec<u32>,
+    phases: Vec<Phase>,
+    timing: JunctionTiming,
+    phase: usize,
+}
+
+/// A junction-level recommendation waiting for its junction's next green.
+struct JunctionHint {
+    lane_id: u32,
+    phase: usize,
+    green_secs: u64,
+}
+
+/// State of a run under the event engine.
+struct Engine {
+    clock: SimClock,
+    log_tx: Sender<LogEvent>,
+    /// Lanes lane-level events are sent for (RTS_LOG_TAGS).
+    log_scope: LogScope,
+    applied_tx: Sender<RecommendationApplied>,
+    network: Network,
+    internal_lanes: Vec<Lane>,
+    boundary: BoundaryLanes,
+    congestion_aware: bool,
+    advisories: AdvisedLanes,
+    queue: BinaryHeap<Reverse<(Duration, u64, Action)>>,
+    /// Scheduled so far; orders events due at the same time.
+    scheduled: u64,
+    /// Simulated time of the event being handled.
+    now: Duration,
+    /// Vehicles yet to spawn.
+    waiting: HashMap<u32, Vehicle>,
+    cars: HashMap<u32, Car>,
+    /// Occupancy of every lane, in footprint units.
+    counts: HashMap<u32, u32>,
+    /// Cars waiting for room on each lane, in the order they were blocked.
+    blocked: HashMap<u32, VecDeque<u32>>,
+    /// Lanes a car has left whose blocked cars may now fit.
+    freed: Vec<u32>,
+    colors: HashMap<u32, LightColor>,
+    /// Cars waiting at each lane's light, in the order they reached it.
+    at_light: HashMap<u32, VecDeque<u32>>,
+    junctions: HashMap<u32, Junction>,
+    boxes: HashMap<u32, JunctionBox>,
+    /// Cars waiting for a conflicting movement to clear each junction, in
+    /// the order they reached it.
+    box_waiting: HashMap<u32, VecDeque<u32>>,
+    hints: HashMap<u32, JunctionHint>,
+    green_overrides: HashMap<u32, u32>,
+    /// All-red holds waiting for each junction's next green.
+    all_red_holds: HashMap<u32, Duration>,
+    /// Phase greens of each junction's latest timing plan.
+    timing_plans: HashMap<u32, Vec<u32>>,
+    coordination: Coordination,
+    metrics: Vec<CarMetrics>,
+    failures: Vec<GenerationFailed>,
+}
+
+impl Engine {
+    fn schedule(&mut self, at: Duration, action: Action) {
+        self.scheduled += 1;
+        self.queue.push(Reverse((at, self.scheduled, action)));
+    }
+
+    fn after(&mut self, secs: f64, action: Action) {
+        self.schedule(self.now + Duration::from_secs_f64(secs), action);
+    }
+
+    fn log(&self, source: String, kind: EventKind) {
+        if !self.log_scope.sends(&kind) {
+            return;
+        }
+        self.log_tx.send(LogEvent::new(source, self.clock.now_secs(), kind)).ok();
+    }
+
+    fn generic(&self, source: String, message: String, level: Level) {
+        self.log_tx.send(LogEvent { source, message, timestamp: self.clock.now_secs(), level, kind: EventKind::Generic }).ok();
+    }
+
+    fn progress(&self, car_id: u32, lane_id: u32, transition: LaneTransition, route_index: usize) {
+        self.log(format!("Car-{}", car_id), EventKind::CarProgress { car_id, lane_id, transition, route_index });
+    }
+
+    /// Lane weights for routing, as `simulation::simulate_car` computes them.
+    fn weights(&mut self) -> Option<HashMap<u32, f64>> {
+        let mut weights = if self.congestion_aware { routing::congestion_weights(&self.counts) } else { HashMap::new() };
+        for lane_id in self.advisories.active(self.clock.now_secs()) {
+            *weights.entry(lane_id).or_insert(0.0) += routing::ADVISED_LANE_PENALTY;
+        }
+        if weights.is_empty() { None } else { Some(weights) }
+    }
+
+    fn spawn(&mut self, car_id: u32) {
+        let Some(vehicle) = self.waiting.remove(&car_id) else {
+            return;
+        };
+        let mut rng = StdRng::seed_from_u64(vehicle.trip_seed);
+        let trip = {
+            let _t = budget::time(Category::Routing);
+            trips::choose_trip(&self.boundary, &self.internal_lanes, &self.network, None, &mut rng, |_, _| None)
+        };
+        let Trip { entry, exit, redraws } = match trip {
+            Ok(trip) => trip,
+            Err(attempts) => {
+                let fail_log = LogEvent::new(
+                    format!("Car-{}", car_id),
+                    self.clock.now_secs(),
+                    EventKind::VehicleGenerationFailed { car_id, attempts },
+                )
+                .with_level(Level::Warn);
+                self.log_tx.send(fail_log).ok();
+                self.failures.push(GenerationFailed { car_id, attempts });
+                return;
+            }
+        };
+        let weights = self.weights();
+        let route = match find_lane_path(entry.end_intersection, exit.start_intersection, &self.internal_lanes, &self.network, weights.as_ref()) {
+            Ok(route) => route,
+            Err(e) => {
+                self.generic(format!("Car-{}", car_id), format!("Routing failed: {}; driving straight to the exit lane", e), Level::Warn);
+                Vec::new()
+            }
+        };
+        self.log(
+            format!("Car-{}", car_id),
+            EventKind::VehicleGenerated {
+                car_id,
+                vehicle_kind: vehicle.kind,
+                speed: vehicle.speed,
+                entry_lane: entry.id,
+                exit_lane: exit.id,
+                route: route.iter().map(|lane| lane.id).collect(),
+            },
+        );
+        self.progress(car_id, entry.id, LaneTransition::Entered, 0);
+        let travel_time = entry.length / vehicle.speed;
+        self.after(travel_time, Action::LaneEnd(car_id));
+        let route_length = entry.length;
+        self.cars.insert(car_id, Car {
+            vehicle,
+            entry,
+            exit,
+            route,
+            index: 0,
+            occupied: None,
+            spawned_at: self.now,
+            wait_start: self.now,
+            occupancy: 0,
+            box_since: self.now,
+            blocks: 0,
+            wait_time: 0.0,
+            drive_time: travel_time,
+            route_length,
+            visits: Vec::new(),
+            redraws,
+        });
+    }
+
+    /// The car has driven to the end of its lane: on to the next one of its
+    /// route, or to its exit lane.
+    fn lane_end(&mut self, car_id: u32) {
+        let Some(car) = self.cars.get_mut(&car_id) else {
+            return;
+        };
+        car.wait_start = self.now;
+        if car.index < car.route.len() {
+            self.try_enter(car_id);
+        } else {
+            self.enter_exit(car_id);
+        }
+    }
+
+    /// Takes a slot on the car's next lane if its footprint fits, releasing
+    /// the lane it held, and queues it at the lane's light; otherwise the car
+    /// waits for room.
+    fn try_enter(&mut self, car_id: u32) -> bool {
+        let car = &self.cars[&car_id];
+        let lane = car.route[car.index].clone();
+        let footprint = car.vehicle.kind.footprint();
+        let count = self.counts.entry(lane.id).or_insert(0);
+        if *count > 0 && *count + footprint > lane.capacity {
+            let car = self.cars.get_mut(&car_id).unwrap();
+            car.blocks += 1;
+            let blocks = car.blocks;
+            self.blocked.entry(lane.id).or_default().push_back(car_id);
+            self.after(BLOCKED_REROUTE_SECS, Action::Reroute(car_id, blocks));
+            return false;
+        }
+        *count += footprint;
+        let occupancy = *count;
+        let car = self.cars.get_mut(&car_id).unwrap();
+        // Any Reroute still pending for the car is stale now.
+        car.blocks += 1;
+        car.occupancy = occupancy;
+        let index = car.index;
+        let previous = car.occupied.replace(lane.id);
+        let entry_id = car.entry.id;
+        self.progress(car_id, lane.id, LaneTransition::Entered, index + 1);
+        match previous {
+            Some(previous) => {
+                self.leave_lane(previous, footprint);
+                self.progress(car_id, previous, LaneTransition::Exited, index);
+            }
+            None => self.progress(car_id, entry_id, LaneTransition::Exited, 0),
+        }
+        self.at_light.entry(lane.id).or_default().push_back(car_id);
+        self.release_light(lane.id);
+        true
+    }
+
+    fn leave_lane(&mut self, lane_id: u32, footprint: u32) {
+        let count = self.counts.entry(lane_id).or_insert(0);
+        *count = count.saturating_sub(footprint);
+        self.freed.push(lane_id);
+    }
+
+    /// Lets the cars blocked on the lanes cars have left in, in the order they
+    /// were blocked, for as long as there is room.
+    fn admit_blocked(&mut self) {
+        while let Some(lane_id) = self.freed.pop() {
+            let Some(waiting) = self.blocked.remove(&lane_id) else {
+                continue;
+            };
+            let mut still_blocked = VecDeque::new();
+            for car_id in waiting {
+                let car = &self.cars[&car_id];
+                let (capacity, footprint) = (car.route[car.index].capacity, car.vehicle.kind.footprint());
+                let count = self.counts.get(&lane_id).copied().unwrap_or(0);
+                if count > 0 && count + footprint > capacity {
+                    still_blocked.push_back(car_id);
+                } else {
+                    self.try_enter(car_id);
+                }
+            }
+            if !still_blocked.is_empty() {
+                self.blocked.entry(lane_id).or_default().extend(still_blocked);
+            }
+        }
+    }
+
+    /// The car has been blocked on the same full lane for BLOCKED_REROUTE_SECS:
+    /// it takes a way around the lane if there is one, or waits another while.
+    fn reroute(&mut self, car_id: u32, blocks: u32) {
+        let Some(car) = self.cars.get(&car_id) else {
+            return;
+        };
+        if car.blocks != blocks {
+            return;
+        }
+        let lane = car.route[car.index].clone();
+        let end = car.exit.start_intersection;
+        let candidates: Vec<Lane> = self.internal_lanes.iter().filter(|l| l.id != lane.id).cloned().collect();
+        let weights = self.weights();
+        let Ok(detour) = find_lane_path(lane.start_intersection, end, &candidates, &self.network, weights.as_ref()) else {
+            self.after(BLOCKED_REROUTE_SECS, Action::Reroute(car_id, blocks));
+            return;
+        };
+        let detour_ids: Vec<u32> = detour.iter().map(|l| l.id).collect();
+        self.generic(
+            format!("Car-{}", car_id),
+            format!("Lane {} full for {:.0}s; re-routed via {:?}", lane.id, BLOCKED_REROUTE_SECS, detour_ids),
+            Level::Info,
+        );
+        if let Some(waiting) = self.blocked.get_mut(&lane.id) {
+            waiting.retain(|&id| id != car_id);
+        }
+        let now = self.now;
+        let car = self.cars.get_mut(&car_id).unwrap();
+        car.wait_time += (now - car.wait_start).as_secs_f64();
+        car.wait_start = now;
+        let index = car.index;
+        car.route.truncate(index);
+        car.route.extend(detour);
+        if car.index < car.route.len() {
+            self.try_enter(car_id);
+        } else {
+            self.enter_exit(car_id);
+        }
+    }
+
+    /// Passes the cars at the front of the lane's light while it lets them:
+    /// all of them on green, none on amber or red. Lanes without a light
+    /// never stop a car.
+    fn release_light(&mut self, lane_id: u32) {
+        if self.colors.get(&lane_id).is_some_and(|&color| color != LightColor::Green) {
+            return;
+        }
+        let Some(waiting) = self.at_light.remove(&lane_id) else {
+            return;
+        };
+        for car_id in waiting {
+            self.reach_junction(car_id);
+        }
+    }
+
+    /// Junction the car is about to cross, and the lanes it comes from and
+    /// goes into as its route is planned now.
+    fn movement(&self, car_id: u32) -> (u32, u32, u32) {
+        let car = &self.cars[&car_id];
+        let lane = &car.route[car.index];
+        let next = car.route.get(car.index + 1).unwrap_or(&car.exit);
+        (lane.end_intersection, lane.id, next.id)
+    }
+
+    /// The car has passed its light: it crosses the junction, or waits for
+    /// the conflicting movements inside to clear.
+    fn reach_junction(&mut self, car_id: u32) {
+        self.cars.get_mut(&car_id).unwrap().box_since = self.now;
+        let (junction, approach, exit) = self.movement(car_id);
+        if self.boxes.get(&junction).is_none_or(|junction_box| junction_box.fits(approach, exit)) {
+            self.cross_junction(car_id);
+        } else {
+            self.box_waiting.entry(junction).or_default().push_back(car_id);
+        }
+    }
+
+    /// Lets in the cars waiting for the junction, in the order they reached
+    /// it, whose movements fit now.
+    fn admit_to_junction(&mut self, junction: u32) {
+        let Some(waiting) = self.box_waiting.remove(&junction) else {
+            return;
+        };
+        let mut still_waiting = VecDeque::new();
+        for car_id in waiting {
+            let (_, approach, exit) = self.movement(car_id);
+            if self.boxes[&junction].fits(approach, exit) {
+                self.cross_junction(car_id);
+            } else {
+                still_waiting.push_back(car_id);
+            }
+        }
+        if !still_waiting.is_empty() {
+            self.box_waiting.insert(junction, still_waiting);
+        }
+    }
+
+    /// The car crosses the junction, then drives its lane.
+    fn cross_junction(&mut self, car_id: u32) {
+        let now = self.now;
+        let (junction, approach, exit) = self.movement(car_id);
+        let car = self.cars.get_mut(&car_id).unwrap();
+        if let Some(junction_box) = self.boxes.get_mut(&junction) {
+            junction_box.enter(car_id, approach, exit, now, (now - car.box_since).as_secs_f64());
+        }
+        let lane = car.route[car.index].clone();
+        // Time spent on this lane's capacity, its light and the junction together.
+        let lane_wait = (now - car.wait_start).as_secs_f64();
+        car.wait_time += lane_wait;
+        car.visits.push(LaneVisit {
+            lane_id: lane.id,
+            junction: lane.end_intersection,
+            occupancy: car.occupancy,
+            wait_time: lane_wait,
+            // The event engine holds every movement for green.
+            right_on_red: false,
+        });
+        let seg_time = car.vehicle.lane_time(lane.length, lane_wait);
+        car.drive_time += CROSSING_SECS + seg_time;
+        car.route_length += lane.length;
+        car.index += 1;
+        self.log(
+            format!("Car-{}", car_id),
+            EventKind::LaneWait { car_id, lane_id: lane.id, junction: lane.end_intersection, wait_time: lane_wait },
+        );
+        self.after(CROSSING_SECS, Action::Cleared(junction, car_id));
+        self.after(CROSSING_SECS + seg_time, Action::LaneEnd(car_id));
+    }
+
+    fn cleared(&mut self, junction: u32, car_id: u32) {
+        if let Some(junction_box) = self.boxes.get_mut(&junction) {
+            junction_box.leave(car_id, self.now);
+        }
+        self.admit_to_junction(junction);
+    }
+
+    /// The car turns onto its exit lane, releasing the lane it held.
+    fn enter_exit(&mut self, car_id: u32) {
+        let car = self.cars.get_mut(&car_id).unwrap();
+        let exit_index = car.route.len() + 1;
+        let (exit_id, entry_id, route_len, previous) = (car.exit.id, car.entry.id, car.route.len(), car.occupied.take());
+        let footprint = car.vehicle.kind.footprint();
+        let exit_time = car.exit.length / car.vehicle.speed;
+        car.drive_time += exit_time;
+        car.route_length += car.exit.length;
+        self.progress(car_id, exit_id, LaneTransition::Entered, exit_index);
+        match previous {
+            Some(previous) => {
+                self.leave_lane(previous, footprint);
+                self.progress(car_id, previous, LaneTransition::Exited, route_len);
+            }
+            None => self.progress(car_id, entry_id, LaneTransition::Exited, 0),
+        }
+        self.after(exit_time, Action::Leave(car_id));
+    }
+
+    fn leave(&mut self, car_id: u32) {
+        let Some(car) = self.cars.remove(&car_id) else {
+            return;
+        };
+        self.progress(car_id, car.exit.id, LaneTransition::Exited, car.route.len() + 1);
+        let total_time = (self.now - car.spawned_at).as_secs_f64();
+        self.log(
+            format!("Car-{}", car_id),
+            EventKind::CarCompleted {
+                car_id,
+                entry_lane: car.entry.id,
+                exit_lane: car.exit.id,
+                route_length: car.route_length,
+                wait_time: car.wait_time,
+                drive_time: car.drive_time,
+                total_time,
+            },
+        );
+        self.metrics.push(CarMetrics {
+            id: car_id,
+            kind: car.vehicle.kind,
+            entry_lane: car.entry.id,
+            exit_lane: car.exit.id,
+            wait_time: car.wait_time,
+            drive_time: car.drive_time,
+            total_time,
+            lanes: car.visits,
+            redraws: car.redraws,
+        });
+    }
+
+    /// Files the analyzer's signal recommendations the way the traffic light
+    /// controller does, for the junctions' next greens, and applies its
+    /// reroute suggestions the way the simulation does.
+    fn take_recommendation(&mut self, recommendation: Recommendation) {
+        match recommendation {
+            Recommendation::ExtendGreen { lane_id, new_green_time, .. } => {
+                if self.colors.contains_key(&lane_id) {
+                    self.green_overrides.insert(lane_id, new_green_time);
+                } else {
+                    println!("Recommendation ignored: lane {} has no traffic light", lane_id);
+                }
+            }
+            Recommendation::ReorderPhases { junction_id, phase, lane_id, new_green_time, .. } => {
+                self.hints.insert(junction_id, JunctionHint { lane_id, phase, green_secs: u64::from(new_green_time) });
+            }
+            Recommendation::TimingPlan(plan) => {
+                self.timing_plans.insert(plan.junction, plan.greens);
+            }
+            Recommendation::EmergencyAllRed { junction_id, duration_secs, .. } => {
+                self.all_red_holds.insert(junction_id, Duration::from_secs(u64::from(duration_secs)));
+            }
+            Recommendation::SuggestReroute(advisory) => {
+                println!("Routing around lanes {:?} until {}", advisory.lanes, advisory.expires_at);
+                self.advisories.apply(&advisory);
+            }
+            Recommendation::AdjustWalkTime { junction_id, .. } => {
+                println!("Recommendation ignored: junction {} has no pedestrian crossings", junction_id);
+            }
+        }
+    }
+
+    /// Turns the junction's next phase green, for its baseline green time or
+    /// longer on recommendation, as `traffic_light::run_traffic_lights` does.
+    fn green(&mut self, junction_id: u32) {
+        // Every lane is red between phases, so an all-red hold just puts the
+        // green off.
+        if let Some(hold) = self.all_red_holds.remove(&junction_id) {
+            println!("Junction {}: holding every lane red for {}s on recommendation", junction_id, hold.as_secs());
+            self.schedule(self.now + hold, Action::Green(junction_id));
+            return;
+        }
+        // A corridor's coordinated phase waits for its slot in the green
+        // wave, unless a recommended phase goes first.
+        let coordinated = self
+            .coordination
+            .junction(junction_id)
+            .filter(|member| member.phase == self.junctions[&junction_id].phase && !self.hints.contains_key(&junction_id));
+        if let Some(member) = coordinated {
+            let wait = member.wait_for_slot(self.now);
+            if !wait.is_zero() {
+                self.schedule(self.now + wait, Action::Green(junction_id));
+                return;
+            }
+        }
+        let hint = self.hints.remove(&junction_id);
+        let junction = self.junctions.get_mut(&junction_id).unwrap();
+        let mut green_time = junction.timing.green;
+        let mut applied_lanes = Vec::new();
+        if let Some(hint) = hint.filter(|hint| hint.phase < junction.phases.len()) {
+            junction.phase = hint.phase;
+            green_time = Duration::from_secs(hint.green_secs).max(junction.timing.green);
+            applied_lanes.push(hint.lane_id);
+            println!("Junction {}: serving recommended phase {} for {}s", junction_id, junction.phase, green_time.as_secs());
+        } else if let Some(&secs) = self.timing_plans.get(&junction_id).and_then(|greens| greens.get(junction.phase)) {
+            green_time = Duration::from_secs(u64::from(secs));
+        }
+        if let Some(member) = coordinated {
+            green_time += member.slack;
+        }
+        let phase = junction.phase;
+        let lane_overrides: Vec<(u32, u32)> = junction.phases[phase]
+            .lanes
+            .iter()
+            .filter_map(|&lane_id| self.green_overrides.remove(&lane_id).map(|secs| (lane_id, secs)))
+            .collect();
+        if let Some(secs) = lane_overrides.iter().map(|&(_, secs)| secs).max() {
+            green_time = green_time.max(Duration::from_secs(u64::from(secs)));
+            applied_lanes.extend(lane_overrides.iter().map(|&(lane_id, _)| lane_id));
+            println!("Junction {}: holding phase {} green for {}s on recommendation", junction_id, phase, green_time.as_secs());
+        }
+        for lane_id in applied_lanes {
+            self.applied_tx.send(RecommendationApplied {
+                lane_id,
+                applied_green_time: green_time.as_secs() as u32,
+                timestamp: self.clock.now_secs(),
+            }).ok();
+        }
+
+        let (green_lanes, red_lanes): (Vec<u32>, Vec<u32>) =
+            junction.lanes.iter().copied().partition(|lane_id| junction.phases[phase].lanes.contains(lane_id));
+        for &lane_id in &green_lanes {
+            self.colors.insert(lane_id, LightColor::Green);
+        }
+        for &lane_id in &red_lanes {
+            self.colors.insert(lane_id, LightColor::Red);
+        }
+        for &lane_id in &green_lanes {
+            self.release_light(lane_id);
+        }
+        self.log(
+            format!("Junction-{}", junction_id),
+            EventKind::PhaseChange { junction: junction_id, phase, green_lanes, red_lanes },
+        );
+        self.schedule(self.now + green_time, Action::Amber(junction_id));
+    }
+
+    /// Turns the green phase amber. Every car queued at a green light has
+    /// already passed it, so nobody is left in the junction to clear it.
+    fn amber(&mut self, junction_id: u32) {
+        let junction = &self.junctions[&junction_id];
+        let amber = junction.timing.amber;
+        for &lane_id in &junction.phases[junction.phase].lanes {
+            self.colors.insert(lane_id, LightColor::Amber);
+        }
+        self.schedule(self.now + amber, Action::AllRed(junction_id));
+    }
+
+    fn all_red(&mut self, junction_id: u32) {
+        let junction = self.junctions.get_mut(&junction_id).unwrap();
+        for &lane_id in &junction.lanes {
+            self.colors.insert(lane_id, LightColor::Red);
+        }
+        junction.phase = (junction.phase + 1) % junction.phases.len();
+        let all_red = junction.timing.all_red;
+        self.schedule(self.now + all_red, Action::Green(junction_id));
+    }
+}
+
+/// Runs the simulation as discrete events in the calling thread (see the top
+/// of this file): the same demand as `simulation::run_simulation` draws for
+/// the same seed, junctions cycling as `traffic_light::run_traffic_lights`
+/// does, lane snapshots to the analyzer at the adaptive cadence, and the
+/// analyzer's recommendations applied at the junctions' next greens, reported
+/// on `applied_tx`. Once `interrupted` is raised no more vehicles spawn, and the
+/// run ends when those on the road are done. Expects `check_supported` to
+/// have passed.
+pub fn run_events(
+    log_tx: Sender<LogEvent>,
+    analyzer: AnalyzerLinks,
+    rec_rx: Receiver<Recommendation>,
+    applied_tx: Sender<RecommendationApplied>,
+    vehicles: RunVehicles,
+    clock: SimClock,
+) {
+    // Replays and generators are refused before the run starts (see `check_supported`).
+    let RunVehicles { demand, interrupted, export, .. } = vehicles;
+    let AnalyzerLinks { snapshots: analyzer_tx, advisories: advisory_rx, pedestrians: _ } = analyzer;
+    let all_lanes = load_lanes();
+    let network = load_network();
+    println!("Discrete-event engine: every car and junction runs in one thread");
+    // Drawn in the same order as `simulation::run_simulation`, so a seed gives
+    // both engines the same vehicles and arrival times.
+    let seed = simulation::run_seed();
+    let mut rng = StdRng::seed_from_u64(seed.into());
+    println!("Run seed {} (set RTS_SEED={} to repeat the same demand)", seed, seed);
+    if let Arrivals::Continuous { per_minute } = demand.arrivals {
+        println!("Spawning {} vehicles at {} per minute", demand.cars, per_minute);
+    }
+    log_tx.send(LogEvent::new(
+        "Simulation",
+        clock.now_secs(),
+        EventKind::RunStarted { seed, car_count: demand.cars, time_scale: clock.scale() },
+    )).ok();
+    let mix = VehicleMix::from_env();
+    let drawn: Vec<Vehicle> = (1..=demand.cars).map(|car_id| Vehicle::new(car_id, mix.sample(&mut rng), &mut rng)).collect();
+    let _pedestrian_seed: u64 = rng.random();
+    let _burst_seed: u64 = rng.random();
+    let arrival_seed: u64 = rng.random();
+
+    let mut junctions = HashMap::new();
+    let timings = JunctionTimings::from_env();
+    let mut colors = HashMap::new();
+    for lane in all_lanes.iter().filter(|lane| lane.end_intersection != 0) {
+        // Junctions without traffic lights are left to the cars: always green.
+        if !network.is_signalized(lane.end_intersection) {
+            colors.insert(lane.id, LightColor::Green);
+            continue;
+        }
+        colors.insert(lane.id, LightColor::Red);
+        junctions
+            .entry(lane.end_intersection)
+            .or_insert_with(|| Junction {
+                lanes: Vec::new(),
+                phases: build_phase_plan(lane.end_intersection, &all_lanes, &network),
+                timing: timings.for_junction(lane.end_intersection),
+                phase: 0,
+            })
+            .lanes
+            .push(lane.id);
+    }
+
+    let congestion_aware = routing::congestion_routing_enabled();
+    if congestion_aware {
+        println!("Congestion-aware routing enabled");
+    }
+    let boxes = junction_box::junction_boxes(&all_lanes, &network);
+    let coordination = Coordination::from_env(&all_lanes, &network, &timings);
+    coordination.publish(&log_tx, &clock);
+    let mut engine = Engine {
+        clock,
+        log_tx,
+        log_scope: LogScope::from_env(&network),
+        applied_tx,
+        internal_lanes: all_lanes.iter().filter(|l| l.category == LaneCategory::Internal).cloned().collect(),
+        boundary: BoundaryLanes::from_lanes(&all_lanes),
+        network,
+        congestion_aware,
+        advisories: AdvisedLanes::default(),
+        queue: BinaryHeap::new(),
+        scheduled: 0,
+        now: Duration::ZERO,
+        waiting: HashMap::new(),
+        cars: HashMap::new(),
+        counts: all_lanes.iter().map(|lane| (lane.id, 0)).collect(),
+        blocked: HashMap::new(),
+        freed: Vec::new(),
+        colors,
+        at_light: HashMap::new(),
+        junctions,
+        boxes,
+        box_waiting: HashMap::new(),
+        hints: HashMap::new(),
+        green_overrides: HashMap::new(),
+        all_red_holds: HashMap::new(),
+        timing_plans: HashMap::new(),
+        coordination,
+        metrics: Vec::new(),
+        failures: Vec::new(),
+    };
+    let mut arrival_rng = StdRng::seed_from_u64(arrival_seed);
+    let mut spawn_at = Duration::ZERO;
+    for vehicle in drawn {
+        engine.schedule(spawn_at, Action::Spawn(vehicle.id));
+        engine.waiting.insert(vehicle.id, vehicle);
+        spawn_at += demand.gap(arrival_rng.random());
+    }
+    let mut junction_ids: Vec<u32> = engine.junctions.keys().copied().collect();
+    junction_ids.sort_unstable();
+    for junction in junction_ids {
+        engine.schedule(Duration::ZERO, Action::Green(junction));
+    }
+    let mut cadence = CadenceController::new(cadence::FLOOR, cadence::CEILING, cadence::INITIAL);
+    let mut lane_series = Vec::new();
+    engine.schedule(cadence.interval(), Action::Snapshot);
+
+    let run_start = Instant::now();
+    while !engine.waiting.is_empty() || !engine.cars.is_empty() {
+        let Some(Reverse((at, _, action))) = engine.queue.pop() else {
+            break;
+        };
+        wait_until(&clock, run_start, at, &interrupted);
+        engine.now = at;
+        if shutdown::is_requested(&interrupted) && !engine.waiting.is_empty() {
+            engine.waiting.clear();
+        }
+        for recommendation in rec_rx.try_iter() {
+            engine.take_recommendation(recommendation);
+        }
+        match action {
+            Action::Spawn(car_id) => engine.spawn(car_id),
+            Action::LaneEnd(car_id) => engine.lane_end(car_id),
+            Action::Leave(car_id) => engine.leave(car_id),
+            Action::Reroute(car_id, blocks) => engine.reroute(car_id, blocks),
+            Action::Cleared(junction, car_id) => engine.cleared(junction, car_id),
+            Action::Green(junction) => engine.green(junction),
+            Action::Amber(junction) => engine.amber(junction),
+            Action::AllRed(junction) => engine.all_red(junction),
+            Action::Snapshot => {
+                let elapsed = cadence.interval();
+                let next = cadence.observe(&engine.counts, elapsed);
+                if export.is_some() {
+                    lane_series.extend(export::lane_samples(clock.now_secs(), &engine.counts));
+                }
+                let snapshot = LaneSnapshot {
+                    lanes: engine.counts.clone(),
+                    interval_ms: next.as_millis() as u64,
+                    departures: junction_box::departures(&engine.boxes),
+                };
+                analyzer_tx.send(snapshot).ok();
+                for recommendation in advisory_rx.try_iter() {
+                    engine.take_recommendation(recommendation);
+                }
+                engine.schedule(at + next, Action::Snapshot);
+            }
+        }
+        engine.admit_blocked();
+    }
+    if shutdown::is_requested(&interrupted) {
+        engine.generic(
+            "Simulation".to_string(),
+            "Run interrupted; the summary covers the vehicles spawned before it".to_string(),
+            Level::Info,
+        );
+    }
+    // Let the analyzer see its channel close.
+    drop(analyzer_tx);
+
+    for failed in &engine.failures {
+        println!("Car {} found no valid trip in {} draws; check the lane topology", failed.car_id, failed.attempts);
+    }
+    simulation::log_averages(&engine.metrics, &engine.log_tx, &clock);
+    if let Some(export) = &export {
+        simulation::export_metrics(export, &engine.metrics, &lane_series);
+    }
+    let mut summary = SimulationSummary::from_metrics(&engine.metrics, &engine.failures, engine.now);
+    summary.junction_utilization = junction_box::utilization(&engine.boxes, engine.now);
+    summary.corridors = engine.coordination.stats(&engine.metrics);
+    summary.count_tags(&engine.metrics, &engine.network);
+    budget::flush("Engine");
+    summary.time_budget = budget::report();
+    println!("{}", summary);
+    engine.log(String::from("Simulation"), EventKind::Summary(Box::new(summary)));
+}
+
+/// Blocks until `at` of simulated time has passed since `run_start`. Once the
+/// run is interrupted the wait goes on regardless, so the cars on the road
+/// still drive at the clock's pace.
+fn wait_until(clock: &SimClock, run_start: Instant, at: Duration, interrupted: &ShutdownFlag) {
+    if !clock.sleep_or_shutdown(at.saturating_sub(clock.since(run_start)), interrupted) {
+        clock.sleep(at.saturating_sub(clock.since(run_start)));
+    }
+}
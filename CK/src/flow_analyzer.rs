@@ -1,47 +1,682 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::mpsc::{Receiver, Sender};
 
-//use crate::system_monitoring::LogEvent;
+use crate::budget::{self, Category};
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use crate::simulation::LaneSnapshot;
+use rts_core::lanes::load_lanes;
+use rts_core::phase_plan::{build_phase_plan, Phase};
+use rts_core::network::load_network;
+use rts_core::clock::SimClock;
+use rts_core::messages::RecommendationApplied;
+use crate::gridlock::{GridlockConfig, GridlockDetector, RerouteAdvisory};
+use crate::crossings::{CrossingConfig, PedestrianUpdate};
+use crate::signal_timing::JunctionTimings;
+use rts_core::webster::{self, TimingPlan};
 
-/// Recommendations generated by the Flow Analyzer for traffic light adjustments.
+/// Recommendations generated by the Flow Analyzer. The signal ones go to the
+/// traffic light controller; reroute suggestions go to the simulation, which
+/// owns routing. These never leave the process, so CK uses this richer type
+/// rather than the wire `rts_core::messages::Recommendation`.
 #[derive(Debug)]
 pub enum Recommendation {
-    AdjustGreenTime {
+    /// Hold the lane's phase green for `new_green_time` the next time it is
+    /// served.
+    ExtendGreen {
         lane_id: u32,
         new_green_time: u32,
-        timestamp: u64,
+    },
+    /// Serve `phase` (an index into the junction's phase plan) next, for
+    /// `new_green_time`, then resume the cycle after it. `lane_id` is the
+    /// phase's busiest lane, for feedback.
+    ReorderPhases {
+        junction_id: u32,
+        phase: usize,
+        lane_id: u32,
+        new_green_time: u32,
+    },
+    /// Route new trips around the advisory's lanes until it expires.
+    SuggestReroute(RerouteAdvisory),
+    /// Run the junction's phases for the plan's green times in place of its
+    /// baseline green, until the next plan.
+    TimingPlan(TimingPlan),
+    /// Hold every lane of the junction red for `duration_secs` before its
+    /// next phase, so nothing more is fed into lanes that cannot drain.
+    EmergencyAllRed {
+        junction_id: u32,
+        duration_secs: u32,
+    },
+    /// Walk time for every later walk phase of a junction with pedestrian
+    /// crossings until the next one; a walk time of 0 skips them.
+    AdjustWalkTime {
+        junction_id: u32,
+        new_walk_time: u32,
     },
 }
 
-fn get_current_timestamp() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// Length of the sliding window used to average each lane's vehicle count.
+const WINDOW_SECS: u64 = 60;
+/// Rolling average at or above which a lane is considered congested.
+const CONGESTION_THRESHOLD: f64 = 4.0;
+/// Fraction of the threshold the rolling average must drop below before a
+/// congested lane counts as clear again, so a lane hovering around the
+/// threshold does not flap in and out of congestion.
+const CLEAR_RATIO: f64 = 0.75;
+/// How long the rolling average must stay at or above the threshold before a
+/// lane counts as congested.
+const SUSTAIN_SECS: u64 = 15;
+/// Trend, in vehicles per minute, below which a lane is draining fast enough
+/// that it is left to clear on its own.
+const DRAINING_TREND: f64 = -2.0;
+/// Rolling average of a junction's total approach count at or above which the
+/// junction as a whole is considered congested.
+const JUNCTION_CONGESTION_THRESHOLD: f64 = 6.0;
+/// Minimum time between two recommendations for the same lane.
+const COOLDOWN_SECS: u64 = 60;
+/// Green time recommended for a lane right at the threshold.
+const BASE_GREEN_TIME: u32 = 10;
+/// Extra green seconds per vehicle the average sits above the threshold.
+const GREEN_SECS_PER_EXCESS_VEHICLE: f64 = 5.0;
+/// Upper bound on any recommended green time.
+const MAX_GREEN_TIME: u32 = 60;
+/// Failed recommendations in a row after which a lane's next one is escalated.
+const ESCALATE_AFTER_FAILURES: u32 = 2;
+/// Extra green seconds added per escalation level.
+const ESCALATION_STEP_SECS: u32 = 10;
+/// Pedestrian arrivals per minute the configured walk time is sized for.
+const WALK_BASELINE_PEDESTRIANS: u32 = 5;
+/// Extra walk seconds per pedestrian above the baseline.
+const WALK_SECS_PER_EXTRA_PEDESTRIAN: u32 = 1;
+/// Upper bound on any recommended walk time.
+const MAX_WALK_TIME: u32 = 30;
+/// How long the junctions a gridlock's lanes lead into are held all-red.
+const GRIDLOCK_ALL_RED_SECS: u32 = 10;
+/// Minimum time between two timing plans for the same junction.
+const PLAN_INTERVAL_SECS: u64 = 60;
+
+/// Where a lane stands between sustained congestion and clear.
+#[derive(Debug, Default)]
+struct LaneCongestion {
+    /// When the rolling average last rose to the threshold, while it stays there.
+    above_since: Option<u64>,
+    /// Sustained congestion detected and not yet cleared.
+    congested: bool,
+    last_recommended: Option<u64>,
 }
 
+/// Tracks a sliding window of vehicle counts per lane and decides when a lane
+/// has been congested long enough to warrant a recommendation. A lane becomes
+/// congested once its rolling average has stayed at or above the threshold
+/// for SUSTAIN_SECS without draining, and stays congested until the average
+/// falls below CLEAR_RATIO of the threshold. A congested lane gets at most
+/// one recommendation per cooldown.
+pub struct CongestionDetector {
+    window_secs: u64,
+    threshold: f64,
+    cooldown_secs: u64,
+    /// (timestamp, vehicle count, milliseconds the sample stands for)
+    samples: HashMap<u32, VecDeque<(u64, u32, u64)>>,
+    lanes: HashMap<u32, LaneCongestion>,
+}
 
-pub fn run_flow_analyzer(analyzer_rx: Receiver<HashMap<u32, u32>>, rec_tx: Sender<Recommendation>) {
-    let mut lane_vehicle_counts: HashMap<u32, u32> = HashMap::new();
+impl CongestionDetector {
+    pub fn new(window_secs: u64, threshold: f64, cooldown_secs: u64) -> Self {
+        CongestionDetector {
+            window_secs,
+            threshold,
+            cooldown_secs,
+            samples: HashMap::new(),
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// Records a vehicle count for a lane and drops samples older than the window.
+    /// `weight_ms` is how long the count stands for, i.e. the publisher's interval
+    /// until its next snapshot, so bursts of fast snapshots don't skew the average.
+    pub fn record(&mut self, lane_id: u32, vehicle_count: u32, weight_ms: u64, now: u64) {
+        let window = self.samples.entry(lane_id).or_default();
+        window.push_back((now, vehicle_count, weight_ms.max(1)));
+        while let Some(&(ts, _, _)) = window.front() {
+            if ts + self.window_secs <= now {
+                window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Time-weighted average of the samples currently in the lane's window.
+    pub fn average(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        if window.is_empty() {
+            return None;
+        }
+        let weighted: f64 = window.iter().map(|&(_, count, weight)| count as f64 * weight as f64).sum();
+        let total_weight: u64 = window.iter().map(|&(_, _, weight)| weight).sum();
+        Some(weighted / total_weight as f64)
+    }
+
+    /// Least-squares slope of the lane's counts over its window, in vehicles
+    /// per minute: positive while the queue builds, negative while it drains.
+    pub fn trend(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        let n = window.len() as f64;
+        let mean_t = window.iter().map(|&(ts, _, _)| ts as f64).sum::<f64>() / n;
+        let mean_c = window.iter().map(|&(_, count, _)| count as f64).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for &(ts, count, _) in window {
+            let dt = ts as f64 - mean_t;
+            covariance += dt * (count as f64 - mean_c);
+            variance += dt * dt;
+        }
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance * 60.0)
+    }
+
+    /// Returns the green time to recommend for the lane, if it is in sustained
+    /// congestion (see `CongestionDetector`), not draining and not cooling down.
+    pub fn evaluate(&mut self, lane_id: u32, now: u64) -> Option<u32> {
+        let average = self.average(lane_id)?;
+        let trend = self.trend(lane_id).unwrap_or(0.0);
+        let lane = self.lanes.entry(lane_id).or_default();
+        if average < self.threshold {
+            lane.above_since = None;
+        } else if lane.above_since.is_none() {
+            lane.above_since = Some(now);
+        }
+        if lane.congested {
+            if average < self.threshold * CLEAR_RATIO {
+                lane.congested = false;
+            }
+        } else if lane.above_since.is_some_and(|since| now >= since + SUSTAIN_SECS) && trend > DRAINING_TREND {
+            lane.congested = true;
+        }
+        if !lane.congested || trend <= DRAINING_TREND {
+            return None;
+        }
+        if lane.last_recommended.is_some_and(|last| now < last + self.cooldown_secs) {
+            return None;
+        }
+        lane.last_recommended = Some(now);
+        Some(green_time_for(average, self.threshold))
+    }
+}
+
+/// Measures the flow out of each approach lane over a sliding window, from
+/// the cumulative departures in the simulation's snapshots.
+pub struct FlowMeter {
+    window_secs: u64,
+    /// (timestamp, departures so far)
+    samples: HashMap<u32, VecDeque<(u64, u32)>>,
+}
+
+impl FlowMeter {
+    pub fn new(window_secs: u64) -> Self {
+        FlowMeter { window_secs, samples: HashMap::new() }
+    }
+
+    /// Records every lane's departures so far and drops samples older than
+    /// the window, keeping the newest one before it as the window's start.
+    pub fn record(&mut self, departures: &HashMap<u32, u32>, now: u64) {
+        for (&lane_id, &count) in departures {
+            let window = self.samples.entry(lane_id).or_default();
+            window.push_back((now, count));
+            while window.len() > 1 && window[1].0 + self.window_secs <= now {
+                window.pop_front();
+            }
+        }
+    }
+
+    /// Vehicles per second that left each lane over its window; lanes
+    /// without two samples at different times are left out.
+    pub fn flows(&self) -> HashMap<u32, f64> {
+        self.samples
+            .iter()
+            .filter_map(|(&lane_id, window)| {
+                let (&(first_ts, first), &(last_ts, last)) = (window.front()?, window.back()?);
+                (last_ts > first_ts).then(|| (lane_id, last.saturating_sub(first) as f64 / (last_ts - first_ts) as f64))
+            })
+            .collect()
+    }
+}
+
+/// How to escalate a lane's next recommendation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escalation {
+    /// Recommend the green time as computed.
+    None,
+    /// Recommend this longer green time instead.
+    LongerGreen(u32),
+    /// Even the longest green time has not helped; favour the lane's phase at
+    /// junction level instead.
+    Junction,
+}
+
+/// What became of an applied recommendation once its window had passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedbackOutcome {
+    pub lane_id: u32,
+    pub applied_green_time: u32,
+    /// Rolling averages when the recommendation was applied and a window later.
+    pub before: f64,
+    pub after: f64,
+    /// Whether the lane's average dropped or fell below the congestion threshold.
+    pub helped: bool,
+    /// Failed recommendations in a row for the lane, this one included.
+    pub failures: u32,
+}
+
+
+/// Learns whether applied recommendations reduced their lane's rolling
+/// average over the following window, and escalates lanes whose
+/// recommendations keep failing.
+pub struct FeedbackTracker {
+    window_secs: u64,
+    threshold: f64,
+    escalate_after: u32,
+    /// Lanes under watch, with the rolling average when their recommendation
+    /// was applied.
+    watching: HashMap<u32, (RecommendationApplied, f64)>,
+    failures: HashMap<u32, u32>,
+}
+
+impl FeedbackTracker {
+    pub fn new(window_secs: u64, threshold: f64, escalate_after: u32) -> Self {
+        FeedbackTracker {
+            window_secs,
+            threshold,
+            escalate_after,
+            watching: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Starts watching the lane of an applied recommendation, whose rolling
+    /// average was `before` at the time. Applying another one restarts the watch.
+    pub fn applied(&mut self, applied: RecommendationApplied, before: f64) {
+        self.watching.insert(applied.lane_id, (applied, before));
+    }
+
+    /// Settles every watch whose window has passed by `now`, comparing against
+    /// the lane's current rolling average (a lane with no samples counts as
+    /// empty). A recommendation helped if the average dropped or the lane is no
+    /// longer congested.
+    pub fn settle(&mut self, now: u64, average: impl Fn(u32) -> Option<f64>) -> Vec<FeedbackOutcome> {
+        let due: Vec<u32> = self
+            .watching
+            .iter()
+            .filter(|(_, (applied, _))| applied.timestamp + self.window_secs <= now)
+            .map(|(&lane_id, _)| lane_id)
+            .collect();
+        let mut outcomes = Vec::new();
+        for lane_id in due {
+            let Some((applied, before)) = self.watching.remove(&lane_id) else {
+                continue;
+            };
+            let after = average(lane_id).unwrap_or(0.0);
+            let helped = after < before || after < self.threshold;
+            let failures = if helped {
+                self.failures.remove(&lane_id);
+                0
+            } else {
+                let failures = self.failures.entry(lane_id).or_insert(0);
+                *failures += 1;
+                *failures
+            };
+            outcomes.push(FeedbackOutcome {
+                lane_id,
+                applied_green_time: applied.applied_green_time,
+                before,
+                after,
+                helped,
+                failures,
+            });
+        }
+        outcomes.sort_by_key(|outcome| outcome.lane_id);
+        outcomes
+    }
+
+    /// How to escalate a `green_time` recommendation for the lane: each failure
+    /// from `escalate_after` on adds ESCALATION_STEP_SECS, and once that would
+    /// pass MAX_GREEN_TIME the lane goes to junction level.
+    pub fn escalation(&self, lane_id: u32, green_time: u32) -> Escalation {
+        let failures = self.failures.get(&lane_id).copied().unwrap_or(0);
+        if failures < self.escalate_after {
+            return Escalation::None;
+        }
+        let level = failures - self.escalate_after + 1;
+        let green = green_time + level * ESCALATION_STEP_SECS;
+        if green <= MAX_GREEN_TIME {
+            Escalation::LongerGreen(green)
+        } else {
+            Escalation::Junction
+        }
+    }
+}
+
+/// Scales the recommended green time with how far the average exceeds the threshold.
+fn green_time_for(average: f64, threshold: f64) -> u32 {
+    let excess = (average - threshold).max(0.0);
+    let green = BASE_GREEN_TIME as f64 + excess * GREEN_SECS_PER_EXCESS_VEHICLE;
+    (green.round() as u32).min(MAX_GREEN_TIME)
+}
+
+/// Walk time for a junction that saw `arrivals` pedestrians in the last
+/// minute: none if nobody arrived, otherwise the configured `walk` plus a
+/// little per pedestrian above the baseline.
+fn walk_time_for(arrivals: u32, walk: u32) -> u32 {
+    if arrivals == 0 {
+        return 0;
+    }
+    let extra = arrivals.saturating_sub(WALK_BASELINE_PEDESTRIANS) * WALK_SECS_PER_EXTRA_PEDESTRIAN;
+    (walk + extra).min(MAX_WALK_TIME.max(walk))
+}
+
+/// Maps every lane that approaches a junction to that junction.
+pub fn lane_junctions() -> HashMap<u32, u32> {
+    load_lanes()
+        .into_iter()
+        .filter(|lane| lane.end_intersection != 0)
+        .map(|lane| (lane.id, lane.end_intersection))
+        .collect()
+}
+
+/// Sums lane counts into per-junction totals. Lanes that don't approach a
+/// junction (exit lanes) are left out.
+pub fn junction_totals(lanes: &HashMap<u32, u32>, lane_junction: &HashMap<u32, u32>) -> HashMap<u32, u32> {
+    let mut totals: HashMap<u32, u32> = HashMap::new();
+    for (lane_id, &count) in lanes {
+        if let Some(&junction) = lane_junction.get(lane_id) {
+            *totals.entry(junction).or_insert(0) += count;
+        }
+    }
+    totals
+}
+
+/// Picks the phase carrying the most vehicles and its busiest lane.
+fn busiest_phase(phases: &[Phase], lanes: &HashMap<u32, u32>) -> Option<(usize, u32)> {
+    let count = |lane_id: &u32| lanes.get(lane_id).copied().unwrap_or(0);
+    let (index, phase) = phases
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, phase)| phase.lanes.iter().map(count).sum::<u32>())?;
+    let lane_id = *phase.lanes.iter().max_by_key(|lane_id| count(lane_id))?;
+    Some((index, lane_id))
+}
+
+/// Runs the flow analyzer until the simulation closes `analyzer_rx`. Applied
+/// recommendations reported on `applied_rx` are checked a window later, and a
+/// lane whose recommendations keep failing gets longer green times and then
+/// a junction-level recommendation. Pedestrian counts on `pedestrian_rx`
+/// lengthen or skip the walk phases of junctions with crossings. A gridlock
+/// gets a reroute suggestion on `advisory_tx`, for the simulation, and an
+/// all-red at the junctions its lanes lead into. With RTS_WEBSTER set, every
+/// junction gets a Webster timing plan from the measured flows of its lanes
+/// (see `rts_core::webster`) in place of lane and junction green
+/// recommendations.
+pub fn run_flow_analyzer(
+    analyzer_rx: Receiver<LaneSnapshot>,
+    rec_tx: Sender<Recommendation>,
+    advisory_tx: Sender<Recommendation>,
+    applied_rx: Receiver<RecommendationApplied>,
+    pedestrian_rx: Receiver<PedestrianUpdate>,
+    log_tx: Sender<LogEvent>,
+    clock: SimClock,
+) {
+    let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+    let mut feedback = FeedbackTracker::new(WINDOW_SECS, CONGESTION_THRESHOLD, ESCALATE_AFTER_FAILURES);
+    let mut junction_detector = CongestionDetector::new(WINDOW_SECS, JUNCTION_CONGESTION_THRESHOLD, COOLDOWN_SECS);
+    let lane_junction = lane_junctions();
+    let all_lanes = load_lanes();
+    let network = load_network();
+    let phase_plans: HashMap<u32, Vec<Phase>> = lane_junction
+        .values()
+        .map(|&junction| (junction, build_phase_plan(junction, &all_lanes, &network)))
+        .collect();
+    let mut gridlock = GridlockDetector::new(GridlockConfig::from_env(), &all_lanes);
+    let configured_walk = CrossingConfig::from_env().walk.as_secs() as u32;
+    // Walk time last recommended per junction.
+    let mut walk_times: HashMap<u32, u32> = HashMap::new();
+    let plan_timing = webster::webster_enabled();
+    if plan_timing {
+        println!("Webster timing plans enabled: junction plans replace lane and junction green recommendations");
+    }
+    let timings = JunctionTimings::from_env();
+    let mut meter = FlowMeter::new(WINDOW_SECS);
+    // When the analyzer started, so the first plans wait for a full window of flows.
+    let mut started: Option<u64> = None;
+    // Time and greens of the plan last sent per junction.
+    let mut plans: HashMap<u32, (u64, Vec<u32>)> = HashMap::new();
 
     // Infinite loop to keep listening for new data
     loop {
         match analyzer_rx.recv() {
-            Ok(lanes) => {
-                lane_vehicle_counts = lanes.clone(); // Update lane vehicle counts
+            Ok(snapshot) => {
+                // Windows and cooldowns run on simulated time.
+                let now = clock.now_secs();
 
-                for (&lane_id, &vehicle_count) in &lane_vehicle_counts {
-                    if vehicle_count >= 4 { // Adjust the congestion threshold as needed
-                        let rec = Recommendation::AdjustGreenTime {
-                            lane_id,
-                            new_green_time: 40, // Example new green time
-                            timestamp: get_current_timestamp(),
+                // Feedback pass: did the recommendations applied a window ago help?
+                for applied in applied_rx.try_iter() {
+                    let before = detector.average(applied.lane_id).unwrap_or(0.0);
+                    feedback.applied(applied, before);
+                }
+                for outcome in feedback.settle(now, |lane_id| detector.average(lane_id)) {
+                    if outcome.helped {
+                        continue;
+                    }
+                    let message = format!("{}s green for lane {} did not reduce congestion (avg {:.1} -> {:.1}); {} in a row",
+                                          outcome.applied_green_time, outcome.lane_id, outcome.before, outcome.after,
+                                          outcome.failures);
+                    println!("{}", message);
+                    log_tx.send(LogEvent {
+                        source: "FlowAnalyzer".to_string(),
+                        message,
+                        timestamp: now,
+                        level: Level::Info,
+                        kind: EventKind::Generic,
+                    }).ok();
+                }
+
+                for (&lane_id, &vehicle_count) in &snapshot.lanes {
+                    detector.record(lane_id, vehicle_count, snapshot.interval_ms, now);
+                    if plan_timing {
+                        continue;
+                    }
+                    if let Some(green_time) = detector.evaluate(lane_id, now) {
+                        println!("Congestion detected at lane {} (avg {:.1} vehicles over {}s, trend {:+.1}/min)",
+                                 lane_id, detector.average(lane_id).unwrap_or(0.0), WINDOW_SECS, detector.trend(lane_id).unwrap_or(0.0));
+                        let (new_green_time, reorder) = match feedback.escalation(lane_id, green_time) {
+                            Escalation::None => (green_time, None),
+                            Escalation::LongerGreen(longer) => {
+                                println!("Escalating lane {}: earlier recommendations did not help; recommending {}s green",
+                                         lane_id, longer);
+                                (longer, None)
+                            }
+                            Escalation::Junction => {
+                                let junction = lane_junction.get(&lane_id).copied();
+                                let phase = junction
+                                    .and_then(|junction| phase_plans.get(&junction))
+                                    .and_then(|phases| phases.iter().position(|phase| phase.lanes.contains(&lane_id)));
+                                println!("Escalating lane {} to junction {:?}: even {}s green did not help",
+                                         lane_id, junction, MAX_GREEN_TIME);
+                                (MAX_GREEN_TIME, junction.zip(phase))
+                            }
+                        };
+                        let rec = match reorder {
+                            Some((junction_id, phase)) => Recommendation::ReorderPhases {
+                                junction_id,
+                                phase,
+                                lane_id,
+                                new_green_time,
+                            },
+                            None => Recommendation::ExtendGreen { lane_id, new_green_time },
+                        };
+
+                        let _t = budget::time(Category::Transport);
+                        if let Err(e) = rec_tx.send(rec) {
+                            println!("Error sending recommendation: {}", e);
+                        }
+                        log_tx.send(LogEvent::new(
+                            "FlowAnalyzer",
+                            now,
+                            EventKind::Recommendation { lane_id, new_green_time },
+                        )).ok();
+                    }
+                }
+
+                // Junction-level pass: congestion spread over several lanes of a
+                // junction may not trip any single lane's threshold.
+                for (junction, total) in junction_totals(&snapshot.lanes, &lane_junction) {
+                    junction_detector.record(junction, total, snapshot.interval_ms, now);
+                    if plan_timing {
+                        continue;
+                    }
+                    let Some(new_green_time) = junction_detector.evaluate(junction, now) else {
+                        continue;
+                    };
+                    let Some((phase, lane_id)) = phase_plans
+                        .get(&junction)
+                        .and_then(|phases| busiest_phase(phases, &snapshot.lanes))
+                    else {
+                        continue;
+                    };
+                    println!("Congestion detected at junction {} (avg {:.1} vehicles over {}s); favouring phase {}",
+                             junction, junction_detector.average(junction).unwrap_or(0.0), WINDOW_SECS, phase);
+                    let rec = Recommendation::ReorderPhases {
+                        junction_id: junction,
+                        phase,
+                        lane_id,
+                        new_green_time,
+                    };
+                    let _t = budget::time(Category::Transport);
+                    if let Err(e) = rec_tx.send(rec) {
+                        println!("Error sending recommendation: {}", e);
+                    }
+                    let mut log_event = LogEvent::new(
+                        "FlowAnalyzer",
+                        now,
+                        EventKind::Recommendation { lane_id, new_green_time },
+                    );
+                    log_event.message = format!("Recommended {}s green for junction {} phase {} (lane {})",
+                                                new_green_time, junction, phase, lane_id);
+                    log_tx.send(log_event).ok();
+                }
+
+                // Timing plan pass: retime each junction's phases to the flows
+                // measured over the last window.
+                meter.record(&snapshot.departures, now);
+                let started = *started.get_or_insert(now);
+                if plan_timing && now >= started + WINDOW_SECS {
+                    let flows = meter.flows();
+                    let mut junctions: Vec<u32> = phase_plans.keys().copied().collect();
+                    junctions.sort_unstable();
+                    for junction in junctions {
+                        if plans.get(&junction).is_some_and(|&(at, _)| now < at + PLAN_INTERVAL_SECS) {
+                            continue;
+                        }
+                        let timing = timings.for_junction(junction);
+                        let lost_secs = (timing.amber + timing.all_red).as_secs_f64();
+                        let Some(plan) = webster::timing_plan(junction, &phase_plans[&junction], &flows, lost_secs) else {
+                            continue;
                         };
-                        println!("Congestion detected at lane {}", lane_id);
+                        let unchanged = plans.get(&junction).is_some_and(|(_, greens)| *greens == plan.greens);
+                        plans.insert(junction, (now, plan.greens.clone()));
+                        if unchanged {
+                            continue;
+                        }
+                        let message = format!("Timing plan for junction {}: {}s cycle, phase greens {:?}s",
+                                              junction, plan.cycle_secs, plan.greens);
+                        println!("{}", message);
+                        let _t = budget::time(Category::Transport);
+                        if let Err(e) = rec_tx.send(Recommendation::TimingPlan(plan)) {
+                            println!("Error sending recommendation: {}", e);
+                        }
+                        log_tx.send(LogEvent {
+                            source: "FlowAnalyzer".to_string(),
+                            message,
+                            timestamp: now,
+                            level: Level::Info,
+                            kind: EventKind::Generic,
+                        }).ok();
+                    }
+                }
 
+                // Pedestrian pass: resize the walk phases to last minute's
+                // arrivals, recommending only changes.
+                for update in pedestrian_rx.try_iter() {
+                    let mut arrivals: Vec<(u32, u32)> = update.arrivals.into_iter().collect();
+                    arrivals.sort();
+                    for (junction, pedestrians) in arrivals {
+                        let new_walk_time = walk_time_for(pedestrians, configured_walk);
+                        let current = walk_times.get(&junction).copied().unwrap_or(configured_walk);
+                        if new_walk_time == current {
+                            continue;
+                        }
+                        walk_times.insert(junction, new_walk_time);
+                        let message = if new_walk_time == 0 {
+                            format!("No pedestrians at junction {} in the last minute; skipping its walk phases", junction)
+                        } else {
+                            format!("Recommended {}s walk for junction {} ({} pedestrians in the last minute)",
+                                    new_walk_time, junction, pedestrians)
+                        };
+                        println!("{}", message);
+                        let rec = Recommendation::AdjustWalkTime { junction_id: junction, new_walk_time };
+                        let _t = budget::time(Category::Transport);
+                        if let Err(e) = rec_tx.send(rec) {
+                            println!("Error sending recommendation: {}", e);
+                        }
+                        log_tx.send(LogEvent {
+                            source: "FlowAnalyzer".to_string(),
+                            message,
+                            timestamp: now,
+                            level: Level::Info,
+                            kind: EventKind::Generic,
+                        }).ok();
+                    }
+                }
+
+                // Gridlock pass: a cluster of stuck lanes is routed around
+                // instead, since more green can't drain into a full lane.
+                for advisory in gridlock.observe(&snapshot.lanes, now) {
+                    println!("Gridlock detected on lanes {:?}; advising reroutes until {}",
+                             advisory.lanes, advisory.expires_at);
+                    let _t = budget::time(Category::Transport);
+                    log_tx.send(LogEvent::new(
+                        "FlowAnalyzer",
+                        now,
+                        EventKind::RerouteAdvisory { lanes: advisory.lanes.clone(), expires_at: advisory.expires_at },
+                    )).ok();
+                    // Stop feeding the cluster while it drains.
+                    let mut junctions: Vec<u32> =
+                        advisory.lanes.iter().filter_map(|lane_id| lane_junction.get(lane_id).copied()).collect();
+                    junctions.sort_unstable();
+                    junctions.dedup();
+                    if let Err(e) = advisory_tx.send(Recommendation::SuggestReroute(advisory)) {
+                        println!("Error sending reroute advisory: {}", e);
+                    }
+                    for junction_id in junctions {
+                        let message = format!("Holding junction {} all-red for {}s to let the gridlock drain",
+                                              junction_id, GRIDLOCK_ALL_RED_SECS);
+                        println!("{}", message);
+                        let rec = Recommendation::EmergencyAllRed {
+                            junction_id,
+                            duration_secs: GRIDLOCK_ALL_RED_SECS,
+                        };
                         if let Err(e) = rec_tx.send(rec) {
                             println!("Error sending recommendation: {}", e);
                         }
+                        log_tx.send(LogEvent {
+                            source: "FlowAnalyzer".to_string(),
+                            message,
+                            timestamp: now,
+                            level: Level::Warn,
+                            kind: EventKind::Generic,
+                        }).ok();
                     }
                 }
+                budget::flush("FlowAnalyzer");
             }
             Err(_) => {
                 println!("Analyzer: No more data. Exiting...");
@@ -87,4 +722,200 @@ pub fn run_flow_analyzer(analyzer_rx: Receiver<HashMap<u32,u32>>, rec_tx: Sender
         }
     }
 }
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lanes 1 and 2 approach junction 10, lane 3 approaches junction 20.
+    fn lane_junction() -> HashMap<u32, u32> {
+        HashMap::from([(1, 10), (2, 10), (3, 20)])
+    }
+
+    #[test]
+    fn junction_totals_sum_the_lanes_of_each_junction() {
+        // Lane 4 leaves the grid and belongs to no junction.
+        let lanes = HashMap::from([(1, 3), (2, 4), (3, 5), (4, 9)]);
+        assert_eq!(junction_totals(&lanes, &lane_junction()), HashMap::from([(10, 7), (20, 5)]));
+    }
+
+    #[test]
+    fn spread_congestion_trips_the_junction_but_no_lane() {
+        let lane_junction = lane_junction();
+        let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        let mut junction_detector = CongestionDetector::new(WINDOW_SECS, JUNCTION_CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        // Lanes 1 and 2 hold 3 vehicles each: under the lane threshold, 6 together.
+        let lanes = HashMap::from([(1, 3), (2, 3), (3, 0)]);
+        let mut junction_green = None;
+        for now in 0..=SUSTAIN_SECS {
+            for (&lane_id, &count) in &lanes {
+                detector.record(lane_id, count, 1000, now);
+                assert_eq!(detector.evaluate(lane_id, now), None);
+            }
+            for (junction, total) in junction_totals(&lanes, &lane_junction) {
+                junction_detector.record(junction, total, 1000, now);
+                if let Some(green) = junction_detector.evaluate(junction, now) {
+                    junction_green = Some((junction, green));
+                }
+            }
+        }
+        assert_eq!(junction_green, Some((10, green_time_for(6.0, JUNCTION_CONGESTION_THRESHOLD))));
+        assert_eq!(junction_detector.average(20), Some(0.0));
+    }
+
+    #[test]
+    fn busiest_phase_carries_the_most_vehicles() {
+        let phases = [Phase { lanes: vec![1, 2] }, Phase { lanes: vec![3] }];
+        // Lane 3 is the busiest lane, but phase 0 carries more in total.
+        let lanes = HashMap::from([(1, 3), (2, 4), (3, 6)]);
+        assert_eq!(busiest_phase(&phases, &lanes), Some((0, 2)));
+        assert_eq!(busiest_phase(&[], &lanes), None);
+    }
+
+    /// Applies a `green_time` recommendation for lane 1 at `at` and settles
+    /// it a window later with the lane's average at `after`.
+    fn apply_and_settle(feedback: &mut FeedbackTracker, at: u64, before: f64, after: f64) -> FeedbackOutcome {
+        let applied = RecommendationApplied { lane_id: 1, applied_green_time: 20, timestamp: at };
+        feedback.applied(applied, before);
+        assert_eq!(feedback.settle(at + WINDOW_SECS - 1, |_| Some(after)), []);
+        feedback.settle(at + WINDOW_SECS, |_| Some(after)).remove(0)
+    }
+
+    #[test]
+    fn failing_recommendations_escalate_to_longer_greens_then_the_junction() {
+        let mut feedback = FeedbackTracker::new(WINDOW_SECS, CONGESTION_THRESHOLD, ESCALATE_AFTER_FAILURES);
+        let green = 20;
+        let mut escalations = Vec::new();
+        for round in 0..6 {
+            // The lane stays as full as it was.
+            let outcome = apply_and_settle(&mut feedback, round * WINDOW_SECS, 8.0, 8.0);
+            assert!(!outcome.helped);
+            assert_eq!(outcome.failures, round as u32 + 1);
+            escalations.push(feedback.escalation(1, green));
+        }
+        assert_eq!(escalations, [
+            Escalation::None,
+            Escalation::LongerGreen(green + ESCALATION_STEP_SECS),
+            Escalation::LongerGreen(green + 2 * ESCALATION_STEP_SECS),
+            Escalation::LongerGreen(green + 3 * ESCALATION_STEP_SECS),
+            Escalation::LongerGreen(green + 4 * ESCALATION_STEP_SECS),
+            Escalation::Junction,
+        ]);
+        // Other lanes are unaffected.
+        assert_eq!(feedback.escalation(2, green), Escalation::None);
+    }
+
+    #[test]
+    fn a_recommendation_that_helps_resets_the_escalation() {
+        let mut feedback = FeedbackTracker::new(WINDOW_SECS, CONGESTION_THRESHOLD, ESCALATE_AFTER_FAILURES);
+        for round in 0..ESCALATE_AFTER_FAILURES as u64 {
+            apply_and_settle(&mut feedback, round * WINDOW_SECS, 8.0, 9.0);
+        }
+        assert_ne!(feedback.escalation(1, 20), Escalation::None);
+        // Still above the threshold, but lower than before.
+        let outcome = apply_and_settle(&mut feedback, 10 * WINDOW_SECS, 9.0, 7.0);
+        assert!(outcome.helped);
+        assert_eq!(outcome.failures, 0);
+        assert_eq!(feedback.escalation(1, 20), Escalation::None);
+        // Higher than before, but no longer congested.
+        let outcome = apply_and_settle(&mut feedback, 20 * WINDOW_SECS, 1.0, CONGESTION_THRESHOLD - 0.5);
+        assert!(outcome.helped);
+    }
+
+    #[test]
+    fn reapplying_restarts_the_watch() {
+        let mut feedback = FeedbackTracker::new(WINDOW_SECS, CONGESTION_THRESHOLD, ESCALATE_AFTER_FAILURES);
+        feedback.applied(RecommendationApplied { lane_id: 1, applied_green_time: 20, timestamp: 0 }, 8.0);
+        feedback.applied(RecommendationApplied { lane_id: 1, applied_green_time: 30, timestamp: 30 }, 6.0);
+        assert_eq!(feedback.settle(WINDOW_SECS, |_| Some(9.0)), []);
+        let outcomes = feedback.settle(30 + WINDOW_SECS, |_| None);
+        // A lane with no samples left counts as empty.
+        assert_eq!(outcomes, [FeedbackOutcome {
+            lane_id: 1,
+            applied_green_time: 30,
+            before: 6.0,
+            after: 0.0,
+            helped: true,
+            failures: 0,
+        }]);
+    }
+
+    /// Records `count` for lane 1 every second from `from` to `to`, evaluating
+    /// each time, and returns the green times recommended with when.
+    fn hold(detector: &mut CongestionDetector, count: u32, from: u64, to: u64) -> Vec<(u64, u32)> {
+        (from..=to)
+            .filter_map(|now| {
+                detector.record(1, count, 1000, now);
+                detector.evaluate(1, now).map(|green| (now, green))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let mut detector = CongestionDetector::new(10, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        detector.record(1, 20, 1000, 0);
+        detector.record(1, 2, 1000, 5);
+        assert_eq!(detector.average(1), Some(11.0));
+        // The sample from 0 is exactly a window old at 10.
+        detector.record(1, 2, 1000, 10);
+        assert_eq!(detector.average(1), Some(2.0));
+        assert_eq!(detector.average(2), None);
+    }
+
+    #[test]
+    fn samples_weigh_by_the_time_they_stand_for() {
+        let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        // A burst of fast snapshots does not outweigh a slow one.
+        detector.record(1, 8, 3000, 0);
+        for now in 3..6 {
+            detector.record(1, 0, 100, now);
+        }
+        assert!((detector.average(1).unwrap() - 8.0 * 3000.0 / 3300.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn congestion_must_be_sustained_and_not_draining() {
+        let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        let count = CONGESTION_THRESHOLD as u32 + 2;
+        // Above the threshold for SUSTAIN_SECS before the first recommendation.
+        let recommended = hold(&mut detector, count, 0, SUSTAIN_SECS);
+        assert_eq!(recommended, [(SUSTAIN_SECS, green_time_for(count as f64, CONGESTION_THRESHOLD))]);
+
+        // A queue emptying fast is left to drain.
+        let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        let recommended: Vec<(u64, u32)> = (0..=SUSTAIN_SECS)
+            .filter_map(|now| {
+                detector.record(1, 40 - 2 * now as u32, 1000, now);
+                detector.evaluate(1, now).map(|green| (now, green))
+            })
+            .collect();
+        assert!(detector.trend(1).unwrap() <= DRAINING_TREND);
+        assert_eq!(recommended, []);
+    }
+
+    #[test]
+    fn congested_lane_waits_out_the_cooldown_and_clears_below_the_ratio() {
+        let mut detector = CongestionDetector::new(10, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+        let count = CONGESTION_THRESHOLD as u32 + 2;
+        let recommended = hold(&mut detector, count, 0, SUSTAIN_SECS + COOLDOWN_SECS);
+        let times: Vec<u64> = recommended.iter().map(|&(now, _)| now).collect();
+        assert_eq!(times, [SUSTAIN_SECS, SUSTAIN_SECS + COOLDOWN_SECS]);
+
+        // Dropping under the threshold but not under CLEAR_RATIO of it keeps
+        // the lane congested; it recommends again once the cooldown is over.
+        let just_under = (CONGESTION_THRESHOLD * CLEAR_RATIO).ceil() as u32;
+        assert!((just_under as f64) < CONGESTION_THRESHOLD);
+        let start = SUSTAIN_SECS + COOLDOWN_SECS + 1;
+        let recommended = hold(&mut detector, just_under, start, start + COOLDOWN_SECS);
+        assert_eq!(recommended.len(), 1, "{:?}", recommended);
+
+        // Under CLEAR_RATIO it clears, and has to build up again from scratch.
+        let start = start + COOLDOWN_SECS + 1;
+        assert_eq!(hold(&mut detector, 0, start, start + 20), []);
+        let start = start + 21;
+        let recommended = hold(&mut detector, count, start, start + COOLDOWN_SECS);
+        let first = recommended.first().map(|&(now, _)| now);
+        assert!(first.is_some_and(|now| now >= start + SUSTAIN_SECS), "{:?}", recommended);
+    }
+}
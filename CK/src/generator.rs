@@ -0,0 +1,181 @@
+// generator.rs
+//
+// Continuous demand fed lane by lane. With `--duration SECS` (or
+// RTS_GENERATOR_SECS) the simulation no longer spawns a fixed number of
+// vehicles: for SECS seconds of wall-clock time every input lane spawns its
+// own Poisson stream of vehicles, which enter the grid on that lane. The run
+// then ends once the last of them has finished.
+//
+// `--lane-rates` (or RTS_LANE_RATES) sets each lane's rate in vehicles per
+// simulated minute, e.g. `1010=6,1016=4,*=1`; `*` covers every input lane
+// not listed and defaults to DEFAULT_LANE_RATE. A rate of 0 closes a lane to
+// new vehicles. Invalid settings are ignored with a warning.
+//
+// A scenario (see `scenario`) can instead give lanes a RateProfile, a rate
+// that changes over the run such as a rush hour ramp; those lanes' arrivals
+// are drawn by thinning a stream at the profile's peak rate.
+//
+// The arrival times are drawn up front from the run seed, so a seed (at the
+// same time scale) always spawns the same vehicles at the same times.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use rand::Rng;
+
+use rts_core::lanes::Lane;
+
+/// Vehicles per simulated minute on an input lane without a rate of its own.
+pub const DEFAULT_LANE_RATE: f64 = 0.5;
+
+/// One generated vehicle: when it spawns, in simulated time after the run
+/// started, and the input lane it enters on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arrival {
+    pub at: Duration,
+    pub entry_lane: u32,
+}
+
+/// A lane's rate over the run, in vehicles per simulated minute: given at
+/// points in simulated seconds after the run started, linear between them
+/// and held before the first and after the last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateProfile {
+    points: Vec<(f64, f64)>,
+}
+
+impl RateProfile {
+    /// A profile through `points` of (seconds, rate), in any order.
+    pub fn new(mut points: Vec<(f64, f64)>) -> Result<RateProfile, String> {
+        if points.is_empty() {
+            return Err("a rate profile needs at least one point".to_string());
+        }
+        if let Some(&(at, rate)) = points.iter().find(|&&(at, rate)| !(at >= 0.0 && at.is_finite() && rate >= 0.0 && rate.is_finite())) {
+            return Err(format!("invalid rate profile point [{}, {}]", at, rate));
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(RateProfile { points })
+    }
+
+    /// Rate `secs` simulated seconds after the run started.
+    pub fn at(&self, secs: f64) -> f64 {
+        let after = self.points.partition_point(|&(at, _)| at <= secs);
+        match (after.checked_sub(1).map(|i| self.points[i]), self.points.get(after)) {
+            (Some((t0, r0)), Some(&(t1, r1))) => r0 + (r1 - r0) * (secs - t0) / (t1 - t0),
+            (Some((_, rate)), None) | (None, Some(&(_, rate))) => rate,
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Highest rate anywhere in the profile.
+    fn peak(&self) -> f64 {
+        self.points.iter().map(|&(_, rate)| rate).fold(0.0, f64::max)
+    }
+}
+
+/// Per-lane arrival rates and how long to keep generating.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    /// Wall-clock time to generate vehicles for.
+    pub duration: Duration,
+    /// Rate of every input lane not in `lane_rates`.
+    pub default_rate: f64,
+    /// Rates by input lane id, vehicles per simulated minute.
+    pub lane_rates: BTreeMap<u32, f64>,
+    /// Lanes whose rate changes over the run; these ignore the rates above.
+    pub profiles: BTreeMap<u32, RateProfile>,
+}
+
+impl Generator {
+    /// The generator asked for by `--duration` and `--lane-rates` in `args`,
+    /// or RTS_GENERATOR_SECS and RTS_LANE_RATES, if a duration is set.
+    /// Rates may only name lanes in `input_lanes`.
+    pub fn from_args(args: &[String], input_lanes: &[Lane]) -> Option<Generator> {
+        let (source, value) = setting(args, "--duration", "RTS_GENERATOR_SECS")?;
+        let duration = match value.trim().parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+            _ => {
+                eprintln!("Ignoring {}: expected a positive number of seconds, got '{}'", source, value.trim());
+                return None;
+            }
+        };
+        let mut generator =
+            Generator { duration, default_rate: DEFAULT_LANE_RATE, lane_rates: BTreeMap::new(), profiles: BTreeMap::new() };
+        if let Some((source, value)) = setting(args, "--lane-rates", "RTS_LANE_RATES") {
+            match parse_lane_rates(&value, input_lanes) {
+                Ok((default_rate, lane_rates)) => {
+                    generator.default_rate = default_rate.unwrap_or(DEFAULT_LANE_RATE);
+                    generator.lane_rates = lane_rates;
+                }
+                Err(e) => eprintln!("Ignoring {}: {}", source, e),
+            }
+        }
+        Some(generator)
+    }
+
+    /// Vehicles per simulated minute entering on `lane_id`.
+    pub fn rate(&self, lane_id: u32) -> f64 {
+        self.lane_rates.get(&lane_id).copied().unwrap_or(self.default_rate)
+    }
+
+    /// Every vehicle to spawn within `span` of simulated time, in spawn order:
+    /// each of `input_lanes` an independent Poisson stream at its rate, or
+    /// following its profile.
+    pub fn schedule(&self, input_lanes: &[Lane], span: Duration, rng: &mut impl Rng) -> Vec<Arrival> {
+        let mut arrivals = Vec::new();
+        for lane in input_lanes {
+            let profile = self.profiles.get(&lane.id);
+            let rate = profile.map_or_else(|| self.rate(lane.id), RateProfile::peak);
+            if rate <= 0.0 {
+                continue;
+            }
+            let mut at = 0.0;
+            loop {
+                at += -(1.0 - rng.random::<f64>()).ln() * 60.0 / rate;
+                if at >= span.as_secs_f64() {
+                    break;
+                }
+                // Under a profile, keep each candidate with the share of the peak the rate then is.
+                if profile.is_some_and(|profile| rng.random::<f64>() * rate >= profile.at(at)) {
+                    continue;
+                }
+                arrivals.push(Arrival { at: Duration::from_secs_f64(at), entry_lane: lane.id });
+            }
+        }
+        arrivals.sort_by_key(|arrival| (arrival.at, arrival.entry_lane));
+        arrivals
+    }
+}
+
+/// Parses lane rates such as `1010=6,1016=4,*=1` into the rate for unlisted
+/// lanes, if given, and the rate of each listed lane.
+pub fn parse_lane_rates(spec: &str, input_lanes: &[Lane]) -> Result<(Option<f64>, BTreeMap<u32, f64>), String> {
+    let mut default_rate = None;
+    let mut lane_rates = BTreeMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (lane, rate) = entry.split_once('=').ok_or_else(|| format!("expected lane=rate, got '{}'", entry))?;
+        let rate = match rate.trim().parse::<f64>() {
+            Ok(rate) if rate >= 0.0 && rate.is_finite() => rate,
+            _ => return Err(format!("invalid rate '{}' for lane {}", rate.trim(), lane.trim())),
+        };
+        if lane.trim() == "*" {
+            default_rate = Some(rate);
+            continue;
+        }
+        let lane_id: u32 = lane.trim().parse().map_err(|_| format!("invalid lane id '{}'", lane.trim()))?;
+        if !input_lanes.iter().any(|input| input.id == lane_id) {
+            return Err(format!("lane {} is not an input lane", lane_id));
+        }
+        lane_rates.insert(lane_id, rate);
+    }
+    Ok((default_rate, lane_rates))
+}
+
+/// The value following `flag` in `args`, or else `var` from the environment,
+/// with the name it came from.
+fn setting(args: &[String], flag: &'static str, var: &'static str) -> Option<(&'static str, String)> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => Some((flag, args.get(i + 1).cloned().unwrap_or_default())),
+        None => std::env::var(var).ok().map(|value| (var, value)),
+    }
+}
@@ -0,0 +1,206 @@
+// junction_box.rs
+//
+// The space inside each junction. A vehicle that has passed its light spends
+// CROSSING_SECS crossing the junction into its next lane, and may only start
+// while nobody inside is making a movement that crosses or merges with its
+// own (see `rts_core::phase_plan::JunctionGeometry`). The phase plan already
+// keeps conflicting approaches from being green together; the box also holds
+// back a vehicle whose light turned green while one from the previous phase
+// is still clearing the junction.
+//
+// Each box also counts its crossings, the vehicles that had to wait for it
+// and for how long, and the time it had anyone inside, for the utilization
+// figures in the summary, and the vehicles it has taken from each approach,
+// for the flows the analyzer's timing plans are based on.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use rts_core::clock::SimClock;
+use rts_core::lanes::{load_lanes, Lane};
+use rts_core::network::{load_network, Network};
+use rts_core::phase_plan::JunctionGeometry;
+
+use crate::budget::{self, Category};
+use crate::summary::JunctionUtilization;
+
+/// Simulated seconds a vehicle spends inside a junction.
+pub const CROSSING_SECS: f64 = 1.0;
+
+/// A vehicle inside a junction.
+#[derive(Debug, Clone, Copy)]
+struct Crossing {
+    car_id: u32,
+    approach: u32,
+    exit: u32,
+}
+
+/// One junction's box: who is inside, and its utilization so far. Times are
+/// simulated time since the start of the run.
+#[derive(Debug)]
+pub struct JunctionBox {
+    geometry: JunctionGeometry,
+    inside: Vec<Crossing>,
+    /// When the box last went from empty to occupied, while occupied.
+    busy_since: Option<Duration>,
+    busy: Duration,
+    crossings: u32,
+    waits: u32,
+    wait_secs: f64,
+    /// Vehicles that have entered from each approach lane.
+    departures: HashMap<u32, u32>,
+}
+
+impl JunctionBox {
+    fn new(geometry: JunctionGeometry) -> Self {
+        JunctionBox { geometry, inside: Vec::new(), busy_since: None, busy: Duration::ZERO, crossings: 0, waits: 0, wait_secs: 0.0, departures: HashMap::new() }
+    }
+
+    /// True if a vehicle going from `approach` into `exit` conflicts with
+    /// nobody inside.
+    pub fn fits(&self, approach: u32, exit: u32) -> bool {
+        self.inside.iter().all(|other| !self.geometry.conflict((approach, exit), (other.approach, other.exit)))
+    }
+
+    /// Lets the vehicle in at `now`, after it waited `waited` seconds for room.
+    pub fn enter(&mut self, car_id: u32, approach: u32, exit: u32, now: Duration, waited: f64) {
+        if self.inside.is_empty() {
+            self.busy_since = Some(now);
+        }
+        self.inside.push(Crossing { car_id, approach, exit });
+        self.crossings += 1;
+        *self.departures.entry(approach).or_insert(0) += 1;
+        if waited > 0.0 {
+            self.waits += 1;
+            self.wait_secs += waited;
+        }
+    }
+
+    /// The vehicle has left the junction at `now`.
+    pub fn leave(&mut self, car_id: u32, now: Duration) {
+        self.inside.retain(|crossing| crossing.car_id != car_id);
+        if self.inside.is_empty() {
+            if let Some(since) = self.busy_since.take() {
+                self.busy += now.saturating_sub(since);
+            }
+        }
+    }
+
+    /// Utilization of junction `junction` over a run of `duration`.
+    pub fn utilization(&self, junction: u32, duration: Duration) -> JunctionUtilization {
+        let busy = match self.busy_since {
+            Some(since) => self.busy + duration.saturating_sub(since),
+            None => self.busy,
+        };
+        let duration_secs = duration.as_secs_f64();
+        JunctionUtilization {
+            junction,
+            crossings: self.crossings,
+            busy_secs: busy.as_secs_f64(),
+            utilization: if duration_secs > 0.0 { busy.as_secs_f64() / duration_secs } else { 0.0 },
+            waits: self.waits,
+            wait_secs: self.wait_secs,
+        }
+    }
+}
+
+/// A box for every junction some lane ends at.
+pub fn junction_boxes(lanes: &[Lane], network: &Network) -> HashMap<u32, JunctionBox> {
+    let mut boxes = HashMap::new();
+    for lane in lanes.iter().filter(|lane| lane.end_intersection != 0) {
+        boxes
+            .entry(lane.end_intersection)
+            .or_insert_with(|| JunctionBox::new(JunctionGeometry::new(lane.end_intersection, lanes, network)));
+    }
+    boxes
+}
+
+/// Sorted by junction; only junctions some vehicle crossed.
+pub fn utilization(boxes: &HashMap<u32, JunctionBox>, duration: Duration) -> Vec<JunctionUtilization> {
+    let mut utilization: Vec<JunctionUtilization> = boxes
+        .iter()
+        .filter(|(_, junction_box)| junction_box.crossings > 0)
+        .map(|(&junction, junction_box)| junction_box.utilization(junction, duration))
+        .collect();
+    utilization.sort_by_key(|junction| junction.junction);
+    utilization
+}
+
+/// Vehicles that have left each approach lane into its junction since the
+/// run started.
+pub fn departures(boxes: &HashMap<u32, JunctionBox>) -> HashMap<u32, u32> {
+    boxes
+        .values()
+        .flat_map(|junction_box| junction_box.departures.iter().map(|(&lane_id, &count)| (lane_id, count)))
+        .collect()
+}
+
+/// The junction boxes shared by the car threads. A car blocks in `cross`
+/// until its movement fits, and everyone waiting is woken whenever a car
+/// leaves a junction.
+pub struct JunctionBoxes {
+    boxes: Mutex<HashMap<u32, JunctionBox>>,
+    cleared: Condvar,
+    clock: SimClock,
+    start: Instant,
+}
+
+impl JunctionBoxes {
+    /// Boxes for every junction of the network, with the run starting now.
+    pub fn new(clock: SimClock) -> Self {
+        JunctionBoxes {
+            boxes: Mutex::new(junction_boxes(&load_lanes(), &load_network())),
+            cleared: Condvar::new(),
+            clock,
+            start: Instant::now(),
+        }
+    }
+
+    /// Blocks until the car may cross `junction` from `approach` into `exit`,
+    /// then crosses it, taking CROSSING_SECS. Returns the seconds it waited
+    /// to get in. A junction without a box is crossed without waiting.
+    pub fn cross(&self, junction: u32, car_id: u32, approach: u32, exit: u32) -> f64 {
+        let waited = {
+            let mut boxes = {
+                let _t = budget::time_lock();
+                self.boxes.lock().unwrap()
+            };
+            let arrived = self.clock.since(self.start);
+            let mut blocked = false;
+            while boxes.get(&junction).is_some_and(|junction_box| !junction_box.fits(approach, exit)) {
+                blocked = true;
+                boxes = self.cleared.wait(boxes).unwrap();
+            }
+            let now = self.clock.since(self.start);
+            let waited = if blocked { (now - arrived).as_secs_f64() } else { 0.0 };
+            if let Some(junction_box) = boxes.get_mut(&junction) {
+                junction_box.enter(car_id, approach, exit, now, waited);
+            }
+            waited
+        };
+        {
+            let _t = budget::time(Category::Sleep);
+            self.clock.sleep(Duration::from_secs_f64(CROSSING_SECS));
+        }
+        let mut boxes = {
+            let _t = budget::time_lock();
+            self.boxes.lock().unwrap()
+        };
+        if let Some(junction_box) = boxes.get_mut(&junction) {
+            junction_box.leave(car_id, self.clock.since(self.start));
+        }
+        self.cleared.notify_all();
+        waited
+    }
+
+    /// Departures from every approach lane so far; see `departures`.
+    pub fn departures(&self) -> HashMap<u32, u32> {
+        departures(&self.boxes.lock().unwrap())
+    }
+
+    /// Utilization of every junction crossed so far; see `utilization`.
+    pub fn utilization(&self) -> Vec<JunctionUtilization> {
+        utilization(&self.boxes.lock().unwrap(), self.clock.since(self.start))
+    }
+}
@@ -1,59 +1,213 @@
 mod simulation;
 mod traffic_light;
 mod system_monitoring;
-mod lanes;
 mod flow_analyzer;
+mod budget;
+mod shutdown;
+mod vehicle;
+mod summary;
+mod signal_timing;
+mod gridlock;
+mod csv_sink;
+mod dashboard;
+#[cfg(feature = "tui")]
+mod tui;
+mod crossings;
+mod scenario;
+mod replay;
+mod generator;
+mod od_matrix;
+mod junction_box;
+mod coordination;
+mod engine;
+mod log_scope;
+#[cfg(feature = "sqlite")]
+mod sqlite_sink;
 
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 use std::thread;
 use std::sync::mpsc;
 
-use simulation::{run_simulation};
+use simulation::{run_simulation, AnalyzerLinks, LaneSnapshot, LatestCounts, RunVehicles};
 use traffic_light::{run_traffic_lights, initialize_traffic_lights, TrafficLightMap};
-use system_monitoring::LogEvent;
-use flow_analyzer::{run_flow_analyzer,Recommendation};
+use system_monitoring::{LogEvent, Sinks};
+use flow_analyzer::{run_flow_analyzer, Recommendation};
+use rts_core::messages::RecommendationApplied;
+use engine::EngineKind;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("query") {
+        run_query(&args[2..]);
+        return;
+    }
+
     println!("=== Real-Time 16-Junction Traffic Simulation ===");
+    budget::init_from_env();
+    let clock = rts_core::clock::SimClock::from_env();
+    if clock.is_accelerated() {
+        println!("Running at {}x real time", clock.scale());
+    }
+
+    // Optional structured outputs: a CSV export of car journeys and phase
+    // changes, and (with the sqlite feature) the full event stream.
+    let csv = match csv_sink::csv_path_from(&args) {
+        Some(path) => match csv_sink::CsvSink::create(&path) {
+            Ok(sink) => {
+                println!("Writing journey and phase records to {}", path);
+                Some(sink)
+            }
+            Err(e) => {
+                eprintln!("Failed to create CSV file {}: {}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(feature = "sqlite")]
+    let sqlite = match sqlite_sink::sqlite_path_from(&args) {
+        Some(path) => match sqlite_sink::SqliteSink::create(std::path::Path::new(&path)) {
+            Ok(sink) => {
+                println!("Writing event stream to {} as run {}", path, sink.run_id());
+                Some(sink)
+            }
+            Err(e) => {
+                eprintln!("Failed to open SQLite database {}: {}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "sqlite"))]
+    if std::env::var("RTS_SQLITE_PATH").is_ok() || args.iter().any(|arg| arg == "--sqlite") {
+        eprintln!("SQLite output requested but RTS was built without the sqlite feature");
+    }
+    let sinks = Sinks {
+        csv,
+        #[cfg(feature = "sqlite")]
+        sqlite,
+    };
+    // A replay that cannot be used stops the run before anything starts.
+    let replay = replay::Replay::from_env(&rts_core::lanes::load_lanes()).unwrap_or_else(|e| {
+        eprintln!("Cannot replay RTS_REPLAY: {}", e);
+        std::process::exit(1);
+    });
+    budget::flush("Main");
+
+    // `--cars` and `--arrival-rate` (or SIM_CARS and SIM_ARRIVAL_RATE) set the demand.
+    // `--duration` and `--lane-rates` (or RTS_GENERATOR_SECS and RTS_LANE_RATES)
+    // generate them lane by lane for a while instead (see `generator`).
+    let input_lanes = rts_core::trips::BoundaryLanes::from_lanes(&rts_core::lanes::load_lanes()).entry;
+    // Ctrl-C stops spawning vehicles; the run then winds down as if it had ended.
+    let interrupted = shutdown::new_flag();
+    shutdown::raise_on_interrupt(&interrupted);
+    // `--dashboard` replaces the event log with a live view of the run;
+    // `--tui` draws it full-screen (see `tui`).
+    let dashboard = dashboard::requested(&args, &interrupted);
+    let vehicles = RunVehicles {
+        demand: rts_core::demand::Demand::from_args(&args),
+        generator: generator::Generator::from_args(&args, &input_lanes),
+        replay,
+        interrupted,
+        // `--export <path>` writes the cars' journeys and the lane counts
+        // when the run ends (see `rts_core::export`).
+        export: rts_core::export::Export::from_args(&args),
+    };
+    // `--engine events` (or RTS_ENGINE=events) runs the cars and junctions as
+    // discrete events in one thread instead (see `engine`), and refuses the
+    // features only the thread engine has.
+    let engine_kind = EngineKind::from_env();
+    if engine_kind == EngineKind::Events {
+        if let Err(e) = engine::check_supported(&vehicles) {
+            eprintln!("Cannot run --engine events: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     // Initialize traffic lights for all lanes that require control.
     // All lights are initialized to Red so that not all are green at startup.
     let traffic_lights: TrafficLightMap = initialize_traffic_lights();
 
     //channel for recommendation
-    let (analyzer_tx, analyzer_rx) = mpsc::channel::<HashMap<u32,u32>>();
+    let (analyzer_tx, analyzer_rx) = mpsc::channel::<LaneSnapshot>();
     let (rec_tx, rec_rx) = mpsc::channel::<Recommendation>();
+    let (advisory_tx, advisory_rx) = mpsc::channel::<Recommendation>();
+    let (applied_tx, applied_rx) = mpsc::channel::<RecommendationApplied>();
+    let (pedestrian_tx, pedestrian_rx) = mpsc::channel::<crossings::PedestrianUpdate>();
+
+    // Lane counts of the latest snapshot, for adaptive junctions.
+    let latest_counts: LatestCounts = Arc::default();
 
     // Channel for log events.
     let (log_tx, log_rx) = mpsc::channel::<LogEvent>();
 
-    // Start the Traffic Light Controller.
-    // This call spawns a thread per junction internally.   
-    let tl_traffic_lights = Arc::clone(&traffic_lights);
-    let tl_log_tx = log_tx.clone();
-    thread::spawn(move || {
-        run_traffic_lights(tl_traffic_lights, tl_log_tx, rec_rx);
-    });
+    // Raised once the simulation is over so the controller threads stop.
+    let shutdown_flag = shutdown::new_flag();
+
+    // Scenario signal overrides share the analyzer's path to the controller.
+    let scenario_rec_tx = rec_tx.clone();
 
-    //start the flow analyzer thread
-    thread::spawn(move || {
-        run_flow_analyzer(analyzer_rx,rec_tx);
+    //start the flow analyzer thread; it exits when the simulation drops analyzer_tx
+    let analyzer_log_tx = log_tx.clone();
+    let analyzer_handle = thread::spawn(move || {
+        run_flow_analyzer(analyzer_rx, rec_tx, advisory_tx, applied_rx, pedestrian_rx, analyzer_log_tx, clock);
     });
 
+    let analyzer_links = AnalyzerLinks { snapshots: analyzer_tx, advisories: advisory_rx, pedestrians: pedestrian_tx };
+    let (traffic_light_handle, simulation_handle) = match engine_kind {
+        EngineKind::Threads => {
+            // Start the Traffic Light Controller.
+            // This call spawns a thread per junction internally.
+            let tl_traffic_lights = Arc::clone(&traffic_lights);
+            let tl_log_tx = log_tx.clone();
+            let tl_shutdown = Arc::clone(&shutdown_flag);
+            let tl_latest_counts = Arc::clone(&latest_counts);
+            let traffic_light_handle = thread::spawn(move || {
+                run_traffic_lights(tl_traffic_lights, tl_log_tx, rec_rx, applied_tx, tl_latest_counts, tl_shutdown, clock);
+            });
 
-    // Spawn the Simulation Engine thread (which spawns 30 car threads).
-    let sim_traffic_lights = Arc::clone(&traffic_lights);
-    let simulation_handle = thread::spawn(move || {
-        run_simulation(sim_traffic_lights, log_tx, analyzer_tx);
-    });
+            // Spawn the Simulation Engine thread (which spawns a thread per car).
+            let sim_traffic_lights = Arc::clone(&traffic_lights);
+            let simulation_handle = thread::spawn(move || {
+                run_simulation(sim_traffic_lights, log_tx, analyzer_links, latest_counts, scenario_rec_tx, vehicles, clock);
+            });
+            (Some(traffic_light_handle), simulation_handle)
+        }
+        EngineKind::Events => {
+            let simulation_handle = thread::spawn(move || {
+                engine::run_events(log_tx, analyzer_links, rec_rx, applied_tx, vehicles, clock);
+            });
+            (None, simulation_handle)
+        }
+    };
 
-    // Spawn the System Monitoring thread.
-    let _monitoring_handle = thread::spawn(move || {
-        system_monitoring::run_monitoring(log_rx);
+    // Spawn the System Monitoring thread; it exits once every log sender is gone.
+    let monitoring_handle = thread::spawn(move || {
+        system_monitoring::run_monitoring(log_rx, sinks, dashboard, clock);
     });
 
     simulation_handle.join().unwrap();
-    // Give some time for pending log messages.
-    thread::sleep(std::time::Duration::from_secs(1));
+
+    // Stop the controller, then let the analyzer and monitoring drain and exit.
+    shutdown::request(&shutdown_flag);
+    if let Some(handle) = traffic_light_handle {
+        handle.join().unwrap();
+    }
+    analyzer_handle.join().unwrap();
+    monitoring_handle.join().unwrap();
     println!("Simulation complete. Exiting.");
-}
\ No newline at end of file
+}
+/// `RTS query <db> ...`: runs SQL against a database written with --sqlite.
+#[cfg(feature = "sqlite")]
+fn run_query(args: &[String]) {
+    if let Err(e) = sqlite_sink::query_command(args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn run_query(_args: &[String]) {
+    eprintln!("RTS was built without the sqlite feature; rebuild with --features sqlite");
+    std::process::exit(1);
+}
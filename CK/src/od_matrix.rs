@@ -0,0 +1,142 @@
+// od_matrix.rs
+//
+// Where vehicles leave the grid. Without a matrix a car's exit lane is drawn
+// uniformly from the output lanes. A scenario's `destinations` (see
+// `scenario`) gives input lanes destination weights instead, e.g.
+//
+//   "destinations": {
+//     "1010": {"1040": 3, "1041": 1},
+//     "*": {"1045": 2, "*": 1}
+//   }
+//
+// sends cars entering on 1010 to 1040 three times as often as to 1041 and
+// nowhere else. `*` as an entry covers the input lanes not listed, and as a
+// destination the output lanes a row does not name. Weights are relative and
+// must not be negative. Input lanes with no row of their own and no `*` row
+// keep drawing their exit uniformly.
+//
+// Every pair an input lane's own row names must be a trip a car can make:
+// it must leave the grid at another junction than it entered, with a chain
+// of internal lanes between them. Pairs a `*` stands for are kept only if
+// they are. The matrix is checked when the scenario is loaded, and every
+// input lane with weights must have somewhere to go.
+
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use rts_core::lanes::{Lane, LaneCategory};
+use rts_core::network::Network;
+use rts_core::routing::find_lane_path;
+
+/// Destination weights of the input lanes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OdMatrix {
+    /// Output lanes and their weights, by input lane; only positive weights.
+    rows: BTreeMap<u32, Vec<(u32, f64)>>,
+}
+
+impl OdMatrix {
+    /// Builds the matrix of a scenario's `destinations` and checks it against
+    /// `lanes`: every lane named must be an input or output lane as its place
+    /// requires, and every pair named must be a possible trip.
+    pub fn parse(spec: &BTreeMap<String, BTreeMap<String, f64>>, lanes: &[Lane]) -> Result<OdMatrix, String> {
+        let network = Network::from_lanes(lanes);
+        let internal: Vec<Lane> = lanes.iter().filter(|lane| lane.category == LaneCategory::Internal).cloned().collect();
+        let of_category = |category: LaneCategory| -> Vec<&Lane> {
+            lanes.iter().filter(|lane| lane.category == category).collect()
+        };
+        let (entries, exits) = (of_category(LaneCategory::InputBoundary), of_category(LaneCategory::OutputBoundary));
+        let lane_of = |id: &str, among: &[&Lane], what: &str| -> Result<Lane, String> {
+            let lane_id: u32 = id.parse().map_err(|_| format!("invalid lane id '{}' in destinations", id))?;
+            among
+                .iter()
+                .find(|lane| lane.id == lane_id)
+                .map(|&lane| lane.clone())
+                .ok_or_else(|| format!("lane {} is not an {} lane", lane_id, what))
+        };
+        let possible = |entry: &Lane, exit: &Lane| {
+            let (start, end) = (entry.end_intersection, exit.start_intersection);
+            start != end && find_lane_path(start, end, &internal, &network, None).is_ok()
+        };
+
+        let mut rows = BTreeMap::new();
+        let mut wildcard_row = None;
+        for (entry_id, destinations) in spec {
+            let named_entries: Vec<Lane> = if entry_id == "*" {
+                Vec::new()
+            } else {
+                vec![lane_of(entry_id, &entries, "input")?]
+            };
+            let mut named = Vec::new();
+            let mut others = None;
+            for (exit_id, &weight) in destinations {
+                if !(weight >= 0.0 && weight.is_finite()) {
+                    return Err(format!("destination {} of lane {}: invalid weight {}", exit_id, entry_id, weight));
+                }
+                if exit_id == "*" {
+                    others = Some(weight);
+                } else {
+                    named.push((lane_of(exit_id, &exits, "output")?, weight));
+                }
+            }
+            let row_entries: Vec<&Lane> = if entry_id == "*" {
+                entries.iter().copied().filter(|lane| !spec.contains_key(&lane.id.to_string())).collect()
+            } else {
+                named_entries.iter().collect()
+            };
+            let mut built = Vec::new();
+            for &entry in &row_entries {
+                let mut row: Vec<(u32, f64)> = Vec::new();
+                for (exit, weight) in &named {
+                    if possible(entry, exit) {
+                        row.push((exit.id, *weight));
+                    } else if entry_id != "*" {
+                        return Err(format!("no trip from lane {} to lane {}", entry.id, exit.id));
+                    }
+                }
+                if let Some(weight) = others {
+                    let unnamed = exits.iter().filter(|exit| !named.iter().any(|(lane, _)| lane.id == exit.id));
+                    row.extend(unnamed.filter(|exit| possible(entry, exit)).map(|exit| (exit.id, weight)));
+                }
+                row.retain(|&(_, weight)| weight > 0.0);
+                if row.is_empty() {
+                    return Err(format!("lane {} has no destination", entry.id));
+                }
+                built.push((entry.id, row));
+            }
+            if entry_id == "*" {
+                wildcard_row = Some(built);
+            } else {
+                rows.extend(built);
+            }
+        }
+        rows.extend(wildcard_row.into_iter().flatten());
+        Ok(OdMatrix { rows })
+    }
+
+    /// True if every input lane draws its exit uniformly.
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Input lanes with destination weights of their own.
+    pub fn entry_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Draws the output lane of a car entering on `entry_lane` by the
+    /// weights of its row; None, without drawing, if it has none.
+    pub fn draw(&self, entry_lane: u32, rng: &mut impl Rng) -> Option<u32> {
+        let row = self.rows.get(&entry_lane)?;
+        let total: f64 = row.iter().map(|&(_, weight)| weight).sum();
+        let mut pick = rng.random::<f64>() * total;
+        for &(exit, weight) in row {
+            pick -= weight;
+            if pick < 0.0 {
+                return Some(exit);
+            }
+        }
+        row.last().map(|&(exit, _)| exit)
+    }
+}
@@ -0,0 +1,160 @@
+// replay.rs
+//
+// Recorded demand, for comparing signal strategies on the same traffic.
+// RTS_RECORD names a file the simulation writes every vehicle that drove to:
+// when it spawned, in simulated seconds after the run started, its kind,
+// speed and entry and exit lanes. RTS_REPLAY names such a file to spawn the
+// vehicles from instead of drawing them, at their recorded times; the
+// controller, the analyzer and any scenario closures or overrides still run
+// live. Scenario bursts spawn nothing while replaying, since their vehicles
+// are in the file.
+//
+// A replay file is a JSON array ordered by car id, e.g.
+//
+//   [
+//     {"car_id": 1, "spawn_at": 0, "kind": "car", "speed": 78.5, "entry_lane": 1001, "exit_lane": 1120},
+//     {"car_id": 31, "spawn_at": 30, "kind": "bus", "speed": 51.2, "entry_lane": 1010, "exit_lane": 1124}
+//   ]
+//
+// Nothing in it depends on thread scheduling, so runs with the same RTS_SEED
+// record byte-identical files. A replayed run's summary carries the file's
+// hash. Vehicles that found no valid trip never drove and are not recorded.
+
+use std::fs;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{self, Category};
+use crate::vehicle::{Vehicle, VehicleKind};
+use rts_core::lanes::{Lane, LaneCategory};
+
+/// One vehicle of a recorded run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayVehicle {
+    pub car_id: u32,
+    /// Simulated seconds after the run started.
+    pub spawn_at: u64,
+    pub kind: VehicleKind,
+    pub speed: f64,
+    pub entry_lane: u32,
+    pub exit_lane: u32,
+}
+
+impl ReplayVehicle {
+    /// The vehicle to launch, with its recorded trip.
+    pub fn vehicle(&self) -> Vehicle {
+        Vehicle {
+            id: self.car_id,
+            kind: self.kind,
+            speed: self.speed,
+            trip_seed: 0,
+            replayed_trip: Some((self.entry_lane, self.exit_lane)),
+        }
+    }
+}
+
+/// A validated replay file, in spawn order.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub path: String,
+    /// Hash of the file's contents (see `file_hash`).
+    pub hash: String,
+    pub vehicles: Vec<ReplayVehicle>,
+}
+
+impl Replay {
+    /// Parses a replay file's contents and checks every vehicle's trip
+    /// against `lanes`: the entry must be an input lane and the exit an
+    /// output lane.
+    pub fn parse(path: &str, contents: &[u8], lanes: &[Lane]) -> Result<Replay, String> {
+        let mut vehicles: Vec<ReplayVehicle> = {
+            let _t = budget::time(Category::Serialization);
+            serde_json::from_slice(contents).map_err(|e| format!("invalid replay file {}: {}", path, e))?
+        };
+        let check = |car_id: u32, lane_id: u32, expected: LaneCategory, role: &str| {
+            match lanes.iter().find(|lane| lane.id == lane_id) {
+                None => Err(format!("car {} uses lane {}, which is not in this topology", car_id, lane_id)),
+                Some(lane) if lane.category != expected => {
+                    Err(format!("car {} uses lane {}, which is not an {} lane", car_id, lane_id, role))
+                }
+                Some(_) => Ok(()),
+            }
+        };
+        for vehicle in &vehicles {
+            check(vehicle.car_id, vehicle.entry_lane, LaneCategory::InputBoundary, "input")?;
+            check(vehicle.car_id, vehicle.exit_lane, LaneCategory::OutputBoundary, "output")?;
+            if !(vehicle.speed > 0.0 && vehicle.speed.is_finite()) {
+                return Err(format!("car {} has invalid speed {}", vehicle.car_id, vehicle.speed));
+            }
+        }
+        vehicles.sort_by_key(|vehicle| (vehicle.spawn_at, vehicle.car_id));
+        Ok(Replay { path: path.to_string(), hash: file_hash(contents), vehicles })
+    }
+
+    /// The replay named by RTS_REPLAY, if it is set. Unlike most settings an
+    /// unusable file is an error rather than ignored, since running with
+    /// fresh demand instead would make the comparison meaningless.
+    pub fn from_env(lanes: &[Lane]) -> Result<Option<Replay>, String> {
+        let Ok(path) = std::env::var("RTS_REPLAY") else {
+            return Ok(None);
+        };
+        let contents = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Replay::parse(&path, &contents, lanes).map(Some)
+    }
+}
+
+/// Collects the vehicles of a run for RTS_RECORD and writes them out once
+/// the run is over.
+#[derive(Debug)]
+pub struct Recorder {
+    path: String,
+    vehicles: Mutex<Vec<ReplayVehicle>>,
+}
+
+impl Recorder {
+    /// A recorder for the file named by RTS_RECORD, if it is set. The file is
+    /// created right away so a bad path is reported before the run rather
+    /// than after it (and recording skipped, with a warning).
+    pub fn from_env() -> Option<Recorder> {
+        let path = std::env::var("RTS_RECORD").ok().filter(|path| !path.is_empty())?;
+        match fs::File::create(&path) {
+            Ok(_) => Some(Recorder { path, vehicles: Mutex::new(Vec::new()) }),
+            Err(e) => {
+                eprintln!("Ignoring RTS_RECORD: cannot create {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn record(&self, vehicle: ReplayVehicle) {
+        self.vehicles.lock().unwrap().push(vehicle);
+    }
+
+    /// Writes every recorded vehicle, ordered by car id, and returns the
+    /// number written and the file's hash.
+    pub fn finish(&self) -> Result<(usize, String), String> {
+        let mut vehicles = self.vehicles.lock().unwrap().clone();
+        vehicles.sort_by_key(|vehicle| vehicle.car_id);
+        let mut contents = {
+            let _t = budget::time(Category::Serialization);
+            serde_json::to_vec_pretty(&vehicles).map_err(|e| e.to_string())?
+        };
+        contents.push(b'\n');
+        fs::write(&self.path, &contents).map_err(|e| format!("cannot write {}: {}", self.path, e))?;
+        Ok((vehicles.len(), file_hash(&contents)))
+    }
+}
+
+/// 64-bit FNV-1a hash of `contents` as 16 hex digits. It identifies a replay
+/// file across runs and machines; it is not meant to resist tampering.
+pub fn file_hash(contents: &[u8]) -> String {
+    let hash = contents
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3));
+    format!("{:016x}", hash)
+}
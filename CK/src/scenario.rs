@@ -0,0 +1,340 @@
+// scenario.rs
+//
+// Scripted events for demos. RTS_SCENARIO names a JSON file with a list of
+// timed events, each firing `at` simulated seconds after the simulation
+// starts, e.g.
+//
+//   [
+//     {"at": 30, "event": "SpawnBurst", "count": 50, "entry_lanes": [1010, 1011]},
+//     {"at": 60, "event": "LaneClosure", "lane_id": 1025, "duration_secs": 120},
+//     {"at": 90, "event": "SignalOverride", "lane_id": 1033, "green_secs": 20}
+//   ]
+//
+// SpawnBurst launches extra vehicles, from the given input lanes or from any
+// of them. LaneClosure takes an internal lane out of routing: new routes
+// avoid it and cars waiting to enter it re-route at once. With a duration it
+// is followed by a LaneReopen, which can also be scripted on its own.
+// SignalOverride holds a lane green for the given time through the same path
+// as the flow analyzer's recommendations. Every event is logged as a
+// ScenarioEvent when it fires, and the run lasts at least until the last one,
+// unless it is interrupted first.
+//
+// For experiments, the file can instead be an object that also fixes the
+// demand and the signal timing, so runs of it are repeatable and comparable:
+//
+//   {
+//     "duration_secs": 600,
+//     "demand": {"1010": [[0, 2], [300, 12], [600, 2]], "*": [[0, 1]]},
+//     "timings": "default=5/10,8=7/3",
+//     "destinations": {"1010": {"1040": 3, "1041": 1}},
+//     "events": [{"at": 120, "event": "LaneClosure", "lane_id": 1025}]
+//   }
+//
+// With `duration_secs`, vehicles are generated lane by lane for that many
+// simulated seconds, as `--duration` does (see `generator`), replacing the
+// run's own demand. `demand` gives input lanes a rate in vehicles per minute
+// that changes over the run: [seconds, rate] points, linear in between, so
+// the example ramps lane 1010 up to a rush hour peak at 300s and back; `*`
+// covers the lanes not listed, which otherwise generate at DEFAULT_LANE_RATE.
+// `timings` is the junction timing in RTS_JUNCTION_TIMING's format, used by
+// the controller unless RTS_JUNCTION_TIMING is set (see `signal_timing`).
+// `destinations` is the origin-destination matrix: where cars entering on
+// each input lane leave the grid (see `od_matrix`). It applies with or
+// without `duration_secs`, and every pair it names must be reachable.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{self, Category};
+use crate::flow_analyzer::Recommendation;
+use crate::generator::{Generator, RateProfile, DEFAULT_LANE_RATE};
+use crate::od_matrix::OdMatrix;
+use crate::shutdown::{ShutdownFlag, SleepOrShutdown};
+use crate::signal_timing::JunctionTimings;
+use crate::system_monitoring::{EventKind, LogEvent};
+use rts_core::clock::SimClock;
+use rts_core::lanes::{Lane, LaneCategory};
+
+/// One scripted change to the run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum ScenarioAction {
+    /// Launch `count` extra vehicles entering from `entry_lanes`, or from any
+    /// input lane if it is empty.
+    SpawnBurst {
+        count: u32,
+        #[serde(default)]
+        entry_lanes: Vec<u32>,
+    },
+    /// Take an internal lane out of routing, for `duration_secs` if given.
+    LaneClosure {
+        lane_id: u32,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    /// Return a closed lane to routing.
+    LaneReopen { lane_id: u32 },
+    /// Hold a lane green for `green_secs` the next time its phase is served.
+    SignalOverride { lane_id: u32, green_secs: u32 },
+}
+
+impl fmt::Display for ScenarioAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioAction::SpawnBurst { count, entry_lanes } if entry_lanes.is_empty() => {
+                write!(f, "spawning {} extra vehicles", count)
+            }
+            ScenarioAction::SpawnBurst { count, entry_lanes } => {
+                write!(f, "spawning {} extra vehicles from lanes {:?}", count, entry_lanes)
+            }
+            ScenarioAction::LaneClosure { lane_id, duration_secs: Some(secs) } => {
+                write!(f, "closing lane {} for {}s", lane_id, secs)
+            }
+            ScenarioAction::LaneClosure { lane_id, duration_secs: None } => write!(f, "closing lane {}", lane_id),
+            ScenarioAction::LaneReopen { lane_id } => write!(f, "reopening lane {}", lane_id),
+            ScenarioAction::SignalOverride { lane_id, green_secs } => {
+                write!(f, "holding lane {} green for {}s", lane_id, green_secs)
+            }
+        }
+    }
+}
+
+/// An action and when it fires, in simulated seconds after the run starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioEntry {
+    pub at: u64,
+    #[serde(flatten)]
+    pub action: ScenarioAction,
+}
+
+/// Internal lanes currently closed by the scenario.
+pub type ClosedLanes = Arc<Mutex<HashSet<u32>>>;
+
+/// A scenario file as written: its events, and for an experiment its demand
+/// and timing as well.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Experiment {
+    duration_secs: Option<u64>,
+    /// [seconds, rate] points by input lane id, or `*`.
+    #[serde(default)]
+    demand: BTreeMap<String, Vec<(f64, f64)>>,
+    timings: Option<String>,
+    /// Destination weights by output lane id or `*`, by input lane id or `*`.
+    #[serde(default)]
+    destinations: BTreeMap<String, BTreeMap<String, f64>>,
+    #[serde(default)]
+    events: Vec<ScenarioEntry>,
+}
+
+impl Experiment {
+    /// A file's contents: a list of events, or an experiment object.
+    fn from_json(json: &str) -> Result<Experiment, String> {
+        let _t = budget::time(Category::Serialization);
+        let invalid = |e: serde_json::Error| format!("invalid scenario: {}", e);
+        let value: serde_json::Value = serde_json::from_str(json).map_err(invalid)?;
+        if value.is_array() {
+            Ok(Experiment { events: serde_json::from_value(value).map_err(invalid)?, ..Experiment::default() })
+        } else {
+            serde_json::from_value(value).map_err(invalid)
+        }
+    }
+}
+
+/// Demand of an experiment: how long to generate vehicles for and the rate
+/// of every input lane over that time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScenarioDemand {
+    /// Simulated time to generate vehicles for.
+    pub duration: Duration,
+    pub profiles: BTreeMap<u32, RateProfile>,
+}
+
+/// A validated scenario, in firing order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    entries: Vec<ScenarioEntry>,
+    demand: Option<ScenarioDemand>,
+    destinations: OdMatrix,
+}
+
+impl Scenario {
+    /// Parses a scenario file's contents and checks every lane it names
+    /// against `lanes`, and every trip its destinations name against the
+    /// lane graph. Timed closures get their LaneReopen added.
+    pub fn parse(json: &str, lanes: &[Lane]) -> Result<Scenario, String> {
+        let experiment = Experiment::from_json(json)?;
+        if let Some(timings) = &experiment.timings {
+            JunctionTimings::parse(timings).map_err(|e| format!("invalid timings: {}", e))?;
+        }
+        let demand = match experiment.duration_secs {
+            Some(0) => return Err("duration_secs must be positive".to_string()),
+            Some(secs) => Some(ScenarioDemand { duration: Duration::from_secs(secs), profiles: demand_profiles(experiment.demand, lanes)? }),
+            None if !experiment.demand.is_empty() => return Err("demand needs a duration_secs".to_string()),
+            None => None,
+        };
+        let destinations = OdMatrix::parse(&experiment.destinations, lanes).map_err(|e| format!("invalid destinations: {}", e))?;
+        let parsed = experiment.events;
+        let category = |lane_id: u32| lanes.iter().find(|lane| lane.id == lane_id).map(|lane| lane.category);
+        let mut entries = Vec::new();
+        for entry in parsed {
+            match &entry.action {
+                ScenarioAction::SpawnBurst { count, entry_lanes } => {
+                    if *count == 0 {
+                        return Err(format!("SpawnBurst at {}s spawns no vehicles", entry.at));
+                    }
+                    if let Some(&lane_id) = entry_lanes.iter().find(|&&id| category(id) != Some(LaneCategory::InputBoundary)) {
+                        return Err(format!("lane {} is not an input lane", lane_id));
+                    }
+                }
+                ScenarioAction::LaneClosure { lane_id, .. } | ScenarioAction::LaneReopen { lane_id } => {
+                    if category(*lane_id) != Some(LaneCategory::Internal) {
+                        return Err(format!("lane {} is not an internal lane", lane_id));
+                    }
+                }
+                ScenarioAction::SignalOverride { lane_id, .. } => {
+                    if !lanes.iter().any(|lane| lane.id == *lane_id && lane.end_intersection != 0) {
+                        return Err(format!("lane {} has no traffic light", lane_id));
+                    }
+                }
+            }
+            if let ScenarioAction::LaneClosure { lane_id, duration_secs: Some(secs) } = entry.action {
+                entries.push(ScenarioEntry { at: entry.at + secs, action: ScenarioAction::LaneReopen { lane_id } });
+            }
+            entries.push(entry);
+        }
+        // Stable, so events scripted for the same second keep their order.
+        entries.sort_by_key(|entry| entry.at);
+        Ok(Scenario { entries, demand, destinations })
+    }
+
+    /// Scenario from the file named by RTS_SCENARIO; none (with a warning if
+    /// the file cannot be read or is invalid) otherwise.
+    pub fn from_env(lanes: &[Lane]) -> Scenario {
+        let Ok(path) = std::env::var("RTS_SCENARIO") else {
+            return Scenario::default();
+        };
+        let loaded = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {}", path, e))
+            .and_then(|json| Scenario::parse(&json, lanes));
+        loaded.unwrap_or_else(|e| {
+            eprintln!("Ignoring RTS_SCENARIO: {}", e);
+            Scenario::default()
+        })
+    }
+
+    /// True if the scenario neither scripts events nor sets the demand or
+    /// the destinations.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.demand.is_none() && self.destinations.is_empty()
+    }
+
+    /// Number of scripted events.
+    pub fn event_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn demand(&self) -> Option<&ScenarioDemand> {
+        self.demand.as_ref()
+    }
+
+    pub fn destinations(&self) -> &OdMatrix {
+        &self.destinations
+    }
+}
+
+impl ScenarioDemand {
+    /// The generator for this demand at `time_scale` simulated seconds per
+    /// real second.
+    pub fn generator(&self, time_scale: f64) -> Generator {
+        Generator {
+            duration: self.duration.div_f64(time_scale),
+            default_rate: DEFAULT_LANE_RATE,
+            lane_rates: BTreeMap::new(),
+            profiles: self.profiles.clone(),
+        }
+    }
+}
+
+/// Rate profiles by input lane from an experiment's `demand`, `*` applying
+/// to every input lane not named.
+fn demand_profiles(demand: BTreeMap<String, Vec<(f64, f64)>>, lanes: &[Lane]) -> Result<BTreeMap<u32, RateProfile>, String> {
+    let mut profiles = BTreeMap::new();
+    let mut others = None;
+    for (lane, points) in demand {
+        let profile = RateProfile::new(points).map_err(|e| format!("demand of lane {}: {}", lane, e))?;
+        if lane == "*" {
+            others = Some(profile);
+            continue;
+        }
+        let lane_id: u32 = lane.parse().map_err(|_| format!("invalid lane id '{}' in demand", lane))?;
+        if !lanes.iter().any(|l| l.id == lane_id && l.category == LaneCategory::InputBoundary) {
+            return Err(format!("lane {} is not an input lane", lane_id));
+        }
+        profiles.insert(lane_id, profile);
+    }
+    if let Some(others) = others {
+        for lane in lanes.iter().filter(|lane| lane.category == LaneCategory::InputBoundary) {
+            profiles.entry(lane.id).or_insert_with(|| others.clone());
+        }
+    }
+    Ok(profiles)
+}
+
+/// Junction timing spec of the RTS_SCENARIO file, if it sets one. Problems
+/// with the file are left to `Scenario::from_env` to report.
+pub fn timings_from_env() -> Option<String> {
+    let json = std::fs::read_to_string(std::env::var("RTS_SCENARIO").ok()?).ok()?;
+    Experiment::from_json(&json).ok()?.timings
+}
+
+/// Where a running scenario's events take effect.
+pub struct ScenarioLinks {
+    pub closed_lanes: ClosedLanes,
+    /// Signal overrides, to the traffic light controller.
+    pub rec_tx: Sender<Recommendation>,
+    pub log_tx: Sender<LogEvent>,
+}
+
+/// Fires the scenario's events at their times, counted from `run_start` in
+/// `clock`'s simulated time, logging each as a ScenarioEvent, until the last
+/// one or until `interrupted` is raised. Closures update `closed_lanes`,
+/// signal overrides go out on `rec_tx` and bursts are handed to `spawn_burst`
+/// with their time, count and entry lanes. Returns the handles of every
+/// vehicle thread `spawn_burst` started.
+pub fn run_scenario(
+    scenario: Scenario,
+    run_start: Instant,
+    links: ScenarioLinks,
+    interrupted: &ShutdownFlag,
+    clock: SimClock,
+    mut spawn_burst: impl FnMut(u64, u32, &[u32]) -> Vec<JoinHandle<()>>,
+) -> Vec<JoinHandle<()>> {
+    let ScenarioLinks { closed_lanes, rec_tx, log_tx } = links;
+    let mut handles = Vec::new();
+    for ScenarioEntry { at, action } in scenario.entries {
+        if !clock.sleep_or_shutdown(Duration::from_secs(at).saturating_sub(clock.since(run_start)), interrupted) {
+            break;
+        }
+        log_tx.send(LogEvent::new("Scenario", clock.now_secs(), EventKind::ScenarioEvent { at, action: action.clone() })).ok();
+        match action {
+            ScenarioAction::SpawnBurst { count, entry_lanes } => handles.extend(spawn_burst(at, count, &entry_lanes)),
+            ScenarioAction::LaneClosure { lane_id, .. } => {
+                closed_lanes.lock().unwrap().insert(lane_id);
+            }
+            ScenarioAction::LaneReopen { lane_id } => {
+                closed_lanes.lock().unwrap().remove(&lane_id);
+            }
+            ScenarioAction::SignalOverride { lane_id, green_secs } => {
+                rec_tx.send(Recommendation::ExtendGreen { lane_id, new_green_time: green_secs }).ok();
+            }
+        }
+    }
+    handles
+}
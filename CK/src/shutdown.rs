@@ -0,0 +1,76 @@
+// shutdown.rs
+//
+// Cooperative shutdown for long-running component threads. main raises the
+// flag once the simulation has finished; loops check it between steps and use
+// sleep_or_shutdown() so that a long phase sleep does not delay the exit.
+//
+// Ctrl-C raises a flag of its own (see `raise_on_interrupt`): the simulation
+// stops spawning vehicles, lets the ones on the road finish and then shuts
+// down as if the run had ended, so logs and sinks are still flushed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rts_core::clock::SimClock;
+
+/// Shared flag raised when components should stop.
+pub type ShutdownFlag = Arc<AtomicBool>;
+
+/// Granularity at which sleeping threads notice a shutdown request.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn new_flag() -> ShutdownFlag {
+    Arc::new(AtomicBool::new(false))
+}
+
+pub fn request(flag: &ShutdownFlag) {
+    flag.store(true, Ordering::SeqCst);
+}
+
+pub fn is_requested(flag: &ShutdownFlag) -> bool {
+    flag.load(Ordering::SeqCst)
+}
+
+/// Raises `flag` on the first Ctrl-C; a second one exits immediately.
+pub fn raise_on_interrupt(flag: &ShutdownFlag) {
+    let flag = Arc::clone(flag);
+    let installed = ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted: no more vehicles will spawn; finishing the ones on the road (Ctrl-C again to quit now)");
+    });
+    if let Err(e) = installed {
+        eprintln!("Cannot handle Ctrl-C, it will stop the run without flushing: {}", e);
+    }
+}
+
+/// Sleeps for `duration`, waking early if shutdown is requested.
+/// Returns false if the sleep was cut short by a shutdown request.
+pub fn sleep_or_shutdown(duration: Duration, flag: &ShutdownFlag) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if is_requested(flag) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Sleeping for simulated time until a shutdown request.
+pub trait SleepOrShutdown {
+    /// Like `sleep_or_shutdown`, for `sim` of simulated time.
+    fn sleep_or_shutdown(&self, sim: Duration, flag: &ShutdownFlag) -> bool;
+}
+
+impl SleepOrShutdown for SimClock {
+    fn sleep_or_shutdown(&self, sim: Duration, flag: &ShutdownFlag) -> bool {
+        sleep_or_shutdown(self.real_duration(sim), flag)
+    }
+}
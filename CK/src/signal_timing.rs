@@ -0,0 +1,101 @@
+// signal_timing.rs
+//
+// Baseline green, amber and all-red durations of the junction controllers.
+// Every junction uses the default timing unless RTS_JUNCTION_TIMING overrides
+// it, e.g. `default=5/10,8=7/3,12=6/2/4` (green/all-red or
+// green/amber/all-red seconds, amber defaulting to 3; `default` sets the
+// timing of junctions not listed). Without it, a scenario's `timings` apply
+// (see `scenario`).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::scenario;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JunctionTiming {
+    /// How long a phase stays green when no recommendation is pending.
+    pub green: Duration,
+    /// How long a phase's lanes show amber after their green.
+    pub amber: Duration,
+    /// Clearance interval with every approach red between two phases.
+    pub all_red: Duration,
+}
+
+/// Amber time of a timing that does not give one.
+const DEFAULT_AMBER: Duration = Duration::from_secs(3);
+
+impl Default for JunctionTiming {
+    fn default() -> Self {
+        JunctionTiming { green: Duration::from_secs(5), amber: DEFAULT_AMBER, all_red: Duration::from_secs(10) }
+    }
+}
+
+impl JunctionTiming {
+    fn parse(spec: &str) -> Result<JunctionTiming, String> {
+        let secs = |value: &str| {
+            value
+                .trim()
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("invalid duration '{}'", value.trim()))
+        };
+        let timing = match spec.split('/').collect::<Vec<_>>()[..] {
+            [green, all_red] => JunctionTiming { green: secs(green)?, amber: DEFAULT_AMBER, all_red: secs(all_red)? },
+            [green, amber, all_red] => JunctionTiming { green: secs(green)?, amber: secs(amber)?, all_red: secs(all_red)? },
+            _ => return Err(format!("expected green/all-red or green/amber/all-red seconds, got '{}'", spec)),
+        };
+        if timing.green.is_zero() {
+            return Err(format!("green time must be positive in '{}'", spec));
+        }
+        Ok(timing)
+    }
+}
+
+/// Timing of every junction, with a fallback for junctions not listed.
+#[derive(Debug, Clone, Default)]
+pub struct JunctionTimings {
+    default: JunctionTiming,
+    junctions: HashMap<u32, JunctionTiming>,
+}
+
+impl JunctionTimings {
+    pub fn for_junction(&self, junction: u32) -> JunctionTiming {
+        self.junctions.get(&junction).copied().unwrap_or(self.default)
+    }
+
+    /// Parses a list such as `default=5/10,8=7/2/3`.
+    pub fn parse(spec: &str) -> Result<JunctionTimings, String> {
+        let mut timings = JunctionTimings::default();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (junction, timing) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected junction=green/all-red, got '{}'", entry))?;
+            let timing = JunctionTiming::parse(timing)?;
+            match junction.trim() {
+                "default" => timings.default = timing,
+                id => {
+                    let id = id.parse().map_err(|_| format!("invalid junction '{}'", id))?;
+                    timings.junctions.insert(id, timing);
+                }
+            }
+        }
+        Ok(timings)
+    }
+
+    /// Timings from RTS_JUNCTION_TIMING, else from the RTS_SCENARIO file,
+    /// falling back to the defaults when neither sets valid ones (with a
+    /// warning if RTS_JUNCTION_TIMING is invalid).
+    pub fn from_env() -> JunctionTimings {
+        match std::env::var("RTS_JUNCTION_TIMING") {
+            Ok(spec) => JunctionTimings::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_JUNCTION_TIMING: {}", e);
+                JunctionTimings::default()
+            }),
+            // An invalid scenario is reported when the simulation loads it.
+            Err(_) => scenario::timings_from_env()
+                .and_then(|spec| JunctionTimings::parse(&spec).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
@@ -1,147 +1,138 @@
-use std::sync::{Arc, Mutex, mpsc::Sender};
-use std::thread;
+use std::sync::{Arc, Mutex, mpsc::Receiver, mpsc::Sender};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
-use rand::Rng;
-use std::collections::{HashMap, BinaryHeap};
-use std::cmp::Ordering;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
 
-use crate::traffic_light::{TrafficLightMap, can_proceed_lane};
-use crate::system_monitoring::LogEvent;
-use crate::lanes::{load_lanes, Lane, LaneCategory};
+use crate::traffic_light::TrafficLightMap;
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use rts_core::lanes::{load_lanes, Lane, LaneCategory, Movement};
+use rts_core::phase_plan;
+use rts_core::trips::{choose_trip, BoundaryLanes, Trip};
+use crate::budget::{self, Category};
+use crate::shutdown::{self, ShutdownFlag, SleepOrShutdown};
+use rts_core::routing::{self, find_lane_path};
+use rts_core::network::load_network;
+use crate::vehicle::{Vehicle, VehicleKind, VehicleMix};
+use crate::summary::SimulationSummary;
+use rts_core::clock::SimClock;
+use crate::gridlock::{AdvisedLanes, SharedAdvisories};
+use crate::crossings::{self, CrossingConfig, PedestrianUpdate};
+use crate::flow_analyzer::Recommendation;
+use crate::scenario::{self, ClosedLanes, Scenario, ScenarioLinks};
+use crate::replay::{Recorder, Replay, ReplayVehicle};
+use rts_core::progress::LaneTransition;
+use rts_core::cadence::{self, CadenceController};
+use rts_core::demand::{Arrivals, Demand};
+use rts_core::seed;
+use rts_core::export::{self, Export, Journey, LaneSample};
+use crate::generator::Generator;
+use crate::od_matrix::OdMatrix;
+use crate::junction_box::{JunctionBoxes, CROSSING_SECS};
+use crate::coordination::Coordination;
+use crate::signal_timing::JunctionTimings;
+use crate::log_scope::LogScope;
 
 /// Metrics recorded for each car’s trip.
 pub struct CarMetrics {
     pub id: u32,
+    pub kind: VehicleKind,
+    pub entry_lane: u32,
+    pub exit_lane: u32,
     pub wait_time: f64,
     pub drive_time: f64,
     pub total_time: f64,
+    /// Internal lanes driven, in order.
+    pub lanes: Vec<LaneVisit>,
+    /// Entry/exit pairs drawn and rejected before the car's trip.
+    pub redraws: u32,
 }
 
-/// A road segment (optional reference structure).
-#[derive(Debug, Clone)]
-pub struct RoadSegment {
-    pub from: u32,
-    pub to: u32,
-    pub length: f64,
-    pub lanes: u32,
+/// A car that gave up without a valid entry/exit pair and never drove.
+pub struct GenerationFailed {
+    pub car_id: u32,
+    /// Entry/exit pairs drawn, all rejected.
+    pub attempts: u32,
 }
 
-/// Internal helper for Dijkstra’s algorithm over intersections.
-#[derive(Debug)]
-struct State {
-    cost: f64,
-    position: u32,
-    prev: Option<u32>,
-}
-impl Eq for State {}
-impl PartialEq for State {
-    fn eq(&self, other: &Self) -> bool {
-        self.cost.eq(&other.cost)
-    }
+/// One internal lane of a car's trip.
+pub struct LaneVisit {
+    pub lane_id: u32,
+    /// Junction at the end of the lane.
+    pub junction: u32,
+    /// Lane occupancy right after the car entered, in footprint units.
+    pub occupancy: u32,
+    /// Time spent waiting for room on the lane and for its light.
+    pub wait_time: f64,
+    /// The car left the lane by turning right on a red light.
+    pub right_on_red: bool,
 }
-impl Ord for State {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
-    }
+
+/// Boundary lanes of the run, and where cars entering on each go.
+pub struct TripDraws {
+    pub boundary: BoundaryLanes,
+    pub destinations: OdMatrix,
 }
-impl PartialOrd for State {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+impl TripDraws {
+    /// These draws with the entries narrowed to `entry_lanes`.
+    fn entering_on(&self, entry_lanes: &[u32]) -> TripDraws {
+        TripDraws { boundary: self.boundary.entering_on(entry_lanes), destinations: self.destinations.clone() }
     }
 }
 
-/// Converts intersection ID (1..16) to (row, col) in a 4×4 grid.
-fn intersection_to_coords(inter: u32) -> (u32, u32) {
-    let row = (inter - 1) / 4;
-    let col = (inter - 1) % 4;
-    (row, col)
+/// What cars weigh besides lane length when they pick a route.
+#[derive(Clone)]
+pub struct RouteOptions {
+    /// Penalize lanes by their current occupancy (RTS_CONGESTION_ROUTING).
+    pub congestion_aware: bool,
+    /// Re-plan the rest of the route at every intersection, with lanes
+    /// penalized by their current occupancy (RTS_DYNAMIC_ROUTING).
+    pub dynamic: bool,
+    /// Lanes under a live reroute advisory, avoided unless there is no other way.
+    pub advisories: SharedAdvisories,
+    /// Lanes closed by the scenario, never routed over while closed.
+    pub closed_lanes: ClosedLanes,
+    /// Lanes the car's lane-level events are sent for (RTS_LOG_TAGS).
+    pub log_scope: LogScope,
 }
 
-/// New function: find_lane_path computes a route based on internal lanes.
-fn find_lane_path(start: u32, end: u32, lanes: &Vec<Lane>) -> Option<Vec<Lane>> {
-    #[derive(Debug)]
-    struct LaneState {
-        cost: f64,
-        position: u32,
-    }
-    impl Eq for LaneState {}
-    impl PartialEq for LaneState {
-        fn eq(&self, other: &Self) -> bool {
-            self.cost == other.cost
-        }
-    }
-    impl Ord for LaneState {
-        fn cmp(&self, other: &Self) -> Ordering {
-            other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
-        }
-    }
-    impl PartialOrd for LaneState {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
+impl RouteOptions {
+    /// `lanes` without the ones currently closed.
+    fn open_lanes(&self, lanes: &[Lane]) -> Vec<Lane> {
+        let closed = self.closed_lanes.lock().unwrap();
+        lanes.iter().filter(|lane| !closed.contains(&lane.id)).cloned().collect()
     }
 
-    let mut dist: HashMap<u32, f64> = HashMap::new();
-    let mut prev: HashMap<u32, (u32, Lane)> = HashMap::new();
-    let mut heap = BinaryHeap::new();
-    
-    for inter in 1..=16 {
-        dist.insert(inter, std::f64::INFINITY);
-    }
-    dist.insert(start, 0.0);
-    heap.push(LaneState { cost: 0.0, position: start });
-    
-    let mut lane_map: HashMap<u32, Vec<&Lane>> = HashMap::new();
-    for lane in lanes {
-        lane_map.entry(lane.start_intersection).or_default().push(lane);
-    }
-    
-    while let Some(LaneState { cost, position }) = heap.pop() {
-        if position == end {
-            break;
-        }
-        if cost > dist[&position] {
-            continue;
-        }
-        if let Some(neighbor_lanes) = lane_map.get(&position) {
-            for &lane in neighbor_lanes {
-                let next = lane.end_intersection;
-                let next_cost = cost + lane.length;
-                if next_cost < *dist.get(&next).unwrap_or(&std::f64::INFINITY) {
-                    dist.insert(next, next_cost);
-                    prev.insert(next, (position, lane.clone()));
-                    heap.push(LaneState { cost: next_cost, position: next });
-                }
-            }
-        }
-    }
-    
-    if !dist.contains_key(&end) || dist[&end] == std::f64::INFINITY {
-        return None;
-    }
-    
-    let mut path: Vec<Lane> = Vec::new();
-    let mut current = end;
-    while current != start {
-        if let Some(&(prev_inter, ref lane)) = prev.get(&current) {
-            path.push(lane.clone());
-            current = prev_inter;
-        } else {
-            break;
-        }
+    fn is_closed(&self, lane_id: u32) -> bool {
+        self.closed_lanes.lock().unwrap().contains(&lane_id)
     }
-    path.reverse();
-    Some(path)
 }
 
-/// Helper: returns the current system time in seconds (Unix epoch).
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// What cars meet at the junctions: the lights, and the boxes they cross.
+#[derive(Clone)]
+pub struct Junctions {
+    pub lights: TrafficLightMap,
+    pub boxes: Arc<JunctionBoxes>,
 }
 
+/// Occupancy of every lane, in units of vehicle footprint (a bus counts as 3).
 pub type SimEvent = Arc<Mutex<HashMap<u32, u32>>>;
 
+/// Lane counts published to the flow analyzer, with the interval the
+/// publisher will wait before sending the next one and the vehicles that have
+/// left each approach lane into its junction since the run started.
+pub struct LaneSnapshot {
+    pub lanes: HashMap<u32, u32>,
+    pub interval_ms: u64,
+    pub departures: HashMap<u32, u32>,
+}
+
+/// Lane counts of the latest snapshot, shared with the traffic light
+/// controller so adaptive junctions can see where vehicles are waiting.
+pub type LatestCounts = Arc<Mutex<HashMap<u32, u32>>>;
+
 pub fn initialize_simdata() -> SimEvent {
     let mut map = HashMap::new();
     let lanes = load_lanes();
@@ -151,124 +142,498 @@ pub fn initialize_simdata() -> SimEvent {
     Arc::new(Mutex::new(map))
 }
 
-/// Simulate a single car traveling from an input boundary lane to an output boundary lane.
-pub fn simulate_car(
-    car_id: u32,
-    traffic_lights: TrafficLightMap,
-    log_tx: Sender<LogEvent>,
-    entry_lanes: &[Lane],
-    exit_lanes: &[Lane],
-    sim_event: Arc<Mutex<HashMap<u32, u32>>>,
-) -> CarMetrics {
-    let mut rng = rand::thread_rng();
-    let speed: f64 = rng.gen_range(70.0..=90.0);
-    // If your version of rand complains, you might try:
-    // let speed: f64 = rng.random_range(10.0..=30.0);
+/// Seconds a car may be blocked on a full lane before it re-routes around it.
+/// This also breaks deadlocks between full lanes that feed each other.
+pub const BLOCKED_REROUTE_SECS: f64 = 10.0;
 
-    // Choose a random entry and exit lane.
-    let input_lane = entry_lanes[rng.gen_range(0..entry_lanes.len())].clone();
-    let mut exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
-    while exit_lane.id == input_lane.id {
-        exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
+/// Claims `footprint` units on `lane` if they fit within its capacity. An empty
+/// lane always admits a vehicle, however large. Returns the lane's new occupancy,
+/// or None if the lane is full.
+fn try_enter_lane(sim_event: &SimEvent, lane: &Lane, footprint: u32) -> Option<u32> {
+    let mut stats = {
+        let _t = budget::time_lock();
+        sim_event.lock().unwrap()
+    };
+    let count = stats.entry(lane.id).or_insert(0);
+    if *count > 0 && *count + footprint > lane.capacity {
+        return None;
     }
+    *count += footprint;
+    Some(*count)
+}
 
-    let start_intersection = input_lane.end_intersection;
-    let end_intersection = exit_lane.start_intersection;
+/// Releases the `footprint` units a vehicle held on `lane_id`.
+fn leave_lane(sim_event: &SimEvent, lane_id: u32, footprint: u32) {
+    let mut stats = {
+        let _t = budget::time_lock();
+        sim_event.lock().unwrap()
+    };
+    let count = stats.entry(lane_id).or_insert(0);
+    *count = count.saturating_sub(footprint);
+}
+
+/// Reports that `car_id` entered or left the lane at `route_index` of its
+/// trip, if the lane is in `scope`.
+fn send_progress(log_tx: &Sender<LogEvent>, scope: &LogScope, clock: &SimClock, car_id: u32, lane_id: u32, transition: LaneTransition, route_index: usize) {
+    if !scope.includes(lane_id) {
+        return;
+    }
+    let progress_log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::CarProgress { car_id, lane_id, transition, route_index },
+    );
+    let _t = budget::time(Category::Transport);
+    log_tx.send(progress_log).ok();
+}
+
+/// Simulate a single vehicle traveling from an input boundary lane to an output boundary lane.
+/// A replayed vehicle takes its recorded entry and exit; otherwise they are
+/// re-drawn until they are different junctions with a route
+/// between them; a car that runs out of draws logs VehicleGenerationFailed and
+/// does not drive. Routes avoid lanes the scenario has closed, and a car waiting
+/// to enter a lane that closes re-routes around it right away. With dynamic routing
+/// the car re-plans the rest of its route at each intersection it reaches, and logs
+/// every change. Every lane the car enters and leaves, from its entry lane to
+/// its exit lane, is logged as CarProgress. Past each light the car crosses
+/// the junction once no conflicting movement is inside (see `junction_box`).
+/// Travel, waits and the returned metrics are all in `clock`'s simulated time.
+pub fn simulate_car(
+    vehicle: Vehicle,
+    junctions: &Junctions,
+    log_tx: Sender<LogEvent>,
+    draws: &TripDraws,
+    sim_event: Arc<Mutex<HashMap<u32, u32>>>,
+    route_options: &RouteOptions,
+    clock: SimClock,
+) -> Result<CarMetrics, GenerationFailed> {
+    let car_id = vehicle.id;
+    let speed = vehicle.speed;
+    let footprint = vehicle.kind.footprint();
+    let mut rng = StdRng::seed_from_u64(vehicle.trip_seed);
 
     let all_lanes = load_lanes();
+    let network = load_network();
     let internal_lanes: Vec<Lane> = all_lanes
-        .into_iter()
+        .iter()
         .filter(|l| l.category == LaneCategory::Internal)
+        .cloned()
         .collect();
 
-    let lane_route = match find_lane_path(start_intersection, end_intersection, &internal_lanes) {
-        Some(route) => route,
-        None => Vec::new(),
+    // Choose a random entry and exit lane, unless the trip is replayed.
+    let trip = match vehicle.replayed_trip {
+        // Replay files are checked against the lanes when they are loaded.
+        Some((entry, exit)) => draws.boundary.trip(entry, exit).ok_or(0),
+        None => {
+            let _t = budget::time(Category::Routing);
+            let open_lanes = route_options.open_lanes(&internal_lanes);
+            let destination = |entry: &Lane, rng: &mut StdRng| draws.destinations.draw(entry.id, rng);
+            choose_trip(&draws.boundary, &open_lanes, &network, None, &mut rng, destination)
+        }
+    };
+    let Trip { entry: input_lane, exit: exit_lane, redraws } =
+        match trip {
+            Ok(trip) => trip,
+            Err(attempts) => {
+                let fail_log = LogEvent::new(
+                    format!("Car-{}", car_id),
+                    clock.now_secs(),
+                    EventKind::VehicleGenerationFailed { car_id, attempts },
+                )
+                .with_level(Level::Warn);
+                log_tx.send(fail_log).ok();
+                return Err(GenerationFailed { car_id, attempts });
+            }
+        };
+
+    let start_intersection = input_lane.end_intersection;
+    let end_intersection = exit_lane.start_intersection;
+
+    // With congestion-aware or dynamic routing, lanes are penalized by their
+    // current load; lanes under a reroute advisory are penalized regardless.
+    let current_weights = || {
+        let _t = budget::time_lock();
+        let mut weights = if route_options.congestion_aware || route_options.dynamic {
+            sim_event.lock().ok().map(|counts| routing::congestion_weights(&counts)).unwrap_or_default()
+        } else {
+            HashMap::new()
+        };
+        for lane_id in route_options.advisories.lock().unwrap().active(clock.now_secs()) {
+            *weights.entry(lane_id).or_insert(0.0) += routing::ADVISED_LANE_PENALTY;
+        }
+        if weights.is_empty() { None } else { Some(weights) }
     };
 
-    let lane_ids: Vec<u32> = lane_route.iter().map(|lane| lane.id).collect();
-    let gen_log = LogEvent {
-        source: format!("Car-{}", car_id),
-        message: format!(
-            "Generated vehicle with speed {:.2} m/s; Entry Lane {} (Inter. {}), Exit Lane {} (Inter. {}); Lane Route: {:?}",
-            speed, input_lane.id, input_lane.end_intersection, exit_lane.id, exit_lane.start_intersection, lane_ids
-        ),
-        timestamp: current_time_secs(),
+    let lane_route = {
+        let weights = current_weights();
+        let open_lanes = route_options.open_lanes(&internal_lanes);
+        let _t = budget::time(Category::Routing);
+        find_lane_path(start_intersection, end_intersection, &open_lanes, &network, weights.as_ref())
+    };
+    let lane_route = match lane_route {
+        Ok(route) => route,
+        Err(e) => {
+            let fail_log = LogEvent {
+                source: format!("Car-{}", car_id),
+                message: format!("Routing failed: {}; driving straight to the exit lane", e),
+                timestamp: clock.now_secs(),
+                level: Level::Warn,
+                kind: EventKind::Generic,
+            };
+            log_tx.send(fail_log).ok();
+            Vec::new()
+        }
     };
-    log_tx.send(gen_log).ok();
+
+    let lane_ids: Vec<u32> = lane_route.iter().map(|lane| lane.id).collect();
+    let gen_log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::VehicleGenerated {
+            car_id,
+            vehicle_kind: vehicle.kind,
+            speed,
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route: lane_ids,
+        },
+    );
+    {
+        let _t = budget::time(Category::Transport);
+        log_tx.send(gen_log).ok();
+    }
 
     let start_time = Instant::now();
     let mut total_wait_time = 0.0;
     let mut total_drive_time = 0.0;
+    // Meters actually driven, including any detours taken.
+    let mut route_length = input_lane.length;
 
     // 1. Travel the entry lane.
+    send_progress(&log_tx, &route_options.log_scope, &clock, car_id, input_lane.id, LaneTransition::Entered, 0);
     let travel_time = input_lane.length / speed;
-    thread::sleep(Duration::from_secs_f64(travel_time));
+    {
+        let _t = budget::time(Category::Sleep);
+        clock.sleep(Duration::from_secs_f64(travel_time));
+    }
     total_drive_time += travel_time;
 
-    // 2. Follow the lane route.
-    for lane in lane_route {
-        // update the data of lane when car enter the lane
-        {
-            let mut stats = sim_event.lock().unwrap();
-            *stats.entry(lane.id).or_insert(0) += 1;
-            println!("car {}  entered lane {}",car_id, lane.id);
+    // 2. Follow the lane route. A car keeps its slot on the previous lane
+    //    until it has secured a slot on the next one, so full lanes back up.
+    let mut route = lane_route;
+    let mut visits = Vec::new();
+    let mut occupied: Option<u32> = None;
+    let mut index = 0;
+    // Set when the car has just re-routed around route[index], so that the
+    // detour is not re-planned straight back onto that lane.
+    let mut detoured = false;
+    while index < route.len() {
+        // At the intersection before route[index]: take a cheaper way from
+        // here if the lane counts have moved since the route was planned.
+        if route_options.dynamic && !detoured {
+            let here = route[index].start_intersection;
+            let weights = current_weights();
+            let open_lanes = route_options.open_lanes(&internal_lanes);
+            let replanned = {
+                let _t = budget::time(Category::Routing);
+                find_lane_path(here, end_intersection, &open_lanes, &network, weights.as_ref())
+            };
+            if let Some(replanned) = replanned.ok().filter(|route| !route.is_empty()) {
+                let old_ids: Vec<u32> = route[index..].iter().map(|l| l.id).collect();
+                let new_ids: Vec<u32> = replanned.iter().map(|l| l.id).collect();
+                if new_ids != old_ids {
+                    let replan_log = LogEvent {
+                        source: format!("Car-{}", car_id),
+                        message: format!("Re-planned at intersection {}: {:?} -> {:?}", here, old_ids, new_ids),
+                        timestamp: clock.now_secs(),
+                        level: Level::Info,
+                        kind: EventKind::Generic,
+                    };
+                    log_tx.send(replan_log).ok();
+                    route.truncate(index);
+                    route.extend(replanned);
+                }
+            }
         }
-        
+        let lane = route[index].clone();
+
+        // Block until the lane has room, re-routing if it stays full too long
+        // or is closed.
         let wait_start = Instant::now();
-        // Wait until the individual lane's light turns green.
-        loop {
-            let can_go = {
-                let locked = traffic_lights.lock().unwrap();
-                can_proceed_lane(lane.id, &*locked)
-            };
-            if can_go {
-                break;
+        let mut blocked_since = Instant::now();
+        // Set once a detour around the closed lane has been looked for.
+        let mut closure_checked = false;
+        // None once the car has re-routed around the lane instead of entering it.
+        let entered = loop {
+            let closed = route_options.is_closed(lane.id);
+            if !closed {
+                closure_checked = false;
+                if let Some(occupancy) = try_enter_lane(&sim_event, &lane, footprint) {
+                    break Some(occupancy);
+                }
+            }
+            if (closed && !closure_checked) || clock.since(blocked_since).as_secs_f64() >= BLOCKED_REROUTE_SECS {
+                closure_checked = closed;
+                let candidates: Vec<Lane> = route_options
+                    .open_lanes(&internal_lanes)
+                    .into_iter()
+                    .filter(|l| l.id != lane.id)
+                    .collect();
+                let weights = current_weights();
+                let detour = {
+                    let _t = budget::time(Category::Routing);
+                    find_lane_path(lane.start_intersection, end_intersection, &candidates, &network, weights.as_ref())
+                };
+                if let Ok(detour) = detour {
+                    let detour_ids: Vec<u32> = detour.iter().map(|l| l.id).collect();
+                    let message = if closed {
+                        format!("Lane {} closed; re-routed via {:?}", lane.id, detour_ids)
+                    } else {
+                        format!("Lane {} full for {:.0}s; re-routed via {:?}", lane.id, BLOCKED_REROUTE_SECS, detour_ids)
+                    };
+                    let reroute_log = LogEvent {
+                        source: format!("Car-{}", car_id),
+                        message,
+                        timestamp: clock.now_secs(),
+                        level: Level::Info,
+                        kind: EventKind::Generic,
+                    };
+                    log_tx.send(reroute_log).ok();
+                    route.truncate(index);
+                    route.extend(detour);
+                    break None;
+                }
+                blocked_since = Instant::now();
             }
-            thread::sleep(Duration::from_millis(100));
+            let _t = budget::time(Category::Sleep);
+            clock.sleep(Duration::from_millis(100));
+        };
+        total_wait_time += clock.since(wait_start).as_secs_f64();
+        detoured = entered.is_none();
+        let Some(occupancy) = entered else {
+            continue;
+        };
+        send_progress(&log_tx, &route_options.log_scope, &clock, car_id, lane.id, LaneTransition::Entered, index + 1);
+        match occupied.replace(lane.id) {
+            Some(previous) => {
+                leave_lane(&sim_event, previous, footprint);
+                send_progress(&log_tx, &route_options.log_scope, &clock, car_id, previous, LaneTransition::Exited, index);
+            }
+            None => send_progress(&log_tx, &route_options.log_scope, &clock, car_id, input_lane.id, LaneTransition::Exited, 0),
         }
-        total_wait_time += wait_start.elapsed().as_secs_f64();
 
-        let seg_time = lane.length / speed;
-        thread::sleep(Duration::from_secs_f64(seg_time));
-        total_drive_time += seg_time;
-        // update the data of lane when car exit the lane
+        // Cross the junction into the next lane of the route as planned now.
+        let next_lane = route.get(index + 1).unwrap_or(&exit_lane).clone();
+        let movement = phase_plan::movement(lane.end_intersection, &lane, &next_lane, &all_lanes, &network)
+            .unwrap_or(Movement::Straight);
+        let light_start = Instant::now();
+        // Wait until the lane's light is green and the cars ahead have passed,
+        // or, turning right, until the next lane has room to go on red.
+        let turned_on_red = {
+            let _t = budget::time(Category::Sleep);
+            let next_occupancy = || sim_event.lock().unwrap().get(&next_lane.id).copied().unwrap_or(0);
+            junctions.lights.wait_for_turn(lane.id, car_id, movement, &next_lane, next_occupancy)
+        };
+        if let Some(occupancy) = turned_on_red.filter(|_| route_options.log_scope.includes(lane.id)) {
+            let turn_log = LogEvent::new(
+                format!("Car-{}", car_id),
+                clock.now_secs(),
+                EventKind::RightTurnOnRed { car_id, lane_id: lane.id, into_lane: next_lane.id, occupancy },
+            );
+            let _t = budget::time(Category::Transport);
+            log_tx.send(turn_log).ok();
+        }
+        total_wait_time += clock.since(light_start).as_secs_f64();
+        let lane_wait = clock.since(wait_start).as_secs_f64();
+        let next_lane = next_lane.id;
+        let box_wait = junctions.boxes.cross(lane.end_intersection, car_id, lane.id, next_lane);
+        total_wait_time += box_wait;
+        total_drive_time += CROSSING_SECS;
+        // Time spent on this lane's capacity, its light and the junction together.
+        let lane_wait = lane_wait + box_wait;
+        let lane_log = LogEvent::new(
+            format!("Car-{}", car_id),
+            clock.now_secs(),
+            EventKind::LaneWait {
+                car_id,
+                lane_id: lane.id,
+                junction: lane.end_intersection,
+                wait_time: lane_wait,
+            },
+        );
+        visits.push(LaneVisit {
+            lane_id: lane.id,
+            junction: lane.end_intersection,
+            occupancy,
+            wait_time: lane_wait,
+            right_on_red: turned_on_red.is_some(),
+        });
+        if route_options.log_scope.includes(lane.id) {
+            let _t = budget::time(Category::Transport);
+            log_tx.send(lane_log).ok();
+        }
+
+        // A car that had to wait pulls away from rest.
+        let seg_time = vehicle.lane_time(lane.length, lane_wait);
         {
-            let mut stats = sim_event.lock().unwrap();
-            *stats.entry(lane.id).or_insert(0) -= 1;
-            println!("car {}  left lane {}",car_id, lane.id);
+            let _t = budget::time(Category::Sleep);
+            clock.sleep(Duration::from_secs_f64(seg_time));
         }
+        total_drive_time += seg_time;
+        route_length += lane.length;
+        index += 1;
+    }
+    // 3. Travel the exit lane, releasing the last internal lane (or the
+    //    entry lane, if the route was empty) on the way in.
+    let exit_index = route.len() + 1;
+    send_progress(&log_tx, &route_options.log_scope, &clock, car_id, exit_lane.id, LaneTransition::Entered, exit_index);
+    match occupied {
+        Some(previous) => {
+            leave_lane(&sim_event, previous, footprint);
+            send_progress(&log_tx, &route_options.log_scope, &clock, car_id, previous, LaneTransition::Exited, route.len());
+        }
+        None => send_progress(&log_tx, &route_options.log_scope, &clock, car_id, input_lane.id, LaneTransition::Exited, 0),
     }
-
-    // 3. Travel the exit lane.
     let exit_time = exit_lane.length / speed;
-    thread::sleep(Duration::from_secs_f64(exit_time));
+    {
+        let _t = budget::time(Category::Sleep);
+        clock.sleep(Duration::from_secs_f64(exit_time));
+    }
+    send_progress(&log_tx, &route_options.log_scope, &clock, car_id, exit_lane.id, LaneTransition::Exited, exit_index);
     total_drive_time += exit_time;
+    route_length += exit_lane.length;
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    let comp_log = LogEvent {
-        source: format!("Car-{}", car_id),
-        message: format!("Completed journey: Wait={:.2}s, Drive={:.2}s, Total={:.2}s",
-                        total_wait_time, total_drive_time, total_time),
-        timestamp: current_time_secs(),
-    };
-    log_tx.send(comp_log).ok();
+    let total_time = clock.since(start_time).as_secs_f64();
+    let comp_log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::CarCompleted {
+            car_id,
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route_length,
+            wait_time: total_wait_time,
+            drive_time: total_drive_time,
+            total_time,
+        },
+    );
+    {
+        let _t = budget::time(Category::Transport);
+        log_tx.send(comp_log).ok();
+    }
+    budget::flush("Simulation");
 
-    CarMetrics {
+    Ok(CarMetrics {
         id: car_id,
+        kind: vehicle.kind,
+        entry_lane: input_lane.id,
+        exit_lane: exit_lane.id,
         wait_time: total_wait_time,
         drive_time: total_drive_time,
         total_time,
+        lanes: visits,
+        redraws,
+    })
+}
+
+/// What every vehicle thread shares, for launching cars at the start of the
+/// run and in scenario bursts alike.
+#[derive(Clone)]
+struct CarLauncher {
+    junctions: Junctions,
+    log_tx: Sender<LogEvent>,
+    result_tx: Sender<Result<CarMetrics, GenerationFailed>>,
+    sim_event: SimEvent,
+    route_options: RouteOptions,
+    clock: SimClock,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl CarLauncher {
+    /// Starts `vehicle`'s thread, drawing its trip by `draws`. A vehicle
+    /// that drives is recorded as spawned `spawn_at` simulated seconds into
+    /// the run, if the run is being recorded.
+    fn launch(&self, vehicle: Vehicle, draws: Arc<TripDraws>, spawn_at: u64) -> JoinHandle<()> {
+        let launcher = self.clone();
+        thread::spawn(move || {
+            let (car_id, kind, speed) = (vehicle.id, vehicle.kind, vehicle.speed);
+            let outcome = simulate_car(
+                vehicle,
+                &launcher.junctions,
+                launcher.log_tx,
+                &draws,
+                launcher.sim_event,
+                &launcher.route_options,
+                launcher.clock,
+            );
+            if let (Some(recorder), Ok(metrics)) = (&launcher.recorder, &outcome) {
+                recorder.record(ReplayVehicle {
+                    car_id,
+                    spawn_at,
+                    kind,
+                    speed,
+                    entry_lane: metrics.entry_lane,
+                    exit_lane: metrics.exit_lane,
+                });
+            }
+            launcher.result_tx.send(outcome).unwrap();
+        })
     }
 }
 
+/// Run seed from `--seed` or RTS_SEED (see `rts_core::seed`), or a random
+/// one. Every vehicle's kind, speed and entry and exit lanes follow from it.
+pub fn run_seed() -> u32 {
+    seed::seed_from_env().unwrap_or_else(rand::random)
+}
+
+/// The simulation's links to the flow analyzer.
+pub struct AnalyzerLinks {
+    /// Lane snapshots for the analyzer.
+    pub snapshots: Sender<LaneSnapshot>,
+    /// Reroute suggestions from the analyzer.
+    pub advisories: Receiver<Recommendation>,
+    /// Pedestrian arrivals at the junctions with crossings.
+    pub pedestrians: Sender<PedestrianUpdate>,
+}
+
+/// The vehicles of a run: drawn as `demand` says, or lane by lane by a
+/// `generator`, unless a `replay` supplies them. None are spawned once
+/// `interrupted` is raised. With an `export`, the cars' journeys and the
+/// lane counts of every snapshot are written out when the run ends.
+pub struct RunVehicles {
+    pub demand: Demand,
+    pub generator: Option<Generator>,
+    pub replay: Option<Replay>,
+    pub interrupted: ShutdownFlag,
+    pub export: Option<Export>,
+}
+
 /// Spawns multiple cars, each from an InputBoundary lane to an OutputBoundary lane.
+/// Announces the run's parameters with a RunStarted event first. While the cars
+/// drive, pedestrian arrivals at the junctions with crossings are drawn every
+/// simulated minute and sent on the analyzer's `pedestrians` link. Every lane snapshot sent to
+/// the analyzer is also stored in `latest_counts`. A scenario from RTS_SCENARIO
+/// runs alongside the cars (see `scenario`); its signal overrides go out on
+/// `rec_tx`, and the run waits for its last event and every burst vehicle.
+/// The vehicles are drawn to the demand of `vehicles`, all at the start or at its arrival
+/// rate (see `rts_core::demand`), or spawned on each input lane at its own rate while its
+/// generator runs (see `generator`). With a replay they are spawned from it at their
+/// recorded times instead, and RTS_RECORD records the run (see `replay`). Once the
+/// run is interrupted no more vehicles spawn, and it ends when those on the road are done.
 pub fn run_simulation(
     traffic_lights: TrafficLightMap,
     log_tx: Sender<LogEvent>,
-    analyzer_tx: Sender<HashMap<u32,u32>>,
+    analyzer: AnalyzerLinks,
+    latest_counts: LatestCounts,
+    rec_tx: Sender<Recommendation>,
+    vehicles: RunVehicles,
+    clock: SimClock,
 ) {
+    let RunVehicles { demand, generator, replay, interrupted, export } = vehicles;
+    let AnalyzerLinks { snapshots: analyzer_tx, advisories: advisory_rx, pedestrians: pedestrian_tx } = analyzer;
     let (result_tx, result_rx) = std::sync::mpsc::channel();
+    let run_start = Instant::now();
 
     //load sim_event for data to send to anlayzer
     let sim_event: SimEvent = initialize_simdata();
@@ -276,64 +641,364 @@ pub fn run_simulation(
     // 1. Load all lanes.
     let all_lanes = load_lanes();
 
+    let route_options = RouteOptions {
+        congestion_aware: routing::congestion_routing_enabled(),
+        dynamic: routing::dynamic_routing_enabled(),
+        advisories: Arc::new(Mutex::new(AdvisedLanes::default())),
+        closed_lanes: ClosedLanes::default(),
+        log_scope: LogScope::from_env(&load_network()),
+    };
+    if !route_options.log_scope.is_all() {
+        println!("Lane-level events logged for the lanes tagged in RTS_LOG_TAGS only");
+    }
+    if route_options.congestion_aware {
+        println!("Congestion-aware routing enabled");
+    }
+    if route_options.dynamic {
+        println!("Dynamic routing enabled: cars re-plan at every intersection");
+    }
+    let crossing_config = CrossingConfig::from_env();
+    if !crossing_config.is_empty() {
+        let junctions: Vec<u32> = crossing_config.junctions().map(|(junction, _)| junction).collect();
+        println!("Pedestrian crossings at junctions {:?}: {}s walk phase every {} cycles",
+                 junctions, crossing_config.walk.as_secs(), crossing_config.every);
+    }
+    let scenario = Scenario::from_env(&all_lanes);
+    if scenario.event_count() > 0 {
+        println!("Running a scenario of {} events", scenario.event_count());
+    }
+    if !scenario.destinations().is_empty() {
+        println!("Scenario destinations for {} input lanes", scenario.destinations().entry_count());
+    }
+
     // 2. Filter boundary lanes.
-    let entry_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::InputBoundary)
-        .cloned()
-        .collect();
-    let exit_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::OutputBoundary)
-        .cloned()
-        .collect();
+    let draws = Arc::new(TripDraws {
+        boundary: BoundaryLanes::from_lanes(&all_lanes),
+        destinations: scenario.destinations().clone(),
+    });
+    // A scenario's demand replaces the run's own, unless a replay supplies the vehicles.
+    let generator = match scenario.demand() {
+        Some(scenario_demand) if replay.is_none() => {
+            println!("Scenario demand: {}s of generated traffic", scenario_demand.duration.as_secs());
+            Some(scenario_demand.generator(clock.scale()))
+        }
+        _ => generator,
+    };
 
-    // 3. Launch 30 car threads.
-    let mut handles = vec![];
-    for car_id in 1..=30 {
-        let tl_clone = Arc::clone(&traffic_lights);
-        let log_tx_clone = log_tx.clone();
-        let result_tx_clone = result_tx.clone();
-        let entry_clone = entry_lanes.clone();
-        let exit_clone = exit_lanes.clone();
-        let sim_event_clone = Arc::clone(&sim_event);      
-
-        let handle = thread::spawn(move || {
-            let metrics = simulate_car(car_id, tl_clone, log_tx_clone, &entry_clone, &exit_clone,  sim_event_clone);
-            result_tx_clone.send(metrics).unwrap();
-        });
-        handles.push(handle);
+    // 3. Launch the vehicle threads, drawing each vehicle's kind from the mix.
+    let seed = run_seed();
+    let mut rng = StdRng::seed_from_u64(seed.into());
+    // A generator's schedule is drawn first, since it decides how many vehicles there are.
+    let schedule = match (&replay, &generator) {
+        (None, Some(generator)) => {
+            let span = generator.duration.mul_f64(clock.scale());
+            Some(generator.schedule(&draws.boundary.entry, span, &mut rng))
+        }
+        _ => None,
+    };
+    let car_count = match (&replay, &schedule) {
+        (Some(replay), _) => {
+            println!("Replaying {} vehicles from {} (hash {})", replay.vehicles.len(), replay.path, replay.hash);
+            replay.vehicles.len() as u32
+        }
+        (None, Some(schedule)) => {
+            println!("Run seed {} (set RTS_SEED={} to repeat the same demand)", seed, seed);
+            println!("Generating {} vehicles across {} input lanes", schedule.len(), draws.boundary.entry.len());
+            schedule.len() as u32
+        }
+        (None, None) => {
+            println!("Run seed {} (set RTS_SEED={} to repeat the same demand)", seed, seed);
+            if let Arrivals::Continuous { per_minute } = demand.arrivals {
+                println!("Spawning {} vehicles at {} per minute", demand.cars, per_minute);
+            }
+            demand.cars
+        }
+    };
+    let recorder = Recorder::from_env().map(Arc::new);
+    if let Some(recorder) = &recorder {
+        println!("Recording vehicles to {}", recorder.path());
     }
+    log_tx.send(LogEvent::new(
+        "Simulation",
+        clock.now_secs(),
+        EventKind::RunStarted { seed, car_count, time_scale: clock.scale() },
+    )).ok();
+    let mix = VehicleMix::from_env();
+    let junctions = Junctions { lights: Arc::clone(&traffic_lights), boxes: Arc::new(JunctionBoxes::new(clock)) };
+    let launcher = CarLauncher {
+        junctions: junctions.clone(),
+        log_tx: log_tx.clone(),
+        result_tx,
+        sim_event: Arc::clone(&sim_event),
+        route_options: route_options.clone(),
+        clock,
+        recorder: recorder.clone(),
+    };
+    let replay_hash = replay.as_ref().map(|replay| replay.hash.clone());
+    let replaying = replay.is_some();
+    let mut handles = vec![];
+    // Replayed vehicles are launched at their times by their own thread.
+    let replay_handle = replay.map(|replay| {
+        let launcher = launcher.clone();
+        let draws = Arc::clone(&draws);
+        let interrupted = Arc::clone(&interrupted);
+        thread::spawn(move || {
+            let mut handles = Vec::new();
+            for recorded in replay.vehicles {
+                let wait = Duration::from_secs(recorded.spawn_at).saturating_sub(clock.since(run_start));
+                if !clock.sleep_or_shutdown(wait, &interrupted) {
+                    break;
+                }
+                handles.push(launcher.launch(recorded.vehicle(), Arc::clone(&draws), recorded.spawn_at));
+            }
+            handles
+        })
+    });
+    let drawn: Vec<Vehicle> = if replaying {
+        Vec::new()
+    } else {
+        (1..=car_count).map(|car_id| Vehicle::new(car_id, mix.sample(&mut rng), &mut rng)).collect()
+    };
+    // Drawn after every vehicle, so crossings leave the vehicles of a seed unchanged.
+    let pedestrian_seed: u64 = rng.random();
+    // Likewise for scenario bursts, after the pedestrians, and arrival times last.
+    let burst_seed: u64 = rng.random();
+    let arrival_seed: u64 = rng.random();
+    // With an arrival rate or a generator the drawn vehicles are launched one by one by
+    // their own thread, a generated vehicle entering on the lane it was generated for.
+    let timed: Vec<(Duration, Vehicle, Arc<TripDraws>)> = match (&schedule, demand.arrivals) {
+        (Some(schedule), _) => {
+            let entering: HashMap<u32, Arc<TripDraws>> = draws
+                .boundary
+                .entry
+                .iter()
+                .map(|lane| (lane.id, Arc::new(draws.entering_on(&[lane.id]))))
+                .collect();
+            schedule
+                .iter()
+                .zip(drawn)
+                .map(|(arrival, vehicle)| (arrival.at, vehicle, Arc::clone(&entering[&arrival.entry_lane])))
+                .collect()
+        }
+        (None, Arrivals::AllAtOnce) => {
+            handles.extend(drawn.into_iter().map(|vehicle| launcher.launch(vehicle, Arc::clone(&draws), 0)));
+            Vec::new()
+        }
+        (None, Arrivals::Continuous { .. }) => {
+            let mut rng = StdRng::seed_from_u64(arrival_seed);
+            let mut spawn_at = Duration::ZERO;
+            drawn
+                .into_iter()
+                .map(|vehicle| {
+                    let at = spawn_at;
+                    spawn_at += demand.gap(rng.random());
+                    (at, vehicle, Arc::clone(&draws))
+                })
+                .collect()
+        }
+    };
+    let arrival_handle = (!timed.is_empty()).then(|| {
+        let launcher = launcher.clone();
+        let interrupted = Arc::clone(&interrupted);
+        thread::spawn(move || {
+            let mut handles = Vec::new();
+            for (spawn_at, vehicle, draws) in timed {
+                if !clock.sleep_or_shutdown(spawn_at.saturating_sub(clock.since(run_start)), &interrupted) {
+                    break;
+                }
+                handles.push(launcher.launch(vehicle, draws, spawn_at.as_secs()));
+            }
+            handles
+        })
+    });
+
+    //fire the scenario's events; burst vehicles are numbered after the others,
+    //and come from the replay file instead when replaying
+    let scenario_handle = (!scenario.is_empty()).then(|| {
+        let links = ScenarioLinks {
+            closed_lanes: Arc::clone(&route_options.closed_lanes),
+            rec_tx,
+            log_tx: log_tx.clone(),
+        };
+        let launcher = launcher.clone();
+        let draws = Arc::clone(&draws);
+        let interrupted = Arc::clone(&interrupted);
+        thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(burst_seed);
+            let mut next_car_id = car_count + 1;
+            scenario::run_scenario(scenario, run_start, links, &interrupted, clock, |at, count, entry_lanes| {
+                if replaying {
+                    return Vec::new();
+                }
+                let burst_draws = if entry_lanes.is_empty() {
+                    Arc::clone(&draws)
+                } else {
+                    Arc::new(draws.entering_on(entry_lanes))
+                };
+                (0..count)
+                    .map(|_| {
+                        let vehicle = Vehicle::new(next_car_id, mix.sample(&mut rng), &mut rng);
+                        next_car_id += 1;
+                        launcher.launch(vehicle, Arc::clone(&burst_draws), at)
+                    })
+                    .collect()
+            })
+        })
+    });
+    drop(launcher);
 
-    //send data to the analyzer every 100ms
+    //send snapshots to the analyzer until the cars are done, faster while lane
+    //counts are changing quickly and slower while the network is quiet, and
+    //pick up the reroute advisories it sends back
     let sim_event_sender = Arc::clone(&sim_event);
+    let snapshot_boxes = Arc::clone(&junctions.boxes);
+    let advisories = Arc::clone(&route_options.advisories);
     let sim_tx_clone = analyzer_tx.clone();
-    thread::spawn(move || {//dont let the thread start immediately otherwise it will end directly
-        loop {
-            thread::sleep(Duration::from_millis(5000)); //make sure there is a delay first and repeats every 5s
-            // Send a clone of the shared SimEvent
-            if let Ok(lanes) = sim_event_sender.lock() {
-                let lanes_clone = lanes.clone(); // Clone the HashMap
-                sim_tx_clone.send(lanes_clone).ok();
+    let cars_done = shutdown::new_flag();
+    let cars_done_clone = Arc::clone(&cars_done);
+    let exporting = export.is_some();
+    let snapshot_handle = thread::spawn(move || {
+        let mut lane_series: Vec<LaneSample> = Vec::new();
+        let mut cadence = CadenceController::new(cadence::FLOOR, cadence::CEILING, cadence::INITIAL);
+        //wait one interval first so the snapshot isn't taken before any car moves
+        while clock.sleep_or_shutdown(cadence.interval(), &cars_done_clone) {
+            let elapsed = cadence.interval();
+            let lanes = match sim_event_sender.lock() {
+                Ok(lanes) => lanes.clone(),
+                Err(_) => break,
+            };
+            let next = cadence.observe(&lanes, elapsed);
+            *latest_counts.lock().unwrap() = lanes.clone();
+            if exporting {
+                lane_series.extend(export::lane_samples(clock.now_secs(), &lanes));
+            }
+            let snapshot =
+                LaneSnapshot { lanes, interval_ms: next.as_millis() as u64, departures: snapshot_boxes.departures() };
+            sim_tx_clone.send(snapshot).ok();
+            for recommendation in advisory_rx.try_iter() {
+                match recommendation {
+                    Recommendation::SuggestReroute(advisory) => {
+                        println!("Routing around lanes {:?} until {}", advisory.lanes, advisory.expires_at);
+                        advisories.lock().unwrap().apply(&advisory);
+                    }
+                    other => println!("Recommendation ignored: {:?} is for the traffic lights", other),
+                }
             }
-            /* 
-            // Stop sending if no cars are left
-            let stats = sim_event_sender.lock().unwrap();
-            if stats.values().sum::<u32>() == 0 {
-                break;
-            } */
         }
+        lane_series
+    });
+
+    //draw pedestrian arrivals once a minute until the cars are done
+    let pedestrians_done = Arc::clone(&cars_done);
+    let pedestrian_handle = (!crossing_config.is_empty()).then(|| {
+        thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(pedestrian_seed);
+            while clock.sleep_or_shutdown(crossings::ARRIVAL_INTERVAL, &pedestrians_done) {
+                let arrivals = crossings::draw_arrivals(&crossing_config, &mut rng);
+                pedestrian_tx.send(PedestrianUpdate { arrivals }).ok();
+            }
+        })
     });
 
     //if no more cars it will terminate
     for handle in handles {
         handle.join().unwrap();
     }
+    for spawner in [replay_handle, arrival_handle, scenario_handle].into_iter().flatten() {
+        for handle in spawner.join().unwrap() {
+            handle.join().unwrap();
+        }
+    }
+    if shutdown::is_requested(&interrupted) {
+        log_tx.send(LogEvent {
+            source: "Simulation".to_string(),
+            message: "Run interrupted; the summary covers the vehicles spawned before it".to_string(),
+            timestamp: clock.now_secs(),
+            level: Level::Info,
+            kind: EventKind::Generic,
+        }).ok();
+    }
+    // Stop the snapshot publisher so the analyzer sees its channel close.
+    shutdown::request(&cars_done);
+    let lane_series = snapshot_handle.join().unwrap_or_default();
+    if let Some(handle) = pedestrian_handle {
+        handle.join().ok();
+    }
+
+    // 4. Collect every car's metrics and compute average times over the cars that drove.
+    let mut metrics: Vec<CarMetrics> = Vec::new();
+    let mut failures: Vec<GenerationFailed> = Vec::new();
+    // Every launcher, and with it every result sender, is gone by now.
+    for outcome in result_rx.iter() {
+        match outcome {
+            Ok(m) => metrics.push(m),
+            Err(failed) => {
+                println!("Car {} found no valid trip in {} draws; check the lane topology", failed.car_id, failed.attempts);
+                failures.push(failed);
+            }
+        }
+    }
+    log_averages(&metrics, &log_tx, &clock);
+    if let Some(export) = &export {
+        export_metrics(export, &metrics, &lane_series);
+    }
+
+    if let Some(recorder) = recorder {
+        match recorder.finish() {
+            Ok((count, hash)) => println!("Recorded {} vehicles to {} (hash {})", count, recorder.path(), hash),
+            Err(e) => eprintln!("Failed to record vehicles: {}", e),
+        }
+    }
+
+    // 5. Final summary, printed and logged for the monitoring sinks.
+    let mut summary = SimulationSummary::from_metrics(&metrics, &failures, clock.since(run_start));
+    summary.replay_hash = replay_hash;
+    summary.junction_utilization = junctions.boxes.utilization();
+    summary.corridors = Coordination::from_env(&all_lanes, &load_network(), &JunctionTimings::from_env()).stats(&metrics);
+    summary.count_tags(&metrics, &load_network());
+    if let Some(schedule) = &schedule {
+        summary.count_generated(schedule.iter().map(|arrival| arrival.entry_lane));
+    }
+    budget::flush("Simulation");
+    summary.time_budget = budget::report();
+    println!("{}", summary);
+    let summary_log = LogEvent::new("Simulation", clock.now_secs(), EventKind::Summary(Box::new(summary)));
+    log_tx.send(summary_log).ok();
+}
+
+/// Writes the journeys of the cars that drove and the sampled lane counts
+/// where `export` says.
+pub fn export_metrics(export: &Export, metrics: &[CarMetrics], lane_series: &[LaneSample]) {
+    let journeys: Vec<Journey> = metrics
+        .iter()
+        .map(|m| Journey {
+            car_id: m.id,
+            entry_lane: m.entry_lane,
+            exit_lane: m.exit_lane,
+            wait: m.wait_time,
+            drive: m.drive_time,
+            total: m.total_time,
+        })
+        .collect();
+    match export.write(&journeys, lane_series) {
+        Ok((cars, lanes)) => println!(
+            "Exported {} journeys to {} and {} lane counts to {}",
+            journeys.len(), cars.display(), lane_series.len(), lanes.display()
+        ),
+        Err(e) => eprintln!("Failed to export metrics: {}", e),
+    }
+}
 
-    // 4. Compute average times.
+/// Logs the average wait, drive and total times of the cars that drove, and
+/// how many of each kind there were.
+pub fn log_averages(metrics: &[CarMetrics], log_tx: &Sender<LogEvent>, clock: &SimClock) {
+    let completed = metrics.len().max(1) as f64;
     let mut total_wait = 0.0;
     let mut total_drive = 0.0;
     let mut total_total = 0.0;
-    for _ in 1..=30 {
-        let m = result_rx.recv().unwrap();
+    let mut kind_counts: HashMap<VehicleKind, u32> = HashMap::new();
+    for m in metrics {
+        *kind_counts.entry(m.kind).or_insert(0) += 1;
         total_wait += m.wait_time;
         total_drive += m.drive_time;
         total_total += m.total_time;
@@ -342,8 +1007,184 @@ pub fn run_simulation(
     let avg_log = LogEvent {
         source: "Simulation".to_string(),
         message: format!("Average Times - Wait: {:.2} s, Drive: {:.2} s, Total: {:.2} s",
-                         total_wait / 30.0, total_drive / 30.0, total_total / 30.0),
-        timestamp: current_time_secs(),
+                         total_wait / completed, total_drive / completed, total_total / completed),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Generic,
     };
     log_tx.send(avg_log).ok();
+
+    let mix_summary: Vec<String> = VehicleKind::ALL
+        .iter()
+        .filter_map(|kind| kind_counts.get(kind).map(|count| format!("{} {}", count, kind)))
+        .collect();
+    let mix_log = LogEvent {
+        source: "Simulation".to_string(),
+        message: format!("Vehicle mix: {}", mix_summary.join(", ")),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Generic,
+    };
+    log_tx.send(mix_log).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc;
+
+    use crate::traffic_light::{initialize_traffic_lights, LightColor};
+
+    /// An internal lane holding `capacity` footprint units.
+    fn lane_of(capacity: u32) -> Lane {
+        Lane {
+            id: 1,
+            start_intersection: 1,
+            end_intersection: 2,
+            length: 100.0,
+            capacity,
+            parallel_count: 1,
+            movement: None,
+            category: LaneCategory::Internal,
+        }
+    }
+
+    /// Junctions on `clock` with every light of `lanes` green.
+    fn all_green(lanes: &[Lane], clock: SimClock) -> Junctions {
+        let lights = initialize_traffic_lights();
+        let green: Vec<(u32, LightColor)> = lanes.iter().map(|lane| (lane.id, LightColor::Green)).collect();
+        lights.set_colors(&green);
+        Junctions { lights, boxes: Arc::new(JunctionBoxes::new(clock)) }
+    }
+
+    /// Static routing, with lane-level events sent for the lanes in `log_scope`.
+    fn route_options(log_scope: LogScope) -> RouteOptions {
+        RouteOptions {
+            congestion_aware: false,
+            dynamic: false,
+            advisories: SharedAdvisories::default(),
+            closed_lanes: ClosedLanes::default(),
+            log_scope,
+        }
+    }
+
+    #[test]
+    fn full_lane_refuses_the_next_vehicle() {
+        let sim_event: SimEvent = Arc::default();
+        let lane = lane_of(4);
+        for expected in 1..=4 {
+            assert_eq!(try_enter_lane(&sim_event, &lane, 1), Some(expected));
+        }
+        assert_eq!(try_enter_lane(&sim_event, &lane, 1), None);
+        // A slot freed is a slot taken, but not by a vehicle that needs more.
+        leave_lane(&sim_event, lane.id, 1);
+        assert_eq!(try_enter_lane(&sim_event, &lane, VehicleKind::Bus.footprint()), None);
+        assert_eq!(try_enter_lane(&sim_event, &lane, 1), Some(4));
+        assert_eq!(sim_event.lock().unwrap()[&lane.id], 4);
+    }
+
+    #[test]
+    fn empty_lane_always_admits() {
+        let sim_event: SimEvent = Arc::default();
+        let lane = lane_of(2);
+        let bus = VehicleKind::Bus.footprint();
+        assert!(bus > lane.capacity);
+        assert_eq!(try_enter_lane(&sim_event, &lane, bus), Some(bus));
+        assert_eq!(try_enter_lane(&sim_event, &lane, 1), None);
+        leave_lane(&sim_event, lane.id, bus);
+        assert_eq!(try_enter_lane(&sim_event, &lane, bus), Some(bus));
+        // Even a lane without room for anything takes a first vehicle.
+        assert_eq!(try_enter_lane(&SimEvent::default(), &lane_of(0), 1), Some(1));
+    }
+
+    #[test]
+    fn car_blocked_on_a_full_lane_reroutes() {
+        let (lanes, network) = (load_lanes(), load_network());
+        let internal: Vec<Lane> = lanes.iter().filter(|l| l.category == LaneCategory::Internal).cloned().collect();
+        let boundary = BoundaryLanes::from_lanes(&lanes);
+        // A trip whose first internal lane has a way around it.
+        let (entry, exit, blocked) = boundary
+            .entry
+            .iter()
+            .flat_map(|entry| boundary.exit.iter().map(move |exit| (entry, exit)))
+            .find_map(|(entry, exit)| {
+                let route = find_lane_path(entry.end_intersection, exit.start_intersection, &internal, &network, None).ok()?;
+                let first = route.first()?.clone();
+                let others: Vec<Lane> = internal.iter().filter(|l| l.id != first.id).cloned().collect();
+                find_lane_path(first.start_intersection, exit.start_intersection, &others, &network, None).ok()?;
+                Some((entry.id, exit.id, first))
+            })
+            .expect("no trip with a detour on the built-in network");
+
+        let clock = SimClock::new(200.0);
+        let junctions = all_green(&lanes, clock);
+        let draws = TripDraws { boundary, destinations: OdMatrix::default() };
+        let route_options = route_options(LogScope::all());
+        let sim_event = initialize_simdata();
+        sim_event.lock().unwrap().insert(blocked.id, blocked.capacity);
+        let vehicle = Vehicle { id: 1, kind: VehicleKind::Car, speed: 50.0, trip_seed: 0, replayed_trip: Some((entry, exit)) };
+        let (log_tx, log_rx) = mpsc::channel();
+
+        let metrics = simulate_car(vehicle, &junctions, log_tx, &draws, Arc::clone(&sim_event), &route_options, clock)
+            .unwrap_or_else(|_| panic!("no trip from {} to {}", entry, exit));
+        assert!(metrics.lanes.iter().all(|visit| visit.lane_id != blocked.id), "drove the full lane {}", blocked.id);
+        assert!(metrics.wait_time >= BLOCKED_REROUTE_SECS, "rerouted after {:.1}s", metrics.wait_time);
+        let rerouted = log_rx
+            .try_iter()
+            .any(|event| event.message.starts_with(&format!("Lane {} full for {:.0}s; re-routed", blocked.id, BLOCKED_REROUTE_SECS)));
+        assert!(rerouted);
+        // The full lane kept its vehicles; every slot the car took is back.
+        let counts = sim_event.lock().unwrap();
+        assert_eq!(counts[&blocked.id], blocked.capacity);
+        assert!(counts.iter().all(|(&id, &count)| id == blocked.id || count == 0));
+    }
+
+    #[test]
+    fn scoped_logging_sends_nothing_for_untagged_lanes() {
+        let lanes = load_lanes();
+        let internal: Vec<Lane> = lanes.iter().filter(|l| l.category == LaneCategory::Internal).cloned().collect();
+        let boundary = BoundaryLanes::from_lanes(&lanes);
+        // A trip of several internal lanes; only the junction it enters at is tagged.
+        let (entry, exit) = boundary
+            .entry
+            .iter()
+            .flat_map(|entry| boundary.exit.iter().map(move |exit| (entry, exit)))
+            .find(|(entry, exit)| {
+                find_lane_path(entry.end_intersection, exit.start_intersection, &internal, &load_network(), None)
+                    .is_ok_and(|route| route.len() >= 3)
+            })
+            .expect("no long trip on the built-in network");
+        let study_area = HashMap::from([(entry.end_intersection, vec!["study-area".to_string()])]);
+        let network = load_network().with_tags(&lanes, &HashMap::new(), &study_area);
+        let tagged: HashSet<u32> = network.tagged_lanes("study-area").collect();
+
+        let clock = SimClock::new(200.0);
+        let draws = TripDraws { boundary: boundary.clone(), destinations: OdMatrix::default() };
+        let vehicle = Vehicle { id: 1, kind: VehicleKind::Car, speed: 50.0, trip_seed: 0, replayed_trip: Some((entry.id, exit.id)) };
+        let (log_tx, log_rx) = mpsc::channel();
+        let scope = LogScope::tagged(&network, &["study-area"]);
+        let metrics = simulate_car(vehicle, &all_green(&lanes, clock), log_tx, &draws, initialize_simdata(), &route_options(scope), clock)
+            .unwrap_or_else(|_| panic!("no trip from {} to {}", entry.id, exit.id));
+
+        let lane_events: Vec<u32> = log_rx
+            .try_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::CarProgress { lane_id, .. } | EventKind::LaneWait { lane_id, .. } | EventKind::RightTurnOnRed { lane_id, .. } => Some(lane_id),
+                _ => None,
+            })
+            .collect();
+        assert!(lane_events.contains(&entry.id), "{:?}", lane_events);
+        assert!(lane_events.iter().all(|lane_id| tagged.contains(lane_id)), "{:?} outside {:?}", lane_events, tagged);
+        // The car drove untagged lanes too; only their events were held back.
+        assert!(metrics.lanes.iter().any(|visit| !tagged.contains(&visit.lane_id)));
+
+        // The summary still counts every visit, and those of the tag apart.
+        let mut summary = SimulationSummary::from_metrics(std::slice::from_ref(&metrics), &[], Duration::from_secs(60));
+        summary.count_tags(std::slice::from_ref(&metrics), &network);
+        let in_tag = metrics.lanes.iter().filter(|visit| tagged.contains(&visit.lane_id)).count();
+        assert_eq!(summary.by_tag.len(), 1);
+        assert_eq!((summary.by_tag[0].tag.as_str(), summary.by_tag[0].visits as usize), ("study-area", in_tag));
+        assert_eq!(summary.by_tag[0].lanes, tagged.len());
+    }
 }
@@ -0,0 +1,820 @@
+// sqlite_sink.rs
+//
+// Optional SQLite persistence of the monitoring event stream, for ad hoc
+// questions that are awkward to answer from the console or the CSV export
+// ("average wait on lane 1042 between minutes 10 and 20").
+//
+// Every event lands in `events`; structured events are also written to a
+// typed table (vehicles, generation_failures, car_metrics, car_progress, lane_waits,
+// right_turns_on_red, phase_reports, phase_decisions, pedestrian_phases, recommendations,
+// reroute_advisories, scenario_events, monitor_overloads, summaries).
+// Several runs can share a database: each sink adds a row to `runs` when it
+// opens, tags every row it writes with that run_id, and fills in the run's
+// seed, vehicle count and time scale from the simulation's RunStarted event.
+// Rows are buffered and written in batched transactions so ingest keeps up
+// with the simulation. The schema version is stored in `PRAGMA user_version`.
+//
+// Only compiled with the `sqlite` feature.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+
+use crate::scenario::ScenarioAction;
+use crate::system_monitoring::{EventKind, LogEvent};
+use rts_core::progress::LaneTransition;
+
+/// Bump whenever the schema below changes.
+pub const SCHEMA_VERSION: i32 = 13;
+
+/// Events buffered before they are committed in one transaction.
+const BATCH_SIZE: usize = 256;
+
+const SCHEMA: &str = "
+CREATE TABLE events (
+    id        INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    source    TEXT NOT NULL,
+    kind      TEXT NOT NULL,
+    message   TEXT NOT NULL
+);
+CREATE INDEX events_timestamp ON events (timestamp);
+
+CREATE TABLE car_metrics (
+    event_id     INTEGER NOT NULL REFERENCES events (id),
+    timestamp    INTEGER NOT NULL,
+    car_id       INTEGER NOT NULL,
+    entry_lane   INTEGER NOT NULL,
+    exit_lane    INTEGER NOT NULL,
+    route_length REAL NOT NULL,
+    wait_time    REAL NOT NULL,
+    drive_time   REAL NOT NULL,
+    total_time   REAL NOT NULL
+);
+CREATE INDEX car_metrics_timestamp ON car_metrics (timestamp);
+CREATE INDEX car_metrics_car ON car_metrics (car_id);
+
+CREATE TABLE lane_waits (
+    event_id  INTEGER NOT NULL REFERENCES events (id),
+    timestamp INTEGER NOT NULL,
+    car_id    INTEGER NOT NULL,
+    lane_id   INTEGER NOT NULL,
+    junction  INTEGER NOT NULL,
+    wait_time REAL NOT NULL
+);
+CREATE INDEX lane_waits_timestamp ON lane_waits (timestamp);
+CREATE INDEX lane_waits_lane ON lane_waits (lane_id);
+CREATE INDEX lane_waits_junction ON lane_waits (junction);
+
+CREATE TABLE phase_reports (
+    event_id    INTEGER NOT NULL REFERENCES events (id),
+    timestamp   INTEGER NOT NULL,
+    junction    INTEGER NOT NULL,
+    phase       INTEGER NOT NULL,
+    green_lanes TEXT NOT NULL,
+    red_lanes   TEXT NOT NULL
+);
+CREATE INDEX phase_reports_timestamp ON phase_reports (timestamp);
+CREATE INDEX phase_reports_junction ON phase_reports (junction);
+
+CREATE TABLE recommendations (
+    event_id       INTEGER NOT NULL REFERENCES events (id),
+    timestamp      INTEGER NOT NULL,
+    lane_id        INTEGER NOT NULL,
+    new_green_time INTEGER NOT NULL
+);
+CREATE INDEX recommendations_timestamp ON recommendations (timestamp);
+CREATE INDEX recommendations_lane ON recommendations (lane_id);
+";
+
+/// Tables added after version 1, in order. A fresh database applies SCHEMA
+/// and then all of them; an older one only the versions it is missing.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (2, SCHEMA_V2),
+    (3, SCHEMA_V3),
+    (4, SCHEMA_V4),
+    (5, SCHEMA_V5),
+    (6, SCHEMA_V6),
+    (7, SCHEMA_V7),
+    (8, SCHEMA_V8),
+    (9, SCHEMA_V9),
+    (10, SCHEMA_V10),
+    (11, SCHEMA_V11),
+    (12, SCHEMA_V12),
+    (13, SCHEMA_V13),
+];
+
+const SCHEMA_V2: &str = "
+CREATE TABLE summaries (
+    event_id         INTEGER NOT NULL REFERENCES events (id),
+    timestamp        INTEGER NOT NULL,
+    vehicles         INTEGER NOT NULL,
+    duration_secs    REAL NOT NULL,
+    mean_wait        REAL NOT NULL,
+    median_wait      REAL NOT NULL,
+    p95_wait         REAL NOT NULL,
+    mean_drive       REAL NOT NULL,
+    busiest_junction INTEGER,
+    summary_json     TEXT NOT NULL
+);
+";
+
+const SCHEMA_V3: &str = "
+CREATE TABLE vehicles (
+    event_id     INTEGER NOT NULL REFERENCES events (id),
+    timestamp    INTEGER NOT NULL,
+    car_id       INTEGER NOT NULL,
+    vehicle_kind TEXT NOT NULL,
+    speed        REAL NOT NULL,
+    entry_lane   INTEGER NOT NULL,
+    exit_lane    INTEGER NOT NULL,
+    route        TEXT NOT NULL
+);
+CREATE INDEX vehicles_car ON vehicles (car_id);
+";
+
+const SCHEMA_V4: &str = "
+CREATE TABLE reroute_advisories (
+    event_id   INTEGER NOT NULL REFERENCES events (id),
+    timestamp  INTEGER NOT NULL,
+    lanes      TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+";
+
+/// Rows written before version 5 keep a NULL run_id.
+const SCHEMA_V5: &str = "
+CREATE TABLE runs (
+    id         INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    event_id   INTEGER REFERENCES events (id),
+    seed       INTEGER,
+    car_count  INTEGER,
+    time_scale REAL
+);
+ALTER TABLE events ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE vehicles ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE car_metrics ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE lane_waits ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE phase_reports ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE recommendations ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE reroute_advisories ADD COLUMN run_id INTEGER REFERENCES runs (id);
+ALTER TABLE summaries ADD COLUMN run_id INTEGER REFERENCES runs (id);
+CREATE INDEX events_run ON events (run_id);
+CREATE INDEX car_metrics_run ON car_metrics (run_id);
+";
+
+const SCHEMA_V6: &str = "
+CREATE TABLE generation_failures (
+    run_id    INTEGER REFERENCES runs (id),
+    event_id  INTEGER NOT NULL REFERENCES events (id),
+    timestamp INTEGER NOT NULL,
+    car_id    INTEGER NOT NULL,
+    attempts  INTEGER NOT NULL
+);
+";
+
+const SCHEMA_V7: &str = "
+CREATE TABLE pedestrian_phases (
+    run_id    INTEGER REFERENCES runs (id),
+    event_id  INTEGER NOT NULL REFERENCES events (id),
+    timestamp INTEGER NOT NULL,
+    junction  INTEGER NOT NULL,
+    crossings TEXT NOT NULL,
+    walk_secs INTEGER NOT NULL
+);
+CREATE INDEX pedestrian_phases_junction ON pedestrian_phases (junction);
+";
+
+const SCHEMA_V8: &str = "
+CREATE TABLE phase_decisions (
+    run_id    INTEGER REFERENCES runs (id),
+    event_id  INTEGER NOT NULL REFERENCES events (id),
+    timestamp INTEGER NOT NULL,
+    junction  INTEGER NOT NULL,
+    phase     INTEGER NOT NULL,
+    demand    TEXT NOT NULL,
+    overdue   INTEGER NOT NULL
+);
+CREATE INDEX phase_decisions_junction ON phase_decisions (junction);
+";
+
+const SCHEMA_V9: &str = "
+CREATE TABLE car_progress (
+    run_id      INTEGER REFERENCES runs (id),
+    event_id    INTEGER NOT NULL REFERENCES events (id),
+    timestamp   INTEGER NOT NULL,
+    car_id      INTEGER NOT NULL,
+    lane_id     INTEGER NOT NULL,
+    transition  TEXT NOT NULL,
+    route_index INTEGER NOT NULL
+);
+CREATE INDEX car_progress_car ON car_progress (run_id, car_id);
+";
+
+const SCHEMA_V10: &str = "
+CREATE TABLE scenario_events (
+    run_id        INTEGER REFERENCES runs (id),
+    event_id      INTEGER NOT NULL REFERENCES events (id),
+    timestamp     INTEGER NOT NULL,
+    at_secs       INTEGER NOT NULL,
+    action        TEXT NOT NULL,
+    lane_id       INTEGER,
+    vehicle_count INTEGER,
+    entry_lanes   TEXT,
+    duration_secs INTEGER,
+    green_secs    INTEGER
+);
+";
+
+const SCHEMA_V11: &str = "
+CREATE TABLE monitor_overloads (
+    run_id       INTEGER REFERENCES runs (id),
+    event_id     INTEGER NOT NULL REFERENCES events (id),
+    timestamp    INTEGER NOT NULL,
+    behind_secs  INTEGER NOT NULL,
+    max_lag_secs INTEGER NOT NULL
+);
+";
+
+const SCHEMA_V12: &str = "
+ALTER TABLE summaries ADD COLUMN replay_hash TEXT;
+";
+
+const SCHEMA_V13: &str = "
+CREATE TABLE right_turns_on_red (
+    run_id    INTEGER REFERENCES runs (id),
+    event_id  INTEGER NOT NULL REFERENCES events (id),
+    timestamp INTEGER NOT NULL,
+    car_id    INTEGER NOT NULL,
+    lane_id   INTEGER NOT NULL,
+    into_lane INTEGER NOT NULL,
+    occupancy INTEGER NOT NULL
+);
+CREATE INDEX right_turns_on_red_lane ON right_turns_on_red (lane_id);
+";
+
+/// Tables counted in the end-of-run report, in order.
+const REPORTED_TABLES: &[&str] = &[
+    "events",
+    "vehicles",
+    "generation_failures",
+    "car_metrics",
+    "car_progress",
+    "lane_waits",
+    "right_turns_on_red",
+    "phase_reports",
+    "phase_decisions",
+    "pedestrian_phases",
+    "recommendations",
+    "reroute_advisories",
+    "scenario_events",
+    "monitor_overloads",
+    "summaries",
+];
+
+/// Canned queries available as `RTS query <db> <name>`.
+pub const CANNED_QUERIES: &[(&str, &str)] = &[
+    (
+        "runs",
+        "SELECT r.id AS run_id, r.started_at, r.seed, r.car_count, r.time_scale,
+                COUNT(c.car_id) AS cars, ROUND(AVG(c.wait_time), 2) AS avg_wait,
+                ROUND(AVG(c.total_time), 2) AS avg_total
+         FROM runs r LEFT JOIN car_metrics c ON c.run_id = r.id
+         GROUP BY r.id
+         ORDER BY r.id",
+    ),
+    (
+        "top-delayed-lanes",
+        "SELECT lane_id, COUNT(*) AS cars, ROUND(AVG(wait_time), 2) AS avg_wait,
+                ROUND(MAX(wait_time), 2) AS max_wait
+         FROM lane_waits
+         GROUP BY lane_id
+         ORDER BY avg_wait DESC
+         LIMIT 10",
+    ),
+    (
+        "junction-delay",
+        "SELECT run_id, junction,
+                (timestamp - (SELECT MIN(e.timestamp) FROM events e WHERE e.run_id IS w.run_id)) / 300 * 5 AS minute,
+                COUNT(*) AS cars, ROUND(AVG(wait_time), 2) AS avg_wait
+         FROM lane_waits w
+         GROUP BY run_id, junction, minute
+         ORDER BY run_id, junction, minute",
+    ),
+    (
+        "recommendations",
+        "SELECT lane_id, COUNT(*) AS issued, MAX(new_green_time) AS max_green
+         FROM recommendations
+         GROUP BY lane_id
+         ORDER BY issued DESC",
+    ),
+    (
+        // Every row should report zero violations.
+        "check",
+        "SELECT 'typed rows without a matching event' AS rule,
+                (SELECT COUNT(*) FROM car_metrics c LEFT JOIN events e ON e.id = c.event_id
+                    WHERE e.id IS NULL OR e.kind != 'car_completed')
+              + (SELECT COUNT(*) FROM lane_waits w LEFT JOIN events e ON e.id = w.event_id
+                    WHERE e.id IS NULL OR e.kind != 'lane_wait')
+              + (SELECT COUNT(*) FROM phase_reports p LEFT JOIN events e ON e.id = p.event_id
+                    WHERE e.id IS NULL OR e.kind != 'phase_change')
+              + (SELECT COUNT(*) FROM recommendations r LEFT JOIN events e ON e.id = r.event_id
+                    WHERE e.id IS NULL OR e.kind != 'recommendation')
+              + (SELECT COUNT(*) FROM summaries s LEFT JOIN events e ON e.id = s.event_id
+                    WHERE e.id IS NULL OR e.kind != 'summary')
+              + (SELECT COUNT(*) FROM vehicles v LEFT JOIN events e ON e.id = v.event_id
+                    WHERE e.id IS NULL OR e.kind != 'vehicle_generated')
+              + (SELECT COUNT(*) FROM reroute_advisories a LEFT JOIN events e ON e.id = a.event_id
+                    WHERE e.id IS NULL OR e.kind != 'reroute_advisory')
+              + (SELECT COUNT(*) FROM generation_failures g LEFT JOIN events e ON e.id = g.event_id
+                    WHERE e.id IS NULL OR e.kind != 'vehicle_generation_failed')
+              + (SELECT COUNT(*) FROM pedestrian_phases p LEFT JOIN events e ON e.id = p.event_id
+                    WHERE e.id IS NULL OR e.kind != 'pedestrian_phase')
+              + (SELECT COUNT(*) FROM phase_decisions d LEFT JOIN events e ON e.id = d.event_id
+                    WHERE e.id IS NULL OR e.kind != 'phase_decision')
+              + (SELECT COUNT(*) FROM car_progress p LEFT JOIN events e ON e.id = p.event_id
+                    WHERE e.id IS NULL OR e.kind != 'car_progress')
+              + (SELECT COUNT(*) FROM scenario_events s LEFT JOIN events e ON e.id = s.event_id
+                    WHERE e.id IS NULL OR e.kind != 'scenario_event')
+              + (SELECT COUNT(*) FROM monitor_overloads o LEFT JOIN events e ON e.id = o.event_id
+                    WHERE e.id IS NULL OR e.kind != 'monitor_overloaded')
+              + (SELECT COUNT(*) FROM right_turns_on_red t LEFT JOIN events e ON e.id = t.event_id
+                    WHERE e.id IS NULL OR e.kind != 'right_turn_on_red')
+              + (SELECT COUNT(*) FROM runs r LEFT JOIN events e ON e.id = r.event_id
+                    WHERE r.event_id IS NOT NULL AND (e.id IS NULL OR e.kind != 'run_started')) AS violations
+         UNION ALL
+         SELECT 'structured events without a typed row',
+                (SELECT COUNT(*) FROM events WHERE kind != 'generic')
+              - (SELECT COUNT(*) FROM car_metrics) - (SELECT COUNT(*) FROM lane_waits)
+              - (SELECT COUNT(*) FROM phase_reports) - (SELECT COUNT(*) FROM recommendations)
+              - (SELECT COUNT(*) FROM summaries) - (SELECT COUNT(*) FROM vehicles)
+              - (SELECT COUNT(*) FROM reroute_advisories) - (SELECT COUNT(*) FROM generation_failures)
+              - (SELECT COUNT(*) FROM pedestrian_phases) - (SELECT COUNT(*) FROM phase_decisions)
+              - (SELECT COUNT(*) FROM car_progress) - (SELECT COUNT(*) FROM scenario_events)
+              - (SELECT COUNT(*) FROM monitor_overloads) - (SELECT COUNT(*) FROM right_turns_on_red)
+              - (SELECT COUNT(*) FROM runs WHERE event_id IS NOT NULL)
+         UNION ALL
+         SELECT 'cars completed more than once in a run',
+                (SELECT COUNT(*) FROM (SELECT car_id FROM car_metrics GROUP BY run_id, car_id HAVING COUNT(*) > 1))
+         UNION ALL
+         SELECT 'lane waits exceeding the car''s total wait',
+                (SELECT COUNT(*) FROM car_metrics c
+                    WHERE (SELECT COALESCE(SUM(wait_time), 0) FROM lane_waits w
+                           WHERE w.car_id = c.car_id AND w.run_id IS c.run_id)
+                          > c.wait_time + 0.01)
+         UNION ALL
+         SELECT 'wait plus drive exceeding total time',
+                (SELECT COUNT(*) FROM car_metrics WHERE wait_time + drive_time > total_time + 0.01)",
+    ),
+];
+
+/// Opens `path`, creating the schema on a fresh database, upgrading an older
+/// one, and rejecting a database written by a newer schema version.
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    match version {
+        SCHEMA_VERSION => {}
+        0..SCHEMA_VERSION => {
+            if version == 0 {
+                conn.execute_batch(SCHEMA)?;
+            }
+            for (_, migration) in MIGRATIONS.iter().filter(|(to, _)| *to > version) {
+                conn.execute_batch(migration)?;
+            }
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        other => {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "{}: schema version {} is not supported (expected {})",
+                path.display(), other, SCHEMA_VERSION
+            )))
+        }
+    }
+    Ok(conn)
+}
+
+/// Buffers monitoring events and writes them to SQLite in batches, all
+/// tagged with the run the sink started.
+pub struct SqliteSink {
+    conn: Connection,
+    run_id: i64,
+    pending: Vec<LogEvent>,
+}
+
+impl SqliteSink {
+    /// Opens the database and starts a new run in it.
+    pub fn create(path: &Path) -> rusqlite::Result<Self> {
+        let conn = open(path)?;
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        conn.execute("INSERT INTO runs (started_at) VALUES (?1)", params![started_at])?;
+        let run_id = conn.last_insert_rowid();
+        Ok(SqliteSink { conn, run_id, pending: Vec::with_capacity(BATCH_SIZE) })
+    }
+
+    pub fn run_id(&self) -> i64 {
+        self.run_id
+    }
+
+    /// Rows this run has written to each table, committed ones only.
+    pub fn row_counts(&self) -> rusqlite::Result<Vec<(&'static str, i64)>> {
+        REPORTED_TABLES
+            .iter()
+            .map(|&table| {
+                let sql = format!("SELECT COUNT(*) FROM {} WHERE run_id = ?1", table);
+                let rows = self.conn.query_row(&sql, params![self.run_id], |row| row.get(0))?;
+                Ok((table, rows))
+            })
+            .collect()
+    }
+
+    /// Queues an event, committing the batch once it is full.
+    pub fn write_event(&mut self, event: &LogEvent) -> rusqlite::Result<()> {
+        self.pending.push(event.clone());
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commits every queued event in a single transaction.
+    pub fn flush(&mut self) -> rusqlite::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert_event = tx.prepare_cached(
+                "INSERT INTO events (run_id, timestamp, source, kind, message) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for event in &self.pending {
+                insert_event.execute(params![
+                    self.run_id,
+                    event.timestamp,
+                    event.source,
+                    event.kind.name(),
+                    event.describe()
+                ])?;
+                let event_id = tx.last_insert_rowid();
+                insert_typed(&tx, self.run_id, event_id, event)?;
+            }
+        }
+        tx.commit()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+fn insert_typed(tx: &rusqlite::Transaction, run_id: i64, event_id: i64, event: &LogEvent) -> rusqlite::Result<()> {
+    let ts = event.timestamp;
+    match &event.kind {
+        EventKind::Generic => {}
+        EventKind::RunStarted { seed, car_count, time_scale } => {
+            tx.prepare_cached(
+                "UPDATE runs SET event_id = ?2, seed = ?3, car_count = ?4, time_scale = ?5 WHERE id = ?1",
+            )?
+            .execute(params![run_id, event_id, seed, car_count, time_scale])?;
+        }
+        EventKind::VehicleGenerated { car_id, vehicle_kind, speed, entry_lane, exit_lane, route } => {
+            tx.prepare_cached(
+                "INSERT INTO vehicles (run_id, event_id, timestamp, car_id, vehicle_kind, speed, entry_lane, exit_lane, route)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, vehicle_kind.name(), speed, entry_lane, exit_lane, join_ids(route)])?;
+        }
+        EventKind::VehicleGenerationFailed { car_id, attempts } => {
+            tx.prepare_cached(
+                "INSERT INTO generation_failures (run_id, event_id, timestamp, car_id, attempts)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, attempts])?;
+        }
+        EventKind::CarCompleted { car_id, entry_lane, exit_lane, route_length, wait_time, drive_time, total_time } => {
+            tx.prepare_cached(
+                "INSERT INTO car_metrics (run_id, event_id, timestamp, car_id, entry_lane, exit_lane, route_length,
+                                          wait_time, drive_time, total_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, entry_lane, exit_lane, route_length, wait_time, drive_time, total_time])?;
+        }
+        EventKind::CarProgress { car_id, lane_id, transition, route_index } => {
+            let transition = match transition {
+                LaneTransition::Entered => "entered",
+                LaneTransition::Exited => "exited",
+            };
+            tx.prepare_cached(
+                "INSERT INTO car_progress (run_id, event_id, timestamp, car_id, lane_id, transition, route_index)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, lane_id, transition, *route_index as i64])?;
+        }
+        EventKind::LaneWait { car_id, lane_id, junction, wait_time } => {
+            tx.prepare_cached(
+                "INSERT INTO lane_waits (run_id, event_id, timestamp, car_id, lane_id, junction, wait_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, lane_id, junction, wait_time])?;
+        }
+        EventKind::RightTurnOnRed { car_id, lane_id, into_lane, occupancy } => {
+            tx.prepare_cached(
+                "INSERT INTO right_turns_on_red (run_id, event_id, timestamp, car_id, lane_id, into_lane, occupancy)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![run_id, event_id, ts, car_id, lane_id, into_lane, occupancy])?;
+        }
+        EventKind::PhaseChange { junction, phase, green_lanes, red_lanes } => {
+            tx.prepare_cached(
+                "INSERT INTO phase_reports (run_id, event_id, timestamp, junction, phase, green_lanes, red_lanes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![run_id, event_id, ts, junction, *phase as i64, join_ids(green_lanes), join_ids(red_lanes)])?;
+        }
+        EventKind::PhaseDecision { junction, phase, demand, overdue } => {
+            tx.prepare_cached(
+                "INSERT INTO phase_decisions (run_id, event_id, timestamp, junction, phase, demand, overdue)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(params![run_id, event_id, ts, junction, *phase as i64, join_ids(demand), overdue])?;
+        }
+        EventKind::PedestrianPhase { junction, crossings, walk_secs } => {
+            let crossings: Vec<String> = crossings.iter().map(|crossing| crossing.to_string()).collect();
+            tx.prepare_cached(
+                "INSERT INTO pedestrian_phases (run_id, event_id, timestamp, junction, crossings, walk_secs)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![run_id, event_id, ts, junction, crossings.join(";"), walk_secs])?;
+        }
+        EventKind::Recommendation { lane_id, new_green_time } => {
+            tx.prepare_cached(
+                "INSERT INTO recommendations (run_id, event_id, timestamp, lane_id, new_green_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![run_id, event_id, ts, lane_id, new_green_time])?;
+        }
+        EventKind::RerouteAdvisory { lanes, expires_at } => {
+            tx.prepare_cached(
+                "INSERT INTO reroute_advisories (run_id, event_id, timestamp, lanes, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![run_id, event_id, ts, join_ids(lanes), expires_at])?;
+        }
+        EventKind::ScenarioEvent { at, action } => {
+            let (lane_id, vehicle_count, entry_lanes, duration_secs, green_secs) = match action {
+                ScenarioAction::SpawnBurst { count, entry_lanes } => (None, Some(*count), Some(join_ids(entry_lanes)), None, None),
+                ScenarioAction::LaneClosure { lane_id, duration_secs } => (Some(*lane_id), None, None, *duration_secs, None),
+                ScenarioAction::LaneReopen { lane_id } => (Some(*lane_id), None, None, None, None),
+                ScenarioAction::SignalOverride { lane_id, green_secs } => (Some(*lane_id), None, None, None, Some(*green_secs)),
+            };
+            let name = match action {
+                ScenarioAction::SpawnBurst { .. } => "SpawnBurst",
+                ScenarioAction::LaneClosure { .. } => "LaneClosure",
+                ScenarioAction::LaneReopen { .. } => "LaneReopen",
+                ScenarioAction::SignalOverride { .. } => "SignalOverride",
+            };
+            tx.prepare_cached(
+                "INSERT INTO scenario_events (run_id, event_id, timestamp, at_secs, action, lane_id, vehicle_count,
+                                              entry_lanes, duration_secs, green_secs)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?
+            .execute(params![run_id, event_id, ts, at, name, lane_id, vehicle_count, entry_lanes, duration_secs, green_secs])?;
+        }
+        EventKind::MonitorOverloaded { behind_secs, max_lag_secs } => {
+            tx.prepare_cached(
+                "INSERT INTO monitor_overloads (run_id, event_id, timestamp, behind_secs, max_lag_secs)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![run_id, event_id, ts, behind_secs, max_lag_secs])?;
+        }
+        EventKind::Summary(summary) => {
+            let json = serde_json::to_string(summary).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            tx.prepare_cached(
+                "INSERT INTO summaries (run_id, event_id, timestamp, vehicles, duration_secs, mean_wait, median_wait,
+                                        p95_wait, mean_drive, busiest_junction, summary_json, replay_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            )?
+            .execute(params![
+                run_id,
+                event_id,
+                ts,
+                summary.vehicles as i64,
+                summary.duration_secs,
+                summary.mean_wait,
+                summary.median_wait,
+                summary.p95_wait,
+                summary.mean_drive,
+                summary.most_congested_junction.as_ref().map(|delay| delay.junction),
+                json,
+                summary.replay_hash
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+fn join_ids(ids: &[u32]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(";")
+}
+
+/// Resolves the database path from `--sqlite <path>` on the command line,
+/// falling back to the RTS_SQLITE_PATH environment variable.
+pub fn sqlite_path_from(args: &[String]) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == "--sqlite")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("RTS_SQLITE_PATH").ok().filter(|p| !p.is_empty()))
+}
+
+/// Runs `sql` against the database at `path` and prints the result as
+/// tab-separated rows with a header line. Stops quietly if stdout closes.
+pub fn run_query(path: &Path, sql: &str) -> rusqlite::Result<()> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut out = io::stdout().lock();
+    if writeln!(out, "{}", columns.join("\t")).is_err() {
+        return Ok(());
+    }
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let mut fields = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            fields.push(match row.get_ref(i)? {
+                ValueRef::Null => "NULL".to_string(),
+                ValueRef::Integer(v) => v.to_string(),
+                ValueRef::Real(v) => v.to_string(),
+                ValueRef::Text(v) => String::from_utf8_lossy(v).into_owned(),
+                ValueRef::Blob(v) => format!("<{} bytes>", v.len()),
+            });
+        }
+        if writeln!(out, "{}", fields.join("\t")).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Entry point for `RTS query <db> (--sql <statement> | <canned query>)`.
+pub fn query_command(args: &[String]) -> Result<(), String> {
+    let usage = || {
+        let names: Vec<&str> = CANNED_QUERIES.iter().map(|(name, _)| *name).collect();
+        format!("usage: RTS query <db> (--sql <statement> | {})", names.join(" | "))
+    };
+    let db = args.first().ok_or_else(usage)?;
+    let sql = match args.get(1).map(String::as_str) {
+        Some("--sql") => args.get(2).ok_or_else(usage)?.as_str(),
+        Some(name) => CANNED_QUERIES
+            .iter()
+            .find(|(canned, _)| *canned == name)
+            .map(|(_, sql)| *sql)
+            .ok_or_else(usage)?,
+        None => return Err(usage()),
+    };
+    let path = Path::new(db);
+    if !path.exists() {
+        return Err(format!("{}: no such database", db));
+    }
+    run_query(path, sql).map_err(|e| format!("query failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::vehicle::VehicleKind;
+    use rts_core::lanes::{load_lanes, Lane, LaneCategory};
+    use rts_core::network::load_network;
+    use rts_core::phase_plan::build_phase_plan;
+    use rts_core::routing::find_lane_path;
+    use rts_core::trips::{choose_trip, BoundaryLanes};
+
+    /// Events of a small run on the built-in network: each car's generation,
+    /// lane waits and completion, a phase change at every junction a car
+    /// waited at, and a recommendation for every third lane waited on.
+    fn seeded_run(seed: u64, cars: u32) -> Vec<LogEvent> {
+        let (lanes, network) = (load_lanes(), load_network());
+        let internal: Vec<Lane> = lanes.iter().filter(|l| l.category == LaneCategory::Internal).cloned().collect();
+        let boundary = BoundaryLanes::from_lanes(&lanes);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut events = vec![LogEvent::new("Simulation", 0, EventKind::RunStarted { seed: seed as u32, car_count: cars, time_scale: 1.0 })];
+        let mut waited_at: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+        for car_id in 1..=cars {
+            let trip = choose_trip(&boundary, &internal, &network, None, &mut rng, |_, _| None).unwrap();
+            let route = find_lane_path(trip.entry.end_intersection, trip.exit.start_intersection, &internal, &network, None).unwrap();
+            let source = format!("Car-{}", car_id);
+            let ts = u64::from(car_id);
+            events.push(LogEvent::new(&source, ts, EventKind::VehicleGenerated {
+                car_id,
+                vehicle_kind: VehicleKind::Car,
+                speed: 10.0,
+                entry_lane: trip.entry.id,
+                exit_lane: trip.exit.id,
+                route: route.iter().map(|lane| lane.id).collect(),
+            }));
+            let mut wait_time = 0.0;
+            for lane in &route {
+                let wait = rng.random_range(0.0..20.0);
+                wait_time += wait;
+                waited_at.entry(lane.end_intersection).or_default().insert(lane.id);
+                events.push(LogEvent::new(&source, ts, EventKind::LaneWait {
+                    car_id,
+                    lane_id: lane.id,
+                    junction: lane.end_intersection,
+                    wait_time: wait,
+                }));
+            }
+            events.push(LogEvent::new(&source, ts + 60, EventKind::CarCompleted {
+                car_id,
+                entry_lane: trip.entry.id,
+                exit_lane: trip.exit.id,
+                route_length: route.iter().map(|lane| lane.length).sum(),
+                wait_time,
+                drive_time: 60.0,
+                total_time: 60.0 + wait_time,
+            }));
+        }
+        for (&junction, waited) in &waited_at {
+            let phases = build_phase_plan(junction, &lanes, &network);
+            let (phase, green) = phases.iter().enumerate().find(|(_, p)| p.lanes.iter().any(|id| waited.contains(id))).unwrap();
+            events.push(LogEvent::new(format!("Junction-{}", junction), 100, EventKind::PhaseChange {
+                junction,
+                phase,
+                green_lanes: green.lanes.clone(),
+                red_lanes: phases.iter().filter(|p| p.lanes != green.lanes).flat_map(|p| p.lanes.clone()).collect(),
+            }));
+        }
+        let lane_ids: BTreeSet<u32> = waited_at.values().flatten().copied().collect();
+        for &lane_id in lane_ids.iter().step_by(3) {
+            events.push(LogEvent::new("FlowAnalyzer", 120, EventKind::Recommendation { lane_id, new_green_time: 40 }));
+        }
+        events
+    }
+
+    fn ids(sink: &SqliteSink, sql: &str) -> BTreeSet<(i64, i64)> {
+        let mut statement = sink.conn.prepare(sql).unwrap();
+        let rows = statement.query_map(params![sink.run_id], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn seeded_run_rows_agree_across_tables() {
+        let events = seeded_run(7, 12);
+        let mut sink = SqliteSink::create(Path::new(":memory:")).unwrap();
+        for event in &events {
+            sink.write_event(event).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let counts: HashMap<&str, i64> = sink.row_counts().unwrap().into_iter().collect();
+        let of_kind = |name: &str| events.iter().filter(|e| e.kind.name() == name).count() as i64;
+        assert_eq!(counts["events"], events.len() as i64);
+        assert_eq!(counts["vehicles"], 12);
+        assert_eq!(counts["car_metrics"], 12);
+        assert_eq!(counts["lane_waits"], of_kind("lane_wait"));
+        assert_eq!(counts["phase_reports"], of_kind("phase_change"));
+        assert_eq!(counts["recommendations"], of_kind("recommendation"));
+        assert!(counts["recommendations"] > 0);
+
+        // Every typed row points at an event of its kind and vice versa.
+        let check = CANNED_QUERIES.iter().find(|(name, _)| *name == "check").unwrap().1;
+        let mut statement = sink.conn.prepare(check).unwrap();
+        let violations: Vec<(String, i64)> =
+            statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(Result::unwrap).collect();
+        assert!(violations.iter().all(|(_, count)| *count == 0), "{:?}", violations);
+
+        // Cars: one generation and one completion each, with the same lanes.
+        let generated = ids(&sink, "SELECT car_id, entry_lane * 100000 + exit_lane FROM vehicles WHERE run_id = ?1");
+        let completed = ids(&sink, "SELECT car_id, entry_lane * 100000 + exit_lane FROM car_metrics WHERE run_id = ?1");
+        assert_eq!(generated, completed);
+        let waited_cars: BTreeSet<i64> =
+            ids(&sink, "SELECT car_id, lane_id FROM lane_waits WHERE run_id = ?1").iter().map(|&(car, _)| car).collect();
+        assert!(waited_cars.iter().all(|car| completed.iter().any(|&(c, _)| c == *car)));
+
+        // Lanes and junctions: the junctions reported are those waited at,
+        // each green lane belongs to its junction, and recommendations name
+        // lanes the cars waited on.
+        let lane_junctions = ids(&sink, "SELECT lane_id, junction FROM lane_waits WHERE run_id = ?1");
+        let waited_junctions: BTreeSet<i64> = lane_junctions.iter().map(|&(_, junction)| junction).collect();
+        let lanes = load_lanes();
+        let mut statement = sink.conn.prepare("SELECT junction, green_lanes FROM phase_reports WHERE run_id = ?1").unwrap();
+        let reports: Vec<(i64, String)> =
+            statement.query_map(params![sink.run_id], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(Result::unwrap).collect();
+        assert_eq!(reports.iter().map(|(junction, _)| *junction).collect::<BTreeSet<_>>(), waited_junctions);
+        for (junction, green) in &reports {
+            for lane_id in green.split(';').map(|id| id.parse::<u32>().unwrap()) {
+                let lane = lanes.iter().find(|lane| lane.id == lane_id).unwrap();
+                assert_eq!(i64::from(lane.end_intersection), *junction, "green lane {}", lane_id);
+            }
+        }
+        let waited_lanes: BTreeSet<i64> = lane_junctions.iter().map(|&(lane, _)| lane).collect();
+        let recommended = ids(&sink, "SELECT lane_id, new_green_time FROM recommendations WHERE run_id = ?1");
+        assert!(recommended.iter().all(|(lane, _)| waited_lanes.contains(lane)), "{:?}", recommended);
+    }
+}
@@ -0,0 +1,393 @@
+// summary.rs
+//
+// End-of-run summary built from the metrics every vehicle reports when it
+// finishes, with the wait, drive and total time statistics of the trips
+// overall, per entry lane (see `rts_core::stats`) and per vehicle kind, and
+// how busy each junction box was (see `junction_box`). It is printed, logged as a structured event for the monitoring
+// sinks, and serializable for any other tooling that wants the numbers.
+// Networks with tagged lanes and junctions get the lane figures once more
+// per tag (see `rts_core::network::Network::tagged_lanes`).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use rts_core::network::Network;
+use rts_core::stats::{mean, percentile, TripReport, TripStats, TripTimes};
+
+use crate::budget::{self, ComponentBudget};
+use crate::simulation::{CarMetrics, GenerationFailed, LaneVisit};
+use crate::vehicle::VehicleKind;
+
+/// Highest occupancy a lane reached during the run, in footprint units.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LaneOccupancy {
+    pub lane_id: u32,
+    pub max_occupancy: u32,
+}
+
+/// Vehicles that entered the grid on one input lane.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryThroughput {
+    pub lane_id: u32,
+    /// Vehicles generated for the lane, when a generator supplied them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated: Option<u32>,
+    /// Vehicles that entered on the lane and completed their journey.
+    pub completed: u32,
+    /// Completed vehicles per minute of simulated run time.
+    pub per_min: f64,
+}
+
+/// Trip statistics of the vehicles of one kind.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KindStats {
+    pub kind: VehicleKind,
+    pub stats: TripStats,
+}
+
+/// The kind's trips with their wait, drive and total times as
+/// mean/median/p95, on one line.
+impl fmt::Display for KindStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} trips): {}", self.kind, self.stats.trips, self.stats.compact())
+    }
+}
+
+/// What happened on the lanes with one tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagStats {
+    pub tag: String,
+    /// Lanes with the tag, their own or a junction's.
+    pub lanes: usize,
+    /// Times a vehicle drove one of the lanes.
+    pub visits: u32,
+    /// Mean time a vehicle waited on one of the lanes.
+    pub mean_wait: f64,
+    /// Highest occupancy any of the lanes reached, in footprint units.
+    pub max_occupancy: u32,
+    pub right_turns_on_red: usize,
+}
+
+/// The tag's lanes, visits, mean wait, peak and red turns, on one line.
+impl fmt::Display for TagStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} lanes): {} visits, {:.1}s mean wait, peak {}, {} right turns on red",
+            self.tag, self.lanes, self.visits, self.mean_wait, self.max_occupancy, self.right_turns_on_red
+        )
+    }
+}
+
+/// Waiting accumulated by vehicles approaching a junction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunctionDelay {
+    pub junction: u32,
+    pub total_wait: f64,
+    pub mean_wait: f64,
+}
+
+/// How much of the run a junction had vehicles crossing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunctionUtilization {
+    pub junction: u32,
+    /// Vehicles that crossed the junction.
+    pub crossings: u32,
+    /// Simulated seconds with at least one vehicle inside.
+    pub busy_secs: f64,
+    /// `busy_secs` as a share of the run, from 0 to 1.
+    pub utilization: f64,
+    /// Vehicles that had to wait for a conflicting movement to clear.
+    pub waits: u32,
+    /// Seconds they waited in total.
+    pub wait_secs: f64,
+}
+
+/// A coordinated corridor: its offsets, and the stops at its coordinated
+/// lights after the first junction (see `coordination`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorridorStats {
+    pub junctions: Vec<u32>,
+    pub cycle_secs: f64,
+    /// When each junction starts its coordinated phase within the cycle.
+    pub offsets_secs: Vec<f64>,
+    pub arrivals: u32,
+    pub stops: u32,
+    /// Stops to expect if the same vehicles had arrived at random.
+    pub expected_stops: f64,
+}
+
+/// The corridor's offsets and the stops it saved, on one line.
+impl fmt::Display for CorridorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let junctions: Vec<String> = self.junctions.iter().map(u32::to_string).collect();
+        let offsets: Vec<String> = self.offsets_secs.iter().map(|offset| format!("{:.0}s", offset)).collect();
+        write!(
+            f,
+            "{} ({:.0}s cycle, offsets {}): {} of {} arrivals stopped, {:.1} saved",
+            junctions.join("-"),
+            self.cycle_secs,
+            offsets.join("/"),
+            self.stops,
+            self.arrivals,
+            self.expected_stops - self.stops as f64
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulationSummary {
+    pub vehicles: usize,
+    pub duration_secs: f64,
+    /// Vehicles completed per minute of simulated run time.
+    pub throughput_per_min: f64,
+    pub mean_wait: f64,
+    pub median_wait: f64,
+    pub p95_wait: f64,
+    pub mean_drive: f64,
+    /// Wait, drive and total times, overall and per entry lane.
+    #[serde(default)]
+    pub trips: TripReport,
+    /// In `VehicleKind::ALL` order; only kinds some completed trip was made by.
+    #[serde(default)]
+    pub by_kind: Vec<KindStats>,
+    /// Sorted by lane id; only lanes that were entered at least once.
+    pub lane_max_occupancy: Vec<LaneOccupancy>,
+    /// Sorted by lane id; only input lanes some vehicle entered on or was
+    /// generated for.
+    #[serde(default)]
+    pub entry_throughput: Vec<EntryThroughput>,
+    /// Junction with the largest total wait, if any vehicle waited at one.
+    pub most_congested_junction: Option<JunctionDelay>,
+    /// Sorted by junction; only junctions some vehicle crossed.
+    #[serde(default)]
+    pub junction_utilization: Vec<JunctionUtilization>,
+    /// Coordinated corridors, in RTS_CORRIDORS order.
+    #[serde(default)]
+    pub corridors: Vec<CorridorStats>,
+    /// Entry/exit pairs rejected because they shared a junction or had no
+    /// route, over all vehicles; a high count points at a topology problem.
+    pub trip_redraws: u32,
+    /// Vehicles that ran out of draws and never drove.
+    pub generation_failures: usize,
+    /// Lanes left by turning right on a red light (see `rts_core::right_on_red`).
+    #[serde(default)]
+    pub right_turns_on_red: usize,
+    /// Per tag of the network, in alphabetical order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by_tag: Vec<TagStats>,
+    /// Hash of the replay file the vehicles came from, if the run was a replay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_hash: Option<String>,
+    /// Where each component's time went so far, with RTS_TIME_BUDGET (see `budget`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_budget: Vec<ComponentBudget>,
+}
+
+impl SimulationSummary {
+    pub fn from_metrics(metrics: &[CarMetrics], failures: &[GenerationFailed], duration: Duration) -> Self {
+        let mut waits: Vec<f64> = metrics.iter().map(|m| m.wait_time).collect();
+        waits.sort_by(f64::total_cmp);
+        let trip_times: Vec<TripTimes> = metrics
+            .iter()
+            .map(|m| TripTimes { entry_lane: m.entry_lane, wait: m.wait_time, drive: m.drive_time, total: m.total_time })
+            .collect();
+        let by_kind = VehicleKind::ALL
+            .into_iter()
+            .filter_map(|kind| {
+                let trips: Vec<TripTimes> =
+                    metrics.iter().zip(&trip_times).filter(|(m, _)| m.kind == kind).map(|(_, trip)| *trip).collect();
+                (!trips.is_empty()).then(|| KindStats { kind, stats: TripStats::of(&trips) })
+            })
+            .collect();
+
+        let mut peaks: HashMap<u32, u32> = HashMap::new();
+        let mut junction_waits: HashMap<u32, (f64, u32)> = HashMap::new();
+        for visit in metrics.iter().flat_map(|m| &m.lanes) {
+            let peak = peaks.entry(visit.lane_id).or_insert(0);
+            *peak = (*peak).max(visit.occupancy);
+            if visit.junction != 0 {
+                let entry = junction_waits.entry(visit.junction).or_insert((0.0, 0));
+                entry.0 += visit.wait_time;
+                entry.1 += 1;
+            }
+        }
+        let mut lane_max_occupancy: Vec<LaneOccupancy> = peaks
+            .into_iter()
+            .map(|(lane_id, max_occupancy)| LaneOccupancy { lane_id, max_occupancy })
+            .collect();
+        lane_max_occupancy.sort_by_key(|lane| lane.lane_id);
+
+        let most_congested_junction = junction_waits
+            .into_iter()
+            .map(|(junction, (total_wait, visits))| JunctionDelay {
+                junction,
+                total_wait,
+                mean_wait: total_wait / visits as f64,
+            })
+            .filter(|delay| delay.total_wait > 0.0)
+            .max_by(|a, b| a.total_wait.total_cmp(&b.total_wait).then(b.junction.cmp(&a.junction)));
+
+        let duration_secs = duration.as_secs_f64();
+        let per_min = |count: usize| if duration_secs > 0.0 { count as f64 * 60.0 / duration_secs } else { 0.0 };
+        let mut entries: BTreeMap<u32, u32> = BTreeMap::new();
+        for m in metrics {
+            *entries.entry(m.entry_lane).or_insert(0) += 1;
+        }
+        let entry_throughput = entries
+            .into_iter()
+            .map(|(lane_id, completed)| EntryThroughput { lane_id, generated: None, completed, per_min: per_min(completed as usize) })
+            .collect();
+
+        SimulationSummary {
+            vehicles: metrics.len(),
+            duration_secs,
+            throughput_per_min: per_min(metrics.len()),
+            mean_wait: mean(&waits),
+            median_wait: percentile(&waits, 50.0),
+            p95_wait: percentile(&waits, 95.0),
+            mean_drive: mean(&metrics.iter().map(|m| m.drive_time).collect::<Vec<_>>()),
+            trips: TripReport::from_trips(&trip_times),
+            by_kind,
+            lane_max_occupancy,
+            entry_throughput,
+            most_congested_junction,
+            junction_utilization: Vec::new(),
+            corridors: Vec::new(),
+            // A failed vehicle's first draw counts as a draw, the rest as re-draws.
+            trip_redraws: metrics.iter().map(|m| m.redraws).sum::<u32>()
+                + failures.iter().map(|f| f.attempts.saturating_sub(1)).sum::<u32>(),
+            generation_failures: failures.len(),
+            right_turns_on_red: metrics.iter().flat_map(|m| &m.lanes).filter(|visit| visit.right_on_red).count(),
+            by_tag: Vec::new(),
+            replay_hash: None,
+            time_budget: Vec::new(),
+        }
+    }
+
+    /// Sums up the lane visits of `metrics` for every tag of `network`.
+    pub fn count_tags(&mut self, metrics: &[CarMetrics], network: &Network) {
+        self.by_tag = network
+            .tags()
+            .map(|tag| {
+                let lanes: HashSet<u32> = network.tagged_lanes(tag).collect();
+                let visits: Vec<&LaneVisit> = metrics.iter().flat_map(|m| &m.lanes).filter(|visit| lanes.contains(&visit.lane_id)).collect();
+                let waits: Vec<f64> = visits.iter().map(|visit| visit.wait_time).collect();
+                TagStats {
+                    tag: tag.to_string(),
+                    lanes: lanes.len(),
+                    visits: visits.len() as u32,
+                    mean_wait: mean(&waits),
+                    max_occupancy: visits.iter().map(|visit| visit.occupancy).max().unwrap_or(0),
+                    right_turns_on_red: visits.iter().filter(|visit| visit.right_on_red).count(),
+                }
+            })
+            .collect();
+    }
+
+    /// Counts the vehicles a generator produced for each input lane, one
+    /// entry lane per vehicle, against those that completed.
+    pub fn count_generated(&mut self, entry_lanes: impl Iterator<Item = u32>) {
+        let mut generated: BTreeMap<u32, u32> = BTreeMap::new();
+        for lane_id in entry_lanes {
+            *generated.entry(lane_id).or_insert(0) += 1;
+        }
+        for entry in &mut self.entry_throughput {
+            entry.generated = Some(generated.remove(&entry.lane_id).unwrap_or(0));
+        }
+        for (lane_id, count) in generated {
+            self.entry_throughput.push(EntryThroughput { lane_id, generated: Some(count), completed: 0, per_min: 0.0 });
+        }
+        self.entry_throughput.sort_by_key(|entry| entry.lane_id);
+    }
+}
+
+impl fmt::Display for SimulationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Simulation summary")?;
+        if let Some(hash) = &self.replay_hash {
+            writeln!(f, "  Replay:      {}", hash)?;
+        }
+        writeln!(f, "  Vehicles:    {} in {:.1}s ({:.1}/min)", self.vehicles, self.duration_secs, self.throughput_per_min)?;
+        writeln!(f, "{}", self.trips.overall)?;
+        writeln!(f, "  Trips:       {} re-draws, {} vehicles without a valid trip", self.trip_redraws, self.generation_failures)?;
+        if self.right_turns_on_red > 0 {
+            writeln!(f, "  Red turns:   {} right turns on red", self.right_turns_on_red)?;
+        }
+        match &self.most_congested_junction {
+            Some(delay) => writeln!(
+                f,
+                "  Congestion:  junction {} ({:.1}s total wait, {:.2}s per vehicle)",
+                delay.junction, delay.total_wait, delay.mean_wait
+            )?,
+            None => writeln!(f, "  Congestion:  no waiting at any junction")?,
+        }
+        let busiest_junctions: Vec<String> = {
+            let mut junctions: Vec<&JunctionUtilization> = self.junction_utilization.iter().collect();
+            junctions.sort_by(|a, b| b.utilization.total_cmp(&a.utilization).then(a.junction.cmp(&b.junction)));
+            junctions
+                .iter()
+                .take(5)
+                .map(|j| format!("{}: {:.0}% ({} crossings, {} waited {:.1}s)", j.junction, j.utilization * 100.0, j.crossings, j.waits, j.wait_secs))
+                .collect()
+        };
+        if !busiest_junctions.is_empty() {
+            writeln!(f, "  Junctions:   {}", busiest_junctions.join(", "))?;
+        }
+        let entries: Vec<String> = self
+            .entry_throughput
+            .iter()
+            .map(|entry| match entry.generated {
+                Some(generated) => format!("{}: {}/{} ({:.1}/min)", entry.lane_id, entry.completed, generated, entry.per_min),
+                None => format!("{}: {} ({:.1}/min)", entry.lane_id, entry.completed, entry.per_min),
+            })
+            .collect();
+        if !entries.is_empty() {
+            writeln!(f, "  Entries:     {}", entries.join(", "))?;
+        }
+        let busiest: Vec<String> = {
+            let mut lanes: Vec<&LaneOccupancy> = self.lane_max_occupancy.iter().collect();
+            lanes.sort_by(|a, b| b.max_occupancy.cmp(&a.max_occupancy).then(a.lane_id.cmp(&b.lane_id)));
+            lanes.iter().take(5).map(|lane| format!("{}: {}", lane.lane_id, lane.max_occupancy)).collect()
+        };
+        if busiest.is_empty() {
+            write!(f, "  Peak lanes:  none")?;
+        } else {
+            write!(f, "  Peak lanes:  {}", busiest.join(", "))?;
+        }
+        if !self.trips.by_entry_lane.is_empty() {
+            write!(f, "\n  By entry lane (mean/median/p95):")?;
+            for entry in &self.trips.by_entry_lane {
+                write!(f, "\n    {}", entry)?;
+            }
+        }
+        if !self.by_kind.is_empty() {
+            write!(f, "\n  By vehicle kind (mean/median/p95):")?;
+            for kind in &self.by_kind {
+                write!(f, "\n    {}", kind)?;
+            }
+        }
+        if !self.by_tag.is_empty() {
+            write!(f, "\n  By tag:")?;
+            for tag in &self.by_tag {
+                write!(f, "\n    {}", tag)?;
+            }
+        }
+        if !self.corridors.is_empty() {
+            write!(f, "\n  Corridors:")?;
+            for corridor in &self.corridors {
+                write!(f, "\n    {}", corridor)?;
+            }
+        }
+        if !self.time_budget.is_empty() {
+            write!(f, "\n  Time budget:")?;
+            for component in &self.time_budget {
+                write!(f, "\n    {}", budget::format_budget(component))?;
+            }
+        }
+        Ok(())
+    }
+}
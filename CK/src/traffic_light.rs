@@ -1,209 +1,721 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex, mpsc::Sender,mpsc::Receiver};
+use std::sync::{Arc, Condvar, Mutex, mpsc::Sender, mpsc::Receiver, mpsc::RecvTimeoutError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::system_monitoring::LogEvent;
-use crate::lanes::{Lane, load_lanes};
+use crate::system_monitoring::{EventKind, LogEvent};
+use rts_core::lane_queue::LaneQueues;
+use rts_core::lanes::{Lane, Movement, load_lanes};
+use rts_core::right_on_red;
 use crate::flow_analyzer::Recommendation;
+use rts_core::messages::RecommendationApplied;
+use crate::budget::{self, Category};
+use crate::shutdown::{self, ShutdownFlag, SleepOrShutdown};
+use rts_core::phase_plan::build_phase_plan;
+use rts_core::phase_order::{phase_demand, PhaseOrder, PhaseSelector};
+use rts_core::network::load_network;
+use crate::signal_timing::JunctionTimings;
+use rts_core::clock::SimClock;
+use crate::crossings::CrossingConfig;
+use crate::coordination::Coordination;
+use crate::simulation::LatestCounts;
 
-/// New traffic light color for individual lane control.
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum LightColor {
-    Red,
-    Green,
-}
+pub use rts_core::messages::LightColor;
+
+/// How often a right-turner at the front of a red light looks at the lane it
+/// turns into again; that lane emptying wakes nobody.
+const RIGHT_ON_RED_RECHECK: Duration = Duration::from_millis(500);
 
-/// Checks whether a given lane’s light (keyed by lane id) is green.
-pub fn can_proceed_lane(lane_id: u32, lights: &HashMap<u32, LightColor>) -> bool {
+/// Checks whether a car may pass a lane's light (keyed by lane id): the light
+/// is green and the car is first in the lane's queue, or the car was already
+/// in the junction when the light turned amber (see `LaneQueues::may_pass`).
+/// A car first in the queue making `movement` into `into`, which holds
+/// `occupancy` vehicles, may also turn right on red (see `rts_core::right_on_red`).
+pub fn can_proceed_lane(
+    lane_id: u32,
+    car_id: u32,
+    movement: Movement,
+    into: &Lane,
+    occupancy: u32,
+    lights: &HashMap<u32, LightColor>,
+    queues: &LaneQueues,
+) -> bool {
     if let Some(&color) = lights.get(&lane_id) {
-        color == LightColor::Green
+        queues.may_pass(lane_id, car_id, color)
+            || (queues.is_first(lane_id, car_id) && right_on_red::may_proceed(color, movement, occupancy, into))
     } else {
         false
     }
 }
 
-/// Helper: Converts an intersection ID (1..16) to (row, col) coordinates in a 4×4 grid.
-fn intersection_to_coords(inter: u32) -> (f64, f64) {
-    let row = ((inter - 1) / 4) as f64;
-    let col = ((inter - 1) % 4) as f64;
-    (row, col)
+/// Light colors and the cars queued at each light, guarded together.
+struct LightState {
+    colors: HashMap<u32, LightColor>,
+    queues: LaneQueues,
 }
 
-/// Computes the approach angle (in degrees) for a lane approaching its junction.
-/// For lanes with a valid start intersection (non-boundary internal lanes),
-/// we compute the vector from the start to the end intersection.
-/// For input lanes (with start_intersection == 0), we assign a default angle
-/// based on the junction’s location.
-fn compute_lane_angle(lane: &Lane) -> f64 {
-    if lane.start_intersection != 0 {
-        let (sx, sy) = intersection_to_coords(lane.start_intersection);
-        let (ex, ey) = intersection_to_coords(lane.end_intersection);
-        let dx = ex - sx;
-        let dy = ey - sy;
-        let angle_rad = dy.atan2(dx);
-        let mut angle_deg = angle_rad.to_degrees();
-        if angle_deg < 0.0 {
-            angle_deg += 360.0;
-        }
-        angle_deg
-    } else {
-        // For input lanes, assign a default based on junction location.
-        let (ex, ey) = intersection_to_coords(lane.end_intersection);
-        if ex == 0.0 {
-            90.0  // Top row: coming from north
-        } else if ex == 3.0 {
-            270.0 // Bottom row: coming from south
-        } else if ey == 0.0 {
-            0.0   // Left column: coming from west
-        } else if ey == 3.0 {
-            180.0 // Right column: coming from east
-        } else {
-            90.0  // Default
-        }
-    }
+/// Light state for every controlled lane, plus one notifier per lane so that a
+/// phase change only wakes the cars waiting on lanes that actually turned green.
+/// Cars pass a light in the order they reached it (see `rts_core::lane_queue`).
+///
+/// The notifier set is fixed at initialization, so memory is bounded by the
+/// number of controlled lanes. Writers update the colors or queues and notify
+/// while holding the lock, and waiters re-check their lane under the same lock,
+/// so a transition can never slip in between a waiter's check and its wait.
+pub struct TrafficLights {
+    state: Mutex<LightState>,
+    notifiers: HashMap<u32, Condvar>,
 }
 
-/// Groups lanes (entering the same junction) by similar approach angles.
-/// Lanes whose computed angles differ by less than a threshold (e.g. 20°)
-/// are grouped together so that they can safely have green simultaneously.
-fn group_lanes_by_direction(lanes: &[Lane]) -> Vec<Vec<u32>> {
-    let threshold = 20.0; // degrees tolerance
-    let mut groups: Vec<(f64, Vec<u32>)> = Vec::new(); // (average angle, list of lane ids)
-    
-    for lane in lanes {
-        let angle = compute_lane_angle(lane);
-        let mut added = false;
-        for group in groups.iter_mut() {
-            if (angle - group.0).abs() <= threshold {
-                group.1.push(lane.id);
-                // Update the group’s average angle (simple average).
-                group.0 = (group.0 * (group.1.len() as f64 - 1.0) + angle) / group.1.len() as f64;
-                added = true;
-                break;
+impl TrafficLights {
+    fn new(colors: HashMap<u32, LightColor>) -> Self {
+        let notifiers = colors.keys().map(|&lane_id| (lane_id, Condvar::new())).collect();
+        TrafficLights { state: Mutex::new(LightState { colors, queues: LaneQueues::default() }), notifiers }
+    }
+
+    /// Applies a batch of color changes and wakes the waiters of every lane
+    /// that turned green, or amber with a car already in the junction. Lanes
+    /// that stay red are left untouched.
+    pub fn set_colors(&self, updates: &[(u32, LightColor)]) {
+        let mut state = {
+            let _t = budget::time_lock();
+            self.state.lock().unwrap()
+        };
+        for &(lane_id, color) in updates {
+            let previous = state.colors.insert(lane_id, color);
+            if color == LightColor::Amber && previous != Some(LightColor::Amber) {
+                state.queues.light_turned_amber(lane_id);
+            }
+            if color != LightColor::Red && previous != Some(color) {
+                if let Some(notifier) = self.notifiers.get(&lane_id) {
+                    notifier.notify_all();
+                }
             }
         }
-        if !added {
-            groups.push((angle, vec![lane.id]));
+    }
+
+    /// True if the lane has a traffic light.
+    pub fn controls(&self, lane_id: u32) -> bool {
+        self.notifiers.contains_key(&lane_id)
+    }
+
+    /// Queues `car_id` at the lane's light and blocks the calling thread until
+    /// the light is green and every car that reached it earlier has passed,
+    /// or, turning right, until `into` has room to go on red (see
+    /// `can_proceed_lane`). `occupancy` reads the vehicles on `into`. Returns
+    /// that occupancy if the car went on red.
+    pub fn wait_for_turn(
+        &self,
+        lane_id: u32,
+        car_id: u32,
+        movement: Movement,
+        into: &Lane,
+        occupancy: impl Fn() -> u32,
+    ) -> Option<u32> {
+        let mut state = {
+            let _t = budget::time_lock();
+            self.state.lock().unwrap()
+        };
+        state.queues.join(lane_id, car_id);
+        let turned_on_red = |state: &LightState, occupancy: u32| {
+            let color = state.colors.get(&lane_id).copied().unwrap_or(LightColor::Red);
+            (!state.queues.may_pass(lane_id, car_id, color)).then_some(occupancy)
+        };
+        match self.notifiers.get(&lane_id) {
+            Some(notifier) => loop {
+                let load = occupancy();
+                if can_proceed_lane(lane_id, car_id, movement, into, load, &state.colors, &state.queues) {
+                    let on_red = turned_on_red(&state, load);
+                    // The next car in line may be able to go at once.
+                    state.queues.leave(lane_id, car_id);
+                    notifier.notify_all();
+                    return on_red;
+                }
+                let red_turner = movement == Movement::RightTurn
+                    && state.colors.get(&lane_id) == Some(&LightColor::Red)
+                    && state.queues.is_first(lane_id, car_id);
+                state = if red_turner {
+                    notifier.wait_timeout(state, RIGHT_ON_RED_RECHECK).unwrap().0
+                } else {
+                    notifier.wait(state).unwrap()
+                };
+                #[cfg(test)]
+                tests::woke(lane_id);
+            },
+            // Lanes without a controlled light never turn green; keep the old
+            // polling behavior rather than parking on a notifier nobody signals.
+            None => loop {
+                let load = occupancy();
+                if can_proceed_lane(lane_id, car_id, movement, into, load, &state.colors, &state.queues) {
+                    let on_red = turned_on_red(&state, load);
+                    state.queues.leave(lane_id, car_id);
+                    return on_red;
+                }
+                drop(state);
+                thread::sleep(Duration::from_millis(100));
+                state = self.state.lock().unwrap();
+            },
         }
     }
-    
-    // Sort groups by average angle and return only the lane id lists.
-    groups.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    groups.into_iter().map(|(_avg, lanes)| lanes).collect()
 }
 
-/// Shared traffic lights mapping: key is lane id, value is LightColor.
-pub type TrafficLightMap = Arc<Mutex<HashMap<u32, LightColor>>>;
+/// A junction-level recommendation waiting to be picked up by its junction:
+/// the phase to serve next and how long to hold it green. `lane_id` is the
+/// lane the recommendation was made for.
+#[derive(Debug, Clone, Copy)]
+struct JunctionHint {
+    lane_id: u32,
+    phase: usize,
+    green_secs: u64,
+}
+
+/// Pending lane-level green-time overrides (lane id -> seconds) from the flow
+/// analyzer. A junction thread consumes the overrides for a phase's lanes when
+/// it turns that phase green, so each override applies once.
+pub type GreenOverrides = Arc<Mutex<HashMap<u32, u32>>>;
+
+/// Walk time of each junction's pedestrian phases as last recommended by the
+/// flow analyzer (junction id -> duration; zero skips them). Junctions not
+/// listed use the configured walk time.
+type WalkOverrides = Arc<Mutex<HashMap<u32, Duration>>>;
+
+/// Green seconds of each junction's phases from the flow analyzer's latest
+/// timing plan (junction id -> greens indexed like its phase plan).
+type TimingPlans = Arc<Mutex<HashMap<u32, Vec<u32>>>>;
+
+/// Pending all-red holds from the flow analyzer (junction id -> duration),
+/// each taken by its junction before it serves its next phase.
+type AllRedHolds = Arc<Mutex<HashMap<u32, Duration>>>;
+
+/// Shared traffic lights, keyed by lane id.
+pub type TrafficLightMap = Arc<TrafficLights>;
 
 /// Initializes the traffic lights for all lanes that end at a junction (i.e. require control).
-/// All lights are initialized to Red so that not all are green at the start.
+/// All lights are initialized to Red so that not all are green at the start, except at
+/// junctions without traffic lights, whose lanes stay Green for good.
 pub fn initialize_traffic_lights() -> TrafficLightMap {
     let mut map = HashMap::new();
     let lanes = load_lanes();
+    let network = load_network();
     for lane in lanes {
         if lane.end_intersection != 0 {
-            map.insert(lane.id, LightColor::Red);
+            let color = if network.is_signalized(lane.end_intersection) { LightColor::Red } else { LightColor::Green };
+            map.insert(lane.id, color);
         }
     }
-    Arc::new(Mutex::new(map))
+    Arc::new(TrafficLights::new(map))
 }
 
-/// Runs the traffic light controller. For each junction, the controller:
+/// Runs the traffic light controller. For each junction with traffic lights, the controller:
 ///   - Identifies all lanes that enter that junction.
-///   - Groups lanes by their approach direction (using compute_lane_angle).
-///   - Cycles through each group in a round-robin fashion, setting the group’s lanes to green
-///     (and all others at that junction to red) for a fixed time slot,
-///     with a brief all-red clearance interval.
-/// This design avoids conflicts and (if lanes are parallel) allows nonconflicting movements simultaneously.
+///   - Builds a phase plan from the geometry of the movements through the junction.
+///   - Cycles through each phase in a round-robin fashion, setting the phase’s lanes to green
+///     (and all others at that junction to red) for the junction's green time, then amber
+///     for its amber time, followed by its all-red clearance interval (see `signal_timing`).
+///   - Holds a phase green for the longest pending lane recommendation among its lanes,
+///     once, instead of the baseline green time.
+///   - When the analyzer recommends a phase for the junction, serves that phase next for the
+///     recommended green time, then resumes the cycle after it.
+///   - Reports every recommendation it serves on `applied_tx`, so the analyzer can check whether it helped.
+///   - With adaptive phase ordering (see `rts_core::phase_order`), serves the phase with the most
+///     vehicles in `latest_counts` instead of the next one in the cycle, logging each choice as a
+///     PhaseDecision event; a recommended phase still goes first.
+///   - When the analyzer sends a timing plan for the junction, uses its green times in place
+///     of the baseline green from then on.
+///   - On a corridor in RTS_CORRIDORS (see `coordination`), serves the corridor's phase at its
+///     slot in the green wave, holding the junction red until then, and for the slack between
+///     its own cycle and the corridor's.
+///   - When the analyzer asks for an all-red at the junction, holds every lane red for that
+///     long before serving the next phase.
+///   - At junctions with pedestrian crossings (see `crossings`), holds every lane red for a walk
+///     phase after every `every`-th cycle, for the walk time the analyzer last recommended.
+///
+/// Lanes in the same phase never have crossing or merging movements; intervals are simulated time on `clock`.
+/// Returns once `shutdown` is raised and every junction thread has stopped.
 pub fn run_traffic_lights(
     traffic_lights: TrafficLightMap,
     log_tx: Sender<LogEvent>,
-    rec_rx: Receiver<Recommendation> // Add this argument
+    rec_rx: Receiver<Recommendation>,
+    applied_tx: Sender<RecommendationApplied>,
+    latest_counts: LatestCounts,
+    shutdown: ShutdownFlag,
+    clock: SimClock,
 ) {
     let lanes = load_lanes();
+    let network = load_network();
     let mut junction_map: HashMap<u32, Vec<Lane>> = HashMap::new();
 
-    // Map each intersection to its lanes
-    for lane in lanes {
-        if lane.end_intersection != 0 {
-            junction_map.entry(lane.end_intersection).or_default().push(lane);
+    // Map each intersection to its lanes; junctions without traffic lights are left to the cars.
+    for lane in &lanes {
+        if lane.end_intersection != 0 && network.is_signalized(lane.end_intersection) {
+            junction_map.entry(lane.end_intersection).or_default().push(lane.clone());
         }
     }
     
     // Spawn a controller thread for each junction.
     // Use into_iter() to move ownership into the loop to satisfy 'static requirements.
+    let hints: Arc<Mutex<HashMap<u32, JunctionHint>>> = Arc::new(Mutex::new(HashMap::new()));
+    let green_overrides: GreenOverrides = Arc::new(Mutex::new(HashMap::new()));
+    let walk_overrides: WalkOverrides = Arc::new(Mutex::new(HashMap::new()));
+    let all_red_holds: AllRedHolds = Arc::new(Mutex::new(HashMap::new()));
+    let timing_plans: TimingPlans = Arc::new(Mutex::new(HashMap::new()));
+    let timings = JunctionTimings::from_env();
+    let crossing_config = CrossingConfig::from_env();
+    let phase_order = PhaseOrder::from_env();
+    if let PhaseOrder::Adaptive { starvation_cycles } = phase_order {
+        println!("Adaptive phase ordering enabled: every phase green at least once per {} cycles", starvation_cycles);
+    }
+    let coordination = Coordination::from_env(&lanes, &network, &timings);
+    coordination.publish(&log_tx, &clock);
+    // The green waves are timed from here.
+    let start = Instant::now();
+    let mut junction_handles = Vec::new();
     for (junction, lane_list) in junction_map.into_iter() {
-        let groups = group_lanes_by_direction(&lane_list);
+        let phases = build_phase_plan(junction, &lanes, &network);
+        let timing = timings.for_junction(junction);
+        let crossings = crossing_config.for_junction(junction).map(|junction| junction.crossings.clone());
+        let (walk_every, configured_walk) = (crossing_config.every, crossing_config.walk);
+        let walk_overrides_clone = Arc::clone(&walk_overrides);
         let traffic_lights_clone = Arc::clone(&traffic_lights);
         let log_tx_clone = log_tx.clone();
+        let shutdown_clone = Arc::clone(&shutdown);
+        let hints_clone = Arc::clone(&hints);
+        let overrides_clone = Arc::clone(&green_overrides);
+        let all_red_clone = Arc::clone(&all_red_holds);
+        let plans_clone = Arc::clone(&timing_plans);
+        let coordinated = coordination.junction(junction);
+        let applied_tx_clone = applied_tx.clone();
+        let latest_counts_clone = Arc::clone(&latest_counts);
 
-        thread::spawn(move || {
+        junction_handles.push(thread::spawn(move || {
             let mut group_index = 0;
+            let mut cycles: u32 = 0;
+            let mut phases_served: usize = 0;
+            let mut selector = match phase_order {
+                PhaseOrder::Fixed => None,
+                PhaseOrder::Adaptive { starvation_cycles } => Some(PhaseSelector::new(phases.len(), starvation_cycles)),
+            };
+
+            while !shutdown::is_requested(&shutdown_clone) {
+                // Every lane is red between phases, so an all-red hold just
+                // keeps it that way for longer.
+                let hold = {
+                    let _t = budget::time_lock();
+                    all_red_clone.lock().unwrap().remove(&junction)
+                };
+                if let Some(hold) = hold {
+                    println!("Junction {}: holding every lane red for {}s on recommendation", junction, hold.as_secs());
+                    let all_red: Vec<(u32, LightColor)> =
+                        lane_list.iter().map(|lane| (lane.id, LightColor::Red)).collect();
+                    traffic_lights_clone.set_colors(&all_red);
+                    let _t = budget::time(Category::Sleep);
+                    clock.sleep_or_shutdown(hold, &shutdown_clone);
+                }
+                let hint = {
+                    let _t = budget::time_lock();
+                    hints_clone.lock().unwrap().remove(&junction)
+                };
+                let mut green_time = timing.green;
+                // Lanes whose recommendation this phase serves.
+                let mut applied_lanes = Vec::new();
+                if let Some(hint) = hint.filter(|hint| hint.phase < phases.len()) {
+                    group_index = hint.phase;
+                    green_time = Duration::from_secs(hint.green_secs).max(timing.green);
+                    applied_lanes.push(hint.lane_id);
+                    println!("Junction {}: serving recommended phase {} for {}s",
+                             junction, group_index, green_time.as_secs());
+                } else if let Some(selector) = &selector {
+                    let demand = {
+                        let _t = budget::time_lock();
+                        phase_demand(&phases, &latest_counts_clone.lock().unwrap())
+                    };
+                    let decision = selector.choose(&demand);
+                    group_index = decision.phase;
+                    let log_event = LogEvent::new(
+                        format!("Junction-{}", junction),
+                        clock.now_secs(),
+                        EventKind::PhaseDecision {
+                            junction,
+                            phase: decision.phase,
+                            demand: decision.demand,
+                            overdue: decision.overdue,
+                        },
+                    );
+                    let _t = budget::time(Category::Transport);
+                    log_tx_clone.send(log_event).ok();
+                }
+                if let Some(selector) = &mut selector {
+                    selector.served(group_index);
+                }
+                // A timing plan replaces the baseline green, unless a
+                // recommended phase has already set it.
+                let planned = {
+                    let _t = budget::time_lock();
+                    plans_clone.lock().unwrap().get(&junction).and_then(|greens| greens.get(group_index).copied())
+                };
+                if let Some(secs) = planned.filter(|_| applied_lanes.is_empty()) {
+                    green_time = Duration::from_secs(u64::from(secs));
+                }
+                if let Some(member) = coordinated.filter(|member| member.phase == group_index) {
+                    let wait = member.wait_for_slot(clock.since(start));
+                    if !wait.is_zero() {
+                        let _t = budget::time(Category::Sleep);
+                        clock.sleep_or_shutdown(wait, &shutdown_clone);
+                    }
+                    green_time += member.slack;
+                }
+                // Lane recommendations for this phase apply once, then are gone.
+                let lane_overrides: Vec<(u32, u32)> = {
+                    let _t = budget::time_lock();
+                    let mut overrides = overrides_clone.lock().unwrap();
+                    phases[group_index].lanes
+                        .iter()
+                        .filter_map(|&lane_id| overrides.remove(&lane_id).map(|secs| (lane_id, secs)))
+                        .collect()
+                };
+                if let Some(secs) = lane_overrides.iter().map(|&(_, secs)| secs).max() {
+                    green_time = green_time.max(Duration::from_secs(u64::from(secs)));
+                    applied_lanes.extend(lane_overrides.iter().map(|&(lane_id, _)| lane_id));
+                    println!("Junction {}: holding phase {} green for {}s on recommendation",
+                             junction, group_index, green_time.as_secs());
+                }
+                for lane_id in applied_lanes {
+                    applied_tx_clone.send(RecommendationApplied {
+                        lane_id,
+                        applied_green_time: green_time.as_secs() as u32,
+                        timestamp: clock.now_secs(),
+                    }).ok();
+                }
 
-            loop {
                 let mut green_lanes = Vec::new();
                 let mut red_lanes = Vec::new();
-
-                {
-                    let mut lights = traffic_lights_clone.lock().unwrap();
-                    for lane in &lane_list {
-                        if groups[group_index].contains(&lane.id) {
-                            lights.insert(lane.id, LightColor::Green);
-                            green_lanes.push(lane.id);
-                        } else {
-                            lights.insert(lane.id, LightColor::Red);
-                            red_lanes.push(lane.id);
-                        }
+                let mut updates = Vec::new();
+                for lane in &lane_list {
+                    if phases[group_index].lanes.contains(&lane.id) {
+                        updates.push((lane.id, LightColor::Green));
+                        green_lanes.push(lane.id);
+                    } else {
+                        updates.push((lane.id, LightColor::Red));
+                        red_lanes.push(lane.id);
                     }
                 }
+                traffic_lights_clone.set_colors(&updates);
 
                 // Log Green and Red lanes for debugging
-                let log_event = LogEvent {
-                    source: format!("Junction-{}", junction),
-                    message: format!(
-                        "Phase {} active: Green lanes {:?}, Red lanes {:?}",
-                        group_index, green_lanes, red_lanes
-                    ),
-                    timestamp: current_time_secs(),
-                };
-                log_tx_clone.send(log_event).unwrap();
+                let log_event = LogEvent::new(
+                    format!("Junction-{}", junction),
+                    clock.now_secs(),
+                    EventKind::PhaseChange {
+                        junction,
+                        phase: group_index,
+                        green_lanes,
+                        red_lanes,
+                    },
+                );
+                {
+                    let _t = budget::time(Category::Transport);
+                    log_tx_clone.send(log_event).ok();
+                }
 
                 // Green light phase
-                thread::sleep(Duration::from_secs(5));
+                {
+                    let _t = budget::time(Category::Sleep);
+                    clock.sleep_or_shutdown(green_time, &shutdown_clone);
+                }
+
+                // Amber: cars already in the junction clear it, the rest stop.
+                let amber: Vec<(u32, LightColor)> =
+                    phases[group_index].lanes.iter().map(|&lane_id| (lane_id, LightColor::Amber)).collect();
+                traffic_lights_clone.set_colors(&amber);
+                {
+                    let _t = budget::time(Category::Sleep);
+                    clock.sleep_or_shutdown(timing.amber, &shutdown_clone);
+                }
 
                 // All-red clearance phase
+                let all_red: Vec<(u32, LightColor)> =
+                    lane_list.iter().map(|lane| (lane.id, LightColor::Red)).collect();
+                traffic_lights_clone.set_colors(&all_red);
                 {
-                    let mut lights = traffic_lights_clone.lock().unwrap();
-                    for lane in &lane_list {
-                        lights.insert(lane.id, LightColor::Red);
-                    }
+                    let _t = budget::time(Category::Sleep);
+                    clock.sleep_or_shutdown(timing.all_red, &shutdown_clone);
                 }
-                thread::sleep(Duration::from_secs(10));
+                budget::flush("TrafficLight");
 
-                // Move to the next group
-                group_index = (group_index + 1) % groups.len();
+                // Move to the next phase
+                group_index = (group_index + 1) % phases.len();
+                phases_served += 1;
+                // Adaptive junctions have no fixed order, so they complete a
+                // cycle every time they have served as many phases as they have.
+                let cycle_complete = match selector {
+                    Some(_) => phases_served.is_multiple_of(phases.len()),
+                    None => group_index == 0,
+                };
+
+                // Walk phase: every lane stays red as the clearance left it.
+                let Some(crossings) = &crossings else {
+                    continue;
+                };
+                if !cycle_complete {
+                    continue;
+                }
+                cycles += 1;
+                if !cycles.is_multiple_of(walk_every) {
+                    continue;
+                }
+                let walk = {
+                    let _t = budget::time_lock();
+                    walk_overrides_clone.lock().unwrap().get(&junction).copied().unwrap_or(configured_walk)
+                };
+                if walk.is_zero() {
+                    println!("Junction {}: skipping walk phase, no pedestrians waiting", junction);
+                    continue;
+                }
+                let log_event = LogEvent::new(
+                    format!("Junction-{}", junction),
+                    clock.now_secs(),
+                    EventKind::PedestrianPhase {
+                        junction,
+                        crossings: crossings.clone(),
+                        walk_secs: walk.as_secs(),
+                    },
+                );
+                {
+                    let _t = budget::time(Category::Transport);
+                    log_tx_clone.send(log_event).ok();
+                }
+                {
+                    let _t = budget::time(Category::Sleep);
+                    clock.sleep_or_shutdown(walk, &shutdown_clone);
+                }
             }
-        });
+        }));
     }
+    // Junction threads hold their own senders; dropping ours lets monitoring
+    // see the channel close once they have all stopped.
+    drop(log_tx);
+    drop(applied_tx);
 
-    loop {
+    while !shutdown::is_requested(&shutdown) {
         // 📥 Receive a recommendation (Example: from a channel)
-        match rec_rx.recv() {
+        match rec_rx.recv_timeout(Duration::from_millis(100)) {
             Ok(new_rec) => {
                 println!("✅ Received Recommendation from analyzer: {:?}", new_rec);
-               //process recommendation
+                let _t = budget::time_lock();
+                match new_rec {
+                    Recommendation::ExtendGreen { lane_id, new_green_time, .. } => {
+                        if traffic_lights.controls(lane_id) {
+                            green_overrides.lock().unwrap().insert(lane_id, new_green_time);
+                        } else {
+                            println!("Recommendation ignored: lane {} has no traffic light", lane_id);
+                        }
+                    }
+                    Recommendation::ReorderPhases { junction_id, phase, lane_id, new_green_time, .. } => {
+                        let hint = JunctionHint { lane_id, phase, green_secs: u64::from(new_green_time) };
+                        hints.lock().unwrap().insert(junction_id, hint);
+                    }
+                    Recommendation::TimingPlan(plan) => {
+                        timing_plans.lock().unwrap().insert(plan.junction, plan.greens);
+                    }
+                    Recommendation::EmergencyAllRed { junction_id, duration_secs, .. } => {
+                        all_red_holds.lock().unwrap().insert(junction_id, Duration::from_secs(u64::from(duration_secs)));
+                    }
+                    Recommendation::SuggestReroute(_) => {
+                        println!("Recommendation ignored: reroutes are for the simulation");
+                    }
+                    Recommendation::AdjustWalkTime { junction_id, new_walk_time } => {
+                        if crossing_config.for_junction(junction_id).is_some() {
+                            walk_overrides.lock().unwrap().insert(junction_id, Duration::from_secs(u64::from(new_walk_time)));
+                        } else {
+                            println!("Recommendation ignored: junction {} has no pedestrian crossings", junction_id);
+                        }
+                    }
+                }
             }
-            Err(e) => {
-                println!("❌ Error receiving recommendation: {:?}", e);
-                break; // Exit loop if the receiver is disconnected
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // The analyzer has stopped; keep cycling until shutdown.
+                shutdown::sleep_or_shutdown(Duration::from_millis(100), &shutdown);
             }
         }
     }
+
+    for handle in junction_handles {
+        handle.join().ok();
+    }
 }
 
-/// Helper: Returns the current system time (in seconds since the Unix epoch).
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::mpsc;
+
+    use crate::signal_timing::JunctionTiming;
+
+    /// Times a waiter on each lane woke up, counted by `wait_for_turn`.
+    static WAKEUPS: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+    pub(super) fn woke(lane_id: u32) {
+        *WAKEUPS.lock().unwrap().entry(lane_id).or_insert(0) += 1;
+    }
+
+    /// Wakeups per lane among `lanes` since the last call.
+    fn take_wakeups(lanes: &[u32]) -> BTreeMap<u32, usize> {
+        let mut wakeups = WAKEUPS.lock().unwrap();
+        lanes.iter().filter_map(|lane_id| wakeups.remove(lane_id).map(|count| (*lane_id, count))).collect()
+    }
+
+    /// Simulated seconds each phase change of `junction` stays in force
+    /// before the next, with the lanes it turned green.
+    fn phase_spans(events: &[LogEvent], junction: u32) -> Vec<(Vec<u32>, u64)> {
+        let changes: Vec<(Vec<u32>, u64)> = events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                EventKind::PhaseChange { junction: j, green_lanes, .. } if *j == junction => {
+                    Some((green_lanes.clone(), event.timestamp))
+                }
+                _ => None,
+            })
+            .collect();
+        changes.windows(2).map(|pair| (pair[0].0.clone(), pair[1].1 - pair[0].1)).collect()
+    }
+
+    #[test]
+    fn recommended_green_time_holds_the_next_phase_once() {
+        let (lanes, network) = (load_lanes(), load_network());
+        // A lane of the second phase, so the junction has yet to serve it
+        // when the recommendation arrives.
+        let (junction, lane_id) = network
+            .intersections()
+            .filter(|&junction| network.is_signalized(junction))
+            .find_map(|junction| {
+                let phases = build_phase_plan(junction, &lanes, &network);
+                Some((junction, *phases.get(1)?.lanes.first()?))
+            })
+            .expect("a signalized junction with two phases");
+        let JunctionTiming { green, amber, all_red } = JunctionTimings::default().for_junction(junction);
+        let recommended: u32 = 30;
+
+        let (log_tx, log_rx) = mpsc::channel();
+        let (rec_tx, rec_rx) = mpsc::channel();
+        let (applied_tx, applied_rx) = mpsc::channel();
+        let shutdown = shutdown::new_flag();
+        rec_tx.send(Recommendation::ExtendGreen { lane_id, new_green_time: recommended }).unwrap();
+        let controller = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let counts = Arc::new(Mutex::new(HashMap::new()));
+                let lights = initialize_traffic_lights();
+                run_traffic_lights(lights, log_tx, rec_rx, applied_tx, counts, shutdown, SimClock::new(50.0))
+            })
+        };
+
+        let applied = applied_rx.recv_timeout(Duration::from_secs(30)).expect("recommendation applied");
+        assert_eq!((applied.lane_id, applied.applied_green_time), (lane_id, recommended));
+        // Wait for the lane's phase to come round twice more after the
+        // recommended one.
+        let mut events = Vec::new();
+        while phase_spans(&events, junction).iter().filter(|(green, _)| green.contains(&lane_id)).count() < 3 {
+            events.push(log_rx.recv_timeout(Duration::from_secs(30)).expect("junction keeps cycling"));
+        }
+        shutdown::request(&shutdown);
+        controller.join().unwrap();
+
+        let served: Vec<u64> = phase_spans(&events, junction)
+            .into_iter()
+            .filter(|(green, _)| green.contains(&lane_id))
+            .map(|(_, secs)| secs)
+            .collect();
+        // Timestamps are whole seconds, so spans may come out a second long.
+        let lasts = |secs: u64, green: Duration| {
+            let expected = (green + amber + all_red).as_secs();
+            (expected..=expected + 1).contains(&secs)
+        };
+        assert!(lasts(served[0], Duration::from_secs(u64::from(recommended))), "{:?}", served);
+        assert!(served[1..].iter().all(|&secs| lasts(secs, green)), "{:?}", served);
+    }
+
+    /// A lane the cars turn into, holding up to 10 vehicles.
+    fn target() -> Lane {
+        Lane {
+            id: 2,
+            start_intersection: 1,
+            end_intersection: 2,
+            length: 100.0,
+            capacity: 10,
+            parallel_count: 1,
+            movement: None,
+            category: rts_core::lanes::LaneCategory::Internal,
+        }
+    }
+
+    #[test]
+    fn right_turn_goes_on_red_only_into_a_lane_with_room() {
+        let lights = TrafficLights::new(HashMap::from([(1, LightColor::Red)]));
+        assert_eq!(lights.wait_for_turn(1, 7, Movement::RightTurn, &target(), || 4), Some(4));
+
+        // Straight on, or into a half-full lane, the car waits for green.
+        let lights = Arc::new(lights);
+        for (movement, occupancy) in [(Movement::Straight, 0), (Movement::RightTurn, 5)] {
+            let waiter = {
+                let lights = Arc::clone(&lights);
+                thread::spawn(move || lights.wait_for_turn(1, 8, movement, &target(), move || occupancy))
+            };
+            thread::sleep(Duration::from_millis(700));
+            assert!(!waiter.is_finished(), "{:?} into {} of 10 went on red", movement, occupancy);
+            lights.set_colors(&[(1, LightColor::Green)]);
+            assert_eq!(waiter.join().unwrap(), None);
+            lights.set_colors(&[(1, LightColor::Red)]);
+        }
+    }
+
+    #[test]
+    fn set_colors_wakes_only_the_lanes_that_changed() {
+        // 1000 cars queued 20 deep on 50 lanes no other test uses.
+        const LANES: u32 = 50;
+        const DEPTH: u32 = 20;
+        let lane_ids: Vec<u32> = (10_001..=10_000 + LANES).collect();
+        let lights = Arc::new(TrafficLights::new(lane_ids.iter().map(|&id| (id, LightColor::Red)).collect()));
+        let waiters: Vec<_> = lane_ids
+            .iter()
+            .flat_map(|&lane_id| (0..DEPTH).map(move |n| (lane_id, lane_id * 100 + n)))
+            .map(|(lane_id, car_id)| {
+                let lights = Arc::clone(&lights);
+                thread::spawn(move || lights.wait_for_turn(lane_id, car_id, Movement::Straight, &target(), || 0))
+            })
+            .collect();
+        while lane_ids.iter().map(|&id| lights.state.lock().unwrap().queues.len(id)).sum::<usize>() < waiters.len() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let settle = || thread::sleep(Duration::from_millis(200));
+        settle();
+        assert!(take_wakeups(&lane_ids).is_empty());
+
+        // Lanes staying red are left alone.
+        let all_red: Vec<(u32, LightColor)> = lane_ids.iter().map(|&id| (id, LightColor::Red)).collect();
+        lights.set_colors(&all_red);
+        settle();
+        assert!(take_wakeups(&lane_ids).is_empty());
+
+        // A green lane wakes its own cars only: each car passing wakes those
+        // still behind it, at most DEPTH * (DEPTH + 1) / 2 wakeups in all.
+        let bound = (DEPTH * (DEPTH + 1) / 2) as usize;
+        for batch in lane_ids.chunks(10) {
+            let mut updates: Vec<(u32, LightColor)> = batch.iter().map(|&id| (id, LightColor::Green)).collect();
+            updates.extend(all_red.iter().filter(|(id, _)| !batch.contains(id)));
+            lights.set_colors(&updates);
+            while batch.iter().any(|&id| lights.state.lock().unwrap().queues.len(id) > 0) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            settle();
+            let wakeups = take_wakeups(&lane_ids);
+            assert!(wakeups.keys().all(|id| batch.contains(id)), "woke {:?} turning {:?} green", wakeups, batch);
+            assert!(wakeups.values().all(|&count| count <= bound), "{:?}", wakeups);
+            lights.set_colors(&all_red);
+        }
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), None);
+        }
+    }
 }
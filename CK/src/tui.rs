@@ -0,0 +1,290 @@
+// tui.rs
+//
+// Full-screen dashboard for `--tui`, drawn with ratatui on the terminal's
+// alternate screen: the junction grid as the network file lays it out, each
+// junction listing its approach lanes and the vehicles on them in the color
+// of their light, above a pane of the latest events. Up/Down and Page
+// Up/Down scroll the pane back; End follows the newest events again.
+//
+// What the other components print while the dashboard is up would land on
+// top of it, so standard output and error are pointed at a pipe for the
+// duration and the captured lines shown in an output pane beside the events;
+// the dashboard itself is drawn through a copy of the original standard
+// output. Both are put back when the run ends, or if anything panics, and
+// everything captured is then printed, so the run's own reports still end
+// up in the terminal.
+//
+// The terminal is in raw mode while the dashboard is up, so Ctrl-C arrives
+// as a key rather than a signal. The first one interrupts the run as Ctrl-C
+// otherwise would (see `shutdown`); a second quits at once.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::Show;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::dashboard::Dashboard;
+use crate::shutdown::{self, ShutdownFlag};
+use crate::traffic_light::LightColor;
+use rts_core::lanes::load_lanes;
+use rts_core::network::load_network;
+
+/// How often the dashboard is redrawn and keys are read, in real time.
+pub const REFRESH: Duration = Duration::from_millis(250);
+
+/// Lines Page Up and Page Down scroll the log pane by.
+const PAGE: usize = 10;
+
+/// Standard output and error as they were before the capture, while it lasts.
+static SAVED_FDS: [AtomicI32; 2] = [AtomicI32::new(-1), AtomicI32::new(-1)];
+
+/// Output captured while the dashboard is up: the lines so far, and the
+/// thread collecting them, which ends once the capture is undone.
+struct Captured {
+    lines: Arc<Mutex<Vec<String>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+/// Points standard output and error at a pipe whose lines a background
+/// thread collects. Returns the terminal, as a copy of the original standard
+/// output, to draw on.
+fn capture_output() -> io::Result<(File, Captured)> {
+    let mut pipe: [RawFd; 2] = [-1; 2];
+    // SAFETY: plain descriptor calls on descriptors this function owns or on
+    // the process's standard ones; every result is checked.
+    let terminal = unsafe {
+        if libc::pipe(pipe.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let terminal = libc::dup(1);
+        let saved = [libc::dup(1), libc::dup(2)];
+        if terminal < 0 || saved.iter().any(|&fd| fd < 0) {
+            return Err(io::Error::last_os_error());
+        }
+        io::stdout().flush().ok();
+        if libc::dup2(pipe[1], 1) < 0 || libc::dup2(pipe[1], 2) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::close(pipe[1]);
+        for (slot, fd) in SAVED_FDS.iter().zip(saved) {
+            slot.store(fd, Ordering::SeqCst);
+        }
+        File::from_raw_fd(terminal)
+    };
+    let lines: Arc<Mutex<Vec<String>>> = Arc::default();
+    // SAFETY: the read end of the pipe is owned by nothing else.
+    let pipe = BufReader::new(unsafe { File::from_raw_fd(pipe[0]) });
+    let collected = Arc::clone(&lines);
+    let reader = thread::spawn(move || {
+        for line in pipe.lines().map_while(Result::ok) {
+            collected.lock().unwrap().push(line);
+        }
+    });
+    Ok((terminal, Captured { lines, reader }))
+}
+
+/// Leaves raw mode and the alternate screen and puts standard output and
+/// error back. Safe to call more than once.
+fn restore() {
+    disable_raw_mode().ok();
+    io::stdout().flush().ok();
+    for (fd, slot) in [1, 2].into_iter().zip(&SAVED_FDS) {
+        let saved = slot.swap(-1, Ordering::SeqCst);
+        if saved >= 0 {
+            // SAFETY: `saved` is a descriptor `capture_output` duplicated and
+            // nothing else closes.
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+    }
+    execute!(io::stdout(), LeaveAlternateScreen, Show).ok();
+}
+
+/// A junction's place on the screen.
+struct GridJunction {
+    id: u32,
+    row: usize,
+    col: usize,
+    approaches: Vec<u32>,
+}
+
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<File>>,
+    /// What the other components printed.
+    output: Captured,
+    junctions: Vec<GridJunction>,
+    rows: usize,
+    cols: usize,
+    /// Lines the log pane is scrolled back from the newest event.
+    scroll: usize,
+    interrupted: ShutdownFlag,
+}
+
+impl Tui {
+    /// Switches the terminal to the dashboard; Ctrl-C raises `interrupted`.
+    pub fn start(interrupted: ShutdownFlag) -> io::Result<Tui> {
+        let lanes = load_lanes();
+        let network = load_network();
+        let ((min_row, min_col), (max_row, max_col)) = network.bounds();
+        let junctions = network
+            .intersections()
+            .filter_map(|id| {
+                let (row, col) = network.coords(id)?;
+                let mut approaches: Vec<u32> =
+                    lanes.iter().filter(|lane| lane.end_intersection == id).map(|lane| lane.id).collect();
+                approaches.sort();
+                Some(GridJunction { id, row: (row - min_row) as usize, col: (col - min_col) as usize, approaches })
+            })
+            .collect();
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            hook(info);
+        }));
+        let (mut terminal, output) = capture_output()?;
+        let terminal = enable_raw_mode()
+            .and_then(|_| execute!(terminal, EnterAlternateScreen))
+            .and_then(|_| Terminal::new(CrosstermBackend::new(terminal)))
+            .inspect_err(|_| restore())?;
+        Ok(Tui {
+            terminal,
+            output,
+            junctions,
+            rows: (max_row - min_row + 1) as usize,
+            cols: (max_col - min_col + 1) as usize,
+            scroll: 0,
+            interrupted,
+        })
+    }
+
+    /// Handles the keys pressed since the last call, then redraws.
+    pub fn draw(&mut self, dashboard: &Dashboard) -> io::Result<()> {
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.interrupt(),
+                    KeyCode::Up => self.scroll += 1,
+                    KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+                    KeyCode::PageUp => self.scroll += PAGE,
+                    KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+                    KeyCode::End => self.scroll = 0,
+                    _ => {}
+                }
+            }
+        }
+        self.scroll = self.scroll.min(dashboard.log().len().saturating_sub(1));
+        let output = self.output.lines.lock().unwrap();
+        let Tui { terminal, junctions, rows, cols, scroll, interrupted, .. } = self;
+        terminal.draw(|frame| render(frame, dashboard, &output, junctions, *rows, *cols, *scroll, interrupted))?;
+        Ok(())
+    }
+
+    fn interrupt(&mut self) {
+        if shutdown::is_requested(&self.interrupted) {
+            restore();
+            std::process::exit(130);
+        }
+        shutdown::request(&self.interrupted);
+    }
+
+    /// Gives the terminal back and prints what was captured.
+    pub fn finish(self) {
+        restore();
+        self.output.reader.join().ok();
+        let mut out = io::stdout().lock();
+        for line in self.output.lines.lock().unwrap().iter() {
+            writeln!(out, "{}", line).ok();
+        }
+    }
+}
+
+fn light_color(light: Option<LightColor>) -> Color {
+    match light {
+        Some(LightColor::Green) => Color::Green,
+        Some(LightColor::Amber) => Color::Yellow,
+        Some(LightColor::Red) => Color::Red,
+        None => Color::DarkGray,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render(
+    frame: &mut Frame,
+    dashboard: &Dashboard,
+    output: &[String],
+    junctions: &[GridJunction],
+    rows: usize,
+    cols: usize,
+    scroll: usize,
+    interrupted: &ShutdownFlag,
+) {
+    let [status, grid, log] =
+        Layout::vertical([Constraint::Length(1), Constraint::Percentage(65), Constraint::Fill(1)]).areas(frame.area());
+
+    let mut header = dashboard.status();
+    if shutdown::is_requested(interrupted) {
+        header.push_str("   Interrupted: finishing the cars on the road (Ctrl-C again to quit now)");
+    }
+    frame.render_widget(Paragraph::new(header), status);
+
+    let row_areas = Layout::vertical(vec![Constraint::Ratio(1, rows as u32); rows]).split(grid);
+    let cells: Vec<Vec<Rect>> = row_areas
+        .iter()
+        .map(|&row| Layout::horizontal(vec![Constraint::Ratio(1, cols as u32); cols]).split(row).to_vec())
+        .collect();
+    let counts = dashboard.lane_counts();
+    for junction in junctions {
+        let Some(&area) = cells.get(junction.row).and_then(|row| row.get(junction.col)) else {
+            continue;
+        };
+        let title = match dashboard.phase(junction.id) {
+            Some(phase) => format!(" J{} phase {} ", junction.id, phase),
+            None => format!(" J{} ", junction.id),
+        };
+        let lines: Vec<Line> = junction
+            .approaches
+            .iter()
+            .map(|&lane_id| {
+                let style = Style::new().fg(light_color(dashboard.light(lane_id)));
+                let vehicles = counts.get(&lane_id).copied().unwrap_or(0);
+                Line::from(vec![Span::styled("● ", style), Span::raw(format!("{} {:>3}", lane_id, vehicles))])
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), area);
+    }
+
+    let [log, printed] = Layout::horizontal([Constraint::Percentage(60), Constraint::Fill(1)]).areas(log);
+    let height = log.height.saturating_sub(2) as usize;
+    let events = dashboard.log();
+    let end = events.len().saturating_sub(scroll);
+    let lines: Vec<Line> = events.range(end.saturating_sub(height)..end).map(|line| Line::raw(line.as_str())).collect();
+    let title = if scroll > 0 {
+        format!(" Events (scrolled back {}, End to follow) ", scroll)
+    } else {
+        " Events ".to_string()
+    };
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), log);
+
+    let height = printed.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = output[output.len().saturating_sub(height)..].iter().map(|line| Line::raw(line.as_str())).collect();
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Output ")), printed);
+}
@@ -0,0 +1,219 @@
+// vehicle.rs
+//
+// Vehicle kinds and the mix they are spawned in. Each kind has its own speed
+// range, a length and an acceleration. The length sets the vehicle's
+// footprint, the number of occupancy units it takes up on a lane, so a bus
+// fills a lane faster than a car does; the acceleration sets how much time a
+// vehicle loses pulling away after it had to stop (see `Vehicle::lane_time`).
+//
+// Speeds and accelerations keep the simulation's time compression (cars
+// still draw 70–90), so runs take as long as before; only the ratios between
+// kinds are meaningful.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VehicleKind {
+    Car,
+    Bus,
+    Truck,
+    Motorcycle,
+    /// Spawnable and weighted like a car; priority handling is not implemented yet.
+    EmergencyVehicle,
+}
+
+/// Length of a car, in meters: one occupancy unit.
+const CAR_LENGTH: f64 = 4.5;
+
+/// Seconds a vehicle must have waited at the start of a lane to count as
+/// stopped, and pull away from rest.
+pub const STOPPED_SECS: f64 = 0.5;
+
+impl VehicleKind {
+    pub const ALL: [VehicleKind; 5] = [
+        VehicleKind::Car,
+        VehicleKind::Bus,
+        VehicleKind::Truck,
+        VehicleKind::Motorcycle,
+        VehicleKind::EmergencyVehicle,
+    ];
+
+    /// Range the vehicle's speed is drawn from.
+    pub fn speed_range(self) -> RangeInclusive<f64> {
+        match self {
+            VehicleKind::Car => 70.0..=90.0,
+            VehicleKind::Bus => 45.0..=60.0,
+            VehicleKind::Truck => 50.0..=70.0,
+            VehicleKind::Motorcycle => 80.0..=100.0,
+            VehicleKind::EmergencyVehicle => 90.0..=110.0,
+        }
+    }
+
+    /// Length of the vehicle, in meters.
+    pub fn length(self) -> f64 {
+        match self {
+            VehicleKind::Car | VehicleKind::EmergencyVehicle => CAR_LENGTH,
+            VehicleKind::Bus => 12.0,
+            VehicleKind::Truck => 9.0,
+            VehicleKind::Motorcycle => 2.2,
+        }
+    }
+
+    /// Acceleration from rest, in speed units per second.
+    pub fn acceleration(self) -> f64 {
+        match self {
+            VehicleKind::Car => 40.0,
+            VehicleKind::Bus => 15.0,
+            VehicleKind::Truck => 20.0,
+            VehicleKind::Motorcycle => 60.0,
+            VehicleKind::EmergencyVehicle => 50.0,
+        }
+    }
+
+    /// Occupancy units the vehicle takes up on a lane: its length in car
+    /// lengths, rounded up.
+    pub fn footprint(self) -> u32 {
+        ((self.length() / CAR_LENGTH).ceil() as u32).max(1)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VehicleKind::Car => "car",
+            VehicleKind::Bus => "bus",
+            VehicleKind::Truck => "truck",
+            VehicleKind::Motorcycle => "motorcycle",
+            VehicleKind::EmergencyVehicle => "emergency",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<VehicleKind> {
+        VehicleKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+impl fmt::Display for VehicleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A simulated vehicle: its kind and the speed it drew for this trip.
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    pub id: u32,
+    pub kind: VehicleKind,
+    pub speed: f64,
+    /// Seeds the vehicle's own random choices (entry and exit lane), so a run
+    /// seed fixes the whole demand however the car threads are scheduled.
+    pub trip_seed: u64,
+    /// Entry and exit lane ids of a replayed trip, used instead of drawing one.
+    pub replayed_trip: Option<(u32, u32)>,
+}
+
+impl Vehicle {
+    pub fn new(id: u32, kind: VehicleKind, rng: &mut impl Rng) -> Self {
+        Vehicle {
+            id,
+            kind,
+            speed: rng.random_range(kind.speed_range()),
+            trip_seed: rng.random(),
+            replayed_trip: None,
+        }
+    }
+
+    /// Seconds to drive a lane of `length` after waiting `waited` seconds at
+    /// its start. A vehicle that stopped accelerates from rest until it
+    /// reaches its speed, or to the end of the lane if that is too short;
+    /// one that did not drives the lane at its speed.
+    pub fn lane_time(&self, length: f64, waited: f64) -> f64 {
+        let cruise = length / self.speed;
+        if waited < STOPPED_SECS {
+            return cruise;
+        }
+        let acceleration = self.kind.acceleration();
+        // Distance covered while accelerating to full speed.
+        let ramp = self.speed * self.speed / (2.0 * acceleration);
+        if ramp >= length {
+            (2.0 * length / acceleration).sqrt()
+        } else {
+            cruise + self.speed / (2.0 * acceleration)
+        }
+    }
+}
+
+/// Relative weights of the vehicle kinds spawned by the simulation.
+#[derive(Debug, Clone)]
+pub struct VehicleMix {
+    weights: Vec<(VehicleKind, f64)>,
+}
+
+impl Default for VehicleMix {
+    /// 80% cars, 15% trucks, 5% buses.
+    fn default() -> Self {
+        VehicleMix {
+            weights: vec![
+                (VehicleKind::Car, 80.0),
+                (VehicleKind::Truck, 15.0),
+                (VehicleKind::Bus, 5.0),
+            ],
+        }
+    }
+}
+
+impl VehicleMix {
+    /// Parses a mix such as `car=80,truck=15,bus=5,motorcycle=3`. Weights are
+    /// relative and need not add up to 100; kinds left out are never spawned.
+    pub fn parse(spec: &str) -> Result<VehicleMix, String> {
+        let mut weights = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected kind=weight, got '{}'", entry))?;
+            let kind = VehicleKind::from_name(name.trim())
+                .ok_or_else(|| format!("unknown vehicle kind '{}'", name.trim()))?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid weight '{}' for {}", weight.trim(), kind))?;
+            if !(weight >= 0.0 && weight.is_finite()) {
+                return Err(format!("invalid weight '{}' for {}", weight, kind));
+            }
+            weights.push((kind, weight));
+        }
+        if weights.iter().all(|&(_, weight)| weight == 0.0) {
+            return Err("vehicle mix has no positive weight".to_string());
+        }
+        Ok(VehicleMix { weights })
+    }
+
+    /// Mix from RTS_VEHICLE_MIX, falling back to the default (with a warning)
+    /// when it is unset or invalid.
+    pub fn from_env() -> VehicleMix {
+        match std::env::var("RTS_VEHICLE_MIX") {
+            Ok(spec) => VehicleMix::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_VEHICLE_MIX: {}", e);
+                VehicleMix::default()
+            }),
+            Err(_) => VehicleMix::default(),
+        }
+    }
+
+    /// Draws a vehicle kind according to the weights.
+    pub fn sample(&self, rng: &mut impl Rng) -> VehicleKind {
+        let total: f64 = self.weights.iter().map(|&(_, weight)| weight).sum();
+        let mut pick = rng.random_range(0.0..total);
+        for &(kind, weight) in &self.weights {
+            if pick < weight {
+                return kind;
+            }
+            pick -= weight;
+        }
+        // Rounding can leave `pick` just past the last bucket.
+        self.weights.iter().rev().find(|&&(_, weight)| weight > 0.0).unwrap().0
+    }
+}
@@ -0,0 +1,194 @@
+// config.rs
+//
+// Settings of a CY run, in layers: built-in defaults, then a JSON config
+// file, then the environment, then command-line flags, each overriding the
+// one before. The file is named by `--config <path>` or RTS_CONFIG and holds
+// an object of settings by name, e.g.
+//
+//   {"green_secs": 8, "clearance_secs": 4, "cars": 100, "logs_port": 7100}
+//
+// Every setting also has a variable and a flag:
+//
+//   green_secs            RTS_GREEN_SECS            --green-secs
+//   amber_secs            RTS_AMBER_SECS            --amber-secs
+//   clearance_secs        RTS_CLEARANCE_SECS        --clearance-secs
+//   poll_ms               RTS_POLL_MS               --poll-ms
+//   cars                  SIM_CARS                  --cars
+//   arrival_rate          SIM_ARRIVAL_RATE          --arrival-rate
+//   min_speed, max_speed  RTS_MIN_SPEED, ...        --min-speed, ...
+//   port_offset           RTS_PORT_OFFSET           --port-offset
+//   logs_port, ...        RTS_LOGS_PORT, ...        --logs-port, ...
+//
+// An invalid or unknown setting is ignored with a warning, leaving the layer
+// below in place. Spawn-all forwards its flags to every process, and each
+// process loads the same file and environment, so they all agree.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use rts_core::demand::{Arrivals, Demand};
+
+use crate::endpoints::Ports;
+
+/// Green time of a phase with no recommendation pending.
+pub const DEFAULT_GREEN_SECS: u32 = 5;
+/// Amber time after every green.
+pub const DEFAULT_AMBER_SECS: u64 = 3;
+/// All-red clearance between two phases.
+pub const DEFAULT_CLEARANCE_SECS: u64 = 10;
+/// How often sleeps, socket reads and waiting cars look again.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Range car speeds are drawn from, in m/s.
+pub const DEFAULT_SPEEDS: (f64, f64) = (70.0, 90.0);
+
+/// The settings every CY process runs with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Default ports of the ZeroMQ channels (see `endpoints`).
+    pub ports: Ports,
+    pub green_secs: u32,
+    pub amber_secs: u64,
+    pub clearance_secs: u64,
+    pub poll_interval: Duration,
+    /// Vehicles the simulation spawns, and when.
+    pub demand: Demand,
+    /// Slowest speed a car is drawn, in m/s.
+    pub min_speed: f64,
+    /// Fastest speed a car is drawn, in m/s.
+    pub max_speed: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ports: Ports::default(),
+            green_secs: DEFAULT_GREEN_SECS,
+            amber_secs: DEFAULT_AMBER_SECS,
+            clearance_secs: DEFAULT_CLEARANCE_SECS,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            demand: Demand::default(),
+            min_speed: DEFAULT_SPEEDS.0,
+            max_speed: DEFAULT_SPEEDS.1,
+        }
+    }
+}
+
+/// Every setting by name, with its variable and flag.
+const SETTINGS: [(&str, &str, &str); 15] = [
+    ("green_secs", "RTS_GREEN_SECS", "--green-secs"),
+    ("amber_secs", "RTS_AMBER_SECS", "--amber-secs"),
+    ("clearance_secs", "RTS_CLEARANCE_SECS", "--clearance-secs"),
+    ("poll_ms", "RTS_POLL_MS", "--poll-ms"),
+    ("cars", "SIM_CARS", "--cars"),
+    ("arrival_rate", "SIM_ARRIVAL_RATE", "--arrival-rate"),
+    ("min_speed", "RTS_MIN_SPEED", "--min-speed"),
+    ("max_speed", "RTS_MAX_SPEED", "--max-speed"),
+    ("port_offset", "RTS_PORT_OFFSET", "--port-offset"),
+    ("logs_port", "RTS_LOGS_PORT", "--logs-port"),
+    ("updates_port", "RTS_UPDATES_PORT", "--updates-port"),
+    ("recommendations_port", "RTS_RECOMMENDATIONS_PORT", "--recommendations-port"),
+    ("queries_port", "RTS_QUERIES_PORT", "--queries-port"),
+    ("control_port", "RTS_CONTROL_PORT", "--control-port"),
+    ("lights_port", "RTS_LIGHTS_PORT", "--lights-port"),
+];
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("invalid value '{}'", value.trim()))
+}
+
+fn positive<T: FromStr + PartialOrd + Default>(value: &str) -> Result<T, String> {
+    let parsed: T = parse(value)?;
+    if parsed <= T::default() {
+        return Err(format!("expected a positive value, got '{}'", value.trim()));
+    }
+    Ok(parsed)
+}
+
+/// A positive, finite rate or speed.
+fn positive_finite(value: &str) -> Result<f64, String> {
+    let parsed: f64 = positive(value)?;
+    if !parsed.is_finite() {
+        return Err(format!("expected a finite value, got '{}'", value.trim()));
+    }
+    Ok(parsed)
+}
+
+impl Config {
+    /// Loads the layers in order: defaults, the config file of `--config` or
+    /// RTS_CONFIG, the environment, then the flags in `args`.
+    pub fn load(args: &[String]) -> Config {
+        let mut config = Config::default();
+        let flag_value = |flag: &str| args.iter().position(|arg| arg == flag).map(|i| args.get(i + 1).cloned().unwrap_or_default());
+
+        let path = flag_value("--config").or_else(|| std::env::var("RTS_CONFIG").ok()).filter(|path| !path.is_empty());
+        if let Some(path) = path {
+            if let Err(e) = config.apply_file(&path) {
+                eprintln!("Ignoring config file {}: {}", path, e);
+            }
+        }
+        for (name, var, _) in SETTINGS {
+            if let Ok(value) = std::env::var(var) {
+                config.apply(name, &value, var);
+            }
+        }
+        for (name, _, flag) in SETTINGS {
+            if let Some(value) = flag_value(flag) {
+                config.apply(name, &value, flag);
+            }
+        }
+
+        if config.min_speed > config.max_speed {
+            eprintln!(
+                "Ignoring speed range {}..{}: min_speed is above max_speed",
+                config.min_speed, config.max_speed
+            );
+            (config.min_speed, config.max_speed) = DEFAULT_SPEEDS;
+        }
+        config
+    }
+
+    /// Sets the setting `name` to `value`, or warns about `source` and keeps
+    /// the current value if it is invalid.
+    fn apply(&mut self, name: &str, value: &str, source: &str) {
+        if let Err(e) = self.set(name, value) {
+            eprintln!("Ignoring {}: {}", source, e);
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "green_secs" => self.green_secs = positive(value)?,
+            "amber_secs" => self.amber_secs = parse(value)?,
+            "clearance_secs" => self.clearance_secs = parse(value)?,
+            "poll_ms" => self.poll_interval = Duration::from_millis(positive(value)?),
+            "cars" => self.demand.cars = positive(value)?,
+            "arrival_rate" => self.demand.arrivals = Arrivals::Continuous { per_minute: positive_finite(value)? },
+            "min_speed" => self.min_speed = positive_finite(value)?,
+            "max_speed" => self.max_speed = positive_finite(value)?,
+            "port_offset" => self.ports.offset = parse(value)?,
+            "logs_port" => self.ports.logs = positive(value)?,
+            "updates_port" => self.ports.updates = positive(value)?,
+            "recommendations_port" => self.ports.recommendations = positive(value)?,
+            "queries_port" => self.ports.queries = positive(value)?,
+            "control_port" => self.ports.control = positive(value)?,
+            "lights_port" => self.ports.lights = positive(value)?,
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Applies the settings of the JSON object in the file at `path`.
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let json: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let object = json.as_object().ok_or("expected an object of settings")?;
+        for (name, value) in object {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            self.apply(name, &value, &format!("{} in {}", name, path));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+// control.rs
+//
+// Shutdown across the CY processes. The simulation binds a PUB socket on the
+// control endpoint and publishes Control::Shutdown once its cars are done;
+// the traffic light controller, the flow analyzer and monitoring subscribe
+// and wind down when it arrives. Ctrl-C, which spawn-all forwards to every
+// process, stops them the same way, so no process is left running on its own
+// and spawn-all returns once the last one has exited.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rts_core::clock::SimClock;
+use rts_core::messages::Control;
+
+use crate::endpoints::Ports;
+use crate::envelope::{self, Feed};
+
+/// Raised once the process should stop.
+pub type StopFlag = Arc<AtomicBool>;
+
+/// A flag raised on Ctrl-C or when the simulation announces shutdown. Call it
+/// once per process: it installs the process's Ctrl-C handler.
+pub fn stop_flag(ports: &Ports) -> StopFlag {
+    let flag = Arc::new(AtomicBool::new(false));
+    let handler_flag = Arc::clone(&flag);
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst))
+        .expect("Failed to install Ctrl-C handler");
+    let control_flag = Arc::clone(&flag);
+    let endpoint = ports.control();
+    thread::spawn(move || {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::SUB).expect("Failed to create control SUB socket");
+        socket.connect(&endpoint.connect).expect("Failed to connect control socket");
+        socket.set_subscribe(b"").expect("Failed to subscribe to control messages");
+        loop {
+            let Ok(Ok(json_str)) = socket.recv_string(0) else { continue };
+            match envelope::open::<Control>(&json_str) {
+                Ok(Control::Shutdown { reason, .. }) => {
+                    println!("Shutting down: {}", reason);
+                    control_flag.store(true, Ordering::SeqCst);
+                    return;
+                }
+                Err(e) => eprintln!("Ignoring malformed control message {}: {}", json_str, e),
+            }
+        }
+    });
+    flag
+}
+
+pub fn is_stopped(flag: &StopFlag) -> bool {
+    flag.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `sim` of simulated time, looking at `flag` every `poll` and
+/// waking early once it is raised. Returns false if the sleep was cut short.
+pub fn sleep_or_stop(clock: &SimClock, sim: Duration, flag: &StopFlag, poll: Duration) -> bool {
+    let deadline = Instant::now() + clock.real_duration(sim);
+    loop {
+        if is_stopped(flag) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(poll.min(deadline - now));
+    }
+}
+
+/// The simulation's end of the control channel. Bind it when the run starts
+/// so the other processes are subscribed by the time it announces anything.
+pub struct Announcer {
+    socket: zmq::Socket,
+}
+
+impl Announcer {
+    pub fn bind(context: &zmq::Context, ports: &Ports) -> Announcer {
+        let socket = context.socket(zmq::PUB).expect("Failed to create control PUB socket");
+        socket.bind(&ports.control().bind).expect("Failed to bind control socket");
+        Announcer { socket }
+    }
+
+    /// Tells every subscribed process to stop.
+    pub fn shutdown(&self, reason: &str, clock: &SimClock) {
+        let message = Control::Shutdown { reason: reason.to_string(), timestamp: clock.now_secs() };
+        let json = envelope::seal(Feed::Control, &message);
+        if let Err(e) = self.socket.send(json.as_bytes(), 0) {
+            eprintln!("Failed to announce shutdown: {}", e);
+        }
+    }
+}
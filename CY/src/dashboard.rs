@@ -0,0 +1,177 @@
+// dashboard.rs
+//
+// Live terminal view of a run for `CY monitoring --dashboard`, in place of
+// the line-by-line event log. The monitor feeds every event to a Dashboard,
+// which keeps the current state built from the typed events: each
+// junction's active phase, the estimated position of every car on the grid
+// (see rts_core::progress), completed cars and their waits, and recent
+// warnings. Lane counts are not in the event stream, so the dashboard asks
+// the simulation for them over the query socket before each redraw. The
+// monitor redraws it every REFRESH by clearing the terminal with plain ANSI
+// escapes.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use crate::endpoints::Ports;
+use crate::query::{self, LaneQuery, LaneQueryResponse};
+use crate::system_monitoring::{EventKind, LogEvent};
+use rts_core::lanes::load_lanes;
+use rts_core::progress::PositionEstimator;
+
+/// How often the monitor redraws the dashboard, in real time.
+pub const REFRESH: Duration = Duration::from_secs(1);
+
+/// Clears the terminal and moves the cursor to the top left.
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Lanes shown in the occupancy table.
+const TOP_LANES: usize = 10;
+
+/// Cars shown in the position table.
+const SHOWN_CARS: usize = 10;
+
+/// Warnings kept for display, newest last.
+const RECENT_WARNINGS: usize = 5;
+
+/// How long a redraw waits for the simulation's lane counts.
+const LANE_QUERY_TIMEOUT_MS: i32 = 200;
+
+/// True if `--dashboard` is among the command-line arguments.
+pub fn requested(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--dashboard")
+}
+
+pub struct Dashboard {
+    /// Active phase and green lanes of every junction that has changed phase.
+    junctions: BTreeMap<u32, (usize, Vec<u32>)>,
+    /// Latest lane counts from the simulation; None if it did not answer.
+    lanes: Option<HashMap<u32, u32>>,
+    /// Where the cars on the grid are.
+    positions: PositionEstimator,
+    completed: u32,
+    total_wait: f64,
+    warnings: VecDeque<String>,
+    /// Timestamp of the latest event.
+    now: u64,
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Dashboard {
+            junctions: BTreeMap::new(),
+            lanes: None,
+            positions: PositionEstimator::new(&load_lanes()),
+            completed: 0,
+            total_wait: 0.0,
+            warnings: VecDeque::new(),
+            now: 0,
+        }
+    }
+}
+
+impl Dashboard {
+    pub fn observe(&mut self, event: &LogEvent) {
+        self.now = self.now.max(event.timestamp);
+        match &event.kind {
+            EventKind::VehicleGenerated { car_id, speed, .. } => {
+                self.positions.generated(*car_id, *speed);
+            }
+            EventKind::CarProgress { car_id, lane_id, transition, route_index } => {
+                self.positions.progress(*car_id, *lane_id, *transition, *route_index, event.timestamp);
+            }
+            EventKind::PhaseChange { junction, phase, green_lanes, .. } => {
+                self.junctions.insert(*junction, (*phase, green_lanes.clone()));
+            }
+            EventKind::CarCompleted { car_id, wait_time, .. } => {
+                self.positions.forget(*car_id);
+                self.completed += 1;
+                self.total_wait += wait_time;
+            }
+            EventKind::ComponentDown { .. }
+            | EventKind::VehicleGenerationFailed { .. }
+            | EventKind::JunctionControllerFailed { .. } => {
+                if self.warnings.len() == RECENT_WARNINGS {
+                    self.warnings.pop_front();
+                }
+                self.warnings.push_back(format!("[{}] {}: {}", event.timestamp, event.source, event.message));
+            }
+            _ => {}
+        }
+    }
+
+    /// Refreshes the lane counts from the running simulation.
+    pub fn poll_lanes(&mut self, context: &zmq::Context, ports: &Ports) {
+        self.lanes = query::ask(context, ports, &LaneQuery::All, LANE_QUERY_TIMEOUT_MS)
+            .ok()
+            .and_then(|reply| match serde_json::from_slice(&reply) {
+                Ok(LaneQueryResponse::All { lanes }) => Some(lanes),
+                _ => None,
+            });
+    }
+}
+
+impl fmt::Display for Dashboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "=== Traffic Dashboard [Time: {}] ===", self.now)?;
+        let mean_wait = if self.completed > 0 { self.total_wait / self.completed as f64 } else { 0.0 };
+        writeln!(f, "Cars completed: {}   Mean wait: {:.2}s   On the grid: {}",
+                 self.completed, mean_wait, self.positions.len())?;
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>5}  Green lanes", "Junction", "Phase")?;
+        if self.junctions.is_empty() {
+            writeln!(f, "  no phase changes yet")?;
+        }
+        for (junction, (phase, green_lanes)) in &self.junctions {
+            let green: Vec<String> = green_lanes.iter().map(u32::to_string).collect();
+            writeln!(f, "{:>8}  {:>5}  {}", junction, phase, green.join(", "))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  Vehicles", "Lane")?;
+        match &self.lanes {
+            None => writeln!(f, "  lane counts unavailable (is the simulation running?)")?,
+            Some(lanes) => {
+                // Busiest first, ties by lane id.
+                let mut busiest: Vec<(u32, u32)> =
+                    lanes.iter().filter(|(_, &count)| count > 0).map(|(&lane, &count)| (lane, count)).collect();
+                busiest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                if busiest.is_empty() {
+                    writeln!(f, "  no vehicles on the grid")?;
+                }
+                for (lane_id, count) in busiest.into_iter().take(TOP_LANES) {
+                    writeln!(f, "{:>8}  {}", lane_id, count)?;
+                }
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>8}  {:>4}  Estimated position", "Car", "Lane", "Leg")?;
+        let positions = self.positions.positions(self.now);
+        if positions.is_empty() {
+            writeln!(f, "  no vehicles on the grid")?;
+        }
+        for position in positions.iter().take(SHOWN_CARS) {
+            let along = match position.distance {
+                Some(distance) => format!("{:.0} of {:.0} m", distance, position.lane_length),
+                None => "unknown speed".to_string(),
+            };
+            writeln!(f, "{:>8}  {:>8}  {:>4}  {}", position.car_id, position.lane_id, position.route_index, along)?;
+        }
+        if positions.len() > SHOWN_CARS {
+            writeln!(f, "  and {} more", positions.len() - SHOWN_CARS)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Recent warnings")?;
+        if self.warnings.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "  {}", warning)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// endpoints.rs
+//
+// ZeroMQ endpoints shared by the CY processes. Each channel has one bound
+// side and any number of connecting sides:
+//
+//   logs             monitoring binds PULL,      everyone else connects PUSH
+//   updates          simulation binds PUSH,      flow analyzer connects PULL
+//   recommendations  flow analyzer binds PUSH,   traffic lights connect PULL
+//   queries          simulation binds REP,       `CY query` connects REQ
+//   control          simulation binds PUB,       everyone else connects SUB
+//   lights           traffic lights bind PUB,    simulation connects SUB
+//
+// The ports come from the run's `Config`: each can be set on its own (e.g.
+// logs_port), and port_offset shifts all of them at once, which is enough to
+// run a second simulation next to the first one. Every address can still be
+// overridden whole through the environment, e.g.
+// RTS_UPDATES_BIND=tcp://*:7101 and RTS_UPDATES_CONNECT=tcp://localhost:7101.
+
+use std::env;
+
+pub struct Endpoint {
+    pub bind: String,
+    pub connect: String,
+}
+
+/// Ports of the channels, before `offset` is added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub logs: u16,
+    pub updates: u16,
+    pub recommendations: u16,
+    pub queries: u16,
+    pub control: u16,
+    pub lights: u16,
+    /// Added to every port.
+    pub offset: u16,
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports { logs: 7000, updates: 7001, recommendations: 7002, queries: 7005, control: 7006, lights: 7007, offset: 0 }
+    }
+}
+
+impl Ports {
+    fn endpoint(&self, name: &str, port: u16) -> Endpoint {
+        let port = port.saturating_add(self.offset);
+        Endpoint {
+            bind: env::var(format!("RTS_{}_BIND", name)).unwrap_or_else(|_| format!("tcp://*:{}", port)),
+            connect: env::var(format!("RTS_{}_CONNECT", name)).unwrap_or_else(|_| format!("tcp://localhost:{}", port)),
+        }
+    }
+
+    pub fn logs(&self) -> Endpoint {
+        self.endpoint("LOGS", self.logs)
+    }
+
+    pub fn updates(&self) -> Endpoint {
+        self.endpoint("UPDATES", self.updates)
+    }
+
+    pub fn recommendations(&self) -> Endpoint {
+        self.endpoint("RECOMMENDATIONS", self.recommendations)
+    }
+
+    pub fn queries(&self) -> Endpoint {
+        self.endpoint("QUERIES", self.queries)
+    }
+
+    pub fn control(&self) -> Endpoint {
+        self.endpoint("CONTROL", self.control)
+    }
+
+    pub fn lights(&self) -> Endpoint {
+        self.endpoint("LIGHTS", self.lights)
+    }
+}
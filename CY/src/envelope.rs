@@ -0,0 +1,55 @@
+// envelope.rs
+//
+// Envelopes of the CY processes (see `rts_core::messages::Envelope`). Every
+// message sent on the logs, updates, recommendations, control and lights
+// channels is sealed by `seal`, which names the process's component and
+// numbers the messages of each channel from 1, and receivers `open` it
+// again. A message without an envelope, from a process that predates them,
+// is taken as it is; one from a newer schema version is refused, so the
+// receiver skips it instead of misreading it.
+//
+// Sequence numbers are per process, but a process may send on a channel from
+// several sockets (every car thread has its own log socket), and ZeroMQ
+// interleaves those at the receiver, so only one socket's messages arrive in
+// order. Queries are request and reply, and go without envelopes.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use rts_core::messages::{self, Envelope, EnvelopeError};
+
+/// The channels whose messages are sealed; see `endpoints`.
+#[derive(Debug, Clone, Copy)]
+pub enum Feed {
+    Logs,
+    Updates,
+    Recommendations,
+    Control,
+    Lights,
+}
+
+/// Number of the last message sealed for each Feed.
+static SEQUENCES: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+
+/// The process's component, e.g. `simulation`.
+static SOURCE: OnceLock<String> = OnceLock::new();
+
+/// Names the process's component in every envelope it seals from now on.
+pub fn set_source(component: &str) {
+    SOURCE.set(component.to_string()).ok();
+}
+
+/// `payload` sealed in the next envelope of `feed`, as JSON.
+pub fn seal<T: Serialize>(feed: Feed, payload: &T) -> String {
+    let sequence = SEQUENCES[feed as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    let source = SOURCE.get().map_or("", String::as_str);
+    serde_json::to_string(&Envelope::new(source, sequence, payload)).unwrap()
+}
+
+/// The message in a received envelope.
+pub fn open<T: DeserializeOwned>(json: &str) -> Result<T, EnvelopeError> {
+    messages::open(json.as_bytes()).map(|envelope| envelope.payload)
+}
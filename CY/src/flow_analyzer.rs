@@ -0,0 +1,285 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use rts_core::clock::SimClock;
+use crate::simulation::LaneSnapshot;
+use crate::config::Config;
+use crate::envelope::{self, Feed};
+use crate::heartbeat;
+use crate::control;
+use rts_core::messages::Recommendation;
+
+/// Length of the sliding window used to average each lane's vehicle count.
+const WINDOW_SECS: u64 = 60;
+/// Rolling average at or above which a lane is considered congested.
+const CONGESTION_THRESHOLD: f64 = 4.0;
+/// Fraction of the threshold the rolling average must drop below before a
+/// congested lane counts as clear again, so a lane hovering around the
+/// threshold does not flap in and out of congestion.
+const CLEAR_RATIO: f64 = 0.75;
+/// How long the rolling average must stay at or above the threshold before a
+/// lane counts as congested.
+const SUSTAIN_SECS: u64 = 15;
+/// Trend, in vehicles per minute, below which a lane is draining fast enough
+/// that it is left to clear on its own.
+const DRAINING_TREND: f64 = -2.0;
+/// Minimum time between two recommendations for the same lane.
+const COOLDOWN_SECS: u64 = 60;
+/// Green time recommended for a lane right at the threshold.
+const BASE_GREEN_TIME: u32 = 10;
+/// Extra green seconds per vehicle the average sits above the threshold.
+const GREEN_SECS_PER_EXCESS_VEHICLE: f64 = 5.0;
+/// Upper bound on any recommended green time.
+const MAX_GREEN_TIME: u32 = 60;
+
+/// Where a lane stands between sustained congestion and clear.
+#[derive(Debug, Default)]
+struct LaneCongestion {
+    /// When the rolling average last rose to the threshold, while it stays there.
+    above_since: Option<u64>,
+    /// Sustained congestion detected and not yet cleared.
+    congested: bool,
+    last_recommended: Option<u64>,
+}
+
+/// Tracks a sliding window of vehicle counts per lane and decides when a lane
+/// has been congested long enough to warrant a recommendation. A lane becomes
+/// congested once its rolling average has stayed at or above the threshold
+/// for SUSTAIN_SECS without draining, and stays congested until the average
+/// falls below CLEAR_RATIO of the threshold. A congested lane gets at most
+/// one recommendation per cooldown.
+pub struct CongestionDetector {
+    window_secs: u64,
+    threshold: f64,
+    cooldown_secs: u64,
+    /// (timestamp, vehicle count, milliseconds the sample stands for)
+    samples: HashMap<u32, VecDeque<(u64, u32, u64)>>,
+    lanes: HashMap<u32, LaneCongestion>,
+}
+
+impl CongestionDetector {
+    pub fn new(window_secs: u64, threshold: f64, cooldown_secs: u64) -> Self {
+        CongestionDetector {
+            window_secs,
+            threshold,
+            cooldown_secs,
+            samples: HashMap::new(),
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// Records a vehicle count for a lane and drops samples older than the window.
+    /// `weight_ms` is how long the count stands for, i.e. the publisher's interval
+    /// until its next snapshot, so bursts of fast snapshots don't skew the average.
+    pub fn record(&mut self, lane_id: u32, vehicle_count: u32, weight_ms: u64, now: u64) {
+        let window = self.samples.entry(lane_id).or_default();
+        window.push_back((now, vehicle_count, weight_ms.max(1)));
+        while let Some(&(ts, _, _)) = window.front() {
+            if ts + self.window_secs <= now {
+                window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Time-weighted average of the samples currently in the lane's window.
+    pub fn average(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        if window.is_empty() {
+            return None;
+        }
+        let weighted: f64 = window.iter().map(|&(_, count, weight)| count as f64 * weight as f64).sum();
+        let total_weight: u64 = window.iter().map(|&(_, _, weight)| weight).sum();
+        Some(weighted / total_weight as f64)
+    }
+
+    /// Least-squares slope of the lane's counts over its window, in vehicles
+    /// per minute: positive while the queue builds, negative while it drains.
+    pub fn trend(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        let n = window.len() as f64;
+        let mean_t = window.iter().map(|&(ts, _, _)| ts as f64).sum::<f64>() / n;
+        let mean_c = window.iter().map(|&(_, count, _)| count as f64).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for &(ts, count, _) in window {
+            let dt = ts as f64 - mean_t;
+            covariance += dt * (count as f64 - mean_c);
+            variance += dt * dt;
+        }
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance * 60.0)
+    }
+
+    /// Returns the green time to recommend for the lane, if it is in sustained
+    /// congestion (see `CongestionDetector`), not draining and not cooling down.
+    pub fn evaluate(&mut self, lane_id: u32, now: u64) -> Option<u32> {
+        let average = self.average(lane_id)?;
+        let trend = self.trend(lane_id).unwrap_or(0.0);
+        let lane = self.lanes.entry(lane_id).or_default();
+        if average < self.threshold {
+            lane.above_since = None;
+        } else if lane.above_since.is_none() {
+            lane.above_since = Some(now);
+        }
+        if lane.congested {
+            if average < self.threshold * CLEAR_RATIO {
+                lane.congested = false;
+            }
+        } else if lane.above_since.is_some_and(|since| now >= since + SUSTAIN_SECS) && trend > DRAINING_TREND {
+            lane.congested = true;
+        }
+        if !lane.congested || trend <= DRAINING_TREND {
+            return None;
+        }
+        if lane.last_recommended.is_some_and(|last| now < last + self.cooldown_secs) {
+            return None;
+        }
+        lane.last_recommended = Some(now);
+        Some(green_time_for(average, self.threshold))
+    }
+}
+
+/// Scales the recommended green time with how far the average exceeds the threshold.
+fn green_time_for(average: f64, threshold: f64) -> u32 {
+    let excess = (average - threshold).max(0.0);
+    let green = BASE_GREEN_TIME as f64 + excess * GREEN_SECS_PER_EXCESS_VEHICLE;
+    (green.round() as u32).min(MAX_GREEN_TIME)
+}
+
+/// Pulls the next lane-count update from the simulation; None if none came
+/// before the socket's receive timeout or it could not be read.
+fn next_update(updates: &zmq::Socket) -> Option<LaneSnapshot> {
+    let json_str = match updates.recv_string(0) {
+        Ok(Ok(json_str)) => json_str,
+        Ok(Err(e)) => {
+            eprintln!("Received non-UTF8 simulation update: {:?}", e);
+            return None;
+        }
+        Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => return None,
+        Err(e) => {
+            eprintln!("Socket error: {:?}", e);
+            return None;
+        }
+    };
+    match envelope::open(&json_str) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            eprintln!("Failed to deserialize simulation update {}: {}", json_str, e);
+            None
+        }
+    }
+}
+
+/// Brings the latest count of every lane up to date: a full update replaces
+/// them, any other carries only the lanes that changed.
+fn apply_update(counts: &mut HashMap<u32, u32>, snapshot: LaneSnapshot) {
+    if snapshot.full {
+        *counts = snapshot.lanes;
+    } else {
+        counts.extend(snapshot.lanes);
+    }
+}
+
+/// Runs the flow analyzer: pulls lane-count snapshots from the simulation and
+/// pushes recommendations to the traffic light controller, until the
+/// simulation announces shutdown or the process is interrupted. Windows and
+/// cooldowns run on `clock`'s simulated time.
+pub fn run_flow_analyzer(config: Config, clock: SimClock) {
+    let stop = control::stop_flag(&config.ports);
+    let context = zmq::Context::new();
+    let update_endpoint = config.ports.updates();
+    let updates = context.socket(zmq::PULL).expect("Failed to create simulation update PULL socket");
+    updates.connect(&update_endpoint.connect).expect("Failed to connect simulation update socket");
+    // Wake up periodically so a stop is noticed even when no updates arrive.
+    updates.set_rcvtimeo(config.poll_interval.as_millis() as i32).expect("Failed to set receive timeout");
+
+    let rec_socket = context.socket(zmq::PUSH).expect("Failed to create recommendation PUSH socket");
+    rec_socket.bind(&config.ports.recommendations().bind).expect("Failed to bind recommendation socket");
+
+    let log_socket = context.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+    log_socket.connect(&config.ports.logs().connect).expect("Failed to connect log socket");
+    heartbeat::start("analyzer", clock, &config.ports);
+
+    println!("Flow Analyzer waiting for simulation updates on {}", update_endpoint.connect);
+
+    let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+    // Latest count of every lane; updates that are not full carry only the
+    // lanes that changed.
+    let mut counts: HashMap<u32, u32> = HashMap::new();
+    while !control::is_stopped(&stop) {
+        let Some(snapshot) = next_update(&updates) else {
+            continue;
+        };
+        let interval_ms = snapshot.interval_ms;
+        apply_update(&mut counts, snapshot);
+
+        let now = clock.now_secs();
+        for (&lane_id, &vehicle_count) in &counts {
+            detector.record(lane_id, vehicle_count, interval_ms, now);
+            if let Some(new_green_time) = detector.evaluate(lane_id, now) {
+                let rec = Recommendation { lane_id, new_green_time, timestamp: now };
+                let rec_json = envelope::seal(Feed::Recommendations, &rec);
+                rec_socket.send(rec_json.as_bytes(), 0).expect("Failed to send recommendation");
+
+                let log_event = LogEvent {
+                    source: "FlowAnalyzer".to_string(),
+                    message: format!("Published recommendation for lane {} (avg {:.1} vehicles over {}s, trend {:+.1}/min, green {}s)",
+                                     lane_id, detector.average(lane_id).unwrap_or(0.0), WINDOW_SECS,
+                                     detector.trend(lane_id).unwrap_or(0.0), new_green_time),
+                    timestamp: now,
+                    level: Level::Info,
+                    kind: EventKind::Generic,
+                };
+                let log_json = envelope::seal(Feed::Logs, &log_event);
+                log_socket.send(log_json.as_bytes(), 0).expect("Failed to send log event");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use crate::endpoints::Ports;
+    use crate::simulation::{lane_totals, publish_counts};
+
+    #[test]
+    fn analyzer_receives_lane_counts_over_inproc() {
+        // The updates channel moved in-process through its address overrides.
+        env::set_var("RTS_UPDATES_BIND", "inproc://rts-updates-test");
+        env::set_var("RTS_UPDATES_CONNECT", "inproc://rts-updates-test");
+        let endpoint = Ports::default().updates();
+        assert_eq!(endpoint.bind, "inproc://rts-updates-test");
+
+        let context = zmq::Context::new();
+        let sim_socket = context.socket(zmq::PUSH).unwrap();
+        sim_socket.bind(&endpoint.bind).unwrap();
+        let updates = context.socket(zmq::PULL).unwrap();
+        updates.connect(&endpoint.connect).unwrap();
+        updates.set_rcvtimeo(1000).unwrap();
+
+        // Per-parallel-lane counts as the simulation keeps them.
+        let road = HashMap::from([(101, vec![2, 3]), (102, vec![4]), (103, vec![0, 0, 1])]);
+        let lanes = lane_totals(&road);
+        publish_counts(&sim_socket, &LaneSnapshot { lanes: lanes.clone(), interval_ms: 500, full: true }).unwrap();
+        publish_counts(&sim_socket, &LaneSnapshot { lanes: HashMap::from([(102, 7)]), interval_ms: 250, full: false }).unwrap();
+
+        let mut counts = HashMap::new();
+        let first = next_update(&updates).expect("no full update");
+        assert_eq!((first.interval_ms, first.full), (500, true));
+        apply_update(&mut counts, first);
+        assert_eq!(counts, HashMap::from([(101, 5), (102, 4), (103, 1)]));
+        let second = next_update(&updates).expect("no partial update");
+        assert_eq!((second.interval_ms, second.full), (250, false));
+        apply_update(&mut counts, second);
+        assert_eq!(counts, HashMap::from([(101, 5), (102, 7), (103, 1)]));
+
+        // Nothing else was sent; the analyzer times out instead of blocking.
+        assert!(next_update(&updates).is_none());
+    }
+}
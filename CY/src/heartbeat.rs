@@ -0,0 +1,175 @@
+// heartbeat.rs
+//
+// Liveness of the CY processes. The simulation, traffic light and analyzer
+// processes each send a Heartbeat event over the log socket every
+// HEARTBEAT_INTERVAL, and monitoring reports a component as down once it has
+// been silent for MISSED_HEARTBEATS intervals. Intervals are real time: they
+// measure whether a process is alive, not how fast the simulation runs.
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rts_core::clock::SimClock;
+
+use crate::endpoints::Ports;
+use crate::envelope::{self, Feed};
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Consecutive intervals a component may miss before it counts as down.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// Starts a thread that sends `component`'s heartbeat until the process exits.
+/// zmq sockets can't be shared between threads, so it connects its own.
+pub fn start(component: &'static str, clock: SimClock, ports: &Ports) {
+    let endpoint = ports.logs();
+    thread::spawn(move || {
+        let ctx = zmq::Context::new();
+        let log_socket = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+        log_socket.connect(&endpoint.connect).expect("Failed to connect log socket");
+        loop {
+            let log_event = LogEvent {
+                source: component.to_string(),
+                message: String::new(),
+                timestamp: clock.now_secs(),
+                level: Level::Info,
+                kind: EventKind::Heartbeat { component: component.to_string() },
+            };
+            let log_json = envelope::seal(Feed::Logs, &log_event);
+            if let Err(e) = log_socket.send(log_json.as_bytes(), 0) {
+                eprintln!("Failed to send heartbeat: {}", e);
+            }
+            thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
+}
+
+struct ComponentHealth {
+    last_seen: Instant,
+    /// Start of the current run of heartbeats.
+    up_since: Instant,
+    /// Uptime of earlier runs, before the component last went down.
+    earlier_uptime: Duration,
+    down: bool,
+}
+
+/// Last-seen times of every component that has sent a heartbeat.
+pub struct HealthTracker {
+    timeout: Duration,
+    components: BTreeMap<String, ComponentHealth>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        HealthTracker::new(HEARTBEAT_INTERVAL * MISSED_HEARTBEATS)
+    }
+}
+
+impl HealthTracker {
+    /// A tracker that reports components silent for longer than `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        HealthTracker { timeout, components: BTreeMap::new() }
+    }
+
+    /// Records a heartbeat. Returns true if the component was down and is back.
+    pub fn beat(&mut self, component: &str, now: Instant) -> bool {
+        match self.components.get_mut(component) {
+            Some(health) => {
+                health.last_seen = now;
+                if health.down {
+                    health.down = false;
+                    health.up_since = now;
+                    return true;
+                }
+                false
+            }
+            None => {
+                self.components.insert(component.to_string(), ComponentHealth {
+                    last_seen: now,
+                    up_since: now,
+                    earlier_uptime: Duration::ZERO,
+                    down: false,
+                });
+                false
+            }
+        }
+    }
+
+    /// Marks components that have been silent too long as down. Returns each
+    /// newly down component with how long it has been silent.
+    pub fn check(&mut self, now: Instant) -> Vec<(String, Duration)> {
+        let mut down = Vec::new();
+        for (component, health) in self.components.iter_mut() {
+            let silent = now.saturating_duration_since(health.last_seen);
+            if !health.down && silent > self.timeout {
+                health.down = true;
+                health.earlier_uptime += health.last_seen - health.up_since;
+                down.push((component.clone(), silent));
+            }
+        }
+        down
+    }
+
+    /// Each component's total uptime at `now`, and whether it is down.
+    pub fn uptimes(&self, now: Instant) -> Vec<(String, Duration, bool)> {
+        self.components
+            .iter()
+            .map(|(component, health)| {
+                let current = if health.down {
+                    Duration::ZERO
+                } else {
+                    now.saturating_duration_since(health.up_since)
+                };
+                (component.clone(), health.earlier_uptime + current, health.down)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopped_heartbeats_mark_a_component_down_once() {
+        let start = Instant::now();
+        let mut tracker = HealthTracker::default();
+        let timeout = HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        // Both beat every interval; the traffic lights stop after the third.
+        for beat in 0..3 {
+            let now = start + HEARTBEAT_INTERVAL * beat;
+            tracker.beat("simulation", now);
+            tracker.beat("traffic_light", now);
+            assert_eq!(tracker.check(now), []);
+        }
+        let last_seen = start + HEARTBEAT_INTERVAL * 2;
+        for beat in 3..=6 {
+            tracker.beat("simulation", start + HEARTBEAT_INTERVAL * beat);
+        }
+        // Exactly MISSED_HEARTBEATS intervals of silence is still up.
+        assert_eq!(tracker.check(last_seen + timeout), []);
+        let now = last_seen + timeout + Duration::from_millis(1);
+        assert_eq!(tracker.check(now), [("traffic_light".to_string(), timeout + Duration::from_millis(1))]);
+        // Reported once, not on every check.
+        assert_eq!(tracker.check(now + HEARTBEAT_INTERVAL), []);
+    }
+
+    #[test]
+    fn returning_component_is_up_again_and_keeps_its_uptime() {
+        let start = Instant::now();
+        let mut tracker = HealthTracker::new(Duration::from_secs(5));
+        tracker.beat("analyzer", start);
+        assert!(!tracker.beat("analyzer", start + Duration::from_secs(4)));
+        let down_at = start + Duration::from_secs(10);
+        assert_eq!(tracker.check(down_at).len(), 1);
+        assert_eq!(tracker.uptimes(down_at), [("analyzer".to_string(), Duration::from_secs(4), true)]);
+
+        let back = start + Duration::from_secs(20);
+        assert!(tracker.beat("analyzer", back));
+        let later = back + Duration::from_secs(3);
+        assert_eq!(tracker.check(later), []);
+        assert_eq!(tracker.uptimes(later), [("analyzer".to_string(), Duration::from_secs(7), false)]);
+    }
+}
@@ -1,29 +1,59 @@
 use std::env;
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rts_core::clock::SimClock;
 
 mod simulation;
 mod traffic_light;
 mod system_monitoring;
-mod lanes;
 mod flow_analyzer;
+mod csv_sink;
+mod query;
+mod endpoints;
+mod summary;
+mod heartbeat;
+mod dashboard;
+mod control;
+mod config;
+mod envelope;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
+    // Leading flags (e.g. `--csv out.csv`, `--cars 50`, `--config cy.json`
+    // or `--speedup 20`) select spawn-all mode and are forwarded to every
+    // process, which each load the same `config::Config`.
+    if args.len() > 1 && !args[1].starts_with("--") {
+        let config = config::Config::load(&args[2..]);
+        // Names the component in the envelope of everything it sends.
+        envelope::set_source(&args[1]);
         match args[1].as_str() {
             "simulation" => {
+                // All red until the traffic light process's first update.
                 let traffic_lights = traffic_light::initialize_traffic_lights();
-                simulation::run_simulation(traffic_lights);
+                // `--export <path>` writes the cars' journeys and the lane
+                // counts when the run ends (see `rts_core::export`).
+                let export = rts_core::export::Export::from_args(&args[2..]);
+                simulation::run_simulation(traffic_lights, config, export, SimClock::from_env());
             },
             "traffic_light" => {
-                let traffic_lights = traffic_light::initialize_traffic_lights();
-                traffic_light::run_traffic_lights(traffic_lights);
+                let signals = traffic_light::Signals {
+                    lights: traffic_light::initialize_traffic_lights(),
+                    queues: traffic_light::LaneQueueMap::default(),
+                };
+                traffic_light::run_traffic_lights(signals, config, SimClock::from_env());
             },
             "analyzer" => {
-                flow_analyzer::run_flow_analyzer();
+                flow_analyzer::run_flow_analyzer(config, SimClock::from_env());
+            },
+            "query" => {
+                query::run_query_client(&args[2..], &config.ports);
             },
             "monitoring" => {
-                system_monitoring::run_monitoring();
+                system_monitoring::run_monitoring(&args[2..], config);
             },
             _ => {
                 eprintln!("Unknown component: {}", args[1]);
@@ -36,16 +66,49 @@ fn main() {
         let mut children = Vec::new();
         
         for comp in &components {
-            let child = Command::new(&current_exe)
-                .arg(comp)
+            let mut command = Command::new(&current_exe);
+            command.arg(comp);
+            command.args(&args[1..]);
+            let child = command
                 .spawn()
-                .expect(&format!("Failed to spawn {} process", comp));
+                .unwrap_or_else(|e| panic!("Failed to spawn {} process: {}", comp, e));
             println!("Spawned {} process", comp);
             children.push(child);
         }
         
+        // Forward Ctrl-C to the components instead of leaving them orphaned.
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let handler_flag = Arc::clone(&interrupted);
+        ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst))
+            .expect("Failed to install Ctrl-C handler");
+
+        while !children.is_empty() {
+            if interrupted.load(Ordering::SeqCst) {
+                println!("Interrupted; stopping {} component(s)", children.len());
+                for child in &mut children {
+                    interrupt_child(child);
+                }
+                break;
+            }
+            children.retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+            thread::sleep(Duration::from_millis(100));
+        }
+
         for mut child in children {
             child.wait().expect("Child process encountered an error");
         }
     }
 }
+
+/// Asks a component process to stop the same way a terminal Ctrl-C would.
+#[cfg(unix)]
+fn interrupt_child(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+}
+
+#[cfg(not(unix))]
+fn interrupt_child(child: &mut Child) {
+    child.kill().ok();
+}
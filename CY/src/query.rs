@@ -0,0 +1,155 @@
+// query.rs
+//
+// Request/reply lane occupancy queries against a running simulation.
+// The simulation process serves a REP socket on the queries endpoint;
+// `CY query` sends one request and prints the answer; the monitoring
+// dashboard polls it for lane counts.
+//
+// Requests and responses are JSON, e.g.
+//   {"query":"lane","lane_id":1023} -> {"lane":{"lane_id":1023,"vehicle_count":2,"sub_lanes":[2]}}
+//   {"query":"all"}                 -> {"all":{"lanes":{...}}}
+// Unknown lanes and malformed requests get {"error":{"message":...}}.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use crate::simulation::{lane_totals, SimEvent};
+use crate::endpoints::Ports;
+
+/// How long the client waits for the simulation to answer.
+const REPLY_TIMEOUT_MS: i32 = 2000;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "query", rename_all = "lowercase")]
+pub enum LaneQuery {
+    Lane { lane_id: u32 },
+    All,
+}
+
+// Externally tagged: serde cannot read the integer keys of `lanes` back
+// through an internally tagged enum.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LaneQueryResponse {
+    /// `vehicle_count` is the sum of `sub_lanes`, the count on each
+    /// parallel lane.
+    Lane {
+        lane_id: u32,
+        vehicle_count: u32,
+        #[serde(default)]
+        sub_lanes: Vec<u32>,
+    },
+    All { lanes: HashMap<u32, u32> },
+    Error { message: String },
+}
+
+fn answer(query: LaneQuery, sim_event: &SimEvent) -> LaneQueryResponse {
+    let lanes = match sim_event.lock() {
+        Ok(lanes) => lanes,
+        Err(_) => return LaneQueryResponse::Error { message: "simulation state unavailable".to_string() },
+    };
+    match query {
+        LaneQuery::Lane { lane_id } => match lanes.get(&lane_id) {
+            Some(sub_lanes) => LaneQueryResponse::Lane {
+                lane_id,
+                vehicle_count: sub_lanes.iter().sum(),
+                sub_lanes: sub_lanes.clone(),
+            },
+            None => LaneQueryResponse::Error { message: format!("unknown lane id {}", lane_id) },
+        },
+        LaneQuery::All => LaneQueryResponse::All { lanes: lane_totals(&lanes) },
+    }
+}
+
+/// Serves lane queries from the simulation's SimEvent until the process exits.
+pub fn serve_lane_queries(ctx: Arc<zmq::Context>, sim_event: SimEvent, ports: Ports) {
+    let socket = ctx.socket(zmq::REP).expect("Failed to create query REP socket");
+    let endpoint = ports.queries();
+    socket.bind(&endpoint.bind).expect("Failed to bind query socket");
+    println!("Serving lane queries on {}", endpoint.bind);
+
+    loop {
+        let request = match socket.recv_bytes(0) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Query socket error: {:?}", e);
+                continue;
+            }
+        };
+        let response = match serde_json::from_slice::<LaneQuery>(&request) {
+            Ok(query) => answer(query, &sim_event),
+            Err(e) => LaneQueryResponse::Error { message: format!("malformed query: {}", e) },
+        };
+        let reply = serde_json::to_string(&response).unwrap();
+        // A REP socket must reply before it can receive again.
+        if let Err(e) = socket.send(reply.as_bytes(), 0) {
+            eprintln!("Failed to send query reply: {:?}", e);
+        }
+    }
+}
+
+/// Sends one query to the running simulation and returns the raw reply, or
+/// EAGAIN if it does not answer within `timeout_ms`. Each call uses a fresh
+/// REQ socket, so a missed reply never leaves a socket stuck mid-exchange.
+pub fn ask(context: &zmq::Context, ports: &Ports, query: &LaneQuery, timeout_ms: i32) -> Result<Vec<u8>, zmq::Error> {
+    let socket = context.socket(zmq::REQ)?;
+    socket.set_rcvtimeo(timeout_ms)?;
+    socket.set_linger(0)?;
+    socket.connect(&ports.queries().connect)?;
+    socket.send(serde_json::to_string(query).unwrap().as_bytes(), 0)?;
+    socket.recv_bytes(0)
+}
+
+/// `CY query lane <id>` or `CY query all`: asks the running simulation and
+/// prints the answer.
+pub fn run_query_client(args: &[String], ports: &Ports) {
+    let query = match args.first().map(String::as_str) {
+        Some("lane") => match args.get(1).and_then(|id| id.parse().ok()) {
+            Some(lane_id) => LaneQuery::Lane { lane_id },
+            None => {
+                eprintln!("usage: CY query lane <lane_id>");
+                return;
+            }
+        },
+        Some("all") => LaneQuery::All,
+        _ => {
+            eprintln!("usage: CY query (lane <lane_id> | all)");
+            return;
+        }
+    };
+
+    let endpoint = ports.queries();
+    let context = zmq::Context::new();
+    let reply = match ask(&context, ports, &query, REPLY_TIMEOUT_MS) {
+        Ok(reply) => reply,
+        Err(zmq::Error::EAGAIN) => {
+            eprintln!("No answer from the simulation on {} (is it running?)", endpoint.connect);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Query failed: {:?}", e);
+            return;
+        }
+    };
+
+    match serde_json::from_slice::<LaneQueryResponse>(&reply) {
+        Ok(LaneQueryResponse::Lane { lane_id, vehicle_count, sub_lanes }) => {
+            if sub_lanes.len() > 1 {
+                println!("Lane {}: {} vehicle(s), {:?} per parallel lane", lane_id, vehicle_count, sub_lanes);
+            } else {
+                println!("Lane {}: {} vehicle(s)", lane_id, vehicle_count);
+            }
+        }
+        Ok(LaneQueryResponse::All { lanes }) => {
+            let mut lanes: Vec<(u32, u32)> = lanes.into_iter().collect();
+            lanes.sort();
+            for (lane_id, vehicle_count) in lanes {
+                println!("Lane {}: {} vehicle(s)", lane_id, vehicle_count);
+            }
+        }
+        Ok(LaneQueryResponse::Error { message }) => eprintln!("Error: {}", message),
+        Err(_) => eprintln!("Unexpected reply: {}", String::from_utf8_lossy(&reply)),
+    }
+}
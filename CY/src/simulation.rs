@@ -1,319 +1,808 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use rand::Rng;
-use std::collections::{HashMap, BinaryHeap};
-use std::cmp::Ordering;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
-use zmq;
 
-use crate::traffic_light::{TrafficLightMap, can_proceed_lane};
-use crate::lanes::{load_lanes, Lane, LaneCategory};
+use crate::traffic_light::{lock, LaneQueueMap, Signals, TrafficLightMap, can_proceed_lane};
+use rts_core::lanes::{load_lanes, Lane, LaneCategory, Movement};
+use rts_core::phase_plan;
+use rts_core::trips::{choose_trip, BoundaryLanes, Trip};
+use crate::system_monitoring::{EventKind, Level, LogEvent};
+use rts_core::routing::{self, find_lane_path};
+use rts_core::network::load_network;
+use crate::query;
+use crate::config::Config;
+use crate::envelope::{self, Feed};
+use crate::endpoints::Ports;
+use crate::heartbeat;
+use crate::control;
+use crate::summary::SimulationSummary;
+use rts_core::clock::SimClock;
+use rts_core::messages::{LightColor, LightUpdate};
+use rts_core::progress::LaneTransition;
+use rts_core::cadence::{self, CadenceController};
+use rts_core::demand::Arrivals;
+use rts_core::seed::{self, Stream};
+use rts_core::export::{self, Export, Journey, LaneSample};
+use rts_core::outbox::LaneCountOutbox;
+
+/// Every how many updates the flow analyzer is sent every lane's count, not
+/// only the counts that changed, so it catches up on anything it missed.
+const FULL_SNAPSHOT_EVERY: u64 = 10;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CarMetrics {
     pub id: u32,
+    pub entry_lane: u32,
+    pub exit_lane: u32,
     pub wait_time: f64,
     pub drive_time: f64,
     pub total_time: f64,
+    /// Internal lanes driven, in order.
+    pub lanes: Vec<LaneVisit>,
+    /// Entry/exit pairs drawn and rejected before the car's trip.
+    pub redraws: u32,
 }
 
-#[derive(Debug, Clone)]
-pub struct RoadSegment {
-    pub from: u32,
-    pub to: u32,
-    pub length: f64,
-    pub lanes: u32,
+/// A car that gave up without a valid entry/exit pair and never drove.
+#[derive(Debug)]
+pub struct GenerationFailed {
+    pub car_id: u32,
+    /// Entry/exit pairs drawn, all rejected.
+    pub attempts: u32,
 }
 
-#[derive(Debug)]
-struct State {
-    cost: f64,
-    position: u32,
-    prev: Option<u32>,
+/// One internal lane of a car's trip.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LaneVisit {
+    pub lane_id: u32,
+    /// Junction at the end of the lane.
+    pub junction: u32,
+    /// Vehicles on the lane right after the car entered.
+    pub occupancy: u32,
+    /// Time spent waiting for room on the lane and for its light.
+    pub wait_time: f64,
+    /// The car left the lane by turning right on a red light.
+    #[serde(default)]
+    pub right_on_red: bool,
 }
-impl Eq for State {}
-impl PartialEq for State {
-    fn eq(&self, other: &Self) -> bool {
-        self.cost.eq(&other.cost)
-    }
+
+/// Vehicles on each lane, broken down by parallel lane: `lanes[&id][i]` is
+/// the count on parallel lane `i` of lane `id`.
+pub type SimEvent = Arc<Mutex<HashMap<u32, Vec<u32>>>>;
+
+/// Vehicle count of every lane, summed over its parallel lanes. This is what
+/// the flow analyzer, routing and lane queries see.
+pub fn lane_totals(lanes: &HashMap<u32, Vec<u32>>) -> HashMap<u32, u32> {
+    lanes.iter().map(|(&lane_id, counts)| (lane_id, counts.iter().sum())).collect()
 }
-impl Ord for State {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
-    }
+
+/// Sends lane counts to the flow analyzer on the updates socket, without
+/// waiting for it to take them (EAGAIN if it cannot).
+pub fn publish_counts(socket: &zmq::Socket, snapshot: &LaneSnapshot) -> zmq::Result<()> {
+    socket.send(envelope::seal(Feed::Updates, snapshot).as_bytes(), zmq::DONTWAIT)
 }
-impl PartialOrd for State {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+
+/// Lane counts published to the flow analyzer, with the interval the
+/// publisher will wait before sending the next one. Unless `full`, `lanes`
+/// holds only the lanes whose count changed since the last update.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LaneSnapshot {
+    pub lanes: HashMap<u32, u32>,
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub full: bool,
 }
 
-fn intersection_to_coords(inter: u32) -> (u32, u32) {
-    let row = (inter - 1) / 4;
-    let col = (inter - 1) % 4;
-    (row, col)
+/// Cars on each parallel lane, in the order they entered it, with their
+/// speeds: `cars[&(id, i)]` is parallel lane `i` of lane `id`.
+pub type SubLaneCars = Arc<Mutex<HashMap<(u32, usize), Vec<(u32, f64)>>>>;
+
+/// The lanes as the cars share them: how many vehicles each parallel lane
+/// holds, and which, so that cars can keep to the pace of those ahead.
+#[derive(Clone)]
+pub struct Road {
+    pub counts: SimEvent,
+    pub cars: SubLaneCars,
 }
 
-fn find_lane_path(start: u32, end: u32, lanes: &Vec<Lane>) -> Option<Vec<Lane>> {
-    #[derive(Debug)]
-    struct LaneState {
-        cost: f64,
-        position: u32,
-    }
-    impl Eq for LaneState {}
-    impl PartialEq for LaneState {
-        fn eq(&self, other: &Self) -> bool {
-            self.cost == other.cost
-        }
-    }
-    impl Ord for LaneState {
-        fn cmp(&self, other: &Self) -> Ordering {
-            other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
-        }
-    }
-    impl PartialOrd for LaneState {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
+pub fn initialize_simdata() -> SimEvent {
+    let mut map = HashMap::new();
+    let lanes = load_lanes();
+    for lane in lanes {
+        map.insert(lane.id, vec![0; lane.parallel_count.max(1) as usize]);
     }
+    Arc::new(Mutex::new(map))
+}
 
-    let mut dist: HashMap<u32, f64> = HashMap::new();
-    let mut prev: HashMap<u32, (u32, Lane)> = HashMap::new();
-    let mut heap = BinaryHeap::new();
-    
-    for inter in 1..=16 {
-        dist.insert(inter, std::f64::INFINITY);
+/// A car's place in a lane's queue at its light, given up when dropped: once
+/// the car passes, or if its thread ends first.
+struct QueuePlace<'a> {
+    queues: &'a LaneQueueMap,
+    lane_id: u32,
+    car_id: u32,
+}
+
+impl<'a> QueuePlace<'a> {
+    fn join(queues: &'a LaneQueueMap, lane_id: u32, car_id: u32) -> QueuePlace<'a> {
+        lock(queues).join(lane_id, car_id);
+        QueuePlace { queues, lane_id, car_id }
     }
-    dist.insert(start, 0.0);
-    heap.push(LaneState { cost: 0.0, position: start });
-    
-    let mut lane_map: HashMap<u32, Vec<&Lane>> = HashMap::new();
-    for lane in lanes {
-        lane_map.entry(lane.start_intersection).or_default().push(lane);
+}
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        lock(self.queues).leave(self.lane_id, self.car_id);
     }
-    
-    while let Some(LaneState { cost, position }) = heap.pop() {
-        if position == end {
-            break;
-        }
-        if cost > dist[&position] {
-            continue;
-        }
-        if let Some(neighbor_lanes) = lane_map.get(&position) {
-            for &lane in neighbor_lanes {
-                let next = lane.end_intersection;
-                let next_cost = cost + lane.length;
-                if next_cost < *dist.get(&next).unwrap_or(&std::f64::INFINITY) {
-                    dist.insert(next, next_cost);
-                    prev.insert(next, (position, lane.clone()));
-                    heap.push(LaneState { cost: next_cost, position: next });
+}
+
+// Helper function: creates a new log socket from the given context.
+/// Follows the traffic light controller's lights channel (see `endpoints`)
+/// in a thread of its own, keeping `signals` current for the cars. A lane
+/// that turns amber lets the car at the front of its queue clear the
+/// junction; a snapshot replaces every color, and lanes it leaves out are red.
+fn follow_lights(ctx: &zmq::Context, signals: Signals, ports: &Ports) {
+    let socket = ctx.socket(zmq::SUB).expect("Failed to create lights SUB socket");
+    socket.connect(&ports.lights().connect).expect("Failed to connect lights socket");
+    socket.set_subscribe(b"").expect("Failed to subscribe to light status");
+    thread::spawn(move || {
+        let show = |lights: &mut HashMap<u32, LightColor>, lane_id: u32, color: LightColor| {
+            if color == LightColor::Amber && lights.get(&lane_id) != Some(&LightColor::Amber) {
+                lock(&signals.queues).light_turned_amber(lane_id);
+            }
+            lights.insert(lane_id, color);
+        };
+        loop {
+            let Ok(Ok(json_str)) = socket.recv_string(0) else { continue };
+            match envelope::open::<LightUpdate>(&json_str) {
+                Ok(LightUpdate::Lane(status)) => show(&mut lock(&signals.lights), status.lane_id, status.status),
+                Ok(LightUpdate::Snapshot(snapshot)) => {
+                    let mut lights = lock(&signals.lights);
+                    for (&lane_id, &color) in &snapshot.lights {
+                        show(&mut lights, lane_id, color);
+                    }
+                    for (lane_id, color) in lights.iter_mut() {
+                        if !snapshot.lights.contains_key(lane_id) {
+                            *color = LightColor::Red;
+                        }
+                    }
                 }
+                Err(e) => eprintln!("Ignoring malformed light status {}: {}", json_str, e),
             }
         }
-    }
-    
-    if !dist.contains_key(&end) || dist[&end] == std::f64::INFINITY {
+    });
+}
+
+fn create_log_socket(ctx: &zmq::Context, ports: &Ports) -> zmq::Socket {
+    let sock = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+    sock.connect(&ports.logs().connect).expect("Failed to connect log socket");
+    sock
+}
+
+/// Reports that `car_id` entered or left the lane at `route_index` of its trip.
+fn send_progress(log_socket: &zmq::Socket, clock: &SimClock, car_id: u32, lane_id: u32, transition: LaneTransition, route_index: usize) {
+    let message = match transition {
+        LaneTransition::Entered => format!("Entered lane {} (trip position {})", lane_id, route_index),
+        LaneTransition::Exited => format!("Left lane {}", lane_id),
+    };
+    let progress_log = LogEvent {
+        source: format!("Car-{}", car_id),
+        message,
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::CarProgress { car_id, lane_id, transition, route_index },
+    };
+    let progress_json = envelope::seal(Feed::Logs, &progress_log);
+    log_socket.send(progress_json.as_bytes(), 0).expect("Failed to send log event");
+}
+
+/// Seconds a car may be blocked on a full lane before it re-routes around it.
+/// This also breaks deadlocks between full lanes that feed each other.
+const BLOCKED_REROUTE_SECS: f64 = 10.0;
+
+/// Simulated seconds a car loses shifting to a parallel lane.
+const LANE_CHANGE_SECS: f64 = 2.0;
+
+/// Speed, in m/s, a car must gain to change lanes to overtake.
+const OVERTAKE_MIN_GAIN: f64 = 2.0;
+
+/// A slot claimed on one of a lane's parallel lanes.
+struct LaneSlot {
+    /// Parallel lane the car is on.
+    sub_lane: usize,
+    /// Parallel lane the car arrived in, if it shifted out of it.
+    shifted_from: Option<usize>,
+    /// Vehicles on the whole lane, this car included.
+    occupancy: u32,
+}
+
+/// Claims a slot on `lane` for `car_id`, driving at `speed`. The car arrives
+/// in parallel lane `preferred` (modulo the lane's parallel count) and shifts
+/// to the emptiest parallel lane if that one holds fewer vehicles. Returns
+/// None if every parallel lane is at its share of the lane's capacity.
+fn try_enter_lane(road: &Road, lane: &Lane, preferred: usize, car_id: u32, speed: f64) -> Option<LaneSlot> {
+    let mut stats = road.counts.lock().unwrap();
+    let counts = stats.entry(lane.id).or_insert_with(|| vec![0; lane.parallel_count.max(1) as usize]);
+    let arrival = preferred % counts.len();
+    // Ties keep the car where it is.
+    let emptiest = (0..counts.len())
+        .min_by_key(|&i| (counts[i], i != arrival))
+        .unwrap_or(arrival);
+    if counts[emptiest] >= lane.sub_lane_capacity() {
         return None;
     }
-    
-    let mut path: Vec<Lane> = Vec::new();
-    let mut current = end;
-    while current != start {
-        if let Some(&(prev_inter, ref lane)) = prev.get(&current) {
-            path.push(lane.clone());
-            current = prev_inter;
-        } else {
-            break;
-        }
+    counts[emptiest] += 1;
+    road.cars.lock().unwrap().entry((lane.id, emptiest)).or_default().push((car_id, speed));
+    Some(LaneSlot {
+        sub_lane: emptiest,
+        shifted_from: (emptiest != arrival).then_some(arrival),
+        occupancy: counts.iter().sum(),
+    })
+}
+
+/// Releases the slot `car_id` held on parallel lane `sub_lane` of `lane_id`.
+fn leave_lane(road: &Road, lane_id: u32, sub_lane: usize, car_id: u32) {
+    let mut stats = road.counts.lock().unwrap();
+    if let Some(count) = stats.get_mut(&lane_id).and_then(|counts| counts.get_mut(sub_lane)) {
+        *count = count.saturating_sub(1);
+    }
+    if let Some(cars) = road.cars.lock().unwrap().get_mut(&(lane_id, sub_lane)) {
+        cars.retain(|&(id, _)| id != car_id);
     }
-    path.reverse();
-    Some(path)
 }
 
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// Speed `car_id` can drive at among `cars`, the cars of one parallel lane
+/// in the order they entered it: its own `speed`, or that of the slowest car
+/// ahead of it.
+fn following_speed(cars: &[(u32, f64)], car_id: u32, speed: f64) -> f64 {
+    cars.iter().take_while(|&&(id, _)| id != car_id).map(|&(_, ahead)| ahead).fold(speed, f64::min)
 }
 
-pub type SimEvent = Arc<Mutex<HashMap<u32, u32>>>;
+/// Speed `car_id` can drive parallel lane `sub_lane` of `lane_id` at.
+fn lane_speed(road: &Road, lane_id: u32, sub_lane: usize, car_id: u32, speed: f64) -> f64 {
+    road.cars.lock().unwrap().get(&(lane_id, sub_lane)).map_or(speed, |cars| following_speed(cars, car_id, speed))
+}
 
-pub fn initialize_simdata() -> SimEvent {
-    let mut map = HashMap::new();
-    let lanes = load_lanes();
-    for lane in lanes {
-        map.insert(lane.id, 0);
+/// Moves `car_id`, held up to `current` on parallel lane `sub_lane` of `lane`,
+/// to the back of the parallel lane with room where it could drive fastest,
+/// if that is at least OVERTAKE_MIN_GAIN faster. Returns that parallel lane
+/// and the car's speed on it.
+fn try_overtake(road: &Road, lane: &Lane, sub_lane: usize, car_id: u32, speed: f64, current: f64) -> Option<(usize, f64)> {
+    let mut stats = road.counts.lock().unwrap();
+    let counts = stats.get_mut(&lane.id)?;
+    let mut cars = road.cars.lock().unwrap();
+    let (target, faster) = (0..counts.len())
+        .filter(|&i| i != sub_lane && counts[i] < lane.sub_lane_capacity())
+        .map(|i| (i, cars.get(&(lane.id, i)).map_or(speed, |ahead| following_speed(ahead, car_id, speed))))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if faster < current + OVERTAKE_MIN_GAIN {
+        return None;
     }
-    Arc::new(Mutex::new(map))
+    counts[sub_lane] = counts[sub_lane].saturating_sub(1);
+    counts[target] += 1;
+    if let Some(from) = cars.get_mut(&(lane.id, sub_lane)) {
+        from.retain(|&(id, _)| id != car_id);
+    }
+    cars.entry((lane.id, target)).or_default().push((car_id, speed));
+    Some((target, faster))
 }
 
-// Helper function: creates a new log socket from the given context.
-fn create_log_socket(ctx: &zmq::Context) -> zmq::Socket {
-    let sock = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
-    sock.connect("tcp://localhost:7000").expect("Failed to connect to tcp://localhost:7000");
-    sock
+/// How the cars of a run draw and follow their routes.
+#[derive(Debug, Clone, Copy)]
+pub struct CarOptions {
+    /// Penalize lanes by their current load when routing.
+    pub congestion_aware: bool,
+    /// Run seed; each car's speed and trip then come from a generator of its
+    /// own (see `rts_core::seed`). Without one they are drawn at random.
+    pub seed: Option<u32>,
+    /// Speed range, poll interval and ports of the run.
+    pub config: Config,
 }
 
+/// A generator for the run's `stream`, seeded from `seed` if there is one.
+fn stream_rng(seed: Option<u32>, stream: Stream, index: u64) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed::derive(seed, stream, index)),
+        None => StdRng::from_rng(&mut rand::rng()),
+    }
+}
+
+/// Drives one car across the grid. The entry and exit are re-drawn until they
+/// are different junctions with a route between them; a car that runs out of
+/// draws logs VehicleGenerationFailed and does not drive. Travel, waits and
+/// the returned metrics are all in `clock`'s simulated time. On lanes with
+/// parallel lanes, a car held up behind a slower one changes lanes to
+/// overtake it when a parallel lane with room lets it drive faster.
 pub fn simulate_car(
     car_id: u32,
-    traffic_lights: TrafficLightMap,
-    entry_lanes: &[Lane],
-    exit_lanes: &[Lane],
-    sim_event: Arc<Mutex<HashMap<u32, u32>>>,
+    signals: Signals,
+    boundary: &BoundaryLanes,
+    road: Road,
     ctx: &zmq::Context,
-) -> CarMetrics {
-    let mut rng = rand::thread_rng();
-    let speed: f64 = rng.gen_range(70.0..=90.0);
-    let input_lane = entry_lanes[rng.gen_range(0..entry_lanes.len())].clone();
-    let mut exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
-    while exit_lane.id == input_lane.id {
-        exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
-    }
-
-    let start_intersection = input_lane.end_intersection;
-    let end_intersection = exit_lane.start_intersection;
+    options: CarOptions,
+    clock: SimClock,
+) -> Result<CarMetrics, GenerationFailed> {
+    let mut rng = stream_rng(options.seed, Stream::Vehicle, car_id.into());
+    let speed: f64 = rng.random_range(options.config.min_speed..=options.config.max_speed);
 
     let all_lanes = load_lanes();
+    let network = load_network();
     let internal_lanes: Vec<Lane> = all_lanes
-        .into_iter()
+        .iter()
         .filter(|l| l.category == LaneCategory::Internal)
+        .cloned()
         .collect();
 
-    let lane_route = find_lane_path(start_intersection, end_intersection, &internal_lanes).unwrap_or_default();
+    let log_socket = create_log_socket(ctx, &options.config.ports);
+    let Trip { entry: input_lane, exit: exit_lane, redraws } =
+        match choose_trip(boundary, &internal_lanes, &network, None, &mut rng, |_, _| None) {
+            Ok(trip) => trip,
+            Err(attempts) => {
+                let fail_log = LogEvent {
+                    source: format!("Car-{}", car_id),
+                    message: format!("Gave up after {} entry/exit draws without a valid trip", attempts),
+                    timestamp: clock.now_secs(),
+                    level: Level::Warn,
+                    kind: EventKind::VehicleGenerationFailed { car_id, attempts },
+                };
+                let fail_json = envelope::seal(Feed::Logs, &fail_log);
+                log_socket.send(fail_json.as_bytes(), 0).expect("Failed to send log event");
+                return Err(GenerationFailed { car_id, attempts });
+            }
+        };
+
+    let start_intersection = input_lane.end_intersection;
+    let end_intersection = exit_lane.start_intersection;
+
+    // With congestion-aware routing, lanes are penalized by their current load.
+    let current_weights = || {
+        if !options.congestion_aware {
+            return None;
+        }
+        road.counts.lock().ok().map(|counts| routing::congestion_weights(&lane_totals(&counts)))
+    };
+
+    let lane_route = match find_lane_path(start_intersection, end_intersection, &internal_lanes, &network, current_weights().as_ref()) {
+        Ok(route) => route,
+        Err(e) => {
+            let fail_log = serde_json::json!({
+                "source": format!("Car-{}", car_id),
+                "message": format!("Routing failed: {}; driving straight to the exit lane", e),
+                "timestamp": clock.now_secs()
+            });
+            log_socket.send(envelope::seal(Feed::Logs, &fail_log).as_bytes(), 0).expect("Failed to send log event");
+            Vec::new()
+        }
+    };
     let lane_ids: Vec<u32> = lane_route.iter().map(|lane| lane.id).collect();
 
-    let log_socket = create_log_socket(ctx);
-    let gen_log = serde_json::json!({
-        "source": format!("Car-{}", car_id),
-        "message": format!("Generated vehicle with speed {:.2} m/s; Entry Lane {} (Inter. {}), Exit Lane {} (Inter. {}); Lane Route: {:?}", 
-                           speed, input_lane.id, input_lane.end_intersection, exit_lane.id, exit_lane.start_intersection, lane_ids),
-        "timestamp": current_time_secs()
-    });
-    log_socket.send(gen_log.to_string().as_bytes(), 0).expect("Failed to send log event");
+    let gen_log = LogEvent {
+        source: format!("Car-{}", car_id),
+        message: format!("Generated vehicle with speed {:.2} m/s; Entry Lane {} (Inter. {}), Exit Lane {} (Inter. {}); Lane Route: {:?}",
+                         speed, input_lane.id, input_lane.end_intersection, exit_lane.id, exit_lane.start_intersection, lane_ids),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::VehicleGenerated {
+            car_id,
+            speed,
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route: lane_ids,
+        },
+    };
+    let gen_json = envelope::seal(Feed::Logs, &gen_log);
+    log_socket.send(gen_json.as_bytes(), 0).expect("Failed to send log event");
 
     let start_time = Instant::now();
     let mut total_wait_time = 0.0;
     let mut total_drive_time = 0.0;
+    // Meters actually driven, including any detours taken.
+    let mut route_length = input_lane.length;
 
+    send_progress(&log_socket, &clock, car_id, input_lane.id, LaneTransition::Entered, 0);
     let travel_time = input_lane.length / speed;
-    thread::sleep(Duration::from_secs_f64(travel_time));
+    clock.sleep(Duration::from_secs_f64(travel_time));
     total_drive_time += travel_time;
 
-    for lane in lane_route {
-        {
-            let mut stats = sim_event.lock().unwrap();
-            *stats.entry(lane.id).or_insert(0) += 1;
-            println!("car {} entered lane {}", car_id, lane.id);
-        }
-        
+    // A car keeps its slot on the previous lane until it has secured a slot
+    // on the next one, so full lanes back up into their feeders. On lanes
+    // with parallel lanes it arrives in the one matching its id.
+    let preferred_sub_lane = car_id as usize;
+    let mut route = lane_route;
+    let mut visits = Vec::new();
+    let mut occupied: Option<(u32, usize)> = None;
+    let mut index = 0;
+    while index < route.len() {
+        let lane = route[index].clone();
+
         let wait_start = Instant::now();
-        loop {
-            let can_go = {
-                let locked = traffic_lights.lock().unwrap();
-                can_proceed_lane(lane.id, &*locked)
+        let mut blocked_since = Instant::now();
+        // None once the car has re-routed around the lane instead of entering it.
+        let entered = loop {
+            if let Some(slot) = try_enter_lane(&road, &lane, preferred_sub_lane, car_id, speed) {
+                break Some(slot);
+            }
+            if clock.since(blocked_since).as_secs_f64() >= BLOCKED_REROUTE_SECS {
+                let candidates: Vec<Lane> = internal_lanes.iter().filter(|l| l.id != lane.id).cloned().collect();
+                if let Ok(detour) = find_lane_path(lane.start_intersection, end_intersection, &candidates, &network, current_weights().as_ref()) {
+                    let detour_ids: Vec<u32> = detour.iter().map(|l| l.id).collect();
+                    let reroute_log = serde_json::json!({
+                        "source": format!("Car-{}", car_id),
+                        "message": format!("Lane {} full for {:.0}s; re-routed via {:?}", lane.id, BLOCKED_REROUTE_SECS, detour_ids),
+                        "timestamp": clock.now_secs()
+                    });
+                    log_socket.send(envelope::seal(Feed::Logs, &reroute_log).as_bytes(), 0).expect("Failed to send log event");
+                    route.truncate(index);
+                    route.extend(detour);
+                    break None;
+                }
+                blocked_since = Instant::now();
+            }
+            clock.sleep(options.config.poll_interval);
+        };
+        total_wait_time += clock.since(wait_start).as_secs_f64();
+        let Some(LaneSlot { mut sub_lane, shifted_from, occupancy }) = entered else {
+            continue;
+        };
+        send_progress(&log_socket, &clock, car_id, lane.id, LaneTransition::Entered, index + 1);
+        match occupied.replace((lane.id, sub_lane)) {
+            Some((previous, previous_sub_lane)) => {
+                leave_lane(&road, previous, previous_sub_lane, car_id);
+                send_progress(&log_socket, &clock, car_id, previous, LaneTransition::Exited, index);
+            }
+            None => send_progress(&log_socket, &clock, car_id, input_lane.id, LaneTransition::Exited, 0),
+        }
+        if let Some(from_sub_lane) = shifted_from {
+            let change_log = LogEvent {
+                source: format!("Car-{}", car_id),
+                message: format!("Shifted from lane {} to lane {} of {}", from_sub_lane + 1, sub_lane + 1, lane.id),
+                timestamp: clock.now_secs(),
+                level: Level::Info,
+                kind: EventKind::LaneChange { car_id, lane_id: lane.id, from_sub_lane, to_sub_lane: sub_lane, overtaking: false },
+            };
+            let change_json = envelope::seal(Feed::Logs, &change_log);
+            log_socket.send(change_json.as_bytes(), 0).expect("Failed to send log event");
+            clock.sleep(Duration::from_secs_f64(LANE_CHANGE_SECS));
+            total_drive_time += LANE_CHANGE_SECS;
+        }
+
+        // Wait for the light, or, turning right, for room on the next lane
+        // to go on red.
+        let next_lane = route.get(index + 1).unwrap_or(&exit_lane).clone();
+        let movement = phase_plan::movement(lane.end_intersection, &lane, &next_lane, &all_lanes, &network)
+            .unwrap_or(Movement::Straight);
+        let light_start = Instant::now();
+        let place = QueuePlace::join(&signals.queues, lane.id, car_id);
+        // The next lane's occupancy if the car went on red.
+        let red_turn = loop {
+            let next_occupancy = road.counts.lock().unwrap().get(&next_lane.id).map_or(0, |counts| counts.iter().sum());
+            let passed = {
+                let lights = lock(&signals.lights);
+                let queues = lock(&signals.queues);
+                can_proceed_lane(lane.id, car_id, movement, &next_lane, next_occupancy, &lights, &queues).then(|| {
+                    let color = lights.get(&lane.id).copied().unwrap_or(LightColor::Red);
+                    (!queues.may_pass(lane.id, car_id, color)).then_some(next_occupancy)
+                })
             };
-            if can_go {
-                break;
+            if let Some(passed) = passed {
+                break passed;
             }
-            thread::sleep(Duration::from_millis(100));
+            clock.sleep(options.config.poll_interval);
+        };
+        drop(place);
+        if let Some(next_occupancy) = red_turn {
+            let turn_log = LogEvent {
+                source: format!("Car-{}", car_id),
+                message: format!(
+                    "Turned right on red from lane {} into lane {} ({} vehicle(s) on it)",
+                    lane.id, next_lane.id, next_occupancy
+                ),
+                timestamp: clock.now_secs(),
+                level: Level::Info,
+                kind: EventKind::RightTurnOnRed { car_id, lane_id: lane.id, into_lane: next_lane.id, occupancy: next_occupancy },
+            };
+            let turn_json = envelope::seal(Feed::Logs, &turn_log);
+            log_socket.send(turn_json.as_bytes(), 0).expect("Failed to send log event");
         }
-        total_wait_time += wait_start.elapsed().as_secs_f64();
+        total_wait_time += clock.since(light_start).as_secs_f64();
+        visits.push(LaneVisit {
+            lane_id: lane.id,
+            junction: lane.end_intersection,
+            occupancy,
+            wait_time: clock.since(wait_start).as_secs_f64(),
+            right_on_red: red_turn.is_some(),
+        });
 
-        let seg_time = lane.length / speed;
-        thread::sleep(Duration::from_secs_f64(seg_time));
+        // Keep to the pace of the slowest car ahead, unless a parallel lane
+        // lets the car overtake it.
+        let mut lane_pace = lane_speed(&road, lane.id, sub_lane, car_id, speed);
+        if lane_pace < speed {
+            if let Some((to_sub_lane, faster)) = try_overtake(&road, &lane, sub_lane, car_id, speed, lane_pace) {
+                let change_log = LogEvent {
+                    source: format!("Car-{}", car_id),
+                    message: format!("Changed from lane {} to lane {} of {} to overtake", sub_lane + 1, to_sub_lane + 1, lane.id),
+                    timestamp: clock.now_secs(),
+                    level: Level::Info,
+                    kind: EventKind::LaneChange { car_id, lane_id: lane.id, from_sub_lane: sub_lane, to_sub_lane, overtaking: true },
+                };
+                let change_json = envelope::seal(Feed::Logs, &change_log);
+                log_socket.send(change_json.as_bytes(), 0).expect("Failed to send log event");
+                clock.sleep(Duration::from_secs_f64(LANE_CHANGE_SECS));
+                total_drive_time += LANE_CHANGE_SECS;
+                sub_lane = to_sub_lane;
+                occupied = Some((lane.id, sub_lane));
+                lane_pace = faster;
+            }
+        }
+        let seg_time = lane.length / lane_pace;
+        clock.sleep(Duration::from_secs_f64(seg_time));
         total_drive_time += seg_time;
-        {
-            let mut stats = sim_event.lock().unwrap();
-            *stats.entry(lane.id).or_insert(0) -= 1;
-            println!("car {} left lane {}", car_id, lane.id);
+        route_length += lane.length;
+        index += 1;
+    }
+    // Enter the exit lane, releasing the last internal lane (or the entry
+    // lane, if the route was empty).
+    let exit_index = route.len() + 1;
+    send_progress(&log_socket, &clock, car_id, exit_lane.id, LaneTransition::Entered, exit_index);
+    match occupied {
+        Some((previous, previous_sub_lane)) => {
+            leave_lane(&road, previous, previous_sub_lane, car_id);
+            send_progress(&log_socket, &clock, car_id, previous, LaneTransition::Exited, route.len());
         }
+        None => send_progress(&log_socket, &clock, car_id, input_lane.id, LaneTransition::Exited, 0),
     }
 
     let exit_time = exit_lane.length / speed;
-    thread::sleep(Duration::from_secs_f64(exit_time));
+    clock.sleep(Duration::from_secs_f64(exit_time));
+    send_progress(&log_socket, &clock, car_id, exit_lane.id, LaneTransition::Exited, exit_index);
     total_drive_time += exit_time;
+    route_length += exit_lane.length;
 
-    let total_time = start_time.elapsed().as_secs_f64();
+    let total_time = clock.since(start_time).as_secs_f64();
     let comp_log = serde_json::json!({
         "source": format!("Car-{}", car_id),
         "message": format!("Completed journey: Wait={:.2}s, Drive={:.2}s, Total={:.2}s", total_wait_time, total_drive_time, total_time),
-        "timestamp": current_time_secs()
+        "timestamp": clock.now_secs(),
+        "kind": EventKind::CarCompleted {
+            car_id,
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route_length,
+            wait_time: total_wait_time,
+            drive_time: total_drive_time,
+            total_time,
+        }
     });
-    log_socket.send(comp_log.to_string().as_bytes(), 0).expect("Failed to send log event");
+    log_socket.send(envelope::seal(Feed::Logs, &comp_log).as_bytes(), 0).expect("Failed to send log event");
 
-    CarMetrics {
+    Ok(CarMetrics {
         id: car_id,
+        entry_lane: input_lane.id,
+        exit_lane: exit_lane.id,
         wait_time: total_wait_time,
         drive_time: total_drive_time,
         total_time,
-    }
+        lanes: visits,
+        redraws,
+    })
 }
 
-pub fn run_simulation(traffic_lights: TrafficLightMap) {
+/// Runs the simulation: `config.demand.cars` vehicles, spawned all at once or
+/// at its arrival rate (see `rts_core::demand`), until every one has finished, then
+/// announces shutdown to the other components. With an `export`, the cars'
+/// journeys and the lane counts of every snapshot are written out first.
+/// `traffic_lights` start red and follow the traffic light process's lights
+/// channel from then on.
+pub fn run_simulation(traffic_lights: TrafficLightMap, config: Config, export: Option<Export>, clock: SimClock) {
+    let demand = config.demand;
     let context = zmq::Context::new();
-    // Create a PUSH socket for sending simulation updates.
+    // The simulation owns the one PUSH socket for updates; the flow analyzer
+    // connects its PULL socket to it. zmq sockets can't be shared between
+    // threads, so the update thread hands its snapshots to this thread.
     let sim_socket = context.socket(zmq::PUSH).expect("Failed to create simulation PUSH socket");
-    sim_socket.bind("tcp://*:7001").expect("Failed to bind tcp://*:7001");
+    sim_socket.bind(&config.ports.updates().bind).expect("Failed to bind simulation update socket");
 
     // For logging outside of car threads.
     let log_socket = context.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
-    log_socket.connect("tcp://localhost:7000").expect("Failed to connect to tcp://localhost:7000");
+    log_socket.connect(&config.ports.logs().connect).expect("Failed to connect log socket");
+    let announcer = control::Announcer::bind(&context, &config.ports);
+    heartbeat::start("simulation", clock, &config.ports);
 
-    let sim_event: SimEvent = initialize_simdata();
+    if clock.is_accelerated() {
+        println!("Running at {}x real time", clock.scale());
+    }
+
+    let road = Road { counts: initialize_simdata(), cars: SubLaneCars::default() };
+    let sim_event = road.counts.clone();
     let all_lanes = load_lanes();
-    let entry_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::InputBoundary)
-        .cloned()
-        .collect();
-    let exit_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::OutputBoundary)
-        .cloned()
-        .collect();
+    let boundary = Arc::new(BoundaryLanes::from_lanes(&all_lanes));
 
     // Share the context in an Arc so car threads can create their own log sockets.
     let ctx_arc = Arc::new(context);
-    let mut handles = vec![];
-
-    for car_id in 1..=30 {
-        let tl_clone = traffic_lights.clone();
-        let entry_clone = entry_lanes.clone();
-        let exit_clone = exit_lanes.clone();
-        let sim_event_clone = sim_event.clone();
-        let ctx_clone = Arc::clone(&ctx_arc);
-        let handle = thread::spawn(move || {
-            let car_metrics = simulate_car(car_id, tl_clone, &entry_clone, &exit_clone, sim_event_clone, &ctx_clone);
-            println!("Car {} metrics: {:?}", car_id, car_metrics);
-        });
-        handles.push(handle);
+    let (result_tx, result_rx) = mpsc::channel();
+    let run_start = Instant::now();
+
+    let seed = seed::seed_from_env();
+    if let Some(seed) = seed {
+        println!("Run seed {}: trips, speeds and arrivals are reproducible", seed);
+    }
+    let congestion_aware = routing::congestion_routing_enabled();
+    let options = CarOptions { congestion_aware, seed, config };
+    if congestion_aware {
+        println!("Congestion-aware routing enabled");
+    }
+
+    let signals = Signals { lights: traffic_lights, queues: LaneQueueMap::default() };
+    follow_lights(&ctx_arc, signals.clone(), &config.ports);
+    let car_road = road.clone();
+    let car_ctx = Arc::clone(&ctx_arc);
+    let spawn_car = move |car_id: u32| {
+        let signals_clone = signals.clone();
+        let boundary_clone = Arc::clone(&boundary);
+        let road_clone = car_road.clone();
+        let ctx_clone = Arc::clone(&car_ctx);
+        let result_tx_clone = result_tx.clone();
+        thread::spawn(move || {
+            let outcome = simulate_car(car_id, signals_clone, &boundary_clone, road_clone, &ctx_clone, options, clock);
+            match &outcome {
+                Ok(car_metrics) => println!("Car {} metrics: {:?}", car_id, car_metrics),
+                Err(failed) => println!("Car {} found no valid trip in {} draws; check the lane topology", failed.car_id, failed.attempts),
+            }
+            result_tx_clone.send(outcome).ok();
+        })
+    };
+    let handles: Vec<thread::JoinHandle<()>> = match demand.arrivals {
+        Arrivals::AllAtOnce => (1..=demand.cars).map(spawn_car).collect(),
+        Arrivals::Continuous { per_minute } => {
+            println!("Spawning {} vehicles at {} per minute", demand.cars, per_minute);
+            // The spawner stands in for its cars: it finishes once they all have.
+            vec![thread::spawn(move || {
+                let mut rng = stream_rng(seed, Stream::Arrivals, 0);
+                let cars: Vec<_> = (1..=demand.cars)
+                    .map(|car_id| {
+                        if car_id > 1 {
+                            clock.sleep(demand.gap(rng.random()));
+                        }
+                        spawn_car(car_id)
+                    })
+                    .collect();
+                for car in cars {
+                    car.join().unwrap();
+                }
+            })]
+        }
+    };
+
+    // Answer lane occupancy queries while the simulation runs.
+    {
+        let sim_event_query = sim_event.clone();
+        let ctx_for_query = Arc::clone(&ctx_arc);
+        thread::spawn(move || query::serve_lane_queries(ctx_for_query, sim_event_query, config.ports));
     }
 
-    // Spawn a thread to periodically send simulation updates.
+    // Spawn a thread to periodically take snapshots for the update socket.
+    let (update_tx, update_rx) = mpsc::channel::<LaneSnapshot>();
     {
         let sim_event_sender = sim_event.clone();
-        // Instead of cloning sim_socket (which is not cloneable), create a new PUSH socket from the shared context.
-        let ctx_for_sim = Arc::clone(&ctx_arc);
         thread::spawn(move || {
-            let sim_sock = ctx_for_sim.socket(zmq::PUSH).expect("Failed to create simulation update socket");
-            // This new socket connects to the same bound endpoint.
-            sim_sock.connect("tcp://localhost:7001").expect("Failed to connect to tcp://localhost:7001");
+            // Publish faster while lane counts change quickly, slower while quiet.
+            let mut cadence = CadenceController::new(cadence::FLOOR, cadence::CEILING, cadence::INITIAL);
             loop {
-                thread::sleep(Duration::from_secs(5));
-                if let Ok(lanes) = sim_event_sender.lock() {
-                    let json_data = serde_json::to_string(&*lanes).unwrap();
-                    sim_sock.send(json_data.as_bytes(), 0).expect("Failed to send simulation update");
+                let elapsed = cadence.interval();
+                clock.sleep(elapsed);
+                let lanes = match sim_event_sender.lock() {
+                    Ok(lanes) => lane_totals(&lanes),
+                    Err(_) => break,
+                };
+                let next = cadence.observe(&lanes, elapsed);
+                let snapshot = LaneSnapshot { lanes, interval_ms: next.as_millis() as u64, full: true };
+                if update_tx.send(snapshot).is_err() {
+                    break;
                 }
             }
         });
     }
 
+    // Forward snapshots until every car has finished; dropping the receiver
+    // afterwards stops the update thread. Only the lanes that changed go out,
+    // and every lane each FULL_SNAPSHOT_EVERY updates; when the analyzer
+    // cannot keep up, the outbox aggregates or drops what it was not sent
+    // (see `rts_core::outbox`).
+    let mut outbox = LaneCountOutbox::from_env();
+    let mut lane_series: Vec<LaneSample> = Vec::new();
+    let mut updates_sent = 0u64;
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        if let Ok(snapshot) = update_rx.recv_timeout(config.poll_interval) {
+            if export.is_some() {
+                lane_series.extend(export::lane_samples(clock.now_secs(), &snapshot.lanes));
+            }
+            outbox.push_all(&snapshot.lanes);
+            let batch = outbox.take(updates_sent.is_multiple_of(FULL_SNAPSHOT_EVERY));
+            // An update goes out even when no count changed, so the analyzer
+            // keeps weighing its samples by the publisher's interval.
+            let (lanes, full) = batch.as_ref().map_or_else(|| (HashMap::new(), false), |batch| (batch.lanes.clone(), batch.full));
+            // A PUSH socket with no analyzer connected would block; leave the
+            // batch to the outbox's policy instead.
+            match publish_counts(&sim_socket, &LaneSnapshot { lanes, interval_ms: snapshot.interval_ms, full }) {
+                Ok(()) => {
+                    updates_sent += 1;
+                    if let Some(batch) = &batch {
+                        outbox.delivered(batch);
+                    }
+                }
+                Err(e) => {
+                    if e != zmq::Error::EAGAIN {
+                        eprintln!("Failed to send simulation update: {:?}", e);
+                    }
+                    if let Some(batch) = batch {
+                        outbox.undelivered(batch);
+                    }
+                }
+            }
+        }
+    }
+    drop(update_rx);
+    let stats = outbox.stats();
+    println!(
+        "Lane counts: {} sent in {} batches, {} aggregated, {} dropped ({} under backpressure)",
+        stats.sent, stats.batches, stats.aggregated, stats.dropped, outbox.policy()
+    );
+
     for handle in handles {
         handle.join().unwrap();
     }
 
+    // Final summary, printed and sent to monitoring before the process exits.
+    let (metrics, failures): (Vec<_>, Vec<_>) = result_rx.iter().partition(Result::is_ok);
+    let metrics: Vec<CarMetrics> = metrics.into_iter().flatten().collect();
+    let failures: Vec<GenerationFailed> = failures.into_iter().filter_map(Result::err).collect();
+    if let Some(export) = &export {
+        export_metrics(export, &metrics, &lane_series);
+    }
+    let summary = SimulationSummary::from_metrics(&metrics, &failures, clock.since(run_start));
+    println!("{}", summary);
+    let summary_log = LogEvent {
+        source: "Simulation".to_string(),
+        message: format!("Summary - {} vehicles, Wait mean/median/p95: {:.2}/{:.2}/{:.2} s",
+                         summary.vehicles, summary.mean_wait, summary.median_wait, summary.p95_wait),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Summary(summary),
+    };
+    let summary_json = envelope::seal(Feed::Logs, &summary_log);
+    log_socket.send(summary_json.as_bytes(), 0).expect("Failed to send log event");
+
     let avg_log = serde_json::json!({
         "source": "Simulation",
         "message": "Simulation complete.",
-        "timestamp": current_time_secs()
+        "timestamp": clock.now_secs()
     });
-    log_socket.send(avg_log.to_string().as_bytes(), 0).expect("Failed to send log event");
+    log_socket.send(envelope::seal(Feed::Logs, &avg_log).as_bytes(), 0).expect("Failed to send log event");
+    announcer.shutdown("simulation complete", &clock);
+}
+
+/// Writes the journeys of the cars that drove and the sampled lane counts
+/// where `export` says.
+fn export_metrics(export: &Export, metrics: &[CarMetrics], lane_series: &[LaneSample]) {
+    let journeys: Vec<Journey> = metrics
+        .iter()
+        .map(|m| Journey {
+            car_id: m.id,
+            entry_lane: m.entry_lane,
+            exit_lane: m.exit_lane,
+            wait: m.wait_time,
+            drive: m.drive_time,
+            total: m.total_time,
+        })
+        .collect();
+    match export.write(&journeys, lane_series) {
+        Ok((cars, lanes)) => println!(
+            "Exported {} journeys to {} and {} lane counts to {}",
+            journeys.len(), cars.display(), lane_series.len(), lanes.display()
+        ),
+        Err(e) => eprintln!("Failed to export metrics: {}", e),
+    }
 }
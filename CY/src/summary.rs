@@ -0,0 +1,152 @@
+// summary.rs
+//
+// End-of-run summary built from the metrics every vehicle reports when it
+// finishes, with the wait, drive and total time statistics of the trips
+// overall and per entry lane (see `rts_core::stats`). It is printed, logged as a structured event for the monitoring
+// sinks, and serializable for any other tooling that wants the numbers.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use rts_core::stats::{mean, percentile, TripReport, TripTimes};
+
+use crate::simulation::{CarMetrics, GenerationFailed};
+
+/// Highest number of vehicles a lane held at once during the run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LaneOccupancy {
+    pub lane_id: u32,
+    pub max_occupancy: u32,
+}
+
+/// Waiting accumulated by vehicles approaching a junction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunctionDelay {
+    pub junction: u32,
+    pub total_wait: f64,
+    pub mean_wait: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulationSummary {
+    pub vehicles: usize,
+    pub duration_secs: f64,
+    /// Vehicles completed per minute of simulated run time.
+    pub throughput_per_min: f64,
+    pub mean_wait: f64,
+    pub median_wait: f64,
+    pub p95_wait: f64,
+    pub mean_drive: f64,
+    /// Wait, drive and total times, overall and per entry lane.
+    #[serde(default)]
+    pub trips: TripReport,
+    /// Sorted by lane id; only lanes that were entered at least once.
+    pub lane_max_occupancy: Vec<LaneOccupancy>,
+    /// Junction with the largest total wait, if any vehicle waited at one.
+    pub most_congested_junction: Option<JunctionDelay>,
+    /// Entry/exit pairs rejected because they shared a junction or had no
+    /// route, over all vehicles; a high count points at a topology problem.
+    pub trip_redraws: u32,
+    /// Vehicles that ran out of draws and never drove.
+    pub generation_failures: usize,
+    /// Lanes left by turning right on a red light (see `rts_core::right_on_red`).
+    #[serde(default)]
+    pub right_turns_on_red: usize,
+}
+
+impl SimulationSummary {
+    pub fn from_metrics(metrics: &[CarMetrics], failures: &[GenerationFailed], duration: Duration) -> Self {
+        let mut waits: Vec<f64> = metrics.iter().map(|m| m.wait_time).collect();
+        waits.sort_by(f64::total_cmp);
+        let trip_times: Vec<TripTimes> = metrics
+            .iter()
+            .map(|m| TripTimes { entry_lane: m.entry_lane, wait: m.wait_time, drive: m.drive_time, total: m.total_time })
+            .collect();
+
+        let mut peaks: HashMap<u32, u32> = HashMap::new();
+        let mut junction_waits: HashMap<u32, (f64, u32)> = HashMap::new();
+        for visit in metrics.iter().flat_map(|m| &m.lanes) {
+            let peak = peaks.entry(visit.lane_id).or_insert(0);
+            *peak = (*peak).max(visit.occupancy);
+            if visit.junction != 0 {
+                let entry = junction_waits.entry(visit.junction).or_insert((0.0, 0));
+                entry.0 += visit.wait_time;
+                entry.1 += 1;
+            }
+        }
+        let mut lane_max_occupancy: Vec<LaneOccupancy> = peaks
+            .into_iter()
+            .map(|(lane_id, max_occupancy)| LaneOccupancy { lane_id, max_occupancy })
+            .collect();
+        lane_max_occupancy.sort_by_key(|lane| lane.lane_id);
+
+        let most_congested_junction = junction_waits
+            .into_iter()
+            .map(|(junction, (total_wait, visits))| JunctionDelay {
+                junction,
+                total_wait,
+                mean_wait: total_wait / visits as f64,
+            })
+            .filter(|delay| delay.total_wait > 0.0)
+            .max_by(|a, b| a.total_wait.total_cmp(&b.total_wait).then(b.junction.cmp(&a.junction)));
+
+        let duration_secs = duration.as_secs_f64();
+        SimulationSummary {
+            vehicles: metrics.len(),
+            duration_secs,
+            throughput_per_min: if duration_secs > 0.0 { metrics.len() as f64 * 60.0 / duration_secs } else { 0.0 },
+            mean_wait: mean(&waits),
+            median_wait: percentile(&waits, 50.0),
+            p95_wait: percentile(&waits, 95.0),
+            mean_drive: mean(&metrics.iter().map(|m| m.drive_time).collect::<Vec<_>>()),
+            trips: TripReport::from_trips(&trip_times),
+            lane_max_occupancy,
+            most_congested_junction,
+            // A failed vehicle's first draw counts as a draw, the rest as re-draws.
+            trip_redraws: metrics.iter().map(|m| m.redraws).sum::<u32>()
+                + failures.iter().map(|f| f.attempts.saturating_sub(1)).sum::<u32>(),
+            generation_failures: failures.len(),
+            right_turns_on_red: metrics.iter().flat_map(|m| &m.lanes).filter(|visit| visit.right_on_red).count(),
+        }
+    }
+}
+
+impl fmt::Display for SimulationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Simulation summary")?;
+        writeln!(f, "  Vehicles:    {} in {:.1}s ({:.1}/min)", self.vehicles, self.duration_secs, self.throughput_per_min)?;
+        writeln!(f, "{}", self.trips.overall)?;
+        writeln!(f, "  Trips:       {} re-draws, {} vehicles without a valid trip", self.trip_redraws, self.generation_failures)?;
+        if self.right_turns_on_red > 0 {
+            writeln!(f, "  Red turns:   {} right turns on red", self.right_turns_on_red)?;
+        }
+        match &self.most_congested_junction {
+            Some(delay) => writeln!(
+                f,
+                "  Congestion:  junction {} ({:.1}s total wait, {:.2}s per vehicle)",
+                delay.junction, delay.total_wait, delay.mean_wait
+            )?,
+            None => writeln!(f, "  Congestion:  no waiting at any junction")?,
+        }
+        let busiest: Vec<String> = {
+            let mut lanes: Vec<&LaneOccupancy> = self.lane_max_occupancy.iter().collect();
+            lanes.sort_by(|a, b| b.max_occupancy.cmp(&a.max_occupancy).then(a.lane_id.cmp(&b.lane_id)));
+            lanes.iter().take(5).map(|lane| format!("{}: {}", lane.lane_id, lane.max_occupancy)).collect()
+        };
+        if busiest.is_empty() {
+            write!(f, "  Peak lanes:  none")?;
+        } else {
+            write!(f, "  Peak lanes:  {}", busiest.join(", "))?;
+        }
+        if !self.trips.by_entry_lane.is_empty() {
+            write!(f, "\n  By entry lane (mean/median/p95):")?;
+            for entry in &self.trips.by_entry_lane {
+                write!(f, "\n    {}", entry)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,42 +1,221 @@
 use serde::{Serialize, Deserialize};
-use zmq;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
+use std::io::Write;
+use std::time::Instant;
+
+use rts_core::clock::SimClock;
+use crate::control;
+use crate::csv_sink::{self, CsvSink};
+use crate::dashboard::{self, Dashboard};
+use crate::envelope;
+use crate::config::Config;
+use crate::heartbeat::{HealthTracker, MISSED_HEARTBEATS};
+use crate::summary::SimulationSummary;
+use rts_core::progress::LaneTransition;
+
+/// Structured payload attached to a log event so consumers don't have to
+/// parse the free-text message. Events without a `kind` field are Generic.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type")]
+pub enum EventKind {
+    #[default]
+    Generic,
+    VehicleGenerated {
+        car_id: u32,
+        speed: f64,
+        entry_lane: u32,
+        exit_lane: u32,
+        route: Vec<u32>,
+    },
+    /// A car found no entry/exit pair with a route between them and never drove.
+    VehicleGenerationFailed {
+        car_id: u32,
+        attempts: u32,
+    },
+    CarCompleted {
+        car_id: u32,
+        entry_lane: u32,
+        exit_lane: u32,
+        route_length: f64,
+        wait_time: f64,
+        drive_time: f64,
+        total_time: f64,
+    },
+    /// A car entered or left a lane; `route_index` is the lane's place in
+    /// the trip (see `rts_core::progress`).
+    CarProgress {
+        car_id: u32,
+        lane_id: u32,
+        transition: LaneTransition,
+        route_index: usize,
+    },
+    /// A car shifted to a less busy parallel lane of `lane_id` on entering
+    /// it, or, `overtaking`, to a faster one while driving it.
+    LaneChange {
+        car_id: u32,
+        lane_id: u32,
+        from_sub_lane: usize,
+        to_sub_lane: usize,
+        #[serde(default)]
+        overtaking: bool,
+    },
+    /// A car turned right on a red light into a lane holding `occupancy`
+    /// vehicles (see `rts_core::right_on_red`).
+    RightTurnOnRed {
+        car_id: u32,
+        lane_id: u32,
+        into_lane: u32,
+        occupancy: u32,
+    },
+    PhaseChange {
+        junction: u32,
+        phase: usize,
+        green_lanes: Vec<u32>,
+        red_lanes: Vec<u32>,
+    },
+    /// End-of-run summary of the simulation.
+    Summary(SimulationSummary),
+    /// Liveness signal, sent every `heartbeat::HEARTBEAT_INTERVAL`.
+    Heartbeat {
+        component: String,
+    },
+    /// Raised by monitoring when a component stops sending heartbeats.
+    ComponentDown {
+        component: String,
+        silent_secs: f64,
+    },
+    /// A junction's controller loop panicked or stalled and was restarted;
+    /// `restarts` counts the junction's restarts so far, this one included.
+    JunctionControllerFailed {
+        junction: u32,
+        reason: String,
+        restarts: u32,
+    },
 }
 
-pub fn current_time_secs() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// A log event with CY's typed payloads.
+pub type LogEvent = rts_core::messages::LogEvent<EventKind>;
+pub use rts_core::messages::Level;
+
+/// Prints an event, or adds it to the dashboard if there is one, and writes
+/// it to the CSV export if enabled.
+fn record(log_event: &LogEvent, csv: &mut Option<CsvSink>, dashboard: &mut Option<Dashboard>) {
+    match dashboard.as_mut() {
+        Some(dashboard) => dashboard.observe(log_event),
+        None => println!("[Time: {}] {}: {}", log_event.timestamp, log_event.source, log_event.message),
+    }
+    if let Some(sink) = csv.as_mut() {
+        if let Err(e) = sink.write_event(log_event.timestamp, &log_event.kind) {
+            eprintln!("Failed to write CSV record: {}", e);
+        }
+    }
 }
 
-pub fn run_monitoring() {
+/// Runs the monitoring process. `args` are the arguments after the component
+/// name; `--csv <path>` (or RTS_CSV_PATH) enables the CSV export, which is
+/// flushed on exit, and `--dashboard` replaces the event log with a live view
+/// redrawn every `dashboard::REFRESH`. Components that stop sending heartbeats
+/// are reported as down, and the uptime of each is printed on exit. Once the
+/// simulation announces shutdown or the process is interrupted, the logs
+/// already sent are drained before it exits.
+pub fn run_monitoring(args: &[String], config: Config) {
+    let mut csv = match csv_sink::csv_path_from(args) {
+        Some(path) => match CsvSink::create(&path) {
+            Ok(sink) => {
+                println!("Writing journey and phase records to {}", path);
+                Some(sink)
+            }
+            Err(e) => {
+                eprintln!("Failed to create CSV file {}: {}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let stop = control::stop_flag(&config.ports);
+
     let context = zmq::Context::new();
+    let log_endpoint = config.ports.logs();
     let socket = context.socket(zmq::PULL).expect("Failed to create PULL socket");
-    socket.bind("tcp://*:7000").expect("Failed to bind to tcp://*:7000 for logs");
-    
-    println!("System Monitoring started. Listening for log events on tcp://*:7000");
+    socket.bind(&log_endpoint.bind).expect("Failed to bind log socket");
+    // Wake up periodically so an interrupt is noticed even when no logs arrive.
+    socket.set_rcvtimeo(500).expect("Failed to set receive timeout");
+
+    println!("System Monitoring started. Listening for log events on {}", log_endpoint.bind);
+
+    let clock = SimClock::from_env();
+    let mut health = HealthTracker::default();
+    let mut dashboard = dashboard::requested(args).then(Dashboard::default);
+    let mut last_draw = Instant::now();
 
     loop {
+        // Checked before reading, so logs sent before the stop are drained.
+        let stopping = control::is_stopped(&stop);
         // recv_string returns a Result<Option<String>, _> in some versions.
         match socket.recv_string(0) {
             Ok(Ok(json_str)) => {
-                if let Ok(log_event) = serde_json::from_str::<LogEvent>(&json_str) {
-                    println!("[Time: {}] {}: {}", log_event.timestamp, log_event.source, log_event.message);
-                } else {
-                    eprintln!("Failed to deserialize log event: {}", json_str);
+                match envelope::open::<LogEvent>(&json_str) {
+                    Ok(log_event) => {
+                        if let EventKind::Heartbeat { component } = &log_event.kind {
+                            if health.beat(component, Instant::now()) {
+                                println!("{} is sending heartbeats again", component);
+                            }
+                        } else {
+                            record(&log_event, &mut csv, &mut dashboard);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to deserialize log event {}: {}", json_str, e),
                 }
             },
             Ok(Err(e)) => {
                 // Here e is a Vec<u8>; use debug formatting.
                 eprintln!("Error receiving log event: {:?}", e);
             },
+            Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => {
+                if stopping {
+                    break;
+                }
+            }
             Err(e) => {
                 eprintln!("Socket error: {:?}", e);
             }
         }
+
+        for (component, silent) in health.check(Instant::now()) {
+            eprintln!("WARNING: {} missed {} heartbeats; no heartbeat for {:.1}s",
+                      component, MISSED_HEARTBEATS, silent.as_secs_f64());
+            let down = LogEvent {
+                source: "SystemMonitoring".to_string(),
+                message: format!("Component down: {}", component),
+                timestamp: clock.now_secs(),
+                level: Level::Warn,
+                kind: EventKind::ComponentDown { component, silent_secs: silent.as_secs_f64() },
+            };
+            record(&down, &mut csv, &mut dashboard);
+        }
+
+        if let Some(dashboard) = dashboard.as_mut() {
+            if last_draw.elapsed() >= dashboard::REFRESH {
+                dashboard.poll_lanes(&context, &config.ports);
+                print!("{}{}", dashboard::CLEAR_SCREEN, dashboard);
+                std::io::stdout().flush().ok();
+                last_draw = Instant::now();
+            }
+        }
+    }
+
+    let uptimes = health.uptimes(Instant::now());
+    if !uptimes.is_empty() {
+        println!("Component uptime:");
+        for (component, uptime, down) in uptimes {
+            println!("  {}: {:.1}s{}", component, uptime.as_secs_f64(), if down { " (down)" } else { "" });
+        }
+    }
+
+    if let Some(sink) = csv.as_mut() {
+        if let Err(e) = sink.flush() {
+            eprintln!("Failed to flush CSV file: {}", e);
+        }
     }
+    println!("Monitoring stopped.");
 }
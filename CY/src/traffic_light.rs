@@ -1,178 +1,550 @@
+use std::any::Any;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use serde::{Serialize, Deserialize};
-use zmq;
-
-use crate::lanes::{Lane, load_lanes};
-use crate::flow_analyzer::Recommendation; // use the common definition
-use crate::system_monitoring::current_time_secs;
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum LightColor {
-    Red,
-    Green,
-}
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rts_core::lane_queue::LaneQueues;
+use rts_core::lanes::{Lane, Movement, load_lanes};
+use rts_core::right_on_red;
+use rts_core::phase_plan::{build_phase_plan, Phase};
+use rts_core::watchdog::{expected_cycle_secs, PhaseWatch};
+use rts_core::network::load_network;
+use rts_core::seed;
+use crate::control::{self, StopFlag};
+use crate::config::Config;
+use crate::envelope::{self, Feed};
+use crate::heartbeat;
+use rts_core::messages::{LightSnapshot, LightStatus, LightUpdate, Recommendation};
+use crate::system_monitoring::{EventKind, Level};
+use rts_core::clock::SimClock;
 
-pub fn can_proceed_lane(lane_id: u32, lights: &HashMap<u32, LightColor>) -> bool {
+pub use rts_core::messages::LightColor;
+
+/// True if `car_id` may pass the lane's light: it is green and the car is
+/// first in the lane's queue, or the car was already in the junction when the
+/// light turned amber (see `LaneQueues::may_pass`). First in the queue and
+/// making `movement` into `into`, which holds `occupancy` vehicles, the car
+/// may also turn right on red (see `rts_core::right_on_red`).
+pub fn can_proceed_lane(
+    lane_id: u32,
+    car_id: u32,
+    movement: Movement,
+    into: &Lane,
+    occupancy: u32,
+    lights: &HashMap<u32, LightColor>,
+    queues: &LaneQueues,
+) -> bool {
     if let Some(&color) = lights.get(&lane_id) {
-        color == LightColor::Green
+        queues.may_pass(lane_id, car_id, color)
+            || (queues.is_first(lane_id, car_id) && right_on_red::may_proceed(color, movement, occupancy, into))
     } else {
         false
     }
 }
 
-fn intersection_to_coords(inter: u32) -> (f64, f64) {
-    let row = ((inter - 1) / 4) as f64;
-    let col = ((inter - 1) % 4) as f64;
-    (row, col)
-}
+pub type TrafficLightMap = Arc<Mutex<HashMap<u32, LightColor>>>;
 
-fn compute_lane_angle(lane: &Lane) -> f64 {
-    if lane.start_intersection != 0 {
-        let (sx, sy) = intersection_to_coords(lane.start_intersection);
-        let (ex, ey) = intersection_to_coords(lane.end_intersection);
-        let dx = ex - sx;
-        let dy = ey - sy;
-        let angle_rad = dy.atan2(dx);
-        let mut angle_deg = angle_rad.to_degrees();
-        if angle_deg < 0.0 {
-            angle_deg += 360.0;
-        }
-        angle_deg
-    } else {
-        let (ex, ey) = intersection_to_coords(lane.end_intersection);
-        if ex == 0.0 {
-            90.0  
-        } else if ex == 3.0 {
-            270.0 
-        } else if ey == 0.0 {
-            0.0   
-        } else if ey == 3.0 {
-            180.0 
-        } else {
-            90.0  
-        }
-    }
-}
+/// Cars waiting at each lane's light, in arrival order.
+pub type LaneQueueMap = Arc<Mutex<LaneQueues>>;
 
-fn group_lanes_by_direction(lanes: &[Lane]) -> Vec<Vec<u32>> {
-    let threshold = 20.0;
-    let mut groups: Vec<(f64, Vec<u32>)> = Vec::new();
-    
-    for lane in lanes {
-        let angle = compute_lane_angle(lane);
-        let mut added = false;
-        for group in groups.iter_mut() {
-            if (angle - group.0).abs() <= threshold {
-                group.1.push(lane.id);
-                group.0 = (group.0 * (group.1.len() as f64 - 1.0) + angle) / group.1.len() as f64;
-                added = true;
-                break;
-            }
-        }
-        if !added {
-            groups.push((angle, vec![lane.id]));
-        }
-    }
-    
-    groups.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    groups.into_iter().map(|(_avg, lanes)| lanes).collect()
+/// The lights as the cars see them: their colors and the queue at each.
+/// Lock `lights` before `queues` when holding both.
+#[derive(Clone)]
+pub struct Signals {
+    pub lights: TrafficLightMap,
+    pub queues: LaneQueueMap,
 }
 
-pub type TrafficLightMap = Arc<Mutex<HashMap<u32, LightColor>>>;
+/// Pending per-lane green durations (seconds) requested by recommendations.
+pub type GreenOverrides = Arc<Mutex<HashMap<u32, u32>>>;
+
+/// Seconds of simulated time between two full light snapshots on the lights
+/// channel, for a simulation that subscribed late or missed a change.
+const LIGHT_SNAPSHOT_INTERVAL_SECS: u64 = 3;
 
+/// Pause before a junction loop that panicked starts again.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Real time the controller waits for its junction threads after a stop
+/// before leaving without the ones that are stuck.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Locks `mutex` even if a thread panicked while holding it. Every update of
+/// the lights, queues and overrides leaves them valid, so one junction's
+/// panic must not take the other junctions and the cars down with it.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lights of every lane into a junction, red to start with, except at
+/// junctions without traffic lights, whose lanes stay green for good.
 pub fn initialize_traffic_lights() -> TrafficLightMap {
     let mut map = HashMap::new();
     let lanes = load_lanes();
+    let network = load_network();
     for lane in lanes {
         if lane.end_intersection != 0 {
-            map.insert(lane.id, LightColor::Red);
+            let color = if network.is_signalized(lane.end_intersection) { LightColor::Red } else { LightColor::Green };
+            map.insert(lane.id, color);
         }
     }
     Arc::new(Mutex::new(map))
 }
 
+/// Publishes every change sent on `changes` on the lights channel (see
+/// `endpoints`), and a snapshot of every light every
+/// LIGHT_SNAPSHOT_INTERVAL_SECS of simulated time, until `stop` is raised.
+/// Pending changes go out before each snapshot, so a snapshot is never
+/// followed by an older change.
+fn publish_lights(lights: TrafficLightMap, changes: mpsc::Receiver<LightStatus>, config: Config, stop: StopFlag, clock: SimClock) {
+    let ctx = zmq::Context::new();
+    let socket = ctx.socket(zmq::PUB).expect("Failed to create lights PUB socket");
+    socket.bind(&config.ports.lights().bind).expect("Failed to bind lights socket");
+    let send = |update: LightUpdate| {
+        let json = envelope::seal(Feed::Lights, &update);
+        if let Err(e) = socket.send(json.as_bytes(), 0) {
+            eprintln!("Failed to publish light status: {}", e);
+        }
+    };
+    let mut next_snapshot = Instant::now();
+    while !control::is_stopped(&stop) {
+        match changes.recv_timeout(config.poll_interval) {
+            Ok(status) => send(LightUpdate::Lane(status)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if Instant::now() >= next_snapshot {
+            while let Ok(status) = changes.try_recv() {
+                send(LightUpdate::Lane(status));
+            }
+            let snapshot = LightSnapshot { lights: lock(&lights).clone(), timestamp: clock.now_secs() };
+            send(LightUpdate::Snapshot(snapshot));
+            next_snapshot = Instant::now() + clock.real_duration(Duration::from_secs(LIGHT_SNAPSHOT_INTERVAL_SECS));
+        }
+    }
+}
+
 /// Runs the traffic light controller.
 /// It spawns one thread per junction and also starts a thread to listen for recommendations.
-pub fn run_traffic_lights(traffic_lights: TrafficLightMap) {
+/// Every color change is published on the lights channel, with a snapshot of
+/// all lights every LIGHT_SNAPSHOT_INTERVAL_SECS, for the simulation process.
+/// A phase with no recommendation pending stays green for `config`'s
+/// green_secs. Every green is followed by its amber_secs of amber, during
+/// which the car already in the junction on each of the phase's lanes clears
+/// it, then by its clearance_secs of all-red.
+/// A junction loop that panics logs JunctionControllerFailed and starts over,
+/// and a watchdog restarts any junction that goes STALL_CYCLES expected cycles
+/// without a phase change (see `rts_core::watchdog`).
+/// Phase and clearance intervals are simulated time on `clock`.
+/// Runs until the simulation announces shutdown or the process is
+/// interrupted (see `control`), then stops every junction thread.
+pub fn run_traffic_lights(signals: Signals, config: Config, clock: SimClock) {
+    let stop = control::stop_flag(&config.ports);
+    heartbeat::start("traffic_light", clock, &config.ports);
     let lanes = load_lanes();
+    let network = load_network();
     let mut junction_map: HashMap<u32, Vec<Lane>> = HashMap::new();
 
-    for lane in lanes {
-        if lane.end_intersection != 0 {
-            junction_map.entry(lane.end_intersection).or_default().push(lane);
+    // Junctions without traffic lights are left to the cars.
+    for lane in &lanes {
+        if lane.end_intersection != 0 && network.is_signalized(lane.end_intersection) {
+            junction_map.entry(lane.end_intersection).or_default().push(lane.clone());
         }
     }
 
+    let (changes, light_changes) = mpsc::channel();
+    let publisher_lights = Arc::clone(&signals.lights);
+    let publisher_stop = Arc::clone(&stop);
+    let publisher = thread::spawn(move || publish_lights(publisher_lights, light_changes, config, publisher_stop, clock));
+
+    // Pending green-time overrides (lane id -> seconds) from the flow analyzer.
+    // A junction thread consumes the overrides for its lanes at the start of a phase.
+    let green_overrides: GreenOverrides = Arc::new(Mutex::new(HashMap::new()));
+
     // Spawn a thread for receiving recommendations via ZeroMQ.
     let rec_context = zmq::Context::new();
     let rec_socket = rec_context.socket(zmq::PULL).expect("Failed to create recommendation PULL socket");
-    rec_socket.connect("tcp://localhost:7002").expect("Failed to connect to tcp://localhost:7002");
-    thread::spawn(move || {
-        loop {
-            if let Ok(msg) = rec_socket.recv_string(0) {
-                if let Ok(json_str) = msg {
-                    println!("✅ Received Recommendation: {}", json_str);
-                    // Here you could deserialize and act on the recommendation.
-                }
+    rec_socket.connect(&config.ports.recommendations().connect).expect("Failed to connect recommendation socket");
+    // Wake up periodically so a stop is noticed even when no recommendations arrive.
+    rec_socket.set_rcvtimeo(config.poll_interval.as_millis() as i32).expect("Failed to set receive timeout");
+    let rec_lights = signals.lights.clone();
+    let rec_overrides = green_overrides.clone();
+    let rec_stop = Arc::clone(&stop);
+    let rec_handle = thread::spawn(move || {
+        let log_socket = rec_context.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+        log_socket.connect(&config.ports.logs().connect).expect("Failed to connect log socket");
+        while !control::is_stopped(&rec_stop) {
+            if let Ok(Ok(json_str)) = rec_socket.recv_string(0) {
+                let rec = match envelope::open::<Recommendation>(&json_str) {
+                    Ok(rec) => rec,
+                    Err(e) => {
+                        eprintln!("Ignoring malformed recommendation {}: {}", json_str, e);
+                        continue;
+                    }
+                };
+                let controlled = lock(&rec_lights).contains_key(&rec.lane_id);
+                let message = if controlled {
+                    lock(&rec_overrides).insert(rec.lane_id, rec.new_green_time);
+                    format!("Recommendation accepted: lane {} green for {}s next cycle", rec.lane_id, rec.new_green_time)
+                } else {
+                    format!("Recommendation ignored: lane {} has no traffic light", rec.lane_id)
+                };
+                let log_event = crate::system_monitoring::LogEvent {
+                    source: "TrafficLightController".to_string(),
+                    message,
+                    timestamp: clock.now_secs(),
+                    level: Level::Info,
+                    kind: EventKind::Generic,
+                };
+                let log_json = envelope::seal(Feed::Logs, &log_event);
+                log_socket.send(log_json.as_bytes(), 0).expect("Failed to send log event");
             }
         }
     });
 
-    // For logging, each junction thread will create its own PUSH socket.
+    // Each junction runs in its own supervised thread; the watchdog restarts
+    // any junction that stops changing phase.
+    let seed = seed::seed_from_env();
+    let mut junctions = Vec::new();
+    let mut handles = Vec::new();
     for (junction, lane_list) in junction_map.into_iter() {
-        let groups = group_lanes_by_direction(&lane_list);
-        let tl_clone = traffic_lights.clone();
-        
-        thread::spawn(move || {
-            // Create a new ZeroMQ context (or reuse one if desired) for this thread.
-            let ctx = zmq::Context::new();
-            let log_socket = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
-            log_socket.connect("tcp://localhost:7000").expect("Failed to connect to tcp://localhost:7000");
-            let mut group_index = 0;
-            loop {
-                let mut green_lanes = Vec::new();
-                let mut red_lanes = Vec::new();
-
-                {
-                    let mut lights = tl_clone.lock().unwrap();
-                    for lane in &lane_list {
-                        if groups[group_index].contains(&lane.id) {
-                            lights.insert(lane.id, LightColor::Green);
-                            green_lanes.push(lane.id);
-                        } else {
-                            lights.insert(lane.id, LightColor::Red);
-                            red_lanes.push(lane.id);
-                        }
+        let phases = build_phase_plan(junction, &lanes, &network);
+        let cycle_secs = expected_cycle_secs(phases.len(), config.green_secs as u64, config.amber_secs + config.clearance_secs);
+        let control = JunctionControl {
+            junction,
+            lanes: lane_list,
+            first_phase: seed::phase_offset(seed, junction, phases.len()),
+            phases,
+            signals: signals.clone(),
+            config,
+            overrides: green_overrides.clone(),
+            changes: changes.clone(),
+            watch: Arc::new(Mutex::new(PhaseWatch::new(cycle_secs, clock.now_secs()))),
+            generation: Arc::default(),
+            restarts: Arc::default(),
+            stop: Arc::clone(&stop),
+            clock,
+        };
+        handles.push(spawn_junction(control.clone()));
+        junctions.push(control);
+    }
+    let watch_stop = Arc::clone(&stop);
+    let watchdog = thread::spawn(move || watch_junctions(junctions, &watch_stop, config, clock));
+
+    while !control::is_stopped(&stop) {
+        thread::sleep(config.poll_interval);
+    }
+    rec_handle.join().ok();
+    publisher.join().ok();
+    handles.extend(watchdog.join().unwrap_or_default());
+    // Each loop stops at its next check; one that is stuck is left behind.
+    let deadline = Instant::now() + JOIN_TIMEOUT;
+    while handles.iter().any(|handle| !handle.is_finished()) && Instant::now() < deadline {
+        thread::sleep(config.poll_interval);
+    }
+    let stuck = handles.iter().filter(|handle| !handle.is_finished()).count();
+    if stuck > 0 {
+        eprintln!("{} junction thread(s) did not stop in time", stuck);
+    }
+    println!("Traffic light controller stopped.");
+}
+
+/// Everything a junction's controller loop works with. Clones share the
+/// lights, the watch and the counters, so a restarted loop picks up where
+/// the failed one left off.
+#[derive(Clone)]
+struct JunctionControl {
+    junction: u32,
+    lanes: Vec<Lane>,
+    phases: Vec<Phase>,
+    /// Phase the cycle starts at; see `rts_core::seed::phase_offset`.
+    first_phase: usize,
+    signals: Signals,
+    /// Default green, amber and clearance times, poll interval and ports.
+    config: Config,
+    overrides: GreenOverrides,
+    /// Where every color change goes, for the lights channel.
+    changes: mpsc::Sender<LightStatus>,
+    watch: Arc<Mutex<PhaseWatch>>,
+    /// Bumped by the watchdog to retire a stalled loop when it replaces it.
+    generation: Arc<AtomicU32>,
+    restarts: Arc<AtomicU32>,
+    stop: StopFlag,
+    clock: SimClock,
+}
+
+impl JunctionControl {
+    /// True once the watchdog has replaced the loop started at `generation`,
+    /// or the controller is stopping.
+    fn retired(&self, generation: u32) -> bool {
+        self.generation.load(Ordering::SeqCst) != generation || control::is_stopped(&self.stop)
+    }
+
+    /// Sleeps for `sim` of simulated time; false if the loop was retired meanwhile.
+    fn sleep(&self, sim: Duration, generation: u32) -> bool {
+        control::sleep_or_stop(&self.clock, sim, &self.stop, self.config.poll_interval) && !self.retired(generation)
+    }
+
+    /// Sets `lane_id`'s light in `lights` and passes the change on to the
+    /// lights channel.
+    fn show(&self, lights: &mut HashMap<u32, LightColor>, lane_id: u32, color: LightColor) {
+        lights.insert(lane_id, color);
+        self.changes.send(LightStatus { lane_id, status: color }).ok();
+    }
+
+    /// Cycles through the junction's phases until the loop is retired.
+    fn cycle(&self, generation: u32, log_socket: &zmq::Socket) {
+        let clock = self.clock;
+        let junction = self.junction;
+        let mut group_index = self.first_phase;
+        while !self.retired(generation) {
+            #[cfg(test)]
+            tests::injected_panic(junction);
+            let phase = &self.phases[group_index];
+            let mut green_lanes = Vec::new();
+            let mut red_lanes = Vec::new();
+
+            {
+                let mut lights = lock(&self.signals.lights);
+                for lane in &self.lanes {
+                    if phase.lanes.contains(&lane.id) {
+                        self.show(&mut lights, lane.id, LightColor::Green);
+                        green_lanes.push(lane.id);
+                    } else {
+                        self.show(&mut lights, lane.id, LightColor::Red);
+                        red_lanes.push(lane.id);
                     }
                 }
+            }
 
-                let log_event = crate::system_monitoring::LogEvent {
-                    source: format!("Junction-{}", junction),
-                    message: format!("Phase {} active: Green lanes {:?}, Red lanes {:?}", group_index, green_lanes, red_lanes),
-                    timestamp: current_time_secs(),
-                };
-                let log_json = serde_json::to_string(&log_event).unwrap();
-                log_socket.send(log_json.as_bytes(), 0).expect("Failed to send log event");
+            // A recommendation for any lane in this phase stretches the whole
+            // phase's green; the override is consumed so it applies only once.
+            let green_secs = {
+                let mut overrides = lock(&self.overrides);
+                phase.lanes
+                    .iter()
+                    .filter_map(|lane_id| overrides.remove(lane_id))
+                    .max()
+                    .unwrap_or(self.config.green_secs)
+            };
+            lock(&self.watch).phase_started(clock.now_secs(), green_secs as u64 + self.config.amber_secs + self.config.clearance_secs);
+
+            let log_event = crate::system_monitoring::LogEvent {
+                source: format!("Junction-{}", junction),
+                message: format!("Phase {} active for {}s: Green lanes {:?}, Red lanes {:?}", group_index, green_secs, green_lanes, red_lanes),
+                timestamp: clock.now_secs(),
+                level: Level::Info,
+                kind: EventKind::PhaseChange {
+                    junction,
+                    phase: group_index,
+                    green_lanes,
+                    red_lanes,
+                },
+            };
+            let log_json = envelope::seal(Feed::Logs, &log_event);
+            log_socket.send(log_json.as_bytes(), 0).expect("Failed to send log event");
+
+            if !self.sleep(Duration::from_secs(green_secs as u64), generation) {
+                return;
+            }
+
+            // Amber: cars already in the junction clear it, the rest stop.
+            {
+                let mut lights = lock(&self.signals.lights);
+                let mut queues = lock(&self.signals.queues);
+                for &lane_id in &phase.lanes {
+                    self.show(&mut lights, lane_id, LightColor::Amber);
+                    queues.light_turned_amber(lane_id);
+                }
+            }
+            if !self.sleep(Duration::from_secs(self.config.amber_secs), generation) {
+                return;
+            }
 
-                thread::sleep(Duration::from_secs(5));
+            {
+                let mut lights = lock(&self.signals.lights);
+                for lane in &self.lanes {
+                    self.show(&mut lights, lane.id, LightColor::Red);
+                }
+            }
+            if !self.sleep(Duration::from_secs(self.config.clearance_secs), generation) {
+                return;
+            }
 
-                {
-                    let mut lights = tl_clone.lock().unwrap();
-                    for lane in &lane_list {
-                        lights.insert(lane.id, LightColor::Red);
+            group_index = (group_index + 1) % self.phases.len();
+        }
+    }
+
+    /// Logs a JunctionControllerFailed event on `log_socket` and counts the restart.
+    fn report_failure(&self, reason: String, log_socket: &zmq::Socket) {
+        let restarts = self.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+        let log_event = crate::system_monitoring::LogEvent {
+            source: format!("Junction-{}", self.junction),
+            message: format!("Controller failed ({}); restart {}", reason, restarts),
+            timestamp: self.clock.now_secs(),
+            level: Level::Error,
+            kind: EventKind::JunctionControllerFailed { junction: self.junction, reason, restarts },
+        };
+        log_socket.send(envelope::seal(Feed::Logs, &log_event).as_bytes(), 0).ok();
+    }
+}
+
+/// Starts a thread running `control`'s loop, restarting the loop whenever it
+/// panics, until the watchdog retires it or the controller stops.
+fn spawn_junction(control: JunctionControl) -> JoinHandle<()> {
+    let generation = control.generation.load(Ordering::SeqCst);
+    thread::spawn(move || {
+        let ctx = zmq::Context::new();
+        let log_socket = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+        log_socket.connect(&control.config.ports.logs().connect).expect("Failed to connect log socket");
+        loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| control.cycle(generation, &log_socket))) {
+                Ok(()) => return,
+                Err(payload) => {
+                    control.report_failure(format!("panicked: {}", panic_message(&*payload)), &log_socket);
+                    if !control.sleep(RESTART_DELAY, generation) {
+                        return;
                     }
                 }
-                thread::sleep(Duration::from_secs(10));
+            }
+        }
+    })
+}
 
-                group_index = (group_index + 1) % groups.len();
+/// Restarts, in a fresh thread, every junction that has stopped changing
+/// phase; the stalled thread stops on its own if it ever wakes up. Returns
+/// the threads it started once `stop` is raised.
+fn watch_junctions(junctions: Vec<JunctionControl>, stop: &StopFlag, config: Config, clock: SimClock) -> Vec<JoinHandle<()>> {
+    let ctx = zmq::Context::new();
+    let log_socket = ctx.socket(zmq::PUSH).expect("Failed to create log PUSH socket");
+    log_socket.connect(&config.ports.logs().connect).expect("Failed to connect log socket");
+    let mut restarted = Vec::new();
+    while control::sleep_or_stop(&clock, Duration::from_secs(1), stop, config.poll_interval) {
+        for control in &junctions {
+            let now = clock.now_secs();
+            let stalled = {
+                let mut watch = lock(&control.watch);
+                let stalled = watch.stalled(now);
+                if stalled.is_some() {
+                    // Give the new loop a full limit to make its first change.
+                    watch.phase_started(now, 0);
+                }
+                stalled
+            };
+            if let Some(silent) = stalled {
+                control.generation.fetch_add(1, Ordering::SeqCst);
+                control.report_failure(format!("no phase change for {}s", silent), &log_socket);
+                restarted.push(spawn_junction(control.clone()));
             }
-        });
+        }
     }
+    restarted
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    use crate::endpoints::Ports;
+    use crate::system_monitoring::LogEvent;
+
+    /// Junctions whose loop panics the next time it starts a phase.
+    static PANIC_AT: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    /// Panics once for each time `junction` was queued in PANIC_AT.
+    pub(super) fn injected_panic(junction: u32) {
+        let mut pending = lock(&PANIC_AT);
+        if let Some(position) = pending.iter().position(|&queued| queued == junction) {
+            pending.remove(position);
+            drop(pending);
+            panic!("injected failure at junction {}", junction);
+        }
+    }
+
+    /// A controller loop for `junction`, logging to `config`'s logs port.
+    fn control(junction: u32, signals: &Signals, config: Config, changes: &mpsc::Sender<LightStatus>, stop: &StopFlag, clock: SimClock) -> JunctionControl {
+        let (lanes, network) = (load_lanes(), load_network());
+        let phases = build_phase_plan(junction, &lanes, &network);
+        let cycle_secs = expected_cycle_secs(phases.len(), config.green_secs as u64, config.amber_secs + config.clearance_secs);
+        JunctionControl {
+            junction,
+            lanes: lanes.into_iter().filter(|lane| lane.end_intersection == junction).collect(),
+            phases,
+            first_phase: 0,
+            signals: signals.clone(),
+            config,
+            overrides: Arc::default(),
+            changes: changes.clone(),
+            watch: Arc::new(Mutex::new(PhaseWatch::new(cycle_secs, clock.now_secs()))),
+            generation: Arc::default(),
+            restarts: Arc::default(),
+            stop: Arc::clone(stop),
+            clock,
+        }
+    }
+
+    #[test]
+    fn panicking_junction_restarts_while_the_others_keep_cycling() {
+        // Ports of their own, clear of a simulation running alongside.
+        let config = Config {
+            ports: Ports { offset: 911, ..Ports::default() },
+            green_secs: 2,
+            amber_secs: 1,
+            clearance_secs: 1,
+            poll_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let ctx = zmq::Context::new();
+        let logs = ctx.socket(zmq::PULL).unwrap();
+        logs.bind(&config.ports.logs().bind).unwrap();
+        logs.set_rcvtimeo(100).unwrap();
+
+        let network = load_network();
+        let junctions: Vec<u32> = network.intersections().filter(|&junction| network.is_signalized(junction)).take(3).collect();
+        let failing = junctions[0];
+        lock(&PANIC_AT).push(failing);
+
+        let signals = Signals { lights: initialize_traffic_lights(), queues: Arc::default() };
+        let (changes, _light_changes) = mpsc::channel();
+        let stop: StopFlag = Arc::new(AtomicBool::new(false));
+        let clock = SimClock::new(20.0);
+        let controls: Vec<JunctionControl> =
+            junctions.iter().map(|&junction| control(junction, &signals, config, &changes, &stop, clock)).collect();
+        let handles: Vec<JoinHandle<()>> = controls.iter().cloned().map(spawn_junction).collect();
+
+        // Phase changes per junction, and the failures reported for it.
+        let mut phase_changes: HashMap<u32, u32> = HashMap::new();
+        let mut failures = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while junctions.iter().any(|junction| phase_changes.get(junction).copied().unwrap_or(0) < 3) {
+            assert!(Instant::now() < deadline, "junctions stopped cycling: {:?}", phase_changes);
+            let Ok(Ok(json)) = logs.recv_string(0) else { continue };
+            match envelope::open::<LogEvent>(&json).unwrap().kind {
+                EventKind::PhaseChange { junction, .. } => *phase_changes.entry(junction).or_default() += 1,
+                EventKind::JunctionControllerFailed { junction, reason, restarts } => failures.push((junction, reason, restarts)),
+                _ => {}
+            }
+        }
+        stop.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
 
-    loop {
-        thread::sleep(Duration::from_secs(1));
+        assert_eq!(failures, [(failing, format!("panicked: injected failure at junction {}", failing), 1)]);
+        assert_eq!(controls.iter().map(|control| control.restarts.load(Ordering::SeqCst)).collect::<Vec<_>>(), [1, 0, 0]);
     }
 }
@@ -0,0 +1,18 @@
+// build.rs
+//
+// With the `grpc` feature, generates the traffic light control plane from
+// proto/traffic_light.proto with a vendored protoc, so no system protoc is
+// needed. Without it there is nothing to do.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/traffic_light.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/traffic_light.proto"], &["proto"])
+            .expect("failed to compile proto/traffic_light.proto");
+    }
+}
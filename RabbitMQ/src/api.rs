@@ -0,0 +1,395 @@
+// api.rs
+//
+// HTTP API for dashboards that poll the simulation. With the `api` feature
+// and RTS_API_PORT set, system monitoring keeps the live state of the run,
+// fed from the logs it already consumes and from the "simulation.updates"
+// and "light_status" feeds, and serves it as JSON at http://0.0.0.0:<port>:
+//
+//   /lanes      vehicles on every lane
+//   /lights     color of every light
+//   /cars/{id}  estimated position and route of a car on the grid; 404 once
+//               it has left or if it never entered
+//   /stats      vehicles generated, completed and on the grid, and the trip
+//               statistics of the completed ones
+//   /events     WebSocket stream of the same feeds as they arrive, and of the
+//               car positions on "car_positions" (see `event_stream`)
+//   /network.geojson
+//               the lanes and junctions as a GeoJSON FeatureCollection, each
+//               lane with its vehicles, occupancy and light color, for
+//               kepler.gl or QGIS (see `rts_core::geojson`)
+//
+// Positions are estimated as for `--positions` (see `rts_core::progress`).
+// The state starts empty, so a dashboard polling before the first snapshots
+// arrive sees only the lanes and lights that have changed since.
+//
+// Without the `api` feature nothing is served, and most of this is unused.
+#![cfg_attr(not(feature = "api"), allow(dead_code))]
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use futures_util::stream::StreamExt;
+use serde::Serialize;
+
+use rts_core::geojson;
+use rts_core::lanes::load_lanes;
+use rts_core::messages::{CarPositions, LightColor, LightUpdate, SimulationUpdate};
+use tokio::sync::broadcast;
+use rts_core::network_file;
+use rts_core::progress::PositionEstimator;
+use rts_core::stats::{TripReport, TripTimes};
+
+use crate::bus::BusResult;
+use crate::clock::SimClock;
+use crate::event_stream::{Filter, FilterParams, Frame, FRAME_BUFFER};
+use crate::events::{EventKind, LogEvent};
+use crate::mq::{self, MqChannel};
+
+/// Vehicles on one lane.
+#[derive(Debug, Serialize)]
+struct LaneState {
+    lane_id: u32,
+    vehicles: u32,
+}
+
+/// Color of one lane's light.
+#[derive(Debug, Serialize)]
+struct LightState {
+    lane_id: u32,
+    color: LightColor,
+}
+
+/// The trip a car was generated with.
+#[derive(Debug, Clone)]
+struct Trip {
+    emergency: bool,
+    entry_lane: u32,
+    exit_lane: u32,
+    route: Vec<u32>,
+}
+
+/// A car on the grid, as served on /cars/{id}.
+#[derive(Debug, Serialize)]
+struct CarState {
+    car_id: u32,
+    emergency: bool,
+    entry_lane: u32,
+    exit_lane: u32,
+    route: Vec<u32>,
+    /// Lane the car last entered.
+    lane_id: u32,
+    /// Place of that lane in the trip, 0 being the entry lane.
+    route_index: usize,
+    /// Estimated meters along the lane; None if the car's speed is unknown.
+    distance: Option<f64>,
+    lane_length: f64,
+}
+
+/// Counts of the run so far, as served on /stats.
+#[derive(Debug, Serialize)]
+struct Stats {
+    timestamp: u64,
+    cars_generated: u64,
+    cars_completed: u64,
+    cars_on_grid: usize,
+    vehicles_on_lanes: u32,
+    trips: TripReport,
+}
+
+/// Everything the API serves.
+#[derive(Default)]
+struct Tracked {
+    lanes: BTreeMap<u32, u32>,
+    lights: BTreeMap<u32, LightColor>,
+    positions: PositionEstimator,
+    trips: HashMap<u32, Trip>,
+    cars_generated: u64,
+    cars_completed: u64,
+    completed: Vec<TripTimes>,
+}
+
+/// The live state of the run, shared between system monitoring, the feeds
+/// and the HTTP server, and the stream of everything feeding it. Clones
+/// share the same state.
+#[derive(Clone)]
+pub struct LiveState {
+    tracked: Arc<Mutex<Tracked>>,
+    frames: broadcast::Sender<Frame>,
+    /// Junction each lane leads into, for the stream's filters.
+    lane_junctions: Arc<HashMap<u32, u32>>,
+    clock: SimClock,
+}
+
+impl LiveState {
+    fn new(clock: SimClock) -> LiveState {
+        let lanes = load_lanes();
+        let tracked = Tracked { positions: PositionEstimator::new(&lanes), ..Tracked::default() };
+        let lane_junctions = lanes.iter().map(|lane| (lane.id, lane.end_intersection)).collect();
+        LiveState {
+            tracked: Arc::new(Mutex::new(tracked)),
+            frames: broadcast::channel(FRAME_BUFFER).0,
+            lane_junctions: Arc::new(lane_junctions),
+            clock,
+        }
+    }
+
+    /// Sends `frame` to the stream's subscribers, if there are any.
+    fn push(&self, frame: impl FnOnce() -> Frame) {
+        if self.frames.receiver_count() > 0 {
+            self.frames.send(frame()).ok();
+        }
+    }
+
+    /// Follows the cars through a log event and streams it.
+    pub fn observe(&self, log: &LogEvent) {
+        self.push(|| Frame::Log(log.clone()));
+        let mut tracked = self.tracked.lock().unwrap();
+        match &log.kind {
+            EventKind::VehicleGenerated { car_id, emergency, speed, entry_lane, exit_lane, route } => {
+                tracked.cars_generated += 1;
+                tracked.positions.generated(*car_id, *speed);
+                let trip = Trip { emergency: *emergency, entry_lane: *entry_lane, exit_lane: *exit_lane, route: route.clone() };
+                tracked.trips.insert(*car_id, trip);
+            }
+            EventKind::CarProgress { car_id, lane_id, transition, route_index } => {
+                tracked.positions.progress(*car_id, *lane_id, *transition, *route_index, log.timestamp);
+            }
+            EventKind::CarCompleted { car_id, entry_lane, wait_time, drive_time, total_time, .. } => {
+                tracked.cars_completed += 1;
+                tracked.positions.forget(*car_id);
+                tracked.trips.remove(car_id);
+                tracked.completed.push(TripTimes { entry_lane: *entry_lane, wait: *wait_time, drive: *drive_time, total: *total_time });
+            }
+            _ => {}
+        }
+    }
+
+    fn observe_update(&self, update: SimulationUpdate) {
+        self.push(|| match &update {
+            SimulationUpdate::Lane(update) => Frame::Lane(update.clone()),
+            SimulationUpdate::Snapshot(snapshot) => Frame::LaneSnapshot(snapshot.clone()),
+            SimulationUpdate::Changes(changes) => Frame::LaneChanges(changes.clone()),
+        });
+        let mut tracked = self.tracked.lock().unwrap();
+        match update {
+            SimulationUpdate::Lane(update) => {
+                tracked.lanes.insert(update.lane_id, update.vehicle_count);
+            }
+            SimulationUpdate::Snapshot(snapshot) => tracked.lanes = snapshot.lanes.into_iter().collect(),
+            SimulationUpdate::Changes(changes) => tracked.lanes.extend(changes.changed),
+        }
+    }
+
+    fn observe_light(&self, update: LightUpdate) {
+        self.push(|| match &update {
+            LightUpdate::Lane(status) => Frame::Light(status.clone()),
+            LightUpdate::Snapshot(snapshot) => Frame::LightSnapshot(snapshot.clone()),
+        });
+        let mut tracked = self.tracked.lock().unwrap();
+        match update {
+            LightUpdate::Lane(status) => {
+                tracked.lights.insert(status.lane_id, status.status);
+            }
+            LightUpdate::Snapshot(snapshot) => tracked.lights.extend(snapshot.lights),
+        }
+    }
+
+    /// Streams the car positions; the API serves its own estimates on /cars.
+    fn observe_positions(&self, update: CarPositions) {
+        self.push(|| Frame::CarPositions(update));
+    }
+
+    fn lanes(&self) -> Vec<LaneState> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked.lanes.iter().map(|(&lane_id, &vehicles)| LaneState { lane_id, vehicles }).collect()
+    }
+
+    fn lights(&self) -> Vec<LightState> {
+        let tracked = self.tracked.lock().unwrap();
+        tracked.lights.iter().map(|(&lane_id, &color)| LightState { lane_id, color }).collect()
+    }
+
+    /// The network with the lane counts and light colors seen so far.
+    fn map(&self) -> serde_json::Value {
+        let tracked = self.tracked.lock().unwrap();
+        geojson::feature_collection(
+            network_file::loaded(),
+            |lane_id| tracked.lanes.get(&lane_id).copied(),
+            |lane_id| tracked.lights.get(&lane_id).copied(),
+        )
+    }
+
+    fn car(&self, car_id: u32) -> Option<CarState> {
+        let tracked = self.tracked.lock().unwrap();
+        let position = tracked.positions.position(car_id, self.clock.now_secs())?;
+        let trip = tracked.trips.get(&car_id)?.clone();
+        Some(CarState {
+            car_id,
+            emergency: trip.emergency,
+            entry_lane: trip.entry_lane,
+            exit_lane: trip.exit_lane,
+            route: trip.route,
+            lane_id: position.lane_id,
+            route_index: position.route_index,
+            distance: position.distance,
+            lane_length: position.lane_length,
+        })
+    }
+
+    /// A subscriber's filter and its end of the stream.
+    fn subscribe(&self, params: FilterParams) -> Result<(Filter, broadcast::Receiver<Frame>), String> {
+        let filter = Filter::new(params, Arc::clone(&self.lane_junctions))?;
+        Ok((filter, self.frames.subscribe()))
+    }
+
+    fn stats(&self) -> Stats {
+        let tracked = self.tracked.lock().unwrap();
+        Stats {
+            timestamp: self.clock.now_secs(),
+            cars_generated: tracked.cars_generated,
+            cars_completed: tracked.cars_completed,
+            cars_on_grid: tracked.positions.len(),
+            vehicles_on_lanes: tracked.lanes.values().sum(),
+            trips: TripReport::from_trips(&tracked.completed),
+        }
+    }
+}
+
+/// Port from RTS_API_PORT, if it is set (with a warning if it is invalid).
+fn port_from_env() -> Option<u16> {
+    let value = std::env::var("RTS_API_PORT").ok()?;
+    match value.trim().parse() {
+        Ok(port) => Some(port),
+        Err(_) => {
+            eprintln!("Ignoring RTS_API_PORT: invalid port '{}'", value.trim());
+            None
+        }
+    }
+}
+
+/// Passes every message on `exchange` that parses as a `T` to `handle`
+/// until the feed ends.
+async fn follow<T: serde::de::DeserializeOwned>(
+    mq: MqChannel,
+    exchange: &str,
+    consumer_tag: &str,
+    handle: impl Fn(T),
+) -> BusResult<()> {
+    let mut consumer = mq.subscribe(exchange, consumer_tag).await?;
+    while let Some(delivery_result) = consumer.next().await {
+        let payload = delivery_result?;
+        match serde_json::from_slice::<T>(&payload) {
+            Ok(message) => handle(message),
+            Err(e) => eprintln!("Ignoring malformed message on '{}': {}", exchange, e),
+        }
+    }
+    Ok(())
+}
+
+/// Starts the API on RTS_API_PORT, if it is set: follows the lane counts, the
+/// lights and the car positions in background tasks and serves the state they and the logs
+/// passed to `LiveState::observe` build up. None if no port is set or the
+/// bin was built without the `api` feature.
+pub async fn start(mq: &MqChannel, clock: SimClock) -> Option<LiveState> {
+    let port = port_from_env()?;
+    if !cfg!(feature = "api") {
+        eprintln!("API requested but system monitoring was built without the api feature");
+        return None;
+    }
+    mq::declare_exchange(mq, mq::SIMULATION_UPDATES).await;
+    mq::declare_exchange(mq, mq::LIGHT_STATUS).await;
+    mq::declare_exchange(mq, mq::CAR_POSITIONS).await;
+    let live = LiveState::new(clock);
+
+    let updates = live.clone();
+    let updates_mq = mq.clone();
+    tokio::spawn(async move {
+        let handle = |update| updates.observe_update(update);
+        if let Err(e) = follow(updates_mq, mq::SIMULATION_UPDATES, "system_monitoring_api_lanes", handle).await {
+            eprintln!("Lost the lane counts; /lanes keeps the last ones: {}", e);
+        }
+    });
+    let lights = live.clone();
+    let lights_mq = mq.clone();
+    tokio::spawn(async move {
+        let handle = |update| lights.observe_light(update);
+        if let Err(e) = follow(lights_mq, mq::LIGHT_STATUS, "system_monitoring_api_lights", handle).await {
+            eprintln!("Lost the light status; /lights keeps the last colors: {}", e);
+        }
+    });
+    let positions = live.clone();
+    let positions_mq = mq.clone();
+    tokio::spawn(async move {
+        let handle = |update| positions.observe_positions(update);
+        if let Err(e) = follow(positions_mq, mq::CAR_POSITIONS, "system_monitoring_api_positions", handle).await {
+            eprintln!("Lost the car positions; /events streams no more of them: {}", e);
+        }
+    });
+    serve(port, live.clone());
+    Some(live)
+}
+
+/// Serves `live` on `port` in a background task.
+#[cfg(feature = "api")]
+fn serve(port: u16, live: LiveState) {
+    use axum::extract::ws::WebSocketUpgrade;
+    use axum::extract::{Path, Query};
+    use axum::http::{header, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+
+    fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_string(value) {
+            Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+
+    let (lanes, lights, cars, stats, map) = (live.clone(), live.clone(), live.clone(), live.clone(), live.clone());
+    let events = live;
+    let app = axum::Router::new()
+        .route("/lanes", get(move || async move { json(&lanes.lanes()) }))
+        .route("/lights", get(move || async move { json(&lights.lights()) }))
+        .route(
+            "/cars/{id}",
+            get(move |Path(car_id): Path<u32>| async move {
+                match cars.car(car_id) {
+                    Some(car) => json(&car),
+                    None => (StatusCode::NOT_FOUND, format!("car {} is not on the grid", car_id)).into_response(),
+                }
+            }),
+        )
+        .route("/stats", get(move || async move { json(&stats.stats()) }))
+        .route(
+            "/network.geojson",
+            get(move || async move {
+                ([(header::CONTENT_TYPE, "application/geo+json")], map.map().to_string()).into_response()
+            }),
+        )
+        .route(
+            "/events",
+            get(move |upgrade: WebSocketUpgrade, Query(params): Query<FilterParams>| async move {
+                match events.subscribe(params) {
+                    Ok((filter, frames)) => {
+                        upgrade.on_upgrade(move |socket| crate::event_stream::stream(socket, frames, filter)).into_response()
+                    }
+                    Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+                }
+            }),
+        );
+    tokio::spawn(async move {
+        let served = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => {
+                println!("Serving the live simulation state on http://0.0.0.0:{}", port);
+                axum::serve(listener, app).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = served {
+            eprintln!("API on port {} stopped: {}", port, e);
+        }
+    });
+}
+
+#[cfg(not(feature = "api"))]
+fn serve(_port: u16, _live: LiveState) {}
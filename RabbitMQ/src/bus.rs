@@ -0,0 +1,354 @@
+// bus.rs
+//
+// The broker behind an MqChannel. Every exchange is a fanout: each message
+// published on it reaches every subscriber bound at the time. MessageBus is
+// that much of a broker, with two implementations:
+//
+//   rabbitmq  (default) lapin, AMQP_ADDR; a topic is a fanout exchange and
+//             each subscription a server-named queue bound to it
+//   kafka     rdkafka, KAFKA_BROKERS (default localhost:9092); a topic is a
+//             Kafka topic and each subscription a consumer group of its own
+//             reading from the latest offset. Needs the `kafka` feature.
+//
+// `--transport <name>` or RTS_TRANSPORT picks one; every bin of a run must
+// use the same. Topics are created when they are declared. A Kafka consumer
+// only receives what is published once its group has been assigned the
+// topic, so a subscriber may miss the first messages after it subscribes.
+//
+// Every bin includes this module, and each uses a different part of it.
+#![allow(dead_code)]
+
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use lapin::{options::*, types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+use tokio::sync::Mutex;
+
+/// Why a broker operation failed.
+#[derive(Debug)]
+pub struct BusError(pub String);
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<lapin::Error> for BusError {
+    fn from(e: lapin::Error) -> Self {
+        BusError(e.to_string())
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl From<rdkafka::error::KafkaError> for BusError {
+    fn from(e: rdkafka::error::KafkaError) -> Self {
+        BusError(e.to_string())
+    }
+}
+
+pub type BusResult<T> = Result<T, BusError>;
+
+/// The payloads of the messages a subscription receives, in order.
+pub type Messages = BoxStream<'static, BusResult<Vec<u8>>>;
+
+/// A fanout broker.
+pub trait MessageBus: Send + Sync {
+    /// Creates `topic` if it does not exist yet.
+    fn declare<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, BusResult<()>>;
+
+    /// Publishes `payload` on `topic` and waits until the broker has taken it.
+    fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8]) -> BoxFuture<'a, BusResult<()>>;
+
+    /// Every message published on `topic` from now on. `name` tells the
+    /// subscription apart in the broker's tools.
+    fn subscribe<'a>(&'a self, topic: &'a str, name: &'a str) -> BoxFuture<'a, BusResult<Messages>>;
+
+    /// As `subscribe`, but the broker sends at most `window` messages ahead
+    /// of the ones taken from the stream, so a slow subscriber holds back
+    /// the broker instead of buffering without bound. Brokers the
+    /// subscriber pulls from already work this way.
+    fn subscribe_windowed<'a>(&'a self, topic: &'a str, name: &'a str, window: u16) -> BoxFuture<'a, BusResult<Messages>> {
+        let _ = window;
+        self.subscribe(topic, name)
+    }
+}
+
+/// The brokers a run can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    RabbitMq,
+    Kafka,
+}
+
+impl Transport {
+    pub fn parse(value: &str) -> Result<Transport, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "rabbitmq" | "amqp" => Ok(Transport::RabbitMq),
+            "kafka" => Ok(Transport::Kafka),
+            other => Err(format!("unknown transport '{}'; expected rabbitmq or kafka", other)),
+        }
+    }
+
+    /// Transport from `--transport <name>` (or `--transport=<name>`), else
+    /// from RTS_TRANSPORT; RabbitMQ, with a warning if it is invalid.
+    pub fn from_env() -> Transport {
+        let args: Vec<String> = std::env::args().collect();
+        let flag = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--transport") {
+            Some("") => Some(("--transport", args.get(i + 1).cloned().unwrap_or_default())),
+            Some(value) => value.strip_prefix('=').map(|value| ("--transport", value.to_string())),
+            None => None,
+        });
+        let (source, value) = match flag {
+            Some(flag) => flag,
+            None => match std::env::var("RTS_TRANSPORT") {
+                Ok(value) => ("RTS_TRANSPORT", value),
+                Err(_) => return Transport::RabbitMq,
+            },
+        };
+        Transport::parse(&value).unwrap_or_else(|e| {
+            eprintln!("Ignoring {}: {}", source, e);
+            Transport::RabbitMq
+        })
+    }
+
+    /// Connects to the transport's broker, once.
+    pub async fn connect(self) -> BusResult<Box<dyn MessageBus>> {
+        match self {
+            Transport::RabbitMq => {
+                let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
+                Ok(Box::new(AmqpBus::connect(addr).await?))
+            }
+            #[cfg(feature = "kafka")]
+            Transport::Kafka => {
+                let brokers = std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".into());
+                Ok(Box::new(kafka::KafkaBus::connect(brokers).await?))
+            }
+            #[cfg(not(feature = "kafka"))]
+            Transport::Kafka => Err(BusError("Kafka requested but the bins were built without the kafka feature".into())),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::RabbitMq => "RabbitMQ",
+            Transport::Kafka => "Kafka",
+        })
+    }
+}
+
+/// RabbitMQ through lapin. When lapin reports the channel closed (broker
+/// restart, TCP reset) the connection is re-established and the exchanges
+/// declared so far are declared again.
+pub struct AmqpBus {
+    addr: String,
+    channel: Mutex<Channel>,
+    /// Exchanges to declare again on a fresh connection.
+    exchanges: Mutex<Vec<String>>,
+}
+
+/// Opens a connection and a channel with publisher confirms enabled.
+async fn open_channel(addr: &str) -> lapin::Result<Channel> {
+    let properties = ConnectionProperties::default().with_executor(tokio_executor_trait::Tokio::current());
+    #[cfg(unix)]
+    let properties = properties.with_reactor(tokio_reactor_trait::Tokio);
+    let connection = Connection::connect(addr, properties).await?;
+    let channel = connection.create_channel().await?;
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+    Ok(channel)
+}
+
+async fn declare_fanout(channel: &Channel, exchange: &str) -> lapin::Result<()> {
+    channel.exchange_declare(exchange, ExchangeKind::Fanout, ExchangeDeclareOptions::default(), FieldTable::default()).await
+}
+
+impl AmqpBus {
+    pub async fn connect(addr: String) -> BusResult<AmqpBus> {
+        let channel = open_channel(&addr).await?;
+        Ok(AmqpBus { addr, channel: Mutex::new(channel), exchanges: Mutex::new(Vec::new()) })
+    }
+
+    /// The current lapin channel; re-opened first if the broker has closed it.
+    async fn channel(&self) -> lapin::Result<Channel> {
+        let mut channel = self.channel.lock().await;
+        if !channel.status().connected() {
+            eprintln!("WARNING: RabbitMQ channel closed; reconnecting");
+            let fresh = open_channel(&self.addr).await?;
+            for exchange in self.exchanges.lock().await.iter() {
+                declare_fanout(&fresh, exchange).await?;
+            }
+            *channel = fresh;
+        }
+        Ok(channel.clone())
+    }
+}
+
+impl MessageBus for AmqpBus {
+    fn declare<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, BusResult<()>> {
+        Box::pin(async move {
+            let mut exchanges = self.exchanges.lock().await;
+            if !exchanges.iter().any(|exchange| exchange == topic) {
+                exchanges.push(topic.to_string());
+            }
+            drop(exchanges);
+            declare_fanout(&self.channel().await?, topic).await?;
+            Ok(())
+        })
+    }
+
+    fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8]) -> BoxFuture<'a, BusResult<()>> {
+        Box::pin(async move {
+            let confirmation = self
+                .channel()
+                .await?
+                .basic_publish(topic, "", BasicPublishOptions::default(), payload, BasicProperties::default())
+                .await?
+                .await?;
+            if confirmation.is_nack() {
+                return Err(BusError("broker did not confirm the message".to_string()));
+            }
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, topic: &'a str, name: &'a str) -> BoxFuture<'a, BusResult<Messages>> {
+        Box::pin(async move {
+            let channel = self.channel().await?;
+            let queue = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::default()).await?;
+            channel.queue_bind(queue.name().as_str(), topic, "", QueueBindOptions::default(), FieldTable::default()).await?;
+            let options = BasicConsumeOptions { no_ack: true, ..BasicConsumeOptions::default() };
+            let consumer = channel.basic_consume(queue.name().as_str(), name, options, FieldTable::default()).await?;
+            let messages = consumer.map(|delivery| delivery.map(|delivery| delivery.data).map_err(BusError::from));
+            Ok(messages.boxed())
+        })
+    }
+
+    /// On a channel of its own, since the window is per channel, with
+    /// deliveries acked together once a quarter of the window has been
+    /// taken from the stream.
+    fn subscribe_windowed<'a>(&'a self, topic: &'a str, name: &'a str, window: u16) -> BoxFuture<'a, BusResult<Messages>> {
+        Box::pin(async move {
+            let channel = open_channel(&self.addr).await?;
+            channel.basic_qos(window, BasicQosOptions::default()).await?;
+            declare_fanout(&channel, topic).await?;
+            let queue = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::default()).await?;
+            channel.queue_bind(queue.name().as_str(), topic, "", QueueBindOptions::default(), FieldTable::default()).await?;
+            let consumer = channel.basic_consume(queue.name().as_str(), name, BasicConsumeOptions::default(), FieldTable::default()).await?;
+            let batch = u64::from(window / 4).max(1);
+            let messages = stream::unfold((consumer, channel, 0u64), move |(mut consumer, channel, mut unacked)| async move {
+                let delivery = match consumer.next().await? {
+                    Ok(delivery) => delivery,
+                    Err(e) => return Some((Err(BusError::from(e)), (consumer, channel, unacked))),
+                };
+                unacked += 1;
+                if unacked >= batch {
+                    let ack = channel.basic_ack(delivery.delivery_tag, BasicAckOptions { multiple: true }).await;
+                    if let Err(e) = ack {
+                        return Some((Err(BusError::from(e)), (consumer, channel, unacked)));
+                    }
+                    unacked = 0;
+                }
+                Some((Ok(delivery.data), (consumer, channel, unacked)))
+            });
+            Ok(messages.boxed())
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures_util::future::BoxFuture;
+    use futures_util::stream::{self, StreamExt};
+    use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+    use rdkafka::client::DefaultClientContext;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{Consumer, StreamConsumer};
+    use rdkafka::message::Message;
+    use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+    use rdkafka::types::RDKafkaErrorCode;
+
+    use super::{BusError, BusResult, MessageBus, Messages};
+
+    /// How long a publish may wait for the broker before it fails.
+    const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Kafka through rdkafka. librdkafka reconnects on its own.
+    pub struct KafkaBus {
+        brokers: String,
+        producer: FutureProducer,
+        admin: AdminClient<DefaultClientContext>,
+        /// Subscriptions made so far, for unique consumer groups.
+        subscriptions: AtomicU64,
+    }
+
+    impl KafkaBus {
+        pub async fn connect(brokers: String) -> BusResult<KafkaBus> {
+            let mut config = ClientConfig::new();
+            config.set("bootstrap.servers", &brokers).set("message.timeout.ms", DELIVERY_TIMEOUT.as_millis().to_string());
+            let producer: FutureProducer = config.create()?;
+            let admin: AdminClient<DefaultClientContext> = config.create()?;
+            // Fail now, like an AMQP connect does, if no broker answers.
+            let probe = producer.clone();
+            tokio::task::spawn_blocking(move || probe.client().fetch_metadata(None, DELIVERY_TIMEOUT).map(|_| ()))
+                .await
+                .map_err(|e| BusError(e.to_string()))??;
+            Ok(KafkaBus { brokers, producer, admin, subscriptions: AtomicU64::new(0) })
+        }
+    }
+
+    impl MessageBus for KafkaBus {
+        fn declare<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, BusResult<()>> {
+            Box::pin(async move {
+                let new_topic = NewTopic::new(topic, 1, TopicReplication::Fixed(1));
+                for result in self.admin.create_topics([&new_topic], &AdminOptions::new()).await? {
+                    match result {
+                        Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                        Err((topic, code)) => return Err(BusError(format!("failed to create topic '{}': {}", topic, code))),
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8]) -> BoxFuture<'a, BusResult<()>> {
+            Box::pin(async move {
+                let record: FutureRecord<'_, (), [u8]> = FutureRecord::to(topic).payload(payload);
+                self.producer.send(record, DELIVERY_TIMEOUT).await.map_err(|(e, _)| BusError::from(e))?;
+                Ok(())
+            })
+        }
+
+        fn subscribe<'a>(&'a self, topic: &'a str, name: &'a str) -> BoxFuture<'a, BusResult<Messages>> {
+            Box::pin(async move {
+                // A group of its own, so every subscriber gets every message.
+                let number = self.subscriptions.fetch_add(1, Ordering::Relaxed);
+                let group = format!("{}-{}-{}", name, std::process::id(), number);
+                let consumer: StreamConsumer = ClientConfig::new()
+                    .set("bootstrap.servers", &self.brokers)
+                    .set("group.id", &group)
+                    .set("auto.offset.reset", "latest")
+                    .set("enable.auto.commit", "true")
+                    .create()?;
+                consumer.subscribe(&[topic])?;
+                let messages = stream::unfold(Arc::new(consumer), |consumer| async move {
+                    let message = consumer
+                        .recv()
+                        .await
+                        .map(|message| message.payload().unwrap_or_default().to_vec())
+                        .map_err(BusError::from);
+                    Some((message, consumer))
+                });
+                Ok(messages.boxed())
+            })
+        }
+    }
+}
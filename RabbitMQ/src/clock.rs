@@ -0,0 +1,17 @@
+// clock.rs
+//
+// Simulated time for the bins (see `rts_core::clock`), with the sleep they
+// wait on: the shared SimClock's sleep blocks its thread, so the bins sleep
+// its `real_duration` on the tokio runtime instead.
+//
+// Every bin includes this module, and not all of them sleep.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+pub use rts_core::clock::SimClock;
+
+/// Waits for `sim` of `clock`'s simulated time.
+pub fn sleep(clock: &SimClock, sim: Duration) -> tokio::time::Sleep {
+    tokio::time::sleep(clock.real_duration(sim))
+}
@@ -0,0 +1,81 @@
+// control.rs
+//
+// Shutdown across the bins. The simulation publishes Control::Shutdown on the
+// "control" exchange once its cars are done, or when it is interrupted; the
+// traffic light controller, the flow analyzer and monitoring each bind a
+// queue to it at startup and wind down when the message arrives. Ctrl-C
+// stops a bin the same way, so every bin leaves through its normal exit path
+// and pending logs and acks are flushed.
+//
+// Every bin includes this module, and each uses a different part of it.
+#![allow(dead_code)]
+
+use futures_util::stream::StreamExt;
+use rts_core::messages::Control;
+use tokio::sync::watch;
+
+use crate::clock::SimClock;
+use crate::bus::BusResult;
+use crate::mq::{self, declare_exchange, publish_message, MqChannel, PublishError};
+
+/// Resolves once the bin should stop. Clones share the same signal.
+#[derive(Clone)]
+pub struct StopSignal {
+    reason: watch::Receiver<Option<String>>,
+}
+
+impl StopSignal {
+    /// Resolves with the reason once a Shutdown has arrived or Ctrl-C was
+    /// pressed; select on it to shut down.
+    pub async fn requested(&mut self) -> String {
+        let reason = self.reason.wait_for(Option::is_some).await.ok().map(|reason| reason.clone().unwrap_or_default());
+        match reason {
+            Some(reason) => reason,
+            // The listener only drops the sender after sending a reason.
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.reason.borrow().is_some()
+    }
+}
+
+/// Subscribes to the control exchange and listens on it, and for Ctrl-C,
+/// in a task of its own. Call it before anything that may take a while, so
+/// that a Shutdown published meanwhile is not missed.
+pub async fn listen(mq: &MqChannel) -> BusResult<StopSignal> {
+    declare_exchange(mq, mq::CONTROL).await;
+    let mut consumer = mq.subscribe(mq::CONTROL, "control").await?;
+
+    let (tx, rx) = watch::channel(None);
+    tokio::spawn(async move {
+        let reason = loop {
+            tokio::select! {
+                delivery_result = consumer.next() => {
+                    let Some(delivery_result) = delivery_result else {
+                        // The channel is gone; only Ctrl-C can stop the bin now.
+                        tokio::signal::ctrl_c().await.ok();
+                        break "interrupted".to_string();
+                    };
+                    let Ok(payload) = delivery_result else { continue };
+                    match serde_json::from_slice::<Control>(&payload) {
+                        Ok(Control::Shutdown { reason, .. }) => break reason,
+                        Err(e) => eprintln!("Ignoring malformed control message: {}", e),
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => break "interrupted".to_string(),
+            }
+        };
+        println!("Shutting down: {}", reason);
+        tx.send_replace(Some(reason));
+    });
+    Ok(StopSignal { reason: rx })
+}
+
+/// Tells every bin listening on the control exchange to stop.
+pub async fn announce_shutdown(mq: &MqChannel, reason: &str, clock: &SimClock) -> Result<(), PublishError> {
+    declare_exchange(mq, mq::CONTROL).await;
+    let message = Control::Shutdown { reason: reason.to_string(), timestamp: clock.now_secs() };
+    publish_message(mq, mq::CONTROL, &message).await
+}
@@ -0,0 +1,217 @@
+// event_stream.rs
+//
+// Live event stream of the API (see `api`), for browser visualizations that
+// cannot speak AMQP. Every log event system monitoring handles, every lane
+// count from "simulation.updates", every light color from "light_status" and
+// every tick of the car positions from "car_positions" is pushed to the subscribers of ws://<host>:<RTS_API_PORT>/events as a
+// JSON text frame, tagged by `type`:
+//
+//   log                    a LogEvent
+//   lane, lane_snapshot    a TrafficUpdate or TrafficSnapshot
+//   lane_changes           a TrafficChanges, the lanes whose count changed
+//   light, light_snapshot  a LightStatus or LightSnapshot
+//   car_positions          a CarPositions, where every car is along its lane
+//
+// Query parameters narrow the stream; every one given must match:
+//
+//   topics=logs,lanes,lights,cars  only those feeds
+//   junction=5                     events of junction 5 and of the lanes into it
+//   lane=1003                      events on lane 1003
+//   car=12                         events of car 12 (log events and positions only)
+//
+// Snapshots, changes and positions are cut down to the matching lanes and cars. A subscriber that falls more
+// than FRAME_BUFFER frames behind loses the oldest and is sent
+// {"type":"lagged","skipped":<n>} in their place.
+//
+// Without the `api` feature nothing is streamed, and most of this is unused.
+#![cfg_attr(not(feature = "api"), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use rts_core::messages::{CarPositions, LightSnapshot, LightStatus, TrafficChanges, TrafficSnapshot, TrafficUpdate};
+
+use crate::events::LogEvent;
+
+/// Frames buffered for each subscriber.
+pub const FRAME_BUFFER: usize = 1024;
+
+/// One message on the stream.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    Log(LogEvent),
+    Lane(TrafficUpdate),
+    LaneSnapshot(TrafficSnapshot),
+    LaneChanges(TrafficChanges),
+    Light(LightStatus),
+    LightSnapshot(LightSnapshot),
+    CarPositions(CarPositions),
+    /// The subscriber fell behind and missed `skipped` frames.
+    Lagged { skipped: u64 },
+}
+
+/// Feeds a subscriber can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Topic {
+    Logs,
+    Lanes,
+    Lights,
+    Cars,
+}
+
+impl Topic {
+    fn parse(name: &str) -> Result<Topic, String> {
+        match name.trim() {
+            "logs" => Ok(Topic::Logs),
+            "lanes" => Ok(Topic::Lanes),
+            "lights" => Ok(Topic::Lights),
+            "cars" => Ok(Topic::Cars),
+            other => Err(format!("unknown topic '{}'; expected logs, lanes, lights or cars", other)),
+        }
+    }
+}
+
+/// The query parameters of /events.
+#[derive(Debug, Default, Deserialize)]
+pub struct FilterParams {
+    topics: Option<String>,
+    junction: Option<u32>,
+    lane: Option<u32>,
+    car: Option<u32>,
+}
+
+/// Which frames a subscriber receives.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// None for every topic.
+    topics: Option<Vec<Topic>>,
+    junction: Option<u32>,
+    lane: Option<u32>,
+    car: Option<u32>,
+    /// Junction each lane leads into.
+    lane_junctions: Arc<HashMap<u32, u32>>,
+}
+
+impl Filter {
+    /// The filter `params` ask for; an error names an unknown topic.
+    pub fn new(params: FilterParams, lane_junctions: Arc<HashMap<u32, u32>>) -> Result<Filter, String> {
+        let topics = params
+            .topics
+            .map(|topics| topics.split(',').filter(|name| !name.trim().is_empty()).map(Topic::parse).collect())
+            .transpose()?;
+        Ok(Filter { topics, junction: params.junction, lane: params.lane, car: params.car, lane_junctions })
+    }
+
+    fn wants(&self, topic: Topic) -> bool {
+        self.topics.as_ref().is_none_or(|topics| topics.contains(&topic))
+    }
+
+    /// True if a lane, or the junction it leads into, matches.
+    fn lane_matches(&self, lane_id: u32) -> bool {
+        self.lane.is_none_or(|lane| lane == lane_id)
+            && self.junction.is_none_or(|junction| self.lane_junctions.get(&lane_id) == Some(&junction))
+    }
+
+    fn log_matches(&self, log: &LogEvent) -> bool {
+        let lanes = log.kind.lanes();
+        self.car.is_none_or(|car| log.kind.car_id() == Some(car))
+            && self.lane.is_none_or(|lane| lanes.contains(&lane))
+            && self.junction.is_none_or(|junction| {
+                log.kind.junction() == Some(junction)
+                    || log.source == format!("Junction-{}", junction)
+                    || lanes.iter().any(|lane_id| self.lane_junctions.get(lane_id) == Some(&junction))
+            })
+    }
+
+    /// `frame` as this subscriber should see it, snapshots, changes and
+    /// positions cut down to the matching lanes and cars; None if nothing of
+    /// it matches.
+    pub fn apply(&self, frame: &Frame) -> Option<Frame> {
+        match frame {
+            Frame::Log(log) => (self.wants(Topic::Logs) && self.log_matches(log)).then(|| frame.clone()),
+            Frame::Lane(update) => {
+                (self.wants(Topic::Lanes) && self.car.is_none() && self.lane_matches(update.lane_id)).then(|| frame.clone())
+            }
+            Frame::Light(status) => {
+                (self.wants(Topic::Lights) && self.car.is_none() && self.lane_matches(status.lane_id)).then(|| frame.clone())
+            }
+            Frame::LaneSnapshot(snapshot) => {
+                if !self.wants(Topic::Lanes) || self.car.is_some() {
+                    return None;
+                }
+                let lanes: HashMap<u32, u32> =
+                    snapshot.lanes.iter().filter(|(&lane_id, _)| self.lane_matches(lane_id)).map(|(&k, &v)| (k, v)).collect();
+                (!lanes.is_empty()).then_some(Frame::LaneSnapshot(TrafficSnapshot { lanes, timestamp: snapshot.timestamp }))
+            }
+            Frame::LaneChanges(changes) => {
+                if !self.wants(Topic::Lanes) || self.car.is_some() {
+                    return None;
+                }
+                let changed: HashMap<u32, u32> =
+                    changes.changed.iter().filter(|(&lane_id, _)| self.lane_matches(lane_id)).map(|(&k, &v)| (k, v)).collect();
+                (!changed.is_empty()).then_some(Frame::LaneChanges(TrafficChanges { changed, timestamp: changes.timestamp }))
+            }
+            Frame::LightSnapshot(snapshot) => {
+                if !self.wants(Topic::Lights) || self.car.is_some() {
+                    return None;
+                }
+                let lights: HashMap<_, _> =
+                    snapshot.lights.iter().filter(|(&lane_id, _)| self.lane_matches(lane_id)).map(|(&k, &v)| (k, v)).collect();
+                (!lights.is_empty()).then_some(Frame::LightSnapshot(LightSnapshot { lights, timestamp: snapshot.timestamp }))
+            }
+            Frame::CarPositions(update) => {
+                if !self.wants(Topic::Cars) {
+                    return None;
+                }
+                let positions: Vec<_> = update
+                    .positions
+                    .iter()
+                    .filter(|position| self.car.is_none_or(|car| car == position.car_id) && self.lane_matches(position.lane_id))
+                    .copied()
+                    .collect();
+                (!positions.is_empty()).then_some(Frame::CarPositions(CarPositions { positions, timestamp: update.timestamp }))
+            }
+            Frame::Lagged { .. } => Some(frame.clone()),
+        }
+    }
+}
+
+/// Pushes the frames `filter` lets through to `socket` until the client goes
+/// away or the feed ends.
+#[cfg(feature = "api")]
+pub async fn stream(
+    mut socket: axum::extract::ws::WebSocket,
+    mut frames: tokio::sync::broadcast::Receiver<Frame>,
+    filter: Filter,
+) {
+    use axum::extract::ws::Message;
+    use tokio::sync::broadcast::error::RecvError;
+
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Ok(frame) => frame,
+                Err(RecvError::Lagged(skipped)) => Frame::Lagged { skipped },
+                Err(RecvError::Closed) => return,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let Some(frame) = filter.apply(&frame) else { continue };
+        let text = match serde_json::to_string(&frame) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("Ignoring an event the stream could not serialize: {}", e);
+                continue;
+            }
+        };
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+    }
+}
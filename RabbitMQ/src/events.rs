@@ -0,0 +1,351 @@
+// events.rs
+//
+// Log events published on the "logs" exchange. The typed payload travels in
+// `kind`, tagged by `type`; messages in the old {source, message, timestamp}
+// shape still parse and come out as Generic events.
+//
+// Every bin but the recorder and roadworks includes this module, and each
+// uses a different part of it.
+#![allow(dead_code)]
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use rts_core::messages::LightColor;
+use rts_core::progress::LaneTransition;
+
+/// How a lane's rolling average moved over the window after a lane closure.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LaneShift {
+    pub lane_id: u32,
+    /// Rolling averages when the closure happened and a window later.
+    pub before: f64,
+    pub after: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type")]
+pub enum EventKind {
+    #[default]
+    Generic,
+    VehicleGenerated {
+        car_id: u32,
+        /// An emergency vehicle, which preempts the lights on its way.
+        #[serde(default)]
+        emergency: bool,
+        speed: f64,
+        entry_lane: u32,
+        exit_lane: u32,
+        route: Vec<u32>,
+    },
+    /// A car found no entry/exit pair with a route between them and never drove.
+    VehicleGenerationFailed {
+        car_id: u32,
+        attempts: u32,
+    },
+    CarCompleted {
+        car_id: u32,
+        #[serde(default)]
+        emergency: bool,
+        entry_lane: u32,
+        exit_lane: u32,
+        route_length: f64,
+        wait_time: f64,
+        drive_time: f64,
+        total_time: f64,
+    },
+    /// A car entered or left a lane; `route_index` is the lane's place in
+    /// the trip (see `rts_core::progress`).
+    CarProgress {
+        car_id: u32,
+        lane_id: u32,
+        transition: LaneTransition,
+        route_index: usize,
+    },
+    /// A car turned right on a red light into a lane holding `occupancy`
+    /// vehicles (see `rts_core::right_on_red`).
+    RightTurnOnRed {
+        car_id: u32,
+        lane_id: u32,
+        into_lane: u32,
+        occupancy: u32,
+    },
+    PhaseChange {
+        junction: u32,
+        phase: usize,
+        green_lanes: Vec<u32>,
+        red_lanes: Vec<u32>,
+    },
+    /// Phase an adaptive junction chose to serve next, and the waiting
+    /// vehicles of every phase it weighed (indexed by phase).
+    PhaseDecision {
+        junction: u32,
+        phase: usize,
+        demand: Vec<u32>,
+        /// Chosen by the starvation guard rather than by demand.
+        overdue: bool,
+    },
+    Recommendation {
+        lane_id: u32,
+        new_green_time: u32,
+    },
+    /// A recommendation for `lane_id` changed the green time of one of its
+    /// junction's phases, from the next time that phase is served.
+    PhaseTimingChanged {
+        junction: u32,
+        phase: usize,
+        lane_id: u32,
+        old_green_secs: u64,
+        new_green_secs: u64,
+    },
+    /// A junction interrupted its cycle to hold `lane_id` green for an
+    /// approaching emergency vehicle.
+    EmergencyPreemption {
+        junction: u32,
+        lane_id: u32,
+        car_id: u32,
+        green_secs: u64,
+    },
+    /// The control plane set the green time of every phase of `junction`,
+    /// in phase order, from the next time each phase is served.
+    PhasePlanSet {
+        junction: u32,
+        green_secs: Vec<u64>,
+    },
+    /// The control plane held `lane_id` green or red for `duration_secs`;
+    /// a green hold is logged once the junction shows it.
+    LaneForced {
+        junction: u32,
+        lane_id: u32,
+        color: LightColor,
+        duration_secs: u64,
+    },
+    /// Gridlocked lanes new routes avoid until `expires_at`.
+    RerouteAdvisory {
+        lanes: Vec<u32>,
+        expires_at: u64,
+    },
+    /// An internal lane was closed to new routes, or reopened.
+    LaneClosure {
+        lane_id: u32,
+        closed: bool,
+    },
+    /// The lanes whose rolling averages moved most in the window after
+    /// `lane_id` was closed or reopened, largest move first.
+    CongestionShift {
+        lane_id: u32,
+        closed: bool,
+        shifts: Vec<LaneShift>,
+    },
+    /// A car broke down on a lane and blocks part of it for `duration_secs`
+    /// (see `incidents`).
+    Incident {
+        car_id: u32,
+        lane_id: u32,
+        duration_secs: u64,
+    },
+    /// A broken-down car was cleared from its lane.
+    IncidentCleared {
+        car_id: u32,
+        lane_id: u32,
+    },
+    /// A lane's recent average count jumped well above its baseline.
+    LaneAnomaly {
+        lane_id: u32,
+        baseline: f64,
+        recent: f64,
+    },
+    /// A lane got back to its average count before an incident,
+    /// `recovery_secs` after the incident cleared.
+    IncidentRecovery {
+        lane_id: u32,
+        before: f64,
+        recovery_secs: u64,
+    },
+    /// Raised by system monitoring when it handles log messages more than
+    /// `max_lag_secs` after they were sent.
+    MonitorOverloaded {
+        behind_secs: u64,
+        max_lag_secs: u64,
+    },
+    /// Raised by system monitoring when a component stops sending heartbeats.
+    ComponentDown {
+        component: String,
+        silent_secs: f64,
+    },
+    /// A junction's controller task panicked or stalled and was restarted;
+    /// `restarts` counts the junction's restarts so far, this one included.
+    JunctionControllerFailed {
+        junction: u32,
+        reason: String,
+        restarts: u32,
+    },
+    /// A component reached RabbitMQ again after `outage_secs` without it and
+    /// sent the `buffered` messages it had kept meanwhile (see `mq`).
+    BrokerReconnected {
+        component: String,
+        outage_secs: f64,
+        buffered: u64,
+    },
+}
+
+impl EventKind {
+    /// The car the event is about, if any.
+    pub fn car_id(&self) -> Option<u32> {
+        match self {
+            EventKind::VehicleGenerated { car_id, .. }
+            | EventKind::VehicleGenerationFailed { car_id, .. }
+            | EventKind::CarCompleted { car_id, .. }
+            | EventKind::CarProgress { car_id, .. }
+            | EventKind::RightTurnOnRed { car_id, .. }
+            | EventKind::EmergencyPreemption { car_id, .. }
+            | EventKind::Incident { car_id, .. }
+            | EventKind::IncidentCleared { car_id, .. } => Some(*car_id),
+            _ => None,
+        }
+    }
+
+    /// The junction the event happened at, if any.
+    pub fn junction(&self) -> Option<u32> {
+        match self {
+            EventKind::PhaseChange { junction, .. }
+            | EventKind::PhaseDecision { junction, .. }
+            | EventKind::PhaseTimingChanged { junction, .. }
+            | EventKind::EmergencyPreemption { junction, .. }
+            | EventKind::PhasePlanSet { junction, .. }
+            | EventKind::LaneForced { junction, .. }
+            | EventKind::JunctionControllerFailed { junction, .. } => Some(*junction),
+            _ => None,
+        }
+    }
+
+    /// The lanes the event happened on: a generated car's entry lane, the
+    /// lane a car entered or left, the lanes of a phase, and so on.
+    pub fn lanes(&self) -> Vec<u32> {
+        match self {
+            EventKind::VehicleGenerated { entry_lane, .. } => vec![*entry_lane],
+            EventKind::CarProgress { lane_id, .. }
+            | EventKind::Recommendation { lane_id, .. }
+            | EventKind::PhaseTimingChanged { lane_id, .. }
+            | EventKind::EmergencyPreemption { lane_id, .. }
+            | EventKind::LaneForced { lane_id, .. }
+            | EventKind::LaneClosure { lane_id, .. }
+            | EventKind::Incident { lane_id, .. }
+            | EventKind::IncidentCleared { lane_id, .. }
+            | EventKind::LaneAnomaly { lane_id, .. }
+            | EventKind::IncidentRecovery { lane_id, .. } => vec![*lane_id],
+            EventKind::RightTurnOnRed { lane_id, into_lane, .. } => vec![*lane_id, *into_lane],
+            EventKind::PhaseChange { green_lanes, red_lanes, .. } => green_lanes.iter().chain(red_lanes).copied().collect(),
+            EventKind::RerouteAdvisory { lanes, .. } => lanes.clone(),
+            EventKind::CongestionShift { lane_id, shifts, .. } => {
+                std::iter::once(*lane_id).chain(shifts.iter().map(|shift| shift.lane_id)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Human-readable rendering of a typed event; Generic events carry their
+/// text in `LogEvent::message` instead.
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::Generic => Ok(()),
+            EventKind::VehicleGenerated { emergency, speed, entry_lane, exit_lane, route, .. } => write!(
+                f,
+                "Generated {} with speed {:.2} m/s; Entry Lane {}, Exit Lane {}; Lane Route: {:?}",
+                if *emergency { "emergency vehicle" } else { "vehicle" }, speed, entry_lane, exit_lane, route
+            ),
+            EventKind::VehicleGenerationFailed { attempts, .. } => {
+                write!(f, "Gave up after {} entry/exit draws without a valid trip", attempts)
+            }
+            EventKind::CarCompleted { wait_time, drive_time, total_time, .. } => write!(
+                f,
+                "Completed journey: Wait={:.2}s, Drive={:.2}s, Total={:.2}s",
+                wait_time, drive_time, total_time
+            ),
+            EventKind::CarProgress { lane_id, transition, route_index, .. } => match transition {
+                LaneTransition::Entered => write!(f, "Entered lane {} (trip position {})", lane_id, route_index),
+                LaneTransition::Exited => write!(f, "Left lane {}", lane_id),
+            },
+            EventKind::RightTurnOnRed { lane_id, into_lane, occupancy, .. } => write!(
+                f,
+                "Turned right on red from lane {} into lane {} ({} vehicle(s) on it)",
+                lane_id, into_lane, occupancy
+            ),
+            EventKind::PhaseChange { phase, green_lanes, red_lanes, .. } => write!(
+                f,
+                "Phase {} active: Green lanes {:?}, Red lanes {:?}",
+                phase, green_lanes, red_lanes
+            ),
+            EventKind::PhaseDecision { phase, demand, overdue, .. } => {
+                let reason = if *overdue { "overdue under the starvation guard" } else { "busiest" };
+                write!(f, "Chose phase {} ({}); waiting per phase {:?}", phase, reason, demand)
+            }
+            EventKind::Recommendation { lane_id, new_green_time } => {
+                write!(f, "Recommended {}s green for lane {}", new_green_time, lane_id)
+            }
+            EventKind::PhaseTimingChanged { phase, lane_id, old_green_secs, new_green_secs, .. } => write!(
+                f,
+                "Phase {} green {}s -> {}s on recommendation for lane {}",
+                phase, old_green_secs, new_green_secs, lane_id
+            ),
+            EventKind::EmergencyPreemption { lane_id, car_id, green_secs, .. } => {
+                write!(f, "Preempted for emergency vehicle {}: lane {} green for {}s", car_id, lane_id, green_secs)
+            }
+            EventKind::PhasePlanSet { green_secs, .. } => write!(f, "Phase plan set: green times {:?}s", green_secs),
+            EventKind::LaneForced { lane_id, color, duration_secs, .. } => {
+                write!(f, "Lane {} forced {:?} for {}s", lane_id, color, duration_secs)
+            }
+            EventKind::RerouteAdvisory { lanes, expires_at } => {
+                write!(f, "Gridlock on lanes {:?}; rerouting around them until {}", lanes, expires_at)
+            }
+            EventKind::LaneClosure { lane_id, closed: true } => write!(f, "Lane {} closed to new routes", lane_id),
+            EventKind::LaneClosure { lane_id, closed: false } => write!(f, "Lane {} reopened", lane_id),
+            EventKind::CongestionShift { lane_id, closed, shifts } => {
+                write!(f, "Congestion after lane {} {}:", lane_id, if *closed { "closed" } else { "reopened" })?;
+                if shifts.is_empty() {
+                    return write!(f, " no lane changed notably");
+                }
+                for (i, shift) in shifts.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}lane {} {:.1} -> {:.1}", separator, shift.lane_id, shift.before, shift.after)?;
+                }
+                Ok(())
+            }
+            EventKind::Incident { lane_id, duration_secs, .. } => {
+                write!(f, "Broke down on lane {}; blocking it for {}s", lane_id, duration_secs)
+            }
+            EventKind::IncidentCleared { lane_id, .. } => write!(f, "Cleared from lane {}", lane_id),
+            EventKind::LaneAnomaly { lane_id, baseline, recent } => write!(
+                f,
+                "Anomaly on lane {}: {:.1} vehicles recently against a baseline of {:.1}",
+                lane_id, recent, baseline
+            ),
+            EventKind::IncidentRecovery { lane_id, before, recovery_secs } => write!(
+                f,
+                "Lane {} back to its pre-incident level ({:.1} vehicles) {}s after the incident cleared",
+                lane_id, before, recovery_secs
+            ),
+            EventKind::MonitorOverloaded { behind_secs, max_lag_secs } => {
+                write!(f, "Monitoring is {}s behind the simulation (limit {}s)", behind_secs, max_lag_secs)
+            }
+            EventKind::ComponentDown { component, silent_secs } => {
+                write!(f, "Component down: {} (no heartbeat for {:.1}s)", component, silent_secs)
+            }
+            EventKind::JunctionControllerFailed { reason, restarts, .. } => {
+                write!(f, "Controller failed ({}); restart {}", reason, restarts)
+            }
+            EventKind::BrokerReconnected { component, outage_secs, buffered } => write!(
+                f,
+                "{} reconnected to RabbitMQ after {:.1}s; sent {} buffered message(s)",
+                component, outage_secs, buffered
+            ),
+        }
+    }
+}
+
+/// A log event with the RabbitMQ deployment's typed payloads.
+pub type LogEvent = rts_core::messages::LogEvent<EventKind>;
+pub use rts_core::messages::Level;
@@ -1,75 +1,576 @@
 // flow_analyzer.rs
-use tokio;
-use lapin::{options::*, types::FieldTable};
 use futures_util::stream::StreamExt;
-use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
 
+mod bus;
 mod mq;
-use mq::{create_channel, publish_message, declare_exchange};
+use mq::{create_channel, publish_message, declare_exchange, MqChannel, PublishError};
+mod events;
+use events::{EventKind, LaneShift, Level, LogEvent};
+mod clock;
+use clock::SimClock;
+mod heartbeat;
+mod control;
+use rts_core::lanes::{load_lanes, Lane, LaneCategory};
+use rts_core::network::{load_network, Network};
+use rts_core::routing::{self, find_route, Congestion, EdgeCost, TravelTime};
+use rts_core::messages::{LaneClosure, Recommendation, RecommendationApplied, SimulationUpdate};
+mod gridlock;
+use gridlock::{GridlockConfig, GridlockDetector};
+mod incidents;
+use incidents::{AnomalyDetector, Incident, RecoveryTracker};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TrafficUpdate {
-    pub lane_id: u32,
-    pub vehicle_count: u32,
-    pub timestamp: u64,
+/// Length of the sliding window used to average each lane's vehicle count.
+const WINDOW_SECS: u64 = 60;
+/// Rolling average at or above which a lane is considered congested.
+const CONGESTION_THRESHOLD: f64 = 4.0;
+/// Fraction of the threshold the rolling average must drop below before a
+/// congested lane counts as clear again, so a lane hovering around the
+/// threshold does not flap in and out of congestion.
+const CLEAR_RATIO: f64 = 0.75;
+/// How long the rolling average must stay at or above the threshold before a
+/// lane counts as congested.
+const SUSTAIN_SECS: u64 = 15;
+/// Trend, in vehicles per minute, below which a lane is draining fast enough
+/// that it is left to clear on its own.
+const DRAINING_TREND: f64 = -2.0;
+/// Minimum time between two recommendations for the same lane.
+const COOLDOWN_SECS: u64 = 60;
+/// Green time recommended for a lane right at the threshold.
+const BASE_GREEN_TIME: u32 = 10;
+/// Extra green seconds per vehicle the average sits above the threshold.
+const GREEN_SECS_PER_EXCESS_VEHICLE: f64 = 5.0;
+/// Upper bound on any recommended green time.
+const MAX_GREEN_TIME: u32 = 60;
+/// Failed recommendations in a row after which a lane's next one is escalated.
+const ESCALATE_AFTER_FAILURES: u32 = 2;
+/// Extra green seconds added per escalation level.
+const ESCALATION_STEP_SECS: u32 = 10;
+/// Smallest move of a lane's rolling average, in vehicles, that a congestion
+/// shift reports.
+const SHIFT_MIN_CHANGE: f64 = 0.5;
+/// Lanes a congestion shift lists, at most.
+const SHIFT_MAX_LANES: usize = 5;
+/// Speed, in meters per second, detours around a closed lane are timed at:
+/// the middle of the range the simulation draws car speeds from.
+const DETOUR_SPEED: f64 = 80.0;
+
+/// Where a lane stands between sustained congestion and clear.
+#[derive(Debug, Default)]
+struct LaneCongestion {
+    /// When the rolling average last rose to the threshold, while it stays there.
+    above_since: Option<u64>,
+    /// Sustained congestion detected and not yet cleared.
+    congested: bool,
+    last_recommended: Option<u64>,
+}
+
+/// Tracks a sliding window of vehicle counts per lane and decides when a lane
+/// has been congested long enough to warrant a recommendation. A lane becomes
+/// congested once its rolling average has stayed at or above the threshold
+/// for SUSTAIN_SECS without draining, and stays congested until the average
+/// falls below CLEAR_RATIO of the threshold. A congested lane gets at most
+/// one recommendation per cooldown.
+pub struct CongestionDetector {
+    window_secs: u64,
+    threshold: f64,
+    cooldown_secs: u64,
+    samples: HashMap<u32, VecDeque<(u64, u32)>>,
+    lanes: HashMap<u32, LaneCongestion>,
+}
+
+impl CongestionDetector {
+    pub fn new(window_secs: u64, threshold: f64, cooldown_secs: u64) -> Self {
+        CongestionDetector {
+            window_secs,
+            threshold,
+            cooldown_secs,
+            samples: HashMap::new(),
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// Records a vehicle count for a lane and drops samples older than the window.
+    pub fn record(&mut self, lane_id: u32, vehicle_count: u32, now: u64) {
+        let window = self.samples.entry(lane_id).or_default();
+        window.push_back((now, vehicle_count));
+        while let Some(&(ts, _)) = window.front() {
+            if ts + self.window_secs <= now {
+                window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Rolling average of the samples currently in the lane's window.
+    pub fn average(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        if window.is_empty() {
+            return None;
+        }
+        let sum: u32 = window.iter().map(|&(_, count)| count).sum();
+        Some(sum as f64 / window.len() as f64)
+    }
+
+    /// Rolling average of every lane with samples in its window.
+    pub fn averages(&self) -> HashMap<u32, f64> {
+        self.samples.keys().filter_map(|&lane_id| Some((lane_id, self.average(lane_id)?))).collect()
+    }
+
+    /// Least-squares slope of the lane's counts over its window, in vehicles
+    /// per minute: positive while the queue builds, negative while it drains.
+    pub fn trend(&self, lane_id: u32) -> Option<f64> {
+        let window = self.samples.get(&lane_id)?;
+        let n = window.len() as f64;
+        let mean_t = window.iter().map(|&(ts, _)| ts as f64).sum::<f64>() / n;
+        let mean_c = window.iter().map(|&(_, count)| count as f64).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for &(ts, count) in window {
+            let dt = ts as f64 - mean_t;
+            covariance += dt * (count as f64 - mean_c);
+            variance += dt * dt;
+        }
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance * 60.0)
+    }
+
+    /// Returns the green time to recommend for the lane, if it is in sustained
+    /// congestion (see `CongestionDetector`), not draining and not cooling down.
+    pub fn evaluate(&mut self, lane_id: u32, now: u64) -> Option<u32> {
+        let average = self.average(lane_id)?;
+        let trend = self.trend(lane_id).unwrap_or(0.0);
+        let lane = self.lanes.entry(lane_id).or_default();
+        if average < self.threshold {
+            lane.above_since = None;
+        } else if lane.above_since.is_none() {
+            lane.above_since = Some(now);
+        }
+        if lane.congested {
+            if average < self.threshold * CLEAR_RATIO {
+                lane.congested = false;
+            }
+        } else if lane.above_since.is_some_and(|since| now >= since + SUSTAIN_SECS) && trend > DRAINING_TREND {
+            lane.congested = true;
+        }
+        if !lane.congested || trend <= DRAINING_TREND {
+            return None;
+        }
+        if lane.last_recommended.is_some_and(|last| now < last + self.cooldown_secs) {
+            return None;
+        }
+        lane.last_recommended = Some(now);
+        Some(green_time_for(average, self.threshold))
+    }
+}
+
+/// How to escalate a lane's next recommendation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escalation {
+    /// Recommend the green time as computed.
+    None,
+    /// Recommend this longer green time instead.
+    LongerGreen(u32),
+    /// Even the longest green time has not helped; favour the lane's phase at
+    /// junction level instead.
+    Junction,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Recommendation {
+/// What became of an applied recommendation once its window had passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedbackOutcome {
     pub lane_id: u32,
-    pub new_green_time: u32,
-    pub timestamp: u64,
+    pub applied_green_time: u32,
+    /// Rolling averages when the recommendation was applied and a window later.
+    pub before: f64,
+    pub after: f64,
+    /// Whether the lane's average dropped or fell below the congestion threshold.
+    pub helped: bool,
+    /// Failed recommendations in a row for the lane, this one included.
+    pub failures: u32,
+}
+
+
+/// Learns whether applied recommendations reduced their lane's rolling
+/// average over the following window, and escalates lanes whose
+/// recommendations keep failing.
+pub struct FeedbackTracker {
+    window_secs: u64,
+    threshold: f64,
+    escalate_after: u32,
+    /// Lanes under watch, with the rolling average when their recommendation
+    /// was applied.
+    watching: HashMap<u32, (RecommendationApplied, f64)>,
+    failures: HashMap<u32, u32>,
+}
+
+impl FeedbackTracker {
+    pub fn new(window_secs: u64, threshold: f64, escalate_after: u32) -> Self {
+        FeedbackTracker {
+            window_secs,
+            threshold,
+            escalate_after,
+            watching: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Starts watching the lane of an applied recommendation, whose rolling
+    /// average was `before` at the time. Applying another one restarts the watch.
+    pub fn applied(&mut self, applied: RecommendationApplied, before: f64) {
+        self.watching.insert(applied.lane_id, (applied, before));
+    }
+
+    /// Settles every watch whose window has passed by `now`, comparing against
+    /// the lane's current rolling average (a lane with no samples counts as
+    /// empty). A recommendation helped if the average dropped or the lane is no
+    /// longer congested.
+    pub fn settle(&mut self, now: u64, average: impl Fn(u32) -> Option<f64>) -> Vec<FeedbackOutcome> {
+        let due: Vec<u32> = self
+            .watching
+            .iter()
+            .filter(|(_, (applied, _))| applied.timestamp + self.window_secs <= now)
+            .map(|(&lane_id, _)| lane_id)
+            .collect();
+        let mut outcomes = Vec::new();
+        for lane_id in due {
+            let Some((applied, before)) = self.watching.remove(&lane_id) else {
+                continue;
+            };
+            let after = average(lane_id).unwrap_or(0.0);
+            let helped = after < before || after < self.threshold;
+            let failures = if helped {
+                self.failures.remove(&lane_id);
+                0
+            } else {
+                let failures = self.failures.entry(lane_id).or_insert(0);
+                *failures += 1;
+                *failures
+            };
+            outcomes.push(FeedbackOutcome {
+                lane_id,
+                applied_green_time: applied.applied_green_time,
+                before,
+                after,
+                helped,
+                failures,
+            });
+        }
+        outcomes.sort_by_key(|outcome| outcome.lane_id);
+        outcomes
+    }
+
+    /// How to escalate a `green_time` recommendation for the lane: each failure
+    /// from `escalate_after` on adds ESCALATION_STEP_SECS, and once that would
+    /// pass MAX_GREEN_TIME the lane goes to junction level.
+    pub fn escalation(&self, lane_id: u32, green_time: u32) -> Escalation {
+        let failures = self.failures.get(&lane_id).copied().unwrap_or(0);
+        if failures < self.escalate_after {
+            return Escalation::None;
+        }
+        let level = failures - self.escalate_after + 1;
+        let green = green_time + level * ESCALATION_STEP_SECS;
+        if green <= MAX_GREEN_TIME {
+            Escalation::LongerGreen(green)
+        } else {
+            Escalation::Junction
+        }
+    }
+}
+
+/// Watches where traffic goes after a lane is closed or reopened: the rolling
+/// average of every lane is kept from the moment of the change, and a window
+/// later compared with the averages then.
+pub struct ClosureWatch {
+    window_secs: u64,
+    /// Changes under watch, with every lane's rolling average at the time.
+    watching: Vec<(LaneClosure, HashMap<u32, f64>)>,
+}
+
+impl ClosureWatch {
+    pub fn new(window_secs: u64) -> Self {
+        ClosureWatch { window_secs, watching: Vec::new() }
+    }
+
+    /// Starts watching a closure or reopening, with the lanes' rolling
+    /// averages at the time.
+    pub fn changed(&mut self, closure: LaneClosure, averages: HashMap<u32, f64>) {
+        self.watching.push((closure, averages));
+    }
+
+    /// Settles every watch whose window has passed by `now` against the
+    /// current `averages` (a lane without one counts as empty). Each comes
+    /// with the SHIFT_MAX_LANES lanes whose average moved most, by at least
+    /// SHIFT_MIN_CHANGE, largest move first.
+    pub fn settle(&mut self, now: u64, averages: &HashMap<u32, f64>) -> Vec<(LaneClosure, Vec<LaneShift>)> {
+        let (due, watching): (Vec<_>, Vec<_>) = std::mem::take(&mut self.watching)
+            .into_iter()
+            .partition(|(closure, _)| closure.timestamp + self.window_secs <= now);
+        self.watching = watching;
+        due.into_iter()
+            .map(|(closure, before)| {
+                let mut lanes: Vec<u32> = before.keys().chain(averages.keys()).copied().collect();
+                lanes.sort();
+                lanes.dedup();
+                let mut shifts: Vec<LaneShift> = lanes
+                    .into_iter()
+                    .map(|lane_id| LaneShift {
+                        lane_id,
+                        before: before.get(&lane_id).copied().unwrap_or(0.0),
+                        after: averages.get(&lane_id).copied().unwrap_or(0.0),
+                    })
+                    .filter(|shift| (shift.after - shift.before).abs() >= SHIFT_MIN_CHANGE)
+                    .collect();
+                shifts.sort_by(|a, b| (b.after - b.before).abs().total_cmp(&(a.after - a.before).abs()));
+                shifts.truncate(SHIFT_MAX_LANES);
+                (closure, shifts)
+            })
+            .collect()
+    }
+}
+
+/// Scales the recommended green time with how far the average exceeds the threshold.
+fn green_time_for(average: f64, threshold: f64) -> u32 {
+    let excess = (average - threshold).max(0.0);
+    let green = BASE_GREEN_TIME as f64 + excess * GREEN_SECS_PER_EXCESS_VEHICLE;
+    (green.round() as u32).min(MAX_GREEN_TIME)
+}
+
+/// Records a lane count and publishes a recommendation if the lane is congested,
+/// escalated if the lane's earlier recommendations did not help.
+/// Windows and cooldowns run on `clock`'s simulated time.
+async fn record_and_recommend(channel: &MqChannel, detector: &mut CongestionDetector, feedback: &FeedbackTracker, clock: &SimClock, lane_id: u32, vehicle_count: u32)
+    -> Result<(), PublishError>
+{
+    let now = clock.now_secs();
+    detector.record(lane_id, vehicle_count, now);
+    if let Some(green_time) = detector.evaluate(lane_id, now) {
+        let new_green_time = match feedback.escalation(lane_id, green_time) {
+            Escalation::None => green_time,
+            Escalation::LongerGreen(longer) => {
+                println!("Escalating lane {}: earlier recommendations did not help; recommending {}s green", lane_id, longer);
+                longer
+            }
+            // The controller here only takes lane recommendations, so the
+            // longest green time is as far as escalation goes.
+            Escalation::Junction => MAX_GREEN_TIME,
+        };
+        let rec = Recommendation {
+            lane_id,
+            new_green_time,
+            timestamp: now,
+        };
+        publish_message(channel, mq::RECOMMENDATIONS, &rec).await?;
+        let mut log = LogEvent::new("FlowAnalyzer", now, EventKind::Recommendation { lane_id, new_green_time });
+        log.message = format!("Published recommendation for lane {} (avg {:.1} vehicles over {}s, trend {:+.1}/min, green {}s)",
+                              lane_id, detector.average(lane_id).unwrap_or(0.0), WINDOW_SECS,
+                              detector.trend(lane_id).unwrap_or(0.0), new_green_time);
+        publish_message(channel, mq::LOGS, &log).await?;
+    }
+    Ok(())
+}
+
+/// Settles the feedback windows that have passed and logs the recommendations
+/// that did not help.
+async fn check_feedback(channel: &MqChannel, feedback: &mut FeedbackTracker, detector: &CongestionDetector, clock: &SimClock)
+    -> Result<(), PublishError>
+{
+    let now = clock.now_secs();
+    for outcome in feedback.settle(now, |lane_id| detector.average(lane_id)) {
+        if outcome.helped {
+            continue;
+        }
+        let mut log = LogEvent::new("FlowAnalyzer", now, EventKind::Generic).with_level(Level::Warn);
+        log.message = format!("{}s green for lane {} did not reduce congestion (avg {:.1} -> {:.1}); {} in a row",
+                              outcome.applied_green_time, outcome.lane_id, outcome.before, outcome.after, outcome.failures);
+        println!("{}", log.message);
+        publish_message(channel, mq::LOGS, &log).await?;
+    }
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
+/// Logs, as a CongestionShift, how the lanes' rolling averages moved over the
+/// window after each lane closure or reopening whose window has passed.
+async fn check_closures(channel: &MqChannel, closures: &mut ClosureWatch, detector: &CongestionDetector, clock: &SimClock)
+    -> Result<(), PublishError>
+{
+    let now = clock.now_secs();
+    for (closure, shifts) in closures.settle(now, &detector.averages()) {
+        let kind = EventKind::CongestionShift { lane_id: closure.lane_id, closed: closure.closed, shifts };
+        println!("{}", kind);
+        publish_message(channel, mq::LOGS, &LogEvent::new("FlowAnalyzer", now, kind)).await?;
+    }
+    Ok(())
 }
 
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// Feeds a lane count to the anomaly detector and the incident recoveries,
+/// and logs a lane that just turned anomalous or recovered from an incident.
+async fn check_incidents(channel: &MqChannel, anomalies: &mut AnomalyDetector, recoveries: &mut RecoveryTracker, clock: &SimClock, lane_id: u32, vehicle_count: u32)
+    -> Result<(), PublishError>
+{
+    let now = clock.now_secs();
+    if let Some((baseline, recent)) = anomalies.observe(lane_id, vehicle_count, now) {
+        let log = LogEvent::new("FlowAnalyzer", now, EventKind::LaneAnomaly { lane_id, baseline, recent })
+            .with_level(Level::Warn);
+        println!("{}", log.kind);
+        publish_message(channel, mq::LOGS, &log).await?;
+    }
+    let recent = anomalies.recent(lane_id, now).unwrap_or(vehicle_count as f64);
+    if let Some((recovery_secs, before)) = recoveries.check(lane_id, recent, now) {
+        let log = LogEvent::new("FlowAnalyzer", now, EventKind::IncidentRecovery { lane_id, before, recovery_secs });
+        println!("{}", log.kind);
+        publish_message(channel, mq::LOGS, &log).await?;
+    }
+    Ok(())
 }
 
-pub async fn run_flow_analyzer() -> Result<(), Box<dyn std::error::Error>> {
-    let channel = create_channel().await;
-    declare_exchange(&channel, "simulation.updates", lapin::ExchangeKind::Fanout).await;
-    declare_exchange(&channel, "recommendations", lapin::ExchangeKind::Fanout).await;
-    declare_exchange(&channel, "logs", lapin::ExchangeKind::Fanout).await;
+/// Publishes a reroute advisory for every new gridlock in the latest lane counts.
+async fn check_gridlock(channel: &MqChannel, gridlock: &mut GridlockDetector, clock: &SimClock, counts: &HashMap<u32, u32>)
+    -> Result<(), PublishError>
+{
+    let now = clock.now_secs();
+    for advisory in gridlock.observe(counts, now) {
+        println!("Gridlock detected on lanes {:?}; advising reroutes until {}", advisory.lanes, advisory.expires_at);
+        publish_message(channel, mq::REROUTE_ADVISORIES, &advisory).await?;
+        let log = LogEvent::new(
+            "FlowAnalyzer",
+            now,
+            EventKind::RerouteAdvisory { lanes: advisory.lanes, expires_at: advisory.expires_at },
+        )
+        .with_level(Level::Warn);
+        publish_message(channel, mq::LOGS, &log).await?;
+    }
+    Ok(())
+}
 
-    let queue = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::default())
-        .await?;
-    channel.queue_bind(queue.name().as_str(), "simulation.updates", "", QueueBindOptions::default(), FieldTable::default())
-        .await?;
+/// How traffic gets around `closed` while it is closed: the quickest route
+/// between its ends over the other internal lanes, timed at DETOUR_SPEED with
+/// the congestion of the latest `counts` priced in.
+fn describe_detour(closed: &Lane, internal_lanes: &[Lane], network: &Network, counts: &HashMap<u32, u32>) -> String {
+    let others: Vec<Lane> = internal_lanes.iter().filter(|lane| lane.id != closed.id).cloned().collect();
+    let weights = routing::congestion_weights(counts);
+    let cost = Congestion { base: TravelTime { speed: DETOUR_SPEED }, weights: &weights };
+    match find_route(closed.start_intersection, closed.end_intersection, &others, network, &cost) {
+        Ok(route) => {
+            let ids: Vec<u32> = route.iter().map(|lane| lane.id).collect();
+            let detour_secs: f64 = route.iter().map(|lane| cost.cost(lane)).sum();
+            format!("detour via lanes {:?} takes about {:.0}s against {:.0}s", ids, detour_secs, cost.cost(closed))
+        }
+        Err(e) => format!("no detour ({})", e),
+    }
+}
 
-    let mut consumer = channel.basic_consume(queue.name().as_str(), "flow_analyzer", BasicConsumeOptions::default(), FieldTable::default())
-        .await?;
+pub async fn run_flow_analyzer(clock: SimClock) -> Result<(), Box<dyn std::error::Error>> {
+    let mq = create_channel().await?;
+    declare_exchange(&mq, mq::SIMULATION_UPDATES).await;
+    declare_exchange(&mq, mq::RECOMMENDATIONS).await;
+    declare_exchange(&mq, mq::REROUTE_ADVISORIES).await;
+    declare_exchange(&mq, mq::RECOMMENDATIONS_APPLIED).await;
+    declare_exchange(&mq, mq::LANE_CLOSURES).await;
+    declare_exchange(&mq, mq::INCIDENTS).await;
+    declare_exchange(&mq, mq::LOGS).await;
+    tokio::spawn(heartbeat::publish_heartbeats(mq.clone(), "flow_analyzer", clock));
+    let mut stop = control::listen(&mq).await?;
+    let mut consumer = mq.subscribe(mq::SIMULATION_UPDATES, "flow_analyzer").await?;
+    let mut applied_consumer = mq.subscribe(mq::RECOMMENDATIONS_APPLIED, "flow_analyzer_feedback").await?;
+    let mut closure_consumer = mq.subscribe(mq::LANE_CLOSURES, "flow_analyzer_closures").await?;
+    let mut incident_consumer = mq.subscribe(mq::INCIDENTS, "flow_analyzer_incidents").await?;
 
     println!("Flow Analyzer waiting for simulation updates...");
 
-    while let Some(delivery_result) = consumer.next().await {
-        if let Ok(delivery) = delivery_result {
-            let data = delivery.data.clone();
-            if let Ok(update) = serde_json::from_slice::<TrafficUpdate>(&data) {
-                println!("Received update: {:?}", update);
-                if update.vehicle_count >= 4 {
-                    let rec = Recommendation {
-                        lane_id: update.lane_id,
-                        new_green_time: 40,
-                        timestamp: current_time_secs(),
-                    };
-                    publish_message(&channel, "recommendations", "", &rec).await;
-                    let log = LogEvent {
-                        source: "FlowAnalyzer".into(),
-                        message: format!("Published recommendation for lane {}", update.lane_id),
-                        timestamp: current_time_secs(),
-                    };
-                    publish_message(&channel, "logs", "", &log).await;
+    let mut detector = CongestionDetector::new(WINDOW_SECS, CONGESTION_THRESHOLD, COOLDOWN_SECS);
+    let mut feedback = FeedbackTracker::new(WINDOW_SECS, CONGESTION_THRESHOLD, ESCALATE_AFTER_FAILURES);
+    let mut closures = ClosureWatch::new(WINDOW_SECS);
+    let mut anomalies = AnomalyDetector::default();
+    let mut recoveries = RecoveryTracker::default();
+    let lanes = load_lanes();
+    let internal_lanes: Vec<Lane> = lanes.iter().filter(|lane| lane.category == LaneCategory::Internal).cloned().collect();
+    let network = load_network();
+    let mut gridlock = GridlockDetector::new(GridlockConfig::from_env(), &lanes);
+    // Latest count of every lane, kept current by every message shape.
+    let mut counts: HashMap<u32, u32> = HashMap::new();
+    loop {
+        tokio::select! {
+            delivery_result = consumer.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    match serde_json::from_slice::<SimulationUpdate>(&payload) {
+                        Ok(SimulationUpdate::Lane(update)) => {
+                            println!("Received update: {:?}", update);
+                            record_and_recommend(&mq, &mut detector, &feedback, &clock, update.lane_id, update.vehicle_count).await?;
+                            check_incidents(&mq, &mut anomalies, &mut recoveries, &clock, update.lane_id, update.vehicle_count).await?;
+                            counts.insert(update.lane_id, update.vehicle_count);
+                            check_gridlock(&mq, &mut gridlock, &clock, &counts).await?;
+                        }
+                        Ok(SimulationUpdate::Snapshot(snapshot)) => {
+                            println!("Received snapshot of {} lanes", snapshot.lanes.len());
+                            for (&lane_id, &vehicle_count) in &snapshot.lanes {
+                                record_and_recommend(&mq, &mut detector, &feedback, &clock, lane_id, vehicle_count).await?;
+                                check_incidents(&mq, &mut anomalies, &mut recoveries, &clock, lane_id, vehicle_count).await?;
+                            }
+                            counts = snapshot.lanes;
+                            check_gridlock(&mq, &mut gridlock, &clock, &counts).await?;
+                        }
+                        Ok(SimulationUpdate::Changes(changes)) => {
+                            println!("Received changes on {} lanes", changes.changed.len());
+                            for (&lane_id, &vehicle_count) in &changes.changed {
+                                record_and_recommend(&mq, &mut detector, &feedback, &clock, lane_id, vehicle_count).await?;
+                                check_incidents(&mq, &mut anomalies, &mut recoveries, &clock, lane_id, vehicle_count).await?;
+                            }
+                            counts.extend(changes.changed);
+                            check_gridlock(&mq, &mut gridlock, &clock, &counts).await?;
+                        }
+                        Err(e) => eprintln!("Ignoring malformed simulation update: {}", e),
+                    }
+                    check_feedback(&mq, &mut feedback, &detector, &clock).await?;
+                    check_closures(&mq, &mut closures, &detector, &clock).await?;
+                }
+            }
+            delivery_result = applied_consumer.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    match serde_json::from_slice::<RecommendationApplied>(&payload) {
+                        Ok(applied) => {
+                            let before = detector.average(applied.lane_id).unwrap_or(0.0);
+                            feedback.applied(applied, before);
+                        }
+                        Err(e) => eprintln!("Ignoring malformed applied recommendation: {}", e),
+                    }
+                }
+            }
+            delivery_result = closure_consumer.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    match serde_json::from_slice::<LaneClosure>(&payload) {
+                        Ok(closure) => {
+                            println!("Lane {} {}; watching the congestion shift for {}s",
+                                     closure.lane_id, if closure.closed { "closed" } else { "reopened" }, WINDOW_SECS);
+                            if let Some(lane) = internal_lanes.iter().find(|lane| lane.id == closure.lane_id).filter(|_| closure.closed) {
+                                println!("Lane {}: {}", lane.id, describe_detour(lane, &internal_lanes, &network, &counts));
+                            }
+                            closures.changed(closure, detector.averages());
+                        }
+                        Err(e) => eprintln!("Ignoring malformed lane closure: {}", e),
+                    }
+                }
+            }
+            delivery_result = incident_consumer.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    match serde_json::from_slice::<Incident>(&payload) {
+                        Ok(incident) => {
+                            let before = detector.average(incident.lane_id).unwrap_or(0.0);
+                            recoveries.incident(&incident, before);
+                        }
+                        Err(e) => eprintln!("Ignoring malformed incident: {}", e),
+                    }
                 }
             }
-            delivery.ack(BasicAckOptions::default()).await?;
+            // A heartbeat that gave up stops the analyzer too.
+            reason = mq.failed() => return Err(reason.into()),
+            _ = stop.requested() => break,
         }
     }
     Ok(())
@@ -77,7 +578,7 @@ pub async fn run_flow_analyzer() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run_flow_analyzer().await {
+    if let Err(e) = run_flow_analyzer(SimClock::from_env()).await {
         eprintln!("Error in flow analyzer: {}", e);
     }
 }
@@ -0,0 +1,212 @@
+// gridlock.rs
+//
+// Gridlock detection. Per-lane recommendations can't clear a jam whose
+// downstream lanes are full as well, so the flow analyzer also looks for
+// clusters of saturated lanes: a lane is stuck once its count has stayed at or
+// above the threshold for `hold_secs`, and stuck lanes that share an
+// intersection belong to the same cluster. A cluster of at least `min_lanes`
+// lanes is a gridlock; the analyzer publishes a RerouteAdvisory for it and the
+// simulation routes cars around those lanes until the advisory expires.
+// Advisories travel on the "reroute_advisories" exchange.
+//
+// Settings come from RTS_GRIDLOCK, e.g. `lanes=3,count=4,hold=15,ttl=60`
+// (cluster size, lane count, seconds saturated, advisory lifetime in seconds).
+//
+// The flow analyzer detects, the simulation applies; each uses its own half.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use rts_core::lanes::{Lane, LaneCategory};
+pub use rts_core::messages::RerouteAdvisory;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridlockConfig {
+    /// Smallest cluster of stuck lanes that counts as a gridlock.
+    pub min_lanes: usize,
+    /// Lane count at or above which a lane is saturated.
+    pub threshold: u32,
+    /// Seconds a lane must stay saturated before it counts as stuck.
+    pub hold_secs: u64,
+    /// Lifetime of an advisory, in seconds.
+    pub ttl_secs: u64,
+}
+
+impl Default for GridlockConfig {
+    fn default() -> Self {
+        GridlockConfig { min_lanes: 3, threshold: 4, hold_secs: 15, ttl_secs: 60 }
+    }
+}
+
+impl GridlockConfig {
+    /// Parses a list such as `lanes=3,count=4,hold=15,ttl=60`; keys left out
+    /// keep their defaults.
+    pub fn parse(spec: &str) -> Result<GridlockConfig, String> {
+        let mut config = GridlockConfig::default();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", entry))?;
+            let value: u64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid value '{}' for {}", value.trim(), key.trim()))?;
+            match key.trim() {
+                "lanes" => config.min_lanes = value as usize,
+                "count" => config.threshold = value as u32,
+                "hold" => config.hold_secs = value,
+                "ttl" => config.ttl_secs = value,
+                other => return Err(format!("unknown setting '{}'", other)),
+            }
+        }
+        if config.min_lanes < 2 {
+            return Err("a gridlock needs at least 2 lanes".to_string());
+        }
+        if config.threshold == 0 || config.ttl_secs == 0 {
+            return Err("count and ttl must be positive".to_string());
+        }
+        Ok(config)
+    }
+
+    /// Settings from RTS_GRIDLOCK, falling back to the defaults (with a
+    /// warning) when it is unset or invalid.
+    pub fn from_env() -> GridlockConfig {
+        match std::env::var("RTS_GRIDLOCK") {
+            Ok(spec) => GridlockConfig::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_GRIDLOCK: {}", e);
+                GridlockConfig::default()
+            }),
+            Err(_) => GridlockConfig::default(),
+        }
+    }
+}
+
+/// Watches full lane-count maps for gridlocked clusters.
+pub struct GridlockDetector {
+    config: GridlockConfig,
+    /// The two grid intersections each watched lane connects.
+    intersections: HashMap<u32, Vec<u32>>,
+    /// When each currently saturated lane became saturated.
+    saturated_since: HashMap<u32, u64>,
+    /// Lanes covered by an advisory that has not expired yet, with its expiry.
+    advised_until: HashMap<u32, u64>,
+}
+
+impl GridlockDetector {
+    /// Only internal lanes are watched; cars don't queue on boundary lanes.
+    pub fn new(config: GridlockConfig, lanes: &[Lane]) -> Self {
+        let intersections = lanes
+            .iter()
+            .filter(|lane| lane.category == LaneCategory::Internal)
+            .map(|lane| {
+                (lane.id, vec![lane.start_intersection, lane.end_intersection])
+            })
+            .collect();
+        GridlockDetector {
+            config,
+            intersections,
+            saturated_since: HashMap::new(),
+            advised_until: HashMap::new(),
+        }
+    }
+
+    /// Feeds the latest count of every lane. Returns an advisory for each
+    /// gridlocked cluster that has a lane not already under an advisory.
+    pub fn observe(&mut self, counts: &HashMap<u32, u32>, now: u64) -> Vec<RerouteAdvisory> {
+        let threshold = self.config.threshold;
+        self.saturated_since
+            .retain(|lane_id, _| counts.get(lane_id).is_some_and(|&count| count >= threshold));
+        for (&lane_id, &count) in counts {
+            if count >= threshold && self.intersections.contains_key(&lane_id) {
+                self.saturated_since.entry(lane_id).or_insert(now);
+            }
+        }
+        self.advised_until.retain(|_, &mut expires_at| expires_at > now);
+
+        let stuck: HashSet<u32> = self
+            .saturated_since
+            .iter()
+            .filter(|&(_, &since)| now >= since + self.config.hold_secs)
+            .map(|(&lane_id, _)| lane_id)
+            .collect();
+
+        let mut advisories = Vec::new();
+        for cluster in self.clusters(&stuck) {
+            if cluster.len() < self.config.min_lanes
+                || cluster.iter().all(|lane_id| self.advised_until.contains_key(lane_id))
+            {
+                continue;
+            }
+            let expires_at = now + self.config.ttl_secs;
+            for &lane_id in &cluster {
+                self.advised_until.insert(lane_id, expires_at);
+            }
+            advisories.push(RerouteAdvisory { lanes: cluster, expires_at });
+        }
+        advisories
+    }
+
+    /// Groups lanes into clusters connected through shared intersections.
+    /// Each cluster is sorted, and clusters are ordered by their first lane.
+    fn clusters(&self, lanes: &HashSet<u32>) -> Vec<Vec<u32>> {
+        let mut by_intersection: HashMap<u32, Vec<u32>> = HashMap::new();
+        for &lane_id in lanes {
+            for &inter in self.intersections.get(&lane_id).into_iter().flatten() {
+                by_intersection.entry(inter).or_default().push(lane_id);
+            }
+        }
+
+        let mut sorted: Vec<u32> = lanes.iter().copied().collect();
+        sorted.sort_unstable();
+        let mut seen: HashSet<u32> = HashSet::new();
+        let mut clusters = Vec::new();
+        for lane_id in sorted {
+            if !seen.insert(lane_id) {
+                continue;
+            }
+            let mut cluster = vec![lane_id];
+            let mut stack = vec![lane_id];
+            while let Some(current) = stack.pop() {
+                for inter in self.intersections.get(&current).into_iter().flatten() {
+                    for &neighbor in &by_intersection[inter] {
+                        if seen.insert(neighbor) {
+                            cluster.push(neighbor);
+                            stack.push(neighbor);
+                        }
+                    }
+                }
+            }
+            cluster.sort_unstable();
+            clusters.push(cluster);
+        }
+        clusters
+    }
+}
+
+/// Lanes under a live advisory, as seen by the simulation.
+#[derive(Debug, Default)]
+pub struct AdvisedLanes {
+    until: HashMap<u32, u64>,
+}
+
+/// Advised lanes shared between the simulation and its car threads.
+pub type SharedAdvisories = Arc<Mutex<AdvisedLanes>>;
+
+impl AdvisedLanes {
+    /// Records an advisory; a lane covered twice keeps the later expiry.
+    pub fn apply(&mut self, advisory: &RerouteAdvisory) {
+        for &lane_id in &advisory.lanes {
+            let expires_at = self.until.entry(lane_id).or_insert(0);
+            *expires_at = (*expires_at).max(advisory.expires_at);
+        }
+    }
+
+    /// Lanes whose advisory is still live at `now`; expired ones are dropped.
+    pub fn active(&mut self, now: u64) -> HashSet<u32> {
+        self.until.retain(|_, &mut expires_at| expires_at > now);
+        self.until.keys().copied().collect()
+    }
+}
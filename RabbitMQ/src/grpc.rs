@@ -0,0 +1,193 @@
+// grpc.rs
+//
+// Control plane of the traffic light controller, for external traffic
+// management. With the `grpc` feature and RTS_GRPC_PORT set, the
+// TrafficLightControl service of proto/traffic_light.proto is served on
+// 0.0.0.0:<port>:
+//
+//   GetLightStates      color of every light, or of one junction's
+//   SetPhasePlan        green time of every phase of a junction, from the
+//                       next time each phase comes round
+//   ForceLane           holds a lane green (after amber and clearance, like
+//                       an emergency vehicle's preemption) or red
+//   StreamPhaseChanges  every phase a junction shows from now on, holds
+//                       included
+//
+// Overrides are logged like the controller's own decisions (PhasePlanSet,
+// LaneForced), so monitoring sees who changed what. A stream subscriber
+// that falls more than PHASE_CHANGE_BUFFER changes behind skips the ones it
+// missed.
+//
+// Only the traffic light bin includes this module. Without the `grpc`
+// feature nothing is served, and most of this is unused.
+#![cfg_attr(not(feature = "grpc"), allow(dead_code))]
+
+use crate::Junctions;
+
+/// Port from RTS_GRPC_PORT, if it is set (with a warning if it is invalid).
+fn port_from_env() -> Option<u16> {
+    let value = std::env::var("RTS_GRPC_PORT").ok()?;
+    match value.trim().parse() {
+        Ok(port) => Some(port),
+        Err(_) => {
+            eprintln!("Ignoring RTS_GRPC_PORT: invalid port '{}'", value.trim());
+            None
+        }
+    }
+}
+
+/// Serves the control plane of `junctions` on RTS_GRPC_PORT in a background
+/// task, if it is set.
+#[cfg(feature = "grpc")]
+pub fn start(junctions: Junctions) {
+    let Some(port) = port_from_env() else { return };
+    let service = proto::traffic_light_control_server::TrafficLightControlServer::new(ControlPlane { junctions });
+    tokio::spawn(async move {
+        println!("Serving the traffic light control plane on 0.0.0.0:{}", port);
+        let served = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(std::net::SocketAddr::from(([0, 0, 0, 0], port)))
+            .await;
+        if let Err(e) = served {
+            eprintln!("Control plane on port {} stopped: {}", port, e);
+        }
+    });
+}
+
+/// Without the `grpc` feature there is nothing to serve; say so if a port
+/// was asked for.
+#[cfg(not(feature = "grpc"))]
+pub fn start(_junctions: Junctions) {
+    if port_from_env().is_some() {
+        eprintln!("Control plane requested but the traffic light controller was built without the grpc feature");
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod proto {
+    tonic::include_proto!("rts.traffic_light.v1");
+}
+
+#[cfg(feature = "grpc")]
+struct ControlPlane {
+    junctions: Junctions,
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use std::pin::Pin;
+
+    use futures_util::stream::{self, Stream, StreamExt};
+    use tokio::sync::broadcast::error::RecvError;
+    use tonic::{Request, Response, Status};
+
+    use super::proto::traffic_light_control_server::TrafficLightControl;
+    use super::proto::{
+        Cause, Color, ForceLaneReply, ForceLaneRequest, GetLightStatesRequest, LightState, LightStates, Phase,
+        PhaseChange, PhasePlan, SetPhasePlanRequest, StreamPhaseChangesRequest,
+    };
+    use super::ControlPlane;
+    use crate::{ControlError, Hold, LightColor, PhaseShown};
+
+    impl From<ControlError> for Status {
+        fn from(e: ControlError) -> Self {
+            match e {
+                ControlError::NotFound(message) => Status::not_found(message),
+                ControlError::Invalid(message) => Status::invalid_argument(message),
+                ControlError::Unavailable(message) => Status::unavailable(message),
+            }
+        }
+    }
+
+    fn color(color: LightColor) -> Color {
+        match color {
+            LightColor::Red => Color::Red,
+            LightColor::Amber => Color::Amber,
+            LightColor::Green => Color::Green,
+        }
+    }
+
+    fn phase_change(shown: PhaseShown) -> PhaseChange {
+        let cause = match shown.hold {
+            None => Cause::Cycle,
+            Some(Hold::Emergency { .. }) => Cause::Emergency,
+            Some(Hold::Forced { .. }) => Cause::Forced,
+        };
+        PhaseChange {
+            junction: shown.junction,
+            phase: shown.phase as u64,
+            green_lanes: shown.green_lanes,
+            red_lanes: shown.red_lanes,
+            timestamp: shown.timestamp,
+            cause: cause.into(),
+        }
+    }
+
+    type PhaseChanges = Pin<Box<dyn Stream<Item = Result<PhaseChange, Status>> + Send>>;
+
+    #[tonic::async_trait]
+    impl TrafficLightControl for ControlPlane {
+        async fn get_light_states(&self, request: Request<GetLightStatesRequest>) -> Result<Response<LightStates>, Status> {
+            let junction = request.into_inner().junction;
+            let mut lights: Vec<LightState> = self
+                .junctions
+                .lights(junction)
+                .await?
+                .into_iter()
+                .filter_map(|(lane_id, light)| {
+                    Some(LightState { lane_id, junction: self.junctions.junction_of(lane_id)?, color: color(light).into() })
+                })
+                .collect();
+            lights.sort_by_key(|light| light.lane_id);
+            Ok(Response::new(LightStates { lights, timestamp: self.junctions.clock.now_secs() }))
+        }
+
+        async fn set_phase_plan(&self, request: Request<SetPhasePlanRequest>) -> Result<Response<PhasePlan>, Status> {
+            let request = request.into_inner();
+            self.junctions.set_greens(request.junction, &request.green_secs).await?;
+            let phases = self.junctions.phases[&request.junction]
+                .iter()
+                .zip(request.green_secs)
+                .map(|(phase, green_secs)| Phase { lanes: phase.lanes.clone(), green_secs })
+                .collect();
+            Ok(Response::new(PhasePlan { junction: request.junction, phases }))
+        }
+
+        async fn force_lane(&self, request: Request<ForceLaneRequest>) -> Result<Response<ForceLaneReply>, Status> {
+            let request = request.into_inner();
+            let light = match request.color() {
+                Color::Green => LightColor::Green,
+                Color::Red => LightColor::Red,
+                Color::Amber => LightColor::Amber,
+                Color::Unspecified => return Err(Status::invalid_argument("no color given")),
+            };
+            let junction = self.junctions.force(request.lane_id, light, request.duration_secs).await?;
+            Ok(Response::new(ForceLaneReply { junction }))
+        }
+
+        type StreamPhaseChangesStream = PhaseChanges;
+
+        async fn stream_phase_changes(
+            &self,
+            request: Request<StreamPhaseChangesRequest>,
+        ) -> Result<Response<Self::StreamPhaseChangesStream>, Status> {
+            let junction = request.into_inner().junction;
+            if junction != 0 && !self.junctions.contains(junction) {
+                return Err(Status::not_found(format!("junction {} has no lights", junction)));
+            }
+            let receiver = self.junctions.shown.subscribe();
+            let changes = stream::unfold(receiver, move |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(shown) if junction == 0 || shown.junction == junction => {
+                            return Some((Ok(phase_change(shown)), receiver))
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            });
+            Ok(Response::new(changes.boxed()))
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// heartbeat.rs
+//
+// Liveness of the bins. The simulation, traffic light and flow analyzer bins
+// each publish a Heartbeat on the "heartbeats" exchange every
+// HEARTBEAT_INTERVAL, and system monitoring reports a component as down once
+// it has been silent for MISSED_HEARTBEATS intervals. Intervals are real time:
+// they measure whether a process is alive, not how fast the simulation runs.
+//
+// Every bin but the recorder and roadworks includes this module, and each
+// uses a different part of it.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+use crate::clock::SimClock;
+use crate::mq::{self, declare_exchange, publish_message, MqChannel};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Consecutive intervals a component may miss before it counts as down.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    pub component: String,
+    pub timestamp: u64,
+}
+
+/// Publishes `component`'s heartbeat until a publish gives up; spawn it as
+/// its own task.
+pub async fn publish_heartbeats(channel: MqChannel, component: &'static str, clock: SimClock) {
+    declare_exchange(&channel, mq::HEARTBEATS).await;
+    loop {
+        let heartbeat = Heartbeat { component: component.to_string(), timestamp: clock.now_secs() };
+        if publish_message(&channel, mq::HEARTBEATS, &heartbeat).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+    }
+}
+
+struct ComponentHealth {
+    last_seen: Instant,
+    /// Start of the current run of heartbeats.
+    up_since: Instant,
+    /// Uptime of earlier runs, before the component last went down.
+    earlier_uptime: Duration,
+    down: bool,
+}
+
+/// Last-seen times of every component that has sent a heartbeat.
+pub struct HealthTracker {
+    timeout: Duration,
+    components: BTreeMap<String, ComponentHealth>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        HealthTracker::new(HEARTBEAT_INTERVAL * MISSED_HEARTBEATS)
+    }
+}
+
+impl HealthTracker {
+    /// A tracker that reports components silent for longer than `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        HealthTracker { timeout, components: BTreeMap::new() }
+    }
+
+    /// Records a heartbeat. Returns true if the component was down and is back.
+    pub fn beat(&mut self, component: &str, now: Instant) -> bool {
+        match self.components.get_mut(component) {
+            Some(health) => {
+                health.last_seen = now;
+                if health.down {
+                    health.down = false;
+                    health.up_since = now;
+                    return true;
+                }
+                false
+            }
+            None => {
+                self.components.insert(component.to_string(), ComponentHealth {
+                    last_seen: now,
+                    up_since: now,
+                    earlier_uptime: Duration::ZERO,
+                    down: false,
+                });
+                false
+            }
+        }
+    }
+
+    /// Marks components that have been silent too long as down. Returns each
+    /// newly down component with how long it has been silent.
+    pub fn check(&mut self, now: Instant) -> Vec<(String, Duration)> {
+        let mut down = Vec::new();
+        for (component, health) in self.components.iter_mut() {
+            let silent = now.saturating_duration_since(health.last_seen);
+            if !health.down && silent > self.timeout {
+                health.down = true;
+                health.earlier_uptime += health.last_seen - health.up_since;
+                down.push((component.clone(), silent));
+            }
+        }
+        down
+    }
+
+    /// Each component's total uptime at `now`, and whether it is down.
+    pub fn uptimes(&self, now: Instant) -> Vec<(String, Duration, bool)> {
+        self.components
+            .iter()
+            .map(|(component, health)| {
+                let current = if health.down {
+                    Duration::ZERO
+                } else {
+                    now.saturating_duration_since(health.up_since)
+                };
+                (component.clone(), health.earlier_uptime + current, health.down)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,231 @@
+// incidents.rs
+//
+// Random breakdowns. With RTS_INCIDENTS, e.g. `chance=0.02,duration=30`,
+// every car that enters an internal lane breaks down on it with that chance
+// and stands there for `duration` simulated seconds. Until it is cleared the
+// car takes one of the lane's parallel lanes out of use: the lane's capacity
+// drops by that much, and cars driving it squeeze past at the share of its
+// width still open, or at SQUEEZE_SPEED_SHARE of their speed on a lane with
+// no other. Breakdowns and clearances travel on the "incidents" exchange.
+//
+// The flow analyzer doesn't need to be told about an incident to notice it:
+// it flags a lane whose recent counts jump well above their own baseline.
+// Told about one, it also measures how long the lane takes to get back to
+// its pre-incident level once the incident clears.
+//
+// The simulation breaks cars down, the flow analyzer watches; each uses its
+// own half.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use rts_core::lanes::Lane;
+pub use rts_core::messages::Incident;
+
+/// Speed share of cars passing a breakdown on a lane with no free parallel lane.
+pub const SQUEEZE_SPEED_SHARE: f64 = 0.25;
+
+/// Seconds of counts an anomaly is measured against.
+pub const BASELINE_SECS: u64 = 60;
+/// Seconds of the most recent counts compared with the baseline.
+pub const RECENT_SECS: u64 = 10;
+/// How many times its baseline a lane's recent average must reach to be
+/// flagged as anomalous.
+pub const ANOMALY_RATIO: f64 = 2.0;
+/// Vehicles by which the recent average must exceed the baseline as well, so
+/// a lane going from one car to two is not flagged.
+pub const ANOMALY_MIN_EXCESS: f64 = 2.0;
+/// Vehicles above its pre-incident average a lane may still hold and count
+/// as recovered.
+pub const RECOVERY_TOLERANCE: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IncidentConfig {
+    /// Chance that a car breaks down on each internal lane it enters.
+    pub chance: f64,
+    /// Simulated seconds a broken-down car blocks its lane.
+    pub duration_secs: u64,
+}
+
+impl IncidentConfig {
+    /// Parses a list such as `chance=0.02,duration=30`; `duration` defaults
+    /// to 30 seconds, `chance` must be given.
+    pub fn parse(spec: &str) -> Result<IncidentConfig, String> {
+        let mut chance = None;
+        let mut duration_secs = 30;
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", entry))?;
+            let invalid = || format!("invalid value '{}' for {}", value.trim(), key.trim());
+            match key.trim() {
+                "chance" => chance = Some(value.trim().parse::<f64>().map_err(|_| invalid())?),
+                "duration" => duration_secs = value.trim().parse().map_err(|_| invalid())?,
+                other => return Err(format!("unknown setting '{}'", other)),
+            }
+        }
+        let chance = chance.ok_or("chance is required")?;
+        if !(0.0..=1.0).contains(&chance) {
+            return Err(format!("chance must be between 0 and 1, got {}", chance));
+        }
+        if duration_secs == 0 {
+            return Err("duration must be positive".to_string());
+        }
+        Ok(IncidentConfig { chance, duration_secs })
+    }
+
+    /// Settings from RTS_INCIDENTS; none when it is unset, and none with a
+    /// warning when it is invalid.
+    pub fn from_env() -> Option<IncidentConfig> {
+        let spec = std::env::var("RTS_INCIDENTS").ok()?;
+        IncidentConfig::parse(&spec)
+            .inspect_err(|e| eprintln!("Ignoring RTS_INCIDENTS: {}", e))
+            .ok()
+    }
+}
+
+/// Breakdowns currently blocking each lane, as seen by the simulation.
+#[derive(Debug, Default)]
+pub struct Breakdowns {
+    blocking: HashMap<u32, u32>,
+}
+
+/// Breakdowns shared between the simulation and its car tasks.
+pub type SharedBreakdowns = Arc<Mutex<Breakdowns>>;
+
+impl Breakdowns {
+    pub fn start(&mut self, lane_id: u32) {
+        *self.blocking.entry(lane_id).or_insert(0) += 1;
+    }
+
+    pub fn clear(&mut self, lane_id: u32) {
+        if let Some(count) = self.blocking.get_mut(&lane_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.blocking.remove(&lane_id);
+            }
+        }
+    }
+
+    /// Parallel lanes of `lane` blocked by a broken-down car.
+    fn blocked(&self, lane: &Lane) -> u32 {
+        self.blocking.get(&lane.id).copied().unwrap_or(0).min(lane.parallel_count.max(1))
+    }
+
+    /// `lane` as the cars see it now: its capacity less that of the parallel
+    /// lanes blocked (at least one vehicle).
+    pub fn effective(&self, lane: &Lane) -> Lane {
+        let blocked = self.blocked(lane);
+        let mut effective = lane.clone();
+        effective.capacity = lane.capacity.saturating_sub(blocked * lane.sub_lane_capacity()).max(1);
+        effective
+    }
+
+    /// Share of their speed cars drive `lane` at: the share of its parallel
+    /// lanes still open, or SQUEEZE_SPEED_SHARE if none is.
+    pub fn speed_share(&self, lane: &Lane) -> f64 {
+        let parallel = lane.parallel_count.max(1);
+        let open = parallel - self.blocked(lane);
+        if open == 0 {
+            SQUEEZE_SPEED_SHARE
+        } else {
+            open as f64 / parallel as f64
+        }
+    }
+}
+
+/// Flags lanes whose counts jump: the average of the last RECENT_SECS at
+/// least ANOMALY_RATIO times, and ANOMALY_MIN_EXCESS vehicles above, the
+/// average of the BASELINE_SECS before. A flagged lane is flagged again only
+/// once it has dropped back below the ratio.
+#[derive(Debug, Default)]
+pub struct AnomalyDetector {
+    samples: HashMap<u32, VecDeque<(u64, u32)>>,
+    flagged: HashMap<u32, bool>,
+}
+
+impl AnomalyDetector {
+    /// Records a lane's count. Returns the baseline and recent averages if the
+    /// lane has just turned anomalous.
+    pub fn observe(&mut self, lane_id: u32, count: u32, now: u64) -> Option<(f64, f64)> {
+        let window = self.samples.entry(lane_id).or_default();
+        window.push_back((now, count));
+        while window.front().is_some_and(|&(ts, _)| ts + BASELINE_SECS + RECENT_SECS <= now) {
+            window.pop_front();
+        }
+        let (baseline, recent) = (self.baseline(lane_id, now)?, self.recent(lane_id, now)?);
+        let anomalous = recent >= baseline * ANOMALY_RATIO && recent - baseline >= ANOMALY_MIN_EXCESS;
+        let flagged = self.flagged.entry(lane_id).or_insert(false);
+        let newly = anomalous && !*flagged;
+        *flagged = anomalous;
+        newly.then_some((baseline, recent))
+    }
+
+    fn average(&self, lane_id: u32, keep: impl Fn(u64) -> bool) -> Option<f64> {
+        let counts: Vec<u32> =
+            self.samples.get(&lane_id)?.iter().filter(|&&(ts, _)| keep(ts)).map(|&(_, count)| count).collect();
+        if counts.is_empty() {
+            return None;
+        }
+        Some(counts.iter().sum::<u32>() as f64 / counts.len() as f64)
+    }
+
+    /// Average count of the lane over the BASELINE_SECS before the recent ones.
+    pub fn baseline(&self, lane_id: u32, now: u64) -> Option<f64> {
+        self.average(lane_id, |ts| ts + RECENT_SECS <= now)
+    }
+
+    /// Average count of the lane over the last RECENT_SECS.
+    pub fn recent(&self, lane_id: u32, now: u64) -> Option<f64> {
+        self.average(lane_id, |ts| ts + RECENT_SECS > now)
+    }
+}
+
+/// An incident's lane on its way back to normal.
+#[derive(Debug)]
+struct Recovery {
+    /// The lane's average count before its first incident.
+    before: f64,
+    /// Incidents on the lane not yet cleared.
+    active: u32,
+    /// When the last of them cleared.
+    cleared_at: Option<u64>,
+}
+
+/// Measures how long a lane takes, once its incidents have cleared, to get
+/// back within RECOVERY_TOLERANCE of its average count before them.
+#[derive(Debug, Default)]
+pub struct RecoveryTracker {
+    lanes: HashMap<u32, Recovery>,
+}
+
+impl RecoveryTracker {
+    /// Notes a breakdown or clearance; `before` is the lane's average count
+    /// at the time, used if this is the lane's first incident.
+    pub fn incident(&mut self, incident: &Incident, before: f64) {
+        let recovery = self.lanes.entry(incident.lane_id).or_insert(Recovery { before, active: 0, cleared_at: None });
+        if incident.cleared {
+            recovery.active = recovery.active.saturating_sub(1);
+            if recovery.active == 0 {
+                recovery.cleared_at = Some(incident.timestamp);
+            }
+        } else {
+            recovery.active += 1;
+            recovery.cleared_at = None;
+        }
+    }
+
+    /// Given a lane's recent average count, returns the seconds it took to
+    /// recover if it just has, with its average before the incident.
+    pub fn check(&mut self, lane_id: u32, recent: f64, now: u64) -> Option<(u64, f64)> {
+        let recovery = self.lanes.get(&lane_id)?;
+        let cleared_at = recovery.cleared_at?;
+        if recent > recovery.before + RECOVERY_TOLERANCE {
+            return None;
+        }
+        let before = recovery.before;
+        self.lanes.remove(&lane_id);
+        Some((now.saturating_sub(cleared_at), before))
+    }
+}
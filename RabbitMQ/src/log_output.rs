@@ -0,0 +1,237 @@
+// log_output.rs
+//
+// Where and how system monitoring writes log events. With `--format text`
+// (the default) each event is one "[Time: t] LEVEL source: text" line; with
+// `--format json`, or RTS_LOG_FORMAT=json, it is the event itself as a JSON
+// line, so long runs can be post-processed. Events below RTS_LOG_LEVEL
+// (info, warn or error; info by default) are left out.
+//
+// Events go to stdout and, when RTS_LOG_FILE names one, to a log file in the
+// same format. The log file rotates once it reaches RTS_LOG_MAX_BYTES
+// (10 MiB by default): "run.log" moves to "run.log.1", "run.log.1" to
+// "run.log.2" and so on, and the oldest beyond RTS_LOG_KEEP (5 by default)
+// is dropped.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Stdout, Write};
+
+use crate::events::{Level, LogEvent};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: u32 = 5;
+
+/// How each event is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    fn parse(value: &str) -> Result<Format, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("invalid format '{}' (expected text or json)", value.trim())),
+        }
+    }
+
+    /// Format from `--format <text|json>` (or `--format=<...>`), else from
+    /// RTS_LOG_FORMAT; text, with a warning, if it is invalid.
+    pub fn from_env() -> Format {
+        let args: Vec<String> = std::env::args().collect();
+        let flag = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--format") {
+            Some("") => Some(("--format", args.get(i + 1).cloned().unwrap_or_default())),
+            Some(value) => value.strip_prefix('=').map(|value| ("--format", value.to_string())),
+            None => None,
+        });
+        let (source, value) = match flag {
+            Some(flag) => flag,
+            None => match std::env::var("RTS_LOG_FORMAT") {
+                Ok(value) => ("RTS_LOG_FORMAT", value),
+                Err(_) => return Format::Text,
+            },
+        };
+        Format::parse(&value).unwrap_or_else(|e| {
+            eprintln!("Ignoring {}: {}", source, e);
+            Format::Text
+        })
+    }
+
+    /// The event as one line, without the newline.
+    pub fn render(self, log: &LogEvent) -> String {
+        match self {
+            Format::Text => format!("[Time: {}] {} {}: {}", log.timestamp, log.level, log.source, log.describe()),
+            Format::Json => serde_json::to_string(log).unwrap(),
+        }
+    }
+}
+
+/// Lowest level written, from RTS_LOG_LEVEL; INFO, with a warning, if it is
+/// invalid.
+fn min_level_from_env() -> Level {
+    match std::env::var("RTS_LOG_LEVEL") {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            eprintln!("Ignoring RTS_LOG_LEVEL: {}", e);
+            Level::Info
+        }),
+        Err(_) => Level::Info,
+    }
+}
+
+/// Reads a positive number from environment variable `name`, or `default`.
+fn positive_from_env<T: std::str::FromStr + PartialOrd + Default + Copy>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => match value.trim().parse::<T>() {
+            Ok(parsed) if parsed > T::default() => parsed,
+            _ => {
+                eprintln!("Ignoring {}: invalid value '{}'", name, value.trim());
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// A log file that moves aside once it reaches `max_bytes`.
+pub struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    keep: u32,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl RotatingFile {
+    /// The file named by RTS_LOG_FILE, appended to, if set and it opens.
+    pub fn from_env() -> Option<RotatingFile> {
+        let path = std::env::var("RTS_LOG_FILE").ok().filter(|p| !p.is_empty())?;
+        let max_bytes = positive_from_env("RTS_LOG_MAX_BYTES", DEFAULT_MAX_BYTES);
+        let keep = positive_from_env("RTS_LOG_KEEP", DEFAULT_KEEP);
+        match Self::open(&path) {
+            Ok((file, written)) => {
+                println!("Writing log to {} (rotated every {} bytes, {} kept)", path, max_bytes, keep);
+                Some(RotatingFile { path, max_bytes, keep, file, written })
+            }
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn open(path: &str) -> std::io::Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok((BufWriter::new(file), written))
+    }
+
+    /// Shifts the older files up by one, dropping the last, and starts a
+    /// fresh file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        for n in (1..self.keep).rev() {
+            let from = format!("{}.{}", self.path, n);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        let (file, written) = Self::open(&self.path)?;
+        self.file = file;
+        self.written = written;
+        Ok(())
+    }
+
+    /// Appends `line` and a newline, rotating first if it would not fit.
+    pub fn write_line(&mut self, line: &str) {
+        let len = line.len() as u64 + 1;
+        if self.written > 0 && self.written + len > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path, e);
+            }
+        }
+        match writeln!(self.file, "{}", line) {
+            Ok(()) => self.written += len,
+            Err(e) => eprintln!("Failed to write log file: {}", e),
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.file.flush() {
+            eprintln!("Failed to write log file: {}", e);
+        }
+    }
+}
+
+/// Stdout and the log and event files system monitoring writes to.
+pub struct LogOutput {
+    pub format: Format,
+    min_level: Level,
+    pub stdout: BufWriter<Stdout>,
+    log_file: Option<RotatingFile>,
+    /// JSON-lines file of every event regardless of format and level; see
+    /// `open_event_file`.
+    event_file: Option<BufWriter<File>>,
+}
+
+/// Opens the JSON-lines event file named by RTS_EVENTS_PATH, if set. Every
+/// event is appended in the typed shape, including old-style messages.
+fn open_event_file() -> Option<BufWriter<File>> {
+    let path = std::env::var("RTS_EVENTS_PATH").ok().filter(|p| !p.is_empty())?;
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            println!("Writing events to {}", path);
+            Some(BufWriter::new(file))
+        }
+        Err(e) => {
+            eprintln!("Failed to open event file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+impl LogOutput {
+    /// Output configured from the command line and environment; see the
+    /// top of this file.
+    pub fn from_env() -> LogOutput {
+        LogOutput {
+            format: Format::from_env(),
+            min_level: min_level_from_env(),
+            stdout: BufWriter::new(std::io::stdout()),
+            log_file: RotatingFile::from_env(),
+            event_file: open_event_file(),
+        }
+    }
+
+    /// Writes an event everywhere. All outputs are buffered; see `flush`.
+    pub fn record(&mut self, log: &LogEvent) {
+        if let Some(file) = self.event_file.as_mut() {
+            let line = serde_json::to_string(log).unwrap();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("Failed to write event file: {}", e);
+            }
+        }
+        if log.level < self.min_level {
+            return;
+        }
+        let line = self.format.render(log);
+        writeln!(self.stdout, "{}", line).ok();
+        if let Some(file) = self.log_file.as_mut() {
+            file.write_line(&line);
+        }
+    }
+
+    /// Writes out what `record` has buffered.
+    pub fn flush(&mut self) {
+        self.stdout.flush().ok();
+        if let Some(file) = self.log_file.as_mut() {
+            file.flush();
+        }
+        if let Some(file) = self.event_file.as_mut() {
+            if let Err(e) = file.flush() {
+                eprintln!("Failed to write event file: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// metrics.rs
+//
+// Prometheus metrics for long-running deployments. The simulation and the
+// traffic light controller keep a process-wide registry, METRICS, fed from
+// the typed messages they already publish: log events go out through
+// `publish_log`, which counts vehicles, red turns and junction restarts and
+// records junction phases, the simulation's lane counts update the occupancy gauges and the
+// controller counts the recommendations it receives. The simulation also
+// reports how its lane counts were batched, aggregated or dropped on their
+// way out (see `rts_core::outbox`). Completed trips are
+// summed per vehicle class, so emergency and normal travel times can be
+// compared. Failed publishes and the latency of the others are
+// counted by the MqChannel itself, as is the latency of the messages each
+// component receives.
+//
+// System monitoring fills its registry from the log events of every
+// component instead (see `Metrics::observe_logged`), so one endpoint covers
+// the whole run: lane occupancy follows the cars' CarProgress events and
+// recommendations are counted as the flow analyzer logs them.
+//
+// With the `metrics` feature and RTS_METRICS_PORT set, the registry is
+// served in the Prometheus text format at http://0.0.0.0:<port>/metrics.
+// Every metric is present from the start, so an idle component reports
+// zeros.
+//
+// Only the simulation, traffic light and system monitoring bins include this
+// module, and each uses a different part of it.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use rts_core::lanes::Lane;
+use rts_core::messages::Recommendation;
+use rts_core::outbox::OutboxStats;
+
+use rts_core::progress::LaneTransition;
+
+use crate::events::{EventKind, LogEvent};
+use crate::mq::{self, MqChannel, PublishError, LATENCY_BUCKETS};
+
+/// Completed trips of one class of vehicle and their summed travel time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TravelTimes {
+    pub trips: u64,
+    pub total_secs: f64,
+}
+
+impl TravelTimes {
+    const fn new() -> TravelTimes {
+        TravelTimes { trips: 0, total_secs: 0.0 }
+    }
+
+    /// Mean travel time, if any trip was completed.
+    pub fn mean(&self) -> Option<f64> {
+        (self.trips > 0).then(|| self.total_secs / self.trips as f64)
+    }
+}
+
+/// Counters and gauges of one component.
+pub struct Metrics {
+    cars_spawned: AtomicU64,
+    cars_completed: AtomicU64,
+    right_turns_on_red: AtomicU64,
+    junction_restarts: AtomicU64,
+    recommendations: AtomicU64,
+    preemptions: AtomicU64,
+    /// What the simulation's lane count outbox has done so far.
+    update_batches: AtomicU64,
+    updates_sent: AtomicU64,
+    updates_aggregated: AtomicU64,
+    updates_dropped: AtomicU64,
+    emergency_travel: Mutex<TravelTimes>,
+    normal_travel: Mutex<TravelTimes>,
+    /// Vehicles on each lane, by lane id.
+    lane_occupancy: Mutex<BTreeMap<u32, u32>>,
+    /// Current phase index of each junction, by junction id.
+    junction_phase: Mutex<BTreeMap<u32, usize>>,
+}
+
+/// The process-wide registry.
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            cars_spawned: AtomicU64::new(0),
+            cars_completed: AtomicU64::new(0),
+            right_turns_on_red: AtomicU64::new(0),
+            junction_restarts: AtomicU64::new(0),
+            recommendations: AtomicU64::new(0),
+            preemptions: AtomicU64::new(0),
+            update_batches: AtomicU64::new(0),
+            updates_sent: AtomicU64::new(0),
+            updates_aggregated: AtomicU64::new(0),
+            updates_dropped: AtomicU64::new(0),
+            emergency_travel: Mutex::new(TravelTimes::new()),
+            normal_travel: Mutex::new(TravelTimes::new()),
+            lane_occupancy: Mutex::new(BTreeMap::new()),
+            junction_phase: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Reports every lane's occupancy, starting at zero.
+    pub fn register_lanes(&self, lanes: &[Lane]) {
+        let mut occupancy = self.lane_occupancy.lock().unwrap();
+        for lane in lanes {
+            occupancy.entry(lane.id).or_insert(0);
+        }
+    }
+
+    /// Reports the phase of every junction `lanes` lead into, starting at phase 0.
+    pub fn register_junctions(&self, lanes: &[Lane]) {
+        let mut phases = self.junction_phase.lock().unwrap();
+        for lane in lanes.iter().filter(|lane| lane.end_intersection != 0) {
+            phases.entry(lane.end_intersection).or_insert(0);
+        }
+    }
+
+    /// Counts a log event's vehicle, trip, red turn, preemption, junction
+    /// restart or phase change.
+    pub fn observe(&self, kind: &EventKind) {
+        match kind {
+            EventKind::VehicleGenerated { .. } => {
+                self.cars_spawned.fetch_add(1, Ordering::Relaxed);
+            }
+            EventKind::CarCompleted { emergency, total_time, .. } => {
+                self.cars_completed.fetch_add(1, Ordering::Relaxed);
+                let class = if *emergency { &self.emergency_travel } else { &self.normal_travel };
+                let mut travel = class.lock().unwrap();
+                travel.trips += 1;
+                travel.total_secs += total_time;
+            }
+            EventKind::EmergencyPreemption { .. } => {
+                self.preemptions.fetch_add(1, Ordering::Relaxed);
+            }
+            EventKind::RightTurnOnRed { .. } => {
+                self.right_turns_on_red.fetch_add(1, Ordering::Relaxed);
+            }
+            EventKind::JunctionControllerFailed { .. } => {
+                self.junction_restarts.fetch_add(1, Ordering::Relaxed);
+            }
+            EventKind::PhaseChange { junction, phase, .. } => {
+                self.junction_phase.lock().unwrap().insert(*junction, *phase);
+            }
+            _ => {}
+        }
+    }
+
+    /// Counts a log event received from any component: what `observe`
+    /// counts, plus the recommendations the flow analyzer logs and the lane
+    /// changes of the cars.
+    pub fn observe_logged(&self, kind: &EventKind) {
+        self.observe(kind);
+        match kind {
+            EventKind::Recommendation { .. } => {
+                self.recommendations.fetch_add(1, Ordering::Relaxed);
+            }
+            EventKind::CarProgress { lane_id, transition, .. } => {
+                let mut occupancy = self.lane_occupancy.lock().unwrap();
+                let count = occupancy.entry(*lane_id).or_insert(0);
+                *count = match transition {
+                    LaneTransition::Entered => count.saturating_add(1),
+                    LaneTransition::Exited => count.saturating_sub(1),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    pub fn observe_lane_count(&self, lane_id: u32, vehicle_count: u32) {
+        self.lane_occupancy.lock().unwrap().insert(lane_id, vehicle_count);
+    }
+
+    /// Records the totals of the simulation's lane count outbox.
+    pub fn observe_outbox(&self, stats: OutboxStats) {
+        self.update_batches.store(stats.batches, Ordering::Relaxed);
+        self.updates_sent.store(stats.sent, Ordering::Relaxed);
+        self.updates_aggregated.store(stats.aggregated, Ordering::Relaxed);
+        self.updates_dropped.store(stats.dropped, Ordering::Relaxed);
+    }
+
+    pub fn observe_recommendation(&self, _recommendation: &Recommendation) {
+        self.recommendations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Trips completed so far by emergency vehicles and by the others.
+    pub fn travel_times(&self) -> (TravelTimes, TravelTimes) {
+        (*self.emergency_travel.lock().unwrap(), *self.normal_travel.lock().unwrap())
+    }
+
+    /// The registry in the Prometheus text exposition format, with the
+    /// publish failures and latency and the delivery latency of the
+    /// component's MqChannel.
+    pub fn render(&self, channel: &MqChannel) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value).unwrap();
+        };
+        counter("cars_spawned_total", "Vehicles generated.", self.cars_spawned.load(Ordering::Relaxed));
+        counter("cars_completed_total", "Vehicles that completed their journey.", self.cars_completed.load(Ordering::Relaxed));
+        counter("right_turns_on_red_total", "Vehicles that turned right on a red light.",
+                self.right_turns_on_red.load(Ordering::Relaxed));
+        counter("junction_restarts_total", "Junction controller tasks restarted after a panic or stall.",
+                self.junction_restarts.load(Ordering::Relaxed));
+        counter("recommendations_issued_total", "Green time recommendations issued by the flow analyzer.",
+                self.recommendations.load(Ordering::Relaxed));
+        counter("emergency_preemptions_total", "Phase cycles interrupted for an emergency vehicle.",
+                self.preemptions.load(Ordering::Relaxed));
+        counter("simulation_update_batches_total", "Batches of lane counts published on simulation.updates.",
+                self.update_batches.load(Ordering::Relaxed));
+        counter("simulation_updates_sent_total", "Lane counts in the published batches.",
+                self.updates_sent.load(Ordering::Relaxed));
+        counter("simulation_updates_aggregated_total", "Lane count updates merged into a later one or a full snapshot.",
+                self.updates_aggregated.load(Ordering::Relaxed));
+        counter("simulation_updates_dropped_total", "Lane count updates dropped under backpressure.",
+                self.updates_dropped.load(Ordering::Relaxed));
+        counter("publish_failures_total", "Publishes that failed, after any retries.", channel.publish_failures());
+
+        let latency = channel.publish_latency();
+        out.push_str("# HELP publish_latency_seconds Time from publishing a message to the broker's confirm, retries included.\n# TYPE publish_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            cumulative += count;
+            writeln!(out, "publish_latency_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative).unwrap();
+        }
+        writeln!(out, "publish_latency_seconds_bucket{{le=\"+Inf\"}} {}", latency.count).unwrap();
+        writeln!(out, "publish_latency_seconds_sum {:.6}\npublish_latency_seconds_count {}", latency.sum_secs, latency.count).unwrap();
+
+        let delivery = channel.delivery_latency();
+        out.push_str("# HELP delivery_latency_seconds Time from publishing a message to receiving it, over the recent messages of each exchange.\n# TYPE delivery_latency_seconds gauge\n");
+        for (exchange, summary) in &delivery {
+            for (quantile, ms) in [("0.5", summary.p50_ms), ("0.95", summary.p95_ms), ("0.99", summary.p99_ms)] {
+                writeln!(out, "delivery_latency_seconds{{exchange=\"{}\",quantile=\"{}\"}} {:.3}", exchange, quantile, ms / 1000.0).unwrap();
+            }
+        }
+        out.push_str("# HELP messages_received_total Messages received, by exchange.\n# TYPE messages_received_total counter\n");
+        for (exchange, summary) in &delivery {
+            writeln!(out, "messages_received_total{{exchange=\"{}\"}} {}", exchange, summary.count).unwrap();
+        }
+
+        let (emergency, normal) = self.travel_times();
+        out.push_str("# HELP vehicle_trips_total Completed trips, by vehicle class.\n# TYPE vehicle_trips_total counter\n");
+        for (class, travel) in [("emergency", emergency), ("normal", normal)] {
+            writeln!(out, "vehicle_trips_total{{vehicle=\"{}\"}} {}", class, travel.trips).unwrap();
+        }
+        out.push_str("# HELP vehicle_travel_seconds_total Travel time of completed trips, by vehicle class.\n# TYPE vehicle_travel_seconds_total counter\n");
+        for (class, travel) in [("emergency", emergency), ("normal", normal)] {
+            writeln!(out, "vehicle_travel_seconds_total{{vehicle=\"{}\"}} {:.3}", class, travel.total_secs).unwrap();
+        }
+
+        out.push_str("# HELP lane_occupancy Vehicles currently on the lane.\n# TYPE lane_occupancy gauge\n");
+        for (lane_id, count) in self.lane_occupancy.lock().unwrap().iter() {
+            writeln!(out, "lane_occupancy{{lane=\"{}\"}} {}", lane_id, count).unwrap();
+        }
+        out.push_str("# HELP junction_phase Index of the phase the junction is serving.\n# TYPE junction_phase gauge\n");
+        for (junction, phase) in self.junction_phase.lock().unwrap().iter() {
+            writeln!(out, "junction_phase{{junction=\"{}\"}} {}", junction, phase).unwrap();
+        }
+        out
+    }
+}
+
+/// Publishes `log` on the "logs" exchange, counting it in METRICS first.
+pub async fn publish_log(channel: &MqChannel, log: &LogEvent) -> Result<(), PublishError> {
+    METRICS.observe(&log.kind);
+    mq::publish_message(channel, mq::LOGS, log).await
+}
+
+/// Port from RTS_METRICS_PORT, if it is set (with a warning if it is invalid).
+fn port_from_env() -> Option<u16> {
+    let value = std::env::var("RTS_METRICS_PORT").ok()?;
+    match value.trim().parse() {
+        Ok(port) => Some(port),
+        Err(_) => {
+            eprintln!("Ignoring RTS_METRICS_PORT: invalid port '{}'", value.trim());
+            None
+        }
+    }
+}
+
+/// Serves METRICS on RTS_METRICS_PORT in a background task, if it is set.
+#[cfg(feature = "metrics")]
+pub fn start(component: &'static str, channel: MqChannel) {
+    let Some(port) = port_from_env() else { return };
+    tokio::spawn(async move {
+        let app = axum::Router::new().route(
+            "/metrics",
+            axum::routing::get(move || {
+                let body = METRICS.render(&channel);
+                async move { ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body) }
+            }),
+        );
+        let served = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => {
+                println!("Serving {} metrics on http://0.0.0.0:{}/metrics", component, port);
+                axum::serve(listener, app).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = served {
+            eprintln!("Metrics endpoint on port {} stopped: {}", port, e);
+        }
+    });
+}
+
+/// Without the `metrics` feature there is nothing to serve; say so if a port
+/// was asked for.
+#[cfg(not(feature = "metrics"))]
+pub fn start(component: &'static str, _channel: MqChannel) {
+    if port_from_env().is_some() {
+        eprintln!("Metrics requested for {} but it was built without the metrics feature", component);
+    }
+}
@@ -1,44 +1,776 @@
 // mq.rs
-use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties, Channel, ExchangeKind, BasicProperties};
-use tokio_amqp::*;
+//
+// Broker plumbing shared by the bins. Everything goes through an
+// MqChannel, over the MessageBus of `--transport` or RTS_TRANSPORT (RabbitMQ
+// unless told otherwise; see `bus`): publishes wait for the broker to take
+// them and are retried with exponential backoff, and the bus re-connects on
+// its own when the broker goes away.
+// A publish that still fails once the RetryPolicy is exhausted is kept in a
+// local backlog instead, and the component carries on. While the backlog
+// holds messages, every publish joins it, and the backlog is sent in order,
+// with its own exponential backoff between attempts, ahead of the next
+// publish once the broker is back. The component then logs a
+// BrokerReconnected event on "logs". Only a publish that finds the backlog
+// full (BACKLOG_CAPACITY messages), and the broker still not taking it, is
+// returned as an error and reported through `MqChannel::failed`, so each
+// bin can shut down on it instead of panicking. Retries are only logged locally, since the broker is what is
+// failing. The time each confirmed publish took, retries included, is kept
+// as a PublishLatency histogram.
+//
+// Every message goes out sealed in an `rts_core::messages::Envelope`: the
+// schema version, the component (its bin's name), the message's number on
+// its exchange and the wall-clock time it was published. Subscriptions open
+// the envelopes again and hand on the bare message, so consumers parse what
+// they always did. A message without an envelope, from a component that
+// predates them, is handed on as it is; one with a schema version newer
+// than this component's is dropped, with a warning the first time each
+// sender's version is seen, since this component may misread it. Opening
+// an envelope stamps it with the time it was taken, and the latency of each
+// exchange's messages since they were published is kept for
+// `MqChannel::delivery_latency` (see `rts_core::latency`).
+//
+// Exchange names live here too. With a namespace, from `--namespace <name>`
+// or RTS_NAMESPACE, every exchange is prefixed with it ("alice.logs"), so
+// runs sharing a broker only see the components started with the same
+// namespace; the queues are server-named and bound to the prefixed
+// exchanges, so nothing crosses between namespaces. Code names exchanges by
+// the constants below and lets `publish_message`, `declare_exchange` and
+// `MqChannel::subscribe` prefix them. For
+// isolation at the broker level, give each run its own virtual host in the
+// AMQP_ADDR path instead.
+//
+// Every bin includes this module, and each uses a different part of it.
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::{future, StreamExt};
+use tokio::sync::{watch, Mutex};
 use serde::Serialize;
-use serde_json;
-
-/// Create a RabbitMQ channel using a connection string from the AMQP_ADDR environment variable.
-pub async fn create_channel() -> Channel {
-    let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-    let connection = Connection::connect(&addr, ConnectionProperties::default().with_tokio())
-        .await
-        .expect("Failed to connect to RabbitMQ");
-    connection.create_channel().await.expect("Failed to create channel")
-}
-
-/// Publish a serializable message to the specified exchange and routing key.
-pub async fn publish_message<T: Serialize>(channel: &Channel, exchange: &str, routing_key: &str, message: &T) {
-    let payload = serde_json::to_vec(message).expect("Failed to serialize message");
-    channel
-        .basic_publish(
-            exchange,
-            routing_key,
-            BasicPublishOptions::default(),
-            &payload,
-            BasicProperties::default(),
-        )
-        .await
-        .expect("Failed to publish message")
-        .await
-        .expect("Publish not confirmed");
-}
-
-/// Declare an exchange if it does not already exist.
-pub async fn declare_exchange(channel: &Channel, exchange: &str, kind: ExchangeKind) {
-    channel
-        .exchange_declare(
-            exchange,
-            kind,
-            ExchangeDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await
-        .expect("Failed to declare exchange");
+
+use crate::bus::{BusResult, MessageBus, Messages, Transport};
+use crate::clock::SimClock;
+use rts_core::latency::{self, LatencySamples, LatencySummary};
+use rts_core::messages::{self, Envelope, EnvelopeError, Level, LogEvent};
+
+/// Log events of every component.
+pub const LOGS: &str = "logs";
+/// Component liveness; see `heartbeat`.
+pub const HEARTBEATS: &str = "heartbeats";
+/// Lane counts and snapshots from the simulation.
+pub const SIMULATION_UPDATES: &str = "simulation.updates";
+/// Light colors and snapshots from the traffic light controller.
+pub const LIGHT_STATUS: &str = "light_status";
+/// Where every car is along its lane, from the simulation.
+pub const CAR_POSITIONS: &str = "car_positions";
+/// Green time recommendations from the flow analyzer.
+pub const RECOMMENDATIONS: &str = "recommendations";
+/// Recommendations the traffic light controller has applied.
+pub const RECOMMENDATIONS_APPLIED: &str = "recommendations.applied";
+/// Gridlock reroute advisories from the flow analyzer.
+pub const REROUTE_ADVISORIES: &str = "reroute_advisories";
+/// Green requests of approaching emergency vehicles, from the simulation.
+pub const PREEMPTION: &str = "preemption";
+/// Lane closures and reopenings, e.g. from `roadworks`.
+pub const LANE_CLOSURES: &str = "lane_closures";
+/// Breakdowns and their clearances, from the simulation; see `incidents`.
+pub const INCIDENTS: &str = "incidents";
+/// Shutdown announcements from the simulation; see `control`.
+pub const CONTROL: &str = "control";
+
+/// How often an operation against the broker is attempted, and how long to
+/// wait between attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Eight attempts over roughly 24 seconds, long enough to ride out a
+    /// broker restart.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 8,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after failed attempt number `attempt` (starting at 1): the
+    /// initial backoff, doubled per attempt, capped at `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        self.initial_backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+}
+
+/// Messages a component keeps while the broker is unreachable before it gives up.
+pub const BACKLOG_CAPACITY: usize = 10_000;
+
+#[derive(Debug)]
+pub enum PublishError {
+    Serialize(serde_json::Error),
+    /// Every attempt failed and the backlog was full; `last` is why the
+    /// final attempt failed.
+    Exhausted { exchange: String, attempts: u32, last: String },
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::Serialize(e) => write!(f, "failed to serialize message: {}", e),
+            PublishError::Exhausted { exchange, attempts, last } => write!(
+                f,
+                "publish to '{}' failed after {} attempts with {} messages already waiting: {}",
+                exchange, attempts, BACKLOG_CAPACITY, last
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+/// Upper bounds, in seconds, of the PublishLatency buckets.
+pub const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.1, 0.5, 2.5];
+
+/// How long successful publishes took, from the first attempt to the
+/// broker's confirm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PublishLatency {
+    /// Publishes that took at most each of LATENCY_BUCKETS, not cumulative;
+    /// slower ones are only in `count`.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+impl PublishLatency {
+    fn record(&mut self, took: Duration) {
+        let secs = took.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+}
+
+/// A message waiting in the backlog.
+struct Pending {
+    exchange: String,
+    payload: Vec<u8>,
+}
+
+/// Publishes kept while the broker is unreachable.
+#[derive(Default)]
+struct Backlog {
+    pending: VecDeque<Pending>,
+    /// Messages kept since the outage began.
+    buffered: u64,
+    /// When the outage began, if the broker is unreachable.
+    since: Option<Instant>,
+    /// Failed attempts at sending the backlog, and when to try again.
+    failed_flushes: u32,
+    next_flush: Option<Instant>,
+}
+
+/// A connection to the broker. Clones share the same underlying bus.
+#[derive(Clone)]
+pub struct MqChannel {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    bus: Box<dyn MessageBus>,
+    policy: RetryPolicy,
+    /// Prefix of every exchange name, if any.
+    namespace: Option<String>,
+    /// The component, as the source of its envelopes.
+    source: String,
+    /// Number of the last message sealed for each (namespaced) exchange.
+    sequences: std::sync::Mutex<HashMap<String, u64>>,
+    backlog: Mutex<Backlog>,
+    /// For the timestamp of BrokerReconnected events.
+    clock: SimClock,
+    /// Set once a publish has given up.
+    failure: watch::Sender<Option<String>>,
+    /// Publishes that returned an error.
+    publish_failures: AtomicU64,
+    /// Publishes that succeeded, and how long they took.
+    publish_latency: std::sync::Mutex<PublishLatency>,
+    /// Latency of the messages received, by exchange (without the namespace).
+    delivery_latency: std::sync::Mutex<BTreeMap<String, LatencySamples>>,
+}
+
+impl Inner {
+    fn exchange(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}.{}", namespace, name),
+            None => name.to_string(),
+        }
+    }
+}
+
+/// Checks a namespace: letters, digits, '-' and '_' only, so the prefixed
+/// names stay valid and a namespace cannot contain another's separator.
+fn parse_namespace(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("empty namespace".to_string());
+    }
+    if !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("invalid namespace '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Namespace from `--namespace <name>` (or `--namespace=<name>`), else from
+/// RTS_NAMESPACE; none, with a warning, if it is invalid.
+fn namespace_from_env() -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    namespace_from(&args, std::env::var("RTS_NAMESPACE").ok())
+}
+
+/// As `namespace_from_env`, from the command line `args` and the value of
+/// RTS_NAMESPACE, `env`.
+fn namespace_from(args: &[String], env: Option<String>) -> Option<String> {
+    let flag = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--namespace") {
+        Some("") => Some(("--namespace", args.get(i + 1).cloned().unwrap_or_default())),
+        Some(value) => value.strip_prefix('=').map(|value| ("--namespace", value.to_string())),
+        None => None,
+    });
+    let (source, value) = match flag {
+        Some(flag) => flag,
+        None => ("RTS_NAMESPACE", env?),
+    };
+    match parse_namespace(&value) {
+        Ok(namespace) => Some(namespace),
+        Err(e) => {
+            eprintln!("Ignoring {}: {}", source, e);
+            None
+        }
+    }
+}
+
+/// Name of the running bin, e.g. `simulation`.
+fn component() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .unwrap_or_default()
+}
+
+/// Hands on the messages of exchange `name` without their envelopes,
+/// recording their latency; see the top of this file.
+fn open_envelopes(inner: Arc<Inner>, name: &str, messages: Messages) -> Messages {
+    let (name, exchange) = (name.to_string(), inner.exchange(name));
+    let mut warned = HashSet::new();
+    messages
+        .filter_map(move |delivery| {
+            let opened = match delivery {
+                Ok(payload) => match messages::open::<serde_json::Value>(&payload) {
+                    Ok(envelope) => {
+                        if envelope.schema_version > 0 {
+                            let mut delivery_latency = inner.delivery_latency.lock().unwrap();
+                            let samples = delivery_latency.entry(name.clone()).or_default();
+                            samples.record(envelope.emitted_at_ms, latency::unix_millis());
+                        }
+                        Some(Ok(serde_json::to_vec(&envelope.payload).unwrap()))
+                    }
+                    Err(EnvelopeError::UnknownVersion { version, source }) => {
+                        if warned.insert((source.clone(), version)) {
+                            eprintln!("WARNING: dropping messages on '{}' from '{}' with unknown schema version {}",
+                                      exchange, source, version);
+                        }
+                        None
+                    }
+                    // Left for the consumer to report, as it would without envelopes.
+                    Err(EnvelopeError::Malformed(_)) => Some(Ok(payload)),
+                },
+                Err(e) => Some(Err(e)),
+            };
+            future::ready(opened)
+        })
+        .boxed()
+}
+
+/// Connect to the broker of the run's transport (see `bus`), retrying the
+/// connection per the default RetryPolicy. Exchanges are namespaced per
+/// `--namespace` or RTS_NAMESPACE.
+pub async fn create_channel() -> BusResult<MqChannel> {
+    let transport = Transport::from_env();
+    if transport != Transport::RabbitMq {
+        println!("Using the {} transport", transport);
+    }
+    let namespace = namespace_from_env();
+    if let Some(namespace) = &namespace {
+        println!("Using exchange namespace '{}'", namespace);
+    }
+    let policy = RetryPolicy::default();
+    let mut attempt = 1;
+    let bus = loop {
+        match transport.connect().await {
+            Ok(bus) => break bus,
+            Err(e) if attempt < policy.max_attempts => {
+                let wait = policy.backoff(attempt);
+                eprintln!("WARNING: failed to connect to {} (attempt {}/{}): {}; retrying in {:.1}s",
+                          transport, attempt, policy.max_attempts, e, wait.as_secs_f64());
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    Ok(MqChannel::over(bus, policy, namespace))
+}
+
+impl MqChannel {
+    /// A channel publishing through `bus` per `policy`, its exchanges in
+    /// `namespace` if any.
+    fn over(bus: Box<dyn MessageBus>, policy: RetryPolicy, namespace: Option<String>) -> MqChannel {
+        let (failure, _) = watch::channel(None);
+        MqChannel {
+            inner: Arc::new(Inner {
+                bus,
+                policy,
+                namespace,
+                source: component(),
+                sequences: std::sync::Mutex::new(HashMap::new()),
+                backlog: Mutex::new(Backlog::default()),
+                clock: SimClock::from_env(),
+                failure,
+                publish_failures: AtomicU64::new(0),
+                publish_latency: std::sync::Mutex::new(PublishLatency::default()),
+                delivery_latency: std::sync::Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// Full name of exchange `name` (one of the constants above) in this
+    /// channel's namespace.
+    pub fn exchange(&self, name: &str) -> String {
+        self.inner.exchange(name)
+    }
+
+    /// Every message published on exchange `name` (in this channel's
+    /// namespace) from now on; `consumer` names the subscription.
+    pub async fn subscribe(&self, name: &str, consumer: &str) -> BusResult<Messages> {
+        let messages = self.inner.bus.subscribe(&self.exchange(name), consumer).await?;
+        Ok(open_envelopes(Arc::clone(&self.inner), name, messages))
+    }
+
+    /// As `subscribe`, with at most `window` messages sent ahead; see
+    /// `MessageBus::subscribe_windowed`.
+    pub async fn subscribe_windowed(&self, name: &str, consumer: &str, window: u16) -> BusResult<Messages> {
+        let messages = self.inner.bus.subscribe_windowed(&self.exchange(name), consumer, window).await?;
+        Ok(open_envelopes(Arc::clone(&self.inner), name, messages))
+    }
+
+    /// `message` sealed in the next envelope of `exchange` (namespaced).
+    fn seal<T: Serialize>(&self, exchange: &str, message: &T) -> serde_json::Result<Vec<u8>> {
+        let sequence = {
+            let mut sequences = self.inner.sequences.lock().unwrap();
+            let last = sequences.entry(exchange.to_string()).or_insert(0);
+            *last += 1;
+            *last
+        };
+        serde_json::to_vec(&Envelope::new(self.inner.source.as_str(), sequence, message))
+    }
+
+    /// Resolves with the reason once a publish has given up after every
+    /// retry; select on it to shut down.
+    pub async fn failed(&self) -> String {
+        let mut failure = self.inner.failure.subscribe();
+        let reason = match failure.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap_or_default(),
+            // The sender lives as long as `self`, so this never happens.
+            Err(_) => return std::future::pending().await,
+        };
+        reason
+    }
+
+    /// Number of publishes through this channel (or its clones) that
+    /// returned an error, whether serializing or after every retry.
+    pub fn publish_failures(&self) -> u64 {
+        self.inner.publish_failures.load(Ordering::Relaxed)
+    }
+
+    /// Latency of the publishes through this channel (or its clones) that
+    /// succeeded.
+    pub fn publish_latency(&self) -> PublishLatency {
+        *self.inner.publish_latency.lock().unwrap()
+    }
+
+    /// End-to-end latency of the messages received through this channel (or
+    /// its clones), from publish to subscriber, per exchange in name order.
+    pub fn delivery_latency(&self) -> Vec<(String, LatencySummary)> {
+        let delivery_latency = self.inner.delivery_latency.lock().unwrap();
+        delivery_latency.iter().map(|(name, samples)| (name.clone(), samples.summary())).collect()
+    }
+
+    async fn try_publish(&self, exchange: &str, payload: &[u8]) -> Result<(), String> {
+        self.inner.bus.publish(exchange, payload).await.map_err(|e| e.to_string())
+    }
+
+    /// Sends the backlog, oldest first, if its backoff has passed; stops at
+    /// the first message the broker does not take. Once it is empty, the
+    /// outage is over and a BrokerReconnected event is logged.
+    async fn flush(&self, backlog: &mut Backlog) {
+        if backlog.next_flush.is_some_and(|next| Instant::now() < next) {
+            return;
+        }
+        while let Some(pending) = backlog.pending.front() {
+            if let Err(e) = self.try_publish(&pending.exchange, &pending.payload).await {
+                backlog.failed_flushes += 1;
+                let wait = self.inner.policy.backoff(backlog.failed_flushes);
+                backlog.next_flush = Some(Instant::now() + wait);
+                eprintln!("WARNING: broker still unreachable ({} message(s) waiting): {}; retrying in {:.1}s",
+                          backlog.pending.len(), e, wait.as_secs_f64());
+                return;
+            }
+            backlog.pending.pop_front();
+        }
+        let outage = backlog.since.take().map_or(Duration::ZERO, |since| since.elapsed());
+        let buffered = std::mem::take(&mut backlog.buffered);
+        backlog.failed_flushes = 0;
+        backlog.next_flush = None;
+        println!("Broker connection restored after {:.1}s; sent {} buffered message(s)", outage.as_secs_f64(), buffered);
+        self.log_reconnected(outage, buffered).await;
+    }
+
+    /// Logs a BrokerReconnected event. This module is shared with bins that
+    /// don't know the log event kinds, so the event is built as JSON.
+    async fn log_reconnected(&self, outage: Duration, buffered: u64) {
+        let component = self.inner.source.clone();
+        let kind = serde_json::json!({
+            "type": "BrokerReconnected",
+            "component": component,
+            "outage_secs": outage.as_secs_f64(),
+            "buffered": buffered,
+        });
+        let event = LogEvent::new(component, self.inner.clock.now_secs(), kind).with_level(Level::Warn);
+        let exchange = self.exchange(LOGS);
+        let declared = self.inner.bus.declare(&exchange).await.is_ok();
+        let payload = self.seal(&exchange, &event).unwrap();
+        if !declared || self.try_publish(&exchange, &payload).await.is_err() {
+            eprintln!("WARNING: could not log the reconnection to the broker");
+        }
+    }
+
+    /// Keeps a message the broker did not take. Returns it back if the
+    /// backlog is full.
+    async fn buffer(&self, pending: Pending) -> Result<(), Pending> {
+        let mut backlog = self.inner.backlog.lock().await;
+        if backlog.pending.len() >= BACKLOG_CAPACITY {
+            return Err(pending);
+        }
+        if backlog.since.is_none() {
+            eprintln!("WARNING: broker unreachable; keeping publishes until it is back");
+            backlog.since = Some(Instant::now());
+            backlog.failed_flushes = 1;
+            backlog.next_flush = Some(Instant::now() + self.inner.policy.backoff(1));
+        }
+        backlog.pending.push_back(pending);
+        backlog.buffered += 1;
+        Ok(())
+    }
+}
+
+/// Publish a serializable message to the specified exchange (in the channel's
+/// namespace), sealed in the exchange's next envelope, retrying per the channel's RetryPolicy until the
+/// broker confirms it. A message the broker doesn't take in time, or that
+/// comes while the backlog holds others, is kept in the backlog and sent
+/// once the broker is back; only a full backlog is an error.
+pub async fn publish_message<T: Serialize>(
+    mq: &MqChannel,
+    exchange: &str,
+    message: &T,
+) -> Result<(), PublishError> {
+    let exchange = mq.exchange(exchange);
+    let payload = mq.seal(&exchange, message).map_err(|e| {
+        mq.inner.publish_failures.fetch_add(1, Ordering::Relaxed);
+        PublishError::Serialize(e)
+    })?;
+    {
+        let mut backlog = mq.inner.backlog.lock().await;
+        if backlog.pending.len() >= BACKLOG_CAPACITY {
+            // Make room first if the broker is back, or a full backlog
+            // would refuse every publish and never be sent.
+            mq.flush(&mut backlog).await;
+        }
+        if !backlog.pending.is_empty() {
+            let pending = Pending { exchange, payload };
+            if backlog.pending.len() >= BACKLOG_CAPACITY {
+                return Err(give_up(mq, pending.exchange, 1, "broker unreachable".to_string()));
+            }
+            backlog.pending.push_back(pending);
+            backlog.buffered += 1;
+            mq.flush(&mut backlog).await;
+            return Ok(());
+        }
+    }
+    let policy = mq.inner.policy;
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let last = match mq.try_publish(&exchange, &payload).await {
+            Ok(()) => {
+                mq.inner.publish_latency.lock().unwrap().record(started.elapsed());
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if attempt == policy.max_attempts {
+            let pending = Pending { exchange, payload };
+            return match mq.buffer(pending).await {
+                Ok(()) => Ok(()),
+                Err(pending) => Err(give_up(mq, pending.exchange, attempt, last)),
+            };
+        }
+        let wait = policy.backoff(attempt);
+        eprintln!("WARNING: publish to '{}' failed (attempt {}/{}): {}; retrying in {:.1}s",
+                  exchange, attempt, policy.max_attempts, last, wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+/// Counts a publish that gave up and reports it through `MqChannel::failed`.
+fn give_up(mq: &MqChannel, exchange: String, attempts: u32, last: String) -> PublishError {
+    let error = PublishError::Exhausted { exchange, attempts, last };
+    mq.inner.publish_failures.fetch_add(1, Ordering::Relaxed);
+    mq.inner.failure.send_replace(Some(error.to_string()));
+    error
+}
+
+/// Declare an exchange (in the channel's namespace) if it does not already
+/// exist, retrying per the channel's RetryPolicy. The bus declares it again
+/// whenever it re-connects, so one the broker could not be reached for is
+/// declared then.
+pub async fn declare_exchange(mq: &MqChannel, exchange: &str) {
+    let exchange = mq.exchange(exchange);
+    let policy = mq.inner.policy;
+    for attempt in 1..=policy.max_attempts {
+        match mq.inner.bus.declare(&exchange).await {
+            Ok(()) => break,
+            Err(e) if attempt < policy.max_attempts => {
+                let wait = policy.backoff(attempt);
+                eprintln!("WARNING: failed to declare exchange '{}' (attempt {}/{}): {}; retrying in {:.1}s",
+                          exchange, attempt, policy.max_attempts, e, wait.as_secs_f64());
+                tokio::time::sleep(wait).await;
+            }
+            Err(e) => eprintln!("WARNING: failed to declare exchange '{}': {}; declaring it on reconnect", exchange, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::BoxFuture;
+    use futures_util::stream;
+
+    use crate::bus::BusError;
+
+    /// An in-process fanout bus whose next `failures` publishes fail, as
+    /// if the broker were down. Clones share the same topics.
+    #[derive(Clone, Default)]
+    struct MockBus {
+        state: Arc<MockState>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        failures: std::sync::Mutex<usize>,
+        /// Every payload the bus took, with its topic, in order.
+        published: std::sync::Mutex<Vec<(String, Vec<u8>)>>,
+        subscribers: std::sync::Mutex<HashMap<String, Vec<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>>>,
+    }
+
+    impl MockBus {
+        fn failing(failures: usize) -> MockBus {
+            let bus = MockBus::default();
+            *bus.state.failures.lock().unwrap() = failures;
+            bus
+        }
+
+        /// Messages published on `topic`, out of their envelopes.
+        fn messages(&self, topic: &str) -> Vec<serde_json::Value> {
+            let published = self.state.published.lock().unwrap();
+            published
+                .iter()
+                .filter(|(t, _)| t == topic)
+                .map(|(_, payload)| messages::open::<serde_json::Value>(payload).unwrap().payload)
+                .collect()
+        }
+    }
+
+    impl MessageBus for MockBus {
+        fn declare<'a>(&'a self, _topic: &'a str) -> BoxFuture<'a, BusResult<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8]) -> BoxFuture<'a, BusResult<()>> {
+            Box::pin(async move {
+                {
+                    let mut failures = self.state.failures.lock().unwrap();
+                    if *failures > 0 {
+                        *failures -= 1;
+                        return Err(BusError("broker down".to_string()));
+                    }
+                }
+                self.state.published.lock().unwrap().push((topic.to_string(), payload.to_vec()));
+                if let Some(subscribers) = self.state.subscribers.lock().unwrap().get(topic) {
+                    for subscriber in subscribers {
+                        subscriber.send(payload.to_vec()).ok();
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        fn subscribe<'a>(&'a self, topic: &'a str, _name: &'a str) -> BoxFuture<'a, BusResult<Messages>> {
+            Box::pin(async move {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                self.state.subscribers.lock().unwrap().entry(topic.to_string()).or_default().push(tx);
+                let messages = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|payload| (Ok(payload), rx)) });
+                Ok(messages.boxed())
+            })
+        }
+    }
+
+    /// Two attempts per publish, a millisecond apart.
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy { max_attempts: 2, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) }
+    }
+
+    fn has_failed(mq: &MqChannel) -> bool {
+        mq.inner.failure.borrow().is_some()
+    }
+
+    #[tokio::test]
+    async fn backlog_flushes_in_order_once_the_broker_is_back() {
+        // The first publish fails both attempts and the backlog's flushes
+        // fail three more times: messages 1 to 5 are kept, and the fifth
+        // publish sends them once the broker takes messages again.
+        let bus = MockBus::failing(5);
+        let mq = MqChannel::over(Box::new(bus.clone()), quick_policy(), None);
+        for n in 1..=6 {
+            publish_message(&mq, SIMULATION_UPDATES, &n).await.unwrap();
+            // Past the backlog's backoff, so the next publish tries to flush.
+            tokio::time::sleep(Duration::from_millis(3)).await;
+        }
+        let sent: Vec<serde_json::Value> = (1..=6).map(serde_json::Value::from).collect();
+        assert_eq!(bus.messages(SIMULATION_UPDATES), sent);
+        assert!(mq.inner.backlog.lock().await.pending.is_empty());
+        // The outage is logged once it is over; it is not a failure.
+        let reconnected = bus.messages(LOGS);
+        assert_eq!(reconnected.len(), 1);
+        assert_eq!(reconnected[0]["kind"]["type"], "BrokerReconnected");
+        assert_eq!(reconnected[0]["kind"]["buffered"], 5);
+        assert!(!has_failed(&mq));
+        assert_eq!(mq.publish_failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn only_a_full_backlog_fails_the_channel() {
+        let bus = MockBus::failing(usize::MAX);
+        let mq = MqChannel::over(Box::new(bus.clone()), quick_policy(), None);
+        for n in 0..BACKLOG_CAPACITY {
+            publish_message(&mq, SIMULATION_UPDATES, &n).await.unwrap();
+            assert!(!has_failed(&mq), "failed with {} message(s) kept", n + 1);
+        }
+        assert_eq!(mq.inner.backlog.lock().await.pending.len(), BACKLOG_CAPACITY);
+
+        let overflow = publish_message(&mq, SIMULATION_UPDATES, &BACKLOG_CAPACITY).await;
+        assert!(matches!(overflow, Err(PublishError::Exhausted { .. })), "{:?}", overflow);
+        assert!(has_failed(&mq));
+        assert_eq!(mq.publish_failures(), 1);
+        assert_eq!(mq.inner.backlog.lock().await.pending.len(), BACKLOG_CAPACITY);
+
+        // Once the broker is back, the kept messages go out oldest first.
+        *bus.state.failures.lock().unwrap() = 0;
+        tokio::time::sleep(Duration::from_millis(3)).await;
+        publish_message(&mq, SIMULATION_UPDATES, &"after").await.unwrap();
+        let sent = bus.messages(SIMULATION_UPDATES);
+        assert_eq!(sent.len(), BACKLOG_CAPACITY + 1);
+        assert!(sent[..BACKLOG_CAPACITY].iter().enumerate().all(|(n, message)| *message == n), "out of order");
+        assert_eq!(sent[BACKLOG_CAPACITY], "after");
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn namespaces_are_single_words() {
+        assert_eq!(parse_namespace(" alice ").unwrap(), "alice");
+        assert_eq!(parse_namespace("run_2-b").unwrap(), "run_2-b");
+        for invalid in ["", "  ", "a.b", "a b", "alice/logs", "ü"] {
+            assert!(parse_namespace(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn namespace_flag_wins_over_the_environment() {
+        let env = || Some("bob".to_string());
+        assert_eq!(namespace_from(&args("simulation --namespace alice"), env()).as_deref(), Some("alice"));
+        assert_eq!(namespace_from(&args("simulation --namespace=alice"), env()).as_deref(), Some("alice"));
+        assert_eq!(namespace_from(&args("simulation"), env()).as_deref(), Some("bob"));
+        assert_eq!(namespace_from(&args("simulation"), None), None);
+        // Invalid or missing values are ignored, not replaced by RTS_NAMESPACE.
+        assert_eq!(namespace_from(&args("simulation --namespace a.b"), env()), None);
+        assert_eq!(namespace_from(&args("simulation --namespace"), env()), None);
+        assert_eq!(namespace_from(&args("simulation"), Some("a.b".to_string())), None);
+        // Only the flag itself, not one that merely starts the same.
+        assert_eq!(namespace_from(&args("simulation --namespaces alice"), env()).as_deref(), Some("bob"));
+    }
+
+    #[tokio::test]
+    async fn exchanges_and_subscriptions_are_prefixed() {
+        let bus = MockBus::default();
+        let alice = MqChannel::over(Box::new(bus.clone()), quick_policy(), Some("alice".to_string()));
+        let plain = MqChannel::over(Box::new(bus.clone()), quick_policy(), None);
+        assert_eq!(alice.exchange(LOGS), "alice.logs");
+        assert_eq!(alice.exchange(RECOMMENDATIONS_APPLIED), "alice.recommendations.applied");
+        assert_eq!(plain.exchange(LOGS), "logs");
+
+        let _messages = alice.subscribe(LIGHT_STATUS, "lights").await.unwrap();
+        assert!(bus.state.subscribers.lock().unwrap().contains_key("alice.light_status"));
+        publish_message(&alice, LIGHT_STATUS, &"green").await.unwrap();
+        publish_message(&plain, LIGHT_STATUS, &"red").await.unwrap();
+        assert_eq!(bus.messages("alice.light_status"), vec![serde_json::Value::from("green")]);
+        assert_eq!(bus.messages(LIGHT_STATUS), vec![serde_json::Value::from("red")]);
+    }
+
+    #[tokio::test]
+    async fn namespaced_channels_on_one_bus_do_not_see_each_other() {
+        let bus = MockBus::default();
+        let alice = MqChannel::over(Box::new(bus.clone()), quick_policy(), Some("alice".to_string()));
+        let bob = MqChannel::over(Box::new(bus.clone()), quick_policy(), Some("bob".to_string()));
+        let mut alice_logs = alice.subscribe(LOGS, "monitor").await.unwrap();
+        let mut bob_logs = bob.subscribe(LOGS, "monitor").await.unwrap();
+
+        for n in 0..3 {
+            publish_message(&alice, LOGS, &format!("alice {}", n)).await.unwrap();
+            publish_message(&bob, LOGS, &format!("bob {}", n)).await.unwrap();
+        }
+        for (name, logs) in [("alice", &mut alice_logs), ("bob", &mut bob_logs)] {
+            for n in 0..3 {
+                let payload = logs.next().await.unwrap().unwrap();
+                let message: String = serde_json::from_slice(&payload).unwrap();
+                assert_eq!(message, format!("{} {}", name, n));
+            }
+            let more = tokio::time::timeout(Duration::from_millis(50), logs.next()).await;
+            assert!(more.is_err(), "{} received another namespace's message", name);
+        }
+    }
 }
@@ -0,0 +1,196 @@
+// recorder.rs
+//
+// Captures the message traffic of a run and plays it back, so the flow
+// analyzer and the traffic light controller can be exercised against the
+// same traffic without running the simulation again.
+//
+//   recorder record <file>   records until the simulation shuts down or Ctrl-C
+//   recorder replay <file>   publishes the recording again, at its original pace
+//
+// Every message on RECORDED_EXCHANGES is written as one JSON line: when it
+// arrived, in milliseconds after recording started, the exchange it came in
+// on (without any namespace) and the message itself, e.g.
+//
+//   {"at_ms":5012,"exchange":"simulation.updates","message":{"lane_id":1003,"vehicle_count":2,"timestamp":1713790005}}
+//
+// A replay publishes each message on its exchange at its recorded offset,
+// divided by `--speedup` (or RTS_TIME_SCALE), so `--speedup 10` replays ten
+// times faster. Timestamps inside the messages are left as recorded.
+// Messages are recorded without their envelopes (see `mq`), and replayed in
+// new ones from the recorder, numbered afresh. The recorded Shutdown on
+// "control" is replayed too, so the consumers wind down when the recording
+// ends as they did in the original run. Heartbeats are not recorded; the
+// replayed components are not the ones running.
+//
+// Both commands use the namespace of `--namespace` or RTS_NAMESPACE, so a
+// run recorded in one namespace can be replayed into another.
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+mod bus;
+mod mq;
+use mq::{create_channel, declare_exchange, publish_message, MqChannel};
+mod clock;
+use clock::SimClock;
+mod control;
+
+/// Exchanges whose messages are recorded and replayed.
+const RECORDED_EXCHANGES: [&str; 11] = [
+    mq::LOGS,
+    mq::SIMULATION_UPDATES,
+    mq::LIGHT_STATUS,
+    mq::CAR_POSITIONS,
+    mq::RECOMMENDATIONS,
+    mq::RECOMMENDATIONS_APPLIED,
+    mq::REROUTE_ADVISORIES,
+    mq::PREEMPTION,
+    mq::LANE_CLOSURES,
+    mq::INCIDENTS,
+    mq::CONTROL,
+];
+
+/// How often the recording is flushed to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// After a stop, how long the exchanges must stay quiet before recording
+/// ends, so messages published just before the shutdown are still captured.
+const DRAIN_IDLE: Duration = Duration::from_millis(500);
+
+/// One line of a recording.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Milliseconds after recording started.
+    at_ms: u64,
+    /// Exchange the message was published on, without the namespace.
+    exchange: String,
+    message: serde_json::Value,
+}
+
+/// Forwards every message on `exchange` to `entries` until the feed ends.
+async fn capture(mq: MqChannel, exchange: &'static str, started: Instant, entries: mpsc::UnboundedSender<Entry>) -> bus::BusResult<()> {
+    let mut consumer = mq.subscribe(exchange, &format!("recorder_{}", exchange)).await?;
+    while let Some(delivery_result) = consumer.next().await {
+        let payload = delivery_result?;
+        let at_ms = started.elapsed().as_millis() as u64;
+        match serde_json::from_slice(&payload) {
+            Ok(message) => {
+                if entries.send(Entry { at_ms, exchange: exchange.to_string(), message }).is_err() {
+                    break;
+                }
+            }
+            Err(e) => eprintln!("Not recording a malformed message on '{}': {}", exchange, e),
+        }
+    }
+    Ok(())
+}
+
+/// Records RECORDED_EXCHANGES to `path` until the simulation announces
+/// shutdown or Ctrl-C is pressed, then drains what is still arriving.
+async fn record(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mq = create_channel().await?;
+    let mut stop = control::listen(&mq).await?;
+    let started = Instant::now();
+    let (entries_tx, mut entries) = mpsc::unbounded_channel();
+    for exchange in RECORDED_EXCHANGES {
+        declare_exchange(&mq, exchange).await;
+        let (mq, entries_tx) = (mq.clone(), entries_tx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = capture(mq, exchange, started, entries_tx).await {
+                eprintln!("Stopped recording '{}': {}", exchange, e);
+            }
+        });
+    }
+    drop(entries_tx);
+    println!("Recording to {}; Ctrl-C stops", path);
+
+    let mut recorded = 0u64;
+    let mut flush_tick = tokio::time::interval(FLUSH_INTERVAL);
+    let mut drain_until: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            entry = entries.recv() => {
+                let Some(entry) = entry else { break };
+                serde_json::to_writer(&mut out, &entry)?;
+                writeln!(out)?;
+                recorded += 1;
+                if drain_until.is_some() {
+                    drain_until = Some(Instant::now() + DRAIN_IDLE);
+                }
+            }
+            _ = flush_tick.tick() => out.flush()?,
+            _ = stop.requested(), if drain_until.is_none() => {
+                drain_until = Some(Instant::now() + DRAIN_IDLE);
+            }
+            _ = tokio::time::sleep_until(drain_until.unwrap_or_else(Instant::now)), if drain_until.is_some() => break,
+        }
+    }
+    out.flush()?;
+    println!("Recorded {} messages over {:.1}s to {}", recorded, started.elapsed().as_secs_f64(), path);
+    Ok(())
+}
+
+/// Publishes the recording at `path` again, paced by `clock`'s scale.
+async fn replay(path: &str, clock: SimClock) -> Result<(), Box<dyn std::error::Error>> {
+    let mut recording = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+        recording.push(entry);
+    }
+    recording.sort_by_key(|entry| entry.at_ms);
+
+    let mq = create_channel().await?;
+    let mut exchanges: Vec<&str> = recording.iter().map(|entry| entry.exchange.as_str()).collect();
+    exchanges.sort();
+    exchanges.dedup();
+    for exchange in exchanges {
+        declare_exchange(&mq, exchange).await;
+    }
+    let length = recording.last().map_or(0, |entry| entry.at_ms);
+    println!(
+        "Replaying {} messages from {} over {:.1}s",
+        recording.len(), path, clock.real_duration(Duration::from_millis(length)).as_secs_f64()
+    );
+
+    let started = Instant::now();
+    let playback = async {
+        for entry in &recording {
+            tokio::time::sleep_until(started + clock.real_duration(Duration::from_millis(entry.at_ms))).await;
+            publish_message(&mq, &entry.exchange, &entry.message).await?;
+        }
+        Ok::<_, mq::PublishError>(())
+    };
+    tokio::select! {
+        played = playback => {
+            played?;
+            println!("Replay complete");
+        }
+        _ = tokio::signal::ctrl_c() => println!("Replay interrupted"),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("record"), Some(path)) => record(path).await,
+        (Some("replay"), Some(path)) => replay(path, SimClock::from_env()).await,
+        _ => {
+            eprintln!("Usage: recorder record <file> | recorder replay <file> [--speedup <n>]");
+            return;
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error in recorder: {}", e);
+    }
+}
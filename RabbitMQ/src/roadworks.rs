@@ -0,0 +1,181 @@
+// roadworks.rs
+//
+// Closes and reopens internal lanes of a running simulation, for roadworks
+// and the like, by publishing LaneClosure messages on "lane_closures".
+//
+//   roadworks close <lane> [--for <secs>]   closes a lane, for that long if given
+//   roadworks open <lane>                   reopens it
+//   roadworks run <file>                    plays the closures of a scenario file
+//
+// The simulation routes new cars around closed lanes and re-routes cars about
+// to enter one; cars already on it drive it to the end. The flow analyzer
+// logs how congestion shifted a window after every change.
+//
+// A scenario file uses the events of the threaded simulation's scenarios (an
+// array of events, or an object with an "events" array), e.g.
+//
+//   [
+//     {"at": 60, "event": "LaneClosure", "lane_id": 1025, "duration_secs": 120},
+//     {"at": 90, "event": "LaneReopen", "lane_id": 1025}
+//   ]
+//
+// `at` counts simulated seconds from when `run` starts. Events other than
+// LaneClosure and LaneReopen are skipped. Waits run on the clock of
+// `--speedup` or RTS_TIME_SCALE; Ctrl-C or the simulation's shutdown ends
+// them, and a lane closed `--for` a time is then reopened at once.
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use tokio::time::Duration;
+
+mod bus;
+mod mq;
+use mq::{create_channel, declare_exchange, publish_message, MqChannel};
+mod clock;
+use clock::SimClock;
+mod control;
+use control::StopSignal;
+use rts_core::lanes::{load_lanes, LaneCategory};
+use rts_core::messages::LaneClosure;
+
+/// What a scenario event does, as far as roadworks is concerned.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event")]
+enum Action {
+    LaneClosure {
+        lane_id: u32,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    LaneReopen {
+        lane_id: u32,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    at: u64,
+    #[serde(flatten)]
+    action: Action,
+}
+
+/// The closures and reopenings of the scenario file at `path`, as
+/// (seconds from start, lane, closed), in order. Timed closures get their
+/// reopening added.
+fn load_schedule(path: &str, internal: &HashSet<u32>) -> Result<Vec<(u64, u32, bool)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut json: serde_json::Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    if let Some(events) = json.get_mut("events") {
+        json = events.take();
+    }
+    let events: Vec<Event> = serde_json::from_value(json).map_err(|e| format!("{}: {}", path, e))?;
+    let mut schedule = Vec::new();
+    let mut skipped = 0;
+    for event in events {
+        let (lane_id, closed, reopen_after) = match event.action {
+            Action::LaneClosure { lane_id, duration_secs } => (lane_id, true, duration_secs),
+            Action::LaneReopen { lane_id } => (lane_id, false, None),
+            Action::Other => {
+                skipped += 1;
+                continue;
+            }
+        };
+        if !internal.contains(&lane_id) {
+            return Err(format!("{}: lane {} is not an internal lane", path, lane_id));
+        }
+        schedule.push((event.at, lane_id, closed));
+        if let Some(secs) = reopen_after {
+            schedule.push((event.at + secs, lane_id, false));
+        }
+    }
+    if skipped > 0 {
+        println!("Skipping {} scenario event(s) other than lane closures", skipped);
+    }
+    schedule.sort_by_key(|&(at, _, _)| at);
+    Ok(schedule)
+}
+
+async fn publish(mq: &MqChannel, clock: &SimClock, lane_id: u32, closed: bool) -> Result<(), mq::PublishError> {
+    let closure = LaneClosure { lane_id, closed, timestamp: clock.now_secs() };
+    publish_message(mq, mq::LANE_CLOSURES, &closure).await?;
+    println!("{} lane {}", if closed { "Closed" } else { "Reopened" }, lane_id);
+    Ok(())
+}
+
+/// Sleeps for `secs` of simulated time; false if Ctrl-C or the simulation's
+/// shutdown came first.
+async fn wait(clock: &SimClock, secs: u64, stop: &mut StopSignal) -> bool {
+    tokio::select! {
+        _ = clock::sleep(clock, Duration::from_secs(secs)) => true,
+        _ = stop.requested() => false,
+    }
+}
+
+/// The command line's lane, checked to be an internal lane.
+fn lane_arg(arg: Option<&String>, internal: &HashSet<u32>) -> Result<u32, String> {
+    let arg = arg.ok_or("missing lane id")?;
+    let lane_id: u32 = arg.parse().map_err(|_| format!("invalid lane id '{}'", arg))?;
+    if !internal.contains(&lane_id) {
+        return Err(format!("lane {} is not an internal lane", lane_id));
+    }
+    Ok(lane_id)
+}
+
+async fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let internal: HashSet<u32> =
+        load_lanes().iter().filter(|lane| lane.category == LaneCategory::Internal).map(|lane| lane.id).collect();
+    let clock = SimClock::from_env();
+    let command = args.get(1).map(String::as_str);
+    let schedule = match command {
+        Some("run") => load_schedule(args.get(2).ok_or("missing scenario file")?, &internal)?,
+        Some("close") | Some("open") => Vec::new(),
+        _ => return Err("Usage: roadworks close <lane> [--for <secs>] | roadworks open <lane> | roadworks run <file>".into()),
+    };
+
+    let mq = create_channel().await?;
+    declare_exchange(&mq, mq::LANE_CLOSURES).await;
+    let mut stop = control::listen(&mq).await?;
+    // Ctrl-C is caught by `control` and ends waits like a shutdown does.
+    match command {
+        Some("close") => {
+            let lane_id = lane_arg(args.get(2), &internal)?;
+            let duration = match args.iter().position(|arg| arg == "--for") {
+                Some(i) => {
+                    let spec = args.get(i + 1).ok_or("--for needs a number of seconds")?;
+                    Some(spec.parse::<u64>().map_err(|_| format!("invalid duration '{}'", spec))?)
+                }
+                None => None,
+            };
+            publish(&mq, &clock, lane_id, true).await?;
+            if let Some(secs) = duration {
+                println!("Reopening lane {} in {}s; Ctrl-C reopens it now", lane_id, secs);
+                wait(&clock, secs, &mut stop).await;
+                publish(&mq, &clock, lane_id, false).await?;
+            }
+        }
+        Some("open") => publish(&mq, &clock, lane_arg(args.get(2), &internal)?, false).await?,
+        _ => {
+            println!("Playing {} lane closure(s); Ctrl-C stops", schedule.len());
+            let mut elapsed = 0;
+            for (at, lane_id, closed) in schedule {
+                if !wait(&clock, at - elapsed, &mut stop).await {
+                    println!("Stopped before the remaining closures");
+                    break;
+                }
+                elapsed = at;
+                publish(&mq, &clock, lane_id, closed).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(e) = run(&args).await {
+        eprintln!("Error in roadworks: {}", e);
+    }
+}
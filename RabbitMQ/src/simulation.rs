@@ -1,125 +1,274 @@
 // simulation.rs
 
-use tokio;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::collections::{HashMap, BinaryHeap};
-use std::cmp::Ordering;
-use tokio::time::{sleep, Duration};
-use serde::{Serialize, Deserialize};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::{oneshot, watch, Mutex, Notify};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::time::Duration;
 use rand::Rng;
 use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use futures_util::stream::StreamExt;
 
+mod bus;
 mod mq;
-mod lanes; // lanes.rs must be in the same folder
-use lanes::{load_lanes, Lane, LaneCategory};
+use mq::MqChannel;
+mod events;
+use events::{EventKind, Level, LogEvent};
+mod clock;
+use clock::SimClock;
+mod heartbeat;
+mod control;
+mod metrics;
+use metrics::METRICS;
+use rts_core::lane_queue::LaneQueues;
+use rts_core::lanes::{load_lanes, Lane, LaneCategory, Movement};
 
-mod model;
-use model::LightStatus;
+use rts_core::messages::{CarPosition, CarPositions, LaneClosure, LightColor, LightUpdate, PreemptionRequest, TrafficChanges, TrafficSnapshot};
+use rts_core::outbox::{LaneBatch, LaneCountOutbox};
 
-#[derive(Serialize, Deserialize)]
-pub struct TrafficUpdate {
-    pub lane_id: u32,
-    pub vehicle_count: u32,
-    pub timestamp: u64,
+use rts_core::routing::{self, find_lane_path, k_shortest_routes, Congestion, TravelTime};
+use rts_core::network::load_network;
+use rts_core::phase_plan;
+use rts_core::car_following::{Idm, Obstacle};
+use rts_core::emissions::{EmissionModel, EmissionReport, Emissions};
+use rts_core::progress::{LaneProgress, LaneTransition};
+use rts_core::demand::{Arrivals, Demand};
+use rts_core::seed::{self, Stream};
+use rts_core::right_on_red;
+use rts_core::trips::{choose_trip, BoundaryLanes, Trip};
+use rts_core::export::{self, Export, Journey, LaneSample};
+
+mod gridlock;
+use gridlock::{AdvisedLanes, RerouteAdvisory, SharedAdvisories};
+mod incidents;
+use incidents::{Incident, IncidentConfig, SharedBreakdowns};
+
+/// Seconds between two full snapshots on "simulation.updates".
+const SNAPSHOT_INTERVAL_SECS: u64 = 5;
+
+/// Lane counts the cars have changed, waiting for `publish_updates`.
+struct PendingCounts {
+    outbox: std::sync::Mutex<LaneCountOutbox>,
+    /// Woken when a count is pushed.
+    pushed: Notify,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
+/// The process-wide lane count outbox, configured from the environment.
+static PENDING_COUNTS: LazyLock<PendingCounts> = LazyLock::new(|| PendingCounts {
+    outbox: std::sync::Mutex::new(LaneCountOutbox::from_env()),
+    pushed: Notify::new(),
+});
+
+/// Simulated time between two publishes of the car positions on "car_positions".
+const POSITION_TICK: Duration = Duration::from_millis(500);
+
+/// Simulated time a car drives between two looks at what is ahead of it.
+const DRIVE_STEP: Duration = Duration::from_millis(250);
+
+/// Meters short of the stop line at which a car has reached it.
+const AT_STOP_LINE: f64 = 0.5;
+
+/// Range a car's cruising speed is drawn from, in km/h: city streets.
+const CRUISE_SPEED_KMH: std::ops::RangeInclusive<f64> = 40.0..=60.0;
+
+/// Every car on the grid, where it is along its lane and how fast it goes.
+#[derive(Default)]
+struct Grid {
+    cars: BTreeMap<u32, LaneProgress>,
+    /// Cars broken down, which the cars behind squeeze past rather than queue behind.
+    broken_down: HashSet<u32>,
 }
 
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// The cars on the grid, shared by the cars following each other (see
+/// `drive_lane`) and `publish_positions`.
+struct Positions {
+    grid: std::sync::Mutex<Grid>,
 }
 
-/// Internal helper for Dijkstra’s algorithm over intersections.
-fn find_lane_path(start: u32, end: u32, lanes: &Vec<Lane>) -> Option<Vec<Lane>> {
-    #[derive(Debug)]
-    struct LaneState {
-        cost: f64,
-        position: u32,
+impl Positions {
+    /// Puts `car_id` at the start of `lane`, the lane at `route_index` of its
+    /// trip. It keeps the speed it had on its last lane, or comes onto the
+    /// grid at `arrival_speed`.
+    fn enter(&self, car_id: u32, lane: &Lane, route_index: usize, arrival_speed: f64) {
+        let mut grid = self.grid.lock().unwrap();
+        let speed = grid.cars.get(&car_id).map_or(arrival_speed, |car| car.speed);
+        grid.cars.insert(car_id, LaneProgress::entered(lane.id, route_index, lane.length, speed));
     }
-    impl Eq for LaneState {}
-    impl PartialEq for LaneState {
-        fn eq(&self, other: &Self) -> bool {
-            self.cost.eq(&other.cost)
-        }
+
+    /// `car_id` as it stands, and what it follows on its lane; None once it
+    /// has left the grid.
+    fn ahead(&self, car_id: u32, idm: &Idm, parallel: u32) -> Option<(LaneProgress, Option<Obstacle>)> {
+        let grid = self.grid.lock().unwrap();
+        let car = *grid.cars.get(&car_id)?;
+        // Cars level with each other are ordered by id, so two cars entering
+        // together do not both wait for the other.
+        let ahead = grid
+            .cars
+            .iter()
+            .filter(|&(&other_id, other)| {
+                other.lane_id == car.lane_id
+                    && (other.distance > car.distance || (other.distance == car.distance && other_id < car_id))
+                    && !grid.broken_down.contains(&other_id)
+            })
+            .map(|(_, other)| (other.distance, other.speed))
+            .collect();
+        Some((car, idm.leader(car.distance, parallel, ahead)))
     }
-    impl Ord for LaneState {
-        fn cmp(&self, other: &Self) -> Ordering {
-            other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+
+    /// Moves `car_id` `meters` on along its lane, now at `speed`.
+    fn advance(&self, car_id: u32, meters: f64, speed: f64) {
+        if let Some(car) = self.grid.lock().unwrap().cars.get_mut(&car_id) {
+            car.advance(meters, speed);
         }
     }
-    impl PartialOrd for LaneState {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
+
+    /// Stops `car_id` where it is, broken down if `broken_down`, until the
+    /// next `drive_lane`.
+    fn halt(&self, car_id: u32, broken_down: bool) {
+        let mut grid = self.grid.lock().unwrap();
+        if let Some(car) = grid.cars.get_mut(&car_id) {
+            car.speed = 0.0;
+        }
+        if broken_down {
+            grid.broken_down.insert(car_id);
         }
     }
 
-    let mut dist: HashMap<u32, f64> = HashMap::new();
-    let mut prev: HashMap<u32, (u32, Lane)> = HashMap::new();
-    let mut heap = BinaryHeap::new();
+    /// Stops `car_id` at the stop line of its lane to wait for its light.
+    /// True if it was still moving.
+    fn wait(&self, car_id: u32) -> bool {
+        let mut grid = self.grid.lock().unwrap();
+        let Some(car) = grid.cars.get_mut(&car_id) else { return false };
+        car.waiting = true;
+        std::mem::replace(&mut car.speed, 0.0) > 0.0
+    }
 
-    // Initialize distances for intersections 1..16.
-    for inter in 1..=16 {
-        dist.insert(inter, std::f64::INFINITY);
+    /// Takes `car_id` off the grid.
+    fn leave(&self, car_id: u32) {
+        let mut grid = self.grid.lock().unwrap();
+        grid.cars.remove(&car_id);
+        grid.broken_down.remove(&car_id);
     }
-    dist.insert(start, 0.0);
-    heap.push(LaneState { cost: 0.0, position: start });
 
-    // Build a mapping from start intersection to lanes.
-    let mut lane_map: HashMap<u32, Vec<&Lane>> = HashMap::new();
-    for lane in lanes {
-        lane_map.entry(lane.start_intersection).or_default().push(lane);
+    /// Where every car on the grid is now, by car id.
+    fn read(&self) -> Vec<CarPosition> {
+        self.grid
+            .lock()
+            .unwrap()
+            .cars
+            .iter()
+            .map(|(&car_id, car)| CarPosition {
+                car_id,
+                lane_id: car.lane_id,
+                route_index: car.route_index,
+                progress: car.progress(),
+                speed: car.speed,
+                waiting: car.waiting,
+            })
+            .collect()
     }
+}
 
-    while let Some(LaneState { cost, position }) = heap.pop() {
-        if position == end {
-            break;
+/// The process-wide position model.
+static POSITIONS: LazyLock<Positions> = LazyLock::new(|| Positions { grid: std::sync::Mutex::new(Grid::default()) });
+
+/// How cars accelerate and brake, from RTS_IDM_ACCELERATION and
+/// RTS_IDM_DECELERATION (see `rts_core::car_following`).
+static IDM: LazyLock<Idm> = LazyLock::new(Idm::from_env);
+
+/// Fuel use and emissions of the cars on each lane so far, for the end-of-run report.
+static LANE_EMISSIONS: LazyLock<std::sync::Mutex<BTreeMap<u32, Emissions>>> = LazyLock::new(Default::default);
+
+/// Adds `emissions` to `lane_id`'s.
+fn emit(lane_id: u32, emissions: Emissions) {
+    *LANE_EMISSIONS.lock().unwrap().entry(lane_id).or_default() += emissions;
+}
+
+/// Drives `car_id` from where it stands on `lane` up to the stop line, one
+/// DRIVE_STEP at a time, by the Intelligent Driver Model (see
+/// `rts_core::car_following`): it accelerates towards `speed`, slowed past a
+/// breakdown, and brakes for the car ahead. With `at_light`, it also brakes
+/// to stop at the line while the lane's light is red, or amber and it can
+/// still stop. Its fuel use and emissions on the way count towards the
+/// lane's. Returns the simulated seconds it spent moving and standing.
+async fn drive_lane(clock: &SimClock, signals: &Signals, car_id: u32, lane: &Lane, speed: f64, at_light: bool) -> (f64, f64) {
+    let idm = *IDM;
+    let model = EmissionModel::default();
+    let step_secs = DRIVE_STEP.as_secs_f64();
+    let (mut moving, mut standing) = (0.0, 0.0);
+    let mut emitted = Emissions::default();
+    {
+        let mut grid = POSITIONS.grid.lock().unwrap();
+        grid.broken_down.remove(&car_id);
+        if let Some(car) = grid.cars.get_mut(&car_id) {
+            car.waiting = false;
         }
-        if cost > dist[&position] {
-            continue;
+    }
+    while let Some((car, leader)) = POSITIONS.ahead(car_id, &idm, lane.parallel_count) {
+        let remaining = car.remaining();
+        if remaining <= AT_STOP_LINE {
+            break;
         }
-        if let Some(neighbor_lanes) = lane_map.get(&position) {
-            for &lane in neighbor_lanes {
-                let next = lane.end_intersection;
-                let next_cost = cost + lane.length;
-                if next_cost < *dist.get(&next).unwrap_or(&std::f64::INFINITY) {
-                    dist.insert(next, next_cost);
-                    prev.insert(next, (position, lane.clone()));
-                    heap.push(LaneState { cost: next_cost, position: next });
-                }
+        let desired_speed = speed * signals.breakdowns.lock().unwrap().speed_share(lane);
+        let mut acceleration = idm.acceleration(car.speed, desired_speed, leader);
+        let stop = at_light
+            && match signals.lights.get(lane.id) {
+                LightColor::Green => false,
+                LightColor::Amber => idm.can_stop(car.speed, remaining),
+                LightColor::Red => true,
+            };
+        let mut ahead = leader;
+        if stop {
+            let stop_line = idm.stop_line(remaining);
+            acceleration = acceleration.min(idm.acceleration(car.speed, desired_speed, Some(stop_line)));
+            if ahead.is_none_or(|leader| stop_line.gap < leader.gap) {
+                ahead = Some(stop_line);
             }
         }
+        let (next_speed, meters) = idm.step(car.speed, acceleration, step_secs, ahead);
+        // The last step ends at the stop line.
+        let secs = if meters > remaining { step_secs * remaining / meters } else { step_secs };
+        POSITIONS.advance(car_id, meters, next_speed);
+        emitted += model.step((car.speed + next_speed) / 2.0, (next_speed - car.speed) / step_secs, secs);
+        if car.speed > 0.0 && next_speed == 0.0 {
+            emitted.stops += 1;
+        }
+        clock::sleep(clock, Duration::from_secs_f64(secs)).await;
+        if meters > 0.0 {
+            moving += secs;
+        } else {
+            standing += secs;
+        }
     }
+    emit(lane.id, emitted);
+    (moving, standing)
+}
 
-    if !dist.contains_key(&end) || dist[&end] == std::f64::INFINITY {
-        return None;
-    }
+/// How often the first car at a red light it may turn right on looks again
+/// at the lane it turns into; nothing signals when that lane frees up.
+const RIGHT_ON_RED_RECHECK: Duration = Duration::from_millis(500);
 
-    let mut path: Vec<Lane> = Vec::new();
-    let mut current = end;
-    while current != start {
-        if let Some(&(prev_inter, ref lane)) = prev.get(&current) {
-            path.push(lane.clone());
-            current = prev_inter;
-        } else {
-            break;
-        }
+/// Share of vehicles that are emergency vehicles, from RTS_EMERGENCY_SHARE
+/// (0 to 1); none when it is unset, and none with a warning when invalid.
+fn emergency_share_from_env() -> f64 {
+    match std::env::var("RTS_EMERGENCY_SHARE") {
+        Ok(spec) => match spec.trim().parse::<f64>() {
+            Ok(share) if (0.0..=1.0).contains(&share) => share,
+            _ => {
+                eprintln!("Ignoring RTS_EMERGENCY_SHARE: expected a share between 0 and 1, got '{}'", spec.trim());
+                0.0
+            }
+        },
+        Err(_) => 0.0,
     }
-    path.reverse();
-    Some(path)
 }
 
 /// Shared simulation state: number of cars per lane.
 pub type SimEvent = Arc<Mutex<HashMap<u32, u32>>>;
 
+/// Lane counts of every snapshot so far, for `--export`.
+type LaneSeries = Arc<Mutex<Vec<LaneSample>>>;
+
 pub fn initialize_simdata() -> SimEvent {
     let mut map = HashMap::new();
     let lanes = load_lanes();
@@ -129,206 +278,917 @@ pub fn initialize_simdata() -> SimEvent {
     Arc::new(Mutex::new(map))
 }
 
-/// Shared light status state: mapping from lane id to its current light status.
-pub type LightStatusMap = Arc<Mutex<HashMap<u32, String>>>;
+/// The color of every lane's light, each behind a watch channel of its own,
+/// so a car waiting at a light wakes as soon as that light changes or the car
+/// ahead of it passes rather than polling. Lanes are added as their colors
+/// arrive; a lane with none yet is red.
+#[derive(Default)]
+pub struct LaneLights {
+    lanes: std::sync::Mutex<HashMap<u32, watch::Sender<LightColor>>>,
+}
+
+impl LaneLights {
+    pub fn get(&self, lane_id: u32) -> LightColor {
+        self.lanes.lock().unwrap().get(&lane_id).map_or(LightColor::Red, |light| *light.borrow())
+    }
+
+    /// Sets the lane's color, waking the cars at its light if it changed, and
+    /// returns the color it had.
+    pub fn set(&self, lane_id: u32, color: LightColor) -> Option<LightColor> {
+        let mut lanes = self.lanes.lock().unwrap();
+        match lanes.get(&lane_id) {
+            Some(light) => Some(light.send_replace(color)),
+            None => {
+                lanes.insert(lane_id, watch::channel(color).0);
+                None
+            }
+        }
+    }
+
+    /// Wakes the cars at the lane's light without changing its color, so they
+    /// look at their place in its queue again.
+    pub fn touch(&self, lane_id: u32) {
+        if let Some(light) = self.lanes.lock().unwrap().get(&lane_id) {
+            light.send_modify(|_| ());
+        }
+    }
+
+    /// A receiver that sees every change of the lane's light.
+    pub fn subscribe(&self, lane_id: u32) -> watch::Receiver<LightColor> {
+        let mut lanes = self.lanes.lock().unwrap();
+        lanes.entry(lane_id).or_insert_with(|| watch::channel(LightColor::Red).0).subscribe()
+    }
+}
+
+/// Shared light status state, keyed by lane id.
+pub type LightStatusMap = Arc<LaneLights>;
+
+/// Cars waiting at each lane's light, in arrival order. A std mutex, so that
+/// a QueuePlace can leave its queue when dropped.
+pub type LaneQueueMap = Arc<std::sync::Mutex<LaneQueues>>;
+
+/// The lights as the cars see them: their colors and the queue at each, and
+/// the broken-down cars blocking lanes on the way to them.
+#[derive(Clone)]
+pub struct Signals {
+    pub lights: LightStatusMap,
+    pub queues: LaneQueueMap,
+    pub breakdowns: SharedBreakdowns,
+}
+
+/// A car's place in a lane's queue at its light, given up when dropped: once
+/// the car passes, or if its task is dropped first. Giving it up wakes the
+/// cars behind it.
+struct QueuePlace<'a> {
+    signals: &'a Signals,
+    lane_id: u32,
+    car_id: u32,
+}
+
+impl<'a> QueuePlace<'a> {
+    fn join(signals: &'a Signals, lane_id: u32, car_id: u32) -> QueuePlace<'a> {
+        signals.queues.lock().unwrap().join(lane_id, car_id);
+        QueuePlace { signals, lane_id, car_id }
+    }
+
+    fn is_first(&self) -> bool {
+        self.signals.queues.lock().unwrap().is_first(self.lane_id, self.car_id)
+    }
+
+    /// True if the car may pass its light showing `color` (see `LaneQueues::may_pass`).
+    fn may_pass(&self, color: LightColor) -> bool {
+        self.signals.queues.lock().unwrap().may_pass(self.lane_id, self.car_id, color)
+    }
+}
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut queues) = self.signals.queues.lock() {
+            queues.leave(self.lane_id, self.car_id);
+        }
+        self.signals.lights.touch(self.lane_id);
+    }
+}
 
 /// Listens for light status updates from the "light_status" exchange and updates the shared state.
-async fn listen_for_light_statuses(channel: &lapin::Channel, light_status_map: LightStatusMap)
+/// A snapshot sets the color of every light of its junction; `first_snapshot` is sent once the
+/// first one has been applied.
+/// A lane that turns amber lets the car at the front of its queue clear the junction; the
+/// queue learns of it before the cars at the light are woken.
+async fn listen_for_light_statuses(mq: &MqChannel, signals: Signals, first_snapshot: oneshot::Sender<()>)
     -> Result<(), Box<dyn std::error::Error>>
 {
-    // Declare the exchange (if not already declared) and bind a temporary queue.
-    channel.exchange_declare(
-        "light_status",
-        lapin::ExchangeKind::Fanout,
-        lapin::options::ExchangeDeclareOptions::default(),
-        lapin::types::FieldTable::default()
-    ).await?;
-    let queue = channel.queue_declare(
-        "",
-        lapin::options::QueueDeclareOptions::default(),
-        lapin::types::FieldTable::default()
-    ).await?;
-    channel.queue_bind(
-        queue.name().as_str(),
-        "light_status",
-        "",
-        lapin::options::QueueBindOptions::default(),
-        lapin::types::FieldTable::default()
-    ).await?;
-    let mut consumer = channel.basic_consume(
-        queue.name().as_str(),
-        "light_status_consumer",
-        lapin::options::BasicConsumeOptions::default(),
-        lapin::types::FieldTable::default()
-    ).await?;
+    mq::declare_exchange(mq, mq::LIGHT_STATUS).await;
+    let mut consumer = mq.subscribe(mq::LIGHT_STATUS, "light_status_consumer").await?;
 
     println!("Simulation listening for light status updates...");
+    let mut first_snapshot = Some(first_snapshot);
+    while let Some(delivery) = consumer.next().await {
+         let payload = delivery?;
+         match serde_json::from_slice::<LightUpdate>(&payload) {
+             Ok(LightUpdate::Lane(light_status)) => {
+                 let lane_id = light_status.lane_id;
+                 if light_status.status == LightColor::Amber && signals.lights.get(lane_id) != LightColor::Amber {
+                     signals.queues.lock().unwrap().light_turned_amber(lane_id);
+                 }
+                 signals.lights.set(lane_id, light_status.status);
+                 println!("Simulation updated light status: {:?}", light_status);
+             }
+             Ok(LightUpdate::Snapshot(snapshot)) => {
+                 for (&lane_id, &color) in &snapshot.lights {
+                     if color == LightColor::Amber && signals.lights.get(lane_id) != LightColor::Amber {
+                         signals.queues.lock().unwrap().light_turned_amber(lane_id);
+                     }
+                     signals.lights.set(lane_id, color);
+                 }
+                 if let Some(sender) = first_snapshot.take() {
+                     sender.send(()).ok();
+                 }
+             }
+             Err(e) => eprintln!("Ignoring malformed light status: {}", e),
+         }
+    }
+    Ok(())
+}
+
+/// Applies the flow analyzer's reroute advisories to the shared advised lanes.
+async fn listen_for_advisories(mq: &MqChannel, advisories: SharedAdvisories)
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    mq::declare_exchange(mq, mq::REROUTE_ADVISORIES).await;
+    let mut consumer = mq.subscribe(mq::REROUTE_ADVISORIES, "reroute_advisory_consumer").await?;
+
     while let Some(delivery) = consumer.next().await {
-         let delivery = delivery?;
-         if let Ok(light_status) = serde_json::from_slice::<LightStatus>(&delivery.data) {
-             let mut statuses = light_status_map.lock().await;
-             statuses.insert(light_status.lane_id, light_status.status.clone());
-             println!("Simulation updated light status: {:?}", light_status);
+         let payload = delivery?;
+         if let Ok(advisory) = serde_json::from_slice::<RerouteAdvisory>(&payload) {
+             println!("Routing around lanes {:?} until {}", advisory.lanes, advisory.expires_at);
+             advisories.lock().await.apply(&advisory);
          }
-         delivery.ack(lapin::options::BasicAckOptions::default()).await?;
     }
     Ok(())
 }
 
-/// Simulates a single car's journey.
+/// Internal lanes currently closed, e.g. for roadworks.
+type ClosedLanes = Arc<Mutex<HashSet<u32>>>;
+
+/// Applies the closures and reopenings on "lane_closures" to the shared
+/// closed lanes, and logs each one that changes them.
+async fn listen_for_lane_closures(mq: &MqChannel, closed_lanes: ClosedLanes, clock: SimClock)
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    let mut consumer = mq.subscribe(mq::LANE_CLOSURES, "lane_closure_consumer").await?;
+
+    while let Some(delivery) = consumer.next().await {
+        let payload = delivery?;
+        match serde_json::from_slice::<LaneClosure>(&payload) {
+            Ok(closure) => {
+                let changed = {
+                    let mut closed_lanes = closed_lanes.lock().await;
+                    if closure.closed {
+                        closed_lanes.insert(closure.lane_id)
+                    } else {
+                        closed_lanes.remove(&closure.lane_id)
+                    }
+                };
+                if changed {
+                    let log = LogEvent::new(
+                        "Simulation",
+                        clock.now_secs(),
+                        EventKind::LaneClosure { lane_id: closure.lane_id, closed: closure.closed },
+                    );
+                    println!("{}", log.kind);
+                    metrics::publish_log(mq, &log).await.ok();
+                }
+            }
+            Err(e) => eprintln!("Ignoring malformed lane closure: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Queues a lane's current vehicle count for "simulation.updates"; see
+/// `publish_updates`.
+fn queue_lane_count(lane_id: u32, vehicle_count: u32) {
+    METRICS.observe_lane_count(lane_id, vehicle_count);
+    PENDING_COUNTS.outbox.lock().unwrap().push(lane_id, vehicle_count);
+    PENDING_COUNTS.pushed.notify_one();
+}
+
+/// Asks the traffic light controller to turn `lane`'s light green for
+/// emergency vehicle `car_id`, which is approaching its junction.
+async fn request_preemption(channel: &MqChannel, clock: &SimClock, car_id: u32, lane: &Lane)
+    -> Result<(), mq::PublishError>
+{
+    let request = PreemptionRequest {
+        car_id,
+        lane_id: lane.id,
+        junction: lane.end_intersection,
+        timestamp: clock.now_secs(),
+    };
+    mq::publish_message(channel, mq::PREEMPTION, &request).await
+}
+
+/// Breaks `car_id` down on `lane` for `duration_secs` of simulated time,
+/// blocking part of the lane meanwhile, and reports the incident and its
+/// clearance on "incidents" and as log events.
+async fn break_down(channel: &MqChannel, clock: &SimClock, signals: &Signals, car_id: u32, lane: &Lane, duration_secs: u64) {
+    let source = format!("Car-{}", car_id);
+    signals.breakdowns.lock().unwrap().start(lane.id);
+    let incident = Incident { car_id, lane_id: lane.id, cleared: false, timestamp: clock.now_secs() };
+    mq::publish_message(channel, mq::INCIDENTS, &incident).await.ok();
+    let kind = EventKind::Incident { car_id, lane_id: lane.id, duration_secs };
+    metrics::publish_log(channel, &LogEvent::new(source.clone(), incident.timestamp, kind).with_level(Level::Warn)).await.ok();
+
+    clock::sleep(clock, Duration::from_secs(duration_secs)).await;
+
+    signals.breakdowns.lock().unwrap().clear(lane.id);
+    let cleared = Incident { cleared: true, timestamp: clock.now_secs(), ..incident };
+    mq::publish_message(channel, mq::INCIDENTS, &cleared).await.ok();
+    let kind = EventKind::IncidentCleared { car_id, lane_id: lane.id };
+    metrics::publish_log(channel, &LogEvent::new(source, cleared.timestamp, kind)).await.ok();
+}
+
+/// Reports that `car_id` entered or left the lane at `route_index` of its trip.
+async fn publish_progress(channel: &MqChannel, clock: &SimClock, car_id: u32, lane_id: u32, transition: LaneTransition, route_index: usize)
+    -> Result<(), mq::PublishError>
+{
+    let log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::CarProgress { car_id, lane_id, transition, route_index },
+    );
+    metrics::publish_log(channel, &log).await
+}
+
+/// Publishes the lane counts queued by `queue_lane_count` on
+/// "simulation.updates", until a publish gives up. Whenever it is free to
+/// send, the counts that changed since the last batch go out as one
+/// TrafficChanges, so while the broker is slow to confirm, updates pile up
+/// in the outbox and are aggregated or dropped there (RTS_UPDATE_BACKPRESSURE)
+/// rather than waiting in line. Every SNAPSHOT_INTERVAL_SECS of simulated
+/// time the whole SimEvent map goes out as a TrafficSnapshot instead, so
+/// consumers catch up on anything they missed. With `lane_series`, every
+/// snapshot's lane counts are also kept for `--export`.
+async fn publish_updates(channel: MqChannel, sim_event: SimEvent, lane_series: Option<LaneSeries>, clock: SimClock) {
+    let mut ticker = tokio::time::interval(clock.real_duration(Duration::from_secs(SNAPSHOT_INTERVAL_SECS)));
+    // The first tick fires immediately; skip it so the first snapshot has data.
+    ticker.tick().await;
+    loop {
+        let full = tokio::select! {
+            _ = ticker.tick() => true,
+            _ = PENDING_COUNTS.pushed.notified() => false,
+        };
+        let timestamp = clock.now_secs();
+        if full {
+            let lanes = sim_event.lock().await.clone();
+            if let Some(lane_series) = &lane_series {
+                lane_series.lock().await.extend(export::lane_samples(timestamp, &lanes));
+            }
+            PENDING_COUNTS.outbox.lock().unwrap().push_all(&lanes);
+        }
+        let Some(batch) = PENDING_COUNTS.outbox.lock().unwrap().take(full) else { continue };
+        let published = match &batch {
+            LaneBatch { lanes, full: true } => {
+                let snapshot = TrafficSnapshot { lanes: lanes.clone(), timestamp };
+                mq::publish_message(&channel, mq::SIMULATION_UPDATES, &snapshot).await
+            }
+            LaneBatch { lanes, full: false } => {
+                let changes = TrafficChanges { changed: lanes.clone(), timestamp };
+                mq::publish_message(&channel, mq::SIMULATION_UPDATES, &changes).await
+            }
+        };
+        let stats = {
+            let mut outbox = PENDING_COUNTS.outbox.lock().unwrap();
+            match published {
+                Ok(()) => outbox.delivered(&batch),
+                Err(_) => outbox.undelivered(batch),
+            }
+            outbox.stats()
+        };
+        METRICS.observe_outbox(stats);
+        if published.is_err() {
+            return;
+        }
+    }
+}
+
+/// Publishes where every car is along its lane on "car_positions" every
+/// POSITION_TICK of simulated time, until a publish fails. Ticks with no car
+/// on the grid publish nothing.
+async fn publish_positions(channel: MqChannel, clock: SimClock) {
+    let mut ticker = tokio::time::interval(clock.real_duration(POSITION_TICK));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let positions = POSITIONS.read();
+        if positions.is_empty() {
+            continue;
+        }
+        let update = CarPositions { positions, timestamp: clock.now_secs() };
+        if mq::publish_message(&channel, mq::CAR_POSITIONS, &update).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// What cars weigh besides lane length when they pick a route.
+#[derive(Clone)]
+struct RouteOptions {
+    /// Penalize lanes by their current occupancy (RTS_CONGESTION_ROUTING).
+    congestion_aware: bool,
+    /// Lanes under a live reroute advisory, avoided unless there is no other way.
+    advisories: SharedAdvisories,
+    /// Closed lanes, never routed over while closed.
+    closed_lanes: ClosedLanes,
+    /// Quickest routes a car draws its route from (RTS_ROUTE_CHOICES).
+    choices: usize,
+}
+
+impl RouteOptions {
+    /// Weights of the closed lanes alone, for checking that a trip is possible.
+    async fn closure_weights(&self) -> HashMap<u32, f64> {
+        self.closed_lanes.lock().await.iter().map(|&lane_id| (lane_id, routing::CLOSED_LANE_COST)).collect()
+    }
+
+    /// Extra cost of every lane for a route picked now: closed lanes can't
+    /// be used, lanes under a reroute advisory are penalized, and with
+    /// congestion-aware routing lanes are penalized by their current load.
+    async fn weights(&self, sim_event: &SimEvent, clock: &SimClock) -> HashMap<u32, f64> {
+        let mut weights = if self.congestion_aware {
+            routing::congestion_weights(&*sim_event.lock().await)
+        } else {
+            HashMap::new()
+        };
+        for lane_id in self.advisories.lock().await.active(clock.now_secs()) {
+            *weights.entry(lane_id).or_insert(0.0) += routing::ADVISED_LANE_PENALTY;
+        }
+        weights.extend(self.closure_weights().await);
+        weights
+    }
+}
+
+/// What a car draws at random besides its trip.
+#[derive(Debug, Clone, Copy)]
+struct CarDraws {
+    /// Run seed from `--seed` or RTS_SEED (see `rts_core::seed`); without
+    /// one each car's generator is seeded from its id alone.
+    seed: Option<u32>,
+    /// Share of the cars that are emergency vehicles (RTS_EMERGENCY_SHARE).
+    emergency_share: f64,
+    /// Breakdowns on the lanes cars enter (RTS_INCIDENTS), if any.
+    incidents: Option<IncidentConfig>,
+}
+
+impl CarDraws {
+    /// Generator for car `car_id`'s speed, trip and kind.
+    fn rng(&self, car_id: u32) -> ChaCha8Rng {
+        match self.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed::derive(seed, Stream::Vehicle, car_id.into())),
+            None => ChaCha8Rng::seed_from_u64(42 + car_id as u64),
+        }
+    }
+}
+
+/// Draws one of `routes`, weighted by `routing::route_choice_weights` of
+/// their costs.
+fn pick_route(mut routes: Vec<(f64, Vec<Lane>)>, rng: &mut impl Rng) -> Vec<Lane> {
+    let costs: Vec<f64> = routes.iter().map(|(cost, _)| *cost).collect();
+    let weights = routing::route_choice_weights(&costs);
+    let mut pick = rng.random::<f64>() * weights.iter().sum::<f64>();
+    let chosen = weights.iter().position(|&weight| {
+        pick -= weight;
+        pick < 0.0
+    });
+    routes.swap_remove(chosen.unwrap_or(0)).1
+}
+
+/// Simulates a single car's journey; travel and waits are in `clock`'s simulated time.
+/// The entry and exit are re-drawn until they are different junctions with a route
+/// between them. The car takes one of the `route_options.choices` quickest
+/// routes at its speed, the quicker ones more often (see `pick_route`), so
+/// cars between the same junctions spread over several corridors.
+/// Routes never use a closed lane, and a car about to enter a lane
+/// closed since it set off re-routes around it. Returns the number of re-draws and the car's journey, or the number of draws if the car
+/// ran out of them, logged VehicleGenerationFailed and never drove. Every lane
+/// the car enters and leaves, from its entry lane to its exit lane, is logged
+/// as CarProgress. The `draws.emergency_share` of the cars are emergency vehicles, which
+/// request a green light (see `request_preemption`) as they enter each lane.
+/// With `draws.incidents`, a car may break down on each internal lane it
+/// enters (see `break_down`); the time it stands there counts as waiting.
+/// The car follows the cars ahead of it along every lane (see `drive_lane`),
+/// standing in their queue as they do, and on each internal lane drives up
+/// to the stop line and waits there for its light; its place on the grid is
+/// kept in POSITIONS.
+/// Publishes that give up are not handled here: `MqChannel::failed` ends the run.
 async fn simulate_car(
     car_id: u32,
-    channel: &lapin::Channel,
+    channel: &MqChannel,
     sim_event: SimEvent,
-    light_status_map: LightStatusMap,
-) {
-    let mut rng = ChaCha8Rng::seed_from_u64(42 + car_id as u64);
-    let speed: f64 = rng.gen_range(70.0..=90.0);
+    signals: Signals,
+    route_options: RouteOptions,
+    draws: CarDraws,
+    clock: SimClock,
+) -> Result<(u32, Journey), u32> {
+    let mut rng = draws.rng(car_id);
+    // In m/s, like every other speed and the car-following model.
+    let speed: f64 = rng.random_range(CRUISE_SPEED_KMH) / 3.6;
 
     let all_lanes = load_lanes();
-    let entry_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::InputBoundary)
-        .cloned()
-        .collect();
-    let exit_lanes: Vec<Lane> = all_lanes.iter()
-        .filter(|l| l.category == LaneCategory::OutputBoundary)
+    let boundary = BoundaryLanes::from_lanes(&all_lanes);
+
+    let network = load_network();
+    let internal_lanes: Vec<Lane> = all_lanes
+        .iter()
+        .filter(|l| l.category == LaneCategory::Internal)
         .cloned()
         .collect();
 
-    let input_lane = entry_lanes[rng.gen_range(0..entry_lanes.len())].clone();
-    let mut exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
-    while exit_lane.id == input_lane.id {
-        exit_lane = exit_lanes[rng.gen_range(0..exit_lanes.len())].clone();
-    }
+    let closures = route_options.closure_weights().await;
+    let closures = if closures.is_empty() { None } else { Some(closures) };
+    let Trip { entry: input_lane, exit: exit_lane, redraws } =
+        match choose_trip(&boundary, &internal_lanes, &network, closures.as_ref(), &mut rng, |_, _| None) {
+            Ok(trip) => trip,
+            Err(attempts) => {
+                let fail_log = LogEvent::new(
+                    format!("Car-{}", car_id),
+                    clock.now_secs(),
+                    EventKind::VehicleGenerationFailed { car_id, attempts },
+                )
+                .with_level(Level::Warn);
+                metrics::publish_log(channel, &fail_log).await.ok();
+                return Err(attempts);
+            }
+        };
+    // Drawn after the trip, so the mix leaves every car's trip as it was.
+    let emergency = draws.emergency_share > 0.0 && rng.random_bool(draws.emergency_share);
 
     // Compute route through internal lanes.
-    let start_intersection = input_lane.end_intersection; // For input lanes, end_intersection is the grid entry.
-    let end_intersection = exit_lane.start_intersection;   // For output lanes, start_intersection is the grid exit.
-    let internal_lanes: Vec<Lane> = load_lanes()
-        .into_iter()
-        .filter(|l| l.category == LaneCategory::Internal)
-        .collect();
-    let lane_route = match find_lane_path(start_intersection, end_intersection, &internal_lanes) {
-        Some(route) => route,
-        None => Vec::new(),
+    let start_intersection = input_lane.end_intersection;
+    let end_intersection = exit_lane.start_intersection;
+    let weights = route_options.weights(&sim_event, &clock).await;
+    let cost = Congestion { base: TravelTime { speed }, weights: &weights };
+    let mut lane_route = match k_shortest_routes(start_intersection, end_intersection, &internal_lanes, &network, &cost, route_options.choices) {
+        Ok(routes) => pick_route(routes, &mut rng),
+        Err(e) => {
+            let fail_log = LogEvent {
+                source: format!("Car-{}", car_id),
+                message: format!("Routing failed: {}; driving straight to the exit lane", e),
+                timestamp: clock.now_secs(),
+                level: Level::Warn,
+                kind: EventKind::Generic,
+            };
+            metrics::publish_log(channel, &fail_log).await.ok();
+            Vec::new()
+        }
     };
 
     let lane_ids: Vec<u32> = lane_route.iter().map(|lane| lane.id).collect();
 
     // Log the generated vehicle details.
-    let log = LogEvent {
-        source: format!("Car-{}", car_id),
-        message: format!(
-            "Generated vehicle with speed {:.2} m/s; Entry Lane {} (Inter. {}), Exit Lane {} (Inter. {}); Lane Route: {:?}",
+    let log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::VehicleGenerated {
+            car_id,
+            emergency,
             speed,
-            input_lane.id,
-            input_lane.end_intersection,
-            exit_lane.id,
-            exit_lane.start_intersection,
-            lane_ids
-        ),
-        timestamp: current_time_secs(),
-    };
-    mq::publish_message(channel, "logs", "", &log).await;
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route: lane_ids,
+        },
+    );
+    metrics::publish_log(channel, &log).await.ok();
 
-    let start_time = tokio::time::Instant::now();
+    let start_time = std::time::Instant::now();
     let mut total_wait_time = 0.0;
     let mut total_drive_time = 0.0;
+    let mut route_length = input_lane.length;
 
     // Travel the entry lane.
-    let travel_time = input_lane.length / speed;
-    sleep(Duration::from_secs_f64(travel_time)).await;
-    total_drive_time += travel_time;
+    publish_progress(channel, &clock, car_id, input_lane.id, LaneTransition::Entered, 0).await.ok();
+    POSITIONS.enter(car_id, &input_lane, 0, speed);
+    let (moving, standing) = drive_lane(&clock, &signals, car_id, &input_lane, speed, false).await;
+    total_drive_time += moving;
+    total_wait_time += standing;
+    publish_progress(channel, &clock, car_id, input_lane.id, LaneTransition::Exited, 0).await.ok();
 
-    // Follow the lane route.
-    for lane in lane_route {
+    // Follow the lane route. A lane closed since the route was picked is
+    // detoured around from where the car stands; cars already on it drive on.
+    let mut index = 0;
+    while index < lane_route.len() {
+        if route_options.closed_lanes.lock().await.contains(&lane_route[index].id) {
+            let from = lane_route[index].start_intersection;
+            let weights = route_options.weights(&sim_event, &clock).await;
+            let mut detour_log = LogEvent::new(format!("Car-{}", car_id), clock.now_secs(), EventKind::Generic);
+            match find_lane_path(from, end_intersection, &internal_lanes, &network, Some(&weights)) {
+                Ok(detour) => {
+                    let detour_ids: Vec<u32> = detour.iter().map(|lane| lane.id).collect();
+                    detour_log.message = format!("Lane {} closed; re-routed via {:?}", lane_route[index].id, detour_ids);
+                    lane_route.truncate(index);
+                    lane_route.extend(detour);
+                }
+                Err(_) => {
+                    detour_log.message = format!("Lane {} closed and no detour around it; driving it anyway", lane_route[index].id);
+                    detour_log.level = Level::Warn;
+                }
+            }
+            metrics::publish_log(channel, &detour_log).await.ok();
+            if index >= lane_route.len() {
+                break;
+            }
+        }
+        let lane = &lane_route[index];
         // When entering the lane, update simulation state.
-        {
+        let vehicle_count = {
             let mut stats = sim_event.lock().await;
-            *stats.entry(lane.id).or_insert(0) += 1;
-            println!("Car {} entered lane {}", car_id, lane.id);
+            let count = stats.entry(lane.id).or_insert(0);
+            *count += 1;
+            *count
+        };
+        queue_lane_count(lane.id, vehicle_count);
+        publish_progress(channel, &clock, car_id, lane.id, LaneTransition::Entered, index + 1).await.ok();
+        POSITIONS.enter(car_id, lane, index + 1, speed);
+        if emergency {
+            request_preemption(channel, &clock, car_id, lane).await.ok();
         }
+        if let Some(incidents) = draws.incidents {
+            if rng.random_bool(incidents.chance) {
+                POSITIONS.halt(car_id, true);
+                break_down(channel, &clock, &signals, car_id, lane, incidents.duration_secs).await;
+                total_wait_time += incidents.duration_secs as f64;
+            }
+        }
+
+        // Drive up to the stop line behind the cars ahead; time spent
+        // standing in the queue counts as waiting.
+        let (moving, standing) = drive_lane(&clock, &signals, car_id, lane, speed, true).await;
+        total_drive_time += moving;
+        total_wait_time += standing;
+        route_length += lane.length;
 
-        // Wait until the traffic light for this lane is green.
-        let wait_start = tokio::time::Instant::now();
+        // At the stop line, wait until the traffic light for this lane is green, or, turning
+        // right, until the lane turned into has room to go on red; either
+        // way only once the cars that reached the light earlier have passed.
+        // A car already in the junction when the light turned amber clears it.
+        let next_lane = lane_route.get(index + 1).unwrap_or(&exit_lane);
+        let movement = phase_plan::movement(lane.end_intersection, lane, next_lane, &all_lanes, &network)
+            .unwrap_or(Movement::Straight);
+        let wait_start = std::time::Instant::now();
+        let mut light = signals.lights.subscribe(lane.id);
+        let place = QueuePlace::join(&signals, lane.id, car_id);
+        let mut stopped = false;
         loop {
-            let status = {
-                let statuses = light_status_map.lock().await;
-                statuses.get(&lane.id).cloned().unwrap_or("Red".to_string())
-            };
-            if status == "Green" {
+            let status = *light.borrow_and_update();
+            let occupancy = sim_event.lock().await.get(&next_lane.id).copied().unwrap_or(0);
+            let passing = place.may_pass(status);
+            let target = signals.breakdowns.lock().unwrap().effective(next_lane);
+            if passing || (place.is_first() && right_on_red::may_proceed(status, movement, occupancy, &target)) {
+                if !passing {
+                    let turn_log = LogEvent::new(
+                        format!("Car-{}", car_id),
+                        clock.now_secs(),
+                        EventKind::RightTurnOnRed { car_id, lane_id: lane.id, into_lane: next_lane.id, occupancy },
+                    );
+                    metrics::publish_log(channel, &turn_log).await.ok();
+                }
                 break;
             }
-            sleep(Duration::from_millis(100)).await;
+            stopped |= POSITIONS.wait(car_id);
+            // Woken by the light changing or a car ahead passing; turning
+            // right on red, also to look at the lane turned into again.
+            if status == LightColor::Red && movement == Movement::RightTurn && place.is_first() {
+                tokio::select! {
+                    _ = light.changed() => {}
+                    _ = clock::sleep(&clock, RIGHT_ON_RED_RECHECK) => {}
+                }
+            } else {
+                light.changed().await.ok();
+            }
         }
-        total_wait_time += wait_start.elapsed().as_secs_f64();
-
-        let seg_time = lane.length / speed;
-        sleep(Duration::from_secs_f64(seg_time)).await;
-        total_drive_time += seg_time;
+        drop(place);
+        let waited = clock.since(wait_start).as_secs_f64();
+        total_wait_time += waited;
+        emit(lane.id, Emissions { stops: stopped as u32, ..EmissionModel::default().idle(waited) });
 
         // When leaving the lane, update simulation state.
-        {
+        let vehicle_count = {
             let mut stats = sim_event.lock().await;
-            *stats.entry(lane.id).or_insert(0) -= 1;
-            println!("Car {} left lane {}", car_id, lane.id);
-        }
+            let count = stats.entry(lane.id).or_insert(0);
+            *count = count.saturating_sub(1);
+            *count
+        };
+        queue_lane_count(lane.id, vehicle_count);
+        publish_progress(channel, &clock, car_id, lane.id, LaneTransition::Exited, index + 1).await.ok();
+        index += 1;
     }
 
     // Travel the exit lane.
-    let exit_time = exit_lane.length / speed;
-    sleep(Duration::from_secs_f64(exit_time)).await;
-    total_drive_time += exit_time;
-
-    let total_time = start_time.elapsed().as_secs_f64();
-    let comp_log = LogEvent {
-        source: format!("Car-{}", car_id),
-        message: format!("Completed journey: Wait={:.2}s, Drive={:.2}s, Total={:.2}s", total_wait_time, total_drive_time, total_time),
-        timestamp: current_time_secs(),
+    let exit_index = lane_route.len() + 1;
+    publish_progress(channel, &clock, car_id, exit_lane.id, LaneTransition::Entered, exit_index).await.ok();
+    POSITIONS.enter(car_id, &exit_lane, exit_index, speed);
+    let (moving, standing) = drive_lane(&clock, &signals, car_id, &exit_lane, speed, false).await;
+    POSITIONS.leave(car_id);
+    publish_progress(channel, &clock, car_id, exit_lane.id, LaneTransition::Exited, exit_index).await.ok();
+    total_drive_time += moving;
+    total_wait_time += standing;
+    route_length += exit_lane.length;
+
+    let total_time = clock.since(start_time).as_secs_f64();
+    let comp_log = LogEvent::new(
+        format!("Car-{}", car_id),
+        clock.now_secs(),
+        EventKind::CarCompleted {
+            car_id,
+            emergency,
+            entry_lane: input_lane.id,
+            exit_lane: exit_lane.id,
+            route_length,
+            wait_time: total_wait_time,
+            drive_time: total_drive_time,
+            total_time,
+        },
+    );
+    metrics::publish_log(channel, &comp_log).await.ok();
+    let journey = Journey {
+        car_id,
+        entry_lane: input_lane.id,
+        exit_lane: exit_lane.id,
+        wait: total_wait_time,
+        drive: total_drive_time,
+        total: total_time,
     };
-    mq::publish_message(channel, "logs", "", &comp_log).await;
+    Ok((redraws, journey))
 }
 
 #[tokio::main]
 async fn main() {
-    let channel = mq::create_channel().await;
-    mq::declare_exchange(&channel, "simulation.updates", lapin::ExchangeKind::Fanout).await;
-    mq::declare_exchange(&channel, "logs", lapin::ExchangeKind::Fanout).await;
+    let channel = match mq::create_channel().await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("Error in simulation: {}", e);
+            return;
+        }
+    };
+    mq::declare_exchange(&channel, mq::SIMULATION_UPDATES).await;
+    mq::declare_exchange(&channel, mq::LOGS).await;
     // Also declare the light_status exchange for consistency.
-    mq::declare_exchange(&channel, "light_status", lapin::ExchangeKind::Fanout).await;
+    mq::declare_exchange(&channel, mq::LIGHT_STATUS).await;
+    mq::declare_exchange(&channel, mq::PREEMPTION).await;
+    mq::declare_exchange(&channel, mq::LANE_CLOSURES).await;
+    mq::declare_exchange(&channel, mq::INCIDENTS).await;
+    mq::declare_exchange(&channel, mq::CAR_POSITIONS).await;
+    // Ctrl-C stops the run; either way the other bins are told to shut down.
+    let mut stop = match control::listen(&channel).await {
+        Ok(stop) => stop,
+        Err(e) => {
+            eprintln!("Error in simulation: {}", e);
+            return;
+        }
+    };
 
+    // `--cars` and `--arrival-rate` (or SIM_CARS and SIM_ARRIVAL_RATE) set the demand.
+    let demand = Demand::from_args(&std::env::args().collect::<Vec<_>>());
     let sim_event = initialize_simdata();
+    METRICS.register_lanes(&load_lanes());
+    metrics::start("simulation", channel.clone());
     // Create a shared state for holding the latest light statuses.
-    let light_status_map: LightStatusMap = Arc::new(Mutex::new(HashMap::new()));
+    let light_status_map = LightStatusMap::default();
+    let signals = Signals { lights: light_status_map, queues: LaneQueueMap::default(), breakdowns: SharedBreakdowns::default() };
+    // Lanes into a junction without traffic lights are never stopped.
+    let network = load_network();
+    let lanes = load_lanes();
+    for lane in lanes.iter().filter(|lane| network.contains(lane.end_intersection) && !network.is_signalized(lane.end_intersection)) {
+        signals.lights.set(lane.id, LightColor::Green);
+    }
+    let signalized = network.intersections().any(|junction| network.is_signalized(junction));
 
     // Spawn a task to listen for light status updates.
+    let (snapshot_tx, snapshot_rx) = oneshot::channel();
     let channel_clone = channel.clone();
-    let light_status_map_clone = Arc::clone(&light_status_map);
+    let signals_clone = signals.clone();
     tokio::spawn(async move {
-        if let Err(e) = listen_for_light_statuses(&channel_clone, light_status_map_clone).await {
+        if let Err(e) = listen_for_light_statuses(&channel_clone, signals_clone, snapshot_tx).await {
             eprintln!("Error listening for light statuses: {}", e);
         }
     });
 
-    let mut handles = vec![];
-    for car_id in 1..=30 {
+    let clock = SimClock::from_env();
+    if clock.is_accelerated() {
+        println!("Running at {}x real time", clock.scale());
+    }
+
+    // Spawn a task that applies reroute advisories from the flow analyzer.
+    let advisories: SharedAdvisories = Arc::new(Mutex::new(AdvisedLanes::default()));
+    let channel_clone = channel.clone();
+    let advisories_clone = Arc::clone(&advisories);
+    tokio::spawn(async move {
+        if let Err(e) = listen_for_advisories(&channel_clone, advisories_clone).await {
+            eprintln!("Error listening for reroute advisories: {}", e);
+        }
+    });
+
+    // Spawn a task that closes and reopens lanes as "lane_closures" says.
+    let closed_lanes = ClosedLanes::default();
+    let channel_clone = channel.clone();
+    let closed_lanes_clone = Arc::clone(&closed_lanes);
+    tokio::spawn(async move {
+        if let Err(e) = listen_for_lane_closures(&channel_clone, closed_lanes_clone, clock).await {
+            eprintln!("Error listening for lane closures: {}", e);
+        }
+    });
+
+    tokio::spawn(heartbeat::publish_heartbeats(channel.clone(), "simulation", clock));
+
+    // Spawn a task that publishes full snapshots alongside the per-lane updates.
+    // `--export <path>` writes the cars' journeys and the snapshots' lane
+    // counts when the run ends (see `rts_core::export`).
+    let export = Export::from_args(&std::env::args().collect::<Vec<_>>());
+    let lane_series: Option<LaneSeries> = export.as_ref().map(|_| LaneSeries::default());
+    tokio::spawn(publish_updates(channel.clone(), Arc::clone(&sim_event), lane_series.clone(), clock));
+    tokio::spawn(publish_positions(channel.clone(), clock));
+
+    // Until the first light snapshot arrives every lane would look red, so
+    // cars only start once the simulation knows the controller's lights.
+    if signalized {
+        println!("Waiting for a light snapshot from the traffic light controller...");
+    }
+    if signalized && snapshot_rx.await.is_err() {
+        eprintln!("Error in simulation: stopped listening for light statuses before a snapshot arrived");
+        return;
+    }
+
+    let route_options = RouteOptions {
+        congestion_aware: routing::congestion_routing_enabled(),
+        advisories,
+        closed_lanes,
+        choices: routing::route_choices_from_env(),
+    };
+    if route_options.congestion_aware {
+        println!("Congestion-aware routing enabled");
+    }
+    if route_options.choices > 1 {
+        println!("Cars choose among the {} quickest routes of their trip", route_options.choices);
+    }
+    let draws = CarDraws {
+        seed: seed::seed_from_env(),
+        emergency_share: emergency_share_from_env(),
+        incidents: IncidentConfig::from_env(),
+    };
+    if draws.emergency_share > 0.0 {
+        println!("{:.0}% of vehicles are emergency vehicles", draws.emergency_share * 100.0);
+    }
+    if let Some(incidents) = draws.incidents {
+        println!(
+            "Vehicles break down with a {:.1}% chance per lane, for {}s",
+            incidents.chance * 100.0, incidents.duration_secs
+        );
+    }
+    if let Some(seed) = draws.seed {
+        println!("Run seed {}: trips, speeds and arrivals are reproducible", seed);
+    }
+
+    let spawn_car = |car_id: u32| {
         let channel_clone = channel.clone();
         let sim_event_clone = Arc::clone(&sim_event);
-        let light_status_map_clone = Arc::clone(&light_status_map);
-        let handle = tokio::spawn(async move {
-            simulate_car(car_id, &channel_clone, sim_event_clone, light_status_map_clone).await;
-        });
-        handles.push(handle);
+        let signals_clone = signals.clone();
+        let route_options = route_options.clone();
+        tokio::spawn(async move {
+            simulate_car(car_id, &channel_clone, sim_event_clone, signals_clone, route_options, draws, clock).await
+        })
+    };
+    if let Arrivals::Continuous { per_minute } = demand.arrivals {
+        println!("Spawning {} vehicles at {} per minute", demand.cars, per_minute);
     }
 
-    for handle in handles {
-        handle.await.unwrap();
+    // Cars are spawned as they arrive, so a broken channel is noticed while
+    // they still are. A failed car's first draw counts as a draw, the rest as re-draws.
+    let cars = async {
+        let mut handles = Vec::new();
+        let mut rng = match draws.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed::derive(seed, Stream::Arrivals, 0)),
+            None => ChaCha8Rng::from_rng(&mut rand::rng()),
+        };
+        for car_id in 1..=demand.cars {
+            if car_id > 1 && demand.arrivals != Arrivals::AllAtOnce {
+                clock::sleep(&clock, demand.gap(rng.random())).await;
+            }
+            handles.push(spawn_car(car_id));
+        }
+        let mut redraws = 0;
+        let mut failures = 0;
+        let mut journeys = Vec::new();
+        for handle in handles {
+            match handle.await.unwrap() {
+                Ok((car_redraws, journey)) => {
+                    redraws += car_redraws;
+                    journeys.push(journey);
+                }
+                Err(attempts) => {
+                    redraws += attempts.saturating_sub(1);
+                    failures += 1;
+                }
+            }
+        }
+        (redraws, failures, journeys)
+    };
+    let (redraws, failures, journeys) = tokio::select! {
+        tally = cars => tally,
+        reason = channel.failed() => {
+            eprintln!("Error in simulation: {}", reason);
+            return;
+        }
+        _ = stop.requested() => {
+            if let Err(e) = control::announce_shutdown(&channel, "simulation interrupted", &clock).await {
+                eprintln!("Error in simulation: {}", e);
+            }
+            return;
+        }
+    };
+    {
+        let outbox = PENDING_COUNTS.outbox.lock().unwrap();
+        let stats = outbox.stats();
+        println!(
+            "Lane counts: {} sent in {} batches, {} aggregated, {} dropped ({} under backpressure)",
+            stats.sent, stats.batches, stats.aggregated, stats.dropped, outbox.policy()
+        );
+    }
+    if let (Some(export), Some(lane_series)) = (&export, &lane_series) {
+        let lane_series = lane_series.lock().await;
+        match export.write(&journeys, &lane_series) {
+            Ok((cars, lanes)) => println!(
+                "Exported {} journeys to {} and {} lane counts to {}",
+                journeys.len(), cars.display(), lane_series.len(), lanes.display()
+            ),
+            Err(e) => eprintln!("Failed to export metrics: {}", e),
+        }
+    }
+    let trips_log = LogEvent {
+        source: "Simulation".into(),
+        message: format!("Trips: {} re-draws, {} vehicles without a valid trip", redraws, failures),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Generic,
+    };
+    println!("{}", trips_log.message);
+    if let Err(e) = metrics::publish_log(&channel, &trips_log).await {
+        eprintln!("Error in simulation: {}", e);
+        return;
+    }
+
+    let lane_junctions: HashMap<u32, u32> = load_lanes()
+        .iter()
+        .filter(|lane| lane.category != LaneCategory::OutputBoundary)
+        .map(|lane| (lane.id, lane.end_intersection))
+        .collect();
+    let emissions = EmissionReport::from_lanes(&LANE_EMISSIONS.lock().unwrap(), &lane_junctions);
+    println!("Emissions:\n{}", emissions);
+    let emissions_log = LogEvent {
+        source: "Simulation".into(),
+        message: format!("Emissions: {}", emissions.overall),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Generic,
+    };
+    if let Err(e) = metrics::publish_log(&channel, &emissions_log).await {
+        eprintln!("Error in simulation: {}", e);
+        return;
+    }
+
+    let (emergency, normal) = METRICS.travel_times();
+    if let Some(emergency_mean) = emergency.mean() {
+        let travel_log = LogEvent {
+            source: "Simulation".into(),
+            message: format!(
+                "Travel time: emergency vehicles {:.2}s on average over {} trips, others {}",
+                emergency_mean,
+                emergency.trips,
+                match normal.mean() {
+                    Some(normal_mean) => format!("{:.2}s over {} trips", normal_mean, normal.trips),
+                    None => "made no trips".to_string(),
+                }
+            ),
+            timestamp: clock.now_secs(),
+            level: Level::Info,
+            kind: EventKind::Generic,
+        };
+        println!("{}", travel_log.message);
+        if let Err(e) = metrics::publish_log(&channel, &travel_log).await {
+            eprintln!("Error in simulation: {}", e);
+            return;
+        }
     }
 
     let log_complete = LogEvent {
         source: "Simulation".into(),
         message: "Simulation complete".into(),
-        timestamp: current_time_secs(),
+        timestamp: clock.now_secs(),
+        level: Level::Info,
+        kind: EventKind::Generic,
     };
-    mq::publish_message(&channel, "logs", "", &log_complete).await;
+    if let Err(e) = metrics::publish_log(&channel, &log_complete).await {
+        eprintln!("Error in simulation: {}", e);
+    }
+    if let Err(e) = control::announce_shutdown(&channel, "simulation complete", &clock).await {
+        eprintln!("Error in simulation: {}", e);
+    }
 }
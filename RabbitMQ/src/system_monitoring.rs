@@ -1,40 +1,261 @@
 // system_monitoring.rs
-use tokio;
-use lapin::{options::*, types::FieldTable};
 use futures_util::stream::StreamExt;
-use serde::{Serialize, Deserialize};
+use std::io::{BufWriter, Stdout, Write};
 
+mod bus;
 mod mq;
-use mq::{create_channel, declare_exchange};
+use mq::{create_channel, declare_exchange, MqChannel};
+mod events;
+use events::{EventKind, Level, LogEvent};
+mod log_output;
+use log_output::LogOutput;
+mod clock;
+use clock::SimClock;
+mod heartbeat;
+mod control;
+mod api;
+mod event_stream;
+mod metrics;
+use metrics::METRICS;
+use heartbeat::{HealthTracker, Heartbeat, HEARTBEAT_INTERVAL, MISSED_HEARTBEATS};
+use rts_core::lag::LagWatch;
+use rts_core::lanes::load_lanes;
+use rts_core::latency::LatencySummary;
+use rts_core::progress::PositionEstimator;
+use rts_core::stats::{TripReport, TripTimes};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
-    pub timestamp: u64,
+/// How often `--positions` prints where the cars on the grid are, in real time.
+const POSITION_REPORT_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+
+/// How often the delivery latency of every exchange is printed, in real time.
+const LATENCY_REPORT_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(30);
+
+/// Exchanges monitoring subscribes to only to time their messages; it reads
+/// the logs, heartbeats and control messages anyway.
+const TIMED_EXCHANGES: [&str; 9] = [
+    mq::SIMULATION_UPDATES,
+    mq::LIGHT_STATUS,
+    mq::CAR_POSITIONS,
+    mq::RECOMMENDATIONS,
+    mq::RECOMMENDATIONS_APPLIED,
+    mq::REROUTE_ADVISORIES,
+    mq::PREEMPTION,
+    mq::LANE_CLOSURES,
+    mq::INCIDENTS,
+];
+
+/// Log messages the broker sends ahead of the ones handled.
+const PREFETCH: u16 = 512;
+
+/// How often output and the log and event files are flushed.
+const FLUSH_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(200);
+
+/// After a stop, how long the log queue must stay quiet before monitoring
+/// exits, so logs published just before the shutdown are still recorded.
+const DRAIN_IDLE: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+
+/// Feeds the events that move a car to the position estimator.
+fn track(positions: &mut PositionEstimator, log: &LogEvent) {
+    match &log.kind {
+        EventKind::VehicleGenerated { car_id, speed, .. } => positions.generated(*car_id, *speed),
+        EventKind::CarProgress { car_id, lane_id, transition, route_index } => {
+            positions.progress(*car_id, *lane_id, *transition, *route_index, log.timestamp);
+        }
+        EventKind::CarCompleted { car_id, .. } => positions.forget(*car_id),
+        _ => {}
+    }
 }
 
-pub async fn run_monitoring() -> Result<(), Box<dyn std::error::Error>> {
-    let channel = create_channel().await;
-    declare_exchange(&channel, "logs", lapin::ExchangeKind::Fanout).await;
+/// Times of the trip a CarCompleted event reports.
+fn trip_times(log: &LogEvent) -> Option<TripTimes> {
+    match log.kind {
+        EventKind::CarCompleted { entry_lane, wait_time, drive_time, total_time, .. } => {
+            Some(TripTimes { entry_lane, wait: wait_time, drive: drive_time, total: total_time })
+        }
+        _ => None,
+    }
+}
+
+/// Subscribes to TIMED_EXCHANGES, apart from those `api` already follows
+/// when it serves, and drains them in background tasks; their latency is
+/// recorded by the channel as they arrive.
+async fn time_exchanges(mq: &MqChannel, api_serving: bool) {
+    for exchange in TIMED_EXCHANGES {
+        if api_serving && [mq::SIMULATION_UPDATES, mq::LIGHT_STATUS, mq::CAR_POSITIONS].contains(&exchange) {
+            continue;
+        }
+        declare_exchange(mq, exchange).await;
+        let consumer = format!("system_monitoring_timing_{}", exchange);
+        let mut messages = match mq.subscribe(exchange, &consumer).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("Not timing '{}': {}", exchange, e);
+                continue;
+            }
+        };
+        tokio::spawn(async move { while messages.next().await.is_some() {} });
+    }
+}
+
+/// Prints the delivery latency of every exchange a message was received on.
+fn report_latency(latency: &[(String, LatencySummary)], out: &mut impl Write) {
+    if latency.is_empty() {
+        return;
+    }
+    writeln!(out, "Delivery latency, publish to monitoring:").ok();
+    for (exchange, summary) in latency {
+        writeln!(out, "  {}: {}", exchange, summary).ok();
+    }
+}
 
-    let queue = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::default())
-        .await?;
-    channel.queue_bind(queue.name().as_str(), "logs", "", QueueBindOptions::default(), FieldTable::default())
-        .await?;
+/// Prints the estimated position of every car on the grid.
+fn report_positions(positions: &PositionEstimator, clock: &SimClock, out: &mut BufWriter<Stdout>) {
+    if positions.is_empty() {
+        return;
+    }
+    writeln!(out, "Car positions [Time: {}]:", clock.now_secs()).ok();
+    for position in positions.positions(clock.now_secs()) {
+        let along = match position.distance {
+            Some(distance) => format!("{:.0} of {:.0} m", distance, position.lane_length),
+            None => "unknown speed".to_string(),
+        };
+        writeln!(out, "  Car {}: lane {} (trip position {}), {}", position.car_id, position.lane_id, position.route_index, along).ok();
+    }
+}
 
-    let mut consumer = channel.basic_consume(queue.name().as_str(), "system_monitoring", BasicConsumeOptions::default(), FieldTable::default())
-        .await?;
+/// Logs and heartbeats until the simulation announces shutdown or Ctrl-C is
+/// pressed (see `control`), then drains the logs still queued. Components that stop sending heartbeats
+/// are reported as down, and the uptime of each is printed on exit, along with
+/// wait, drive and total time statistics of the completed trips. With
+/// `--positions`, the estimated position of every car on the grid is printed
+/// every POSITION_REPORT_INTERVAL. With RTS_API_PORT, the live state of the
+/// run is served over HTTP and streamed over a WebSocket (see `api`); with
+/// RTS_METRICS_PORT, counters and gauges of the whole run are served to
+/// Prometheus (see `metrics`).
+///
+/// Monitoring also subscribes to every other exchange, to measure how long
+/// messages take from their publisher (see `mq`). The median, 95th and 99th
+/// percentile latency of each exchange is printed every
+/// LATENCY_REPORT_INTERVAL and on exit, and served with the metrics.
+///
+/// Log messages are consumed with a PREFETCH window (see
+/// `MessageBus::subscribe_windowed`); output is buffered and flushed every FLUSH_INTERVAL. When a
+/// log message arrives more than RTS_MONITOR_MAX_LAG simulated seconds after
+/// it was sent, a MonitorOverloaded event is recorded, once per backlog.
+/// Heartbeats are consumed without a window. Events are written as text or JSON
+/// lines, to stdout and optionally a rotating log file; see `log_output`.
+pub async fn run_monitoring() -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = LogOutput::from_env();
+    let mut lag = LagWatch::from_env();
+    let mut positions = std::env::args()
+        .any(|arg| arg == "--positions")
+        .then(|| PositionEstimator::new(&load_lanes()));
+    let clock = SimClock::from_env();
+    let mq = create_channel().await?;
+    declare_exchange(&mq, mq::LOGS).await;
+    declare_exchange(&mq, mq::HEARTBEATS).await;
+    let mut stop = control::listen(&mq).await?;
+    let live = api::start(&mq, clock).await;
+    time_exchanges(&mq, live.is_some()).await;
+    let lanes = load_lanes();
+    METRICS.register_lanes(&lanes);
+    METRICS.register_junctions(&lanes);
+    metrics::start("system_monitoring", mq.clone());
+    let mut consumer = mq.subscribe_windowed(mq::LOGS, "system_monitoring", PREFETCH).await?;
+    let mut heartbeats = mq.subscribe(mq::HEARTBEATS, "system_monitoring_heartbeats").await?;
 
     println!("System Monitoring waiting for log messages...");
 
-    while let Some(delivery_result) = consumer.next().await {
-        if let Ok(delivery) = delivery_result {
-            let data = delivery.data.clone();
-            if let Ok(log) = serde_json::from_slice::<LogEvent>(&data) {
-                println!("[Time: {}] {}: {}", log.timestamp, log.source, log.message);
+    let mut health = HealthTracker::default();
+    let mut health_check = tokio::time::interval(HEARTBEAT_INTERVAL / 2);
+    let mut position_report = tokio::time::interval(POSITION_REPORT_INTERVAL);
+    let mut latency_report = tokio::time::interval(LATENCY_REPORT_INTERVAL);
+    latency_report.reset();
+    let mut flush_tick = tokio::time::interval(FLUSH_INTERVAL);
+    // Set once stopping: monitoring exits when no log has arrived by then.
+    let mut drain_until: Option<tokio::time::Instant> = None;
+    let mut trips: Vec<TripTimes> = Vec::new();
+    loop {
+        tokio::select! {
+            delivery_result = consumer.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    if let Ok(log) = serde_json::from_slice::<LogEvent>(&payload) {
+                        let now = clock.now_secs();
+                        if let Some(behind_secs) = lag.check(log.timestamp, now) {
+                            eprintln!("WARNING: monitoring is {}s behind the simulation", behind_secs);
+                            let kind = EventKind::MonitorOverloaded { behind_secs, max_lag_secs: lag.max_lag_secs() };
+                            output.record(&LogEvent::new("SystemMonitoring", now, kind).with_level(Level::Warn));
+                        }
+                        output.record(&log);
+                        trips.extend(trip_times(&log));
+                        METRICS.observe_logged(&log.kind);
+                        if let Some(live) = &live {
+                            live.observe(&log);
+                        }
+                        if let Some(positions) = positions.as_mut() {
+                            track(positions, &log);
+                        }
+                    }
+                    if drain_until.is_some() {
+                        drain_until = Some(tokio::time::Instant::now() + DRAIN_IDLE);
+                    }
+                }
+            }
+            delivery_result = heartbeats.next() => {
+                let Some(delivery_result) = delivery_result else { break };
+                if let Ok(payload) = delivery_result {
+                    if let Ok(heartbeat) = serde_json::from_slice::<Heartbeat>(&payload) {
+                        if health.beat(&heartbeat.component, tokio::time::Instant::now()) {
+                            let mut log = LogEvent::new("SystemMonitoring", clock.now_secs(), EventKind::Generic);
+                            log.message = format!("{} is sending heartbeats again", heartbeat.component);
+                            output.record(&log);
+                        }
+                    }
+                }
+            }
+            _ = flush_tick.tick() => {
+                output.flush();
             }
-            delivery.ack(BasicAckOptions::default()).await?;
+            _ = health_check.tick() => {
+                for (component, silent) in health.check(tokio::time::Instant::now()) {
+                    eprintln!("WARNING: {} missed {} heartbeats; no heartbeat for {:.1}s",
+                              component, MISSED_HEARTBEATS, silent.as_secs_f64());
+                    let kind = EventKind::ComponentDown { component, silent_secs: silent.as_secs_f64() };
+                    output.record(&LogEvent::new("SystemMonitoring", clock.now_secs(), kind).with_level(Level::Warn));
+                }
+            }
+            _ = position_report.tick(), if positions.is_some() => {
+                if let Some(positions) = &positions {
+                    report_positions(positions, &clock, &mut output.stdout);
+                }
+            }
+            _ = latency_report.tick() => {
+                report_latency(&mq.delivery_latency(), &mut output.stdout);
+            }
+            _ = stop.requested(), if drain_until.is_none() => {
+                drain_until = Some(tokio::time::Instant::now() + DRAIN_IDLE);
+            }
+            _ = tokio::time::sleep_until(drain_until.unwrap_or_else(tokio::time::Instant::now)), if drain_until.is_some() => break,
+        }
+    }
+    output.flush();
+
+    let uptimes = health.uptimes(tokio::time::Instant::now());
+    if !uptimes.is_empty() {
+        println!("Component uptime:");
+        for (component, uptime, down) in uptimes {
+            println!("  {}: {:.1}s{}", component, uptime.as_secs_f64(), if down { " (down)" } else { "" });
+        }
+    }
+    report_latency(&mq.delivery_latency(), &mut std::io::stdout());
+    if !trips.is_empty() {
+        let report = TripReport::from_trips(&trips);
+        println!("Trip statistics ({} trips):", report.overall.trips);
+        println!("{}", report.overall);
+        println!("  By entry lane (mean/median/p95):");
+        for entry in &report.by_entry_lane {
+            println!("    {}", entry);
         }
     }
     Ok(())
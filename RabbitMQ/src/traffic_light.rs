@@ -1,248 +1,798 @@
 // traffic_light.rs
 
-use tokio::time::{sleep, Duration};
-use tokio::sync::Mutex;
+use tokio::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use std::any::Any;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
+use futures_util::future::FutureExt;
 use futures_util::stream::StreamExt;
 
+mod bus;
 mod mq;
-use mq::{create_channel, declare_exchange, publish_message};
-mod lanes;
-use lanes::{load_lanes, Lane};
-use tokio;
-use lapin::ExchangeKind;
-use rand::Rng;
+use bus::BusResult;
+use mq::{create_channel, declare_exchange, publish_message, MqChannel, PublishError};
+mod events;
+use events::{EventKind, Level, LogEvent};
+mod clock;
+use clock::SimClock;
+mod heartbeat;
+mod control;
+use control::StopSignal;
+mod metrics;
+use metrics::METRICS;
+mod grpc;
+use rts_core::lanes::{load_lanes, Lane};
+use rts_core::phase_plan::{build_phase_plan, Phase};
+use rts_core::phase_order::{phase_demand, PhaseOrder, PhaseSelector};
+use rts_core::watchdog::{expected_cycle_secs, PhaseWatch};
+use rts_core::network::load_network;
+use rts_core::seed;
+use rts_core::messages::{LightSnapshot, LightStatus, PreemptionRequest, Recommendation, RecommendationApplied, SimulationUpdate};
 use std::error::Error;
-use serde_json;
 
-mod model;
-use model::LightStatus;
+pub use rts_core::messages::LightColor;
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-pub enum LightColor {
-    Red,
-    Green,
+/// Seconds of simulated time between two snapshots of a junction's lights on "light_status".
+const LIGHT_SNAPSHOT_INTERVAL_SECS: u64 = 3;
+
+/// Green time of every phase until a recommendation changes it.
+const GREEN_SECS: u64 = 5;
+
+/// Shortest and longest green time a recommendation can give a phase.
+const MIN_GREEN_SECS: u64 = 3;
+const MAX_GREEN_SECS: u64 = 60;
+
+/// Amber time after every green unless RTS_AMBER_SECS sets it.
+const DEFAULT_AMBER_SECS: u64 = 3;
+
+/// All-red clearance between two phases.
+const CLEARANCE_SECS: u64 = 10;
+
+/// Green a junction holds for an emergency vehicle's lane.
+const PREEMPTION_GREEN_SECS: u64 = 8;
+
+/// Phase changes a slow subscriber of the control plane's stream may fall
+/// behind by before it misses some.
+const PHASE_CHANGE_BUFFER: usize = 256;
+
+/// Pause before a failed junction task is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Messages a junction's mailbox holds before their senders have to wait.
+const JUNCTION_MAILBOX: usize = 64;
+
+/// Latest vehicle count of each lane into one junction, as published on
+/// "simulation.updates".
+type JunctionCounts = watch::Receiver<HashMap<u32, u32>>;
+
+/// Amber time from RTS_AMBER_SECS, falling back to DEFAULT_AMBER_SECS (with
+/// a warning) when it is unset or invalid.
+fn amber_secs_from_env() -> u64 {
+    match std::env::var("RTS_AMBER_SECS") {
+        Ok(spec) => spec.trim().parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring RTS_AMBER_SECS: invalid duration '{}'", spec.trim());
+            DEFAULT_AMBER_SECS
+        }),
+        Err(_) => DEFAULT_AMBER_SECS,
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Recommendation {
-    pub lane_id: u32,
-    pub new_green_time: u32,
-    pub timestamp: u64,
+/// Hands the emergency vehicles' requests from "preemption" to their
+/// junctions until the feed ends.
+async fn follow_preemptions(mq: MqChannel, junctions: Junctions) -> BusResult<()> {
+    let mut consumer = mq.subscribe(mq::PREEMPTION, "traffic_light_preemption").await?;
+    while let Some(delivery_result) = consumer.next().await {
+        let payload = delivery_result?;
+        match serde_json::from_slice::<PreemptionRequest>(&payload) {
+            Ok(request) => {
+                let hold = Hold::Emergency { car_id: request.car_id };
+                if let Err(e) = junctions.tell(request.junction, JunctionMessage::Hold { lane_id: request.lane_id, hold }).await {
+                    eprintln!("Ignoring preemption request: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Ignoring malformed preemption request: {}", e),
+        }
+    }
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LogEvent {
-    pub source: String,
-    pub message: String,
+/// Hands the lane counts on "simulation.updates" to the junctions their
+/// lanes enter, until the feed ends. A snapshot replaces every junction's
+/// counts.
+async fn follow_lane_counts(
+    mq: MqChannel,
+    counts: HashMap<u32, watch::Sender<HashMap<u32, u32>>>,
+    lane_junctions: Arc<HashMap<u32, u32>>,
+) -> BusResult<()> {
+    let mut consumer = mq.subscribe(mq::SIMULATION_UPDATES, "traffic_light_counts").await?;
+    let set = |lane_id: u32, vehicle_count: u32| {
+        if let Some(junction_counts) = lane_junctions.get(&lane_id).and_then(|junction| counts.get(junction)) {
+            junction_counts.send_modify(|counts| {
+                counts.insert(lane_id, vehicle_count);
+            });
+        }
+    };
+    while let Some(delivery_result) = consumer.next().await {
+        let payload = delivery_result?;
+        match serde_json::from_slice::<SimulationUpdate>(&payload) {
+            Ok(SimulationUpdate::Lane(update)) => set(update.lane_id, update.vehicle_count),
+            Ok(SimulationUpdate::Changes(changes)) => {
+                for (lane_id, vehicle_count) in changes.changed {
+                    set(lane_id, vehicle_count);
+                }
+            }
+            Ok(SimulationUpdate::Snapshot(snapshot)) => {
+                let mut by_junction: HashMap<u32, HashMap<u32, u32>> = HashMap::new();
+                for (lane_id, vehicle_count) in snapshot.lanes {
+                    if let Some(&junction) = lane_junctions.get(&lane_id) {
+                        by_junction.entry(junction).or_default().insert(lane_id, vehicle_count);
+                    }
+                }
+                for (junction, junction_counts) in &counts {
+                    junction_counts.send_replace(by_junction.remove(junction).unwrap_or_default());
+                }
+            }
+            Err(e) => eprintln!("Ignoring malformed simulation update: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Why a junction holds a lane green out of its cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hold {
+    /// An approaching emergency vehicle, for PREEMPTION_GREEN_SECS.
+    Emergency { car_id: u32 },
+    /// A ForceLane call of the control plane (see `grpc`).
+    Forced { green_secs: u64 },
+}
+
+impl Hold {
+    fn green_secs(&self) -> u64 {
+        match self {
+            Hold::Emergency { .. } => PREEMPTION_GREEN_SECS,
+            Hold::Forced { green_secs } => *green_secs,
+        }
+    }
+}
+
+/// Lights a junction has just shown, for the control plane's stream of
+/// phase changes.
+#[derive(Debug, Clone)]
+pub struct PhaseShown {
+    pub junction: u32,
+    /// Phase of the cycle shown, or interrupted by `hold`.
+    pub phase: usize,
+    pub hold: Option<Hold>,
+    pub green_lanes: Vec<u32>,
+    pub red_lanes: Vec<u32>,
     pub timestamp: u64,
 }
 
-// Helper: returns the current system time in seconds.
-fn current_time_secs() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+/// What the rest of the controller tells or asks a junction.
+enum JunctionMessage {
+    /// Hold a lane green, after amber and clearance.
+    Hold { lane_id: u32, hold: Hold },
+    /// Keep a lane red for `duration_secs`, dropping any hold of it green.
+    HoldRed { lane_id: u32, duration_secs: u64 },
+    /// Set every phase serving a lane to a recommended green time.
+    Retime { lane_id: u32, green_secs: u64 },
+    /// Set the green time of every phase, in phase order.
+    SetGreens { green_secs: Vec<u64> },
+    /// Send back the color of every light of the junction.
+    Lights { reply: oneshot::Sender<HashMap<u32, LightColor>> },
 }
 
-// Helper: converts an intersection ID (1..16) to (row, col) coordinates in a 4×4 grid.
-fn intersection_to_coords(inter: u32) -> (f64, f64) {
-    let row = ((inter - 1) / 4) as f64;
-    let col = ((inter - 1) % 4) as f64;
-    (row, col)
+/// One junction's controller, as an actor: it owns the junction's lights,
+/// phase plan and holds, takes messages from the rest of the controller
+/// through its mailbox, and publishes the junction's light changes, light
+/// snapshots and log events itself. Nothing of it is shared with the other
+/// junctions.
+struct Junction {
+    junction: u32,
+    /// Source of the junction's log events.
+    source: String,
+    lanes: Vec<Lane>,
+    phases: Vec<Phase>,
+    /// Green time of each phase, by phase index.
+    greens: Vec<u64>,
+    amber_secs: u64,
+    /// Phase being served, or the one to resume after a hold.
+    phase: usize,
+    selector: Option<PhaseSelector>,
+    /// Color of each lane's light.
+    lights: HashMap<u32, LightColor>,
+    /// Lane and hold of each request, in arrival order, at most one per
+    /// lane; a forced hold replaces an emergency vehicle's.
+    pending: VecDeque<(u32, Hold)>,
+    /// Lanes kept red, by the simulated second they are released at.
+    red_until: HashMap<u32, u64>,
+    mailbox: mpsc::Receiver<JunctionMessage>,
+    /// Set once every sender of the mailbox is gone.
+    mailbox_closed: bool,
+    counts: JunctionCounts,
+    /// When the next light snapshot is due.
+    snapshots: tokio::time::Interval,
+    mq: MqChannel,
+    shown: broadcast::Sender<PhaseShown>,
+    clock: SimClock,
 }
 
-// Helper: computes the approach angle (in degrees) for a lane approaching its junction.
-fn compute_lane_angle(lane: &Lane) -> f64 {
-    if lane.start_intersection != 0 {
-        let (sx, sy) = intersection_to_coords(lane.start_intersection);
-        let (ex, ey) = intersection_to_coords(lane.end_intersection);
-        let dx = ex - sx;
-        let dy = ey - sy;
-        let mut angle_deg = dy.atan2(dx).to_degrees();
-        if angle_deg < 0.0 {
-            angle_deg += 360.0;
+impl Junction {
+    /// Publishes a log event of the junction, counting it in METRICS.
+    async fn log(&self, kind: EventKind) -> Result<(), PublishError> {
+        metrics::publish_log(&self.mq, &LogEvent::new(self.source.clone(), self.clock.now_secs(), kind)).await
+    }
+
+    fn request(&mut self, lane_id: u32, hold: Hold) {
+        match self.pending.iter_mut().find(|(lane, _)| *lane == lane_id) {
+            Some(pending) if matches!(hold, Hold::Forced { .. }) => pending.1 = hold,
+            Some(_) => {}
+            None => self.pending.push_back((lane_id, hold)),
         }
-        angle_deg
-    } else {
-        // For input lanes, assign a default based on junction location.
-        let (ex, _) = intersection_to_coords(lane.end_intersection);
-        if ex == 0.0 {
-            90.0  // Top row: coming from north
-        } else if ex == 3.0 {
-            270.0 // Bottom row: coming from south
-        } else {
-            90.0  // Default
+        if matches!(hold, Hold::Forced { .. }) {
+            self.red_until.remove(&lane_id);
         }
     }
-}
 
-// Helper: groups lanes (entering the same junction) by similar approach angles.
-// Lanes whose computed angles differ by less than a threshold (20°) are grouped.
-fn group_lanes_by_direction(lanes: &[Lane]) -> Vec<Vec<u32>> {
-    let threshold = 20.0;
-    let mut groups: Vec<(f64, Vec<u32>)> = Vec::new(); // (average angle, list of lane ids)
-    
-    for lane in lanes {
-        let angle = compute_lane_angle(lane);
-        let mut added = false;
-        for group in groups.iter_mut() {
-            if (angle - group.0).abs() <= threshold {
-                group.1.push(lane.id);
-                // Update the group's average angle.
-                group.0 = (group.0 * (group.1.len() as f64 - 1.0) + angle) / (group.1.len() as f64);
-                added = true;
-                break;
+    /// Keeps `lane_id` red until simulated second `until`, dropping any
+    /// request to hold it green.
+    fn hold_red(&mut self, lane_id: u32, until: u64) {
+        self.pending.retain(|&(lane, _)| lane != lane_id);
+        self.red_until.insert(lane_id, until);
+    }
+
+    /// `lanes` without the ones held red now.
+    fn not_held_red(&mut self, lanes: &[u32]) -> Vec<u32> {
+        let now = self.clock.now_secs();
+        self.red_until.retain(|_, until| *until > now);
+        lanes.iter().copied().filter(|lane_id| !self.red_until.contains_key(lane_id)).collect()
+    }
+
+    /// Drops the emergency vehicles' requests for `green_lanes`, whose
+    /// vehicles can already go; true if requests for other lanes remain.
+    /// Forced holds of green lanes wait for the end of the phase.
+    fn waiting_beyond(&mut self, green_lanes: &[u32]) -> bool {
+        self.pending.retain(|(lane_id, hold)| matches!(hold, Hold::Forced { .. }) || !green_lanes.contains(lane_id));
+        self.pending.iter().any(|(lane_id, _)| !green_lanes.contains(lane_id))
+    }
+
+    /// Sets every phase that serves `lane_id` to `green_secs` of green,
+    /// clamped to MIN_GREEN_SECS..=MAX_GREEN_SECS, and returns a
+    /// PhaseTimingChanged event for each phase whose green time changed.
+    fn retime(&mut self, lane_id: u32, green_secs: u64) -> Vec<EventKind> {
+        let new_green_secs = green_secs.clamp(MIN_GREEN_SECS, MAX_GREEN_SECS);
+        let mut changes = Vec::new();
+        for (phase, served) in self.phases.iter().enumerate() {
+            if served.lanes.contains(&lane_id) && self.greens[phase] != new_green_secs {
+                changes.push(EventKind::PhaseTimingChanged {
+                    junction: self.junction,
+                    phase,
+                    lane_id,
+                    old_green_secs: self.greens[phase],
+                    new_green_secs,
+                });
+                self.greens[phase] = new_green_secs;
             }
         }
-        if !added {
-            groups.push((angle, vec![lane.id]));
+        changes
+    }
+
+    /// Acts on `message`; true if it holds a lane green or red.
+    async fn handle(&mut self, message: JunctionMessage) -> Result<bool, PublishError> {
+        match message {
+            JunctionMessage::Hold { lane_id, hold } => {
+                self.request(lane_id, hold);
+                return Ok(true);
+            }
+            JunctionMessage::HoldRed { lane_id, duration_secs } => {
+                self.hold_red(lane_id, self.clock.now_secs() + duration_secs);
+                let junction = self.junction;
+                self.log(EventKind::LaneForced { junction, lane_id, color: LightColor::Red, duration_secs }).await?;
+                return Ok(true);
+            }
+            JunctionMessage::Retime { lane_id, green_secs } => {
+                for kind in self.retime(lane_id, green_secs) {
+                    self.log(kind).await?;
+                }
+            }
+            JunctionMessage::SetGreens { green_secs } => {
+                self.greens.copy_from_slice(&green_secs);
+                self.log(EventKind::PhasePlanSet { junction: self.junction, green_secs }).await?;
+            }
+            JunctionMessage::Lights { reply } => {
+                reply.send(self.lights.clone()).ok();
+            }
+        }
+        Ok(false)
+    }
+
+    /// Waits out `duration` of simulated time, taking messages and publishing
+    /// light snapshots meanwhile. With `green_lanes`, the wait is cut short
+    /// once a hold is requested for another lane or one of them is held red;
+    /// true if it was.
+    async fn wait(&mut self, duration: Duration, green_lanes: Option<&[u32]>) -> Result<bool, PublishError> {
+        let clock = self.clock;
+        let sleep = clock::sleep(&clock, duration);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return Ok(false),
+                _ = self.snapshots.tick() => {
+                    let snapshot = LightSnapshot { lights: self.lights.clone(), timestamp: clock.now_secs() };
+                    publish_message(&self.mq, mq::LIGHT_STATUS, &snapshot).await?;
+                }
+                message = self.mailbox.recv(), if !self.mailbox_closed => {
+                    let Some(message) = message else {
+                        self.mailbox_closed = true;
+                        continue;
+                    };
+                    if !self.handle(message).await? {
+                        continue;
+                    }
+                    if let Some(green_lanes) = green_lanes {
+                        let held_red = self.not_held_red(green_lanes).len() < green_lanes.len();
+                        if self.waiting_beyond(green_lanes) || held_red {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets `lane_id`'s light to `color` and publishes it.
+    async fn show(&mut self, lane_id: u32, status: LightColor) -> Result<(), PublishError> {
+        self.lights.insert(lane_id, status);
+        publish_message(&self.mq, mq::LIGHT_STATUS, &LightStatus { lane_id, status }).await
+    }
+
+    /// Sets `lanes` green, and the junction's other lanes red, publishing
+    /// each color. Returns the green and red lanes.
+    async fn show_green(&mut self, lanes: &[u32]) -> Result<(Vec<u32>, Vec<u32>), PublishError> {
+        let (green_lanes, red_lanes): (Vec<u32>, Vec<u32>) =
+            self.lanes.iter().map(|lane| lane.id).partition(|lane_id| lanes.contains(lane_id));
+        for lane_id in self.lanes.iter().map(|lane| lane.id).collect::<Vec<_>>() {
+            let status = if lanes.contains(&lane_id) { LightColor::Green } else { LightColor::Red };
+            self.show(lane_id, status).await?;
+        }
+        Ok((green_lanes, red_lanes))
+    }
+
+    /// Turns `lanes` amber for the amber time, then every lane of the
+    /// junction red for the all-red clearance, publishing each color.
+    async fn clear(&mut self, lanes: &[u32]) -> Result<(), PublishError> {
+        for &lane_id in lanes {
+            self.show(lane_id, LightColor::Amber).await?;
+        }
+        self.wait(Duration::from_secs(self.amber_secs), None).await?;
+        for lane_id in self.lanes.iter().map(|lane| lane.id).collect::<Vec<_>>() {
+            self.show(lane_id, LightColor::Red).await?;
+        }
+        self.wait(Duration::from_secs(CLEARANCE_SECS), None).await?;
+        Ok(())
+    }
+
+    /// Cycles through the junction's phases, logging and publishing each
+    /// one, until a publish gives up. An emergency vehicle's request, or a
+    /// forced green, cuts the current green short, unless its lane is
+    /// already green: after amber and clearance its lane alone is held green
+    /// for the hold's time, logged as EmergencyPreemption or LaneForced, and
+    /// the cycle resumes with the interrupted phase. Lanes held red stay red
+    /// through their phases; holding a green lane red cuts its green short
+    /// the same way. Every phase started is reported to `watch`.
+    async fn cycle(&mut self, watch: &std::sync::Mutex<PhaseWatch>) -> Result<(), PublishError> {
+        let junction = self.junction;
+        loop {
+            while let Some((lane_id, hold)) = self.pending.pop_front() {
+                let green_secs = hold.green_secs();
+                watch.lock().unwrap().phase_started(self.clock.now_secs(), green_secs + self.amber_secs + CLEARANCE_SECS);
+                let (green_lanes, red_lanes) = self.show_green(&[lane_id]).await?;
+                let timestamp = self.clock.now_secs();
+                self.shown.send(PhaseShown { junction, phase: self.phase, hold: Some(hold), green_lanes, red_lanes, timestamp }).ok();
+                let kind = match hold {
+                    Hold::Emergency { car_id } => EventKind::EmergencyPreemption { junction, lane_id, car_id, green_secs },
+                    Hold::Forced { .. } => EventKind::LaneForced { junction, lane_id, color: LightColor::Green, duration_secs: green_secs },
+                };
+                self.log(kind).await?;
+                self.wait(Duration::from_secs(green_secs), None).await?;
+                self.clear(&[lane_id]).await?;
+            }
+            if let Some(selector) = &mut self.selector {
+                let demand = phase_demand(&self.phases, &self.counts.borrow());
+                let decision = selector.choose(&demand);
+                selector.served(decision.phase);
+                self.phase = decision.phase;
+                self.log(EventKind::PhaseDecision {
+                    junction,
+                    phase: decision.phase,
+                    demand: decision.demand,
+                    overdue: decision.overdue,
+                })
+                .await?;
+            }
+            let green_secs = self.greens[self.phase];
+            watch.lock().unwrap().phase_started(self.clock.now_secs(), green_secs + self.amber_secs + CLEARANCE_SECS);
+            let served = self.phases[self.phase].lanes.clone();
+            let phase_lanes = self.not_held_red(&served);
+            let (green_lanes, red_lanes) = self.show_green(&phase_lanes).await?;
+            self.shown.send(PhaseShown {
+                junction,
+                phase: self.phase,
+                hold: None,
+                green_lanes: green_lanes.clone(),
+                red_lanes: red_lanes.clone(),
+                timestamp: self.clock.now_secs(),
+            }).ok();
+            self.log(EventKind::PhaseChange { junction, phase: self.phase, green_lanes, red_lanes }).await?;
+            // Green phase, unless a hold cuts it short.
+            let preempted = self.wait(Duration::from_secs(green_secs), Some(&phase_lanes)).await?;
+            // Amber: cars already in the junction clear it, the rest stop; then all-red clearance.
+            self.clear(&phase_lanes).await?;
+            // Move to the next phase, or back to the interrupted one after the hold.
+            if !preempted {
+                self.phase = (self.phase + 1) % self.phases.len();
+            }
+        }
+    }
+
+    /// Runs the junction until a publish gives up (`mq.failed()` then ends
+    /// the controller) or `stop` is requested. Its cycle is started over
+    /// whenever it panics, or goes STALL_CYCLES expected cycles of
+    /// `cycle_secs` without a phase change (see `rts_core::watchdog`), and a
+    /// JunctionControllerFailed event logged each time; the junction keeps
+    /// its lights, plan, holds and mailbox, and resumes at the phase it was on.
+    async fn run(mut self, cycle_secs: u64, mut stop: StopSignal) {
+        let clock = self.clock;
+        let watch = std::sync::Mutex::new(PhaseWatch::new(cycle_secs, clock.now_secs()));
+        let mut restarts = 0;
+        loop {
+            let mut ticker = tokio::time::interval(clock.real_duration(Duration::from_secs(1)));
+            let reason = {
+                let cycle = AssertUnwindSafe(self.cycle(&watch)).catch_unwind();
+                tokio::pin!(cycle);
+                loop {
+                    tokio::select! {
+                        outcome = &mut cycle => match outcome {
+                            Err(panic) => break format!("panicked: {}", panic_message(&*panic)),
+                            Ok(_) => return,
+                        },
+                        _ = ticker.tick() => {
+                            if let Some(silent) = watch.lock().unwrap().stalled(clock.now_secs()) {
+                                break format!("no phase change for {}s", silent);
+                            }
+                        }
+                        _ = stop.requested() => return,
+                    }
+                }
+            };
+            restarts += 1;
+            let kind = EventKind::JunctionControllerFailed { junction: self.junction, reason, restarts };
+            let log_event = LogEvent::new(self.source.clone(), clock.now_secs(), kind).with_level(Level::Error);
+            if metrics::publish_log(&self.mq, &log_event).await.is_err() {
+                return;
+            }
+            tokio::select! {
+                _ = clock::sleep(&clock, RESTART_DELAY) => {}
+                _ = stop.requested() => return,
+            }
+            // Give the cycle a full limit to make its first change.
+            watch.lock().unwrap().phase_started(clock.now_secs(), 0);
         }
     }
-    
-    groups.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    groups.into_iter().map(|(_avg, ids)| ids).collect()
 }
 
-/// Shared traffic lights mapping: key is lane id, value is LightColor.
-pub type TrafficLightMap = Arc<Mutex<HashMap<u32, LightColor>>>;
+/// Why the control plane turned a request down.
+#[derive(Debug)]
+pub enum ControlError {
+    /// No junction or light by that id.
+    NotFound(String),
+    Invalid(String),
+    /// The junction has stopped.
+    Unavailable(String),
+}
 
-/// Initializes the traffic lights for all lanes that end at a junction.
-pub fn initialize_traffic_lights() -> TrafficLightMap {
-    let mut map = HashMap::new();
-    let lanes = load_lanes();
-    for lane in lanes {
-        if lane.end_intersection != 0 {
-            map.insert(lane.id, LightColor::Red);
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::NotFound(message) | ControlError::Invalid(message) | ControlError::Unavailable(message) => {
+                f.write_str(message)
+            }
         }
     }
-    Arc::new(Mutex::new(map))
+}
+
+/// The controller's junctions, as the recommendations, the preemption
+/// requests and the control plane (see `grpc`) reach them: through the
+/// mailbox of each one.
+#[derive(Clone)]
+pub struct Junctions {
+    mailboxes: Arc<HashMap<u32, mpsc::Sender<JunctionMessage>>>,
+    /// Phases of every junction, fixed for the run.
+    pub phases: Arc<HashMap<u32, Vec<Phase>>>,
+    /// Junction every light's lane enters.
+    lane_junctions: Arc<HashMap<u32, u32>>,
+    pub shown: broadcast::Sender<PhaseShown>,
+    pub clock: SimClock,
+}
+
+impl Junctions {
+    pub fn junction_of(&self, lane_id: u32) -> Option<u32> {
+        self.lane_junctions.get(&lane_id).copied()
+    }
+
+    /// Whether `junction` has lights.
+    pub fn contains(&self, junction: u32) -> bool {
+        self.mailboxes.contains_key(&junction)
+    }
+
+    /// Puts `message` in `junction`'s mailbox, waiting while it is full.
+    async fn tell(&self, junction: u32, message: JunctionMessage) -> Result<(), ControlError> {
+        let mailbox = self.mailboxes.get(&junction).ok_or_else(|| ControlError::NotFound(format!("junction {} has no lights", junction)))?;
+        mailbox.send(message).await.map_err(|_| ControlError::Unavailable(format!("junction {} has stopped", junction)))
+    }
+
+    /// Color of every light of `junction`, or of every junction if it is 0.
+    pub async fn lights(&self, junction: u32) -> Result<HashMap<u32, LightColor>, ControlError> {
+        let junctions: Vec<u32> = if junction == 0 { self.mailboxes.keys().copied().collect() } else { vec![junction] };
+        let mut replies = Vec::new();
+        for junction in junctions {
+            let (reply, colors) = oneshot::channel();
+            self.tell(junction, JunctionMessage::Lights { reply }).await?;
+            replies.push((junction, colors));
+        }
+        let mut lights = HashMap::new();
+        for (junction, colors) in replies {
+            lights.extend(colors.await.map_err(|_| ControlError::Unavailable(format!("junction {} has stopped", junction)))?);
+        }
+        Ok(lights)
+    }
+
+    /// Sets the green time of every phase of `junction`, in phase order,
+    /// from the next time each phase comes round; the junction logs it as
+    /// PhasePlanSet. Every time must be within MIN_GREEN_SECS..=MAX_GREEN_SECS.
+    pub async fn set_greens(&self, junction: u32, green_secs: &[u64]) -> Result<(), ControlError> {
+        let phases = self.phases.get(&junction).ok_or_else(|| ControlError::NotFound(format!("junction {} has no lights", junction)))?;
+        if green_secs.len() != phases.len() {
+            return Err(ControlError::Invalid(format!(
+                "junction {} has {} phases, got {} green times",
+                junction,
+                phases.len(),
+                green_secs.len()
+            )));
+        }
+        if let Some(&secs) = green_secs.iter().find(|secs| !(MIN_GREEN_SECS..=MAX_GREEN_SECS).contains(secs)) {
+            return Err(ControlError::Invalid(format!(
+                "green time {}s is outside {}..={}s",
+                secs, MIN_GREEN_SECS, MAX_GREEN_SECS
+            )));
+        }
+        self.tell(junction, JunctionMessage::SetGreens { green_secs: green_secs.to_vec() }).await
+    }
+
+    /// Holds `lane_id` green (after amber and clearance) or red for
+    /// `duration_secs`. Returns the lane's junction.
+    pub async fn force(&self, lane_id: u32, color: LightColor, duration_secs: u64) -> Result<u32, ControlError> {
+        let junction = self.junction_of(lane_id).ok_or_else(|| ControlError::NotFound(format!("lane {} has no light", lane_id)))?;
+        let message = match color {
+            LightColor::Green if (MIN_GREEN_SECS..=MAX_GREEN_SECS).contains(&duration_secs) => {
+                JunctionMessage::Hold { lane_id, hold: Hold::Forced { green_secs: duration_secs } }
+            }
+            LightColor::Green => {
+                return Err(ControlError::Invalid(format!(
+                    "green time {}s is outside {}..={}s",
+                    duration_secs, MIN_GREEN_SECS, MAX_GREEN_SECS
+                )))
+            }
+            LightColor::Red if duration_secs > 0 => JunctionMessage::HoldRed { lane_id, duration_secs },
+            LightColor::Red => return Err(ControlError::Invalid("a red hold needs a duration".to_string())),
+            LightColor::Amber => return Err(ControlError::Invalid("only green or red can be forced".to_string())),
+        };
+        self.tell(junction, message).await?;
+        Ok(junction)
+    }
+
+    /// Sets every phase serving `lane_id` to a recommended `green_secs`;
+    /// the junction logs each phase it changes as PhaseTimingChanged.
+    /// Returns the lane's junction.
+    pub async fn recommend(&self, lane_id: u32, green_secs: u64) -> Result<u32, ControlError> {
+        let junction = self.junction_of(lane_id).ok_or_else(|| ControlError::NotFound(format!("lane {} has no light", lane_id)))?;
+        self.tell(junction, JunctionMessage::Retime { lane_id, green_secs }).await?;
+        Ok(junction)
+    }
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
 }
 
 /// Runs the traffic light controller:
-/// - For each junction, it spawns an async task that cycles through lane groups in round-robin fashion.
-/// - It logs each phase, waits 5 seconds for green and 10 seconds for all-red clearance.
-/// - Concurrently, it listens for recommendations via RabbitMQ.
-pub async fn run_traffic_lights() -> Result<(), Box<dyn Error>> {
-    let channel = create_channel().await;
-    declare_exchange(&channel, "logs", ExchangeKind::Fanout).await;
-    declare_exchange(&channel, "recommendations", ExchangeKind::Fanout).await;
+/// - Each junction is an actor (see `Junction`) that cycles through its phase plan in round-robin fashion,
+///   or with adaptive phase ordering (see `rts_core::phase_order`) serves the phase with the most
+///   vehicles in the latest counts from "simulation.updates", logging each choice as a PhaseDecision.
+///   It owns its lights, plan and holds; the rest of the controller reaches it only through its mailbox.
+/// - Each junction publishes its lanes' color changes on "light_status", and a snapshot of its
+///   lights every LIGHT_SNAPSHOT_INTERVAL_SECS for consumers that joined late.
+/// - It logs each phase, waits the phase's green time (5 seconds until a recommendation
+///   changes it), RTS_AMBER_SECS (3 by default) for amber and 10 seconds for all-red
+///   clearance, in `clock`'s simulated time.
+/// - An emergency vehicle's request on "preemption" interrupts its junction's cycle to
+///   hold the vehicle's lane green, after which the cycle resumes (see `Junction::cycle`).
+/// - Each junction watches its own cycle: one that panics or stops changing phase is
+///   started over, and the failure logged as JunctionControllerFailed.
+/// - Concurrently, it listens for recommendations via RabbitMQ. A recommendation sets the
+///   green time of every phase serving its lane from the next time that phase comes round,
+///   logged as PhaseTimingChanged, and is reported on "recommendations.applied".
+/// - With the `grpc` feature and RTS_GRPC_PORT set, external traffic management can
+///   read the lights, set phase plans, force lanes and follow phase changes (see `grpc`).
+///
+/// Runs until the simulation announces shutdown or Ctrl-C is pressed (see
+/// `control`), then stops every junction and waits for it to end.
+/// Returns an error once any publish has given up after its retries.
+pub async fn run_traffic_lights(clock: SimClock) -> Result<(), Box<dyn Error>> {
+    let mq = create_channel().await?;
+    declare_exchange(&mq, mq::LOGS).await;
+    declare_exchange(&mq, mq::RECOMMENDATIONS).await;
+    declare_exchange(&mq, mq::RECOMMENDATIONS_APPLIED).await;
     // Declare a new exchange for light status updates.
-    declare_exchange(&channel, "light_status", ExchangeKind::Fanout).await;
+    declare_exchange(&mq, mq::LIGHT_STATUS).await;
+    declare_exchange(&mq, mq::PREEMPTION).await;
+    tokio::spawn(heartbeat::publish_heartbeats(mq.clone(), "traffic_light", clock));
+    let mut stop = control::listen(&mq).await?;
 
-    let traffic_lights = initialize_traffic_lights();
+    METRICS.register_junctions(&load_lanes());
+    metrics::start("traffic_light", mq.clone());
 
     // Build a map: junction -> list of lanes that enter that junction.
     let lanes = load_lanes();
+    let network = load_network();
     let mut junction_map: HashMap<u32, Vec<Lane>> = HashMap::new();
-    for lane in lanes {
-        if lane.end_intersection != 0 {
-            junction_map.entry(lane.end_intersection).or_default().push(lane);
+    for lane in &lanes {
+        // Junctions without traffic lights are left to the cars.
+        if lane.end_intersection != 0 && network.is_signalized(lane.end_intersection) {
+            junction_map.entry(lane.end_intersection).or_default().push(lane.clone());
         }
     }
-    
-    // For each junction, spawn an asynchronous task for round-robin phase cycling.
+    let lane_junctions: Arc<HashMap<u32, u32>> = Arc::new(
+        junction_map.iter().flat_map(|(&junction, lane_list)| lane_list.iter().map(move |lane| (lane.id, junction))).collect(),
+    );
+
+    // For each junction, spawn its actor, starting with every light red.
+    let phase_order = PhaseOrder::from_env();
+    let amber_secs = amber_secs_from_env();
+    let seed = seed::seed_from_env();
+    let mut mailboxes = HashMap::new();
+    let mut plans = HashMap::new();
+    let mut lane_counts = HashMap::new();
+    let mut actors = Vec::new();
+    let (shown, _) = broadcast::channel(PHASE_CHANGE_BUFFER);
     for (junction, lane_list) in junction_map.into_iter() {
-        let groups = group_lanes_by_direction(&lane_list);
-        let tl_clone = Arc::clone(&traffic_lights);
-        let channel_clone = channel.clone();
+        let phases = build_phase_plan(junction, &lanes, &network);
+        let cycle_secs = expected_cycle_secs(phases.len(), GREEN_SECS, amber_secs + CLEARANCE_SECS);
+        let (mailbox_tx, mailbox) = mpsc::channel(JUNCTION_MAILBOX);
+        let (counts_tx, counts) = watch::channel(HashMap::new());
+        mailboxes.insert(junction, mailbox_tx);
+        plans.insert(junction, phases.clone());
+        lane_counts.insert(junction, counts_tx);
+        let mut snapshots = tokio::time::interval(clock.real_duration(Duration::from_secs(LIGHT_SNAPSHOT_INTERVAL_SECS)));
+        snapshots.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let actor = Junction {
+            junction,
+            source: format!("Junction-{}", junction),
+            lights: lane_list.iter().map(|lane| (lane.id, LightColor::Red)).collect(),
+            lanes: lane_list,
+            greens: vec![GREEN_SECS; phases.len()],
+            amber_secs,
+            phase: seed::phase_offset(seed, junction, phases.len()),
+            selector: match phase_order {
+                PhaseOrder::Fixed => None,
+                PhaseOrder::Adaptive { starvation_cycles } => Some(PhaseSelector::new(phases.len(), starvation_cycles)),
+            },
+            phases,
+            pending: VecDeque::new(),
+            red_until: HashMap::new(),
+            mailbox,
+            mailbox_closed: false,
+            counts,
+            snapshots,
+            mq: mq.clone(),
+            shown: shown.clone(),
+            clock,
+        };
+        actors.push(tokio::spawn(actor.run(cycle_secs, stop.clone())));
+    }
+
+    if let PhaseOrder::Adaptive { starvation_cycles } = phase_order {
+        println!("Adaptive phase ordering enabled: every phase green at least once per {} cycles", starvation_cycles);
+        declare_exchange(&mq, mq::SIMULATION_UPDATES).await;
+        let counts_mq = mq.clone();
+        let lane_junctions = Arc::clone(&lane_junctions);
         tokio::spawn(async move {
-            let mut group_index = 0;
-            loop {
-                let mut green_lanes = Vec::new();
-                let mut red_lanes = Vec::new();
-                {
-                    let mut lights = tl_clone.lock().await;
-                    for lane in &lane_list {
-                        if groups[group_index].contains(&lane.id) {
-                            lights.insert(lane.id, LightColor::Green);
-                            green_lanes.push(lane.id);
-                        } else {
-                            lights.insert(lane.id, LightColor::Red);
-                            red_lanes.push(lane.id);
-                        }
-                    }
-                }
-                // After updating, publish the light status for each lane.
-                for lane in &lane_list {
-                    let status = {
-                        let lights = tl_clone.lock().await;
-                        match lights.get(&lane.id) {
-                            Some(LightColor::Green) => "Green",
-                            _ => "Red",
-                        }
-                    };
-                    let light_status = LightStatus {
-                        lane_id: lane.id,
-                        status: status.to_string(),
-                    };
-                    // Publish to the "light_status" exchange.
-                    publish_message(&channel_clone, "light_status", "", &light_status).await;
-                }
-                // Log the current phase.
-                let log_event = LogEvent {
-                    source: format!("Junction-{}", junction),
-                    message: format!("Phase {} active: Green lanes {:?}, Red lanes {:?}", group_index, green_lanes, red_lanes),
-                    timestamp: current_time_secs(),
-                };
-                let _ = publish_message(&channel_clone, "logs", "", &log_event).await;
-                // Green phase: hold for 5 seconds.
-                sleep(Duration::from_secs(5)).await;
-                // All-red clearance phase.
-                {
-                    let mut lights = tl_clone.lock().await;
-                    for lane in &lane_list {
-                        lights.insert(lane.id, LightColor::Red);
-                    }
-                }
-                // Publish the all-red status.
-                for lane in &lane_list {
-                    let light_status = LightStatus {
-                        lane_id: lane.id,
-                        status: "Red".to_string(),
-                    };
-                    publish_message(&channel_clone, "light_status", "", &light_status).await;
-                }
-                sleep(Duration::from_secs(10)).await;
-                // Move to the next group.
-                group_index = (group_index + 1) % groups.len();
+            if let Err(e) = follow_lane_counts(counts_mq, lane_counts, lane_junctions).await {
+                eprintln!("Lost the lane counts; adaptive junctions keep their last ones: {}", e);
             }
         });
     }
 
-    // Separately, subscribe to recommendations from RabbitMQ.
-    let queue = channel.queue_declare("", lapin::options::QueueDeclareOptions::default(), lapin::types::FieldTable::default()).await?;
-    channel.queue_bind(queue.name().as_str(), "recommendations", "", lapin::options::QueueBindOptions::default(), lapin::types::FieldTable::default()).await?;
-    let mut consumer = channel.basic_consume(queue.name().as_str(), "traffic_light_recs", lapin::options::BasicConsumeOptions::default(), lapin::types::FieldTable::default()).await?;
+    let junctions = Junctions {
+        mailboxes: Arc::new(mailboxes),
+        phases: Arc::new(plans),
+        lane_junctions,
+        shown,
+        clock,
+    };
+    grpc::start(junctions.clone());
+    let preemption_mq = mq.clone();
+    let preemption_junctions = junctions.clone();
+    tokio::spawn(async move {
+        if let Err(e) = follow_preemptions(preemption_mq, preemption_junctions).await {
+            eprintln!("Lost the preemption requests; emergency vehicles wait like the others: {}", e);
+        }
+    });
+
+    // Separately, subscribe to recommendations from the broker.
+    let mut consumer = mq.subscribe(mq::RECOMMENDATIONS, "traffic_light_recs").await?;
     
     println!("Traffic Light Controller waiting for recommendations...");
-    while let Some(delivery_result) = consumer.next().await {
-        if let Ok(delivery) = delivery_result {
-            let data = delivery.data.clone();
-            if let Ok(rec) = serde_json::from_slice::<Recommendation>(&data) {
+    loop {
+        let delivery_result = tokio::select! {
+            delivery_result = consumer.next() => delivery_result,
+            reason = mq.failed() => return Err(reason.into()),
+            _ = stop.requested() => break,
+        };
+        let Some(delivery_result) = delivery_result else { break };
+        if let Ok(payload) = delivery_result {
+            if let Ok(rec) = serde_json::from_slice::<Recommendation>(&payload) {
                 println!("Received recommendation: {:?}", rec);
-                let mut lights = traffic_lights.lock().await;
-                if let Some(light) = lights.get_mut(&rec.lane_id) {
-                    *light = LightColor::Green;
-                    let log_event = LogEvent {
-                        source: format!("TrafficLight-{}", rec.lane_id),
-                        message: "Set to Green per recommendation".into(),
-                        timestamp: current_time_secs(),
-                    };
-                    let _ = publish_message(&channel, "logs", "", &log_event).await;
+                METRICS.observe_recommendation(&rec);
+                let green_secs = u64::from(rec.new_green_time);
+                match junctions.recommend(rec.lane_id, green_secs).await {
+                    Ok(_) => {
+                        let applied = RecommendationApplied {
+                            lane_id: rec.lane_id,
+                            applied_green_time: green_secs.clamp(MIN_GREEN_SECS, MAX_GREEN_SECS) as u32,
+                            timestamp: clock.now_secs(),
+                        };
+                        publish_message(&mq, mq::RECOMMENDATIONS_APPLIED, &applied).await?;
+                    }
+                    Err(ControlError::NotFound(_)) => {
+                        let log_event = LogEvent {
+                            source: "TrafficLightController".to_string(),
+                            message: format!("Recommendation ignored: lane {} has no traffic light", rec.lane_id),
+                            timestamp: clock.now_secs(),
+                            level: Level::Warn,
+                            kind: EventKind::Generic,
+                        };
+                        metrics::publish_log(&mq, &log_event).await?;
+                    }
+                    Err(e) => eprintln!("Recommendation for lane {} not applied: {}", rec.lane_id, e),
                 }
             }
-            delivery.ack(lapin::options::BasicAckOptions::default()).await?;
         }
     }
 
+    // Every junction stops at the same request; if the recommendations
+    // ended instead, the runtime drops them on return.
+    if stop.is_requested() {
+        for actor in actors {
+            actor.await.ok();
+        }
+        println!("Traffic Light Controller stopped.");
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run_traffic_lights().await {
+    if let Err(e) = run_traffic_lights(SimClock::from_env()).await {
         eprintln!("Error in traffic light controller: {}", e);
     }
 }
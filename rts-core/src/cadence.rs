@@ -0,0 +1,169 @@
+// cadence.rs
+//
+// Adaptive cadence for the lane snapshot publisher. The controller looks at
+// how much the lane counts moved since the previous snapshot and shortens the
+// interval while the network is volatile, lengthening it while it is quiet.
+// The interval never leaves [floor, ceiling], so a full snapshot still goes
+// out at least once per ceiling for consumers that join late.
+//
+// The controller never reads a clock: callers pass in the counts and the
+// elapsed time, so its decisions depend only on its inputs.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Shortest interval between two snapshots.
+pub const FLOOR: Duration = Duration::from_secs(1);
+/// Longest interval between two snapshots.
+pub const CEILING: Duration = Duration::from_secs(10);
+/// Interval used before anything has been observed.
+pub const INITIAL: Duration = Duration::from_secs(5);
+/// Lane-count changes per second at or above which the interval shrinks.
+const HIGH_CHANGE_RATE: f64 = 1.0;
+/// Lane-count changes per second at or below which the interval grows.
+const LOW_CHANGE_RATE: f64 = 0.2;
+
+/// Interval between lane snapshots, adapted to how fast the counts change.
+#[derive(Debug, Clone)]
+pub struct CadenceController {
+    floor: Duration,
+    ceiling: Duration,
+    current: Duration,
+    previous: Option<HashMap<u32, u32>>,
+}
+
+impl CadenceController {
+    /// A controller starting at `initial`, clamped to [`floor`, `ceiling`].
+    pub fn new(floor: Duration, ceiling: Duration, initial: Duration) -> Self {
+        CadenceController {
+            floor,
+            ceiling,
+            current: initial.clamp(floor, ceiling),
+            previous: None,
+        }
+    }
+
+    /// Interval to wait before the next snapshot.
+    pub fn interval(&self) -> Duration {
+        self.current
+    }
+
+    /// Feeds the lane counts taken `elapsed` after the previous snapshot and
+    /// returns the interval to wait before the next one.
+    pub fn observe(&mut self, lanes: &HashMap<u32, u32>, elapsed: Duration) -> Duration {
+        if let Some(previous) = &self.previous {
+            let rate = change_count(previous, lanes) as f64 / elapsed.as_secs_f64().max(0.001);
+            self.current = if rate >= HIGH_CHANGE_RATE {
+                self.current / 2
+            } else if rate <= LOW_CHANGE_RATE {
+                self.current * 3 / 2
+            } else {
+                self.current
+            }
+            .clamp(self.floor, self.ceiling);
+        }
+        self.previous = Some(lanes.clone());
+        self.current
+    }
+}
+
+/// Total absolute change in vehicle counts between two snapshots.
+fn change_count(before: &HashMap<u32, u32>, after: &HashMap<u32, u32>) -> u32 {
+    let mut changes = 0;
+    for (lane_id, &count) in after {
+        changes += count.abs_diff(before.get(lane_id).copied().unwrap_or(0));
+    }
+    for (lane_id, &count) in before {
+        if !after.contains_key(lane_id) {
+            changes += count;
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts of lanes 1 to 4, each holding `count` vehicles.
+    fn counts(count: u32) -> HashMap<u32, u32> {
+        (1..=4).map(|lane_id| (lane_id, count)).collect()
+    }
+
+    /// Feeds `controller` snapshots whose counts move by `step` per lane
+    /// each time, `rounds` times, and returns the intervals it chose.
+    fn feed(controller: &mut CadenceController, start: u32, step: u32, rounds: usize) -> Vec<Duration> {
+        let mut count = start;
+        (0..rounds)
+            .map(|_| {
+                count += step;
+                let elapsed = controller.interval();
+                controller.observe(&counts(count), elapsed)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_snapshot_keeps_the_initial_interval() {
+        let mut controller = CadenceController::new(FLOOR, CEILING, INITIAL);
+        assert_eq!(controller.observe(&counts(3), INITIAL), INITIAL);
+    }
+
+    #[test]
+    fn initial_interval_is_clamped() {
+        let controller = CadenceController::new(FLOOR, CEILING, Duration::from_secs(60));
+        assert_eq!(controller.interval(), CEILING);
+        let controller = CadenceController::new(FLOOR, CEILING, Duration::from_millis(10));
+        assert_eq!(controller.interval(), FLOOR);
+    }
+
+    #[test]
+    fn volatile_counts_halve_the_interval_down_to_the_floor() {
+        let mut controller = CadenceController::new(FLOOR, CEILING, INITIAL);
+        controller.observe(&counts(0), INITIAL);
+        // 4 lanes moving by 5 each: 20 changes per snapshot, well above 1/s.
+        let intervals = feed(&mut controller, 0, 5, 4);
+        assert_eq!(intervals, [Duration::from_millis(2500), Duration::from_millis(1250), FLOOR, FLOOR]);
+    }
+
+    #[test]
+    fn quiet_counts_lengthen_the_interval_up_to_the_ceiling() {
+        let mut controller = CadenceController::new(FLOOR, CEILING, INITIAL);
+        controller.observe(&counts(2), INITIAL);
+        let intervals = feed(&mut controller, 2, 0, 4);
+        assert_eq!(intervals, [Duration::from_millis(7500), CEILING, CEILING, CEILING]);
+    }
+
+    #[test]
+    fn interval_follows_a_step_in_volatility_both_ways() {
+        let mut controller = CadenceController::new(FLOOR, CEILING, INITIAL);
+        controller.observe(&counts(0), INITIAL);
+        feed(&mut controller, 0, 0, 3);
+        assert_eq!(controller.interval(), CEILING);
+        // Rush hour: the interval drops to the floor within a few snapshots.
+        feed(&mut controller, 0, 10, 4);
+        assert_eq!(controller.interval(), FLOOR);
+        // And recovers once it is over.
+        let quiet = feed(&mut controller, 40, 0, 6);
+        assert!(quiet.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", quiet);
+        assert_eq!(controller.interval(), CEILING);
+    }
+
+    #[test]
+    fn moderate_change_holds_the_interval() {
+        let mut controller = CadenceController::new(FLOOR, CEILING, INITIAL);
+        controller.observe(&counts(0), INITIAL);
+        // 4 changes in 5 s: between the two thresholds.
+        let mut next = counts(0);
+        next.insert(1, 4);
+        assert_eq!(controller.observe(&next, INITIAL), INITIAL);
+    }
+
+    #[test]
+    fn lanes_that_empty_out_count_as_changes() {
+        let before = counts(2);
+        let after: HashMap<u32, u32> = HashMap::from([(1, 2), (2, 2)]);
+        assert_eq!(change_count(&before, &after), 4);
+        assert_eq!(change_count(&after, &before), 4);
+    }
+}
@@ -0,0 +1,267 @@
+// car_following.rs
+//
+// The Intelligent Driver Model (Treiber, Hennecke and Helbing). A car
+// accelerates towards its desired speed and brakes for whatever is ahead of
+// it on its lane: the rear of the car in front, or the stop line of a light
+// it has to stop at, which counts as a standing car. How hard it brakes
+// depends on the gap and on how fast it is closing in, so a car slowing down
+// slows the cars behind it a little later, and a queue at a red light grows
+// backwards from the stop line and dissolves from the front once it turns
+// green. A simulation steps every car forward a short time at a time with
+// `Idm::step`.
+//
+// A lane may stand for several parallel lanes. Cars spread over them, so a
+// car follows the car as many places ahead of it as there are parallel
+// lanes, and cars side by side do not hold each other up.
+//
+// Distances are in meters, speeds in m/s and accelerations in m/s², like
+// the lane lengths of the rest of the simulation; a queued car takes up
+// METERS_PER_VEHICLE of the lane, its length and the minimum gap together.
+// The defaults are the usual ones for city traffic: a car pulls away at
+// DEFAULT_MAX_ACCELERATION and brakes at DEFAULT_COMFORTABLE_DECELERATION,
+// keeping 1.5 s behind the car in front. RTS_IDM_ACCELERATION and
+// RTS_IDM_DECELERATION set others.
+
+use crate::lanes::METERS_PER_VEHICLE;
+
+/// Acceleration from rest unless RTS_IDM_ACCELERATION says otherwise, in m/s².
+pub const DEFAULT_MAX_ACCELERATION: f64 = 1.4;
+
+/// Comfortable braking unless RTS_IDM_DECELERATION says otherwise, in m/s².
+pub const DEFAULT_COMFORTABLE_DECELERATION: f64 = 2.0;
+
+/// Exponent of the free-road term: how late a car stops accelerating as it
+/// nears its desired speed.
+const ACCELERATION_EXPONENT: i32 = 4;
+
+/// Whatever a car is following on its lane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Obstacle {
+    /// Meters from the car's front to the obstacle: the rear of the car in
+    /// front, or the stop line.
+    pub gap: f64,
+    /// The obstacle's speed; 0.0 for a stop line.
+    pub speed: f64,
+}
+
+/// Parameters of the model, the same for every car; each car brings its own
+/// desired speed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Idm {
+    /// Acceleration from rest, in m/s².
+    pub max_acceleration: f64,
+    /// Deceleration a car brakes with when it can, in m/s²; it brakes
+    /// harder when it has to.
+    pub comfortable_deceleration: f64,
+    /// Seconds behind the car in front a car keeps to while moving.
+    pub time_headway: f64,
+    /// Meters a car keeps to the car in front while standing.
+    pub min_gap: f64,
+    /// Meters of lane a car covers itself.
+    pub vehicle_length: f64,
+}
+
+impl Default for Idm {
+    /// City traffic: DEFAULT_MAX_ACCELERATION and
+    /// DEFAULT_COMFORTABLE_DECELERATION, 1.5 s behind the car in front, and
+    /// queuing METERS_PER_VEHICLE apart.
+    fn default() -> Self {
+        Idm {
+            max_acceleration: DEFAULT_MAX_ACCELERATION,
+            comfortable_deceleration: DEFAULT_COMFORTABLE_DECELERATION,
+            time_headway: 1.5,
+            min_gap: 2.0,
+            vehicle_length: METERS_PER_VEHICLE - 2.0,
+        }
+    }
+}
+
+/// A positive number of m/s² from `var`, if it is set (with a warning, and
+/// None, if it is invalid).
+fn rate_from_env(var: &str) -> Option<f64> {
+    let value = std::env::var(var).ok()?;
+    match value.trim().parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Some(rate),
+        _ => {
+            eprintln!("Ignoring {}: expected a positive acceleration in m/s², got '{}'", var, value.trim());
+            None
+        }
+    }
+}
+
+impl Idm {
+    /// The defaults, with the acceleration from RTS_IDM_ACCELERATION and the
+    /// braking from RTS_IDM_DECELERATION where they are set.
+    pub fn from_env() -> Idm {
+        let defaults = Idm::default();
+        Idm {
+            max_acceleration: rate_from_env("RTS_IDM_ACCELERATION").unwrap_or(defaults.max_acceleration),
+            comfortable_deceleration: rate_from_env("RTS_IDM_DECELERATION").unwrap_or(defaults.comfortable_deceleration),
+            ..defaults
+        }
+    }
+
+    /// Acceleration of a car at `speed` that wants to drive at
+    /// `desired_speed`, following `ahead` if anything is; negative when it
+    /// brakes. With nothing ahead it only approaches its desired speed.
+    pub fn acceleration(&self, speed: f64, desired_speed: f64, ahead: Option<Obstacle>) -> f64 {
+        let free_road = if desired_speed > 0.0 {
+            1.0 - (speed / desired_speed).powi(ACCELERATION_EXPONENT)
+        } else {
+            // A car that may not move at all brakes to a stop.
+            -1.0
+        };
+        let interaction = match ahead {
+            Some(obstacle) => {
+                let closing = speed - obstacle.speed;
+                let desired_gap = self.min_gap
+                    + (speed * self.time_headway
+                        + speed * closing / (2.0 * (self.max_acceleration * self.comfortable_deceleration).sqrt()))
+                    .max(0.0);
+                (desired_gap / obstacle.gap.max(f64::EPSILON)).powi(2)
+            }
+            None => 0.0,
+        };
+        self.max_acceleration * (free_road - interaction)
+    }
+
+    /// Moves a car at `speed` on for `secs` under `acceleration`: its new
+    /// speed and the meters it covered. A braking car stops rather than
+    /// reversing, and a car never closes in on `ahead` to less than min_gap
+    /// (right up to it, for a stop line): one that would stops there.
+    pub fn step(&self, speed: f64, acceleration: f64, secs: f64, ahead: Option<Obstacle>) -> (f64, f64) {
+        let next = speed + acceleration * secs;
+        let (next, meters) = if next >= 0.0 {
+            (next, (speed + next) / 2.0 * secs)
+        } else {
+            // Stops part way through the step.
+            (0.0, speed * speed / (-2.0 * acceleration))
+        };
+        match ahead {
+            Some(obstacle) if meters >= obstacle.gap - self.min_gap => (0.0, (obstacle.gap - self.min_gap).max(0.0)),
+            _ => (next, meters),
+        }
+    }
+
+    /// The stop line `meters` ahead, as an obstacle a car stops right at
+    /// rather than min_gap short of.
+    pub fn stop_line(&self, meters: f64) -> Obstacle {
+        Obstacle { gap: meters + self.min_gap, speed: 0.0 }
+    }
+
+    /// What a car `distance` meters along a lane of `parallel` parallel
+    /// lanes follows, given the distance along it and the speed of every
+    /// other car ahead of it on the lane; None if it has the road to itself.
+    pub fn leader(&self, distance: f64, parallel: u32, mut ahead: Vec<(f64, f64)>) -> Option<Obstacle> {
+        ahead.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let &(front, speed) = ahead.get(parallel.max(1) as usize - 1)?;
+        Some(Obstacle { gap: front - self.vehicle_length - distance, speed })
+    }
+
+    /// True if a car at `speed` `gap` meters from a stop line can still
+    /// stop before it braking comfortably; one that cannot drives on over
+    /// an amber light.
+    pub fn can_stop(&self, speed: f64, gap: f64) -> bool {
+        speed * speed / (2.0 * self.comfortable_deceleration) <= gap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drives a car at `speed` from the start of a lane behind `ahead` for
+    /// `secs`, a quarter second at a time: where it ends up and its speed.
+    fn drive(idm: &Idm, mut speed: f64, desired_speed: f64, ahead: &[(f64, f64)], secs: f64) -> (f64, f64) {
+        let mut distance = 0.0;
+        for _ in 0..(secs / 0.25) as usize {
+            let leader = idm.leader(distance, 1, ahead.to_vec());
+            let acceleration = idm.acceleration(speed, desired_speed, leader);
+            let (next, meters) = idm.step(speed, acceleration, 0.25, leader);
+            distance += meters;
+            speed = next;
+        }
+        (distance, speed)
+    }
+
+    #[test]
+    fn default_parameters_are_city_traffic() {
+        let idm = Idm::default();
+        assert!((1.0..=1.5).contains(&idm.max_acceleration));
+        assert!((1.5..=2.0).contains(&idm.comfortable_deceleration));
+    }
+
+    #[test]
+    fn car_pulls_away_at_max_acceleration() {
+        let idm = Idm::default();
+        assert_eq!(idm.acceleration(0.0, 14.0, None), idm.max_acceleration);
+        // It levels off at its desired speed.
+        let (_, speed) = drive(&idm, 0.0, 14.0, &[], 120.0);
+        assert!((speed - 14.0).abs() < 0.1, "cruising at {}", speed);
+    }
+
+    #[test]
+    fn follower_stops_behind_stopped_leader_at_min_gap() {
+        let idm = Idm::default();
+        // A standing car whose front is 150 m along the lane.
+        let leader_front = 150.0;
+        for approach_speed in [5.0, 14.0, 20.0] {
+            let (distance, speed) = drive(&idm, approach_speed, approach_speed, &[(leader_front, 0.0)], 120.0);
+            let gap = leader_front - idm.vehicle_length - distance;
+            assert!(speed < 0.01, "still moving at {} from {}", speed, approach_speed);
+            assert!(gap >= idm.min_gap, "stopped {} m behind from {}", gap, approach_speed);
+            assert!(gap < idm.min_gap + 0.5, "stopped {} m behind from {}", gap, approach_speed);
+        }
+    }
+
+    #[test]
+    fn car_stops_at_the_stop_line() {
+        let idm = Idm::default();
+        let line = 100.0;
+        let (mut distance, mut speed) = (0.0, 12.0);
+        for _ in 0..480 {
+            let stop_line = Some(idm.stop_line(line - distance));
+            let acceleration = idm.acceleration(speed, 12.0, stop_line);
+            let (next, meters) = idm.step(speed, acceleration, 0.25, stop_line);
+            distance += meters;
+            speed = next;
+        }
+        assert!(speed < 0.01);
+        assert!(distance <= line && distance > line - 0.5, "stopped at {}", distance);
+    }
+
+    #[test]
+    fn parallel_lanes_follow_the_car_that_many_places_ahead() {
+        let idm = Idm::default();
+        let ahead = vec![(60.0, 3.0), (30.0, 5.0), (90.0, 1.0)];
+        assert_eq!(idm.leader(10.0, 1, ahead.clone()).map(|o| o.speed), Some(5.0));
+        assert_eq!(idm.leader(10.0, 2, ahead.clone()).map(|o| o.speed), Some(3.0));
+        assert_eq!(idm.leader(10.0, 4, ahead), None);
+    }
+
+    #[test]
+    fn braking_never_reverses() {
+        let idm = Idm::default();
+        let (speed, meters) = idm.step(2.0, -8.0, 1.0, None);
+        assert_eq!(speed, 0.0);
+        assert!((meters - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn step_never_closes_in_below_min_gap() {
+        let idm = Idm::default();
+        let ahead = Obstacle { gap: 3.0, speed: 0.0 };
+        assert_eq!(idm.step(10.0, 0.0, 1.0, Some(ahead)), (0.0, 1.0));
+        // Nor does it drive over a stop line.
+        assert_eq!(idm.step(10.0, 0.0, 1.0, Some(idm.stop_line(4.0))), (0.0, 4.0));
+        assert_eq!(idm.step(1.0, 0.0, 1.0, Some(idm.stop_line(4.0))), (1.0, 1.0));
+    }
+
+    #[test]
+    fn can_stop_only_within_comfortable_braking_distance() {
+        let idm = Idm::default();
+        // 14 m/s stops in 49 m at 2 m/s².
+        assert!(idm.can_stop(14.0, 50.0));
+        assert!(!idm.can_stop(14.0, 40.0));
+    }
+}
@@ -0,0 +1,147 @@
+// clock.rs
+//
+// Simulated time. Components sleep and take timestamps through a SimClock
+// whose scale is the number of simulated seconds that pass per real second:
+// with `--speedup 10` (or RTS_TIME_SCALE=10) a 5 s green phase lasts 500 ms
+// of wall time. Every duration handed to the clock and every reading taken
+// from it is simulated time. At the default scale of 1.0 the clock is the
+// wall clock.
+//
+// Each process builds its own clock from its arguments and environment, so
+// give all of them the same `--speedup` or RTS_TIME_SCALE. Accelerated
+// simulated time starts from each process's start-up, so timestamps taken in
+// different processes only agree to within their start-up gap times the
+// scale.
+//
+// `sleep` blocks the calling thread; the tokio deployments wait for
+// `real_duration` on their runtime instead, and CK wakes early on shutdown.
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A clock running at a fixed multiple of real time.
+#[derive(Debug, Clone, Copy)]
+pub struct SimClock {
+    scale: f64,
+    /// Real instant the clock was created at.
+    origin: Instant,
+    /// Unix time at `origin`; simulated time starts from it.
+    origin_unix: Duration,
+}
+
+impl SimClock {
+    /// A clock running `scale` times faster than real time.
+    /// Panics unless `scale` is positive and finite.
+    pub fn new(scale: f64) -> Self {
+        assert!(scale > 0.0 && scale.is_finite(), "invalid time scale {}", scale);
+        SimClock {
+            scale,
+            origin: Instant::now(),
+            origin_unix: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+        }
+    }
+
+    /// Parses a time scale such as `10` or `0.5`.
+    pub fn parse_scale(spec: &str) -> Result<f64, String> {
+        let scale: f64 = spec
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid time scale '{}'", spec.trim()))?;
+        if !(scale > 0.0 && scale.is_finite()) {
+            return Err(format!("time scale must be positive, got '{}'", spec.trim()));
+        }
+        Ok(scale)
+    }
+
+    /// Clock scaled by `--speedup <n>` (or `--speedup=<n>`), else by
+    /// RTS_TIME_SCALE, falling back to real time (with a warning) when
+    /// neither is set or the scale is invalid.
+    pub fn from_env() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let flag = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--speedup") {
+            Some("") => Some(("--speedup", args.get(i + 1).cloned().unwrap_or_default())),
+            Some(value) => value.strip_prefix('=').map(|value| ("--speedup", value.to_string())),
+            None => None,
+        });
+        let (source, spec) = match flag {
+            Some(flag) => flag,
+            None => match std::env::var("RTS_TIME_SCALE") {
+                Ok(spec) => ("RTS_TIME_SCALE", spec),
+                Err(_) => return SimClock::new(1.0),
+            },
+        };
+        let scale = SimClock::parse_scale(&spec).unwrap_or_else(|e| {
+            eprintln!("Ignoring {}: {}", source, e);
+            1.0
+        });
+        SimClock::new(scale)
+    }
+
+    /// Simulated seconds per real second.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Whether the clock runs at anything other than real time.
+    pub fn is_accelerated(&self) -> bool {
+        self.scale != 1.0
+    }
+
+    /// Real time it takes for `sim` of simulated time to pass.
+    pub fn real_duration(&self, sim: Duration) -> Duration {
+        if self.is_accelerated() {
+            sim.div_f64(self.scale)
+        } else {
+            sim
+        }
+    }
+
+    /// Simulated time that has passed since `start`, a reading of `Instant::now()`.
+    pub fn since(&self, start: Instant) -> Duration {
+        let real = start.elapsed();
+        if self.is_accelerated() {
+            real.mul_f64(self.scale)
+        } else {
+            real
+        }
+    }
+
+    /// Blocks the calling thread for `sim` of simulated time.
+    pub fn sleep(&self, sim: Duration) {
+        thread::sleep(self.real_duration(sim));
+    }
+
+    /// Current time in Unix seconds. When accelerated, simulated time runs
+    /// from the wall-clock time the clock was created at.
+    pub fn now_secs(&self) -> u64 {
+        if self.is_accelerated() {
+            (self.origin_unix + self.since(self.origin)).as_secs()
+        } else {
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_positive_scales_only() {
+        assert_eq!(SimClock::parse_scale(" 10 "), Ok(10.0));
+        assert_eq!(SimClock::parse_scale("0.5"), Ok(0.5));
+        assert!(SimClock::parse_scale("0").is_err());
+        assert!(SimClock::parse_scale("-2").is_err());
+        assert!(SimClock::parse_scale("inf").is_err());
+        assert!(SimClock::parse_scale("fast").is_err());
+    }
+
+    #[test]
+    fn simulated_time_runs_at_the_scale() {
+        let clock = SimClock::new(10.0);
+        assert_eq!(clock.real_duration(Duration::from_secs(5)), Duration::from_millis(500));
+        let real_time = SimClock::new(1.0);
+        assert!(!real_time.is_accelerated());
+        assert_eq!(real_time.real_duration(Duration::from_secs(5)), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,82 @@
+// demand.rs
+//
+// How many vehicles a run spawns, and when. `--cars N` on the command line,
+// or SIM_CARS when the flag is absent, sets the count. By default they all
+// start together; `--arrival-rate R`, or SIM_ARRIVAL_RATE, instead spawns
+// them one after another as a Poisson stream averaging R vehicles per
+// simulated minute, so the network sees steady demand rather than one wave.
+// An invalid value is ignored with a warning.
+
+use std::time::Duration;
+
+/// Vehicles spawned when neither `--cars` nor SIM_CARS says otherwise.
+pub const DEFAULT_CARS: u32 = 30;
+
+/// When a run's vehicles are spawned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arrivals {
+    /// Every vehicle at the start of the run.
+    AllAtOnce,
+    /// One at a time, averaging `per_minute` vehicles per simulated minute.
+    Continuous {
+        /// Mean arrival rate, vehicles per simulated minute.
+        per_minute: f64,
+    },
+}
+
+/// The vehicles a run spawns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Demand {
+    /// Number of vehicles, not counting scenario bursts.
+    pub cars: u32,
+    /// When they are spawned.
+    pub arrivals: Arrivals,
+}
+
+impl Default for Demand {
+    fn default() -> Self {
+        Demand { cars: DEFAULT_CARS, arrivals: Arrivals::AllAtOnce }
+    }
+}
+
+impl Demand {
+    /// Demand from `--cars` and `--arrival-rate` in `args`, falling back to
+    /// SIM_CARS and SIM_ARRIVAL_RATE for a flag that is absent.
+    pub fn from_args(args: &[String]) -> Demand {
+        let mut demand = Demand::default();
+        if let Some((source, value)) = setting(args, "--cars", "SIM_CARS") {
+            match value.trim().parse() {
+                Ok(cars) if cars > 0 => demand.cars = cars,
+                _ => eprintln!("Ignoring {}: expected a positive number of cars, got '{}'", source, value.trim()),
+            }
+        }
+        if let Some((source, value)) = setting(args, "--arrival-rate", "SIM_ARRIVAL_RATE") {
+            match value.trim().parse::<f64>() {
+                Ok(per_minute) if per_minute > 0.0 && per_minute.is_finite() => {
+                    demand.arrivals = Arrivals::Continuous { per_minute }
+                }
+                _ => eprintln!("Ignoring {}: expected a positive rate in vehicles per minute, got '{}'", source, value.trim()),
+            }
+        }
+        demand
+    }
+
+    /// Simulated time from one spawn to the next: none when every vehicle
+    /// starts at once, otherwise an exponentially distributed gap drawn with
+    /// `uniform`, a sample from [0, 1).
+    pub fn gap(&self, uniform: f64) -> Duration {
+        match self.arrivals {
+            Arrivals::AllAtOnce => Duration::ZERO,
+            Arrivals::Continuous { per_minute } => Duration::from_secs_f64(-(1.0 - uniform).ln() * 60.0 / per_minute),
+        }
+    }
+}
+
+/// The value following `flag` in `args`, or else `var` from the environment,
+/// with the name it came from.
+fn setting(args: &[String], flag: &'static str, var: &'static str) -> Option<(&'static str, String)> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => Some((flag, args.get(i + 1).cloned().unwrap_or_default())),
+        None => std::env::var(var).ok().map(|value| (var, value)),
+    }
+}
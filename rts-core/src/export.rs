@@ -0,0 +1,218 @@
+// export.rs
+//
+// End-of-run metrics export for `--export <path>`. A run writes two tables
+// next to each other: the journey of every car that completed its trip
+// (`<stem>-cars.<ext>`) and the vehicle count of every lane at each snapshot
+// the simulation took (`<stem>-lanes.<ext>`). The extension of `path` picks
+// the format: `.parquet` writes Parquet, anything else CSV.
+//
+// Parquet needs the `parquet` feature; without it a Parquet export is
+// written as CSV instead, with a warning when the run starts.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// One completed trip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Journey {
+    /// The car that drove it.
+    pub car_id: u32,
+    /// Input lane the car entered the grid on.
+    pub entry_lane: u32,
+    /// Output lane the car left the grid on.
+    pub exit_lane: u32,
+    /// Seconds spent waiting at lights.
+    pub wait: f64,
+    /// Seconds spent driving.
+    pub drive: f64,
+    /// Seconds from entering the grid to leaving it.
+    pub total: f64,
+}
+
+/// The vehicle count of one lane at one point in the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneSample {
+    /// When the count was taken, in (simulated) Unix seconds.
+    pub timestamp: u64,
+    /// The lane counted.
+    pub lane_id: u32,
+    /// Vehicles on it.
+    pub count: u32,
+}
+
+/// One sample per lane of a snapshot of lane counts taken at `timestamp`,
+/// in lane order.
+pub fn lane_samples(timestamp: u64, counts: &HashMap<u32, u32>) -> Vec<LaneSample> {
+    let mut samples: Vec<LaneSample> =
+        counts.iter().map(|(&lane_id, &count)| LaneSample { timestamp, lane_id, count }).collect();
+    samples.sort_by_key(|sample| sample.lane_id);
+    samples
+}
+
+/// File format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// Apache Parquet.
+    Parquet,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// Where and how a run's metrics are exported.
+#[derive(Debug, Clone)]
+pub struct Export {
+    /// Path the two table paths are derived from.
+    base: PathBuf,
+    format: Format,
+}
+
+impl Export {
+    /// The export `--export <path>` asks for, if any.
+    pub fn from_args(args: &[String]) -> Option<Export> {
+        let path = args.iter().position(|arg| arg == "--export").and_then(|i| args.get(i + 1))?;
+        Some(Export::new(path))
+    }
+
+    /// An export to `path`, in the format its extension names.
+    pub fn new(path: impl AsRef<Path>) -> Export {
+        let base = path.as_ref().to_path_buf();
+        let parquet = base.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("parquet"));
+        let format = if !parquet {
+            Format::Csv
+        } else if cfg!(feature = "parquet") {
+            Format::Parquet
+        } else {
+            eprintln!("Parquet export requested but built without the parquet feature; writing CSV instead");
+            Format::Csv
+        };
+        Export { base, format }
+    }
+
+    /// Path of the table named `table`: `<stem>-<table>.<ext>` beside the
+    /// export path.
+    fn table_path(&self, table: &str) -> PathBuf {
+        let stem = self.base.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        self.base.with_file_name(format!("{}-{}.{}", stem, table, self.format.extension()))
+    }
+
+    /// Writes the journeys and lane samples, and returns the paths of the two
+    /// tables.
+    pub fn write(&self, journeys: &[Journey], lanes: &[LaneSample]) -> io::Result<(PathBuf, PathBuf)> {
+        let cars = Table {
+            name: "cars",
+            columns: vec![
+                ("car_id", Column::Int(journeys.iter().map(|j| j.car_id as i64).collect())),
+                ("entry_lane", Column::Int(journeys.iter().map(|j| j.entry_lane as i64).collect())),
+                ("exit_lane", Column::Int(journeys.iter().map(|j| j.exit_lane as i64).collect())),
+                ("wait", Column::Float(journeys.iter().map(|j| j.wait).collect())),
+                ("drive", Column::Float(journeys.iter().map(|j| j.drive).collect())),
+                ("total", Column::Float(journeys.iter().map(|j| j.total).collect())),
+            ],
+            rows: journeys.len(),
+        };
+        let series = Table {
+            name: "lanes",
+            columns: vec![
+                ("timestamp", Column::Int(lanes.iter().map(|s| s.timestamp as i64).collect())),
+                ("lane_id", Column::Int(lanes.iter().map(|s| s.lane_id as i64).collect())),
+                ("count", Column::Int(lanes.iter().map(|s| s.count as i64).collect())),
+            ],
+            rows: lanes.len(),
+        };
+        let cars_path = self.table_path(cars.name);
+        let lanes_path = self.table_path(series.name);
+        for (table, path) in [(&cars, &cars_path), (&series, &lanes_path)] {
+            match self.format {
+                Format::Csv => table.write_csv(path)?,
+                Format::Parquet => table.write_parquet(path)?,
+            }
+        }
+        Ok((cars_path, lanes_path))
+    }
+}
+
+/// Values of one column.
+enum Column {
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+}
+
+/// A table of equally long columns.
+struct Table {
+    /// Names the table's file and, in Parquet, its schema.
+    name: &'static str,
+    columns: Vec<(&'static str, Column)>,
+    rows: usize,
+}
+
+impl Table {
+    fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let header: Vec<&str> = self.columns.iter().map(|(name, _)| *name).collect();
+        writeln!(out, "{}", header.join(","))?;
+        for row in 0..self.rows {
+            let fields: Vec<String> = self
+                .columns
+                .iter()
+                .map(|(_, column)| match column {
+                    Column::Int(values) => values[row].to_string(),
+                    Column::Float(values) => format!("{:.3}", values[row]),
+                })
+                .collect();
+            writeln!(out, "{}", fields.join(","))?;
+        }
+        out.flush()
+    }
+
+    #[cfg(feature = "parquet")]
+    fn write_parquet(&self, path: &Path) -> io::Result<()> {
+        use std::sync::Arc;
+
+        use parquet::data_type::{DoubleType, Int64Type};
+        use parquet::file::properties::WriterProperties;
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+
+        let fields: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(name, column)| match column {
+                Column::Int(_) => format!("REQUIRED INT64 {};", name),
+                Column::Float(_) => format!("REQUIRED DOUBLE {};", name),
+            })
+            .collect();
+        let schema = parse_message_type(&format!("message {} {{ {} }}", self.name, fields.join(" "))).map_err(io::Error::other)?;
+        let properties = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(File::create(path)?, Arc::new(schema), properties).map_err(io::Error::other)?;
+        let mut row_group = writer.next_row_group().map_err(io::Error::other)?;
+        for (_, column) in &self.columns {
+            let Some(mut writer) = row_group.next_column().map_err(io::Error::other)? else { break };
+            match column {
+                Column::Int(values) => writer.typed::<Int64Type>().write_batch(values, None, None),
+                Column::Float(values) => writer.typed::<DoubleType>().write_batch(values, None, None),
+            }
+            .map_err(io::Error::other)?;
+            writer.close().map_err(io::Error::other)?;
+        }
+        row_group.close().map_err(io::Error::other)?;
+        writer.close().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    // Never called: `Export::new` only picks Parquet when the feature is on.
+    #[cfg(not(feature = "parquet"))]
+    fn write_parquet(&self, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without the parquet feature"))
+    }
+}
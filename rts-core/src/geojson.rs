@@ -0,0 +1,158 @@
+// geojson.rs
+//
+// The network and how busy it is as a GeoJSON FeatureCollection, to drop
+// into kepler.gl, QGIS or anything else that reads GeoJSON. Every junction
+// is a Point and every lane a LineString from the junction it leaves to the
+// one it reaches, drawn LANE_OFFSET to the right of the road's center line
+// so the two directions of a road stay apart. Lanes carry their vehicles,
+// their occupancy (vehicles over capacity) and the color of their light, as
+// far as the caller knows them; null where it does not.
+//
+// Junctions sit at their latitude and longitude where the network file has
+// them for every junction, as it does for OpenStreetMap imports (see `osm`).
+// Otherwise their grid rows and columns are laid out GRID_SPACING apart,
+// north-west of (0, 0). A boundary lane has no junction at its outer end; it
+// points away from the middle of the network, as long as the lane is.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::lanes::{Lane, LaneCategory};
+use crate::messages::LightColor;
+use crate::network_file::NetworkFile;
+
+/// Meters between two rows or columns of a network without coordinates.
+pub const GRID_SPACING: f64 = 300.0;
+
+/// Meters between a lane and the center line of its road.
+pub const LANE_OFFSET: f64 = 4.0;
+
+/// Meters per degree of latitude.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Longitude and latitude of every junction of `file`.
+pub fn positions(file: &NetworkFile) -> HashMap<u32, (f64, f64)> {
+    let located: Option<HashMap<u32, (f64, f64)>> =
+        file.intersections.iter().map(|inter| Some((inter.id, (inter.lon?, inter.lat?)))).collect();
+    located.unwrap_or_else(|| {
+        file.intersections
+            .iter()
+            .map(|inter| {
+                let degrees = |cells: i32| cells as f64 * GRID_SPACING / METERS_PER_DEGREE;
+                (inter.id, (degrees(inter.col), -degrees(inter.row)))
+            })
+            .collect()
+    })
+}
+
+/// Converts between longitude and latitude and meters east and north of a
+/// reference point; fine over the span of a city.
+#[derive(Debug, Clone, Copy)]
+struct Plane {
+    origin: (f64, f64),
+    /// Meters per degree of longitude at the origin.
+    lon_scale: f64,
+}
+
+impl Plane {
+    fn new(origin: (f64, f64)) -> Plane {
+        Plane { origin, lon_scale: METERS_PER_DEGREE * origin.1.to_radians().cos() }
+    }
+
+    fn meters(&self, (lon, lat): (f64, f64)) -> (f64, f64) {
+        ((lon - self.origin.0) * self.lon_scale, (lat - self.origin.1) * METERS_PER_DEGREE)
+    }
+
+    fn degrees(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (self.origin.0 + x / self.lon_scale, self.origin.1 + y / METERS_PER_DEGREE)
+    }
+}
+
+/// Name of a lane category as network files spell it.
+fn category_name(category: LaneCategory) -> &'static str {
+    match category {
+        LaneCategory::InputBoundary => "input_boundary",
+        LaneCategory::OutputBoundary => "output_boundary",
+        LaneCategory::Internal => "internal",
+    }
+}
+
+/// The network of `file` as a FeatureCollection, with the vehicles on each
+/// lane from `vehicles` and the color of its light from `light`.
+pub fn feature_collection(
+    file: &NetworkFile,
+    vehicles: impl Fn(u32) -> Option<u32>,
+    light: impl Fn(u32) -> Option<LightColor>,
+) -> Value {
+    let positions = positions(file);
+    let count = positions.len().max(1) as f64;
+    let center = positions.values().fold((0.0, 0.0), |(x, y), &(lon, lat)| (x + lon / count, y + lat / count));
+    let plane = Plane::new(center);
+    let meters: HashMap<u32, (f64, f64)> = positions.iter().map(|(&id, &position)| (id, plane.meters(position))).collect();
+
+    let mut features = Vec::new();
+    for inter in &file.intersections {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "Point", "coordinates": [positions[&inter.id].0, positions[&inter.id].1]},
+            "properties": {
+                "kind": "junction",
+                "id": inter.id,
+                "row": inter.row,
+                "col": inter.col,
+                "signals": inter.signals.unwrap_or(true),
+            },
+        }));
+    }
+    for lane in file.lanes() {
+        let Some((start, end)) = ends(&lane, &meters) else { continue };
+        let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+        let norm = dx.hypot(dy).max(f64::EPSILON);
+        // To the right of the direction of travel.
+        let (ox, oy) = (dy / norm * LANE_OFFSET, -dx / norm * LANE_OFFSET);
+        let coordinates: Vec<[f64; 2]> = [start, end]
+            .iter()
+            .map(|&(x, y)| {
+                let (lon, lat) = plane.degrees((x + ox, y + oy));
+                [lon, lat]
+            })
+            .collect();
+        let on_lane = vehicles(lane.id);
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": coordinates},
+            "properties": {
+                "kind": "lane",
+                "id": lane.id,
+                "from": lane.start_intersection,
+                "to": lane.end_intersection,
+                "category": category_name(lane.category),
+                "length": lane.length,
+                "capacity": lane.capacity,
+                "parallel_count": lane.parallel_count,
+                "vehicles": on_lane,
+                "occupancy": on_lane.map(|count| count as f64 / lane.capacity.max(1) as f64),
+                "light": light(lane.id),
+            },
+        }));
+    }
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+/// Where `lane` starts and ends, in meters from the middle of the network;
+/// the outside end of a boundary lane points away from the middle. None if
+/// it touches no known junction.
+fn ends(lane: &Lane, meters: &HashMap<u32, (f64, f64)>) -> Option<((f64, f64), (f64, f64))> {
+    let outside = |junction: (f64, f64)| {
+        let norm = junction.0.hypot(junction.1);
+        let (ux, uy) = if norm > 0.0 { (junction.0 / norm, junction.1 / norm) } else { (1.0, 0.0) };
+        (junction.0 + ux * lane.length, junction.1 + uy * lane.length)
+    };
+    match (meters.get(&lane.start_intersection), meters.get(&lane.end_intersection)) {
+        (Some(&start), Some(&end)) => Some((start, end)),
+        (None, Some(&end)) => Some((outside(end), end)),
+        (Some(&start), None) => Some((start, outside(start))),
+        (None, None) => None,
+    }
+}
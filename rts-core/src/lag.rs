@@ -0,0 +1,59 @@
+// lag.rs
+//
+// How far behind a log consumer is. Every event carries the time it was
+// sent, so a consumer comparing it to its own clock sees how long the event
+// waited in the channel or queue. LagWatch raises a warning when that wait
+// goes over a limit and stays quiet until the consumer has caught up again,
+// so a long backlog produces one warning rather than one per event.
+
+/// Lag limit used when RTS_MONITOR_MAX_LAG is unset, in seconds.
+pub const DEFAULT_MAX_LAG_SECS: u64 = 10;
+
+/// Watches event timestamps for a consumer falling behind.
+#[derive(Debug, Clone)]
+pub struct LagWatch {
+    max_lag_secs: u64,
+    /// True between a warning and the consumer catching up.
+    behind: bool,
+}
+
+impl LagWatch {
+    /// A watch warning once events are more than `max_lag_secs` old.
+    pub fn new(max_lag_secs: u64) -> LagWatch {
+        LagWatch { max_lag_secs, behind: false }
+    }
+
+    /// A watch with the limit from RTS_MONITOR_MAX_LAG (the default, with a
+    /// warning, if it is invalid).
+    pub fn from_env() -> LagWatch {
+        let max_lag_secs = match std::env::var("RTS_MONITOR_MAX_LAG") {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                eprintln!("Ignoring RTS_MONITOR_MAX_LAG: invalid number of seconds '{}'", value.trim());
+                DEFAULT_MAX_LAG_SECS
+            }),
+            Err(_) => DEFAULT_MAX_LAG_SECS,
+        };
+        LagWatch::new(max_lag_secs)
+    }
+
+    /// The limit in seconds.
+    pub fn max_lag_secs(&self) -> u64 {
+        self.max_lag_secs
+    }
+
+    /// Checks an event sent at `timestamp` and handled at `now`, both in
+    /// seconds. Returns the lag if it has just gone over the limit; None
+    /// while within it or already warned about.
+    pub fn check(&mut self, timestamp: u64, now: u64) -> Option<u64> {
+        let lag = now.saturating_sub(timestamp);
+        if lag <= self.max_lag_secs {
+            self.behind = false;
+            return None;
+        }
+        if self.behind {
+            return None;
+        }
+        self.behind = true;
+        Some(lag)
+    }
+}
@@ -0,0 +1,215 @@
+// lane_queue.rs
+//
+// Arrival order at the lights. Every car that enters a lane joins the back of
+// that lane's queue, and only the car at the front may pass the light; when it
+// does, it leaves the queue and the next car moves up. Without it, every car
+// waiting on a lane would race for the junction when the light turns green,
+// and a car that arrived last could go first. A car that leaves a lane some
+// other way (a re-route, or the run ending) must leave its queue too, or the
+// cars behind it would wait forever.
+//
+// When a light turns amber, the car at the front of its queue is already too
+// close to stop and counts as in the junction: it may clear it whatever the
+// light shows next, while every car behind it stops (see `may_pass`).
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::messages::LightColor;
+
+/// Cars waiting at each lane's light, in arrival order.
+#[derive(Debug, Clone, Default)]
+pub struct LaneQueues {
+    queues: HashMap<u32, VecDeque<u32>>,
+    /// Car already in the junction on each lane: the one at the front when
+    /// the lane's light turned amber.
+    clearing: HashMap<u32, u32>,
+}
+
+impl LaneQueues {
+    /// Puts `car_id` at the back of `lane_id`'s queue, unless it is queued there already.
+    pub fn join(&mut self, lane_id: u32, car_id: u32) {
+        let queue = self.queues.entry(lane_id).or_default();
+        if !queue.contains(&car_id) {
+            queue.push_back(car_id);
+        }
+    }
+
+    /// True if `car_id` is at the front of `lane_id`'s queue.
+    pub fn is_first(&self, lane_id: u32, car_id: u32) -> bool {
+        self.queues.get(&lane_id).and_then(|queue| queue.front()) == Some(&car_id)
+    }
+
+    /// True if `car_id` may pass `lane_id`'s light showing `color`: it is
+    /// green and the car is first in the queue, or the car is already in the
+    /// junction, having been first when the light turned amber.
+    pub fn may_pass(&self, lane_id: u32, car_id: u32, color: LightColor) -> bool {
+        match color {
+            LightColor::Green => self.is_first(lane_id, car_id),
+            LightColor::Amber | LightColor::Red => self.clearing.get(&lane_id) == Some(&car_id),
+        }
+    }
+
+    /// Records that `lane_id`'s light turned amber: the car at the front of
+    /// its queue, if any, is already in the junction.
+    pub fn light_turned_amber(&mut self, lane_id: u32) {
+        if let Some(&car_id) = self.queues.get(&lane_id).and_then(|queue| queue.front()) {
+            self.clearing.insert(lane_id, car_id);
+        }
+    }
+
+    /// Takes `car_id` out of `lane_id`'s queue, wherever it is. Returns true
+    /// if it was at the front, so another car may now be first.
+    pub fn leave(&mut self, lane_id: u32, car_id: u32) -> bool {
+        let Some(queue) = self.queues.get_mut(&lane_id) else {
+            return false;
+        };
+        let Some(position) = queue.iter().position(|&id| id == car_id) else {
+            return false;
+        };
+        queue.remove(position);
+        if self.clearing.get(&lane_id) == Some(&car_id) {
+            self.clearing.remove(&lane_id);
+        }
+        if queue.is_empty() {
+            self.queues.remove(&lane_id);
+        }
+        position == 0
+    }
+
+    /// Number of cars queued on `lane_id`.
+    pub fn len(&self, lane_id: u32) -> usize {
+        self.queues.get(&lane_id).map_or(0, VecDeque::len)
+    }
+
+    /// True if no car is queued on any lane.
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Empties every queue.
+    pub fn clear(&mut self) {
+        self.queues.clear();
+        self.clearing.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::stats::{mean, percentile};
+
+    /// Lets cars through `lane_id`'s green light one at a time and returns
+    /// the order they left in.
+    fn drain_on_green(queues: &mut LaneQueues, lane_id: u32, cars: &[u32]) -> Vec<u32> {
+        let mut order = Vec::new();
+        while !queues.is_empty() {
+            let car_id = *cars
+                .iter()
+                .find(|&&car_id| queues.may_pass(lane_id, car_id, LightColor::Green))
+                .expect("someone may pass");
+            assert!(queues.leave(lane_id, car_id));
+            order.push(car_id);
+        }
+        order
+    }
+
+    #[test]
+    fn cars_leave_in_the_order_they_arrived() {
+        let mut queues = LaneQueues::default();
+        for car_id in [7, 3, 9, 1] {
+            queues.join(1000, car_id);
+        }
+        // Joining twice keeps the first place.
+        queues.join(1000, 7);
+        queues.join(1000, 3);
+        assert_eq!(queues.len(1000), 4);
+        assert_eq!(drain_on_green(&mut queues, 1000, &[1, 3, 7, 9]), [7, 3, 9, 1]);
+    }
+
+    #[test]
+    fn lanes_queue_independently() {
+        let mut queues = LaneQueues::default();
+        queues.join(1000, 1);
+        queues.join(1001, 2);
+        queues.join(1000, 3);
+        assert!(queues.is_first(1000, 1) && queues.is_first(1001, 2));
+        assert!(!queues.may_pass(1000, 3, LightColor::Green));
+        assert!(!queues.may_pass(1000, 1, LightColor::Red));
+    }
+
+    #[test]
+    fn car_leaving_mid_queue_keeps_the_order_of_the_rest() {
+        let mut queues = LaneQueues::default();
+        for car_id in 1..=4 {
+            queues.join(1000, car_id);
+        }
+        // Car 2 re-routes away: nobody moves up to the front.
+        assert!(!queues.leave(1000, 2));
+        assert!(!queues.leave(1000, 9));
+        assert_eq!(drain_on_green(&mut queues, 1000, &[1, 2, 3, 4]), [1, 3, 4]);
+    }
+
+    #[test]
+    fn only_the_car_in_the_junction_clears_on_amber() {
+        let mut queues = LaneQueues::default();
+        for car_id in 1..=3 {
+            queues.join(1000, car_id);
+        }
+        queues.light_turned_amber(1000);
+        assert!(queues.may_pass(1000, 1, LightColor::Amber));
+        assert!(queues.may_pass(1000, 1, LightColor::Red));
+        assert!(!queues.may_pass(1000, 2, LightColor::Amber));
+        assert!(queues.leave(1000, 1));
+        // The next car stops for the red.
+        assert!(!queues.may_pass(1000, 2, LightColor::Red));
+        assert!(queues.may_pass(1000, 2, LightColor::Green));
+    }
+
+    /// Waits at a light cycling 30s green and 30s red over an hour, one car
+    /// passing every 2s of green. Arrivals are drawn from `seed`; `pick`
+    /// chooses which of the waiting cars (in arrival order) goes next.
+    fn hour_of_waits(seed: u64, mut pick: impl FnMut(&[(u32, u32)]) -> usize) -> Vec<f64> {
+        let mut arrivals = StdRng::seed_from_u64(seed);
+        let mut waiting: Vec<(u32, u32)> = Vec::new();
+        let mut waits = Vec::new();
+        for (second, car_id) in (0..3600).zip(1..) {
+            if arrivals.random_bool(0.22) {
+                waiting.push((car_id, second));
+            }
+            if second % 60 < 30 && second % 2 == 0 && !waiting.is_empty() {
+                let (_, arrived) = waiting.remove(pick(&waiting));
+                waits.push(f64::from(second - arrived));
+            }
+        }
+        waits.sort_by(f64::total_cmp);
+        waits
+    }
+
+    #[test]
+    fn queue_keeps_p95_wait_below_a_free_for_all() {
+        // With the queue, the car at its front goes.
+        let mut queues = LaneQueues::default();
+        let queued = hour_of_waits(5, |waiting| {
+            for &(car_id, _) in waiting {
+                queues.join(1000, car_id);
+            }
+            let next = waiting.iter().position(|&(car_id, _)| queues.may_pass(1000, car_id, LightColor::Green)).unwrap();
+            queues.leave(1000, waiting[next].0);
+            next
+        });
+        // Without it, whichever waiting car wins the race.
+        let mut race = StdRng::seed_from_u64(6);
+        let raced = hour_of_waits(5, |waiting| race.random_range(0..waiting.len()));
+
+        // The same cars get through, with the same mean wait, but nobody is
+        // overtaken indefinitely.
+        assert_eq!(queued.len(), raced.len());
+        assert!((mean(&queued) - mean(&raced)).abs() < 1e-9);
+        let (queued_p95, raced_p95) = (percentile(&queued, 95.0), percentile(&raced, 95.0));
+        assert!(queued_p95 < raced_p95, "p95 {:.0}s queued against {:.0}s raced", queued_p95, raced_p95);
+        assert!(queued.last() < raced.last());
+    }
+}
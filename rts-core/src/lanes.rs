@@ -0,0 +1,98 @@
+// lanes.rs
+//
+// This file provides a LaneCategory enum, a Lane struct, and a function
+// load_lanes() that returns the lanes of the network file (see network_file);
+// the built-in grid has 52 (18 boundary + 34 internal). Each lane is tagged
+// as InputBoundary, OutputBoundary, or Internal.
+//
+// The Direction field has been removed. Instead, each lane now has two fields:
+//   - start_intersection: for boundary lanes, this is the junction on the grid (for output lanes)
+//     or 0 (for input lanes coming from outside).
+//   - end_intersection: for boundary lanes, this is the junction on the grid (for input lanes)
+//     or 0 (for output lanes exiting the grid).
+// For internal lanes, both start and end intersections are specified based on the previous direction.
+//
+// A lane id stands for one direction of a road. A road has one to
+// MAX_PARALLEL_LANES parallel lanes in that direction (parallel_count); the
+// longest roads of the built-in grid have two. They share the lane id and its
+// light, and capacity covers them all.
+//
+// A lane can be reserved for one movement through the junction it arrives at
+// (movement); otherwise the movement a car makes follows from the geometry of
+// the lane and the one it turns into (see phase_plan::movement).
+
+use serde::Deserialize;
+
+/// Where a lane sits in the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaneCategory {
+    /// Enters the grid from outside; vehicles start on these.
+    InputBoundary,
+    /// Leaves the grid; vehicles finish on these.
+    OutputBoundary,
+    /// Connects two junctions of the grid.
+    Internal,
+}
+
+/// What a vehicle does at the junction at the end of its lane, with traffic
+/// driving on the right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Movement {
+    /// Carries on in the same direction.
+    Straight,
+    /// Turns left, across oncoming traffic (U-turns count as left turns).
+    LeftTurn,
+    /// Turns right, merging into the lane it turns into.
+    RightTurn,
+}
+
+/// A one-way lane between two intersections.
+#[derive(Debug, Clone)]
+pub struct Lane {
+    /// Unique lane id, starting at 1000.
+    pub id: u32,
+    /// Intersection the lane leaves, or 0 for an input boundary lane.
+    pub start_intersection: u32,
+    /// Intersection the lane arrives at, or 0 for an output boundary lane.
+    pub end_intersection: u32,
+    /// Length in meters.
+    pub length: f64,
+    /// Maximum number of vehicles the lane can hold at once, across all of
+    /// its parallel lanes.
+    pub capacity: u32,
+    /// Number of side-by-side lanes the road has in this direction, at
+    /// least 1. They share one light and one lane id.
+    pub parallel_count: u32,
+    /// Movement the lane is reserved for at its end intersection, or None if
+    /// vehicles on it may go any way.
+    pub movement: Option<Movement>,
+    /// Boundary or internal.
+    pub category: LaneCategory,
+}
+
+impl Lane {
+    /// Vehicles one of the lane's parallel lanes can hold (at least one).
+    pub fn sub_lane_capacity(&self) -> u32 {
+        (self.capacity / self.parallel_count.max(1)).max(1)
+    }
+}
+
+/// Most parallel lanes a road has in one direction.
+pub const MAX_PARALLEL_LANES: u32 = 3;
+
+/// Road space (in meters) occupied by one queued vehicle.
+pub const METERS_PER_VEHICLE: f64 = 10.0;
+
+/// Derives a lane's vehicle capacity from its length (at least one vehicle).
+pub fn lane_capacity(length: f64) -> u32 {
+    ((length / METERS_PER_VEHICLE).floor() as u32).max(1)
+}
+
+/// The lanes of the network file (see `network_file`), in file order. The
+/// built-in grid has 52: output boundary lanes first, then input boundary
+/// lanes, then internal lanes, with ids in that order.
+pub fn load_lanes() -> Vec<Lane> {
+    crate::network_file::loaded().lanes()
+}
@@ -0,0 +1,87 @@
+// latency.rs
+//
+// How long messages take to get from publisher to consumer. Log event
+// timestamps are whole seconds of simulated time, too coarse and on the wrong
+// clock for that, so this goes by the envelope instead: a message is stamped
+// with the wall-clock millisecond it was published (`Envelope::emitted_at_ms`)
+// and again when a consumer takes it, and the difference is its latency.
+// Publisher and consumer clocks are assumed to agree, as they do on one host;
+// across hosts the latency includes their offset.
+//
+// LatencySamples keeps the last LATENCY_WINDOW latencies of a feed, and its
+// summary gives their nearest-rank percentiles.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::stats::percentile;
+
+/// Latencies a LatencySamples keeps for its percentiles.
+pub const LATENCY_WINDOW: usize = 4096;
+
+/// Wall-clock time now, in milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Latencies of the messages of one feed.
+#[derive(Debug, Clone, Default)]
+pub struct LatencySamples {
+    /// The last LATENCY_WINDOW latencies, in milliseconds.
+    recent: VecDeque<u64>,
+    /// Messages ever recorded.
+    count: u64,
+}
+
+impl LatencySamples {
+    /// Records a message published at `emitted_at_ms` and taken at
+    /// `received_at_ms`. One that seems to arrive before it was sent, from
+    /// clocks that disagree, counts as 0.
+    pub fn record(&mut self, emitted_at_ms: u64, received_at_ms: u64) {
+        if self.recent.len() == LATENCY_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(received_at_ms.saturating_sub(emitted_at_ms));
+        self.count += 1;
+    }
+
+    /// Percentiles of the recent latencies.
+    pub fn summary(&self) -> LatencySummary {
+        let mut sorted: Vec<f64> = self.recent.iter().map(|&ms| ms as f64).collect();
+        sorted.sort_by(f64::total_cmp);
+        LatencySummary {
+            count: self.count,
+            p50_ms: percentile(&sorted, 50.0),
+            p95_ms: percentile(&sorted, 95.0),
+            p99_ms: percentile(&sorted, 99.0),
+            max_ms: sorted.last().copied().unwrap_or(0.0),
+        }
+    }
+}
+
+/// Percentiles of a feed's recent latencies, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencySummary {
+    /// Messages ever recorded, not only the recent ones.
+    pub count: u64,
+    /// Median latency.
+    pub p50_ms: f64,
+    /// 95th percentile.
+    pub p95_ms: f64,
+    /// 99th percentile.
+    pub p99_ms: f64,
+    /// Highest recent latency.
+    pub max_ms: f64,
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages, p50 {:.0} ms, p95 {:.0} ms, p99 {:.0} ms, max {:.0} ms",
+            self.count, self.p50_ms, self.p95_ms, self.p99_ms, self.max_ms
+        )
+    }
+}
@@ -0,0 +1,73 @@
+//! Code shared by every deployment of the traffic simulation: the lane
+//! network, loaded from a JSON description, imported from SUMO or
+//! OpenStreetMap or built in code, and its grid layout, how many vehicles a
+//! run spawns, when, and where they enter and leave, how cars follow each
+//! other along a lane,
+//! shortest-path routing over lanes, the per-junction signal phase plans and
+//! their Webster timing, right turns on red, the order cars pass the lights
+//! in, stall detection for junction controllers, run seeds, trip time
+//! statistics, fuel use and emissions, message latency, the end-of-run
+//! metrics export, GeoJSON maps of the network, and the messages the
+//! components exchange, how lane counts are batched into them and how often
+//! snapshots of them go out, all on a simulated clock.
+//!
+//! Transport stays in the deployments (mpsc in CK, ZeroMQ in CY, lapin in
+//! RabbitMQ and Berry); everything here is plain data and pure functions.
+#![warn(missing_docs)]
+
+/// Adaptive interval between lane snapshots.
+pub mod cadence;
+/// How cars accelerate and brake behind whatever is ahead of them on a lane.
+pub mod car_following;
+/// Simulated time, running at a multiple of real time.
+pub mod clock;
+/// Number of vehicles a run spawns and their arrival times.
+pub mod demand;
+/// Fuel use and exhaust emissions estimated from the cars' speed profiles.
+pub mod emissions;
+/// The network and its live state as GeoJSON, for map tools.
+pub mod geojson;
+/// End-of-run export of car journeys and lane counts to CSV or Parquet.
+pub mod export;
+/// How far behind a log consumer is.
+pub mod lag;
+/// Arrival order of the cars waiting at each lane's light.
+pub mod lane_queue;
+/// The lanes of the road network.
+pub mod lanes;
+/// How long messages take from publisher to consumer.
+pub mod latency;
+/// Messages exchanged between the components of a deployment.
+pub mod messages;
+/// Intersections and their grid positions, derived from the lanes.
+pub mod network;
+/// Road networks built in code, for tests and examples.
+pub mod network_builder;
+/// The JSON description the network is loaded from.
+pub mod network_file;
+/// Road networks imported from OpenStreetMap extracts.
+pub mod osm;
+/// Lane count updates batched on their way out, under backpressure.
+pub mod outbox;
+/// Order in which a junction serves its phases.
+pub mod phase_order;
+/// Conflict-free signal phases of each junction.
+pub mod phase_plan;
+/// Car progress along a trip and along each lane, and estimated car positions.
+pub mod progress;
+/// Right turns on a red light.
+pub mod right_on_red;
+/// Shortest-path routing over lanes.
+pub mod routing;
+/// Run seeds and the per-car and per-junction generator seeds derived from them.
+pub mod seed;
+/// Trip time statistics for end-of-run reports.
+pub mod stats;
+/// Road networks imported from SUMO .net.xml files.
+pub mod sumo;
+/// Entry and exit lanes of the cars' trips.
+pub mod trips;
+/// Junction controllers that have stopped changing phase.
+pub mod watchdog;
+/// Signal timing plans by Webster's method.
+pub mod webster;
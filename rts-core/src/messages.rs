@@ -0,0 +1,492 @@
+// messages.rs
+//
+// Messages exchanged between the simulation, flow analyzer, traffic light
+// controller and system monitoring. The networked deployments serialize them
+// as JSON, so field names are part of the wire format.
+//
+// Log events share one envelope, but what a typed event can carry differs per
+// deployment (only some run heartbeats, vehicle mixes or crossings), so each
+// deployment supplies its own kind type and aliases `LogEvent<ItsKind>`.
+// Every event carries a Level; events from senders that predate levels are
+// INFO.
+//
+// On the wire every published message travels in an Envelope that names its
+// schema version, sender and place in the sender's feed. `open` takes
+// messages from senders that predate envelopes as they are, and refuses
+// versions newer than SCHEMA_VERSION, so components can be upgraded one at
+// a time.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Severity of a log event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Level {
+    /// Normal operation.
+    #[default]
+    Info,
+    /// Something went wrong that the run recovers from, e.g. a car that
+    /// found no route or a component that missed heartbeats.
+    Warn,
+    /// A component failed.
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        })
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    /// Parses `info`, `warn` (or `warning`) and `error`, in any case.
+    fn from_str(value: &str) -> Result<Level, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "info" => Ok(Level::Info),
+            "warn" | "warning" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!("invalid log level '{}'", value.trim())),
+        }
+    }
+}
+
+/// A log event for system monitoring. `K` is the deployment's typed payload;
+/// events sent without a `kind` (the old `{source, message, timestamp}`
+/// shape) get its default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEvent<K> {
+    /// Component that raised the event, e.g. `Car-12` or `Junction-6`.
+    pub source: String,
+    /// Free text for untyped events, or extra context for a typed one.
+    /// Empty when the typed event speaks for itself.
+    #[serde(default)]
+    pub message: String,
+    /// The sender's `SimClock::now_secs()`.
+    pub timestamp: u64,
+    /// Severity.
+    #[serde(default)]
+    pub level: Level,
+    /// Structured payload.
+    #[serde(default)]
+    pub kind: K,
+}
+
+impl<K> LogEvent<K> {
+    /// A typed INFO event without extra text.
+    pub fn new(source: impl Into<String>, timestamp: u64, kind: K) -> Self {
+        LogEvent { source: source.into(), message: String::new(), timestamp, level: Level::Info, kind }
+    }
+
+    /// The event at `level` instead.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+impl<K: fmt::Display> LogEvent<K> {
+    /// Text shown for the event: its message if it has one, otherwise the
+    /// rendering of its typed payload.
+    pub fn describe(&self) -> String {
+        if self.message.is_empty() {
+            self.kind.to_string()
+        } else {
+            self.message.clone()
+        }
+    }
+}
+
+/// A map keyed by lane id. JSON keys are strings, and inside an untagged
+/// enum serde no longer reads them as numbers, so the ids are parsed here.
+fn lane_map<'de, D, V>(deserializer: D) -> Result<HashMap<u32, V>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(lane_id, value)| match lane_id.parse() {
+            Ok(lane_id) => Ok((lane_id, value)),
+            Err(_) => Err(serde::de::Error::custom(format!("invalid lane id '{}'", lane_id))),
+        })
+        .collect()
+}
+
+/// New vehicle count of a single lane.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficUpdate {
+    /// Lane whose count changed.
+    pub lane_id: u32,
+    /// Vehicles now on the lane.
+    pub vehicle_count: u32,
+    /// Simulated time of the change, in seconds.
+    pub timestamp: u64,
+}
+
+/// Vehicle count of every lane, published periodically so consumers can
+/// recover from missed incremental updates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficSnapshot {
+    /// Lane id to vehicle count.
+    #[serde(deserialize_with = "lane_map")]
+    pub lanes: HashMap<u32, u32>,
+    /// Simulated time of the snapshot, in seconds.
+    pub timestamp: u64,
+}
+
+/// Vehicle count of the lanes that changed since the publisher's last
+/// message, batched so a slow consumer gets fewer, larger messages; see
+/// `outbox`. Lanes left out keep their count.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficChanges {
+    /// Lane id to vehicle count, for the lanes that changed.
+    #[serde(deserialize_with = "lane_map")]
+    pub changed: HashMap<u32, u32>,
+    /// Simulated time of the batch, in seconds.
+    pub timestamp: u64,
+}
+
+/// Any message shape a simulation publishes on its lane-count feed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SimulationUpdate {
+    /// Every lane at once.
+    Snapshot(TrafficSnapshot),
+    /// The lanes that changed.
+    Changes(TrafficChanges),
+    /// One lane that changed.
+    Lane(TrafficUpdate),
+}
+
+/// Green time the flow analyzer recommends for a congested lane.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recommendation {
+    /// Lane that should get the extra green.
+    pub lane_id: u32,
+    /// Seconds to hold the lane's phase green.
+    pub new_green_time: u32,
+    /// Simulated time of the recommendation, in seconds.
+    pub timestamp: u64,
+}
+
+/// Lanes the simulation should route around until `expires_at`, sent by the
+/// flow analyzer when it finds a gridlock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RerouteAdvisory {
+    /// Sorted lane ids of the gridlocked cluster.
+    pub lanes: Vec<u32>,
+    /// Simulated time the advisory lapses, in seconds.
+    pub expires_at: u64,
+}
+
+/// Takes an internal lane out of routing, or returns it, e.g. for roadworks.
+/// New routes never use a closed lane; cars already on it drive it to the end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LaneClosure {
+    /// The lane closed or reopened.
+    pub lane_id: u32,
+    /// True to close the lane, false to reopen it.
+    pub closed: bool,
+    /// Simulated time of the change, in seconds.
+    pub timestamp: u64,
+}
+
+/// Sent by the simulation when a car breaks down on a lane, and again when
+/// it is cleared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Incident {
+    /// The broken-down car.
+    pub car_id: u32,
+    /// Lane the car is blocking.
+    pub lane_id: u32,
+    /// False when the car breaks down, true once it is cleared.
+    pub cleared: bool,
+    /// Simulated time of the breakdown or clearance, in seconds.
+    pub timestamp: u64,
+}
+
+/// Sent back by the traffic light controller when a junction actually serves
+/// a recommendation, so the flow analyzer can check whether it helped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecommendationApplied {
+    /// Lane the recommendation was made for.
+    pub lane_id: u32,
+    /// Green time the junction ended up holding, in seconds.
+    pub applied_green_time: u32,
+    /// Simulated time the recommendation was applied, in seconds.
+    pub timestamp: u64,
+}
+
+/// Sent by the simulation when an emergency vehicle approaches a junction,
+/// asking the traffic light controller to turn its lane green.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreemptionRequest {
+    /// The emergency vehicle.
+    pub car_id: u32,
+    /// Lane the vehicle is on, whose light it needs green.
+    pub lane_id: u32,
+    /// Junction the lane leads into.
+    pub junction: u32,
+    /// Simulated time of the request, in seconds.
+    pub timestamp: u64,
+}
+
+/// Instructions to every component of a networked deployment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Control {
+    /// The run is over or was interrupted: stop once pending work is flushed.
+    Shutdown {
+        /// Why, for the components' output.
+        reason: String,
+        /// Simulated time of the request, in seconds.
+        timestamp: u64,
+    },
+}
+
+/// Color of a lane's light. Serialized as `Green`, `Amber` or `Red`; parsed
+/// without regard to case, and any other value is an error rather than red.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LightColor {
+    /// Vehicles must stop.
+    Red,
+    /// Vehicles must stop unless already in the junction, which they may
+    /// clear (see `lane_queue::LaneQueues::may_pass`).
+    Amber,
+    /// Vehicles may proceed.
+    Green,
+}
+
+impl<'de> Deserialize<'de> for LightColor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        if name.eq_ignore_ascii_case("green") {
+            Ok(LightColor::Green)
+        } else if name.eq_ignore_ascii_case("amber") {
+            Ok(LightColor::Amber)
+        } else if name.eq_ignore_ascii_case("red") {
+            Ok(LightColor::Red)
+        } else {
+            Err(serde::de::Error::unknown_variant(&name, &["Green", "Amber", "Red"]))
+        }
+    }
+}
+
+/// Color of one lane's light, as published by the traffic light controller
+/// whenever the lane changes color.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LightStatus {
+    /// Lane the light controls.
+    pub lane_id: u32,
+    /// The lane's new color.
+    pub status: LightColor,
+}
+
+/// Color of every light, or of every light of one junction, published
+/// periodically so consumers that start late or miss a change still learn
+/// every lane's color.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LightSnapshot {
+    /// Lane id to light color.
+    #[serde(deserialize_with = "lane_map")]
+    pub lights: HashMap<u32, LightColor>,
+    /// Simulated time of the snapshot, in seconds.
+    pub timestamp: u64,
+}
+
+/// Either message shape the traffic light controller publishes on its
+/// light status feed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LightUpdate {
+    /// Every light at once.
+    Snapshot(LightSnapshot),
+    /// One lane that changed.
+    Lane(LightStatus),
+}
+
+/// Where a car is along the lane it is on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CarPosition {
+    /// The car.
+    pub car_id: u32,
+    /// Lane the car is on.
+    pub lane_id: u32,
+    /// Place of the lane in the car's trip, 0 being its entry lane.
+    pub route_index: usize,
+    /// Share of the lane behind the car, from 0.0 at its start to 1.0 at
+    /// its stop line.
+    pub progress: f64,
+    /// The car's speed, in m/s; 0.0 while it stands in a queue.
+    pub speed: f64,
+    /// Whether the car is stopped at the stop line, waiting to go on.
+    pub waiting: bool,
+}
+
+/// Position of every car on the grid, published by the simulation on every
+/// tick of its position model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CarPositions {
+    /// One position per car, by car id.
+    pub positions: Vec<CarPosition>,
+    /// Simulated time of the positions, in seconds.
+    pub timestamp: u64,
+}
+
+/// Version of the Envelope and of the messages inside it. Raise it when a
+/// change would make older consumers misread a message; they then drop it
+/// instead, and report the version they did not know.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// What every networked component wraps a published message in. Sequence
+/// numbers count up from 1 per source and feed, so a consumer can tell a
+/// late or missing message from the gap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    /// SCHEMA_VERSION of the sender.
+    pub schema_version: u32,
+    /// Number of the message on its feed, from 1; 0 for a message sent
+    /// without an envelope.
+    pub sequence: u64,
+    /// Component that sent the message, e.g. `simulation`.
+    pub source: String,
+    /// Wall-clock time it was sent, in milliseconds since the Unix epoch.
+    pub emitted_at_ms: u64,
+    /// The message itself.
+    pub payload: T,
+}
+
+impl<T> Envelope<T> {
+    /// `payload` as message number `sequence` of `source`, sent now.
+    pub fn new(source: impl Into<String>, sequence: u64, payload: T) -> Self {
+        let emitted_at_ms = crate::latency::unix_millis();
+        Envelope { schema_version: SCHEMA_VERSION, sequence, source: source.into(), emitted_at_ms, payload }
+    }
+}
+
+/// Why a received message could not be opened.
+#[derive(Debug)]
+pub enum EnvelopeError {
+    /// Not JSON, or not the message expected.
+    Malformed(serde_json::Error),
+    /// Sent by a newer component, whose messages this one may misread.
+    UnknownVersion {
+        /// The envelope's schema_version.
+        version: u32,
+        /// The envelope's source.
+        source: String,
+    },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed(e) => write!(f, "{}", e),
+            EnvelopeError::UnknownVersion { version, source } => write!(
+                f,
+                "unknown schema version {} from '{}' (this component knows up to {})",
+                version, source, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// The envelope fields alone, to check the version before the payload.
+#[derive(Deserialize)]
+struct Header {
+    schema_version: u32,
+    #[serde(default)]
+    source: String,
+}
+
+/// Opens a received message. A message sent without an envelope, by a
+/// component that predates them, is taken as it is, with sequence 0 and no
+/// source; one from a newer SCHEMA_VERSION is an error, whatever it holds.
+pub fn open<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<Envelope<T>, EnvelopeError> {
+    let value: serde_json::Value = serde_json::from_slice(bytes).map_err(EnvelopeError::Malformed)?;
+    let sealed = value.get("schema_version").is_some() && value.get("payload").is_some();
+    if !sealed {
+        let payload = serde_json::from_value(value).map_err(EnvelopeError::Malformed)?;
+        return Ok(Envelope { schema_version: 0, sequence: 0, source: String::new(), emitted_at_ms: 0, payload });
+    }
+    let header = Header::deserialize(&value).map_err(EnvelopeError::Malformed)?;
+    if header.schema_version == 0 || header.schema_version > SCHEMA_VERSION {
+        return Err(EnvelopeError::UnknownVersion { version: header.schema_version, source: header.source });
+    }
+    serde_json::from_value(value).map_err(EnvelopeError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(update: &SimulationUpdate) -> SimulationUpdate {
+        serde_json::from_str(&serde_json::to_string(update).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let snapshot = SimulationUpdate::Snapshot(TrafficSnapshot { lanes: HashMap::from([(1001, 3), (1002, 0)]), timestamp: 7 });
+        match round_trip(&snapshot) {
+            SimulationUpdate::Snapshot(TrafficSnapshot { lanes, timestamp }) => {
+                assert_eq!(lanes, HashMap::from([(1001, 3), (1002, 0)]));
+                assert_eq!(timestamp, 7);
+            }
+            other => panic!("read back as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn changes_round_trip() {
+        let changes = SimulationUpdate::Changes(TrafficChanges { changed: HashMap::from([(1005, 2)]), timestamp: 9 });
+        match round_trip(&changes) {
+            SimulationUpdate::Changes(TrafficChanges { changed, timestamp }) => {
+                assert_eq!(changed, HashMap::from([(1005, 2)]));
+                assert_eq!(timestamp, 9);
+            }
+            other => panic!("read back as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_lane_updates_round_trip() {
+        let update = SimulationUpdate::Lane(TrafficUpdate { lane_id: 1010, vehicle_count: 4, timestamp: 11 });
+        match round_trip(&update) {
+            SimulationUpdate::Lane(TrafficUpdate { lane_id, vehicle_count, timestamp }) => {
+                assert_eq!((lane_id, vehicle_count, timestamp), (1010, 4, 11));
+            }
+            other => panic!("read back as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lane_map_rejects_non_numeric_ids() {
+        let error = serde_json::from_str::<TrafficSnapshot>(r#"{"lanes": {"north": 1}, "timestamp": 0}"#).unwrap_err();
+        assert!(error.to_string().contains("invalid lane id 'north'"), "{}", error);
+    }
+
+    #[test]
+    fn light_colors_parse_in_any_case() {
+        for (name, color) in [("green", LightColor::Green), ("AMBER", LightColor::Amber), ("Red", LightColor::Red)] {
+            assert_eq!(serde_json::from_str::<LightColor>(&format!("\"{}\"", name)).unwrap(), color);
+        }
+        assert_eq!(serde_json::to_string(&LightColor::Amber).unwrap(), "\"Amber\"");
+    }
+
+    #[test]
+    fn unknown_light_colors_are_errors() {
+        assert!(serde_json::from_str::<LightColor>("\"blue\"").is_err());
+        assert!(serde_json::from_str::<LightStatus>(r#"{"lane_id": 1001, "status": "off"}"#).is_err());
+    }
+}
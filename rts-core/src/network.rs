@@ -0,0 +1,259 @@
+// network.rs
+//
+// The road network as routing and signal planning see it: the set of
+// intersections the lanes connect, and a (row, column) position for each.
+// Both are derived from the lane list, so a topology with a different number
+// of intersections needs no changes elsewhere. Positions come from an explicit
+// layout where one is given and from a default square grid otherwise, in
+// which intersections are numbered row by row starting at 1. `load_network`
+// takes both the lanes and the layout from the network file (see
+// network_file), which intersections have no traffic lights, and the tags on
+// lanes and intersections. A lane counts as tagged with its own tags and with
+// those of the intersections it leaves and arrives at, so tagging a junction
+// picks out every lane around it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::lanes::{load_lanes, Lane};
+
+/// The intersections of a lane list and their grid positions.
+#[derive(Debug, Clone)]
+pub struct Network {
+    intersections: BTreeSet<u32>,
+    coords: HashMap<u32, (i32, i32)>,
+    /// Intersections without traffic lights.
+    unsignalized: BTreeSet<u32>,
+    /// Lanes with each tag, their own or an end intersection's.
+    lanes_by_tag: BTreeMap<String, BTreeSet<u32>>,
+    /// Intersections with each tag.
+    junctions_by_tag: BTreeMap<String, BTreeSet<u32>>,
+}
+
+impl Network {
+    /// The intersections `lanes` connect, laid out on the default grid.
+    /// Intersection 0 stands for outside the network and is left out.
+    pub fn from_lanes(lanes: &[Lane]) -> Self {
+        Network::with_layout(lanes, &HashMap::new())
+    }
+
+    /// Like `from_lanes`, with positions taken from `layout`; intersections
+    /// it leaves out keep their default grid position.
+    pub fn with_layout(lanes: &[Lane], layout: &HashMap<u32, (i32, i32)>) -> Self {
+        let intersections: BTreeSet<u32> = lanes
+            .iter()
+            .flat_map(|lane| [lane.start_intersection, lane.end_intersection])
+            .filter(|&inter| inter != 0)
+            .collect();
+        let side = grid_side(intersections.last().copied().unwrap_or(0));
+        let coords = intersections
+            .iter()
+            .map(|&inter| {
+                let position = layout.get(&inter).copied().unwrap_or_else(|| grid_position(inter, side));
+                (inter, position)
+            })
+            .collect();
+        Network {
+            intersections,
+            coords,
+            unsignalized: BTreeSet::new(),
+            lanes_by_tag: BTreeMap::new(),
+            junctions_by_tag: BTreeMap::new(),
+        }
+    }
+
+    /// The same network with no traffic lights at `junctions`.
+    pub fn without_signals(mut self, junctions: impl IntoIterator<Item = u32>) -> Self {
+        self.unsignalized.extend(junctions);
+        self
+    }
+
+    /// The same network with `lane_tags` on the lanes of `lanes` and
+    /// `junction_tags` on intersections, each keyed by id.
+    pub fn with_tags(
+        mut self,
+        lanes: &[Lane],
+        lane_tags: &HashMap<u32, Vec<String>>,
+        junction_tags: &HashMap<u32, Vec<String>>,
+    ) -> Self {
+        for (&inter, tags) in junction_tags {
+            for tag in tags {
+                self.junctions_by_tag.entry(tag.clone()).or_default().insert(inter);
+            }
+        }
+        for lane in lanes {
+            let tags = [lane_tags.get(&lane.id), junction_tags.get(&lane.start_intersection), junction_tags.get(&lane.end_intersection)];
+            for tag in tags.into_iter().flatten().flatten() {
+                self.lanes_by_tag.entry(tag.clone()).or_default().insert(lane.id);
+            }
+        }
+        self
+    }
+
+    /// Every tag on a lane or intersection, in alphabetical order.
+    pub fn tags(&self) -> impl Iterator<Item = &str> + '_ {
+        self.lanes_by_tag.keys().chain(self.junctions_by_tag.keys()).map(String::as_str).collect::<BTreeSet<_>>().into_iter()
+    }
+
+    /// Lanes tagged `tag`, themselves or through an intersection they
+    /// leave or arrive at, in ascending order.
+    pub fn tagged_lanes(&self, tag: &str) -> impl Iterator<Item = u32> + '_ {
+        self.lanes_by_tag.get(tag).into_iter().flatten().copied()
+    }
+
+    /// Intersections tagged `tag`, in ascending order.
+    pub fn tagged_junctions(&self, tag: &str) -> impl Iterator<Item = u32> + '_ {
+        self.junctions_by_tag.get(tag).into_iter().flatten().copied()
+    }
+
+    /// True if `inter` has traffic lights. Cars pass a junction without
+    /// them as if its lights were always green.
+    pub fn is_signalized(&self, inter: u32) -> bool {
+        !self.unsignalized.contains(&inter)
+    }
+
+    /// True if `inter` is one of the network's intersections.
+    pub fn contains(&self, inter: u32) -> bool {
+        self.intersections.contains(&inter)
+    }
+
+    /// Intersection ids in ascending order.
+    pub fn intersections(&self) -> impl Iterator<Item = u32> + '_ {
+        self.intersections.iter().copied()
+    }
+
+    /// (row, column) of an intersection, rows growing southwards.
+    pub fn coords(&self, inter: u32) -> Option<(i32, i32)> {
+        self.coords.get(&inter).copied()
+    }
+
+    /// Smallest and largest (row, column) over all intersections; the edge
+    /// of the network is where boundary lanes attach.
+    pub fn bounds(&self) -> ((i32, i32), (i32, i32)) {
+        let rows = self.coords.values().map(|&(row, _)| row);
+        let cols = self.coords.values().map(|&(_, col)| col);
+        (
+            (rows.clone().min().unwrap_or(0), cols.clone().min().unwrap_or(0)),
+            (rows.max().unwrap_or(0), cols.max().unwrap_or(0)),
+        )
+    }
+}
+
+/// Side length of the smallest square grid holding intersections 1..=`highest`.
+fn grid_side(highest: u32) -> u32 {
+    let mut side = 1;
+    while side * side < highest {
+        side += 1;
+    }
+    side
+}
+
+fn grid_position(inter: u32, side: u32) -> (i32, i32) {
+    (((inter - 1) / side) as i32, ((inter - 1) % side) as i32)
+}
+
+/// The network of `load_lanes`, laid out, signalized and tagged as the
+/// network file says.
+pub fn load_network() -> Network {
+    let file = crate::network_file::loaded();
+    let lanes = load_lanes();
+    Network::with_layout(&lanes, &file.layout())
+        .without_signals(file.unsignalized())
+        .with_tags(&lanes, &file.lane_tags(), &file.junction_tags())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lanes::LaneCategory;
+    use crate::network_builder::NetworkBuilder;
+    use crate::phase_plan::build_phase_plan;
+    use crate::routing::find_lane_path;
+
+    /// A 3×3 grid numbered row by row, with a road each way between
+    /// neighbours and an entry and an exit at every junction on the edge.
+    fn three_by_three() -> Vec<Lane> {
+        let mut builder = NetworkBuilder::new();
+        for inter in 1..=9 {
+            builder.junction(inter);
+        }
+        for inter in 1..=9 {
+            if inter % 3 != 0 {
+                builder.road(inter, inter + 1).two_way();
+            }
+            if inter <= 6 {
+                builder.road(inter, inter + 3).two_way();
+            }
+            if inter != 5 {
+                builder.entry(inter);
+                builder.exit(inter);
+            }
+        }
+        builder.build().unwrap().lanes
+    }
+
+    #[test]
+    fn intersections_and_grid_come_from_the_lanes() {
+        let network = Network::from_lanes(&three_by_three());
+        assert_eq!(network.intersections().collect::<Vec<_>>(), (1..=9).collect::<Vec<_>>());
+        assert!(!network.contains(0) && !network.contains(10));
+        assert_eq!(network.coords(1), Some((0, 0)));
+        assert_eq!(network.coords(6), Some((1, 2)));
+        assert_eq!(network.coords(8), Some((2, 1)));
+        assert_eq!(network.bounds(), ((0, 0), (2, 2)));
+    }
+
+    #[test]
+    fn three_by_three_grid_routes_corner_to_corner() {
+        let lanes = three_by_three();
+        let network = Network::from_lanes(&lanes);
+        let internal: Vec<Lane> = lanes.iter().filter(|l| l.category == LaneCategory::Internal).cloned().collect();
+        for (start, end) in [(1, 9), (9, 1), (3, 7), (2, 8)] {
+            let route = find_lane_path(start, end, &internal, &network, None).unwrap();
+            let hops = (network.coords(start).unwrap(), network.coords(end).unwrap());
+            let manhattan = ((hops.0).0 - (hops.1).0).abs() + ((hops.0).1 - (hops.1).1).abs();
+            assert_eq!(route.len(), manhattan as usize, "{} -> {}", start, end);
+            assert_eq!(route.first().unwrap().start_intersection, start);
+            assert_eq!(route.last().unwrap().end_intersection, end);
+            assert!(route.windows(2).all(|pair| pair[0].end_intersection == pair[1].start_intersection));
+        }
+    }
+
+    #[test]
+    fn three_by_three_grid_groups_every_approach_into_one_phase() {
+        let lanes = three_by_three();
+        let network = Network::from_lanes(&lanes);
+        for junction in network.intersections() {
+            let phases = build_phase_plan(junction, &lanes, &network);
+            let mut served: Vec<u32> = phases.iter().flat_map(|phase| phase.lanes.clone()).collect();
+            served.sort();
+            let mut approaches: Vec<u32> = lanes.iter().filter(|l| l.end_intersection == junction).map(|l| l.id).collect();
+            approaches.sort();
+            assert_eq!(served, approaches, "junction {}", junction);
+            // Four approaches, each free to turn, never all go at once.
+            assert!(phases.len() > 1, "junction {}: {:?}", junction, phases);
+        }
+    }
+
+    #[test]
+    fn tagged_junctions_tag_the_lanes_around_them() {
+        let mut builder = NetworkBuilder::new();
+        builder.junction(1).tag("downtown");
+        builder.junction(2);
+        builder.junction(3);
+        let into = builder.entry(1).handle();
+        let main = builder.road(1, 2).two_way().handle();
+        let side = builder.road(2, 3).two_way().tag("study-area").handle();
+        let out = builder.exit(3).handle();
+        let built = builder.build().unwrap();
+        let network = &built.network;
+
+        assert_eq!(network.tags().collect::<Vec<_>>(), ["downtown", "study-area"]);
+        assert_eq!(network.tagged_junctions("downtown").collect::<Vec<_>>(), [1]);
+        let downtown = [built.id(into), built.id(main), built.reverse_id(main).unwrap()];
+        assert_eq!(network.tagged_lanes("downtown").collect::<Vec<_>>(), downtown);
+        let study_area = [built.id(side), built.reverse_id(side).unwrap()];
+        assert_eq!(network.tagged_lanes("study-area").collect::<Vec<_>>(), study_area);
+        assert!(!network.tagged_lanes("downtown").any(|lane| lane == built.id(out)));
+        assert_eq!(network.tagged_lanes("elsewhere").count(), 0);
+    }
+}
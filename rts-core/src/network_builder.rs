@@ -0,0 +1,435 @@
+// network_builder.rs
+//
+// Road networks put together in code rather than read from a network file,
+// for tests and examples:
+//
+//   let mut builder = NetworkBuilder::new();
+//   builder.junction(1).at(0, 0);
+//   builder.junction(2).at(0, 1).without_signals();
+//   builder.road(1, 2).length(300.0).two_way();
+//   let entry = builder.entry(1).length(200.0).handle();
+//   builder.exit(2);
+//   let built = builder.build()?;
+//   assert_eq!(built.lane(entry).length, 200.0);
+//
+// Every junction a lane touches must be declared. A road joins two junctions
+// in one direction, or in both with `two_way`; an entry comes into a junction
+// from outside the grid and an exit leaves it. Lanes default to 100 m on one
+// parallel lane, with the capacity their length holds (see
+// `lanes::lane_capacity`). Junctions without a position take their place on
+// the default grid (see `network::Network`). Lanes and junctions take tags as
+// the network file's do; a two-way road's tags go on both its lanes.
+//
+// Lane ids start at 1000 and follow the order of the builder calls, the
+// reverse lane of a two-way road right after its forward lane, so the same
+// calls always give the same ids. `handle` keeps hold of a call so its lane
+// id can be looked up in the built network.
+//
+// `build` runs the checks a network file gets (see `network_file`). A
+// problem with a single call names the call and its position among the
+// builder calls, e.g. "road(1, 7) (builder call 4): junction 7 is not
+// declared"; problems of the network as a whole come from
+// `network_file::validate_network`.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::lanes::{lane_capacity, Lane, LaneCategory, Movement, MAX_PARALLEL_LANES};
+use crate::network::Network;
+use crate::network_file::validate_network;
+
+/// Length of a lane whose length is not given, in meters.
+pub const DEFAULT_LENGTH: f64 = 100.0;
+
+/// First lane id the builder hands out.
+pub const FIRST_LANE_ID: u32 = 1000;
+
+/// A lane declared with `road`, `entry` or `exit`, for finding its id in the
+/// built network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneHandle(usize);
+
+#[derive(Debug, Clone)]
+struct JunctionDecl {
+    /// Position among all builder calls, from 1.
+    call: usize,
+    id: u32,
+    at: Option<(i32, i32)>,
+    signals: bool,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct LaneDecl {
+    /// Position among all builder calls, from 1.
+    call: usize,
+    from: u32,
+    to: u32,
+    category: LaneCategory,
+    length: f64,
+    two_way: bool,
+    parallel_count: u32,
+    capacity: Option<u32>,
+    movement: Option<Movement>,
+    tags: Vec<String>,
+}
+
+impl LaneDecl {
+    /// The call that declared the lane, as it was written.
+    fn describe(&self) -> String {
+        match self.category {
+            LaneCategory::Internal => format!("road({}, {})", self.from, self.to),
+            LaneCategory::InputBoundary => format!("entry({})", self.to),
+            LaneCategory::OutputBoundary => format!("exit({})", self.from),
+        }
+    }
+
+    fn lane(&self, id: u32, from: u32, to: u32) -> Lane {
+        Lane {
+            id,
+            start_intersection: from,
+            end_intersection: to,
+            length: self.length,
+            capacity: self.capacity.unwrap_or_else(|| lane_capacity(self.length) * self.parallel_count),
+            parallel_count: self.parallel_count,
+            movement: self.movement,
+            category: self.category,
+        }
+    }
+}
+
+/// Collects junctions and lanes and turns them into a validated network.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkBuilder {
+    junctions: Vec<JunctionDecl>,
+    lanes: Vec<LaneDecl>,
+    calls: usize,
+}
+
+/// A junction being declared; see `NetworkBuilder::junction`.
+pub struct JunctionBuilder<'a> {
+    builder: &'a mut NetworkBuilder,
+    index: usize,
+}
+
+impl JunctionBuilder<'_> {
+    /// Places the junction at (row, column) on the grid, rows growing southwards.
+    pub fn at(self, row: i32, col: i32) -> Self {
+        self.builder.junctions[self.index].at = Some((row, col));
+        self
+    }
+
+    /// Leaves the junction without traffic lights.
+    pub fn without_signals(self) -> Self {
+        self.builder.junctions[self.index].signals = false;
+        self
+    }
+
+    /// Tags the junction.
+    pub fn tag(self, tag: &str) -> Self {
+        self.builder.junctions[self.index].tags.push(tag.to_string());
+        self
+    }
+}
+
+/// A lane being declared; see `NetworkBuilder::road`.
+pub struct LaneBuilder<'a> {
+    builder: &'a mut NetworkBuilder,
+    index: usize,
+}
+
+impl LaneBuilder<'_> {
+    fn decl(&mut self) -> &mut LaneDecl {
+        &mut self.builder.lanes[self.index]
+    }
+
+    /// Length in meters.
+    pub fn length(mut self, meters: f64) -> Self {
+        self.decl().length = meters;
+        self
+    }
+
+    /// Adds the lane back the other way, with the same settings. Roads only.
+    pub fn two_way(mut self) -> Self {
+        self.decl().two_way = true;
+        self
+    }
+
+    /// Number of side-by-side lanes in this direction.
+    pub fn parallel(mut self, count: u32) -> Self {
+        self.decl().parallel_count = count;
+        self
+    }
+
+    /// Vehicles the lane holds, instead of what its length holds.
+    pub fn capacity(mut self, vehicles: u32) -> Self {
+        self.decl().capacity = Some(vehicles);
+        self
+    }
+
+    /// Reserves the lane for one movement at the junction it arrives at.
+    pub fn movement(mut self, movement: Movement) -> Self {
+        self.decl().movement = Some(movement);
+        self
+    }
+
+    /// Tags the lane.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.decl().tags.push(tag.to_string());
+        self
+    }
+
+    /// The lane, for looking up its id with `BuiltNetwork::id`.
+    pub fn handle(self) -> LaneHandle {
+        LaneHandle(self.index)
+    }
+}
+
+impl NetworkBuilder {
+    /// A builder with nothing declared yet.
+    pub fn new() -> Self {
+        NetworkBuilder::default()
+    }
+
+    /// Declares junction `id`, with traffic lights.
+    pub fn junction(&mut self, id: u32) -> JunctionBuilder<'_> {
+        self.calls += 1;
+        self.junctions.push(JunctionDecl { call: self.calls, id, at: None, signals: true, tags: Vec::new() });
+        JunctionBuilder { index: self.junctions.len() - 1, builder: self }
+    }
+
+    /// Declares a road from junction `from` to junction `to`.
+    pub fn road(&mut self, from: u32, to: u32) -> LaneBuilder<'_> {
+        self.lane(from, to, LaneCategory::Internal)
+    }
+
+    /// Declares a lane into `junction` from outside the grid.
+    pub fn entry(&mut self, junction: u32) -> LaneBuilder<'_> {
+        self.lane(0, junction, LaneCategory::InputBoundary)
+    }
+
+    /// Declares a lane out of the grid from `junction`.
+    pub fn exit(&mut self, junction: u32) -> LaneBuilder<'_> {
+        self.lane(junction, 0, LaneCategory::OutputBoundary)
+    }
+
+    fn lane(&mut self, from: u32, to: u32, category: LaneCategory) -> LaneBuilder<'_> {
+        self.calls += 1;
+        self.lanes.push(LaneDecl {
+            call: self.calls,
+            from,
+            to,
+            category,
+            length: DEFAULT_LENGTH,
+            two_way: false,
+            parallel_count: 1,
+            capacity: None,
+            movement: None,
+            tags: Vec::new(),
+        });
+        LaneBuilder { index: self.lanes.len() - 1, builder: self }
+    }
+
+    /// Checks every call, assigns the lane ids and checks the network as a
+    /// whole. The error names the first call found wrong, or every problem
+    /// of the network as `validate_network` reports them.
+    pub fn build(&self) -> Result<BuiltNetwork, String> {
+        let mut declared = HashMap::new();
+        for junction in &self.junctions {
+            let problem = if junction.id == 0 {
+                Some("intersection 0 stands for outside the grid and cannot be declared".to_string())
+            } else if junction.tags.iter().any(|tag| tag.trim().is_empty()) {
+                Some("blank tag".to_string())
+            } else {
+                declared
+                    .insert(junction.id, junction.call)
+                    .map(|first| format!("junction {} is already declared by builder call {}", junction.id, first))
+            };
+            if let Some(problem) = problem {
+                return Err(format!("junction({}) (builder call {}): {}", junction.id, junction.call, problem));
+            }
+        }
+
+        let mut roads = HashMap::new();
+        let mut next_id = FIRST_LANE_ID;
+        let mut lanes = Vec::new();
+        let mut ids = Vec::new();
+        let mut lane_tags = HashMap::new();
+        for decl in &self.lanes {
+            let fail = |problem: String| format!("{} (builder call {}): {}", decl.describe(), decl.call, problem);
+            if let Some(&inter) = [decl.from, decl.to].iter().find(|&&inter| inter != 0 && !declared.contains_key(&inter)) {
+                return Err(fail(format!("junction {} is not declared", inter)));
+            }
+            if !(decl.length > 0.0 && decl.length.is_finite()) {
+                return Err(fail(format!("invalid length {}", decl.length)));
+            }
+            if decl.parallel_count == 0 || decl.capacity == Some(0) {
+                return Err(fail("needs at least one parallel lane and room for one vehicle".to_string()));
+            }
+            if decl.parallel_count > MAX_PARALLEL_LANES {
+                return Err(fail(format!("more than {} parallel lanes", MAX_PARALLEL_LANES)));
+            }
+            if decl.tags.iter().any(|tag| tag.trim().is_empty()) {
+                return Err(fail("blank tag".to_string()));
+            }
+            if decl.category != LaneCategory::Internal && decl.two_way {
+                return Err(fail("boundary lanes are one-way".to_string()));
+            }
+            if decl.category == LaneCategory::Internal && decl.from == decl.to {
+                return Err(fail("a road needs two different junctions".to_string()));
+            }
+
+            let mut ends = vec![(decl.from, decl.to)];
+            if decl.two_way {
+                ends.push((decl.to, decl.from));
+            }
+            let mut assigned = Vec::new();
+            for (from, to) in ends {
+                if decl.category == LaneCategory::Internal {
+                    if let Some(first) = roads.insert((from, to), decl.call) {
+                        return Err(fail(format!("the road from {} to {} is already declared by builder call {}", from, to, first)));
+                    }
+                }
+                lanes.push(decl.lane(next_id, from, to));
+                if !decl.tags.is_empty() {
+                    lane_tags.insert(next_id, decl.tags.clone());
+                }
+                assigned.push(next_id);
+                next_id += 1;
+            }
+            ids.push(assigned);
+        }
+
+        validate_network(&lanes)?;
+        let layout: HashMap<u32, (i32, i32)> =
+            self.junctions.iter().filter_map(|junction| Some((junction.id, junction.at?))).collect();
+        let unsignalized: BTreeSet<u32> =
+            self.junctions.iter().filter(|junction| !junction.signals).map(|junction| junction.id).collect();
+        let junction_tags: HashMap<u32, Vec<String>> = self
+            .junctions
+            .iter()
+            .filter(|junction| !junction.tags.is_empty())
+            .map(|junction| (junction.id, junction.tags.clone()))
+            .collect();
+        let network = Network::with_layout(&lanes, &layout)
+            .without_signals(unsignalized)
+            .with_tags(&lanes, &lane_tags, &junction_tags);
+        Ok(BuiltNetwork { lanes, network, ids })
+    }
+}
+
+/// A network made by `NetworkBuilder::build`.
+#[derive(Debug, Clone)]
+pub struct BuiltNetwork {
+    /// The lanes, in the order they were declared.
+    pub lanes: Vec<Lane>,
+    /// Junctions, positions and signals.
+    pub network: Network,
+    /// Lane ids of each declared lane: one, or two for a two-way road.
+    ids: Vec<Vec<u32>>,
+}
+
+impl BuiltNetwork {
+    /// Id of the lane `handle` declared (the forward lane of a two-way road).
+    pub fn id(&self, handle: LaneHandle) -> u32 {
+        self.ids[handle.0][0]
+    }
+
+    /// Id of the lane back the other way, if `handle` declared a two-way road.
+    pub fn reverse_id(&self, handle: LaneHandle) -> Option<u32> {
+        self.ids[handle.0].get(1).copied()
+    }
+
+    /// The lane `handle` declared.
+    pub fn lane(&self, handle: LaneHandle) -> &Lane {
+        let id = self.id(handle);
+        self.lanes.iter().find(|lane| lane.id == id).expect("every handle has a lane")
+    }
+
+    /// Id of the road from junction `from` to junction `to`, either way of a
+    /// two-way road.
+    pub fn road(&self, from: u32, to: u32) -> Option<u32> {
+        self.lanes
+            .iter()
+            .find(|lane| lane.category == LaneCategory::Internal && lane.start_intersection == from && lane.end_intersection == to)
+            .map(|lane| lane.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Junctions 1 and 2 joined both ways, with an entry into 1 and an exit
+    /// out of 2.
+    fn pair() -> NetworkBuilder {
+        let mut builder = NetworkBuilder::new();
+        builder.junction(1).at(0, 0);
+        builder.junction(2).at(0, 1);
+        builder.road(1, 2).length(300.0).two_way();
+        builder.entry(1).length(200.0);
+        builder.exit(2);
+        builder
+    }
+
+    #[test]
+    fn ids_follow_the_calls_and_handles_find_them() {
+        let mut builder = pair();
+        builder.junction(3).at(1, 1).without_signals();
+        let detour = builder.road(2, 3).parallel(2).handle();
+        let back = builder.road(3, 1).capacity(4).movement(Movement::RightTurn).handle();
+        let built = builder.build().unwrap();
+
+        let ends: Vec<(u32, u32, u32)> = built.lanes.iter().map(|l| (l.id, l.start_intersection, l.end_intersection)).collect();
+        assert_eq!(ends, [(1000, 1, 2), (1001, 2, 1), (1002, 0, 1), (1003, 2, 0), (1004, 2, 3), (1005, 3, 1)]);
+        assert_eq!(built.road(2, 1), Some(1001));
+        assert_eq!(built.road(1, 3), None);
+        assert_eq!((built.id(detour), built.reverse_id(detour)), (1004, None));
+        assert_eq!(built.lane(detour).capacity, 20);
+        assert_eq!(built.lane(back).capacity, 4);
+        assert_eq!(built.lane(back).movement, Some(Movement::RightTurn));
+        assert_eq!(built.lanes[2].length, 200.0);
+        assert_eq!(built.lanes[2].category, LaneCategory::InputBoundary);
+
+        assert_eq!(built.network.coords(3), Some((1, 1)));
+        assert!(built.network.is_signalized(1) && !built.network.is_signalized(3));
+        // The same calls give the same ids.
+        assert_eq!(
+            pair().build().unwrap().lanes.iter().map(|l| l.id).collect::<Vec<_>>(),
+            [1000, 1001, 1002, 1003]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_offending_call() {
+        let mut builder = pair();
+        builder.road(2, 7);
+        assert_eq!(builder.build().unwrap_err(), "road(2, 7) (builder call 6): junction 7 is not declared");
+
+        let mut builder = pair();
+        builder.entry(2).length(-5.0);
+        assert_eq!(builder.build().unwrap_err(), "entry(2) (builder call 6): invalid length -5");
+
+        let mut builder = pair();
+        builder.road(2, 1);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            "road(2, 1) (builder call 6): the road from 2 to 1 is already declared by builder call 3"
+        );
+
+        let mut builder = pair();
+        builder.junction(2);
+        assert_eq!(builder.build().unwrap_err(), "junction(2) (builder call 6): junction 2 is already declared by builder call 2");
+
+        let mut builder = pair();
+        builder.exit(1).two_way();
+        assert_eq!(builder.build().unwrap_err(), "exit(1) (builder call 6): boundary lanes are one-way");
+    }
+
+    #[test]
+    fn network_wide_problems_are_caught() {
+        let mut builder = pair();
+        builder.junction(3);
+        builder.road(2, 3);
+        assert_eq!(builder.build().unwrap_err(), "invalid network: junctions [3] have no lane leading out; junctions [3] lead to no output lane");
+    }
+}
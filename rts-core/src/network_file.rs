@@ -0,0 +1,431 @@
+// network_file.rs
+//
+// The road network as a JSON description, so a different grid needs no
+// recompiling. RTS_NETWORK names the file; without it the built-in 16-junction
+// grid (network.json next to this crate's Cargo.toml) is used. Every
+// component loads it once, on first use, through `lanes::load_lanes` and
+// `network::load_network`, so set RTS_NETWORK the same for all of them.
+//
+// The file lists the intersections with their grid positions and the lanes
+// between them:
+//
+//   {
+//     "intersections": [{"id": 1, "row": 0, "col": 0}, {"id": 2, "row": 0, "col": 1, "signals": false}],
+//     "lanes": [
+//       {"id": 1000, "from": 0, "to": 1, "length": 300.0, "category": "input_boundary"},
+//       {"id": 1001, "from": 1, "to": 2, "length": 500.0, "category": "internal", "parallel_count": 2},
+//       {"id": 1002, "from": 2, "to": 0, "length": 200.0, "category": "output_boundary"}
+//     ]
+//   }
+//
+// Intersection 0 stands for outside the grid: input boundary lanes come from
+// it and output boundary lanes lead to it. A lane's capacity defaults to what
+// its length holds on each of its parallel lanes (see `lanes::lane_capacity`),
+// and `movement` reserves it for one movement ("straight", "left_turn" or
+// "right_turn"). An intersection has traffic lights unless `signals` is
+// false; the lanes into one without are never stopped (see
+// `network::Network::is_signalized`). `lat` and `lon` place an intersection
+// on a map for drawing it (see `geojson`); the simulation ignores them.
+// Lanes and intersections can carry `tags`, free-form names such as
+// "downtown" that pick out the parts of the network worth a closer look
+// (see `network::Network::tagged_lanes`).
+//
+// Beyond each lane making sense on its own, the network as a whole must hold
+// together (see `validate_network`): every junction needs a lane in and a
+// lane out, and, counting the outside as one more node, the lanes must
+// connect everything both ways, so that every junction can be reached from
+// an input lane and leads on to an output lane. A file that fails any check
+// is rejected before anything runs on it: a component whose RTS_NETWORK
+// cannot be read, parsed or validated exits with the reason rather than
+// simulating the built-in grid while the others simulate another network.
+//
+// RTS_NETWORK may also name a SUMO network (a file ending in .xml, as
+// netconvert writes them, see `sumo`) or an OpenStreetMap extract (Overpass
+// JSON in a file ending in .osm.json, see `osm`), which is imported into the
+// same description and checked the same way.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+use crate::lanes::{lane_capacity, Lane, LaneCategory, Movement, MAX_PARALLEL_LANES};
+
+/// The built-in network, used unless RTS_NETWORK names another.
+pub const DEFAULT_NETWORK: &str = include_str!("../network.json");
+
+/// An intersection and its (row, column) on the grid, rows growing southwards.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntersectionSpec {
+    /// Intersection id, not 0.
+    pub id: u32,
+    /// Grid row.
+    pub row: i32,
+    /// Grid column.
+    pub col: i32,
+    /// Whether the intersection has traffic lights; true if left out.
+    #[serde(default)]
+    pub signals: Option<bool>,
+    /// Latitude, where the network is on a map; only used to draw it.
+    #[serde(default)]
+    pub lat: Option<f64>,
+    /// Longitude, where the network is on a map.
+    #[serde(default)]
+    pub lon: Option<f64>,
+    /// Names the intersection is tagged with.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// A lane as the file describes it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaneSpec {
+    /// Unique lane id.
+    pub id: u32,
+    /// Intersection the lane leaves, 0 for an input boundary lane.
+    pub from: u32,
+    /// Intersection the lane arrives at, 0 for an output boundary lane.
+    pub to: u32,
+    /// Length in meters.
+    pub length: f64,
+    /// Boundary or internal.
+    pub category: LaneCategory,
+    /// Side-by-side lanes in this direction; 1 if left out.
+    #[serde(default)]
+    pub parallel_count: Option<u32>,
+    /// Vehicles the lane holds across its parallel lanes; derived from the
+    /// length if left out.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    /// Movement the lane is reserved for, if any.
+    #[serde(default)]
+    pub movement: Option<Movement>,
+    /// Names the lane is tagged with.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl LaneSpec {
+    /// The lane the spec describes, with its defaults filled in.
+    pub fn lane(&self) -> Lane {
+        let parallel_count = self.parallel_count.unwrap_or(1);
+        Lane {
+            id: self.id,
+            start_intersection: self.from,
+            end_intersection: self.to,
+            length: self.length,
+            capacity: self.capacity.unwrap_or_else(|| lane_capacity(self.length) * parallel_count),
+            parallel_count,
+            movement: self.movement,
+            category: self.category,
+        }
+    }
+}
+
+/// A validated network description.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkFile {
+    /// Every intersection a lane starts or ends at.
+    pub intersections: Vec<IntersectionSpec>,
+    /// The lanes, in the order `load_lanes` returns them.
+    pub lanes: Vec<LaneSpec>,
+}
+
+impl NetworkFile {
+    /// Parses a network description and checks it with `validate`.
+    pub fn parse(json: &str) -> Result<NetworkFile, String> {
+        let file: NetworkFile = serde_json::from_str(json).map_err(|e| format!("invalid network: {}", e))?;
+        file.validate()
+    }
+
+    /// Checks a network description: ids are unique, every lane has a
+    /// positive length and one to MAX_PARALLEL_LANES parallel lanes, connects
+    /// declared intersections, and touches intersection 0 exactly as its
+    /// category says; no tag is blank; then the lanes are checked together
+    /// with `validate_network`.
+    pub fn validate(self) -> Result<NetworkFile, String> {
+        let file = self;
+        if file.lanes.is_empty() {
+            return Err("the network has no lanes".to_string());
+        }
+        let mut intersections = HashSet::new();
+        for inter in &file.intersections {
+            if inter.id == 0 {
+                return Err("intersection 0 stands for outside the grid and cannot be declared".to_string());
+            }
+            if !intersections.insert(inter.id) {
+                return Err(format!("intersection {} is declared twice", inter.id));
+            }
+            if inter.tags.iter().any(|tag| tag.trim().is_empty()) {
+                return Err(format!("intersection {} has a blank tag", inter.id));
+            }
+        }
+        let mut lane_ids = HashSet::new();
+        for lane in &file.lanes {
+            if !lane_ids.insert(lane.id) {
+                return Err(format!("lane {} is declared twice", lane.id));
+            }
+            if !(lane.length > 0.0 && lane.length.is_finite()) {
+                return Err(format!("lane {} has invalid length {}", lane.id, lane.length));
+            }
+            if lane.parallel_count == Some(0) || lane.capacity == Some(0) {
+                return Err(format!("lane {} needs at least one parallel lane and room for one vehicle", lane.id));
+            }
+            if lane.tags.iter().any(|tag| tag.trim().is_empty()) {
+                return Err(format!("lane {} has a blank tag", lane.id));
+            }
+            if lane.parallel_count.is_some_and(|count| count > MAX_PARALLEL_LANES) {
+                return Err(format!("lane {} has more than {} parallel lanes", lane.id, MAX_PARALLEL_LANES));
+            }
+            let ends_ok = match lane.category {
+                LaneCategory::InputBoundary => lane.from == 0 && lane.to != 0,
+                LaneCategory::OutputBoundary => lane.from != 0 && lane.to == 0,
+                LaneCategory::Internal => lane.from != 0 && lane.to != 0 && lane.from != lane.to,
+            };
+            if !ends_ok {
+                return Err(format!("lane {} runs from {} to {}, which does not fit a {:?} lane", lane.id, lane.from, lane.to, lane.category));
+            }
+            if let Some(&inter) = [lane.from, lane.to].iter().find(|&&inter| inter != 0 && !intersections.contains(&inter)) {
+                return Err(format!("lane {} uses undeclared intersection {}", lane.id, inter));
+            }
+        }
+        validate_network(&file.lanes())?;
+        Ok(file)
+    }
+
+    /// The lanes, in file order.
+    pub fn lanes(&self) -> Vec<Lane> {
+        self.lanes.iter().map(LaneSpec::lane).collect()
+    }
+
+    /// Intersections without traffic lights.
+    pub fn unsignalized(&self) -> Vec<u32> {
+        self.intersections.iter().filter(|inter| inter.signals == Some(false)).map(|inter| inter.id).collect()
+    }
+
+    /// Tags of every tagged lane.
+    pub fn lane_tags(&self) -> HashMap<u32, Vec<String>> {
+        self.lanes.iter().filter(|lane| !lane.tags.is_empty()).map(|lane| (lane.id, lane.tags.clone())).collect()
+    }
+
+    /// Tags of every tagged intersection.
+    pub fn junction_tags(&self) -> HashMap<u32, Vec<String>> {
+        self.intersections.iter().filter(|inter| !inter.tags.is_empty()).map(|inter| (inter.id, inter.tags.clone())).collect()
+    }
+
+    /// Grid position of every intersection.
+    pub fn layout(&self) -> HashMap<u32, (i32, i32)> {
+        self.intersections.iter().map(|inter| (inter.id, (inter.row, inter.col))).collect()
+    }
+
+    /// The file named by RTS_NETWORK, imported from SUMO if it ends in .xml
+    /// and from OpenStreetMap if it ends in .osm.json, or the built-in
+    /// network if it is unset. An error if the file cannot be read or is
+    /// invalid.
+    pub fn from_env() -> Result<NetworkFile, String> {
+        let Ok(path) = std::env::var("RTS_NETWORK") else {
+            return Ok(NetworkFile::parse(DEFAULT_NETWORK).expect("the built-in network is valid"));
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let file = if path.ends_with(".xml") {
+            crate::sumo::import(&text)
+        } else if path.ends_with(".osm.json") {
+            crate::osm::import(&text)
+        } else {
+            NetworkFile::parse(&text)
+        };
+        file.map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// Rank of `value` among the distinct `values`, smallest first: the grid
+/// row or column of an imported junction from its coordinates.
+pub(crate) fn ranks(values: impl Iterator<Item = f64>) -> impl Fn(f64) -> i32 {
+    let mut distinct: Vec<f64> = values.collect();
+    distinct.sort_by(f64::total_cmp);
+    distinct.dedup();
+    move |value| distinct.partition_point(|&v| v < value) as i32
+}
+
+/// Junctions reachable from intersection 0 over `lanes`, following them
+/// forwards or, with `backwards`, against their direction.
+pub(crate) fn reachable_from_outside(lanes: &[Lane], backwards: bool) -> HashSet<u32> {
+    let mut next: HashMap<u32, Vec<u32>> = HashMap::new();
+    for lane in lanes {
+        let (from, to) = if backwards {
+            (lane.end_intersection, lane.start_intersection)
+        } else {
+            (lane.start_intersection, lane.end_intersection)
+        };
+        next.entry(from).or_default().push(to);
+    }
+    let mut seen = HashSet::from([0]);
+    let mut queue = VecDeque::from([0]);
+    while let Some(inter) = queue.pop_front() {
+        for &to in next.get(&inter).into_iter().flatten() {
+            if seen.insert(to) {
+                queue.push_back(to);
+            }
+        }
+    }
+    seen
+}
+
+/// Checks that `lanes` make a network cars can drive: lane ids are unique,
+/// every junction has at least one lane in and one lane out, and the lanes
+/// with the outside (intersection 0) are strongly connected, so every
+/// junction can be reached from an input lane and leads to an output lane.
+/// The error names every problem found, separated by semicolons.
+pub fn validate_network(lanes: &[Lane]) -> Result<(), String> {
+    let mut problems = Vec::new();
+
+    let mut seen = HashSet::new();
+    let duplicated: BTreeSet<u32> = lanes.iter().map(|lane| lane.id).filter(|&id| !seen.insert(id)).collect();
+    if !duplicated.is_empty() {
+        problems.push(format!("lane ids {:?} are used more than once", duplicated));
+    }
+
+    let junctions: BTreeSet<u32> = lanes
+        .iter()
+        .flat_map(|lane| [lane.start_intersection, lane.end_intersection])
+        .filter(|&inter| inter != 0)
+        .collect();
+    let without = |has: &dyn Fn(&Lane, u32) -> bool| -> Vec<u32> {
+        junctions.iter().copied().filter(|&inter| !lanes.iter().any(|lane| has(lane, inter))).collect()
+    };
+    let no_inbound = without(&|lane, inter| lane.end_intersection == inter);
+    if !no_inbound.is_empty() {
+        problems.push(format!("junctions {:?} have no lane leading in", no_inbound));
+    }
+    let no_outbound = without(&|lane, inter| lane.start_intersection == inter);
+    if !no_outbound.is_empty() {
+        problems.push(format!("junctions {:?} have no lane leading out", no_outbound));
+    }
+
+    let unreached = |reached: HashSet<u32>| -> Vec<u32> {
+        junctions.iter().copied().filter(|inter| !reached.contains(inter)).collect()
+    };
+    let cut_off = unreached(reachable_from_outside(lanes, false));
+    if !cut_off.is_empty() {
+        problems.push(format!("junctions {:?} cannot be reached from any input lane", cut_off));
+    }
+    let dead_ends = unreached(reachable_from_outside(lanes, true));
+    if !dead_ends.is_empty() {
+        problems.push(format!("junctions {:?} lead to no output lane", dead_ends));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid network: {}", problems.join("; ")))
+    }
+}
+
+/// The process's network, loaded by `NetworkFile::from_env` on first use.
+/// Exits the process if RTS_NETWORK names a network that cannot be loaded.
+pub fn loaded() -> &'static NetworkFile {
+    static NETWORK: OnceLock<NetworkFile> = OnceLock::new();
+    NETWORK.get_or_init(|| {
+        NetworkFile::from_env().unwrap_or_else(|e| {
+            eprintln!("Cannot load RTS_NETWORK: {}", e);
+            std::process::exit(1);
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lane(id: u32, from: u32, to: u32) -> Lane {
+        let category = match (from, to) {
+            (0, _) => LaneCategory::InputBoundary,
+            (_, 0) => LaneCategory::OutputBoundary,
+            _ => LaneCategory::Internal,
+        };
+        LaneSpec { id, from, to, length: 100.0, category, parallel_count: None, capacity: None, movement: None, tags: Vec::new() }.lane()
+    }
+
+    /// Into junction 1, on to 2 and back, and out of 2.
+    fn two_junctions() -> Vec<Lane> {
+        vec![lane(1000, 0, 1), lane(1001, 1, 2), lane(1002, 2, 1), lane(1003, 2, 0)]
+    }
+
+    #[test]
+    fn built_in_network_is_valid() {
+        let file = NetworkFile::parse(DEFAULT_NETWORK).unwrap();
+        assert_eq!(file.intersections.len(), 16);
+        assert!(validate_network(&file.lanes()).is_ok());
+    }
+
+    #[test]
+    fn connected_network_is_valid() {
+        assert_eq!(validate_network(&two_junctions()), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_lane_ids_are_named() {
+        let mut lanes = two_junctions();
+        lanes.push(lane(1001, 1, 2));
+        let error = validate_network(&lanes).unwrap_err();
+        assert!(error.contains("lane ids {1001} are used more than once"), "{}", error);
+    }
+
+    #[test]
+    fn junctions_no_input_lane_reaches_are_named() {
+        let mut lanes = two_junctions();
+        // 3 and 4 lead on to 1 but nothing leads to them.
+        lanes.extend([lane(1004, 3, 4), lane(1005, 4, 3), lane(1006, 4, 1)]);
+        let error = validate_network(&lanes).unwrap_err();
+        assert!(error.contains("junctions [3, 4] cannot be reached from any input lane"), "{}", error);
+        assert!(!error.contains("lead to no output lane"), "{}", error);
+    }
+
+    #[test]
+    fn dead_ends_and_missing_lanes_are_all_named() {
+        let mut lanes = two_junctions();
+        lanes.push(lane(1004, 2, 3));
+        let error = validate_network(&lanes).unwrap_err();
+        assert!(error.contains("junctions [3] have no lane leading out"), "{}", error);
+        assert!(error.contains("junctions [3] lead to no output lane"), "{}", error);
+    }
+
+    #[test]
+    fn parse_rejects_duplicate_intersections_and_lanes() {
+        let error = NetworkFile::parse(
+            r#"{"intersections": [{"id": 1, "row": 0, "col": 0}, {"id": 1, "row": 0, "col": 1}],
+                "lanes": [{"id": 1000, "from": 0, "to": 1, "length": 50.0, "category": "input_boundary"}]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(error, "intersection 1 is declared twice");
+        let error = NetworkFile::parse(
+            r#"{"intersections": [{"id": 1, "row": 0, "col": 0}],
+                "lanes": [{"id": 1000, "from": 0, "to": 1, "length": 50.0, "category": "input_boundary"},
+                          {"id": 1000, "from": 1, "to": 0, "length": 50.0, "category": "output_boundary"}]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(error, "lane 1000 is declared twice");
+    }
+
+    #[test]
+    fn tags_are_read_and_blank_ones_rejected() {
+        let file = NetworkFile::parse(
+            r#"{"intersections": [{"id": 1, "row": 0, "col": 0, "tags": ["downtown"]}],
+                "lanes": [{"id": 1000, "from": 0, "to": 1, "length": 50.0, "category": "input_boundary", "tags": ["study-area"]},
+                          {"id": 1001, "from": 1, "to": 0, "length": 50.0, "category": "output_boundary"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(file.junction_tags(), HashMap::from([(1, vec!["downtown".to_string()])]));
+        assert_eq!(file.lane_tags(), HashMap::from([(1000, vec!["study-area".to_string()])]));
+        let error = NetworkFile::parse(
+            r#"{"intersections": [{"id": 1, "row": 0, "col": 0}],
+                "lanes": [{"id": 1000, "from": 0, "to": 1, "length": 50.0, "category": "input_boundary", "tags": [" "]},
+                          {"id": 1001, "from": 1, "to": 0, "length": 50.0, "category": "output_boundary"}]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(error, "lane 1000 has a blank tag");
+    }
+}
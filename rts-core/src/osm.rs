@@ -0,0 +1,339 @@
+// osm.rs
+//
+// Road networks from OpenStreetMap, so any area can be simulated without
+// drawing it first. An Overpass query for the drivable ways of an area and
+// their nodes, output as JSON, gives a file `import` turns into a network
+// description (see network_file):
+//
+//   [out:json][bbox:52.515,13.375,52.525,13.395];
+//   way[highway~"^(motorway|trunk|primary|secondary|tertiary|unclassified|residential|living_street)(_link)?$"];
+//   (._;>;);
+//   out bb;
+//
+// The area is the file's "bounds" if it has them, or else every node it
+// holds. Only the DRIVABLE ways count, and they are cut where they leave the
+// area: the way on to the first node outside, or CUT_LANE_LENGTH of it if
+// the file does not hold that node, leads to or from the outside.
+//
+// A node shared by two ways, or met twice by one, is a junction and becomes
+// an intersection, numbered from 1 in node id order, and so is a node where
+// a way leaves the area. The outside (intersection 0) is beyond the area,
+// and where a way ends without meeting another, at a dead end. The way between two such nodes becomes a lane
+// in each direction it may be driven: one from the outside is an input
+// boundary lane, one to the outside an output boundary lane, any other an
+// internal lane. Lane ids are numbered from FIRST_LANE_ID.
+//
+// Ways are one-way if tagged oneway=yes (or -1, the other way round), or if
+// they are motorways or roundabouts not tagged otherwise. A direction's
+// parallel lanes come from lanes:forward and lanes:backward, or else from
+// lanes, split evenly between the two directions of a two-way road; one if
+// untagged, and at most MAX_PARALLEL_LANES. A lane is as long as its way
+// between the two junctions, measured over every node in between.
+//
+// A junction has traffic lights if it is tagged highway=traffic_signals, or
+// if a node tagged so lies on one of its ways within SIGNAL_REACH of it, as
+// mappers often tag the stop lines of the approaches instead of the
+// junction. Every other junction is left without (see
+// `network::Network::is_signalized`).
+//
+// OpenStreetMap extracts rarely connect everything both ways: a one-way
+// street can lead out of the area with no way back in. Junctions that
+// cannot be reached from the outside, or lead nowhere out of it, are left
+// out with their lanes, with a warning; what remains is checked like any
+// network file.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::lanes::{LaneCategory, MAX_PARALLEL_LANES};
+use crate::network_file::{ranks, reachable_from_outside, IntersectionSpec, LaneSpec, NetworkFile};
+
+/// Highway types cars drive on.
+pub const DRIVABLE: [&str; 14] = [
+    "motorway",
+    "motorway_link",
+    "trunk",
+    "trunk_link",
+    "primary",
+    "primary_link",
+    "secondary",
+    "secondary_link",
+    "tertiary",
+    "tertiary_link",
+    "unclassified",
+    "residential",
+    "living_street",
+    "road",
+];
+
+/// Meters from a junction within which a traffic_signals node on one of
+/// its ways puts lights on the junction.
+pub const SIGNAL_REACH: f64 = 30.0;
+
+/// Id of the first imported lane; the rest follow.
+pub const FIRST_LANE_ID: u32 = 1000;
+
+/// Shortest lane imported, in meters; nodes closer than that still make a lane.
+const MIN_LANE_LENGTH: f64 = 1.0;
+
+/// Length of the way out of the area to a node the file does not hold.
+const CUT_LANE_LENGTH: f64 = 100.0;
+
+/// Stands for every node outside the area.
+const OUTSIDE: i64 = i64::MIN;
+
+/// Mean radius of the earth, in meters.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// The area an Overpass query covered.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Bounds {
+    minlat: f64,
+    minlon: f64,
+    maxlat: f64,
+    maxlon: f64,
+}
+
+impl Bounds {
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.minlat..=self.maxlat).contains(&lat) && (self.minlon..=self.maxlon).contains(&lon)
+    }
+}
+
+/// One element of an Overpass result.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Element {
+    Node {
+        id: i64,
+        lat: f64,
+        lon: f64,
+        #[serde(default)]
+        tags: HashMap<String, String>,
+    },
+    Way {
+        #[serde(default)]
+        nodes: Vec<i64>,
+        #[serde(default)]
+        tags: HashMap<String, String>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// An Overpass result in JSON.
+#[derive(Debug, Deserialize)]
+struct Overpass {
+    #[serde(default)]
+    bounds: Option<Bounds>,
+    elements: Vec<Element>,
+}
+
+/// A node of the area.
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    lat: f64,
+    lon: f64,
+    signals: bool,
+}
+
+/// Great-circle distance between two points, in meters.
+fn distance(a: Point, b: Point) -> f64 {
+    let (lat_a, lat_b) = (a.lat.to_radians(), b.lat.to_radians());
+    let half_dlat = (lat_b - lat_a) / 2.0;
+    let half_dlon = (b.lon - a.lon).to_radians() / 2.0;
+    let h = half_dlat.sin().powi(2) + lat_a.cos() * lat_b.cos() * half_dlon.sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// Parallel lanes a way has forwards and backwards; 0 where it may not be driven.
+fn directions(tags: &HashMap<String, String>) -> (u32, u32) {
+    let tag = |name: &str| tags.get(name).map(String::as_str);
+    let count = |name: &str| tag(name).and_then(|value| value.trim().parse::<u32>().ok()).filter(|&count| count > 0);
+    let implied_oneway = tag("highway") == Some("motorway") || tag("junction") == Some("roundabout");
+    let (forward, backward) = match tag("oneway") {
+        Some("yes" | "true" | "1") => (true, false),
+        Some("-1" | "reverse") => (false, true),
+        Some("no" | "false" | "0") => (true, true),
+        _ => (true, !implied_oneway),
+    };
+    let total = count("lanes");
+    let per_direction = |direction: &str, driven: bool| -> u32 {
+        if !driven {
+            return 0;
+        }
+        let lanes = count(direction)
+            .or_else(|| total.map(|total| if forward && backward { total / 2 } else { total }))
+            .unwrap_or(1);
+        lanes.clamp(1, MAX_PARALLEL_LANES)
+    };
+    (per_direction("lanes:forward", forward), per_direction("lanes:backward", backward))
+}
+
+/// The way between two graph nodes of a run.
+#[derive(Debug)]
+struct Piece {
+    from: i64,
+    to: i64,
+    length: f64,
+    /// Distances of the traffic_signals nodes on it from `from`.
+    signals: Vec<f64>,
+    forward: u32,
+    backward: u32,
+}
+
+/// The network description of an Overpass JSON result, checked as
+/// `NetworkFile::validate` checks any other.
+pub fn import(json: &str) -> Result<NetworkFile, String> {
+    let overpass: Overpass = serde_json::from_str(json).map_err(|e| format!("invalid Overpass JSON: {}", e))?;
+    let mut points: HashMap<i64, Point> = HashMap::new();
+    // Nodes outside the area, for the length of the ways leaving it.
+    let mut beyond: HashMap<i64, Point> = HashMap::new();
+    let mut ways = Vec::new();
+    for element in overpass.elements {
+        match element {
+            Element::Node { id, lat, lon, tags } => {
+                let signals = tags.get("highway").is_some_and(|highway| highway == "traffic_signals");
+                let point = Point { lat, lon, signals };
+                if overpass.bounds.is_none_or(|bounds| bounds.contains(lat, lon)) {
+                    points.insert(id, point);
+                } else {
+                    beyond.insert(id, point);
+                }
+            }
+            Element::Way { nodes, tags } => {
+                if tags.get("highway").is_some_and(|highway| DRIVABLE.contains(&highway.as_str())) {
+                    ways.push((nodes, directions(&tags)));
+                }
+            }
+            Element::Other => {}
+        }
+    }
+
+    // Ways cut to the area: runs of consecutive nodes inside it, and the
+    // cuts, each a stretch of way between a node inside and one outside.
+    let mut runs: Vec<(Vec<i64>, (u32, u32))> = Vec::new();
+    let mut cuts: Vec<(i64, i64, (u32, u32))> = Vec::new();
+    for (nodes, directions) in ways {
+        for pair in nodes.windows(2) {
+            match (points.contains_key(&pair[0]), points.contains_key(&pair[1])) {
+                (true, false) => cuts.push((pair[0], pair[1], directions)),
+                (false, true) => cuts.push((pair[1], pair[0], (directions.1, directions.0))),
+                _ => {}
+            }
+        }
+        for run in nodes.split(|node| !points.contains_key(node)) {
+            if run.len() >= 2 {
+                runs.push((run.to_vec(), directions));
+            }
+        }
+    }
+    if runs.is_empty() {
+        return Err("the extract has no drivable roads".to_string());
+    }
+    let mut uses: HashMap<i64, u32> = HashMap::new();
+    for (run, _) in &runs {
+        for node in run {
+            *uses.entry(*node).or_default() += 1;
+        }
+    }
+    // A cut node is where the way out of the area branches off.
+    for &(inside, _, _) in &cuts {
+        *uses.entry(inside).or_default() += 1;
+    }
+    let is_junction = |node: &i64| uses[node] >= 2;
+
+    let mut pieces = Vec::new();
+    for (run, (forward, backward)) in &runs {
+        let mut piece = Piece { from: run[0], to: run[0], length: 0.0, signals: Vec::new(), forward: *forward, backward: *backward };
+        for pair in run.windows(2) {
+            let next = points[&pair[1]];
+            piece.length += distance(points[&pair[0]], next);
+            piece.to = pair[1];
+            if next.signals {
+                piece.signals.push(piece.length);
+            }
+            if is_junction(&pair[1]) || pair[1] == run[run.len() - 1] {
+                let from = pair[1];
+                let done = std::mem::replace(
+                    &mut piece,
+                    Piece { from, to: from, length: 0.0, signals: Vec::new(), forward: *forward, backward: *backward },
+                );
+                pieces.push(done);
+            }
+        }
+    }
+    for (inside, outside, (forward, backward)) in cuts {
+        let length = beyond.get(&outside).map_or(CUT_LANE_LENGTH, |&point| distance(points[&inside], point));
+        pieces.push(Piece { from: inside, to: OUTSIDE, length, signals: Vec::new(), forward, backward });
+    }
+
+    // Junctions numbered by node id; everything else is the outside.
+    let junction_nodes: BTreeSet<i64> = uses.iter().filter(|&(_, &count)| count >= 2).map(|(&node, _)| node).collect();
+    let numbers: HashMap<i64, u32> = junction_nodes.iter().enumerate().map(|(index, &node)| (node, index as u32 + 1)).collect();
+    let number = |node: i64| numbers.get(&node).copied().unwrap_or(0);
+    let mut signalized: HashSet<u32> =
+        junction_nodes.iter().filter(|node| points[node].signals).map(|node| numbers[node]).collect();
+    let mut lanes = Vec::new();
+    for piece in &pieces {
+        let (from, to) = (number(piece.from), number(piece.to));
+        if from == to {
+            continue;
+        }
+        if piece.signals.iter().any(|&at| at <= SIGNAL_REACH) && from != 0 {
+            signalized.insert(from);
+        }
+        if piece.signals.iter().any(|&at| piece.length - at <= SIGNAL_REACH) && to != 0 {
+            signalized.insert(to);
+        }
+        let length = piece.length.max(MIN_LANE_LENGTH);
+        for (count, from, to) in [(piece.forward, from, to), (piece.backward, to, from)] {
+            if count == 0 {
+                continue;
+            }
+            let category = match (from, to) {
+                (0, _) => LaneCategory::InputBoundary,
+                (_, 0) => LaneCategory::OutputBoundary,
+                _ => LaneCategory::Internal,
+            };
+            lanes.push(LaneSpec { id: 0, from, to, length, category, parallel_count: Some(count), capacity: None, movement: None, tags: Vec::new() });
+        }
+    }
+
+    // Keep the junctions the outside reaches and that reach it in turn.
+    let all: Vec<_> = lanes.iter().map(LaneSpec::lane).collect();
+    let reached = reachable_from_outside(&all, false);
+    let leaving = reachable_from_outside(&all, true);
+    let kept = |inter: &u32| *inter == 0 || (reached.contains(inter) && leaving.contains(inter));
+    let dropped = numbers.values().filter(|inter| !kept(inter)).count();
+    if dropped > 0 {
+        eprintln!("OpenStreetMap import: leaving out {} junctions cut off from the outside one way or the other", dropped);
+    }
+    lanes.retain(|lane| kept(&lane.from) && kept(&lane.to));
+    for (index, lane) in lanes.iter_mut().enumerate() {
+        lane.id = FIRST_LANE_ID + index as u32;
+    }
+
+    let used: BTreeMap<u32, Point> = junction_nodes
+        .iter()
+        .map(|node| (numbers[node], points[node]))
+        .filter(|(inter, _)| kept(inter) && lanes.iter().any(|lane| lane.from == *inter || lane.to == *inter))
+        .collect();
+    // Rows grow southwards, latitudes northwards.
+    let row = ranks(used.values().map(|point| -point.lat));
+    let col = ranks(used.values().map(|point| point.lon));
+    let intersections = used
+        .iter()
+        .map(|(&id, point)| IntersectionSpec {
+            id,
+            row: row(-point.lat),
+            col: col(point.lon),
+            signals: Some(signalized.contains(&id)),
+            lat: Some(point.lat),
+            lon: Some(point.lon),
+            tags: Vec::new(),
+        })
+        .collect();
+    NetworkFile { intersections, lanes }.validate()
+}
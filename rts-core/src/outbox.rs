@@ -0,0 +1,231 @@
+// outbox.rs
+//
+// Lane counts on their way from a simulation to its consumers. The
+// simulation pushes every lane count that changes, and its publisher takes
+// what has piled up as one batch whenever it is free to send again, so a
+// slow consumer or broker means fewer, larger messages instead of a growing
+// pile of them. A batch holds only the lanes whose count differs from what
+// was last delivered, unless a full batch of every lane is asked for; the
+// publisher sends one of those now and then so consumers that joined late
+// or missed a batch catch up.
+//
+// At most `capacity` updates wait. What happens to the rest, and to a batch
+// the publisher could not deliver, is the outbox's Backpressure policy:
+//
+//   aggregate  (default) an update replaces the one waiting for its lane;
+//              once `capacity` lanes wait, or a batch is not delivered,
+//              everything waiting is folded into the next full batch
+//   drop       updates beyond `capacity`, and batches not delivered, are
+//              dropped; consumers catch up with the next full batch
+//
+// RTS_UPDATE_BACKPRESSURE (aggregate or drop) and RTS_UPDATE_QUEUE (the
+// capacity) set them. OutboxStats counts what was sent, aggregated and
+// dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+/// Updates that may wait when RTS_UPDATE_QUEUE is unset.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What an outbox does with updates it has no room for, and with batches
+/// that were not delivered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Fold them into fewer, fuller batches.
+    #[default]
+    Aggregate,
+    /// Drop them.
+    Drop,
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    /// Parses `aggregate` or `drop`, in any case.
+    fn from_str(value: &str) -> Result<Backpressure, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "aggregate" => Ok(Backpressure::Aggregate),
+            "drop" => Ok(Backpressure::Drop),
+            _ => Err(format!("invalid backpressure policy '{}' (expected aggregate or drop)", value.trim())),
+        }
+    }
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backpressure::Aggregate => "aggregate",
+            Backpressure::Drop => "drop",
+        })
+    }
+}
+
+/// What an outbox has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutboxStats {
+    /// Batches delivered.
+    pub batches: u64,
+    /// Lane counts in the delivered batches.
+    pub sent: u64,
+    /// Updates merged into another one or into a full batch.
+    pub aggregated: u64,
+    /// Updates dropped.
+    pub dropped: u64,
+}
+
+/// Lane counts to send in one message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaneBatch {
+    /// Lane id to vehicle count.
+    pub lanes: HashMap<u32, u32>,
+    /// True if `lanes` holds every lane, false if only those that changed.
+    pub full: bool,
+}
+
+/// Lane counts waiting for a publisher; see the top of this file.
+#[derive(Debug, Clone)]
+pub struct LaneCountOutbox {
+    policy: Backpressure,
+    capacity: usize,
+    /// Updates waiting, oldest first; with Aggregate, one per lane at most.
+    waiting: VecDeque<(u32, u32)>,
+    /// Every lane's latest count.
+    latest: HashMap<u32, u32>,
+    /// Every lane's count as consumers last received it.
+    delivered: HashMap<u32, u32>,
+    /// Whether the next batch is full, whatever is asked for.
+    resync: bool,
+    stats: OutboxStats,
+}
+
+impl LaneCountOutbox {
+    /// An empty outbox holding at most `capacity` updates (at least one).
+    pub fn new(policy: Backpressure, capacity: usize) -> LaneCountOutbox {
+        LaneCountOutbox {
+            policy,
+            capacity: capacity.max(1),
+            waiting: VecDeque::new(),
+            latest: HashMap::new(),
+            delivered: HashMap::new(),
+            resync: false,
+            stats: OutboxStats::default(),
+        }
+    }
+
+    /// An outbox with the policy of RTS_UPDATE_BACKPRESSURE and the capacity
+    /// of RTS_UPDATE_QUEUE (the defaults, with a warning, if invalid).
+    pub fn from_env() -> LaneCountOutbox {
+        let policy = match std::env::var("RTS_UPDATE_BACKPRESSURE") {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_UPDATE_BACKPRESSURE: {}", e);
+                Backpressure::default()
+            }),
+            Err(_) => Backpressure::default(),
+        };
+        let capacity = match std::env::var("RTS_UPDATE_QUEUE") {
+            Ok(value) => match value.trim().parse() {
+                Ok(capacity) if capacity > 0 => capacity,
+                _ => {
+                    eprintln!("Ignoring RTS_UPDATE_QUEUE: invalid queue length '{}'", value.trim());
+                    DEFAULT_CAPACITY
+                }
+            },
+            Err(_) => DEFAULT_CAPACITY,
+        };
+        LaneCountOutbox::new(policy, capacity)
+    }
+
+    /// The policy under backpressure.
+    pub fn policy(&self) -> Backpressure {
+        self.policy
+    }
+
+    /// What the outbox has done so far.
+    pub fn stats(&self) -> OutboxStats {
+        self.stats
+    }
+
+    /// Queues `lane_id`'s new count, if it changed.
+    pub fn push(&mut self, lane_id: u32, count: u32) {
+        if self.latest.insert(lane_id, count) == Some(count) {
+            return;
+        }
+        if self.resync {
+            self.stats.aggregated += 1;
+            return;
+        }
+        match self.policy {
+            Backpressure::Aggregate => {
+                if let Some(waiting) = self.waiting.iter_mut().find(|(lane, _)| *lane == lane_id) {
+                    waiting.1 = count;
+                    self.stats.aggregated += 1;
+                    return;
+                }
+                if self.waiting.len() >= self.capacity {
+                    self.stats.aggregated += 1;
+                    self.fold();
+                    return;
+                }
+            }
+            Backpressure::Drop => {
+                if self.waiting.len() >= self.capacity {
+                    self.stats.dropped += 1;
+                    return;
+                }
+            }
+        }
+        self.waiting.push_back((lane_id, count));
+    }
+
+    /// Queues the count of every lane in `lanes` that changed, in lane order.
+    pub fn push_all(&mut self, lanes: &HashMap<u32, u32>) {
+        let mut lanes: Vec<(u32, u32)> = lanes.iter().map(|(&lane_id, &count)| (lane_id, count)).collect();
+        lanes.sort_unstable();
+        for (lane_id, count) in lanes {
+            self.push(lane_id, count);
+        }
+    }
+
+    /// Folds everything waiting into the next full batch.
+    fn fold(&mut self) {
+        self.stats.aggregated += self.waiting.len() as u64;
+        self.waiting.clear();
+        self.resync = true;
+    }
+
+    /// The next batch to send: every lane if `full` (or after a fold), else
+    /// the lanes whose count differs from the one delivered. None if there
+    /// is nothing to send. Pass it back to `delivered` or `undelivered`.
+    pub fn take(&mut self, full: bool) -> Option<LaneBatch> {
+        let full = full || std::mem::take(&mut self.resync);
+        let lanes = if full {
+            self.waiting.clear();
+            self.latest.clone()
+        } else {
+            let mut changed: HashMap<u32, u32> = self.waiting.drain(..).collect();
+            changed.retain(|lane_id, count| self.delivered.get(lane_id) != Some(count));
+            changed
+        };
+        (!lanes.is_empty()).then_some(LaneBatch { lanes, full })
+    }
+
+    /// Records that consumers received `batch`.
+    pub fn delivered(&mut self, batch: &LaneBatch) {
+        self.delivered.extend(batch.lanes.iter().map(|(&lane_id, &count)| (lane_id, count)));
+        self.stats.batches += 1;
+        self.stats.sent += batch.lanes.len() as u64;
+    }
+
+    /// Records that `batch` could not be sent, and applies the policy to it.
+    pub fn undelivered(&mut self, batch: LaneBatch) {
+        match self.policy {
+            Backpressure::Aggregate => {
+                self.stats.aggregated += batch.lanes.len() as u64;
+                self.fold();
+            }
+            Backpressure::Drop => self.stats.dropped += batch.lanes.len() as u64,
+        }
+    }
+}
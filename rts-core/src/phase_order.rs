@@ -0,0 +1,205 @@
+// phase_order.rs
+//
+// Chooses which phase a junction serves next. By default junctions cycle
+// through their phases in a fixed round-robin order. With RTS_ADAPTIVE_PHASES
+// set they serve demand instead: before each phase the controller sums the
+// latest vehicle counts of every phase's lanes and turns the busiest phase
+// green. Ties go to the phase that has waited longest for green, then to the
+// lowest phase index.
+//
+// A starvation guard keeps a quiet phase from waiting forever behind a busy
+// one. A "cycle" is as many phases as the junction has, and every phase gets
+// green at least once per RTS_STARVATION_CYCLES cycles: a phase that has gone
+// that many cycles less one without green is overdue, and overdue phases are
+// served ahead of demand, longest-waiting first. At most every other phase
+// can be overdue ahead of it, so it waits less than one more cycle.
+
+use std::collections::HashMap;
+
+use crate::phase_plan::Phase;
+
+/// Starvation bound used when RTS_STARVATION_CYCLES is unset.
+pub const DEFAULT_STARVATION_CYCLES: u32 = 3;
+
+/// How a junction picks its next phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseOrder {
+    /// Round-robin through the phase plan.
+    Fixed,
+    /// Busiest phase first, with every phase green at least once per
+    /// `starvation_cycles` cycles.
+    Adaptive {
+        /// Cycles within which every phase gets green, at least 1.
+        starvation_cycles: u32,
+    },
+}
+
+impl PhaseOrder {
+    /// Adaptive ordering if RTS_ADAPTIVE_PHASES is `1`, `true` or `yes`,
+    /// with the starvation bound from RTS_STARVATION_CYCLES (the default,
+    /// with a warning, if it is invalid); fixed ordering otherwise.
+    pub fn from_env() -> PhaseOrder {
+        let adaptive = matches!(
+            std::env::var("RTS_ADAPTIVE_PHASES").as_deref(),
+            Ok("1") | Ok("true") | Ok("yes")
+        );
+        if !adaptive {
+            return PhaseOrder::Fixed;
+        }
+        let starvation_cycles = match std::env::var("RTS_STARVATION_CYCLES") {
+            Ok(value) => parse_starvation_cycles(&value).unwrap_or_else(|e| {
+                eprintln!("Ignoring RTS_STARVATION_CYCLES: {}", e);
+                DEFAULT_STARVATION_CYCLES
+            }),
+            Err(_) => DEFAULT_STARVATION_CYCLES,
+        };
+        PhaseOrder::Adaptive { starvation_cycles }
+    }
+}
+
+fn parse_starvation_cycles(value: &str) -> Result<u32, String> {
+    let cycles: u32 = value.trim().parse().map_err(|_| format!("invalid cycle count '{}'", value.trim()))?;
+    if cycles == 0 {
+        return Err("starvation cycles must be positive".to_string());
+    }
+    Ok(cycles)
+}
+
+/// Vehicles waiting on each phase's lanes, indexed like `phases`. Lanes
+/// missing from `counts` count as empty.
+pub fn phase_demand(phases: &[Phase], counts: &HashMap<u32, u32>) -> Vec<u32> {
+    phases
+        .iter()
+        .map(|phase| phase.lanes.iter().map(|lane_id| counts.get(lane_id).copied().unwrap_or(0)).sum())
+        .collect()
+}
+
+/// One adaptive choice of the next phase and what it was based on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseDecision {
+    /// Phase to serve next.
+    pub phase: usize,
+    /// Demand of every phase that was considered, indexed by phase.
+    pub demand: Vec<u32>,
+    /// True if the starvation guard picked the phase rather than demand.
+    pub overdue: bool,
+}
+
+/// Adaptive phase selection for one junction. The controller asks `choose`
+/// for the next phase and reports every phase it actually serves, chosen or
+/// not, to `served`.
+#[derive(Debug, Clone)]
+pub struct PhaseSelector {
+    /// Phases served since each phase was last green.
+    waited: Vec<u32>,
+    /// `waited` at which a phase is overdue.
+    overdue_after: u32,
+}
+
+impl PhaseSelector {
+    /// A selector for `phase_count` phases, none of which has waited yet.
+    pub fn new(phase_count: usize, starvation_cycles: u32) -> PhaseSelector {
+        let overdue_after = starvation_cycles.max(1).saturating_sub(1).saturating_mul(phase_count as u32);
+        PhaseSelector { waited: vec![0; phase_count], overdue_after }
+    }
+
+    /// Picks the next phase for the given per-phase demand (see
+    /// `phase_demand`); `demand` must have one entry per phase.
+    pub fn choose(&self, demand: &[u32]) -> PhaseDecision {
+        let longest_waiting = |a: &usize, b: &usize| self.waited[*a].cmp(&self.waited[*b]).then(b.cmp(a));
+        let overdue = (0..self.waited.len())
+            .filter(|&phase| self.waited[phase] >= self.overdue_after)
+            .max_by(longest_waiting);
+        let phase = overdue.unwrap_or_else(|| {
+            (0..self.waited.len())
+                .max_by(|a, b| demand[*a].cmp(&demand[*b]).then_with(|| longest_waiting(a, b)))
+                .unwrap_or(0)
+        });
+        PhaseDecision { phase, demand: demand.to_vec(), overdue: overdue.is_some() }
+    }
+
+    /// Records that `phase` has just been turned green.
+    pub fn served(&mut self, phase: usize) {
+        for (index, waited) in self.waited.iter_mut().enumerate() {
+            *waited = if index == phase { 0 } else { waited.saturating_add(1) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves `rounds` phases chosen for a fixed `demand`, returning them.
+    fn serve(selector: &mut PhaseSelector, demand: &[u32], rounds: usize) -> Vec<PhaseDecision> {
+        (0..rounds)
+            .map(|_| {
+                let decision = selector.choose(demand);
+                selector.served(decision.phase);
+                decision
+            })
+            .collect()
+    }
+
+    #[test]
+    fn busiest_phase_goes_first() {
+        let phases = [Phase { lanes: vec![1, 2] }, Phase { lanes: vec![3] }, Phase { lanes: vec![4] }];
+        // Lane 4 has no count and counts as empty.
+        let demand = phase_demand(&phases, &HashMap::from([(1, 2), (2, 1), (3, 5)]));
+        assert_eq!(demand, [3, 5, 0]);
+        let selector = PhaseSelector::new(phases.len(), DEFAULT_STARVATION_CYCLES);
+        assert_eq!(selector.choose(&demand), PhaseDecision { phase: 1, demand, overdue: false });
+    }
+
+    #[test]
+    fn ties_go_to_the_longest_waiting_then_the_lowest_phase() {
+        let mut selector = PhaseSelector::new(3, DEFAULT_STARVATION_CYCLES);
+        // Nobody has waited: the lowest phase.
+        assert_eq!(selector.choose(&[4, 4, 4]).phase, 0);
+        selector.served(2);
+        selector.served(1);
+        // Phase 0 has waited two phases, phase 2 one.
+        assert_eq!(selector.choose(&[4, 4, 4]).phase, 0);
+        assert_eq!(selector.choose(&[1, 4, 4]).phase, 2);
+        // Equal waits fall back to the lowest index.
+        let selector = PhaseSelector::new(3, DEFAULT_STARVATION_CYCLES);
+        assert_eq!(selector.choose(&[0, 7, 7]).phase, 1);
+    }
+
+    #[test]
+    fn starved_phase_is_served_within_the_bound() {
+        for starvation_cycles in 1..=4 {
+            let phase_count = 3;
+            let mut selector = PhaseSelector::new(phase_count, starvation_cycles);
+            // Phase 0 is always the busiest and phase 2 never has demand.
+            let decisions = serve(&mut selector, &[9, 1, 0], 60);
+            let bound = starvation_cycles as usize * phase_count;
+            for window in decisions.windows(bound) {
+                for phase in 0..phase_count {
+                    assert!(window.iter().any(|decision| decision.phase == phase),
+                            "phase {} starved for {} phases with {} cycles", phase, bound, starvation_cycles);
+                }
+            }
+            // Demand, not the guard, picks the busy phase whenever it can.
+            let guarded = decisions.iter().filter(|decision| decision.overdue).count();
+            assert!(decisions.iter().filter(|decision| !decision.overdue).all(|decision| decision.phase == 0));
+            assert!(guarded > 0);
+        }
+    }
+
+    #[test]
+    fn one_cycle_bound_is_round_robin() {
+        let mut selector = PhaseSelector::new(4, 1);
+        // Whatever the demand, every phase is overdue at once and the
+        // longest waiting goes first.
+        let phases: Vec<usize> = serve(&mut selector, &[0, 0, 0, 9], 8).iter().map(|decision| decision.phase).collect();
+        assert_eq!(phases, [0, 1, 2, 3, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn starvation_cycles_must_be_a_positive_count() {
+        assert_eq!(parse_starvation_cycles(" 5 "), Ok(5));
+        assert!(parse_starvation_cycles("0").is_err());
+        assert!(parse_starvation_cycles("three").is_err());
+    }
+}
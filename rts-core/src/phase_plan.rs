@@ -0,0 +1,538 @@
+// phase_plan.rs
+//
+// Builds the signal phases of a junction from the geometry of the movements
+// through it. Each approach lane can turn into any lane leaving the junction
+// (except straight back where it came from); a movement is modelled as a
+// segment inside the junction box from the approach's stop line to the exit
+// lane, with traffic driving on the right. A lane reserved for one movement
+// only takes the exits of that movement.
+//
+// Every pair of movements gets an entry in the junction's conflict matrix
+// (see `ConflictMatrix`): movements that cross block each other, and so do
+// movements merging into the same exit, except that a right turn merging
+// with another movement is permissive: it goes on the same green and yields.
+// Two approaches conflict if any of their movements block each other, and a
+// phase only holds approaches that are pairwise conflict-free.
+//
+// Phases are formed as at a real signalized intersection: through traffic
+// first, opposing approaches together, then protected left-turn phases for
+// the lanes reserved for left turns, each placed just before the through
+// phase of its sides (leading lefts). A mixed lane whose left turns cross
+// the opposing through traffic ends up with a phase of its own (split
+// phasing). The same geometry tells whether two vehicles crossing the
+// junction at once get in each other's way (see `JunctionGeometry`).
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::lanes::{Lane, LaneCategory, Movement};
+use crate::network::Network;
+
+/// Lanes that may be green together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    /// Ids of the approach lanes turned green together.
+    pub lanes: Vec<u32>,
+}
+
+/// Side of a junction a lane arrives from or leaves towards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Side {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Side {
+    /// Outward unit vector as (x = column, y = row), rows growing southwards.
+    fn outward(self) -> (f64, f64) {
+        match self {
+            Side::North => (0.0, -1.0),
+            Side::East => (1.0, 0.0),
+            Side::South => (0.0, 1.0),
+            Side::West => (-1.0, 0.0),
+        }
+    }
+
+    /// Stop-line point of traffic entering the junction from this side.
+    fn entry_point(self) -> (f64, f64) {
+        let (ox, oy) = self.outward();
+        (ox + LANE_OFFSET * oy, oy - LANE_OFFSET * ox)
+    }
+
+    /// Point where traffic leaves the junction through this side.
+    fn exit_point(self) -> (f64, f64) {
+        let (ox, oy) = self.outward();
+        (ox - LANE_OFFSET * oy, oy + LANE_OFFSET * ox)
+    }
+}
+
+/// Movement of traffic entering the junction from side `from` and leaving
+/// through side `to`. Rows grow southwards, so a clockwise turn is a right turn.
+fn turn(from: Side, to: Side) -> Movement {
+    if from == to {
+        return Movement::LeftTurn;
+    }
+    let (fx, fy) = from.outward();
+    let (tx, ty) = to.outward();
+    // Heading in is the reverse of `from`'s outward vector.
+    let cross = (-fx) * ty - (-fy) * tx;
+    if cross > 0.0 {
+        Movement::RightTurn
+    } else if cross < 0.0 {
+        Movement::LeftTurn
+    } else {
+        Movement::Straight
+    }
+}
+
+/// Distance of a lane's centerline from the road's centerline, relative to
+/// the half-width of the junction box.
+const LANE_OFFSET: f64 = 0.3;
+
+fn coords(network: &Network, inter: u32) -> (i32, i32) {
+    network.coords(inter).unwrap_or_else(|| panic!("intersection {} is not in the network", inter))
+}
+
+/// Side of `junction` that faces intersection `other`.
+fn side_towards(network: &Network, junction: u32, other: u32) -> Side {
+    let (jr, jc) = coords(network, junction);
+    let (or, oc) = coords(network, other);
+    if or < jr {
+        Side::North
+    } else if or > jr {
+        Side::South
+    } else if oc < jc {
+        Side::West
+    } else {
+        Side::East
+    }
+}
+
+/// Sides of `junction` on the edge of the network, where boundary lanes attach.
+fn open_sides(network: &Network, junction: u32) -> Vec<Side> {
+    let (row, col) = coords(network, junction);
+    let ((min_row, min_col), (max_row, max_col)) = network.bounds();
+    let mut sides = Vec::new();
+    if row == min_row {
+        sides.push(Side::North);
+    }
+    if col == max_col {
+        sides.push(Side::East);
+    }
+    if row == max_row {
+        sides.push(Side::South);
+    }
+    if col == min_col {
+        sides.push(Side::West);
+    }
+    if sides.is_empty() {
+        sides.push(Side::North);
+    }
+    sides
+}
+
+/// Assigns a side to every lane arriving at (`arriving == true`) or leaving
+/// `junction`. Boundary lanes have no intersection on their far end, so they
+/// are spread over the junction's open sides in id order.
+fn lane_sides(network: &Network, junction: u32, lanes: &[Lane], arriving: bool) -> Vec<(u32, Side)> {
+    let mut boundary: Vec<&Lane> = Vec::new();
+    let mut sides = Vec::new();
+    for lane in lanes {
+        let (near, far) = if arriving {
+            (lane.end_intersection, lane.start_intersection)
+        } else {
+            (lane.start_intersection, lane.end_intersection)
+        };
+        if near != junction {
+            continue;
+        }
+        if far == 0 || lane.category != LaneCategory::Internal {
+            boundary.push(lane);
+        } else {
+            sides.push((lane.id, side_towards(network, junction, far)));
+        }
+    }
+    boundary.sort_by_key(|lane| lane.id);
+    let open = open_sides(network, junction);
+    for (i, lane) in boundary.into_iter().enumerate() {
+        sides.push((lane.id, open[i % open.len()]));
+    }
+    sides
+}
+
+type Point = (f64, f64);
+
+fn orientation(a: Point, b: Point, c: Point) -> f64 {
+    (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+}
+
+/// True if segments p1-p2 and q1-q2 cross at a point interior to both.
+fn segments_cross(p1: Point, p2: Point, q1: Point, q2: Point) -> bool {
+    const EPS: f64 = 1e-9;
+    let d1 = orientation(q1, q2, p1);
+    let d2 = orientation(q1, q2, p2);
+    let d3 = orientation(p1, p2, q1);
+    let d4 = orientation(p1, p2, q2);
+    ((d1 > EPS && d2 < -EPS) || (d1 < -EPS && d2 > EPS))
+        && ((d3 > EPS && d4 < -EPS) || (d3 < -EPS && d4 > EPS))
+}
+
+/// An approach lane with the exits its traffic may take.
+struct Approach {
+    lane_id: u32,
+    side: Side,
+    exits: Vec<Side>,
+}
+
+impl Approach {
+    /// Reserved for left turns, so signalled in a protected left-turn phase.
+    fn left_only(&self) -> bool {
+        !self.exits.is_empty() && self.exits.iter().all(|&exit| turn(self.side, exit) == Movement::LeftTurn)
+    }
+}
+
+fn movements_conflict(a_from: Side, a_to: Side, b_from: Side, b_to: Side) -> bool {
+    if a_to == b_to {
+        return true;
+    }
+    segments_cross(a_from.entry_point(), a_to.exit_point(), b_from.entry_point(), b_to.exit_point())
+}
+
+/// How two movements through a junction get along on the same green.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// Their paths neither cross nor merge.
+    None,
+    /// They merge, and one is a right turn, which yields: both may go on
+    /// the same green.
+    Yield,
+    /// They cross or merge, and must go on different greens.
+    Block,
+}
+
+fn movement_conflict(a_from: Side, a_to: Side, b_from: Side, b_to: Side) -> Conflict {
+    if !movements_conflict(a_from, a_to, b_from, b_to) {
+        return Conflict::None;
+    }
+    let merge_only = !segments_cross(a_from.entry_point(), a_to.exit_point(), b_from.entry_point(), b_to.exit_point());
+    if merge_only && (turn(a_from, a_to) == Movement::RightTurn || turn(b_from, b_to) == Movement::RightTurn) {
+        Conflict::Yield
+    } else {
+        Conflict::Block
+    }
+}
+
+fn approaches_conflict(a: &Approach, b: &Approach) -> bool {
+    a.exits.iter().any(|&a_to| {
+        b.exits.iter().any(|&b_to| movement_conflict(a.side, a_to, b.side, b_to) == Conflict::Block)
+    })
+}
+
+/// One movement through a junction: from an approach lane out through a side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovementRef {
+    /// The approach lane.
+    pub lane_id: u32,
+    /// Which way it turns.
+    pub movement: Movement,
+    from: Side,
+    to: Side,
+}
+
+/// The conflicts between every pair of movements through a junction, as the
+/// phase plan weighs them.
+#[derive(Debug, Clone)]
+pub struct ConflictMatrix {
+    /// Movements by approach lane, then exit side.
+    pub movements: Vec<MovementRef>,
+    /// `conflicts[i][j]` is how `movements[i]` and `movements[j]` get along.
+    pub conflicts: Vec<Vec<Conflict>>,
+}
+
+impl ConflictMatrix {
+    fn from_approaches(approaches: &[Approach]) -> ConflictMatrix {
+        let mut movements: Vec<MovementRef> = approaches
+            .iter()
+            .flat_map(|a| {
+                a.exits.iter().map(|&to| MovementRef { lane_id: a.lane_id, movement: turn(a.side, to), from: a.side, to })
+            })
+            .collect();
+        movements.sort_by_key(|m| (m.lane_id, m.to));
+        let conflicts = movements
+            .iter()
+            .map(|a| {
+                movements
+                    .iter()
+                    .map(|b| if a.lane_id == b.lane_id { Conflict::None } else { movement_conflict(a.from, a.to, b.from, b.to) })
+                    .collect()
+            })
+            .collect();
+        ConflictMatrix { movements, conflicts }
+    }
+}
+
+/// One row per movement, e.g. `1003 left: X at 1005 straight, yields to ...`.
+impl fmt::Display for ConflictMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |m: &MovementRef| {
+            let movement = match m.movement {
+                Movement::Straight => "straight",
+                Movement::LeftTurn => "left",
+                Movement::RightTurn => "right",
+            };
+            format!("{} {}", m.lane_id, movement)
+        };
+        for (i, movement) in self.movements.iter().enumerate() {
+            let with = |kind: Conflict| -> Vec<String> {
+                self.movements.iter().zip(&self.conflicts[i]).filter(|&(_, &c)| c == kind).map(|(m, _)| name(m)).collect()
+            };
+            let (blocks, yields) = (with(Conflict::Block), with(Conflict::Yield));
+            write!(f, "{}: blocks [{}]", name(movement), blocks.join(", "))?;
+            if !yields.is_empty() {
+                write!(f, ", merges permissively with [{}]", yields.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The approaches of `junction`, with the exits their traffic may take.
+fn approaches(junction: u32, lanes: &[Lane], network: &Network) -> Vec<Approach> {
+    let mut exit_sides: Vec<Side> = lane_sides(network, junction, lanes, false)
+        .into_iter()
+        .map(|(_, side)| side)
+        .collect();
+    exit_sides.sort();
+    exit_sides.dedup();
+
+    let reserved = |lane_id: u32| lanes.iter().find(|lane| lane.id == lane_id).and_then(|lane| lane.movement);
+    let mut approaches: Vec<Approach> = lane_sides(network, junction, lanes, true)
+        .into_iter()
+        .map(|(lane_id, side)| Approach {
+            lane_id,
+            side,
+            exits: exit_sides
+                .iter()
+                .copied()
+                .filter(|&exit| exit != side)
+                .filter(|&exit| reserved(lane_id).is_none_or(|movement| turn(side, exit) == movement))
+                .collect(),
+        })
+        .collect();
+    approaches.sort_by_key(|a| (a.side, a.lane_id));
+    approaches
+}
+
+/// The conflict matrix of the movements through `junction` (see the module
+/// comment). `lanes` is the whole network.
+pub fn conflict_matrix(junction: u32, lanes: &[Lane], network: &Network) -> ConflictMatrix {
+    ConflictMatrix::from_approaches(&approaches(junction, lanes, network))
+}
+
+/// Builds the phase plan for `junction`. `lanes` is the whole network: lanes
+/// ending at the junction are the approaches to signal, lanes starting there
+/// are the exits their traffic can take, and `network` is built from them.
+/// Every approach appears in exactly one phase, no two approaches in a phase
+/// have movements that block each other, and protected left-turn phases lead
+/// the through phases of their sides.
+pub fn build_phase_plan(junction: u32, lanes: &[Lane], network: &Network) -> Vec<Phase> {
+    let mut approaches = approaches(junction, lanes, network);
+    // Through traffic is grouped first, so left-turn lanes form phases of their own.
+    approaches.sort_by_key(|a| (a.left_only(), a.side, a.lane_id));
+
+    // Greedy first fit: each approach joins the first phase it is compatible with.
+    let mut phases: Vec<Vec<&Approach>> = Vec::new();
+    for approach in &approaches {
+        match phases
+            .iter_mut()
+            .find(|phase| phase.iter().all(|other| !approaches_conflict(approach, other)))
+        {
+            Some(phase) => phase.push(approach),
+            None => phases.push(vec![approach]),
+        }
+    }
+
+    // Each protected left-turn phase leads the first through phase that
+    // serves one of its sides.
+    let (lefts, mut ordered): (Vec<_>, Vec<_>) =
+        phases.into_iter().partition(|phase| phase.iter().all(|a| a.left_only()));
+    for left in lefts {
+        let at = ordered
+            .iter()
+            .position(|phase| phase.iter().any(|a| !a.left_only() && left.iter().any(|l| l.side == a.side)))
+            .unwrap_or(ordered.len());
+        ordered.insert(at, left);
+    }
+
+    ordered
+        .into_iter()
+        .map(|phase| Phase { lanes: phase.iter().map(|a| a.lane_id).collect() })
+        .collect()
+}
+
+/// Movement a vehicle makes at `junction` going from `approach` (a lane ending
+/// there) into `exit` (a lane starting there): the approach's reserved movement
+/// if it has one, otherwise the turn between the sides of the junction the two
+/// lanes are on, placed as in the phase plan. None if either lane does not
+/// meet the junction. `lanes` is the whole network.
+pub fn movement(junction: u32, approach: &Lane, exit: &Lane, lanes: &[Lane], network: &Network) -> Option<Movement> {
+    if approach.end_intersection != junction || exit.start_intersection != junction {
+        return None;
+    }
+    if let Some(reserved) = approach.movement {
+        return Some(reserved);
+    }
+    let side_of = |lane_id: u32, arriving: bool| {
+        lane_sides(network, junction, lanes, arriving)
+            .into_iter()
+            .find(|&(id, _)| id == lane_id)
+            .map(|(_, side)| side)
+    };
+    Some(turn(side_of(approach.id, true)?, side_of(exit.id, false)?))
+}
+
+/// Sides of a junction its lanes meet it on, for telling whether the
+/// movements of two vehicles inside the junction conflict.
+#[derive(Debug, Clone)]
+pub struct JunctionGeometry {
+    arriving: HashMap<u32, Side>,
+    leaving: HashMap<u32, Side>,
+}
+
+impl JunctionGeometry {
+    /// Geometry of `junction`, placing its lanes as the phase plan does.
+    /// `lanes` is the whole network.
+    pub fn new(junction: u32, lanes: &[Lane], network: &Network) -> JunctionGeometry {
+        JunctionGeometry {
+            arriving: lane_sides(network, junction, lanes, true).into_iter().collect(),
+            leaving: lane_sides(network, junction, lanes, false).into_iter().collect(),
+        }
+    }
+
+    /// True if a vehicle going from approach lane `a.0` into exit lane `a.1`
+    /// and one going from `b.0` into `b.1` cross or merge inside the junction.
+    /// Vehicles from the same approach follow each other and never conflict;
+    /// a lane that does not meet the junction conflicts with everything.
+    pub fn conflict(&self, a: (u32, u32), b: (u32, u32)) -> bool {
+        if a.0 == b.0 {
+            return false;
+        }
+        let sides = (self.arriving.get(&a.0), self.leaving.get(&a.1), self.arriving.get(&b.0), self.leaving.get(&b.1));
+        match sides {
+            (Some(&a_from), Some(&a_to), Some(&b_from), Some(&b_to)) => movements_conflict(a_from, a_to, b_from, b_to),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network_builder::{BuiltNetwork, NetworkBuilder};
+    use crate::network_file::{NetworkFile, DEFAULT_NETWORK};
+
+    /// A lone junction with an entry and an exit on each side: lanes in are
+    /// 1000 (north), 1001 (east), 1002 (south) and 1003 (west), each
+    /// reserved for `movement` if given.
+    fn crossroads(movement: Option<Movement>) -> NetworkBuilder {
+        let mut builder = NetworkBuilder::new();
+        builder.junction(1);
+        for _ in 0..4 {
+            let entry = builder.entry(1);
+            if let Some(movement) = movement {
+                entry.movement(movement);
+            }
+        }
+        for _ in 0..4 {
+            builder.exit(1);
+        }
+        builder
+    }
+
+    /// Lane pairs of `phases` whose movements block each other.
+    fn blocked_pairs(junction: u32, lanes: &[Lane], network: &Network, phases: &[Phase]) -> Vec<(u32, u32)> {
+        let matrix = conflict_matrix(junction, lanes, network);
+        let mut blocked = Vec::new();
+        for (i, a) in matrix.movements.iter().enumerate() {
+            for (j, b) in matrix.movements.iter().enumerate() {
+                let together = phases.iter().any(|phase| phase.lanes.contains(&a.lane_id) && phase.lanes.contains(&b.lane_id));
+                if together && matrix.conflicts[i][j] == Conflict::Block {
+                    blocked.push((a.lane_id, b.lane_id));
+                }
+            }
+        }
+        blocked
+    }
+
+    #[test]
+    fn no_phase_of_the_built_in_grid_holds_crossing_paths() {
+        let lanes = NetworkFile::parse(DEFAULT_NETWORK).unwrap().lanes();
+        let network = Network::from_lanes(&lanes);
+        for junction in network.intersections() {
+            let phases = build_phase_plan(junction, &lanes, &network);
+            assert_eq!(blocked_pairs(junction, &lanes, &network, &phases), [], "junction {}: {:?}", junction, phases);
+            // Every approach, boundary lanes included, is served exactly once.
+            let mut served: Vec<u32> = phases.iter().flat_map(|phase| phase.lanes.clone()).collect();
+            served.sort();
+            let mut approaches: Vec<u32> = lanes.iter().filter(|l| l.end_intersection == junction).map(|l| l.id).collect();
+            approaches.sort();
+            assert_eq!(served, approaches, "junction {}", junction);
+        }
+    }
+
+    #[test]
+    fn boundary_lanes_take_the_open_sides() {
+        let BuiltNetwork { lanes, network, .. } = crossroads(None).build().unwrap();
+        let sides: Vec<(u32, Side)> = lane_sides(&network, 1, &lanes, true);
+        assert_eq!(sides, [(1000, Side::North), (1001, Side::East), (1002, Side::South), (1003, Side::West)]);
+    }
+
+    #[test]
+    fn opposing_mixed_approaches_never_share_a_green() {
+        // Left turns from either side cross the other's through traffic.
+        let BuiltNetwork { lanes, network, .. } = crossroads(None).build().unwrap();
+        let phases = build_phase_plan(1, &lanes, &network);
+        assert_eq!(blocked_pairs(1, &lanes, &network, &phases), []);
+        for phase in &phases {
+            assert!(!(phase.lanes.contains(&1000) && phase.lanes.contains(&1002)), "{:?}", phases);
+            assert!(!(phase.lanes.contains(&1001) && phase.lanes.contains(&1003)), "{:?}", phases);
+        }
+    }
+
+    #[test]
+    fn opposing_through_lanes_go_together() {
+        let BuiltNetwork { lanes, network, .. } = crossroads(Some(Movement::Straight)).build().unwrap();
+        let phases = build_phase_plan(1, &lanes, &network);
+        assert_eq!(phases, [Phase { lanes: vec![1000, 1002] }, Phase { lanes: vec![1001, 1003] }]);
+    }
+
+    #[test]
+    fn left_turn_lanes_lead_the_through_phase_of_their_side() {
+        let mut builder = crossroads(Some(Movement::Straight));
+        // Left-turn lanes 1008 and 1009, from the north and the south.
+        builder.entry(1).movement(Movement::LeftTurn);
+        builder.entry(1).movement(Movement::LeftTurn);
+        let BuiltNetwork { lanes, network, .. } = builder.build().unwrap();
+        let phases = build_phase_plan(1, &lanes, &network);
+        assert_eq!(blocked_pairs(1, &lanes, &network, &phases), []);
+        let position = |lane_id: u32| phases.iter().position(|phase| phase.lanes.contains(&lane_id)).unwrap();
+        assert!(position(1008) < position(1000) || position(1008) < position(1001), "{:?}", phases);
+    }
+
+    #[test]
+    fn crossing_paths_block_and_right_turn_merges_yield() {
+        // North to south crosses east to west.
+        assert_eq!(movement_conflict(Side::North, Side::South, Side::East, Side::West), Conflict::Block);
+        // Opposing through traffic keeps to its own side.
+        assert_eq!(movement_conflict(Side::North, Side::South, Side::South, Side::North), Conflict::None);
+        // A right turn from the west merges with through traffic from the
+        // north into the south exit, and yields.
+        assert_eq!(turn(Side::West, Side::South), Movement::RightTurn);
+        assert_eq!(movement_conflict(Side::North, Side::South, Side::West, Side::South), Conflict::Yield);
+        // A left turn across oncoming traffic blocks it.
+        assert_eq!(movement_conflict(Side::North, Side::East, Side::South, Side::North), Conflict::Block);
+    }
+}
@@ -0,0 +1,176 @@
+// progress.rs
+//
+// Where cars are between their generation and completion events. The
+// simulations report every lane a car enters and leaves as a progress event
+// with the lane's place in the trip: 0 is the entry lane, 1..=n the n lanes
+// of the route, and n + 1 the exit lane, so a car with an empty route still
+// enters and leaves the grid. A car keeps its slot on a lane until it has
+// secured the next one, so it enters the next lane before it leaves the
+// previous one.
+//
+// PositionEstimator turns those events into an approximate position: the lane
+// a car last entered and how far along it the car is, assuming it has driven
+// at its generated speed since entering. A car held at a light therefore
+// shows further along than it is, up to the end of the lane.
+//
+// A simulation that knows where its cars are keeps a LaneProgress for each
+// instead: the meters the car has covered of its lane and its speed, moved
+// on as it drives (see `car_following`), so its progress along the lane can
+// be read off at any tick.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::lanes::Lane;
+
+/// Whether a progress event reports a car entering or leaving a lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LaneTransition {
+    /// The car has claimed the lane.
+    Entered,
+    /// The car has given the lane up.
+    Exited,
+}
+
+/// Estimated position of a car on the grid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionEstimate {
+    /// The car.
+    pub car_id: u32,
+    /// Lane the car last entered.
+    pub lane_id: u32,
+    /// Place of the lane in the car's trip, 0 being its entry lane.
+    pub route_index: usize,
+    /// Meters along the lane, capped at its length; None if the car's speed
+    /// is unknown because its generation event was missed.
+    pub distance: Option<f64>,
+    /// Length of the lane in meters, 0 for a lane not in the lane list.
+    pub lane_length: f64,
+}
+
+/// The lane a car is on and when it got there.
+#[derive(Debug, Clone, Copy)]
+struct LaneEntry {
+    lane_id: u32,
+    route_index: usize,
+    entered_at: u64,
+}
+
+/// Tracks every car on the grid from its progress events.
+#[derive(Debug, Clone, Default)]
+pub struct PositionEstimator {
+    lane_lengths: HashMap<u32, f64>,
+    speeds: HashMap<u32, f64>,
+    /// Current lane of every car on the grid, by car id.
+    cars: BTreeMap<u32, LaneEntry>,
+}
+
+impl PositionEstimator {
+    /// An estimator for cars driving on `lanes`.
+    pub fn new(lanes: &[Lane]) -> PositionEstimator {
+        PositionEstimator {
+            lane_lengths: lanes.iter().map(|lane| (lane.id, lane.length)).collect(),
+            ..PositionEstimator::default()
+        }
+    }
+
+    /// Records a car's speed from its generation event.
+    pub fn generated(&mut self, car_id: u32, speed: f64) {
+        self.speeds.insert(car_id, speed);
+    }
+
+    /// Records a progress event sent at `timestamp`. Leaving a lane only
+    /// takes the car off the grid if it has not entered another one since.
+    pub fn progress(&mut self, car_id: u32, lane_id: u32, transition: LaneTransition, route_index: usize, timestamp: u64) {
+        match transition {
+            LaneTransition::Entered => {
+                self.cars.insert(car_id, LaneEntry { lane_id, route_index, entered_at: timestamp });
+            }
+            LaneTransition::Exited => {
+                if self.cars.get(&car_id).is_some_and(|entry| entry.lane_id == lane_id) {
+                    self.forget(car_id);
+                }
+            }
+        }
+    }
+
+    /// Forgets a car that has completed its journey.
+    pub fn forget(&mut self, car_id: u32) {
+        self.cars.remove(&car_id);
+        self.speeds.remove(&car_id);
+    }
+
+    /// Number of cars on the grid.
+    pub fn len(&self) -> usize {
+        self.cars.len()
+    }
+
+    /// True if no car is on the grid.
+    pub fn is_empty(&self) -> bool {
+        self.cars.is_empty()
+    }
+
+    /// Estimated position of `car_id` at simulated time `now`, or None if the
+    /// car is not on the grid.
+    pub fn position(&self, car_id: u32, now: u64) -> Option<PositionEstimate> {
+        let entry = self.cars.get(&car_id)?;
+        let lane_length = self.lane_lengths.get(&entry.lane_id).copied().unwrap_or(0.0);
+        let distance = self.speeds.get(&car_id).map(|&speed| {
+            let elapsed = now.saturating_sub(entry.entered_at) as f64;
+            (speed * elapsed).min(lane_length)
+        });
+        Some(PositionEstimate { car_id, lane_id: entry.lane_id, route_index: entry.route_index, distance, lane_length })
+    }
+
+    /// Estimated positions of every car on the grid, by car id.
+    pub fn positions(&self, now: u64) -> Vec<PositionEstimate> {
+        self.cars.keys().filter_map(|&car_id| self.position(car_id, now)).collect()
+    }
+}
+
+/// A car's progress along the lane it is on, as a simulation drives it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LaneProgress {
+    /// The lane.
+    pub lane_id: u32,
+    /// Place of the lane in the car's trip, 0 being its entry lane.
+    pub route_index: usize,
+    /// Meters from the start of the lane to its stop line.
+    pub length: f64,
+    /// Meters of the lane behind the car's front.
+    pub distance: f64,
+    /// The car's speed, in m/s.
+    pub speed: f64,
+    /// Whether the car has stopped at the stop line to wait.
+    pub waiting: bool,
+}
+
+impl LaneProgress {
+    /// A car that has just entered a lane of `length` at `speed`.
+    pub fn entered(lane_id: u32, route_index: usize, length: f64, speed: f64) -> LaneProgress {
+        LaneProgress { lane_id, route_index, length, distance: 0.0, speed, waiting: false }
+    }
+
+    /// Moves the car `meters` on, up to the stop line, now at `speed`.
+    pub fn advance(&mut self, meters: f64, speed: f64) {
+        self.distance = (self.distance + meters).min(self.length);
+        self.speed = speed;
+    }
+
+    /// Meters left to the stop line.
+    pub fn remaining(&self) -> f64 {
+        self.length - self.distance
+    }
+
+    /// Share of the lane behind the car, from 0.0 at its start to 1.0 at
+    /// the stop line.
+    pub fn progress(&self) -> f64 {
+        if self.length > 0.0 {
+            (self.distance / self.length).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
+}
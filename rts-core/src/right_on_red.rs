@@ -0,0 +1,74 @@
+// right_on_red.rs
+//
+// Right turns on red. A vehicle turning right does not cross oncoming
+// traffic, only merges into the lane it turns into, so it may go on a red
+// light when that lane has room to spare: fewer vehicles than
+// RIGHT_ON_RED_MAX_LOAD of its capacity. Every other movement waits for
+// green, and the phase plan is unchanged; a red turn yields to whatever the
+// green phase is sending into the same lane through that occupancy check.
+// Amber means stop for every movement: a right turn waits for the red.
+
+use crate::lanes::{Lane, Movement};
+use crate::messages::LightColor;
+
+/// Share of the target lane's capacity below which a right turn may go on red.
+pub const RIGHT_ON_RED_MAX_LOAD: f64 = 0.5;
+
+/// True if a lane holding `occupancy` vehicles is clear enough for a vehicle
+/// to turn into it on red.
+pub fn clear_to_merge(occupancy: u32, target: &Lane) -> bool {
+    (occupancy as f64) < RIGHT_ON_RED_MAX_LOAD * target.capacity as f64
+}
+
+/// True if a vehicle making `movement` under a `color` light may enter
+/// `target`, which currently holds `target_occupancy` vehicles.
+pub fn may_proceed(color: LightColor, movement: Movement, target_occupancy: u32, target: &Lane) -> bool {
+    match color {
+        LightColor::Green => true,
+        LightColor::Amber => false,
+        LightColor::Red => movement == Movement::RightTurn && clear_to_merge(target_occupancy, target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lanes::LaneCategory;
+
+    fn lane_of(capacity: u32) -> Lane {
+        Lane { id: 1, start_intersection: 1, end_intersection: 2, length: 100.0, capacity, parallel_count: 1, movement: None, category: LaneCategory::Internal }
+    }
+
+    #[test]
+    fn right_turn_on_red_needs_the_target_under_half_full() {
+        let target = lane_of(10);
+        for occupancy in 0..5 {
+            assert!(may_proceed(LightColor::Red, Movement::RightTurn, occupancy, &target), "{} of 10", occupancy);
+        }
+        for occupancy in 5..=12 {
+            assert!(!may_proceed(LightColor::Red, Movement::RightTurn, occupancy, &target), "{} of 10", occupancy);
+        }
+        // Half of an odd capacity: 3 of 7 is under, 4 of 7 is not.
+        assert!(clear_to_merge(3, &lane_of(7)));
+        assert!(!clear_to_merge(4, &lane_of(7)));
+        // A lane without room never takes a red turn.
+        assert!(!clear_to_merge(0, &lane_of(0)));
+    }
+
+    #[test]
+    fn straight_and_left_never_go_on_red() {
+        let target = lane_of(10);
+        for movement in [Movement::Straight, Movement::LeftTurn] {
+            assert!(!may_proceed(LightColor::Red, movement, 0, &target), "{:?}", movement);
+            assert!(may_proceed(LightColor::Green, movement, 10, &target), "{:?}", movement);
+        }
+    }
+
+    #[test]
+    fn amber_stops_every_movement() {
+        let target = lane_of(10);
+        for movement in [Movement::Straight, Movement::LeftTurn, Movement::RightTurn] {
+            assert!(!may_proceed(LightColor::Amber, movement, 0, &target), "{:?}", movement);
+        }
+    }
+}
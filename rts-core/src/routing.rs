@@ -0,0 +1,537 @@
+// routing.rs
+//
+// Shortest-path routing over lanes, with A*. What a lane costs is up to an
+// `EdgeCost`: its length (`Distance`), the time to drive it (`TravelTime`),
+// or either plus a per-lane penalty (`Congestion`), which callers use to
+// steer cars away from congested lanes. The search is guided by the straight
+// line from each intersection to the destination on the network's grid,
+// scaled by the shortest lane per grid unit so it never overestimates.
+// With dynamic routing, cars also re-plan the rest of their route at every
+// intersection from the lane counts at that moment.
+//
+// So that cars between the same entry and exit don't all pile onto one
+// corridor, `k_shortest_routes` finds the k cheapest loopless routes (Yen's
+// algorithm) and `route_choice_weights` says how likely a car is to take
+// each: the cheapest most often, one ROUTE_CHOICE_SENSITIVITY-th dearer
+// about e times less often.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+
+use crate::lanes::Lane;
+use crate::network::Network;
+
+/// Extra cost, in meters, for every vehicle currently on a lane.
+pub const CONGESTION_PENALTY_PER_VEHICLE: f64 = 50.0;
+
+/// Extra cost, in meters, for a lane under a reroute advisory: more than any
+/// detour across the grid, but finite so a car with no other way still gets a route.
+pub const ADVISED_LANE_PENALTY: f64 = 100_000.0;
+
+/// Cost of a closed lane: infinite, so routes never use it.
+pub const CLOSED_LANE_COST: f64 = f64::INFINITY;
+
+/// Routes a car chooses among unless RTS_ROUTE_CHOICES says otherwise.
+pub const DEFAULT_ROUTE_CHOICES: usize = 3;
+
+/// How strongly route choice favours the cheapest route: a route that costs
+/// 1/ROUTE_CHOICE_SENSITIVITY more than it is e times less likely to be taken.
+pub const ROUTE_CHOICE_SENSITIVITY: f64 = 10.0;
+
+/// Why a route could not be computed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    /// Both intersections exist but no chain of lanes connects them.
+    NoPath {
+        /// Intersection the route was to start from.
+        start: u32,
+        /// Intersection the route was to reach.
+        end: u32,
+    },
+    /// The intersection is not part of the network.
+    InvalidIntersection(u32),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NoPath { start, end } => {
+                write!(f, "no path from intersection {} to intersection {}", start, end)
+            }
+            RouteError::InvalidIntersection(id) => write!(f, "intersection {} does not exist", id),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// What driving a lane costs, for `find_route`.
+pub trait EdgeCost {
+    /// Cost of driving `lane`; infinite if it must not be used.
+    fn cost(&self, lane: &Lane) -> f64;
+
+    /// Lower bound of the cost of a meter of any lane, which scales the
+    /// search's estimate of the cost left to the destination.
+    fn min_cost_per_meter(&self) -> f64;
+}
+
+/// Lanes cost their length in meters.
+#[derive(Debug, Clone, Copy)]
+pub struct Distance;
+
+impl EdgeCost for Distance {
+    fn cost(&self, lane: &Lane) -> f64 {
+        lane.length
+    }
+
+    fn min_cost_per_meter(&self) -> f64 {
+        1.0
+    }
+}
+
+/// Lanes cost the seconds a car at `speed` takes to drive them.
+#[derive(Debug, Clone, Copy)]
+pub struct TravelTime {
+    /// Meters per second.
+    pub speed: f64,
+}
+
+impl EdgeCost for TravelTime {
+    fn cost(&self, lane: &Lane) -> f64 {
+        lane.length / self.speed
+    }
+
+    fn min_cost_per_meter(&self) -> f64 {
+        1.0 / self.speed
+    }
+}
+
+/// Lanes cost what `base` says plus their entry in `weights`, a penalty in
+/// meters (see `congestion_weights`), valued as that many meters of `base`.
+#[derive(Debug, Clone, Copy)]
+pub struct Congestion<'a, C> {
+    /// Cost of the lane itself.
+    pub base: C,
+    /// Penalty of each lane, in meters; lanes left out have none.
+    pub weights: &'a HashMap<u32, f64>,
+}
+
+impl<C: EdgeCost> EdgeCost for Congestion<'_, C> {
+    fn cost(&self, lane: &Lane) -> f64 {
+        let penalty = self.weights.get(&lane.id).copied().unwrap_or(0.0);
+        self.base.cost(lane) + penalty * self.base.min_cost_per_meter()
+    }
+
+    fn min_cost_per_meter(&self) -> f64 {
+        self.base.min_cost_per_meter()
+    }
+}
+
+#[derive(Debug)]
+struct LaneState {
+    /// Cost so far plus the estimate of the cost left; the heap's order.
+    estimate: f64,
+    cost: f64,
+    position: u32,
+}
+impl Eq for LaneState {}
+impl PartialEq for LaneState {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+impl Ord for LaneState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+impl PartialOrd for LaneState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Computes the shortest lane route from `start` to `end`. A lane costs its
+/// length plus its entry in `weights`, if any; a lane weighted
+/// CLOSED_LANE_COST is never used. See `find_route`.
+pub fn find_lane_path(
+    start: u32,
+    end: u32,
+    lanes: &[Lane],
+    network: &Network,
+    weights: Option<&HashMap<u32, f64>>,
+) -> Result<Vec<Lane>, RouteError> {
+    match weights {
+        Some(weights) => find_route(start, end, lanes, network, &Congestion { base: Distance, weights }),
+        None => find_route(start, end, lanes, network, &Distance),
+    }
+}
+
+/// Straight-line distance between two intersections in grid units.
+fn grid_distance(network: &Network, a: u32, b: u32) -> Option<f64> {
+    let ((ar, ac), (br, bc)) = (network.coords(a)?, network.coords(b)?);
+    Some(f64::from(ar - br).hypot(f64::from(ac - bc)))
+}
+
+/// Meters per grid unit no lane of `lanes` beats, so that a straight line
+/// on the grid never overestimates the length of a route. Zero if no lane
+/// joins two positioned intersections, which turns the estimate off.
+fn meters_per_grid_unit(lanes: &[Lane], network: &Network) -> f64 {
+    lanes
+        .iter()
+        .filter_map(|lane| {
+            let span = grid_distance(network, lane.start_intersection, lane.end_intersection)?;
+            (span > 0.0).then(|| lane.length / span)
+        })
+        .reduce(f64::min)
+        .unwrap_or(0.0)
+}
+
+/// Computes the cheapest lane route from `start` to `end` with A*, each lane
+/// costing what `cost` says; a lane of infinite cost is never used.
+/// `lanes` may be any subset of `network`'s lanes. Returns an empty route
+/// when `start == end`.
+pub fn find_route(
+    start: u32,
+    end: u32,
+    lanes: &[Lane],
+    network: &Network,
+    cost: &impl EdgeCost,
+) -> Result<Vec<Lane>, RouteError> {
+    for inter in [start, end] {
+        if !network.contains(inter) {
+            return Err(RouteError::InvalidIntersection(inter));
+        }
+    }
+    if start == end {
+        return Ok(Vec::new());
+    }
+
+    let mut dist: HashMap<u32, f64> = HashMap::new();
+    let mut prev: HashMap<u32, (u32, &Lane)> = HashMap::new();
+    let mut heap = BinaryHeap::new();
+    let scale = meters_per_grid_unit(lanes, network) * cost.min_cost_per_meter();
+    let remaining = |inter: u32| grid_distance(network, inter, end).map_or(0.0, |units| units * scale);
+
+    for inter in network.intersections() {
+        dist.insert(inter, f64::INFINITY);
+    }
+    dist.insert(start, 0.0);
+    heap.push(LaneState { estimate: remaining(start), cost: 0.0, position: start });
+
+    let mut lane_map: HashMap<u32, Vec<&Lane>> = HashMap::new();
+    for lane in lanes {
+        lane_map.entry(lane.start_intersection).or_default().push(lane);
+    }
+
+    while let Some(LaneState { cost: so_far, position, .. }) = heap.pop() {
+        if position == end {
+            break;
+        }
+        if so_far > dist[&position] {
+            continue;
+        }
+        if let Some(neighbor_lanes) = lane_map.get(&position) {
+            for &lane in neighbor_lanes {
+                let next = lane.end_intersection;
+                let next_cost = so_far + cost.cost(lane);
+                if next_cost < *dist.get(&next).unwrap_or(&f64::INFINITY) {
+                    dist.insert(next, next_cost);
+                    prev.insert(next, (position, lane));
+                    heap.push(LaneState { estimate: next_cost + remaining(next), cost: next_cost, position: next });
+                }
+            }
+        }
+    }
+
+    if dist[&end] == f64::INFINITY {
+        return Err(RouteError::NoPath { start, end });
+    }
+
+    let mut path: Vec<Lane> = Vec::new();
+    let mut current = end;
+    while current != start {
+        let (prev_inter, lane) = prev[&current];
+        path.push(lane.clone());
+        current = prev_inter;
+    }
+    path.reverse();
+    Ok(path)
+}
+
+/// Total cost of `route` under `cost`.
+pub fn route_cost(route: &[Lane], cost: &impl EdgeCost) -> f64 {
+    route.iter().map(|lane| cost.cost(lane)).sum()
+}
+
+/// Intersections `route` from `start` passes through, in order, `start` first.
+fn route_intersections(start: u32, route: &[Lane]) -> Vec<u32> {
+    std::iter::once(start).chain(route.iter().map(|lane| lane.end_intersection)).collect()
+}
+
+fn same_lanes(a: &[Lane], b: &[Lane]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.id == b.id)
+}
+
+/// Computes up to `k` cheapest loopless lane routes from `start` to `end`
+/// with Yen's algorithm, each with its cost under `cost`, cheapest first.
+/// Fails as `find_route` does when there is no route at all.
+pub fn k_shortest_routes(
+    start: u32,
+    end: u32,
+    lanes: &[Lane],
+    network: &Network,
+    cost: &impl EdgeCost,
+    k: usize,
+) -> Result<Vec<(f64, Vec<Lane>)>, RouteError> {
+    let shortest = find_route(start, end, lanes, network, cost)?;
+    let mut found = vec![(route_cost(&shortest, cost), shortest)];
+    let mut candidates: Vec<(f64, Vec<Lane>)> = Vec::new();
+    while found.len() < k {
+        let previous = &found[found.len() - 1].1;
+        let previous_stops = route_intersections(start, previous);
+        // Deviate from the previous route at each of its intersections in turn.
+        for spur in 0..previous.len() {
+            let root = &previous[..spur];
+            let spur_node = previous_stops[spur];
+            // Lanes the routes found so far take from this root on, and the
+            // root's own intersections, are out of the deviation.
+            let taken: Vec<u32> = found
+                .iter()
+                .filter(|(_, route)| route.len() > spur && same_lanes(&route[..spur], root))
+                .map(|(_, route)| route[spur].id)
+                .collect();
+            let visited = &previous_stops[..spur];
+            let remaining: Vec<Lane> = lanes
+                .iter()
+                .filter(|lane| !taken.contains(&lane.id))
+                .filter(|lane| !visited.contains(&lane.start_intersection) && !visited.contains(&lane.end_intersection))
+                .cloned()
+                .collect();
+            let Ok(deviation) = find_route(spur_node, end, &remaining, network, cost) else { continue };
+            let route: Vec<Lane> = root.iter().cloned().chain(deviation).collect();
+            let route_total = route_cost(&route, cost);
+            if !route_total.is_finite() {
+                continue;
+            }
+            let known = found.iter().chain(&candidates).any(|(_, other)| same_lanes(other, &route));
+            if !known {
+                candidates.push((route_total, route));
+            }
+        }
+        let Some(cheapest) = candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+            .map(|(i, _)| i)
+        else {
+            break;
+        };
+        found.push(candidates.swap_remove(cheapest));
+    }
+    Ok(found)
+}
+
+/// Relative likelihood of a car taking each route of the given costs, as
+/// `exp(-ROUTE_CHOICE_SENSITIVITY * (cost / cheapest - 1))`: 1 for the
+/// cheapest, less for dearer ones.
+pub fn route_choice_weights(costs: &[f64]) -> Vec<f64> {
+    let cheapest = costs.iter().copied().fold(f64::INFINITY, f64::min);
+    costs
+        .iter()
+        .map(|&cost| {
+            if cheapest > 0.0 && cheapest.is_finite() {
+                (-ROUTE_CHOICE_SENSITIVITY * (cost / cheapest - 1.0)).exp()
+            } else if cost == cheapest {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+/// Routes a car chooses among, from RTS_ROUTE_CHOICES (at least 1; 1 always
+/// takes the cheapest); DEFAULT_ROUTE_CHOICES when it is unset, and with a
+/// warning when it is invalid.
+pub fn route_choices_from_env() -> usize {
+    match std::env::var("RTS_ROUTE_CHOICES") {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(choices) if choices >= 1 => choices,
+            _ => {
+                eprintln!("Ignoring RTS_ROUTE_CHOICES: expected a positive number of routes, got '{}'", value.trim());
+                DEFAULT_ROUTE_CHOICES
+            }
+        },
+        Err(_) => DEFAULT_ROUTE_CHOICES,
+    }
+}
+
+/// Turns per-lane vehicle counts into routing penalties.
+pub fn congestion_weights(counts: &HashMap<u32, u32>) -> HashMap<u32, f64> {
+    counts
+        .iter()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(&lane_id, &count)| (lane_id, count as f64 * CONGESTION_PENALTY_PER_VEHICLE))
+        .collect()
+}
+
+/// Congestion-aware routing is opt-in via RTS_CONGESTION_ROUTING=1.
+pub fn congestion_routing_enabled() -> bool {
+    matches!(
+        std::env::var("RTS_CONGESTION_ROUTING").as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+/// Re-planning at every intersection is opt-in via RTS_DYNAMIC_ROUTING=1.
+pub fn dynamic_routing_enabled() -> bool {
+    matches!(
+        std::env::var("RTS_DYNAMIC_ROUTING").as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lanes::LaneCategory;
+
+    fn lane(id: u32, from: u32, to: u32, length: f64) -> Lane {
+        Lane {
+            id,
+            start_intersection: from,
+            end_intersection: to,
+            length,
+            capacity: 10,
+            parallel_count: 1,
+            movement: None,
+            category: LaneCategory::Internal,
+        }
+    }
+
+    /// Four junctions on a 2×2 grid, 1 top left and 4 bottom right, with
+    /// three ways from 1 to 4: via 2 (200 m), via 2 and 3 (260 m) and via 3
+    /// (300 m).
+    fn diamond() -> (Vec<Lane>, Network) {
+        let lanes = vec![
+            lane(1000, 1, 2, 100.0),
+            lane(1001, 2, 4, 100.0),
+            lane(1002, 1, 3, 150.0),
+            lane(1003, 3, 4, 150.0),
+            lane(1004, 2, 3, 10.0),
+        ];
+        let layout = HashMap::from([(1, (0, 0)), (2, (0, 1)), (3, (1, 0)), (4, (1, 1))]);
+        let network = Network::with_layout(&lanes, &layout);
+        (lanes, network)
+    }
+
+    fn ids(route: &[Lane]) -> Vec<u32> {
+        route.iter().map(|lane| lane.id).collect()
+    }
+
+    #[test]
+    fn finds_the_shortest_route() {
+        let (lanes, network) = diamond();
+        let route = find_lane_path(1, 4, &lanes, &network, None).unwrap();
+        assert_eq!(ids(&route), [1000, 1001]);
+    }
+
+    #[test]
+    fn closed_lanes_are_routed_around() {
+        let (lanes, network) = diamond();
+        let closed = HashMap::from([(1001, CLOSED_LANE_COST)]);
+        let route = find_lane_path(1, 4, &lanes, &network, Some(&closed)).unwrap();
+        assert_eq!(ids(&route), [1000, 1004, 1003]);
+        let closed = HashMap::from([(1001, CLOSED_LANE_COST), (1004, CLOSED_LANE_COST)]);
+        let route = find_lane_path(1, 4, &lanes, &network, Some(&closed)).unwrap();
+        assert_eq!(ids(&route), [1002, 1003]);
+    }
+
+    #[test]
+    fn closing_every_way_in_leaves_no_route() {
+        let (lanes, network) = diamond();
+        let closed = HashMap::from([(1001, CLOSED_LANE_COST), (1003, CLOSED_LANE_COST)]);
+        let error = find_lane_path(1, 4, &lanes, &network, Some(&closed)).unwrap_err();
+        assert_eq!(error, RouteError::NoPath { start: 1, end: 4 });
+    }
+
+    #[test]
+    fn yen_lists_routes_cheapest_first() {
+        let (lanes, network) = diamond();
+        let routes = k_shortest_routes(1, 4, &lanes, &network, &Distance, 5).unwrap();
+        let found: Vec<(f64, Vec<u32>)> = routes.iter().map(|(cost, route)| (*cost, ids(route))).collect();
+        assert_eq!(
+            found,
+            [(200.0, vec![1000, 1001]), (260.0, vec![1000, 1004, 1003]), (300.0, vec![1002, 1003])]
+        );
+    }
+
+    #[test]
+    fn yen_stops_at_k_and_skips_closed_lanes() {
+        let (lanes, network) = diamond();
+        let routes = k_shortest_routes(1, 4, &lanes, &network, &Distance, 2).unwrap();
+        assert_eq!(routes.len(), 2);
+        let closed = HashMap::from([(1004, CLOSED_LANE_COST)]);
+        let cost = Congestion { base: Distance, weights: &closed };
+        let routes = k_shortest_routes(1, 4, &lanes, &network, &cost, 5).unwrap();
+        let found: Vec<Vec<u32>> = routes.iter().map(|(_, route)| ids(route)).collect();
+        assert_eq!(found, [vec![1000, 1001], vec![1002, 1003]]);
+    }
+
+    #[test]
+    fn same_start_and_end_is_an_empty_route() {
+        let (lanes, network) = diamond();
+        assert!(find_lane_path(2, 2, &lanes, &network, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn intersections_outside_the_network_are_invalid() {
+        let (lanes, network) = diamond();
+        let error = find_lane_path(1, 9, &lanes, &network, None).unwrap_err();
+        assert_eq!(error, RouteError::InvalidIntersection(9));
+        let error = find_lane_path(9, 4, &lanes, &network, None).unwrap_err();
+        assert_eq!(error, RouteError::InvalidIntersection(9));
+    }
+
+    #[test]
+    fn unreachable_intersections_have_no_path() {
+        let (lanes, network) = diamond();
+        // Every lane leads away from 1.
+        let error = find_lane_path(4, 1, &lanes, &network, None).unwrap_err();
+        assert_eq!(error, RouteError::NoPath { start: 4, end: 1 });
+        assert_eq!(error.to_string(), "no path from intersection 4 to intersection 1");
+    }
+
+    #[test]
+    fn congestion_steers_routes_onto_emptier_lanes() {
+        let (lanes, network) = diamond();
+        // A car on 1→2 costs 50 m more: 250 m, still cheaper than via 3.
+        let weights = congestion_weights(&HashMap::from([(1000, 1), (1003, 0)]));
+        assert_eq!(weights, HashMap::from([(1000, CONGESTION_PENALTY_PER_VEHICLE)]));
+        let route = find_lane_path(1, 4, &lanes, &network, Some(&weights)).unwrap();
+        assert_eq!(ids(&route), [1000, 1001]);
+        // Three make it 350 m.
+        let weights = congestion_weights(&HashMap::from([(1000, 3)]));
+        let route = find_lane_path(1, 4, &lanes, &network, Some(&weights)).unwrap();
+        assert_eq!(ids(&route), [1002, 1003]);
+    }
+
+    #[test]
+    fn congestion_is_valued_in_the_base_cost() {
+        let (lanes, network) = diamond();
+        let weights = congestion_weights(&HashMap::from([(1001, 1)]));
+        let cost = Congestion { base: TravelTime { speed: 10.0 }, weights: &weights };
+        // 200 m at 10 m/s plus 50 m of penalty at 10 m/s.
+        assert_eq!(route_cost(&find_route(1, 4, &lanes, &network, &cost).unwrap(), &cost), 25.0);
+    }
+
+    #[test]
+    fn cheapest_route_is_chosen_most_often() {
+        let weights = route_choice_weights(&[200.0, 220.0, 400.0]);
+        assert_eq!(weights[0], 1.0);
+        assert!((weights[1] - (-1.0f64).exp()).abs() < 1e-12);
+        assert!(weights[2] < 1e-4);
+    }
+}
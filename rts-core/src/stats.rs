@@ -0,0 +1,152 @@
+// stats.rs
+//
+// Trip time statistics for the end-of-run reports. Each deployment turns the
+// trips its vehicles completed into TripTimes, and `TripReport::from_trips`
+// gives the mean, median and 95th percentile of their wait, drive and total
+// times, over every trip and per entry lane. Percentiles are nearest-rank.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Mean of `values`, or 0 if there are none.
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Nearest-rank percentile of already sorted values, or 0 if there are none.
+pub fn percentile(sorted: &[f64], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Mean, median and 95th percentile of a set of times, in seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimeStats {
+    /// Mean time.
+    pub mean: f64,
+    /// Median time.
+    pub median: f64,
+    /// 95th percentile.
+    pub p95: f64,
+}
+
+impl TimeStats {
+    /// Statistics of `values`, all zero if there are none.
+    pub fn of(values: impl IntoIterator<Item = f64>) -> TimeStats {
+        let mut sorted: Vec<f64> = values.into_iter().collect();
+        sorted.sort_by(f64::total_cmp);
+        TimeStats { mean: mean(&sorted), median: percentile(&sorted, 50.0), p95: percentile(&sorted, 95.0) }
+    }
+}
+
+impl fmt::Display for TimeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mean {:.2}s, median {:.2}s, p95 {:.2}s", self.mean, self.median, self.p95)
+    }
+}
+
+/// Times of one completed trip, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TripTimes {
+    /// Input lane the vehicle entered the grid on.
+    pub entry_lane: u32,
+    /// Time spent waiting for lane capacity and lights.
+    pub wait: f64,
+    /// Time spent moving.
+    pub drive: f64,
+    /// Time from entering the grid to leaving it.
+    pub total: f64,
+}
+
+/// Wait, drive and total time statistics of a group of trips.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TripStats {
+    /// Trips in the group.
+    pub trips: usize,
+    /// Time spent waiting.
+    pub wait: TimeStats,
+    /// Time spent moving.
+    pub drive: TimeStats,
+    /// Time from entry to exit.
+    pub total: TimeStats,
+}
+
+impl TripStats {
+    /// Statistics of `trips`.
+    pub fn of(trips: &[TripTimes]) -> TripStats {
+        TripStats {
+            trips: trips.len(),
+            wait: TimeStats::of(trips.iter().map(|trip| trip.wait)),
+            drive: TimeStats::of(trips.iter().map(|trip| trip.drive)),
+            total: TimeStats::of(trips.iter().map(|trip| trip.total)),
+        }
+    }
+}
+
+/// Trip statistics of the vehicles that entered on one input lane.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EntryLaneStats {
+    /// The input lane.
+    pub lane_id: u32,
+    /// Statistics of its trips.
+    pub stats: TripStats,
+}
+
+/// Trip statistics of a run, overall and per entry lane.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TripReport {
+    /// Every completed trip.
+    pub overall: TripStats,
+    /// Sorted by lane id; only lanes some completed trip entered on.
+    pub by_entry_lane: Vec<EntryLaneStats>,
+}
+
+impl TripReport {
+    /// The report of `trips`.
+    pub fn from_trips(trips: &[TripTimes]) -> TripReport {
+        let mut by_lane: BTreeMap<u32, Vec<TripTimes>> = BTreeMap::new();
+        for trip in trips {
+            by_lane.entry(trip.entry_lane).or_default().push(*trip);
+        }
+        TripReport {
+            overall: TripStats::of(trips),
+            by_entry_lane: by_lane
+                .into_iter()
+                .map(|(lane_id, trips)| EntryLaneStats { lane_id, stats: TripStats::of(&trips) })
+                .collect(),
+        }
+    }
+}
+
+/// One line each for the wait, drive and total times, indented by two spaces.
+impl fmt::Display for TripStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  Wait:        {}", self.wait)?;
+        writeln!(f, "  Drive:       {}", self.drive)?;
+        write!(f, "  Total:       {}", self.total)
+    }
+}
+
+impl TripStats {
+    /// The wait, drive and total times as mean/median/p95, on one line.
+    pub fn compact(&self) -> String {
+        let compact = |times: &TimeStats| format!("{:.2}/{:.2}/{:.2}s", times.mean, times.median, times.p95);
+        format!("wait {}, drive {}, total {}", compact(&self.wait), compact(&self.drive), compact(&self.total))
+    }
+}
+
+/// The lane's trips with their wait, drive and total times as
+/// mean/median/p95, on one line.
+impl fmt::Display for EntryLaneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lane {} ({} trips): {}", self.lane_id, self.stats.trips, self.stats.compact())
+    }
+}
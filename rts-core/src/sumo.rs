@@ -0,0 +1,299 @@
+// sumo.rs
+//
+// Road networks from SUMO (https://eclipse.dev/sumo), so real city extracts
+// can be simulated: netconvert turns OpenStreetMap data into a .net.xml
+// file, and `import` turns that into a network description (see
+// network_file). Only what the lane graph needs is read:
+//
+//   <junction id="J1" type="traffic_light" x="100.0" y="200.0" .../>
+//   <edge id="E1" from="J1" to="J2" ...>
+//     <lane id="E1_0" index="0" length="98.5" .../>
+//     <lane id="E1_1" index="1" length="98.5" .../>
+//   </edge>
+//
+// Every junction becomes an intersection, numbered from 1 in file order,
+// with traffic lights if it is of one of SUMO's traffic_light types, except
+// dead ends: the fringe of the extract, which become the outside
+// (intersection 0). An edge becomes one lane, its SUMO lanes the parallel
+// lanes of it: an edge from a dead end is an input boundary lane, one into
+// a dead end an output boundary lane, and any other an internal lane. Lane
+// ids are numbered from FIRST_LANE_ID in file order. SUMO's internal edges
+// and junctions (the paths through a junction), edges between two dead ends
+// and edges that start and end at the same junction are left out, as are
+// turn restrictions: any lane into a junction may go on to any lane out.
+//
+// Rows and columns come from the junction coordinates, ranked: the junction
+// furthest north is in row 0, the furthest west in column 0, so directions
+// between junctions, and with them the turns, keep their sense.
+//
+// An edge's length is the mean of its lanes' lengths, which must all be
+// positive, and an edge of more than MAX_PARALLEL_LANES lanes counts as
+// MAX_PARALLEL_LANES. The result is checked like any network file, so an
+// extract whose lanes do not connect every junction both ways is rejected;
+// such errors name junctions by the numbers they were given.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::lanes::{LaneCategory, MAX_PARALLEL_LANES};
+use crate::network_file::{ranks, IntersectionSpec, LaneSpec, NetworkFile};
+
+/// Id of the first imported lane; the rest follow in file order.
+pub const FIRST_LANE_ID: u32 = 1000;
+
+/// A start tag and its attributes.
+#[derive(Debug)]
+struct Tag {
+    name: String,
+    attrs: HashMap<String, String>,
+    /// `<name .../>`, with no children.
+    empty: bool,
+}
+
+/// What the reader finds in the file.
+#[derive(Debug)]
+enum Node {
+    Start(Tag),
+    End(String),
+}
+
+/// Replaces the five predefined entities and numeric character references.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else { break };
+        let decoded = match &rest[1..semi] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The attributes of a start tag, from just after its name to its end.
+fn attributes(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=').ok_or_else(|| format!("attribute without a value in '{}'", text.trim()))?;
+        let name = rest[..eq].trim().to_string();
+        let after = rest[eq + 1..].trim_start();
+        let quote = after.chars().next().filter(|&c| c == '"' || c == '\'');
+        let quote = quote.ok_or_else(|| format!("unquoted value of attribute '{}'", name))?;
+        let close = after[1..].find(quote).ok_or_else(|| format!("unterminated value of attribute '{}'", name))?;
+        attrs.insert(name, unescape(&after[1..1 + close]));
+        rest = after[close + 2..].trim_start();
+    }
+    Ok(attrs)
+}
+
+/// The tags of an XML document, in order; text, comments, processing
+/// instructions and declarations are skipped.
+fn read_nodes(xml: &str) -> Result<Vec<Node>, String> {
+    let mut nodes = Vec::new();
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open..];
+        let skip_to = |end: &str| rest.find(end).map(|at| at + end.len());
+        let consumed = if rest.starts_with("<!--") {
+            skip_to("-->").ok_or("unterminated comment")?
+        } else if rest.starts_with("<![CDATA[") {
+            skip_to("]]>").ok_or("unterminated CDATA section")?
+        } else if rest.starts_with("<?") {
+            skip_to("?>").ok_or("unterminated processing instruction")?
+        } else if rest.starts_with("<!") {
+            skip_to(">").ok_or("unterminated declaration")?
+        } else {
+            let close = rest.find('>').ok_or("unterminated tag")?;
+            let inner = &rest[1..close];
+            if let Some(name) = inner.strip_prefix('/') {
+                nodes.push(Node::End(name.trim().to_string()));
+            } else {
+                let (inner, empty) = match inner.strip_suffix('/') {
+                    Some(inner) => (inner, true),
+                    None => (inner, false),
+                };
+                let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+                let name = inner[..name_end].to_string();
+                if name.is_empty() {
+                    return Err("tag without a name".to_string());
+                }
+                let attrs = attributes(&inner[name_end..]).map_err(|e| format!("<{}>: {}", name, e))?;
+                nodes.push(Node::Start(Tag { name, attrs, empty }));
+            }
+            close + 1
+        };
+        rest = &rest[consumed..];
+    }
+    Ok(nodes)
+}
+
+/// A SUMO junction.
+#[derive(Debug)]
+struct Junction {
+    id: String,
+    dead_end: bool,
+    /// Of a traffic_light type.
+    signals: bool,
+    x: f64,
+    y: f64,
+}
+
+/// A SUMO edge and the lengths of its lanes.
+#[derive(Debug)]
+struct Edge {
+    id: String,
+    from: String,
+    to: String,
+    lanes: Vec<f64>,
+}
+
+/// `tag`'s attribute `name`, which must be there.
+fn required<'a>(tag: &'a Tag, name: &str) -> Result<&'a str, String> {
+    let id = tag.attrs.get("id").map_or("", String::as_str);
+    tag.attrs.get(name).map(String::as_str).ok_or_else(|| format!("<{} id=\"{}\"> has no {}", tag.name, id, name))
+}
+
+/// `tag`'s attribute `name` as a number.
+fn number(tag: &Tag, name: &str) -> Result<f64, String> {
+    let value = required(tag, name)?;
+    value.trim().parse().map_err(|_| format!("<{}> has invalid {} '{}'", tag.name, name, value))
+}
+
+/// The junctions and edges of a SUMO network, internal ones left out.
+fn read_network(xml: &str) -> Result<(Vec<Junction>, Vec<Edge>), String> {
+    let mut junctions = Vec::new();
+    let mut edges: Vec<Edge> = Vec::new();
+    // The edge whose lanes are being read, and whether it is kept.
+    let mut in_edge: Option<bool> = None;
+    for node in read_nodes(xml)? {
+        match node {
+            Node::Start(tag) if tag.name == "junction" => {
+                if tag.attrs.get("type").map(String::as_str) == Some("internal") {
+                    continue;
+                }
+                junctions.push(Junction {
+                    id: required(&tag, "id")?.to_string(),
+                    dead_end: tag.attrs.get("type").map(String::as_str) == Some("dead_end"),
+                    signals: tag.attrs.get("type").is_some_and(|kind| kind.starts_with("traffic_light")),
+                    x: number(&tag, "x")?,
+                    y: number(&tag, "y")?,
+                });
+            }
+            Node::Start(tag) if tag.name == "edge" => {
+                let keep = tag.attrs.get("function").is_none_or(|function| function == "normal");
+                if keep {
+                    edges.push(Edge {
+                        id: required(&tag, "id")?.to_string(),
+                        from: required(&tag, "from")?.to_string(),
+                        to: required(&tag, "to")?.to_string(),
+                        lanes: Vec::new(),
+                    });
+                }
+                in_edge = (!tag.empty).then_some(keep);
+            }
+            Node::Start(tag) if tag.name == "lane" && in_edge == Some(true) => {
+                let length = number(&tag, "length")?;
+                let edge = edges.last_mut().expect("a kept edge is being read");
+                if !(length > 0.0 && length.is_finite()) {
+                    return Err(format!("lane {} of edge {} has invalid length {}", required(&tag, "id")?, edge.id, length));
+                }
+                edge.lanes.push(length);
+            }
+            Node::End(name) if name == "edge" => in_edge = None,
+            _ => {}
+        }
+    }
+    Ok((junctions, edges))
+}
+
+/// The network description of a SUMO .net.xml file, checked as
+/// `NetworkFile::validate` checks any other. Errors about a single junction,
+/// edge or lane name its SUMO id.
+pub fn import(xml: &str) -> Result<NetworkFile, String> {
+    let (junctions, edges) = read_network(xml)?;
+    let mut numbers: HashMap<&str, u32> = HashMap::new();
+    let mut dead_ends: BTreeSet<&str> = BTreeSet::new();
+    for junction in &junctions {
+        if junction.dead_end {
+            dead_ends.insert(&junction.id);
+        } else {
+            let number = numbers.len() as u32 + 1;
+            if numbers.insert(&junction.id, number).is_some() {
+                return Err(format!("junction {} is declared twice", junction.id));
+            }
+        }
+    }
+    let inner = || junctions.iter().filter(|junction| !junction.dead_end);
+    // Rows grow southwards, SUMO's y northwards.
+    let row = ranks(inner().map(|junction| -junction.y));
+    let col = ranks(inner().map(|junction| junction.x));
+    let intersections = inner()
+        .map(|junction| IntersectionSpec {
+            id: numbers[junction.id.as_str()],
+            row: row(-junction.y),
+            col: col(junction.x),
+            signals: Some(junction.signals),
+            lat: None,
+            lon: None,
+            tags: Vec::new(),
+        })
+        .collect();
+
+    let end = |junction: &str, edge: &Edge| -> Result<u32, String> {
+        match numbers.get(junction) {
+            Some(&number) => Ok(number),
+            None if dead_ends.contains(junction) => Ok(0),
+            None => Err(format!("edge {} uses undeclared junction {}", edge.id, junction)),
+        }
+    };
+    let mut lanes = Vec::new();
+    for edge in &edges {
+        if edge.lanes.is_empty() {
+            return Err(format!("edge {} has no lanes", edge.id));
+        }
+        let (from, to) = (end(&edge.from, edge)?, end(&edge.to, edge)?);
+        let category = match (from, to) {
+            (0, 0) => continue,
+            (0, _) => LaneCategory::InputBoundary,
+            (_, 0) => LaneCategory::OutputBoundary,
+            (from, to) if from == to => continue,
+            _ => LaneCategory::Internal,
+        };
+        lanes.push(LaneSpec {
+            id: FIRST_LANE_ID + lanes.len() as u32,
+            from,
+            to,
+            length: edge.lanes.iter().sum::<f64>() / edge.lanes.len() as f64,
+            category,
+            parallel_count: Some((edge.lanes.len() as u32).min(MAX_PARALLEL_LANES)),
+            capacity: None,
+            movement: None,
+            tags: Vec::new(),
+        });
+    }
+
+    NetworkFile { intersections, lanes }.validate()
+}
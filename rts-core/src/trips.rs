@@ -0,0 +1,100 @@
+// trips.rs
+//
+// Where a car enters the grid and where it leaves. A car draws an input
+// boundary lane and an output boundary lane at random, the exit by a
+// caller's destination weights where it has them (CK's scenarios), and
+// draws again while the pair would enter and leave at the same junction or
+// has no route between them, up to MAX_TRIP_DRAWS times. Every deployment
+// draws its trips this way, so a seeded car takes the same trip in each.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::lanes::{Lane, LaneCategory};
+use crate::network::Network;
+use crate::routing::find_lane_path;
+
+/// Entry/exit pairs a car draws before it gives up on finding a trip.
+pub const MAX_TRIP_DRAWS: u32 = 10;
+
+/// Boundary lanes vehicles enter and leave the grid through.
+#[derive(Debug, Clone, Default)]
+pub struct BoundaryLanes {
+    /// Input boundary lanes.
+    pub entry: Vec<Lane>,
+    /// Output boundary lanes.
+    pub exit: Vec<Lane>,
+}
+
+impl BoundaryLanes {
+    /// The boundary lanes among `lanes`.
+    pub fn from_lanes(lanes: &[Lane]) -> Self {
+        let of_category = |category: LaneCategory| -> Vec<Lane> {
+            lanes.iter().filter(|l| l.category == category).cloned().collect()
+        };
+        BoundaryLanes {
+            entry: of_category(LaneCategory::InputBoundary),
+            exit: of_category(LaneCategory::OutputBoundary),
+        }
+    }
+
+    /// The trip between two of these lanes, if both are boundary lanes here.
+    pub fn trip(&self, entry: u32, exit: u32) -> Option<Trip> {
+        let entry = self.entry.iter().find(|lane| lane.id == entry)?;
+        let exit = self.exit.iter().find(|lane| lane.id == exit)?;
+        Some(Trip { entry: entry.clone(), exit: exit.clone(), redraws: 0 })
+    }
+
+    /// These lanes with the entries narrowed to `entry_lanes`.
+    pub fn entering_on(&self, entry_lanes: &[u32]) -> BoundaryLanes {
+        BoundaryLanes {
+            entry: self.entry.iter().filter(|lane| entry_lanes.contains(&lane.id)).cloned().collect(),
+            exit: self.exit.clone(),
+        }
+    }
+}
+
+/// A car's entry and exit lanes, with the number of pairs rejected before them.
+#[derive(Debug, Clone)]
+pub struct Trip {
+    /// Lane the car enters the grid on.
+    pub entry: Lane,
+    /// Lane the car leaves the grid on.
+    pub exit: Lane,
+    /// Pairs drawn and rejected before this one.
+    pub redraws: u32,
+}
+
+/// Draws entry/exit pairs until one starts and ends at different intersections
+/// with a route between them through `internal_lanes` under `weights`. The
+/// exit is the one `destination` picks for the entry if it picks one of
+/// `boundary.exit`, drawn uniformly otherwise. Gives up after MAX_TRIP_DRAWS
+/// pairs and returns how many were drawn.
+pub fn choose_trip<R: Rng>(
+    boundary: &BoundaryLanes,
+    internal_lanes: &[Lane],
+    network: &Network,
+    weights: Option<&HashMap<u32, f64>>,
+    rng: &mut R,
+    mut destination: impl FnMut(&Lane, &mut R) -> Option<u32>,
+) -> Result<Trip, u32> {
+    for draw in 0..MAX_TRIP_DRAWS {
+        let entry = &boundary.entry[rng.random_range(0..boundary.entry.len())];
+        let exit = match destination(entry, rng).and_then(|id| boundary.exit.iter().find(|lane| lane.id == id)) {
+            Some(exit) => exit,
+            None => &boundary.exit[rng.random_range(0..boundary.exit.len())],
+        };
+        // For input lanes, end_intersection is the grid entry; for output lanes,
+        // start_intersection is the grid exit.
+        let (start, end) = (entry.end_intersection, exit.start_intersection);
+        // Entering and leaving at the same junction never meets a light.
+        if start == end {
+            continue;
+        }
+        if find_lane_path(start, end, internal_lanes, network, weights).is_ok() {
+            return Ok(Trip { entry: entry.clone(), exit: exit.clone(), redraws: draw });
+        }
+    }
+    Err(MAX_TRIP_DRAWS)
+}
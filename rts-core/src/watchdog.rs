@@ -0,0 +1,82 @@
+// watchdog.rs
+//
+// Spots junction controllers that have stopped cycling. A junction whose
+// controller dies or hangs keeps whatever colors it last set, often all red,
+// and cars queue at it forever without any error. Each junction's loop
+// reports every phase it starts to a PhaseWatch, and the controller's
+// watchdog restarts any junction that has gone STALL_CYCLES expected cycles
+// without a phase change. A phase stretched by a recommendation counts as a
+// cycle of its own length, so a long green is not taken for a stall.
+//
+// Times are whole simulated seconds, as the components' clocks report them.
+
+/// Expected cycles without a phase change before a junction counts as stalled.
+pub const STALL_CYCLES: u64 = 3;
+
+/// Seconds for a junction to serve each of its `phase_count` phases once,
+/// with `green_secs` of green and `clearance_secs` of all-red per phase.
+pub fn expected_cycle_secs(phase_count: usize, green_secs: u64, clearance_secs: u64) -> u64 {
+    phase_count.max(1) as u64 * (green_secs + clearance_secs)
+}
+
+/// Phase progress of one junction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseWatch {
+    cycle_secs: u64,
+    last_change: u64,
+    /// Green plus clearance of the phase being served.
+    phase_secs: u64,
+}
+
+impl PhaseWatch {
+    /// A watch for a junction with the given expected cycle, counting from `now`.
+    pub fn new(cycle_secs: u64, now: u64) -> PhaseWatch {
+        PhaseWatch { cycle_secs, last_change: now, phase_secs: 0 }
+    }
+
+    /// Records that a phase lasting `phase_secs` started at `now`.
+    pub fn phase_started(&mut self, now: u64, phase_secs: u64) {
+        self.last_change = now;
+        self.phase_secs = phase_secs;
+    }
+
+    /// Seconds without a phase change after which the junction is stalled.
+    pub fn limit_secs(&self) -> u64 {
+        STALL_CYCLES * self.cycle_secs.max(self.phase_secs)
+    }
+
+    /// Seconds since the last phase change if that is over the limit, None
+    /// while the junction is keeping up.
+    pub fn stalled(&self, now: u64) -> Option<u64> {
+        let silent = now.saturating_sub(self.last_change);
+        (silent > self.limit_secs()).then_some(silent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn junction_stalls_after_three_silent_cycles() {
+        // Two phases of 5s green and 4s amber and all-red.
+        let cycle = expected_cycle_secs(2, 5, 4);
+        assert_eq!(cycle, 18);
+        let mut watch = PhaseWatch::new(cycle, 100);
+        watch.phase_started(100, 9);
+        assert_eq!(watch.stalled(100 + STALL_CYCLES * cycle), None);
+        assert_eq!(watch.stalled(101 + STALL_CYCLES * cycle), Some(1 + STALL_CYCLES * cycle));
+        // A new phase resets the count.
+        watch.phase_started(200, 9);
+        assert_eq!(watch.stalled(210), None);
+    }
+
+    #[test]
+    fn long_recommended_phase_is_no_stall() {
+        let mut watch = PhaseWatch::new(18, 0);
+        // A 60s green with 4s of clearance outlasts three cycles.
+        watch.phase_started(0, 64);
+        assert_eq!(watch.limit_secs(), STALL_CYCLES * 64);
+        assert_eq!(watch.stalled(STALL_CYCLES * 18 + 1), None);
+    }
+}
@@ -0,0 +1,125 @@
+// webster.rs
+//
+// Signal timing plans by Webster's method. Each phase's flow ratio y is the
+// measured flow of its busiest lane over the saturation flow of a lane, and
+// Y is the sum over the phases. With L seconds lost per cycle (each phase's
+// amber and all-red), the cycle that minimizes the average delay is
+//
+//   C = (1.5 L + 5) / (1 - Y)
+//
+// and the green left over after the lost time, C - L, is split between the
+// phases in proportion to their y. Near saturation (Y close to 1) the formula
+// runs away, so the cycle is capped at MAX_CYCLE_SECS; every phase keeps at
+// least MIN_GREEN_SECS so a quiet one is still served.
+
+use std::collections::HashMap;
+
+use crate::phase_plan::Phase;
+
+/// Vehicles per second a lane discharges at while green with a standing
+/// queue (1800 an hour).
+pub const SATURATION_FLOW: f64 = 0.5;
+/// Shortest green a plan gives any phase, in seconds.
+pub const MIN_GREEN_SECS: u32 = 5;
+/// Longest cycle a plan uses, in seconds.
+pub const MAX_CYCLE_SECS: u32 = 150;
+/// Sum of flow ratios above which a junction counts as saturated and gets the
+/// longest cycle.
+const SATURATED_FLOW_RATIO: f64 = 0.9;
+
+/// A junction's cycle and the green time of each of its phases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimingPlan {
+    /// The junction.
+    pub junction: u32,
+    /// Cycle length in seconds, lost time included.
+    pub cycle_secs: u32,
+    /// Green seconds of each phase, indexed like the junction's phase plan.
+    pub greens: Vec<u32>,
+}
+
+/// Flow ratio of each phase: its busiest lane's flow, in vehicles per
+/// second, over SATURATION_FLOW. Lanes missing from `flows` carry none.
+pub fn flow_ratios(phases: &[Phase], flows: &HashMap<u32, f64>) -> Vec<f64> {
+    phases
+        .iter()
+        .map(|phase| {
+            let busiest = phase.lanes.iter().map(|lane_id| flows.get(lane_id).copied().unwrap_or(0.0)).fold(0.0, f64::max);
+            (busiest / SATURATION_FLOW).min(1.0)
+        })
+        .collect()
+}
+
+/// Webster's plan for `junction` (see the top of this file), with
+/// `lost_secs` lost in each phase change. None if no phase carries any flow.
+pub fn timing_plan(junction: u32, phases: &[Phase], flows: &HashMap<u32, f64>, lost_secs: f64) -> Option<TimingPlan> {
+    let ratios = flow_ratios(phases, flows);
+    let total: f64 = ratios.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let lost = lost_secs * phases.len() as f64;
+    let min_cycle = lost + (MIN_GREEN_SECS as usize * phases.len()) as f64;
+    let cycle = if total >= SATURATED_FLOW_RATIO {
+        MAX_CYCLE_SECS as f64
+    } else {
+        (1.5 * lost + 5.0) / (1.0 - total)
+    };
+    let cycle = cycle.min(MAX_CYCLE_SECS as f64).max(min_cycle);
+    let effective_green = cycle - lost;
+    let greens: Vec<u32> =
+        ratios.iter().map(|ratio| ((effective_green * ratio / total).round() as u32).max(MIN_GREEN_SECS)).collect();
+    let cycle_secs = (lost.round() as u32) + greens.iter().sum::<u32>();
+    Some(TimingPlan { junction, cycle_secs, greens })
+}
+
+/// Webster timing plans are opt-in via RTS_WEBSTER=1.
+pub fn webster_enabled() -> bool {
+    matches!(std::env::var("RTS_WEBSTER").as_deref(), Ok("1") | Ok("true") | Ok("yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two phases of one approach lane each, lanes 1 and 2.
+    fn two_phases() -> Vec<Phase> {
+        vec![Phase { lanes: vec![1] }, Phase { lanes: vec![2] }]
+    }
+
+    #[test]
+    fn flow_ratio_is_the_busiest_lane_over_saturation() {
+        let phases = vec![Phase { lanes: vec![1, 2] }, Phase { lanes: vec![3] }];
+        let flows = HashMap::from([(1, 0.1), (2, 0.2), (3, 0.8)]);
+        assert_eq!(flow_ratios(&phases, &flows), [0.4, 1.0]);
+    }
+
+    #[test]
+    fn splits_the_optimal_cycle_by_flow_ratio() {
+        // y = 0.3 and 0.2, L = 8 s: C = (1.5 × 8 + 5) / (1 - 0.5) = 34 s.
+        let flows = HashMap::from([(1, 0.15), (2, 0.1)]);
+        let plan = timing_plan(7, &two_phases(), &flows, 4.0).unwrap();
+        assert_eq!(plan, TimingPlan { junction: 7, cycle_secs: 34, greens: vec![16, 10] });
+    }
+
+    #[test]
+    fn saturated_junctions_get_the_longest_cycle() {
+        let flows = HashMap::from([(1, 0.3), (2, 0.2)]);
+        let plan = timing_plan(7, &two_phases(), &flows, 4.0).unwrap();
+        assert_eq!(plan.cycle_secs, MAX_CYCLE_SECS);
+        assert_eq!(plan.greens, [85, 57]);
+    }
+
+    #[test]
+    fn quiet_phases_keep_the_minimum_green() {
+        let flows = HashMap::from([(1, 0.2)]);
+        let plan = timing_plan(7, &two_phases(), &flows, 4.0).unwrap();
+        assert_eq!(plan.greens, [20, MIN_GREEN_SECS]);
+        assert_eq!(plan.cycle_secs, 8 + 20 + MIN_GREEN_SECS);
+    }
+
+    #[test]
+    fn no_flow_means_no_plan() {
+        assert_eq!(timing_plan(7, &two_phases(), &HashMap::new(), 4.0), None);
+    }
+}